@@ -0,0 +1,115 @@
+//! Round-trip latency of `poll_hot` against `dequeue`, ping-ponging one
+//! message between two threads over two ports. Each round trip is timed
+//! with `HwClock`, the cycle counter, for nanosecond resolution.
+//!
+//! Run with `cargo bench --bench hot_poll`. On Linux the threads are
+//! pinned to CPUs 0 and 1; the numbers only mean something on otherwise
+//! idle cores.
+
+use std::hint::spin_loop;
+use std::ptr::NonNull;
+use std::thread;
+use ring_buffer::{Clock, HwClock, Message, QueueingPort, Segment, SIZE};
+
+const WARMUP: u32 = 10_000;
+const ROUND_TRIPS: u32 = 200_000;
+
+#[derive(Clone, Copy)]
+enum Receive {
+    Dequeue,
+    PollHot,
+}
+
+/// Spins until a message arrives and returns its first byte.
+fn receive(port: &mut QueueingPort, how: Receive) -> u8 {
+    loop {
+        match how {
+            Receive::Dequeue => {
+                if let Ok(message) = port.try_dequeue() {
+                    return message.0[0];
+                }
+            }
+            Receive::PollHot => {
+                if let Some(bytes) = port.poll_hot() {
+                    return bytes[0];
+                }
+            }
+        }
+        spin_loop();
+    }
+}
+
+fn pin_to_cpu(cpu: usize) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        // Best effort: on a single-CPU machine this fails and nothing changes.
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = cpu;
+}
+
+fn attach_pair() -> (QueueingPort, QueueingPort) {
+    // Leaked, so it outlives both threads.
+    let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+    unsafe { (QueueingPort::attach(segment), QueueingPort::attach(segment)) }
+}
+
+/// Every timed round trip in nanoseconds, sorted.
+fn ping_pong(how: Receive) -> Vec<u64> {
+    let (mut ping_tx, mut ping_rx) = attach_pair();
+    let (mut pong_tx, mut pong_rx) = attach_pair();
+    let total = WARMUP + ROUND_TRIPS;
+
+    let echo = thread::spawn(move || {
+        pin_to_cpu(1);
+        for _ in 0..total {
+            let byte = receive(&mut ping_rx, how);
+            while pong_tx.enqueue(Message([byte; SIZE])).is_err() {
+                spin_loop();
+            }
+        }
+    });
+
+    pin_to_cpu(0);
+    let clock = HwClock;
+    let mut round_trips = Vec::with_capacity(ROUND_TRIPS as usize);
+    for i in 0..total {
+        let started = clock.now_ns();
+        ping_tx.enqueue(Message([i as u8; SIZE])).unwrap();
+        assert_eq!(receive(&mut pong_rx, how), i as u8);
+        if i >= WARMUP {
+            round_trips.push(clock.now_ns() - started);
+        }
+    }
+    echo.join().unwrap();
+    round_trips.sort_unstable();
+    round_trips
+}
+
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+fn main() {
+    if thread::available_parallelism().map_or(1, |cpus| cpus.get()) < 2 {
+        // Both threads would spin on one CPU, a scheduler tick per hand-off.
+        println!("hot_poll needs two CPUs; skipped");
+        return;
+    }
+    // Calibrates the clock before anything is timed.
+    println!("cycle counter: {:.3} cycles per ns", HwClock::cycles_per_ns());
+    for (name, how) in [("dequeue", Receive::Dequeue), ("poll_hot", Receive::PollHot)] {
+        let round_trips = ping_pong(how);
+        let mean = round_trips.iter().sum::<u64>() as f64 / round_trips.len() as f64;
+        println!(
+            "{:>8}: {:8.1} ns mean, {:6} ns p50, {:6} ns p99 per round trip",
+            name,
+            mean,
+            percentile(&round_trips, 50),
+            percentile(&round_trips, 99)
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Slot index math: a mask against a division, alone and on the hot path
+//! of an `AlignedQueueingPort` with 8 slots against one with 10.
+//!
+//! Run with `cargo bench --bench index_math`. Hosted CPUs divide in
+//! hardware, so the gap here is far smaller than on a core without a
+//! divide instruction, where the division is a library call.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use ring_buffer::{AlignedQueueingPort, IndexMath};
+
+const POSITIONS: usize = 100_000_000;
+const ROUND_TRIPS: usize = 10_000_000;
+
+/// Nanoseconds per index, with the slot count hidden from the compiler so
+/// it cannot turn the division into a multiplication.
+fn index_ns(math: IndexMath, count: usize) -> f64 {
+    let count = black_box(count);
+    let started = Instant::now();
+    let mut sum = 0usize;
+    for position in 0..POSITIONS {
+        let index = match math {
+            IndexMath::Mask => position & (count - 1),
+            IndexMath::Modulo => position % count,
+        };
+        sum = sum.wrapping_add(black_box(index));
+    }
+    black_box(sum);
+    started.elapsed().as_nanos() as f64 / POSITIONS as f64
+}
+
+/// Nanoseconds per enqueue and dequeue of one message.
+fn round_trip_ns<const MSG_COUNT: usize>() -> f64 {
+    let mut port = AlignedQueueingPort::<8, MSG_COUNT, 8>::new();
+    let started = Instant::now();
+    for i in 0..ROUND_TRIPS {
+        port.enqueue(black_box(&[i as u8; 8])).unwrap();
+        black_box(port.dequeue().unwrap());
+    }
+    started.elapsed().as_nanos() as f64 / ROUND_TRIPS as f64
+}
+
+fn main() {
+    println!("    mask index: {:6.2} ns", index_ns(IndexMath::Mask, 8));
+    println!("  modulo index: {:6.2} ns", index_ns(IndexMath::Modulo, 10));
+    println!(" 8-slot port:   {:6.2} ns per round trip", round_trip_ns::<8>());
+    println!("10-slot port:   {:6.2} ns per round trip", round_trip_ns::<10>());
+}
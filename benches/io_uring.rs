@@ -0,0 +1,67 @@
+//! Cost of an enqueue and dequeue through `IoUringPort` against the same
+//! pair on the `QueueingPort` underneath, which copies with the CPU.
+//!
+//! Run with `cargo bench --bench io_uring --features linux-io-uring`. Both
+//! ends are in this thread, so the numbers are per-operation overhead, not
+//! latency between processes.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ring_buffer::uring::IoUringPort;
+use ring_buffer::{Message, PortError, QueueingPort, SIZE};
+
+const WARMUP: u32 = 10_000;
+const PAIRS: u32 = 200_000;
+
+/// Creates a named port and opens it again, returning both ends.
+fn named_pair(name: &str) -> (QueueingPort, QueueingPort) {
+    let opener = thread::spawn({
+        let name = name.to_owned();
+        move || loop {
+            match QueueingPort::open(&name) {
+                Err(PortError::Open(_)) => thread::sleep(Duration::from_millis(1)),
+                result => break result.unwrap(),
+            }
+        }
+    });
+    let writer = QueueingPort::create(name).unwrap();
+    (writer, opener.join().unwrap())
+}
+
+/// Nanoseconds per enqueue-and-dequeue pair.
+fn measure(mut pair: impl FnMut(u8)) -> f64 {
+    for i in 0..WARMUP {
+        pair(i as u8);
+    }
+    let start = Instant::now();
+    for i in 0..PAIRS {
+        pair(i as u8);
+    }
+    start.elapsed().as_nanos() as f64 / f64::from(PAIRS)
+}
+
+fn main() {
+    let name = format!("/qp_bench_io_uring_{}", std::process::id());
+    let (mut writer, mut reader) = named_pair(&name);
+    let baseline = measure(|i| {
+        writer.enqueue(Message([i; SIZE])).unwrap();
+        assert_eq!(reader.dequeue().unwrap().0[0], i);
+    });
+
+    let mut writer = match IoUringPort::new(writer) {
+        Ok(port) => port,
+        Err(error) => {
+            eprintln!("skipped: io_uring is not available here ({:?})", error);
+            return;
+        }
+    };
+    let mut reader = IoUringPort::new(reader).unwrap();
+    let uring = measure(|i| {
+        writer.enqueue(Message([i; SIZE])).unwrap();
+        assert_eq!(reader.dequeue().unwrap().0[0], i);
+    });
+
+    println!("QueueingPort:  {:>8.1} ns per pair", baseline);
+    println!("IoUringPort:   {:>8.1} ns per pair", uring);
+}
@@ -0,0 +1,67 @@
+//! A `LocalQueue` against a named port in shared memory: setting one up
+//! and tearing it down, and an enqueue and dequeue on it.
+//!
+//! Run with `cargo bench --bench local_queue`. Once set up, both are the
+//! same ring in ordinary memory, so the gap is mostly in the set-up: a
+//! heap allocation against `shm_open`, `ftruncate` and `mmap` on both
+//! ends, the handshake between them and the unlink and unmaps on drop.
+
+use std::hint::black_box;
+use std::thread;
+use std::time::Instant;
+
+use ring_buffer::{Message, QueueingPort, SIZE};
+
+const PORTS: u32 = 200;
+const ROUND_TRIPS: u32 = 5_000_000;
+
+/// A named port's writer and reader, in this process.
+fn named(n: u32) -> (QueueingPort, QueueingPort) {
+    let name = format!("/local_queue_bench_{}_{}", std::process::id(), n);
+    thread::scope(|scope| {
+        let writer = scope.spawn(|| QueueingPort::create(&name).unwrap());
+        let reader = loop {
+            match QueueingPort::open(&name) {
+                Ok(reader) => break reader,
+                Err(_) => thread::yield_now(),
+            }
+        };
+        (writer.join().unwrap(), reader)
+    })
+}
+
+/// Nanoseconds to set up and drop what `create` makes.
+fn setup_ns<T>(mut create: impl FnMut(u32) -> T) -> f64 {
+    let started = Instant::now();
+    for n in 0..PORTS {
+        drop(black_box(create(n)));
+    }
+    started.elapsed().as_nanos() as f64 / PORTS as f64
+}
+
+/// Nanoseconds per `round_trip`, an enqueue and a dequeue of the message.
+fn round_trip_ns(mut round_trip: impl FnMut(Message) -> Message) -> f64 {
+    let started = Instant::now();
+    for i in 0..ROUND_TRIPS {
+        black_box(round_trip(black_box(Message([i as u8; SIZE]))));
+    }
+    started.elapsed().as_nanos() as f64 / ROUND_TRIPS as f64
+}
+
+fn main() {
+    let local = setup_ns(|_| QueueingPort::boxed());
+    let shared = setup_ns(named);
+    println!("set up and drop:  local {:12.1} ns   named {:12.1} ns", local, shared);
+
+    let mut queue = QueueingPort::boxed();
+    let local = round_trip_ns(|message| {
+        queue.enqueue(message).unwrap();
+        queue.dequeue().unwrap()
+    });
+    let (mut writer, mut reader) = named(PORTS);
+    let shared = round_trip_ns(|message| {
+        writer.enqueue(message).unwrap();
+        reader.dequeue().unwrap()
+    });
+    println!("round trip:       local {:12.1} ns   named {:12.1} ns", local, shared);
+}
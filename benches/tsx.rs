@@ -0,0 +1,49 @@
+//! `TsxMutex` against the `SpinMutex` it falls back on, around an enqueue
+//! and a dequeue on a shared port.
+//!
+//! Run with `cargo bench --bench tsx --features tsx`. Two threads take
+//! turns at the port, each one making round trips under the lock. On a CPU
+//! without RTM, or with TSX disabled, the two are the same lock and the
+//! numbers differ only by noise; the first line says which it is.
+
+use std::hint::black_box;
+use std::thread;
+use std::time::Instant;
+
+use ring_buffer::{Message, QueueingPort, SpinMutex, TsxMutex, SIZE};
+
+const THREADS: u32 = 2;
+const ROUND_TRIPS: u32 = 1_000_000;
+
+/// Nanoseconds per round trip, over all threads each making
+/// `ROUND_TRIPS` of them with `round_trip`.
+fn round_trip_ns(round_trip: impl Fn(Message) + Sync) -> f64 {
+    let started = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..THREADS {
+            scope.spawn(|| {
+                for i in 0..ROUND_TRIPS {
+                    round_trip(black_box(Message([i as u8; SIZE])));
+                }
+            });
+        }
+    });
+    started.elapsed().as_nanos() as f64 / f64::from(THREADS * ROUND_TRIPS)
+}
+
+fn main() {
+    let tsx = TsxMutex::new(QueueingPort::new());
+    println!("RTM: {}", if tsx.elides() { "yes, eliding" } else { "no, spinning" });
+    let elided = round_trip_ns(|message| {
+        let mut port = tsx.lock();
+        port.enqueue(message).unwrap();
+        black_box(port.dequeue().unwrap());
+    });
+    let spin = SpinMutex::new(QueueingPort::new());
+    let spinning = round_trip_ns(|message| {
+        let mut port = spin.lock();
+        port.enqueue(message).unwrap();
+        black_box(port.dequeue().unwrap());
+    });
+    println!("round trip:  TsxMutex {:10.1} ns   SpinMutex {:10.1} ns", elided, spinning);
+}
@@ -0,0 +1,14 @@
+fn main() {
+    // The C side of the `ffi` tests; the archive is only pulled into
+    // binaries that call `qp_c_selftest`.
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=include/queueing_port.h");
+        println!("cargo:rerun-if-changed=tests/c/queueing_port_test.c");
+        cc::Build::new()
+            .file("tests/c/queueing_port_test.c")
+            .include("include")
+            .warnings(true)
+            .compile("queueing_port_test");
+    }
+}
@@ -0,0 +1,57 @@
+//! Drains ten messages sent from another thread through an async stream.
+//!
+//! Any executor will do; this one parks the thread until the stream's
+//! waker is called, so the example needs no async runtime.
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use ring_buffer::{Message, QueueingPort, SIZE};
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+fn main() {
+    let (sender, receiver) = QueueingPort::new().into_mpsc_channel();
+    let producer = thread::spawn(move || {
+        for i in 0..10u8 {
+            sender.send(Message([i; SIZE])).unwrap();
+        }
+        // Dropping the last sender ends the stream.
+    });
+
+    let messages = block_on(async {
+        let mut stream = receiver.stream();
+        let mut messages = Vec::new();
+        while let Some(message) = stream.next().await {
+            messages.push(message.expect("dequeue failed"));
+        }
+        messages
+    });
+    producer.join().unwrap();
+
+    assert_eq!(messages.len(), 10);
+    for message in &messages {
+        println!("{}", message.0[0]);
+    }
+}
@@ -0,0 +1,76 @@
+/*
+ * C interface to the ring_buffer port registry (the `ffi` feature).
+ *
+ * Ports are created once and addressed by an id below QP_MAX_PORTS. Every
+ * function returns QP_OK or one of the negative QP_E* codes. The registry
+ * is guarded by a mutex, so any thread may call any function.
+ */
+#ifndef QUEUEING_PORT_H
+#define QUEUEING_PORT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define QP_MAX_PORTS 64
+
+#define QP_OK 0
+/* An id out of range, a null pointer, or a size the geometry cannot hold. */
+#define QP_EINVAL (-1)
+/* qp_create on an id that is already in use. */
+#define QP_EEXIST (-2)
+/* No port was created with this id. */
+#define QP_ENOENT (-3)
+#define QP_EFULL (-4)
+#define QP_EEMPTY (-5)
+/* The message is longer than the port's message size, or the receive
+ * buffer shorter; nothing was sent or received. */
+#define QP_ESIZE (-6)
+
+/* Partition operating modes and start conditions (ARINC 653
+ * OPERATING_MODE_TYPE and START_CONDITION_TYPE), as a port's partition
+ * status records them. */
+#define QP_IDLE 0
+#define QP_COLD_START 1
+#define QP_WARM_START 2
+#define QP_NORMAL 3
+#define QP_NORMAL_START 0
+#define QP_PARTITION_RESTART 1
+#define QP_HM_MODULE_RESTART 2
+#define QP_HM_PARTITION_RESTART 3
+
+/* Creates port `port_id`, holding up to `msg_count` messages of `msg_size`
+ * bytes. Both are limited by the library's build-time geometry (256-byte
+ * messages, 10 per port, by default). */
+int32_t qp_create(uint32_t port_id, size_t msg_size, size_t msg_count);
+
+/* Enqueues `len` bytes from `data`, at most the port's message size. */
+int32_t qp_send(uint32_t port_id, const uint8_t *data, size_t len);
+
+/* Dequeues the next message into `data`, which holds `len` bytes. On
+ * success `*actual` is the port's message size; shorter messages are
+ * zero-filled up to it. */
+int32_t qp_recv(uint32_t port_id, uint8_t *data, size_t len, size_t *actual);
+
+/* Enqueues `len` bytes from `data`, at most the library's message size,
+ * into the signal queue the Rust side drains into a port. Takes no lock,
+ * so it may be called from a signal handler; QP_EFULL if the signal queue
+ * is full. */
+int32_t qp_signal_enqueue(const uint8_t *data, size_t len);
+
+/* A port the Rust side gave up with QueueingPort::into_raw, outside the
+ * id table. Opaque: only ever handled by pointer. */
+typedef struct QueueingPort QueueingPort;
+
+/* Drops a port from QueueingPort::into_raw; the pointer must not be used,
+ * or dropped, again. NULL is ignored. */
+void qp_drop(QueueingPort *port);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* QUEUEING_PORT_H */
@@ -0,0 +1,110 @@
+//! `queue_channel!`, re-exported as `ring_buffer::queue_channel`.
+//!
+//! A declarative macro cannot make the `send_<name>` and `recv_<name>`
+//! identifiers, so this is a procedural one. It parses its input by hand
+//! rather than with `syn`, to keep the crate free of dependencies, and
+//! writes the expansion as source text.
+
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+/// Declares a channel of `Type` values holding up to `capacity` of them.
+///
+/// ```ignore
+/// queue_channel!(telemetry: TelemetryFrame, capacity: 16);
+/// queue_channel!(pub(crate) commands: Command, capacity: 4);
+/// ```
+///
+/// expands, in the current module, to a static `ring_buffer::MpmcQueue`
+/// with `Type`-sized slots, and to
+///
+/// ```ignore
+/// fn send_telemetry(msg: TelemetryFrame) -> Result<(), ring_buffer::QueueError>;
+/// fn recv_telemetry() -> Result<TelemetryFrame, ring_buffer::QueueError>;
+/// ```
+///
+/// with the visibility given before the name, if any. `Type` must be
+/// `ring_buffer::Pod`. Sending to a full channel fails with
+/// `QueueError::FullBuffer`, and receiving from an empty one with
+/// `QueueError::EmptyBuffer`. The expansion names the crate as
+/// `::ring_buffer`, so it must be a direct dependency under that name.
+#[proc_macro]
+pub fn queue_channel(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(expansion) => expansion.parse().unwrap(),
+        Err(message) => format!("::core::compile_error!({:?});", message).parse().unwrap(),
+    }
+}
+
+const USAGE: &str = "expected `queue_channel!(name: Type, capacity: N)`";
+
+fn expand(input: TokenStream) -> Result<String, String> {
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+    let mut at = 0;
+
+    let mut visibility = String::new();
+    if matches!(tokens.first(), Some(TokenTree::Ident(ident)) if ident.to_string() == "pub") {
+        visibility.push_str("pub");
+        at = 1;
+        if let Some(TokenTree::Group(group)) = tokens.get(1) {
+            if group.delimiter() == Delimiter::Parenthesis {
+                visibility.push_str(&group.to_string());
+                at = 2;
+            }
+        }
+    }
+
+    let name = match tokens.get(at) {
+        // `send_` and `recv_` make a raw name an ordinary one.
+        Some(TokenTree::Ident(ident)) => ident.to_string().trim_start_matches("r#").to_owned(),
+        _ => return Err(USAGE.into()),
+    };
+    if !is_punct(tokens.get(at + 1), ':') {
+        return Err(USAGE.into());
+    }
+    let rest = &tokens[at + 2..];
+
+    // The type runs to the `, capacity:` after it; it may hold commas of
+    // its own, between angle brackets, which are not groups.
+    let split = (0..rest.len())
+        .find(|&i| {
+            is_punct(rest.get(i), ',')
+                && matches!(rest.get(i + 1), Some(TokenTree::Ident(ident)) if ident.to_string() == "capacity")
+                && is_punct(rest.get(i + 2), ':')
+        })
+        .ok_or(USAGE)?;
+    let ty = source(&rest[..split]);
+    let mut capacity = &rest[split + 3..];
+    if is_punct(capacity.last(), ',') {
+        capacity = &capacity[..capacity.len() - 1];
+    }
+    if ty.is_empty() || capacity.is_empty() {
+        return Err(USAGE.into());
+    }
+    let capacity = source(capacity);
+
+    let queue = format!("__QUEUE_CHANNEL_{}", name.to_uppercase());
+    Ok(format!(
+        "static {queue}: ::ring_buffer::MpmcQueue<{{ ::core::mem::size_of::<{ty}>() }}, {{ {capacity} }}> =
+            ::ring_buffer::MpmcQueue::new();
+
+        /// Sends `msg` on the `{name}` channel, failing with
+        /// `QueueError::FullBuffer` if it is full.
+        {visibility} fn send_{name}(msg: {ty}) -> ::core::result::Result<(), ::ring_buffer::QueueError> {{
+            {queue}.push_value(msg)
+        }}
+
+        /// Receives the oldest message on the `{name}` channel, failing
+        /// with `QueueError::EmptyBuffer` if there is none.
+        {visibility} fn recv_{name}() -> ::core::result::Result<{ty}, ::ring_buffer::QueueError> {{
+            {queue}.pop_value()
+        }}"
+    ))
+}
+
+fn is_punct(token: Option<&TokenTree>, ch: char) -> bool {
+    matches!(token, Some(TokenTree::Punct(punct)) if punct.as_char() == ch)
+}
+
+fn source(tokens: &[TokenTree]) -> String {
+    tokens.iter().cloned().collect::<TokenStream>().to_string()
+}
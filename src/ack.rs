@@ -0,0 +1,120 @@
+//! Delivery confirmed by the receiver.
+//!
+//! `send_until_acked` enqueues a message and waits for the receiver to
+//! `ack` it, by the sequence number `dequeue_timed` reported for it. With
+//! no acknowledgement within the retry interval it enqueues another copy,
+//! up to `max_retries` times. An acknowledgement of any of the copies
+//! counts, so a receiver should expect repeats of a message whose
+//! acknowledgement was late.
+//!
+//! The receiver records the sequence number in the header, then bumps a
+//! count of acknowledgements, which the sender watches. The sender takes
+//! its copies' sequence numbers from the `enqueued` count, so there must be
+//! one sender on the port while it waits.
+
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::{Message, QueueError, QueueingPort};
+
+impl QueueingPort {
+    /// Acknowledges the message with sequence number `sequence`, as
+    /// reported by `dequeue_timed`, to a sender in `send_until_acked`.
+    pub fn ack(&mut self, sequence: u32) {
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        header.acked.store(order, sequence, Ordering::Relaxed);
+        // Publishes `acked` to the sender watching the count.
+        header.acks.fetch_add(order, 1, Ordering::Release);
+    }
+
+    /// Enqueues `msg` and waits for the receiver to acknowledge it, which
+    /// it is given `retry_interval` of the port's clock to do, enqueueing a
+    /// copy again each time it has not. Returns how many retries it took.
+    ///
+    /// Fails with `QueueError::MaxRetriesExceeded` once `max_retries`
+    /// retries went unacknowledged, and like `enqueue` if a copy cannot be
+    /// enqueued.
+    pub fn send_until_acked(
+        &mut self,
+        msg: Message,
+        max_retries: u32,
+        retry_interval: Duration,
+    ) -> Result<u32, QueueError> {
+        let interval_ns = retry_interval.as_nanos() as u64;
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let first = header.enqueued.load(order, Ordering::Relaxed);
+        let mut acks_seen = header.acks.load(order, Ordering::Acquire);
+        for retries in 0..=max_retries {
+            self.enqueue(Message(msg.0))?;
+            let header = &self.segment().header;
+            let sent = header.enqueued.load(order, Ordering::Relaxed).wrapping_sub(first);
+            let deadline_ns = self.clock().now_ns().saturating_add(interval_ns);
+            loop {
+                let acks = header.acks.load(order, Ordering::Acquire);
+                if acks != acks_seen {
+                    acks_seen = acks;
+                    // Any of the copies; an acknowledgement of an older
+                    // message is not ours.
+                    if header.acked.load(order, Ordering::Relaxed).wrapping_sub(first) < sent {
+                        return Ok(retries);
+                    }
+                }
+                if self.clock().now_ns() >= deadline_ns {
+                    break;
+                }
+                self.clock().pause(deadline_ns);
+            }
+        }
+        Err(QueueError::MaxRetriesExceeded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Segment, SimulatedClock, SIZE};
+    use core::ptr::NonNull;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn succeeds_once_a_retry_is_acked() {
+        let segment = Box::new(Segment::new());
+        let (mut sender, mut receiver) =
+            unsafe { (QueueingPort::attach(NonNull::from(&*segment)), QueueingPort::attach(NonNull::from(&*segment))) };
+        // Ignores the first two copies, as if their acknowledgements were lost.
+        let acker = thread::spawn(move || {
+            let mut copies = 0;
+            loop {
+                let Ok(received) = receiver.dequeue_timed() else {
+                    thread::yield_now();
+                    continue;
+                };
+                assert_eq!(received.message.0, [7; SIZE]);
+                copies += 1;
+                if copies == 3 {
+                    receiver.ack(received.sequence);
+                    return copies;
+                }
+            }
+        });
+        let retries = sender.send_until_acked(Message([7; SIZE]), 5, Duration::from_millis(50));
+        assert_eq!(retries.unwrap(), 2);
+        assert_eq!(acker.join().unwrap(), 3);
+        assert!(sender.is_empty());
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let mut port = QueueingPort::new();
+        port.set_clock(Arc::new(SimulatedClock::new(0)));
+        // An acknowledgement of a message sent before does not count.
+        port.enqueue(Message([1; SIZE])).unwrap();
+        port.ack(0);
+        let result = port.send_until_acked(Message([2; SIZE]), 2, Duration::from_millis(100));
+        assert!(matches!(result, Err(QueueError::MaxRetriesExceeded)));
+        assert_eq!(port.len(), 4, "the message and both retries");
+    }
+}
@@ -0,0 +1,374 @@
+//! Ports whose message slots start on an alignment the hardware demands,
+//! such as the 64 or 128 bytes some DMA controllers need.
+//!
+//! The segment is a header of two counters and the length of each slot's
+//! message, padded to `ALIGN`, followed by `MSG_COUNT` slots of
+//! `slot_stride()` bytes: `MSG_SIZE` rounded up to the next multiple of
+//! `ALIGN`. With the segment itself aligned to `ALIGN`, every slot is, so a
+//! slot's address can be handed to the device as is. The padding after
+//! each message is never written.
+//!
+//! ```text
+//! 0   4  enqueued count
+//! 4   4  dequeued count
+//! 8   4  length of the message in slot 0, then slot 1, ...
+//! header_len      slot 0
+//! + slot_stride   slot 1, ...
+//! ```
+//!
+//! Messages can also be read in place as a typed value; see the `typed`
+//! module. With a power-of-two `MSG_COUNT` the counters become slot
+//! indices by masking, with no division; see the `index` module.
+//!
+//! `ALIGN` must be a power of two, or the port does not compile:
+//!
+//! ```compile_fail
+//! # use ring_buffer::AlignedQueueingPort;
+//! let _ = AlignedQueueingPort::<100, 4, 48>::slot_stride();
+//! ```
+//!
+//! Nor does a port whose slots would not all start on an 8-byte boundary,
+//! as for `SIZE`: with `ALIGN` below 8, `slot_stride()` must still be a
+//! multiple of 8.
+//!
+//! ```compile_fail
+//! # use ring_buffer::AlignedQueueingPort;
+//! let _ = AlignedQueueingPort::<7, 4, 1>::new();
+//! ```
+//!
+//! ```
+//! # use ring_buffer::AlignedQueueingPort;
+//! let port = AlignedQueueingPort::<7, 4, 8>::new();
+//! assert_eq!(AlignedQueueingPort::<7, 4, 8>::slot_stride(), 8);
+//! assert!(port.is_empty());
+//! ```
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::index::{counter_distance, next_counter, wrap};
+use crate::{IndexMath, PortError, QueueError};
+
+enum AlignedMemory {
+    /// Allocated by `new` with the segment's layout.
+    #[cfg(feature = "alloc")]
+    Owned(NonNull<u8>),
+    Attached(NonNull<u8>),
+}
+
+/// A port handing out `MSG_SIZE`-byte messages, up to `MSG_COUNT` at a
+/// time, from slots aligned to `ALIGN` bytes.
+pub struct AlignedQueueingPort<const MSG_SIZE: usize, const MSG_COUNT: usize, const ALIGN: usize> {
+    memory: AlignedMemory,
+    /// Messages `dequeue` copied out of their slots.
+    copied_out: u64,
+}
+
+// Each handle only writes the slots and count its end owns.
+unsafe impl<const MSG_SIZE: usize, const MSG_COUNT: usize, const ALIGN: usize> Send
+    for AlignedQueueingPort<MSG_SIZE, MSG_COUNT, ALIGN>
+{
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize, const ALIGN: usize> AlignedQueueingPort<MSG_SIZE, MSG_COUNT, ALIGN> {
+    /// Distance between the starts of two neighbouring slots: `MSG_SIZE`
+    /// padded to the next multiple of `ALIGN`.
+    pub const fn slot_stride() -> usize {
+        const { assert!(ALIGN.is_power_of_two(), "ALIGN must be a power of two") };
+        MSG_SIZE.next_multiple_of(ALIGN)
+    }
+
+    /// Bytes before the first slot: the counters and lengths, padded to
+    /// `ALIGN`.
+    pub const fn header_len() -> usize {
+        const { assert!(ALIGN.is_power_of_two(), "ALIGN must be a power of two") };
+        (8 + 4 * MSG_COUNT).next_multiple_of(ALIGN)
+    }
+
+    /// Size in bytes of the memory `attach` expects.
+    pub const fn segment_len() -> usize {
+        Self::header_len() + MSG_COUNT * Self::slot_stride()
+    }
+
+    #[cfg(feature = "alloc")]
+    fn layout() -> core::alloc::Layout {
+        // ALIGN is a power of two, and a segment large enough to overflow
+        // isize could not be allocated anyway.
+        core::alloc::Layout::from_size_align(Self::segment_len(), ALIGN.max(4)).expect("segment too large")
+    }
+
+    /// A port on zeroed memory of its own, aligned to `ALIGN`.
+    #[cfg(feature = "alloc")]
+    pub fn new() -> Self {
+        const { assert!(MSG_COUNT > 0 && MSG_COUNT <= u32::MAX as usize / 2, "MSG_COUNT out of range") };
+        const { assert!(Self::slot_stride().is_multiple_of(8), "slots must be 8-byte aligned") };
+        let layout = Self::layout();
+        let memory = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        let memory = NonNull::new(memory).unwrap_or_else(|| alloc::alloc::handle_alloc_error(layout));
+        AlignedQueueingPort { memory: AlignedMemory::Owned(memory), copied_out: 0 }
+    }
+
+    /// Creates a port operating on memory that lives elsewhere, typically a
+    /// buffer the device can reach. Fails with `PortError::Misaligned` if
+    /// `segment` is not aligned to `ALIGN` (or to the 4 bytes of the
+    /// counters).
+    ///
+    /// # Safety
+    ///
+    /// `segment` must point to `segment_len()` bytes whose header is zeroed
+    /// or was written by another port of the same geometry, that outlive
+    /// the returned port. Among all ports attached to it at most one may
+    /// enqueue and at most one may dequeue.
+    pub unsafe fn attach(segment: NonNull<u8>) -> Result<Self, PortError> {
+        const { assert!(MSG_COUNT > 0 && MSG_COUNT <= u32::MAX as usize / 2, "MSG_COUNT out of range") };
+        const { assert!(Self::slot_stride().is_multiple_of(8), "slots must be 8-byte aligned") };
+        if !(segment.as_ptr() as usize).is_multiple_of(ALIGN.max(4)) {
+            return Err(PortError::Misaligned);
+        }
+        Ok(AlignedQueueingPort { memory: AlignedMemory::Attached(segment), copied_out: 0 })
+    }
+
+    fn base(&self) -> NonNull<u8> {
+        match self.memory {
+            #[cfg(feature = "alloc")]
+            AlignedMemory::Owned(memory) => memory,
+            AlignedMemory::Attached(memory) => memory,
+        }
+    }
+
+    pub(crate) fn counters(&self) -> (&AtomicU32, &AtomicU32) {
+        // The header starts the aligned segment, which lives as long as the
+        // port.
+        let header = self.base().as_ptr().cast::<AtomicU32>();
+        unsafe { (&*header, &*header.add(1)) }
+    }
+
+    /// The length word of slot `index`, written by the writer before it
+    /// publishes the slot.
+    pub(crate) fn slot_len(&self, index: usize) -> &AtomicU32 {
+        // Inside the header, after the two counters.
+        unsafe { &*self.base().as_ptr().cast::<AtomicU32>().add(2 + wrap(index, MSG_COUNT)) }
+    }
+
+    /// Start of slot `index`, a multiple of `ALIGN` past the segment start.
+    /// The slot holds the message with sequence number `index` modulo
+    /// `MSG_COUNT`; writing it while queued is the caller's responsibility.
+    pub fn slot_ptr(&self, index: usize) -> *mut u8 {
+        let offset = Self::header_len() + wrap(index, MSG_COUNT) * Self::slot_stride();
+        // Inside the `segment_len()` bytes of the segment.
+        unsafe { self.base().as_ptr().add(offset) }
+    }
+
+    /// Queues a copy of `message`, failing with `QueueError::FullBuffer` if
+    /// all slots are taken.
+    pub fn enqueue(&mut self, message: &[u8; MSG_SIZE]) -> Result<(), QueueError> {
+        self.enqueue_bytes(message)
+    }
+
+    /// Queues a copy of `bytes`, a message of `bytes.len()` bytes. The rest
+    /// of the slot is zeroed. Fails with `QueueError::MessageTooLarge` for
+    /// more than `MSG_SIZE` bytes.
+    pub fn enqueue_bytes(&mut self, bytes: &[u8]) -> Result<(), QueueError> {
+        if bytes.len() > MSG_SIZE {
+            return Err(QueueError::MessageTooLarge { len: bytes.len() });
+        }
+        let (enqueued, dequeued) = self.counters();
+        let write = enqueued.load(Ordering::Relaxed);
+        if counter_distance(dequeued.load(Ordering::Acquire), write, MSG_COUNT) >= MSG_COUNT {
+            return Err(QueueError::FullBuffer);
+        }
+        let slot = self.slot_ptr(write as usize);
+        // A free slot, which only the writer touches.
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), slot, bytes.len());
+            core::ptr::write_bytes(slot.add(bytes.len()), 0, MSG_SIZE - bytes.len());
+        }
+        self.slot_len(write as usize).store(bytes.len() as u32, Ordering::Relaxed);
+        enqueued.store(next_counter(write, MSG_COUNT), Ordering::Release);
+        Ok(())
+    }
+
+    pub fn dequeue(&mut self) -> Result<[u8; MSG_SIZE], QueueError> {
+        let (enqueued, dequeued) = self.counters();
+        let read = dequeued.load(Ordering::Relaxed);
+        if enqueued.load(Ordering::Acquire) == read {
+            return Err(QueueError::EmptyBuffer);
+        }
+        let mut message = [0; MSG_SIZE];
+        // An occupied slot, which only the reader touches.
+        unsafe { core::ptr::copy_nonoverlapping(self.slot_ptr(read as usize), message.as_mut_ptr(), MSG_SIZE) };
+        dequeued.store(next_counter(read, MSG_COUNT), Ordering::Release);
+        self.copied_out += 1;
+        Ok(message)
+    }
+
+    /// How many messages `dequeue` has copied out of their slots through
+    /// this handle.
+    pub fn copied_out(&self) -> u64 {
+        self.copied_out
+    }
+
+    pub fn len(&self) -> usize {
+        let (enqueued, dequeued) = self.counters();
+        counter_distance(dequeued.load(Ordering::Acquire), enqueued.load(Ordering::Acquire), MSG_COUNT).min(MSG_COUNT)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity() -> usize {
+        MSG_COUNT
+    }
+
+    /// Bytes of the segment, `segment_len()`: every slot in full with its
+    /// padding, and the header.
+    pub const fn capacity_bytes(&self) -> usize {
+        Self::segment_len()
+    }
+
+    /// Bytes of the queued messages, `MSG_SIZE` for each whatever its
+    /// length.
+    pub fn utilization_bytes(&self) -> usize {
+        self.len() * MSG_SIZE
+    }
+
+    /// 1.0 minus `utilization_bytes / capacity_bytes`, header and padding
+    /// counting as unused.
+    pub fn fragmentation_ratio(&self) -> f32 {
+        1.0 - self.utilization_bytes() as f32 / self.capacity_bytes() as f32
+    }
+
+    /// `IndexMath::Mask` if `MSG_COUNT` is a power of two.
+    pub const fn index_math() -> IndexMath {
+        IndexMath::for_slots(MSG_COUNT)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize, const ALIGN: usize> Default
+    for AlignedQueueingPort<MSG_SIZE, MSG_COUNT, ALIGN>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize, const ALIGN: usize> Drop
+    for AlignedQueueingPort<MSG_SIZE, MSG_COUNT, ALIGN>
+{
+    fn drop(&mut self) {
+        #[cfg(feature = "alloc")]
+        if let AlignedMemory::Owned(memory) = self.memory {
+            // Allocated in `new` with this layout.
+            unsafe { alloc::alloc::dealloc(memory.as_ptr(), Self::layout()) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type DmaPort = AlignedQueueingPort<100, 4, 64>;
+
+    #[test]
+    fn slots_are_padded_to_the_alignment() {
+        assert_eq!(DmaPort::slot_stride(), 128);
+        assert_eq!(DmaPort::header_len(), 64);
+        assert_eq!(DmaPort::segment_len(), 64 + 4 * 128);
+        assert_eq!(AlignedQueueingPort::<128, 4, 64>::slot_stride(), 128);
+        assert_eq!(AlignedQueueingPort::<100, 4, 1>::slot_stride(), 100);
+
+        let port = DmaPort::new();
+        for index in 0..4 {
+            assert!((port.slot_ptr(index) as usize).is_multiple_of(64), "slot {} misaligned", index);
+        }
+        assert_eq!(port.slot_ptr(1) as usize - port.slot_ptr(0) as usize, 128);
+    }
+
+    #[test]
+    fn byte_usage_counts_padding() {
+        let mut port = DmaPort::new();
+        assert_eq!((port.capacity_bytes(), port.utilization_bytes(), port.fragmentation_ratio()), (576, 0, 1.0));
+        port.enqueue(&[1; 100]).unwrap();
+        port.enqueue_bytes(&[2; 10]).unwrap();
+        assert_eq!(port.utilization_bytes(), 200);
+        assert!((port.fragmentation_ratio() - (1.0 - 200.0 / 576.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn messages_round_trip_across_the_wrap() {
+        let mut port = DmaPort::default();
+        for round in 0..3u8 {
+            for i in 0..4 {
+                port.enqueue(&[round * 4 + i; 100]).unwrap();
+            }
+            assert!(matches!(port.enqueue(&[0; 100]), Err(QueueError::FullBuffer)));
+            for i in 0..4 {
+                assert_eq!(port.dequeue().unwrap(), [round * 4 + i; 100]);
+            }
+            assert!(matches!(port.dequeue(), Err(QueueError::EmptyBuffer)));
+        }
+    }
+
+    #[test]
+    fn masked_and_divided_ports_wrap_alike() {
+        type Masked = AlignedQueueingPort<8, 8, 4>;
+        type Divided = AlignedQueueingPort<8, 6, 4>;
+        assert_eq!((Masked::index_math(), Divided::index_math()), (IndexMath::Mask, IndexMath::Modulo));
+        let (masked, divided) = (Masked::new(), Divided::new());
+        for index in 0..50 {
+            assert_eq!((masked.slot_ptr(index) as usize - masked.slot_ptr(0) as usize) / 8, index % 8);
+            assert_eq!((divided.slot_ptr(index) as usize - divided.slot_ptr(0) as usize) / 8, index % 6);
+        }
+
+        // Messages keep their order as the counters wrap around u32.
+        let mut port = Masked::new();
+        let (enqueued, dequeued) = port.counters();
+        enqueued.store(u32::MAX - 3, Ordering::Relaxed);
+        dequeued.store(u32::MAX - 3, Ordering::Relaxed);
+        for round in 0..4u8 {
+            for i in 0..8 {
+                port.enqueue(&[round * 8 + i; 8]).unwrap();
+            }
+            for i in 0..8 {
+                assert_eq!(port.dequeue().unwrap(), [round * 8 + i; 8]);
+            }
+        }
+
+        // And with six slots, wrapping before 2^32, half full across it.
+        let mut port = Divided::new();
+        let (enqueued, dequeued) = port.counters();
+        let start = (crate::index::counter_lap(6) - 4) as u32;
+        enqueued.store(start, Ordering::Relaxed);
+        dequeued.store(start, Ordering::Relaxed);
+        for i in 0..3 {
+            port.enqueue(&[i; 8]).unwrap();
+        }
+        for i in 3..40u8 {
+            port.enqueue(&[i; 8]).unwrap();
+            assert_eq!(port.len(), 4);
+            assert_eq!(port.dequeue().unwrap(), [i - 3; 8]);
+        }
+        assert!(port.counters().0.load(Ordering::Relaxed) < 40, "wrapped");
+    }
+
+    #[test]
+    fn attach_checks_alignment() {
+        #[repr(C, align(64))]
+        struct Memory([u8; DmaPort::segment_len() + 64]);
+        let mut memory = Box::new(Memory([0; DmaPort::segment_len() + 64]));
+        let base = NonNull::new(memory.0.as_mut_ptr()).unwrap();
+
+        let misaligned = unsafe { base.add(32) };
+        assert!(matches!(unsafe { DmaPort::attach(misaligned) }, Err(PortError::Misaligned)));
+
+        let mut sender = unsafe { DmaPort::attach(base) }.unwrap();
+        let mut receiver = unsafe { DmaPort::attach(base) }.unwrap();
+        sender.enqueue(&[7; 100]).unwrap();
+        assert_eq!(receiver.len(), 1);
+        assert_eq!(receiver.dequeue().unwrap(), [7; 100]);
+        assert_eq!(memory.0[64 + 100], 0, "the padding is never written");
+    }
+}
@@ -0,0 +1,643 @@
+//! Many ports in one named segment.
+//!
+//! Every named port is a mapping of its own, with a file descriptor and
+//! page table entries to go with it. A `SharedMemoryArena` maps one
+//! segment holding `MAX_PORTS` port slots behind an allocation table:
+//!
+//! ```text
+//! 0..4     size_of::<Segment>() of the creating build
+//! 4..8     MAX_PORTS
+//! 8..      MAX_PORTS entries of 16 bytes: state, msg_size, msg_count, zero
+//! ..+4     enqueues and dequeues in progress
+//! ..+4     freezes so far
+//! ..+8     the freeze word, see below
+//! then     MAX_PORTS segments, one after the other
+//! ```
+//!
+//! An entry's state is 0 while its slot is free, 1 while a process sets the
+//! slot up and 2 once the segment in it is a port. `arena_alloc` takes the
+//! first free slot with a compare-and-swap, so processes sharing an arena
+//! may allocate at the same time. Each slot holds a whole `Segment`, of
+//! the build's geometry; the message size and count asked for are checked
+//! against it and recorded for the other processes, who join the port of
+//! a slot with `arena_attach`.
+//!
+//! A port lives in the arena's mapping, which it keeps mapped for as long
+//! as it lives. `arena_free` gives the slot back; the next port to take it
+//! gets a new `segment_generation`, so handles that still point there fail
+//! with `QueueError::StaleSegment`.
+//!
+//! `freeze_epoch` snapshots every port of the arena at one moment, so the
+//! totals of ports that messages move between add up. It raises the freeze
+//! word, with the tick of the arena's clock it ends at, waits for the
+//! enqueues and dequeues already under way to finish, snapshots the ports
+//! with `QueueingPort::snapshot` and lowers the word. Meanwhile an enqueue
+//! or dequeue on any port of the arena, in any process, waits or fails
+//! with `QueueError::Frozen`, as the `FreezePolicy` says; once the end tick
+//! has passed on the port's clock, the port lowers the word itself and goes
+//! on, so a snapshotter that dies mid-freeze holds nothing up for longer.
+//! Every enqueue path is held off, claimed slots at `commit_write` and
+//! forwarding for both ports; a hold taken inside another on the same
+//! arena, as forwarding between two of its ports takes, goes on at once,
+//! the freezer already waiting on the outer one. The clocks of all the
+//! processes are assumed to agree, as for the `timing` module.
+
+use alloc::sync::Arc;
+use core::cell::Cell;
+use core::mem::size_of;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use shared_memory::{ShmemConf, ShmemError};
+
+use crate::quota::QuotaCharge;
+use crate::{
+    Clock, ClockRef, Memory, QueueError, QueueingPort, Segment, ShmemBuffer, ShmemHandle, SnapshotBlob, MSGS, SIZE,
+};
+
+/// How many ports an arena holds.
+pub const MAX_PORTS: usize = 16;
+
+const FREE: u32 = 0;
+const CLAIMING: u32 = 1;
+const ALLOCATED: u32 = 2;
+
+/// Set in the freeze word under `FreezePolicy::Fail`; the rest of the word
+/// is the end tick, or 0 while nothing is frozen.
+const FAIL: u64 = 1;
+
+/// An entry of the allocation table.
+#[repr(C)]
+struct PortSlot {
+    state: AtomicU32,
+    msg_size: AtomicU32,
+    msg_count: AtomicU32,
+    _reserved: AtomicU32,
+}
+
+#[repr(C)]
+struct ArenaTable {
+    segment_len: AtomicU32,
+    port_count: AtomicU32,
+    slots: [PortSlot; MAX_PORTS],
+    /// Enqueues and dequeues between their freeze check and their end.
+    active: AtomicU32,
+    epoch: AtomicU32,
+    freeze: AtomicU64,
+}
+
+const ARENA_LEN: usize = size_of::<ArenaTable>() + MAX_PORTS * size_of::<Segment>();
+
+// The segments follow the table, and must be aligned as it leaves them.
+const _: () = assert!(size_of::<ArenaTable>().is_multiple_of(core::mem::align_of::<Segment>()));
+
+#[derive(Debug)]
+pub enum ArenaError {
+    Shmem(ShmemError),
+    /// The arena was made by a build whose segments or table are laid out
+    /// otherwise.
+    LayoutMismatch,
+    /// Creating the arena would take the process past its `ShmemQuota`.
+    QuotaExceeded,
+    /// Every slot holds a port.
+    Full,
+    /// The slots are made for at most `SIZE` bytes and `MSGS` messages.
+    Geometry { msg_size: usize, msg_count: usize },
+    /// Slot `index` holds no port.
+    NoSuchPort { index: usize },
+    /// The port does not live in this arena.
+    NotInArena,
+    /// Another `freeze_epoch` holds the arena.
+    Frozen,
+    /// The freeze reached its end tick before the snapshots were taken, so
+    /// they were not.
+    FreezeExpired,
+}
+
+/// What an enqueue or dequeue does on a port of a frozen arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreezePolicy {
+    /// Polls until the freeze ends, pausing on the port's clock.
+    Wait,
+    /// Fails with `QueueError::Frozen`.
+    Fail,
+}
+
+/// The snapshots of one `freeze_epoch`.
+#[derive(Debug, Clone)]
+pub struct EpochSnapshot {
+    /// Counts the freezes of the arena, from 1.
+    pub epoch: u32,
+    /// The slot and snapshot, with statistics, of every port.
+    pub ports: Vec<(usize, SnapshotBlob)>,
+}
+
+/// Ports in one shared segment, see the module documentation.
+pub struct SharedMemoryArena {
+    mapping: Arc<ShmemHandle>,
+    table: NonNull<ArenaTable>,
+    /// For freezes, and given to the ports; `None` for the default clock.
+    clock: Option<ClockRef>,
+}
+
+// The table is only accessed through its atomics.
+unsafe impl Send for SharedMemoryArena {}
+
+impl SharedMemoryArena {
+    /// Creates the arena `name`, every slot free.
+    pub fn create(name: &str) -> Result<SharedMemoryArena, ArenaError> {
+        let quota = QuotaCharge::reserve(ARENA_LEN).map_err(|_| ArenaError::QuotaExceeded)?;
+        let shmem = ShmemConf::new().size(ARENA_LEN).os_id(name).create().map_err(ArenaError::Shmem)?;
+        let arena = SharedMemoryArena::new(ShmemHandle { shmem, _quota: Some(quota), _open: None, attach: None, creator: true });
+        let table = arena.table();
+        table.port_count.store(MAX_PORTS as u32, Ordering::Relaxed);
+        // Last, as `open` checks it first.
+        table.segment_len.store(size_of::<Segment>() as u32, Ordering::Release);
+        Ok(arena)
+    }
+
+    /// Maps the arena `name` made by another process's `create`.
+    pub fn open(name: &str) -> Result<SharedMemoryArena, ArenaError> {
+        let shmem = ShmemConf::new().os_id(name).open().map_err(ArenaError::Shmem)?;
+        if shmem.len() < ARENA_LEN {
+            return Err(ArenaError::LayoutMismatch);
+        }
+        let arena = SharedMemoryArena::new(ShmemHandle { shmem, _quota: None, _open: None, attach: None, creator: false });
+        let table = arena.table();
+        if table.segment_len.load(Ordering::Acquire) as usize != size_of::<Segment>()
+            || table.port_count.load(Ordering::Relaxed) as usize != MAX_PORTS
+        {
+            return Err(ArenaError::LayoutMismatch);
+        }
+        Ok(arena)
+    }
+
+    fn new(handle: ShmemHandle) -> SharedMemoryArena {
+        // Mapped, and page aligned.
+        let table = unsafe { NonNull::new_unchecked(handle.shmem.as_ptr()) }.cast::<ArenaTable>();
+        SharedMemoryArena { mapping: Arc::new(handle), table, clock: None }
+    }
+
+    /// Sets the clock freezes are timed with, which the ports the arena
+    /// hands out from now on get, too.
+    pub fn set_clock(&mut self, clock: ClockRef) {
+        self.clock = Some(clock);
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        match &self.clock {
+            Some(clock) => &**clock,
+            None => &crate::clock::DEFAULT_CLOCK,
+        }
+    }
+
+    fn table(&self) -> &ArenaTable {
+        // At the start of the mapping, which lives as long as `self`.
+        unsafe { self.table.as_ref() }
+    }
+
+    fn segment(&self, index: usize) -> NonNull<Segment> {
+        let offset = size_of::<ArenaTable>() + index * size_of::<Segment>();
+        // Inside the `ARENA_LEN` bytes of the mapping.
+        unsafe { NonNull::new_unchecked(self.table.as_ptr().cast::<u8>().add(offset)) }.cast()
+    }
+
+    fn port(&self, index: usize) -> QueueingPort {
+        let buffer = ShmemBuffer::new(self.segment(index));
+        let mut port = QueueingPort::from_memory(Memory::Arena { buffer, mapping: Arc::clone(&self.mapping) });
+        if let Some(clock) = &self.clock {
+            port.set_clock(ClockRef::clone(clock));
+        }
+        port
+    }
+
+    /// Sets up a port in the first free slot, for messages of up to
+    /// `msg_size` bytes and up to `msg_count` queued at once. Fails with
+    /// `ArenaError::Geometry` for more than a slot holds, and with
+    /// `ArenaError::Full` if no slot is free.
+    pub fn arena_alloc(&mut self, msg_size: usize, msg_count: usize) -> Result<QueueingPort, ArenaError> {
+        if msg_size > SIZE || msg_count > MSGS {
+            return Err(ArenaError::Geometry { msg_size, msg_count });
+        }
+        let table = self.table();
+        let index = (0..MAX_PORTS)
+            .find(|&index| {
+                let state = &table.slots[index].state;
+                state.compare_exchange(FREE, CLAIMING, Ordering::Acquire, Ordering::Relaxed).is_ok()
+            })
+            .ok_or(ArenaError::Full)?;
+        let segment = self.segment(index);
+        // Claimed, so no port uses the segment.
+        let previous = unsafe {
+            let header = &segment.as_ref().header;
+            let previous = header.segment_generation.load(header.byte_order(), Ordering::Relaxed);
+            segment.as_ptr().write(Segment::new());
+            previous
+        };
+        let mut port = self.port(index);
+        // Stamped over the old generation, so the new one differs from it.
+        let header = &port.segment().header;
+        header.segment_generation.store(header.byte_order(), previous, Ordering::Relaxed);
+        port.stamp_generation();
+        let slot = &table.slots[index];
+        slot.msg_size.store(msg_size as u32, Ordering::Relaxed);
+        slot.msg_count.store(msg_count as u32, Ordering::Relaxed);
+        slot.state.store(ALLOCATED, Ordering::Release);
+        Ok(port)
+    }
+
+    /// Gives the slot of `port`, a handle from this `SharedMemoryArena`,
+    /// back to the arena. Other handles on the port, in this process or
+    /// another, must not be used any more.
+    pub fn arena_free(&mut self, port: QueueingPort) -> Result<(), ArenaError> {
+        let index = self.port_slot(&port).ok_or(ArenaError::NotInArena)?;
+        drop(port);
+        self.table().slots[index].state.store(FREE, Ordering::Release);
+        Ok(())
+    }
+
+    /// Another handle on the port in slot `index`, for its other end.
+    pub fn arena_attach(&self, index: usize) -> Result<QueueingPort, ArenaError> {
+        match self.table().slots.get(index) {
+            Some(slot) if slot.state.load(Ordering::Acquire) == ALLOCATED => Ok(self.port(index)),
+            _ => Err(ArenaError::NoSuchPort { index }),
+        }
+    }
+
+    /// The slot `port` lives in, if it lives in this arena and the slot
+    /// holds a port.
+    pub fn port_slot(&self, port: &QueueingPort) -> Option<usize> {
+        let at = port.segment() as *const Segment as usize;
+        let first = self.segment(0).as_ptr() as usize;
+        let index = at.checked_sub(first)? / size_of::<Segment>();
+        let allocated = |index: usize| self.table().slots[index].state.load(Ordering::Acquire) == ALLOCATED;
+        (index < MAX_PORTS && self.segment(index).as_ptr() as usize == at && allocated(index)).then_some(index)
+    }
+
+    /// The message size and count slot `index` was allocated for.
+    pub fn geometry(&self, index: usize) -> Option<(usize, usize)> {
+        let slot = self.table().slots.get(index)?;
+        (slot.state.load(Ordering::Acquire) == ALLOCATED)
+            .then(|| (slot.msg_size.load(Ordering::Relaxed) as usize, slot.msg_count.load(Ordering::Relaxed) as usize))
+    }
+
+    /// How many slots hold a port.
+    pub fn allocated(&self) -> usize {
+        self.table().slots.iter().filter(|slot| slot.state.load(Ordering::Acquire) == ALLOCATED).count()
+    }
+
+    /// Snapshots every port at one moment, see the module documentation.
+    /// The freeze lasts `max_ticks` of the arena's clock at most; fails
+    /// with `ArenaError::FreezeExpired` if that was not long enough, and
+    /// with `ArenaError::Frozen` while another freeze holds the arena.
+    pub fn freeze_epoch(&self, policy: FreezePolicy, max_ticks: u64) -> Result<EpochSnapshot, ArenaError> {
+        let (epoch, word) = self.begin_freeze(policy, max_ticks)?;
+        let table = self.table();
+        let clock = self.clock();
+        let thaw_at = word & !FAIL;
+        while table.active.load(Ordering::SeqCst) != 0 {
+            if clock.now_ns() >= thaw_at {
+                self.end_freeze(word);
+                return Err(ArenaError::FreezeExpired);
+            }
+            clock.pause(thaw_at);
+        }
+        let ports = (0..MAX_PORTS)
+            .filter(|&index| table.slots[index].state.load(Ordering::Acquire) == ALLOCATED)
+            .map(|index| (index, self.port(index).snapshot(true)))
+            .collect();
+        // A port that found the end tick passed may have gone on already.
+        let in_time = clock.now_ns() < thaw_at;
+        if !self.end_freeze(word) || !in_time {
+            return Err(ArenaError::FreezeExpired);
+        }
+        Ok(EpochSnapshot { epoch, ports })
+    }
+
+    /// Whether a freeze holds the arena now.
+    pub fn is_frozen(&self) -> bool {
+        let word = self.table().freeze.load(Ordering::Acquire);
+        word != 0 && self.clock().now_ns() < word & !FAIL
+    }
+
+    /// Raises the freeze word, returning the epoch and the word.
+    fn begin_freeze(&self, policy: FreezePolicy, max_ticks: u64) -> Result<(u32, u64), ArenaError> {
+        let table = self.table();
+        let now = self.clock().now_ns();
+        let current = table.freeze.load(Ordering::Acquire);
+        if current != 0 && now < current & !FAIL {
+            return Err(ArenaError::Frozen);
+        }
+        // Never 0, whatever the clock reads.
+        let thaw_at = now.saturating_add(max_ticks).max(2) & !FAIL;
+        let word = thaw_at | if policy == FreezePolicy::Fail { FAIL } else { 0 };
+        // Sequentially consistent with the `active` count, see
+        // `hold_off_freeze`. A freeze that ran out is taken over.
+        table
+            .freeze
+            .compare_exchange(current, word, Ordering::SeqCst, Ordering::Relaxed)
+            .map_err(|_| ArenaError::Frozen)?;
+        Ok((table.epoch.fetch_add(1, Ordering::Relaxed).wrapping_add(1), word))
+    }
+
+    /// Lowers the freeze word if it is still `word`, returning whether it
+    /// was.
+    fn end_freeze(&self, word: u64) -> bool {
+        self.table().freeze.compare_exchange(word, 0, Ordering::Release, Ordering::Relaxed).is_ok()
+    }
+}
+
+std::thread_local! {
+    /// The address of the table this thread's outermost hold is on, or 0.
+    static HOLDING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// An enqueue or dequeue under way on a port of an arena.
+pub(crate) struct FreezeHold<'a> {
+    active: Option<&'a AtomicU32>,
+    outermost: bool,
+}
+
+impl FreezeHold<'_> {
+    fn outside() -> FreezeHold<'static> {
+        FreezeHold { active: None, outermost: false }
+    }
+
+    fn on(table: &ArenaTable) -> FreezeHold<'_> {
+        let address = table as *const ArenaTable as usize;
+        let outermost = HOLDING.with(|holding| {
+            let outermost = holding.get() == 0;
+            if outermost {
+                holding.set(address);
+            }
+            outermost
+        });
+        FreezeHold { active: Some(&table.active), outermost }
+    }
+}
+
+impl Drop for FreezeHold<'_> {
+    fn drop(&mut self) {
+        if let Some(active) = self.active {
+            active.fetch_sub(1, Ordering::Release);
+        }
+        if self.outermost {
+            HOLDING.with(|holding| holding.set(0));
+        }
+    }
+}
+
+impl QueueingPort {
+    /// Counts an enqueue or dequeue in for `freeze_epoch` to wait on, first
+    /// waiting out or failing on a freeze of the port's arena; see the
+    /// module documentation. Ports outside an arena go on at once.
+    pub(crate) fn hold_off_freeze(&self) -> Result<FreezeHold<'_>, QueueError> {
+        let Memory::Arena { mapping, .. } = &self.memory else {
+            return Ok(FreezeHold::outside());
+        };
+        // The table starts the mapping, which the port keeps alive.
+        let table = unsafe { &*mapping.shmem.as_ptr().cast::<ArenaTable>() };
+        if HOLDING.with(Cell::get) == table as *const ArenaTable as usize {
+            // Within a hold on this arena, which any freezer waits on.
+            table.active.fetch_add(1, Ordering::SeqCst);
+            return Ok(FreezeHold::on(table));
+        }
+        loop {
+            // Counted in before the freeze word is read, and the freezer
+            // raises it before reading the count: one of the two sees the
+            // other.
+            table.active.fetch_add(1, Ordering::SeqCst);
+            let word = table.freeze.load(Ordering::SeqCst);
+            let thaw_at = word & !FAIL;
+            if word == 0 {
+                return Ok(FreezeHold::on(table));
+            }
+            if self.clock().now_ns() >= thaw_at {
+                // The freezer ran out of time, or died.
+                let _ = table.freeze.compare_exchange(word, 0, Ordering::AcqRel, Ordering::Relaxed);
+                return Ok(FreezeHold::on(table));
+            }
+            table.active.fetch_sub(1, Ordering::Release);
+            if word & FAIL != 0 {
+                return Err(QueueError::Frozen);
+            }
+            self.clock().pause(thaw_at);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, MockClock};
+    use std::thread;
+    use std::time::Duration;
+
+    fn arena_name(test: &str) -> String {
+        format!("/qp_arena_{}_{}", test, std::process::id())
+    }
+
+    fn numbered(port: u8, n: u32) -> Message {
+        let mut bytes = [port; SIZE];
+        bytes[..4].copy_from_slice(&n.to_le_bytes());
+        Message(bytes)
+    }
+
+    #[test]
+    fn four_ports_exchange_messages_at_once() {
+        const COUNT: u32 = 2_000;
+        let mut arena = SharedMemoryArena::create(&arena_name("four")).unwrap();
+        let peer = SharedMemoryArena::open(&arena_name("four")).unwrap();
+        let senders: Vec<_> = (0..4).map(|_| arena.arena_alloc(SIZE, MSGS).unwrap()).collect();
+        let receivers: Vec<_> =
+            senders.iter().map(|port| peer.arena_attach(arena.port_slot(port).unwrap()).unwrap()).collect();
+        assert_eq!(arena.allocated(), 4);
+
+        let threads: Vec<_> = senders
+            .into_iter()
+            .zip(receivers)
+            .enumerate()
+            .flat_map(|(tag, (mut sender, mut receiver))| {
+                let tag = tag as u8;
+                let send = std::thread::spawn(move || {
+                    for n in 0..COUNT {
+                        while let Err(QueueError::FullBuffer) = sender.enqueue(numbered(tag, n)) {
+                            std::thread::yield_now();
+                        }
+                    }
+                    sender
+                });
+                let receive = std::thread::spawn(move || {
+                    for n in 0..COUNT {
+                        let message = loop {
+                            match receiver.try_dequeue() {
+                                Ok(message) => break message,
+                                Err(QueueError::EmptyBuffer) => std::thread::yield_now(),
+                                Err(error) => panic!("{:?}", error),
+                            }
+                        };
+                        assert_eq!(message.0, numbered(tag, n).0, "port {}", tag);
+                    }
+                    receiver
+                });
+                [send, receive]
+            })
+            .collect();
+        let ports: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        for port in &ports {
+            let stats = port.stats();
+            assert_eq!((stats.enqueued, stats.dequeued, port.len()), (COUNT, COUNT, 0));
+        }
+        // Senders and receivers alternate; the senders came from `arena_alloc`.
+        for port in ports.into_iter().step_by(2) {
+            arena.arena_free(port).unwrap();
+        }
+        assert_eq!(arena.allocated(), 0);
+    }
+
+    #[test]
+    fn slots_are_allocated_and_given_back() {
+        let mut arena = SharedMemoryArena::create(&arena_name("slots")).unwrap();
+        assert!(matches!(arena.arena_alloc(SIZE + 1, 1), Err(ArenaError::Geometry { msg_size: 257, msg_count: 1 })));
+        let mut ports: Vec<_> = (0..MAX_PORTS).map(|n| arena.arena_alloc(64, n % MSGS + 1).unwrap()).collect();
+        assert!(matches!(arena.arena_alloc(64, 1), Err(ArenaError::Full)));
+        assert_eq!(arena.geometry(3), Some((64, 4)));
+
+        let mut freed = ports.remove(3);
+        freed.enqueue(numbered(3, 0)).unwrap();
+        let mut stale = arena.arena_attach(3).unwrap();
+        arena.arena_free(freed).unwrap();
+        assert!(matches!(arena.arena_attach(3), Err(ArenaError::NoSuchPort { index: 3 })));
+        assert_eq!((arena.allocated(), arena.geometry(3)), (MAX_PORTS - 1, None));
+
+        let mut reused = arena.arena_alloc(SIZE, MSGS).unwrap();
+        assert_eq!(arena.port_slot(&reused), Some(3));
+        assert!(reused.is_empty(), "a fresh port");
+        assert!(matches!(stale.try_dequeue(), Err(QueueError::StaleSegment)));
+        assert!(matches!(arena.arena_free(QueueingPort::new()), Err(ArenaError::NotInArena)));
+        assert!(matches!(arena.arena_attach(MAX_PORTS), Err(ArenaError::NoSuchPort { .. })));
+        reused.enqueue(numbered(3, 1)).unwrap();
+        assert_eq!(arena.arena_attach(3).unwrap().dequeue().unwrap().0, numbered(3, 1).0);
+    }
+
+    /// The enqueued and dequeued counts and the length in a port's snapshot.
+    fn totals(blob: &SnapshotBlob) -> (u32, u32, usize) {
+        let port = QueueingPort::restore(blob.as_bytes()).unwrap();
+        let stats = port.stats();
+        (stats.enqueued, stats.dequeued, port.len())
+    }
+
+    #[test]
+    fn frozen_snapshots_add_up_across_ports() {
+        const COUNT: u32 = 20_000;
+        let mut arena = SharedMemoryArena::create(&arena_name("epoch")).unwrap();
+        let mut source = arena.arena_alloc(SIZE, MSGS).unwrap();
+        let mut forward_in = arena.arena_attach(0).unwrap();
+        let mut forward_out = arena.arena_alloc(SIZE, MSGS).unwrap();
+        let mut sink = arena.arena_attach(1).unwrap();
+        let threads = [
+            thread::spawn(move || {
+                for n in 0..COUNT {
+                    while source.enqueue(numbered(0, n)).is_err() {
+                        thread::yield_now();
+                    }
+                }
+            }),
+            thread::spawn(move || {
+                for _ in 0..COUNT {
+                    let message = loop {
+                        match forward_in.try_dequeue() {
+                            Ok(message) => break message,
+                            Err(_) => thread::yield_now(),
+                        }
+                    };
+                    while forward_out.enqueue(Message(message.0)).is_err() {
+                        thread::yield_now();
+                    }
+                }
+            }),
+            thread::spawn(move || {
+                for _ in 0..COUNT {
+                    while sink.try_dequeue().is_err() {
+                        thread::yield_now();
+                    }
+                }
+            }),
+        ];
+
+        let mut epochs = 0;
+        while threads.iter().any(|thread| !thread.is_finished()) {
+            let snapshot = arena.freeze_epoch(FreezePolicy::Wait, 1_000_000_000).unwrap();
+            epochs += 1;
+            assert_eq!(snapshot.epoch, epochs);
+            let [(0, first), (1, second)] = &snapshot.ports[..] else { panic!("{:?}", snapshot.ports) };
+            let (first_in, first_out, first_len) = totals(first);
+            let (second_in, second_out, second_len) = totals(second);
+            assert_eq!(first_in - first_out, first_len as u32);
+            assert_eq!(second_in - second_out, second_len as u32);
+            // Only the one message in the forwarder's hands is in neither.
+            assert!(first_out - second_in <= 1, "{} dequeued, {} forwarded", first_out, second_in);
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(epochs > 0);
+    }
+
+    #[test]
+    fn a_dead_snapshotter_holds_ports_up_for_a_bounded_time() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut arena = SharedMemoryArena::create(&arena_name("dead")).unwrap();
+        arena.set_clock(clock.clone());
+        let mut port = arena.arena_alloc(SIZE, MSGS).unwrap();
+
+        // Frozen and never thawed, as by a process killed mid-snapshot.
+        arena.begin_freeze(FreezePolicy::Fail, 500).unwrap();
+        assert!(arena.is_frozen());
+        assert!(matches!(port.enqueue(numbered(0, 0)), Err(QueueError::Frozen)));
+        assert!(matches!(port.try_dequeue(), Err(QueueError::Frozen)));
+        assert!(matches!(arena.freeze_epoch(FreezePolicy::Fail, 500), Err(ArenaError::Frozen)));
+        clock.advance(500);
+        port.enqueue(numbered(0, 0)).unwrap();
+        assert!(!arena.is_frozen(), "released by the port");
+
+        // Under the waiting policy the port polls until the end tick.
+        arena.begin_freeze(FreezePolicy::Wait, 500).unwrap();
+        let waiter = thread::spawn(move || port.enqueue(numbered(0, 1)).map(|()| port));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        clock.advance(500);
+        let port = waiter.join().unwrap().unwrap();
+        assert_eq!(port.len(), 2);
+
+        let snapshot = arena.freeze_epoch(FreezePolicy::Fail, 500).unwrap();
+        assert_eq!(snapshot.epoch, 3);
+        assert_eq!(totals(&snapshot.ports[0].1), (2, 0, 2));
+    }
+
+    #[test]
+    fn claims_and_forwarding_are_held_off_too() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut arena = SharedMemoryArena::create(&arena_name("paths")).unwrap();
+        arena.set_clock(clock.clone());
+        let mut first = arena.arena_alloc(SIZE, MSGS).unwrap();
+        let mut second = arena.arena_alloc(SIZE, MSGS).unwrap();
+        first.enqueue(numbered(0, 0)).unwrap();
+        second.claim_write_slice().unwrap().fill(1);
+
+        arena.begin_freeze(FreezePolicy::Fail, 500).unwrap();
+        assert!(matches!(first.forward_with(&mut second, |_| SIZE), Err(QueueError::Frozen)));
+        assert!(matches!(second.commit_write(), Err(QueueError::Frozen)));
+        assert_eq!((first.len(), second.len()), (1, 0));
+        assert_eq!(arena.table().active.load(Ordering::Relaxed), 0);
+        clock.advance(500);
+
+        assert_eq!(second.claim_write_slice().unwrap(), &[1; SIZE], "left as claimed");
+        second.commit_write().unwrap();
+        // Holding both ports of the one arena at once.
+        first.forward_with(&mut second, |_| SIZE).unwrap();
+        assert_eq!(arena.table().active.load(Ordering::Relaxed), 0);
+        assert_eq!(second.len(), 2);
+        arena.freeze_epoch(FreezePolicy::Fail, 500).unwrap();
+    }
+}
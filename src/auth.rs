@@ -0,0 +1,155 @@
+//! Signing messages, for integrity without secrecy.
+//!
+//! An `AuthenticatedPort` wraps another port. Its `enqueue` fills the
+//! last `AUTH_MAC_LEN` bytes of every message with the HMAC-SHA256, under
+//! the port's 32-byte key, of the `AUTH_PAYLOAD` bytes before them; its
+//! `dequeue` recomputes it and fails with `QueueError::AuthFailed` if the
+//! message does not match. The inner port's slots are no larger than any
+//! other's, so the MAC takes the end of the message rather than following
+//! it: `enqueue` fails with `QueueError::MessageTooLarge` for a message
+//! with anything but zeros there, instead of overwriting it, and
+//! `enqueue_payload` takes just the `AUTH_PAYLOAD` bytes. Both ends need the same key. Payloads go in
+//! the clear, so anyone who can read the segment reads them too, but no
+//! one without the key can change a message or put a new one in unnoticed.
+//!
+//! The MAC does not cover the message's place in the queue: a process
+//! that can write the segment may still drop, repeat or reorder signed
+//! messages. A sequence number in the payload, checked by the receiver,
+//! tells.
+//!
+//! The tags are compared in constant time. A message that fails is
+//! consumed all the same, so the next `dequeue` goes on with the message
+//! after it.
+
+// TODO: use the `hmac` and `sha2` crates in place of `sha256` once they can
+// be dependencies; it stands in for them until then.
+use crate::sha256::{hmac_sha256, DIGEST_LEN};
+use crate::{DequeuePort, EnqueuePort, Message, QueueError, SIZE};
+
+/// Bytes at the end of a message taken by its MAC.
+pub const AUTH_MAC_LEN: usize = DIGEST_LEN;
+/// Bytes the MAC covers.
+pub const AUTH_PAYLOAD: usize = SIZE - AUTH_MAC_LEN;
+
+/// A port whose messages are signed, see the module documentation.
+pub struct AuthenticatedPort<P> {
+    port: P,
+    key: [u8; 32],
+}
+
+impl<P> AuthenticatedPort<P> {
+    pub fn new(inner: P, key: [u8; 32]) -> AuthenticatedPort<P> {
+        AuthenticatedPort { port: inner, key }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+
+    fn mac(&self, message: &Message) -> [u8; AUTH_MAC_LEN] {
+        hmac_sha256(&self.key, &message.0[..AUTH_PAYLOAD])
+    }
+}
+
+impl<P: EnqueuePort> AuthenticatedPort<P> {
+    /// Signs `payload` and enqueues it with the MAC after it.
+    pub fn enqueue_payload(&mut self, payload: &[u8; AUTH_PAYLOAD]) -> Result<(), QueueError> {
+        let mut message = Message([0; SIZE]);
+        message.0[..AUTH_PAYLOAD].copy_from_slice(payload);
+        self.enqueue(message)
+    }
+}
+
+impl<P: EnqueuePort> EnqueuePort for AuthenticatedPort<P> {
+    /// Signs `message` into its last `AUTH_MAC_LEN` bytes, which must be
+    /// zero, and enqueues it on the inner port. Fails with
+    /// `QueueError::MessageTooLarge`, giving the length up to the last
+    /// non-zero byte, if they are not.
+    fn enqueue(&mut self, mut message: Message) -> Result<(), QueueError> {
+        if let Some(last) = message.0[AUTH_PAYLOAD..].iter().rposition(|&byte| byte != 0) {
+            return Err(QueueError::MessageTooLarge { len: AUTH_PAYLOAD + last + 1 });
+        }
+        let mac = self.mac(&message);
+        message.0[AUTH_PAYLOAD..].copy_from_slice(&mac);
+        self.port.enqueue(message)
+    }
+}
+
+impl<P: DequeuePort> DequeuePort for AuthenticatedPort<P> {
+    /// Dequeues from the inner port and checks the MAC; errors from the
+    /// inner port are passed on.
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        let message = self.port.dequeue()?;
+        let expected = self.mac(&message);
+        let difference = expected.iter().zip(&message.0[AUTH_PAYLOAD..]).fold(0, |acc, (a, b)| acc | (a ^ b));
+        // Opaque to the optimizer, which could otherwise stop the fold at
+        // the first difference.
+        if core::hint::black_box(difference) != 0 {
+            return Err(QueueError::AuthFailed);
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{QueueingPort, Segment};
+    use core::ptr::NonNull;
+
+    const KEY: [u8; 32] = [0x42; 32];
+
+    #[test]
+    fn signed_messages_pass_and_tampered_ones_fail() {
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut sender = AuthenticatedPort::new(unsafe { QueueingPort::attach(segment) }, KEY);
+        let mut receiver = AuthenticatedPort::new(unsafe { QueueingPort::attach(segment) }, KEY);
+        for tag in 0..3 {
+            sender.enqueue_payload(&[tag; AUTH_PAYLOAD]).unwrap();
+        }
+
+        let first = receiver.dequeue().unwrap();
+        assert_eq!(first.0[..AUTH_PAYLOAD], [0; AUTH_PAYLOAD]);
+        assert_ne!(first.0[AUTH_PAYLOAD..], [0; AUTH_MAC_LEN], "the MAC follows the payload");
+        // One flipped payload bit in the second message, where it waits in
+        // its slot.
+        unsafe { *segment.as_ref().slot(1).add(17) ^= 1 };
+        assert!(matches!(receiver.dequeue(), Err(QueueError::AuthFailed)));
+        assert_eq!(receiver.dequeue().unwrap().0[0], 2, "the bad message was consumed");
+        assert!(matches!(receiver.dequeue(), Err(QueueError::EmptyBuffer)));
+    }
+
+    #[test]
+    fn another_key_fails_every_message() {
+        let mut port = AuthenticatedPort::new(QueueingPort::new(), KEY);
+        port.enqueue_payload(&[7; AUTH_PAYLOAD]).unwrap();
+        let mut plain = port.into_inner();
+        let signed = plain.dequeue().unwrap();
+        plain.enqueue(signed).unwrap();
+        // Unsigned messages, too, fail.
+        plain.enqueue(Message([7; SIZE])).unwrap();
+
+        let mut wrong_key = AuthenticatedPort::new(plain, [0x43; 32]);
+        assert!(matches!(wrong_key.dequeue(), Err(QueueError::AuthFailed)));
+        let mut right_key = AuthenticatedPort::new(wrong_key.into_inner(), KEY);
+        assert!(matches!(right_key.dequeue(), Err(QueueError::AuthFailed)));
+    }
+
+    #[test]
+    fn a_message_using_the_mac_bytes_is_refused() {
+        let mut port = AuthenticatedPort::new(QueueingPort::new(), KEY);
+        let mut message = Message([7; SIZE]);
+        message.0[SIZE - 1] = 0;
+        assert!(matches!(port.enqueue(message), Err(QueueError::MessageTooLarge { len }) if len == SIZE - 1));
+        let mut message = Message([0; SIZE]);
+        message.0[AUTH_PAYLOAD] = 1;
+        assert!(matches!(port.enqueue(message), Err(QueueError::MessageTooLarge { len }) if len == AUTH_PAYLOAD + 1));
+        assert!(port.into_inner().is_empty(), "nothing was queued");
+
+        let mut port = AuthenticatedPort::new(QueueingPort::new(), KEY);
+        let mut message = Message([0; SIZE]);
+        message.0[..AUTH_PAYLOAD].fill(9);
+        port.enqueue(message).unwrap();
+        assert_eq!(port.dequeue().unwrap().0[..AUTH_PAYLOAD], [9; AUTH_PAYLOAD]);
+    }
+}
@@ -0,0 +1,208 @@
+//! Enqueueing many messages with one hand-off to the reader.
+
+use core::sync::atomic::Ordering;
+
+use crate::{trace, Message, QueueingPort, MSGS, SIZE};
+
+impl QueueingPort {
+    /// Enqueues messages from `iter` until it ends or the queue is full or
+    /// out of credits, returning how many were enqueued and the first one
+    /// that did not fit, or that the validator refused. Where `enqueue`
+    /// would fail whatever the message, on a closed port for one, nothing
+    /// is enqueued and the first message is returned.
+    ///
+    /// The iterator is not advanced past that message, so nothing is lost:
+    /// pass `iter.by_ref()` to continue with the rest later. Messages are
+    /// handed to the reader in batches, one `message_count` update per run
+    /// of free slots.
+    pub fn enqueue_from_iter<I: Iterator<Item = Message>>(&mut self, iter: I) -> (usize, Option<Message>) {
+        self.enqueue_batch(iter, |message, slot| {
+            *slot = message.0;
+            true
+        })
+    }
+
+    /// Enqueues messages from `iter` until it ends or the queue is full,
+    /// returning how many were enqueued. Unlike `enqueue_from_iter`, the
+    /// first message that did not fit is dropped with the rest.
+    pub fn extend(&mut self, iter: impl IntoIterator<Item = Message>) -> usize {
+        self.enqueue_from_iter(iter.into_iter()).0
+    }
+
+    /// An in-process port holding the first `capacity` messages of `iter`,
+    /// or as many as fit in `capacity()`.
+    pub fn from_iter(iter: impl IntoIterator<Item = Message>, capacity: usize) -> QueueingPort {
+        let mut port = QueueingPort::new();
+        port.extend(iter.into_iter().take(capacity));
+        port
+    }
+
+    /// Enqueues `bytes` as one message, zero-filled up to `SIZE`. On a port
+    /// with `WireFeatures::COMPRESSION`, the message carries its length and
+    /// is compressed where that helps; see the `compress` module.
+    pub fn enqueue_bytes(&mut self, bytes: &[u8]) -> Result<(), crate::QueueError> {
+        #[cfg(feature = "compress")]
+        if self.wire_features().contains(crate::WireFeatures::COMPRESSION) {
+            return self.enqueue_compressed(bytes);
+        }
+        self.enqueue_vectored(&[bytes])
+    }
+
+    /// Like `enqueue_from_iter`, with each item the bytes of one message as
+    /// for `enqueue_bytes`. Also stops at, and returns, an item longer than
+    /// `SIZE`.
+    pub fn enqueue_from_slices<'a, I: Iterator<Item = &'a [u8]>>(&mut self, iter: I) -> (usize, Option<&'a [u8]>) {
+        self.enqueue_batch(iter, |bytes, slot| {
+            if bytes.len() > SIZE {
+                return false;
+            }
+            slot[..bytes.len()].copy_from_slice(bytes);
+            slot[bytes.len()..].fill(0);
+            true
+        })
+    }
+
+    fn enqueue_batch<T>(
+        &mut self,
+        mut items: impl Iterator<Item = T>,
+        // Returns false when the item cannot be written.
+        mut write: impl FnMut(&T, &mut [u8; SIZE]) -> bool,
+    ) -> (usize, Option<T>) {
+        let mut result = (0, None);
+        // The span reports one enqueue for the whole batch.
+        let _ = trace::enqueue(self, |port| {
+            let segment = port.segment();
+            let header = &segment.header;
+            let _hold = match port.admit_enqueue() {
+                Ok(hold) => hold,
+                Err(error) => {
+                    result.1 = items.next();
+                    return Err(error);
+                }
+            };
+
+            let mut available = port.room();
+            let mut pending = 0;
+            // One reading for the whole batch.
+            let enqueue_time = port.enqueue_time();
+            let leftover = loop {
+                let Some(item) = items.next() else { break None };
+                if pending == available {
+                    // The reader may have freed slots or granted credits meanwhile.
+                    segment.publish(pending, enqueue_time, port.clock());
+                    result.0 += pending;
+                    pending = 0;
+                    available = port.room();
+                    if available == 0 {
+                        if port.len() >= MSGS {
+                            header.rejected.fetch_add(header.byte_order(), 1, Ordering::Relaxed);
+                        }
+                        break Some(item);
+                    }
+                }
+                let mut written = false;
+                segment.fill_free_slot(pending, |slot| written = write(&item, slot));
+                if !written || port.validate_written(segment, pending).is_err() {
+                    break Some(item);
+                }
+                pending += 1;
+            };
+            segment.publish(pending, enqueue_time, port.clock());
+            if result.0 + pending > 0 {
+                port.wake_reader();
+            }
+            result.0 += pending;
+            result.1 = leftover;
+            Ok(())
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(range: core::ops::Range<u8>) -> impl Iterator<Item = Message> {
+        range.map(|i| Message([i; SIZE]))
+    }
+
+    #[test]
+    fn longer_iterator_returns_first_unplaced_message() {
+        let mut port = QueueingPort::new();
+        let mut messages = numbered(0..15);
+        let (placed, leftover) = port.enqueue_from_iter(messages.by_ref());
+        assert_eq!(placed, MSGS);
+        assert_eq!(leftover.unwrap().0, [10; SIZE]);
+        assert_eq!(messages.next().unwrap().0, [11; SIZE], "not advanced past the leftover");
+
+        for i in 0..MSGS as u8 {
+            assert_eq!(port.dequeue().unwrap().0, [i; SIZE]);
+        }
+        assert_eq!(port.stats().high_watermark, MSGS as u32);
+    }
+
+    #[test]
+    fn extend_fills_to_capacity() {
+        let sentinel = [0x5e; SIZE];
+        let mut port = QueueingPort::new();
+        assert_eq!(port.extend(core::iter::repeat(sentinel).map(Message)), port.capacity());
+        assert_eq!(port.extend(core::iter::repeat(sentinel).map(Message)), 0, "already full");
+        assert_eq!(port.len(), port.capacity());
+        while let Ok(message) = port.try_dequeue() {
+            assert_eq!(message.0, sentinel);
+        }
+
+        let mut port = QueueingPort::from_iter(numbered(0..15), 3);
+        assert_eq!(port.len(), 3);
+        for i in 0..3 {
+            assert_eq!(port.dequeue().unwrap().0, [i; SIZE]);
+        }
+        assert_eq!(QueueingPort::from_iter(numbered(0..15), 100).len(), MSGS);
+    }
+
+    #[test]
+    fn exactly_capacity_returns_none() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([0; SIZE])).unwrap();
+        port.dequeue().unwrap();
+        // Starts at slot 1, so the batch wraps.
+        assert!(matches!(port.enqueue_from_iter(numbered(0..MSGS as u8)), (MSGS, None)));
+        assert_eq!(port.len(), MSGS);
+        assert_eq!(port.check_invariants(), Ok(()));
+        assert_eq!(port.dequeue().unwrap().0, [0; SIZE]);
+    }
+
+    #[test]
+    fn empty_iterator_is_a_noop() {
+        let mut port = QueueingPort::new();
+        assert!(matches!(port.enqueue_from_iter(core::iter::empty()), (0, None)));
+        assert_eq!(port.stats(), Default::default());
+    }
+
+    #[test]
+    fn a_closed_port_takes_no_batch() {
+        let mut port = QueueingPort::new();
+        port.close_with_summary(b"").unwrap();
+        let (placed, leftover) = port.enqueue_from_iter(numbered(0..3));
+        assert_eq!((placed, leftover.map(|message| message.0[0])), (0, Some(0)));
+        assert_eq!(port.extend(numbered(0..3)), 0);
+        let items: [&[u8]; 1] = [b"ab"];
+        assert!(matches!(port.enqueue_from_slices(items.into_iter()), (0, Some(b"ab"))));
+        assert_eq!(port.len(), 1, "only the summary");
+    }
+
+    #[test]
+    fn slices_stop_at_oversized_item() {
+        let mut port = QueueingPort::new();
+        let long = [1; SIZE + 1];
+        let items: [&[u8]; 3] = [b"ab", &long, b"cd"];
+        let (placed, leftover) = port.enqueue_from_slices(items.into_iter());
+        assert_eq!((placed, leftover.map(<[u8]>::len)), (1, Some(SIZE + 1)));
+        let message = port.dequeue().unwrap();
+        assert_eq!(&message.0[..3], b"ab\0");
+
+        port.enqueue_bytes(b"xyz").unwrap();
+        assert_eq!(&port.dequeue().unwrap().0[..3], b"xyz");
+    }
+}
@@ -0,0 +1,188 @@
+//! Enqueue-to-dequeue latency over named ports, between two threads.
+//!
+//! The writer stamps each message with the time it enqueues it; the reader
+//! takes the difference when it dequeues it, and echoes the message back
+//! on a second port, where the writer times the round trip. Up to
+//! `--queue-depth` messages are in flight at once, so a depth above 1
+//! measures latency with a queue behind each message.
+//!
+//! Run with `cargo run --release --bin latency_bench -- --iterations 100000`.
+//! `--msg-size` is how many bytes are written and read per message, at
+//! least the 12 of the stamp and at most `SIZE`.
+
+use std::hint::spin_loop;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+use ring_buffer::{PortError, QueueingPort, MSGS, SIZE};
+
+const STAMP_LEN: usize = 12;
+const OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Options {
+    msg_size: usize,
+    queue_depth: usize,
+    iterations: u32,
+    /// Whether to spin while waiting; on one CPU that holds the other
+    /// thread off for a scheduler tick, so waits yield instead.
+    spin: bool,
+}
+
+impl Options {
+    fn relax(&self) {
+        if self.spin {
+            spin_loop();
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+const USAGE: &str = "usage: latency_bench [--msg-size BYTES] [--queue-depth MESSAGES] [--iterations N]";
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options { msg_size: SIZE, queue_depth: 1, iterations: 10_000, spin: true };
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            value.parse::<u64>().map_err(|_| format!("{} {}: not a number", flag, value))
+        };
+        match flag.as_str() {
+            "--msg-size" => options.msg_size = value()? as usize,
+            "--queue-depth" => options.queue_depth = value()? as usize,
+            "--iterations" => options.iterations = value()? as u32,
+            _ => return Err(format!("unknown argument {}", flag)),
+        }
+    }
+    if !(STAMP_LEN..=SIZE).contains(&options.msg_size) {
+        return Err(format!("--msg-size must be {} to {}", STAMP_LEN, SIZE));
+    }
+    if !(1..=MSGS).contains(&options.queue_depth) {
+        return Err(format!("--queue-depth must be 1 to {}", MSGS));
+    }
+    if options.iterations == 0 {
+        return Err("--iterations must be at least 1".into());
+    }
+    options.spin = thread::available_parallelism().map_or(1, |cpus| cpus.get()) > 1;
+    Ok(options)
+}
+
+/// Opens `name`, waiting for its creator to get there first.
+fn open(name: &str) -> QueueingPort {
+    let deadline = Instant::now() + OPEN_TIMEOUT;
+    loop {
+        match QueueingPort::open(name) {
+            Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+            result => return result.unwrap_or_else(|err| panic!("opening {}: {:?}", name, err)),
+        }
+    }
+}
+
+/// Reads the stamp at the start of a message: the sequence number and the
+/// nanoseconds since `epoch` it was enqueued at.
+fn read_stamp(bytes: &[u8]) -> (u32, u64) {
+    let sequence = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+    (sequence, u64::from_le_bytes(bytes[4..STAMP_LEN].try_into().unwrap()))
+}
+
+fn nanos_since(epoch: Instant) -> u64 {
+    epoch.elapsed().as_nanos() as u64
+}
+
+/// Dequeues every message, recording its latency, and echoes its stamp.
+fn reader(ping_name: &str, pong_name: &str, options: &Options, epoch: Instant) -> Vec<u64> {
+    let mut ping = open(ping_name);
+    let mut pong = QueueingPort::create(pong_name).expect("creating the return port");
+    let mut latencies = Vec::with_capacity(options.iterations as usize);
+    let mut payload = [0; SIZE];
+    for expected in 0..options.iterations {
+        let stamp = loop {
+            if let Ok(stamp) = ping.dequeue_with(|slot| {
+                payload[..options.msg_size].copy_from_slice(&slot[..options.msg_size]);
+                read_stamp(slot)
+            }) {
+                break stamp;
+            }
+            options.relax();
+        };
+        latencies.push(nanos_since(epoch).saturating_sub(stamp.1));
+        assert_eq!(stamp.0, expected, "messages in order");
+        while pong.enqueue_bytes(&payload[..STAMP_LEN]).is_err() {
+            options.relax();
+        }
+    }
+    latencies
+}
+
+/// Sends every message, at most `queue_depth` unanswered, and returns the
+/// round trips.
+fn writer(ping_name: &str, pong_name: &str, options: &Options, epoch: Instant) -> Vec<u64> {
+    let mut ping = QueueingPort::create(ping_name).expect("creating the data port");
+    let mut pong = open(pong_name);
+    let mut round_trips = Vec::with_capacity(options.iterations as usize);
+    let mut payload = vec![0xa5; options.msg_size];
+    let mut sent = 0;
+    while (round_trips.len() as u32) < options.iterations {
+        let in_flight = sent - round_trips.len() as u32;
+        if sent < options.iterations && in_flight < options.queue_depth as u32 {
+            payload[..4].copy_from_slice(&sent.to_le_bytes());
+            payload[4..STAMP_LEN].copy_from_slice(&nanos_since(epoch).to_le_bytes());
+            if ping.enqueue_bytes(&payload).is_ok() {
+                sent += 1;
+            }
+        }
+        if let Ok((_, stamped)) = pong.dequeue_with(|slot| read_stamp(slot)) {
+            round_trips.push(nanos_since(epoch).saturating_sub(stamped));
+        } else if in_flight >= options.queue_depth as u32 {
+            options.relax();
+        }
+    }
+    round_trips
+}
+
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+fn print_row(name: &str, samples: &mut [u64]) {
+    samples.sort_unstable();
+    let mean = samples.iter().sum::<u64>() as f64 / samples.len() as f64;
+    println!(
+        "{:<12} {:>10} {:>10} {:>12.1} {:>10} {:>10} {:>10}",
+        name,
+        samples[0],
+        samples[samples.len() - 1],
+        mean,
+        percentile(samples, 50),
+        percentile(samples, 95),
+        percentile(samples, 99)
+    );
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("latency_bench: {}\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let ping_name = format!("/latency_bench_ping_{}", std::process::id());
+    let pong_name = format!("/latency_bench_pong_{}", std::process::id());
+    let epoch = Instant::now();
+
+    let (mut one_way, mut round_trips) = thread::scope(|scope| {
+        let reader = scope.spawn(|| reader(&ping_name, &pong_name, &options, epoch));
+        let round_trips = writer(&ping_name, &pong_name, &options, epoch);
+        (reader.join().unwrap(), round_trips)
+    });
+
+    println!(
+        "{} messages of {} bytes, up to {} in flight; nanoseconds:",
+        options.iterations, options.msg_size, options.queue_depth
+    );
+    println!("{:<12} {:>10} {:>10} {:>12} {:>10} {:>10} {:>10}", "", "min", "max", "mean", "p50", "p95", "p99");
+    print_row("one way", &mut one_way);
+    print_row("round trip", &mut round_trips);
+    ExitCode::SUCCESS
+}
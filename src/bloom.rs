@@ -0,0 +1,158 @@
+//! Dropping repeated messages with a Bloom filter, on any dequeue port.
+//!
+//! Unlike the exact dedup window of `QueueingPort::set_dedup_window`, the
+//! filter remembers every message since its last reset in 64 bytes, at the
+//! price of false positives: now and then a new message is taken for a
+//! repeat and dropped. Resetting after `capacity` messages keeps the filter
+//! from filling up.
+
+use crate::dedup::fnv1a;
+use crate::{DequeuePort, Message, QueueError};
+
+const WORDS: usize = 8;
+const BITS: u32 = (WORDS * 64) as u32;
+/// Bits set per message.
+const HASHES: u32 = 3;
+
+/// A `DequeuePort` that skips messages it has already returned since the
+/// filter was last reset.
+pub struct DeduplicatingPort<P> {
+    port: P,
+    bits: [u64; WORDS],
+    capacity: usize,
+    passed: usize,
+}
+
+impl<P: DequeuePort> DeduplicatingPort<P> {
+    /// Wraps `port`, resetting the filter after every `capacity` messages
+    /// returned. A `capacity` of 0 is taken as 1.
+    pub fn new(port: P, capacity: usize) -> DeduplicatingPort<P> {
+        DeduplicatingPort {
+            port,
+            bits: [0; WORDS],
+            capacity: capacity.max(1),
+            passed: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+
+    /// Chance that the next new message is taken for a repeat, from how
+    /// many bits are set.
+    pub fn false_positive_estimate(&self) -> f32 {
+        let set: u32 = self.bits.iter().map(|word| word.count_ones()).sum();
+        let fill = set as f32 / BITS as f32;
+        // `powi` needs std.
+        (0..HASHES).fold(1.0, |estimate, _| estimate * fill)
+    }
+
+    /// Sets the message's bits, returning whether all were set already.
+    fn seen_before(&mut self, message: &Message) -> bool {
+        // Multiplying only carries upwards, so the low bits of FNV-1a, that
+        // the probe positions come from, are poorly spread; mix them first.
+        let mut hash = fnv1a(&message.0);
+        hash = (hash ^ (hash >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        // Double hashing: the two halves give every probe position.
+        let (low, high) = (hash as u32, (hash >> 32) as u32 | 1);
+        let mut seen = true;
+        for i in 0..HASHES {
+            let bit = low.wrapping_add(i.wrapping_mul(high)) % BITS;
+            let (word, mask) = ((bit / 64) as usize, 1u64 << (bit % 64));
+            seen &= self.bits[word] & mask != 0;
+            self.bits[word] |= mask;
+        }
+        seen
+    }
+}
+
+impl<P: DequeuePort> DequeuePort for DeduplicatingPort<P> {
+    /// Dequeues from the inner port until a message not seen before turns
+    /// up; errors from the inner port are passed on.
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        loop {
+            let message = self.port.dequeue()?;
+            if self.seen_before(&message) {
+                continue;
+            }
+            self.passed += 1;
+            if self.passed == self.capacity {
+                self.bits = [0; WORDS];
+                self.passed = 0;
+            }
+            return Ok(message);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::testing::InMemoryQueueingPort;
+    use crate::{EnqueuePort, SIZE};
+
+    fn event(id: u32) -> Message {
+        let mut bytes = [0; SIZE];
+        bytes[..4].copy_from_slice(&id.to_le_bytes());
+        Message(bytes)
+    }
+
+    #[test]
+    fn redundant_copies_are_dropped() {
+        let mut inner = InMemoryQueueingPort::new();
+        for id in 0..50 {
+            inner.enqueue(event(id)).unwrap();
+            // Every fifth event also arrives from a second sensor.
+            if id % 5 == 0 {
+                inner.enqueue(event(id)).unwrap();
+            }
+        }
+        assert_eq!(inner.len(), 60);
+
+        let mut port = DeduplicatingPort::new(inner, 64);
+        let received: Vec<u32> = core::iter::from_fn(|| port.dequeue().ok())
+            .map(|message| u32::from_le_bytes(message.0[..4].try_into().unwrap()))
+            .collect();
+        assert_eq!(received, (0..50).collect::<Vec<_>>());
+        assert!(matches!(port.dequeue(), Err(QueueError::EmptyBuffer)));
+    }
+
+    #[test]
+    fn filter_resets_after_capacity() {
+        let mut inner = InMemoryQueueingPort::new();
+        for id in [1, 2, 1, 3, 1] {
+            inner.enqueue(event(id)).unwrap();
+        }
+        let mut port = DeduplicatingPort::new(inner, 2);
+        assert_eq!(port.dequeue().unwrap().0, event(1).0);
+        assert!(port.false_positive_estimate() > 0.0);
+        assert_eq!(port.dequeue().unwrap().0, event(2).0);
+        // Reset after two: the repeat of 1 is new again.
+        assert_eq!(port.false_positive_estimate(), 0.0);
+        assert_eq!(port.dequeue().unwrap().0, event(1).0);
+        assert_eq!(port.dequeue().unwrap().0, event(3).0);
+        assert_eq!(port.dequeue().unwrap().0, event(1).0, "and after the second reset");
+        assert!(port.dequeue().is_err());
+        assert!(port.into_inner().is_empty());
+    }
+
+    #[test]
+    fn estimate_follows_fill() {
+        let mut inner = InMemoryQueueingPort::new();
+        for id in 0..60 {
+            inner.enqueue(event(id)).unwrap();
+        }
+        let mut port = DeduplicatingPort::new(inner, usize::MAX);
+        assert_eq!(port.false_positive_estimate(), 0.0);
+        let mut last = 0.0;
+        while port.dequeue().is_ok() {
+            let estimate = port.false_positive_estimate();
+            assert!(estimate >= last);
+            last = estimate;
+        }
+        // At most 180 of 512 bits set: (180 / 512)^3 is about 0.04.
+        assert!(last > 0.005 && last < 0.05, "{}", last);
+    }
+}
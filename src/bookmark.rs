@@ -0,0 +1,134 @@
+//! Scanning queued messages without consuming them, then consuming up to
+//! where the scan got.
+//!
+//! A `Bookmark` is a position in the queue: the sequence number of the
+//! message there, its `epoch`, and the slot that message is or will be in.
+//! `QueueingPort::bookmark` marks the oldest message. `iter_from_bookmark`
+//! copies the messages from a bookmark on, oldest first, leaving them
+//! queued, and `BookmarkIter::bookmark` marks where the iterator has got
+//! to. `consume_to_bookmark` then dequeues every message before a bookmark,
+//! as `dequeue_unfiltered` would, so a consumer can scan for a complete
+//! batch and take exactly that.
+//!
+//! A bookmark behind the oldest queued message marks the oldest one
+//! instead: the messages it was ahead of are gone. Iterating reads slots
+//! the reader owns, so as with `dequeue_shared` no other handle may
+//! dequeue from the segment meanwhile.
+
+use core::sync::atomic::Ordering;
+
+use crate::{slot_index, Message, QueueingPort};
+
+/// A position in a port's queue, see the `bookmark` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bookmark {
+    /// The slot of the message at the position.
+    pub slot_index: usize,
+    /// The sequence number of the message at the position.
+    pub epoch: u32,
+}
+
+/// The queued messages from a bookmark on, see
+/// `QueueingPort::iter_from_bookmark`.
+pub struct BookmarkIter<'a> {
+    port: &'a QueueingPort,
+    next: u32,
+}
+
+impl QueueingPort {
+    /// A bookmark at the oldest queued message, or where the next one
+    /// enqueued will be if there is none.
+    pub fn bookmark(&self) -> Bookmark {
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        Bookmark {
+            slot_index: slot_index(header.read_index.load(order, Ordering::Relaxed) as usize),
+            epoch: header.dequeued.load(order, Ordering::Acquire),
+        }
+    }
+
+    /// Copies the messages from `bookmark` on, leaving them queued.
+    pub fn iter_from_bookmark<'a>(&'a self, bookmark: &Bookmark) -> BookmarkIter<'a> {
+        BookmarkIter { port: self, next: self.clamp(bookmark) }
+    }
+
+    /// Dequeues every message before `bookmark`, returning how many.
+    pub fn consume_to_bookmark(&mut self, bookmark: &Bookmark) -> usize {
+        let mut consumed = 0;
+        while self.bookmark().epoch != self.clamp(bookmark) && self.dequeue_unfiltered().is_ok() {
+            consumed += 1;
+        }
+        consumed
+    }
+
+    /// The sequence number `bookmark` marks, moved up to the oldest queued
+    /// message if it is behind it.
+    fn clamp(&self, bookmark: &Bookmark) -> u32 {
+        let oldest = self.bookmark().epoch;
+        let behind = bookmark.epoch.wrapping_sub(oldest) as i32 <= 0;
+        if behind {
+            oldest
+        } else {
+            bookmark.epoch
+        }
+    }
+}
+
+impl BookmarkIter<'_> {
+    /// A bookmark at the message `next` would return.
+    pub fn bookmark(&self) -> Bookmark {
+        let oldest = self.port.bookmark();
+        let ahead = self.next.wrapping_sub(oldest.epoch) as usize;
+        Bookmark { slot_index: slot_index(oldest.slot_index + ahead), epoch: self.next }
+    }
+}
+
+impl Iterator for BookmarkIter<'_> {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        // A message dequeued behind the iterator's back moves it on.
+        self.next = self.port.clamp(&Bookmark { slot_index: 0, epoch: self.next });
+        let message = self.port.dequeue_shared(self.next)?;
+        self.next = self.next.wrapping_add(1);
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MSGS, SIZE};
+
+    #[test]
+    fn scan_then_consume_a_batch() {
+        let mut port = QueueingPort::new();
+        // Wrapped around, so the tenth message is in slot 2.
+        for _ in 0..3 {
+            port.enqueue(Message([0xff; SIZE])).unwrap();
+            port.dequeue().unwrap();
+        }
+        for tag in 0..MSGS as u8 {
+            port.enqueue(Message([tag; SIZE])).unwrap();
+        }
+
+        let start = port.bookmark();
+        assert_eq!(start, Bookmark { slot_index: 3, epoch: 3 });
+        assert_eq!(port.iter_from_bookmark(&start).count(), MSGS, "the scan consumes nothing");
+        assert_eq!(port.len(), MSGS);
+
+        // Scan up to the first message tagged 6.
+        let mut scan = port.iter_from_bookmark(&start);
+        assert_eq!(scan.by_ref().take_while(|message| message.0[0] != 6).count(), 6);
+        let after = scan.bookmark();
+        assert_eq!(after, Bookmark { slot_index: 0, epoch: 10 });
+
+        assert_eq!(port.consume_to_bookmark(&after), 7);
+        assert_eq!(port.dequeue().unwrap().0, [7; SIZE]);
+        let rest: Vec<u8> = port.iter_from_bookmark(&start).map(|message| message.0[0]).collect();
+        assert_eq!(rest, [8, 9], "a stale bookmark starts at the oldest message");
+        assert_eq!(port.consume_to_bookmark(&start), 0);
+        assert_eq!(port.consume_to_bookmark(&Bookmark { slot_index: 0, epoch: 100 }), 2);
+        assert!(port.is_empty());
+    }
+}
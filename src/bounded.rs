@@ -0,0 +1,133 @@
+//! Ports whose depth is limited below their capacity at compile time.
+//!
+//! The limit is part of the type, so a static analysis tool (or a reviewer)
+//! can read the worst-case depth of every queue off the declarations, via
+//! `BoundedQueueingPort::max_depth`. A limit the segment cannot hold does
+//! not compile:
+//!
+//! ```compile_fail
+//! # use ring_buffer::{BoundedQueueingPort, MSGS};
+//! let port = BoundedQueueingPort::<{ MSGS + 1 }>::new();
+//! ```
+
+use crate::{DequeuePort, EnqueuePort, Message, QueueError, QueueingPort, MSGS};
+
+/// A `QueueingPort` that never holds more than `MAX_DEPTH` messages.
+///
+/// Ports have the crate's fixed geometry, `SIZE`-byte messages in `MSGS`
+/// slots, so only the depth is a parameter.
+pub struct BoundedQueueingPort<const MAX_DEPTH: usize> {
+    port: QueueingPort,
+}
+
+impl<const MAX_DEPTH: usize> BoundedQueueingPort<MAX_DEPTH> {
+    pub fn new() -> BoundedQueueingPort<MAX_DEPTH> {
+        BoundedQueueingPort::from_port(QueueingPort::new())
+    }
+
+    /// Limits an existing port, which may already hold more than
+    /// `MAX_DEPTH` messages; enqueueing fails until enough are dequeued.
+    pub fn from_port(port: QueueingPort) -> BoundedQueueingPort<MAX_DEPTH> {
+        const { assert!(MAX_DEPTH <= MSGS, "MAX_DEPTH exceeds the slots of a segment") };
+        BoundedQueueingPort { port }
+    }
+
+    /// The depth limit, for tools that check queue bounds statically.
+    pub const fn max_depth() -> usize {
+        MAX_DEPTH
+    }
+
+    /// Enqueues `message`, failing with `QueueError::DepthExceeded` once
+    /// `MAX_DEPTH` messages are queued, even if slots are free.
+    pub fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        if self.port.len() >= MAX_DEPTH {
+            return Err(QueueError::DepthExceeded);
+        }
+        self.port.enqueue(message)
+    }
+
+    pub fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.port.try_dequeue()
+    }
+
+    pub fn len(&self) -> usize {
+        self.port.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.port.is_empty()
+    }
+
+    /// The underlying port, for reading; enqueueing through it would
+    /// bypass the limit.
+    pub fn port(&self) -> &QueueingPort {
+        &self.port
+    }
+
+    pub fn into_inner(self) -> QueueingPort {
+        self.port
+    }
+}
+
+impl<const MAX_DEPTH: usize> Default for BoundedQueueingPort<MAX_DEPTH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_DEPTH: usize> EnqueuePort for BoundedQueueingPort<MAX_DEPTH> {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        BoundedQueueingPort::enqueue(self, message)
+    }
+}
+
+impl<const MAX_DEPTH: usize> DequeuePort for BoundedQueueingPort<MAX_DEPTH> {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        BoundedQueueingPort::dequeue(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+
+    #[test]
+    fn depth_is_limited_below_capacity() {
+        let mut port = BoundedQueueingPort::<3>::new();
+        assert_eq!(BoundedQueueingPort::<3>::max_depth(), 3);
+        for i in 0..3 {
+            port.enqueue(Message([i; SIZE])).unwrap();
+        }
+        assert!(matches!(port.enqueue(Message([3; SIZE])), Err(QueueError::DepthExceeded)));
+        assert_eq!(port.port().stats().rejected, 0, "the segment was never full");
+
+        port.dequeue().unwrap();
+        port.enqueue(Message([3; SIZE])).unwrap();
+        assert_eq!(port.len(), 3);
+    }
+
+    #[test]
+    fn existing_port_over_the_limit() {
+        let mut inner = QueueingPort::new();
+        for _ in 0..4 {
+            inner.enqueue(Message([0; SIZE])).unwrap();
+        }
+        let mut port = BoundedQueueingPort::<2>::from_port(inner);
+        assert!(matches!(port.enqueue(Message([1; SIZE])), Err(QueueError::DepthExceeded)));
+        port.dequeue().unwrap();
+        port.dequeue().unwrap();
+        port.dequeue().unwrap();
+        port.enqueue(Message([1; SIZE])).unwrap();
+        assert_eq!(port.into_inner().len(), 2);
+    }
+
+    #[test]
+    fn full_depth_behaves_like_the_port() {
+        let mut port = BoundedQueueingPort::<MSGS>::default();
+        for _ in 0..MSGS {
+            port.enqueue(Message([0; SIZE])).unwrap();
+        }
+        assert!(matches!(port.enqueue(Message([0; SIZE])), Err(QueueError::DepthExceeded)));
+    }
+}
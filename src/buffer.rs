@@ -0,0 +1,65 @@
+//! Ports laid out in a caller-provided byte buffer.
+
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+use crate::{DequeuePort, EnqueuePort, Memory, Message, PortError, QueueError, QueueingPort, Segment, ShmemBuffer};
+
+/// A port operating on a borrowed byte buffer, see `QueueingPort::with_buffer`.
+pub struct BufferPort<'a> {
+    port: QueueingPort,
+    _buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl QueueingPort {
+    /// Interprets `buffer` as a segment in the layout described in
+    /// `PROTOCOL.md` and returns a port operating on it in place.
+    ///
+    /// The buffer must be at least `size_of::<Segment>()` bytes long and
+    /// aligned to `align_of::<Segment>()`. Its current contents are taken as
+    /// they are, so a buffer filled in by another implementation can be read
+    /// directly; an all-zero buffer is an empty port.
+    pub fn with_buffer(buffer: &mut [u8]) -> Result<BufferPort<'_>, PortError> {
+        if buffer.len() < core::mem::size_of::<Segment>() {
+            return Err(PortError::LayoutMismatch);
+        }
+        if buffer.as_ptr().align_offset(core::mem::align_of::<Segment>()) != 0 {
+            return Err(PortError::Misaligned);
+        }
+        // Every bit pattern is a valid header: all fields are plain atomics
+        // or bytes, indices are reduced modulo MSGS before use and the
+        // metadata length is capped.
+        let buffer = ShmemBuffer(NonNull::from(&mut *buffer).cast(), buffer.len());
+        Ok(BufferPort {
+            port: QueueingPort::from_memory(Memory::Attached(buffer)),
+            _buffer: PhantomData,
+        })
+    }
+}
+
+impl Deref for BufferPort<'_> {
+    type Target = QueueingPort;
+
+    fn deref(&self) -> &QueueingPort {
+        &self.port
+    }
+}
+
+impl DerefMut for BufferPort<'_> {
+    fn deref_mut(&mut self) -> &mut QueueingPort {
+        &mut self.port
+    }
+}
+
+impl EnqueuePort for BufferPort<'_> {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        self.port.enqueue(message)
+    }
+}
+
+impl DequeuePort for BufferPort<'_> {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.port.try_dequeue()
+    }
+}
@@ -0,0 +1,350 @@
+//! The byte order of the multi-byte header fields.
+//!
+//! A segment shared between machines of different endianness, say a
+//! big-endian POWER writer and a little-endian x86 reader over a PCIe
+//! window, needs one order both agree on. `PortConfig::byte_order` picks
+//! it when the port is created, and every `u32` of the header (indices,
+//! `message_count`, the counters, sequence numbers, timestamps) is then
+//! stored in that order, converted on every access with `read_u32_le` and
+//! `write_u32_le` or their big-endian twins.
+//!
+//! The order is recorded in the `features` word with bits that sit at the
+//! same place whichever way the word is read: `LITTLE_ENDIAN_MARK` is bits
+//! 12 and 20, `BIG_ENDIAN_MARK` bits 13 and 21, one pair mirroring the
+//! other's bytes. A peer can so tell the order before it reads anything
+//! else. Neither set means `ByteOrder::Native`, the order of the machine
+//! using the segment, which is what segments written before the marks
+//! existed use. Bits 12 and 13 fall among the required feature bits, so a
+//! peer too old to know the marks refuses a marked port rather than
+//! misreading it. `wire_features` leaves the marks out.
+//!
+//! Conversions are atomic like the fields themselves: an order the
+//! machine does not share costs a byte swap per load and store, and turns
+//! read-modify-write operations into compare-and-swap loops.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::QueueingPort;
+
+/// How the header's multi-byte fields are stored, see the module
+/// documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+    /// Whatever the machine using the segment uses; unmarked.
+    #[default]
+    Native,
+}
+
+pub(crate) const LITTLE_ENDIAN_MARK: u32 = 1 << 12 | 1 << 20;
+pub(crate) const BIG_ENDIAN_MARK: u32 = 1 << 13 | 1 << 21;
+/// Both marks, which are not features.
+pub(crate) const MARKS: u32 = LITTLE_ENDIAN_MARK | BIG_ENDIAN_MARK;
+
+pub(crate) fn read_u32_le(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+pub(crate) fn write_u32_le(buf: &mut [u8], val: u32) {
+    buf[..4].copy_from_slice(&val.to_le_bytes());
+}
+
+pub(crate) fn read_u32_be(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+pub(crate) fn write_u32_be(buf: &mut [u8], val: u32) {
+    buf[..4].copy_from_slice(&val.to_be_bytes());
+}
+
+impl ByteOrder {
+    /// The order recorded in a raw `features` word.
+    pub(crate) fn from_features(raw: u32) -> ByteOrder {
+        if raw & BIG_ENDIAN_MARK == BIG_ENDIAN_MARK {
+            ByteOrder::BigEndian
+        } else if raw & LITTLE_ENDIAN_MARK == LITTLE_ENDIAN_MARK {
+            ByteOrder::LittleEndian
+        } else {
+            ByteOrder::Native
+        }
+    }
+
+    /// The bits recording this order in the `features` word.
+    pub(crate) fn mark(self) -> u32 {
+        match self {
+            ByteOrder::LittleEndian => LITTLE_ENDIAN_MARK,
+            ByteOrder::BigEndian => BIG_ENDIAN_MARK,
+            ByteOrder::Native => 0,
+        }
+    }
+
+    /// The value of a field whose memory holds `raw` as a native `u32`.
+    fn decode(self, raw: u32) -> u32 {
+        match self {
+            ByteOrder::LittleEndian => read_u32_le(&raw.to_ne_bytes()),
+            ByteOrder::BigEndian => read_u32_be(&raw.to_ne_bytes()),
+            ByteOrder::Native => raw,
+        }
+    }
+
+    /// What to store, as a native `u32`, for a field to read as `value`.
+    fn encode(self, value: u32) -> u32 {
+        let mut bytes = [0; 4];
+        match self {
+            ByteOrder::LittleEndian => write_u32_le(&mut bytes, value),
+            ByteOrder::BigEndian => write_u32_be(&mut bytes, value),
+            ByteOrder::Native => return value,
+        }
+        u32::from_ne_bytes(bytes)
+    }
+
+    /// Whether values need converting on this machine.
+    fn swaps(self) -> bool {
+        self.encode(1) != 1
+    }
+}
+
+impl QueueingPort {
+    /// The order the port's header fields are stored in.
+    pub fn byte_order(&self) -> ByteOrder {
+        self.segment().header.byte_order()
+    }
+}
+
+/// A `u32` header field, stored in the segment's `ByteOrder`.
+///
+/// The methods are those of `AtomicU32`, taking the order first. Zero
+/// reads the same in every order, so all-zero memory is still an empty
+/// segment.
+#[repr(transparent)]
+pub(crate) struct WireU32(AtomicU32);
+
+impl WireU32 {
+    pub(crate) const fn zero() -> WireU32 {
+        WireU32(AtomicU32::new(0))
+    }
+
+    /// The field as the native atomic, for the futex, which only compares
+    /// against zero.
+    pub(crate) fn raw(&self) -> &AtomicU32 {
+        &self.0
+    }
+
+    pub(crate) fn load(&self, order: ByteOrder, ordering: Ordering) -> u32 {
+        order.decode(self.0.load(ordering))
+    }
+
+    pub(crate) fn store(&self, order: ByteOrder, value: u32, ordering: Ordering) {
+        self.0.store(order.encode(value), ordering)
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn compare_exchange(
+        &self,
+        order: ByteOrder,
+        current: u32,
+        new: u32,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<u32, u32> {
+        self.0
+            .compare_exchange(order.encode(current), order.encode(new), success, failure)
+            .map(|raw| order.decode(raw))
+            .map_err(|raw| order.decode(raw))
+    }
+
+    pub(crate) fn compare_exchange_weak(
+        &self,
+        order: ByteOrder,
+        current: u32,
+        new: u32,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<u32, u32> {
+        self.0
+            .compare_exchange_weak(order.encode(current), order.encode(new), success, failure)
+            .map(|raw| order.decode(raw))
+            .map_err(|raw| order.decode(raw))
+    }
+
+    pub(crate) fn fetch_add(&self, order: ByteOrder, value: u32, ordering: Ordering) -> u32 {
+        if !order.swaps() {
+            return self.0.fetch_add(value, ordering);
+        }
+        self.update(order, ordering, |current| current.wrapping_add(value))
+    }
+
+    pub(crate) fn fetch_sub(&self, order: ByteOrder, value: u32, ordering: Ordering) -> u32 {
+        if !order.swaps() {
+            return self.0.fetch_sub(value, ordering);
+        }
+        self.update(order, ordering, |current| current.wrapping_sub(value))
+    }
+
+    pub(crate) fn fetch_max(&self, order: ByteOrder, value: u32, ordering: Ordering) -> u32 {
+        if !order.swaps() {
+            return self.0.fetch_max(value, ordering);
+        }
+        self.update(order, ordering, |current| current.max(value))
+    }
+
+    /// Bitwise operations do not care about the byte order, only about the
+    /// operand being in it.
+    pub(crate) fn fetch_or(&self, order: ByteOrder, value: u32, ordering: Ordering) -> u32 {
+        order.decode(self.0.fetch_or(order.encode(value), ordering))
+    }
+
+    pub(crate) fn fetch_and(&self, order: ByteOrder, value: u32, ordering: Ordering) -> u32 {
+        order.decode(self.0.fetch_and(order.encode(value), ordering))
+    }
+
+    /// Applies `f` with a compare-and-swap loop, returning the old value.
+    fn update(&self, order: ByteOrder, ordering: Ordering, f: impl Fn(u32) -> u32) -> u32 {
+        let failure = match ordering {
+            Ordering::Release => Ordering::Relaxed,
+            Ordering::AcqRel => Ordering::Acquire,
+            other => other,
+        };
+        let mut raw = self.0.load(failure);
+        loop {
+            let current = order.decode(raw);
+            match self.0.compare_exchange_weak(raw, order.encode(f(current)), ordering, failure) {
+                Ok(_) => return current,
+                Err(actual) => raw = actual,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, PortConfig, Segment, WireFeatures, SIZE, SLOT_STRIDE};
+    use core::ptr::NonNull;
+
+    const WRITE_INDEX: usize = 0;
+    const MESSAGE_COUNT: usize = 8;
+    const ENQUEUED: usize = 12;
+    const FEATURES: usize = 40;
+    const BUFFER: usize = 1280;
+
+    fn bytes_of(port: &QueueingPort) -> &[u8] {
+        let segment: *const Segment = port.segment();
+        unsafe { core::slice::from_raw_parts(segment.cast(), core::mem::size_of::<Segment>()) }
+    }
+
+    /// A segment holding two messages, its header written byte by byte as
+    /// a writer storing `order` would, whatever this machine's order.
+    fn hand_built(order: ByteOrder, write: fn(&mut [u8], u32)) -> QueueingPort {
+        let segment = Box::leak(Box::new(Segment::new()));
+        let len = core::mem::size_of::<Segment>();
+        let bytes = unsafe { core::slice::from_raw_parts_mut((segment as *mut Segment).cast::<u8>(), len) };
+        write(&mut bytes[FEATURES..], order.mark());
+        for offset in [WRITE_INDEX, MESSAGE_COUNT, ENQUEUED] {
+            write(&mut bytes[offset..], 2);
+        }
+        bytes[BUFFER..BUFFER + SIZE].fill(1);
+        bytes[BUFFER + SLOT_STRIDE..BUFFER + SLOT_STRIDE + SIZE].fill(2);
+        unsafe { QueueingPort::attach(NonNull::from(segment)) }
+    }
+
+    #[test]
+    fn hand_built_segments_parse_in_either_order() {
+        for (order, write) in [
+            (ByteOrder::LittleEndian, write_u32_le as fn(&mut [u8], u32)),
+            (ByteOrder::BigEndian, write_u32_be),
+        ] {
+            let mut port = hand_built(order, write);
+            assert_eq!(port.byte_order(), order);
+            assert_eq!(port.wire_features(), WireFeatures::empty(), "marks are not features");
+            assert_eq!(port.missing_features(WireFeatures::KNOWN), None);
+            assert_eq!((port.len(), port.stats().enqueued), (2, 2));
+            assert_eq!(port.dequeue().unwrap().0, [1; SIZE]);
+            port.enqueue(Message([3; SIZE])).unwrap();
+            let read = if order == ByteOrder::LittleEndian { read_u32_le } else { read_u32_be };
+            let bytes = bytes_of(&port);
+            assert_eq!(read(&bytes[MESSAGE_COUNT..]), 2, "{:?}", order);
+            assert_eq!(read(&bytes[ENQUEUED..]), 3);
+            assert_eq!(read(&bytes[WRITE_INDEX..]), 3);
+            assert_eq!(port.dequeue().unwrap().0, [2; SIZE]);
+            assert_eq!(port.dequeue().unwrap().0, [3; SIZE]);
+            assert_eq!(port.stats().dequeued, 3);
+        }
+    }
+
+    #[test]
+    fn configured_order_reaches_the_header() {
+        let config =
+            PortConfig::new().byte_order(ByteOrder::BigEndian).wire_features(WireFeatures::CRC).credit_limit(4);
+        let mut port = QueueingPort::with_config(&config);
+        assert_eq!(port.byte_order(), ByteOrder::BigEndian);
+        assert_eq!(port.wire_features(), WireFeatures::CRC);
+        port.grant_credits(4);
+        for tag in 0..3 {
+            let mut message = Message([tag; SIZE]);
+            message.seal_crc();
+            port.enqueue(message).unwrap();
+        }
+        let bytes = bytes_of(&port);
+        assert_eq!(bytes[MESSAGE_COUNT..MESSAGE_COUNT + 4], [0, 0, 0, 3]);
+        assert_eq!(read_u32_be(&bytes[FEATURES..]), BIG_ENDIAN_MARK | WireFeatures::CRC.bits());
+        assert_eq!(port.credits_remaining(), Some(1));
+
+        // Snapshots are little endian whatever the port's order.
+        let restored = QueueingPort::restore_from_bytes(&port.snapshot_to_bytes()).unwrap();
+        assert_eq!(restored.byte_order(), ByteOrder::Native);
+        assert_eq!(restored.wire_features(), WireFeatures::CRC);
+        assert_eq!((restored.len(), restored.stats().enqueued), (3, 3));
+        assert_eq!(port.dequeue().unwrap().0[..8], [0; 8]);
+        assert_eq!(QueueingPort::new().byte_order(), ByteOrder::Native);
+    }
+
+    #[test]
+    fn fields_hold_the_bytes_of_their_order() {
+        for (order, bytes) in [
+            (ByteOrder::LittleEndian, [0x04, 0x03, 0x02, 0x01]),
+            (ByteOrder::BigEndian, [0x01, 0x02, 0x03, 0x04]),
+            (ByteOrder::Native, 0x0102_0304u32.to_ne_bytes()),
+        ] {
+            let field = WireU32::zero();
+            field.store(order, 0x0102_0304, Ordering::Relaxed);
+            assert_eq!(field.raw().load(Ordering::Relaxed).to_ne_bytes(), bytes, "{:?}", order);
+            assert_eq!(field.load(order, Ordering::Relaxed), 0x0102_0304);
+        }
+    }
+
+    #[test]
+    fn read_modify_write_in_a_foreign_order() {
+        // Whichever order this machine uses, one of the two is foreign.
+        for order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let field = WireU32::zero();
+            field.store(order, 0xff, Ordering::Relaxed);
+            assert_eq!(field.fetch_add(order, 1, Ordering::Relaxed), 0xff);
+            assert_eq!(field.fetch_sub(order, 0x10, Ordering::Relaxed), 0x100);
+            assert_eq!(field.fetch_max(order, 0x20, Ordering::Relaxed), 0xf0);
+            assert_eq!(field.load(order, Ordering::Relaxed), 0xf0);
+            assert_eq!(field.fetch_or(order, 0x0f00, Ordering::Relaxed), 0xf0);
+            assert_eq!(field.compare_exchange(order, 1, 2, Ordering::Relaxed, Ordering::Relaxed), Err(0x0ff0));
+            assert_eq!(field.compare_exchange_weak(order, 0x0ff0, 0, Ordering::Relaxed, Ordering::Relaxed), Ok(0x0ff0));
+            field.fetch_sub(order, 1, Ordering::Relaxed);
+            assert_eq!(field.load(order, Ordering::Relaxed), u32::MAX, "wraps like an atomic");
+        }
+    }
+
+    #[test]
+    fn marks_read_the_same_either_way() {
+        for order in [ByteOrder::LittleEndian, ByteOrder::BigEndian, ByteOrder::Native] {
+            let mark = order.mark();
+            assert_eq!(mark.swap_bytes(), mark);
+            assert_eq!(ByteOrder::from_features(mark | 0x0003_0007), order);
+            assert_eq!(ByteOrder::from_features((mark | 0x0003_0007).swap_bytes()), order);
+        }
+        let mut buf = [0; 6];
+        write_u32_le(&mut buf[1..], 0xdead_beef);
+        assert_eq!(buf, [0, 0xef, 0xbe, 0xad, 0xde, 0]);
+        assert_eq!(read_u32_le(&buf[1..]), 0xdead_beef);
+        write_u32_be(&mut buf[1..], 0xdead_beef);
+        assert_eq!(read_u32_be(&buf[1..]), 0xdead_beef);
+    }
+}
@@ -0,0 +1,244 @@
+//! Adapting a port to the `std::sync::mpsc` sync-channel API.
+//!
+//! The standard channel types cannot be built around a foreign queue, so
+//! `PortSender` and `PortReceiver` mirror the methods of `SyncSender` and
+//! `Receiver` and reuse their error types. Both halves share the port behind
+//! a mutex; blocking calls retry with the lock released in between, so a
+//! blocked sender never keeps the receiver out.
+
+use core::task::{Poll, Waker};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvError, SendError, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::{wait, Message, QueueError, QueueingPort};
+
+struct Shared {
+    port: Mutex<QueueingPort>,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+impl Shared {
+    fn port(&self) -> MutexGuard<'_, QueueingPort> {
+        // A panic while holding the lock cannot leave the port half-updated:
+        // the header is only ever changed through atomics.
+        self.port.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The sending half of `QueueingPort::into_mpsc_channel`, like `SyncSender`.
+pub struct PortSender {
+    shared: Arc<Shared>,
+}
+
+/// The receiving half of `QueueingPort::into_mpsc_channel`, like `Receiver`.
+pub struct PortReceiver {
+    shared: Arc<Shared>,
+}
+
+impl QueueingPort {
+    /// Turns the port into a sender/receiver pair with `std::sync::mpsc`
+    /// semantics: `send` blocks while the port is full, `recv` while it is
+    /// empty, and either fails once the other half is gone.
+    pub fn into_mpsc_channel(self) -> (PortSender, PortReceiver) {
+        let shared = Arc::new(Shared {
+            port: Mutex::new(self),
+            senders: AtomicUsize::new(1),
+            receiver_alive: AtomicBool::new(true),
+        });
+        (
+            PortSender {
+                shared: Arc::clone(&shared),
+            },
+            PortReceiver { shared },
+        )
+    }
+}
+
+// The errors hand the rejected message back, as the std channels do.
+#[allow(clippy::result_large_err)]
+impl PortSender {
+    pub fn send(&self, message: Message) -> Result<(), SendError<Message>> {
+        let mut message = message;
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(rejected)) => message = rejected,
+                Err(TrySendError::Disconnected(rejected)) => return Err(SendError(rejected)),
+            }
+            wait::backoff();
+        }
+    }
+
+    /// Fails with `Full` while the port has no room or credits, or waits
+    /// for its receiver, and with `Disconnected` once the receiver is gone
+    /// or for any error that waiting would not cure, such as a closed port
+    /// or a message the validator refuses.
+    pub fn try_send(&self, message: Message) -> Result<(), TrySendError<Message>> {
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(message));
+        }
+        let mut port = self.shared.port();
+        if port.room() == 0 {
+            // Checked up front so that a full port does not count a rejection
+            // for every retry of `send`.
+            return Err(TrySendError::Full(message));
+        }
+        // Kept so the message can be handed back whatever `enqueue` says.
+        let bytes = message.0;
+        port.enqueue(message).map_err(|error| match error {
+            QueueError::FullBuffer | QueueError::NoCredit | QueueError::NoPeer => {
+                TrySendError::Full(Message(bytes))
+            }
+            _ => TrySendError::Disconnected(Message(bytes)),
+        })
+    }
+}
+
+impl Clone for PortSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        PortSender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for PortSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::Release) == 1 {
+            // A stream waiting for messages has to see its end.
+            self.shared.port().wake_reader();
+        }
+    }
+}
+
+impl PortReceiver {
+    pub fn recv(&self) -> Result<Message, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => wait::backoff(),
+            }
+        }
+    }
+
+    /// Returns a queued message even after every sender is gone, and only
+    /// reports `Disconnected` once the port has been drained.
+    pub fn try_recv(&self) -> Result<Message, TryRecvError> {
+        let senders_gone = self.shared.senders.load(Ordering::Acquire) == 0;
+        match self.shared.port().try_dequeue() {
+            Ok(message) => Ok(message),
+            Err(QueueError::EmptyBuffer) if senders_gone => Err(TryRecvError::Disconnected),
+            Err(_) => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Blocks for each message in turn, ending once all senders are gone.
+    pub fn iter(&self) -> impl Iterator<Item = Message> + '_ {
+        core::iter::from_fn(move || self.recv().ok())
+    }
+
+    /// Like `try_recv`, but with `Pending` for an empty port that still has
+    /// senders, after arranging for `waker` to be woken by the next send.
+    pub(crate) fn poll_recv(&self, waker: &Waker) -> Poll<Option<Result<Message, QueueError>>> {
+        let senders_gone = self.shared.senders.load(Ordering::Acquire) == 0;
+        // Registering under the lock the senders enqueue under means no
+        // send can slip in between the empty dequeue and the registration.
+        let port = &mut *self.shared.port();
+        match port.try_dequeue() {
+            Ok(message) => Poll::Ready(Some(Ok(message))),
+            Err(QueueError::EmptyBuffer) if senders_gone => Poll::Ready(None),
+            Err(QueueError::EmptyBuffer) => {
+                port.register_reader_waker(waker);
+                Poll::Pending
+            }
+            Err(error) => Poll::Ready(Some(Err(error))),
+        }
+    }
+}
+
+impl Drop for PortReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MSGS, SIZE};
+    use std::thread;
+
+    #[test]
+    fn messages_arrive_in_order() {
+        let (sender, receiver) = QueueingPort::new().into_mpsc_channel();
+        for i in 0..10u8 {
+            sender.send(Message([i; SIZE])).unwrap();
+        }
+        drop(sender);
+        let received: Vec<u8> = receiver.iter().map(|message| message.0[0]).collect();
+        assert_eq!(received, (0..10).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn send_blocks_until_receiver_catches_up() {
+        let (sender, receiver) = QueueingPort::new().into_mpsc_channel();
+        let count = 3 * MSGS as u8;
+        let producer = thread::spawn(move || {
+            for i in 0..count {
+                sender.send(Message([i; SIZE])).unwrap();
+            }
+        });
+        for i in 0..count {
+            assert_eq!(receiver.recv().unwrap().0[0], i);
+        }
+        producer.join().unwrap();
+        assert!(receiver.recv().is_err());
+    }
+
+    #[test]
+    fn halves_report_disconnection() {
+        let (sender, receiver) = QueueingPort::new().into_mpsc_channel();
+        for _ in 0..MSGS {
+            sender.try_send(Message([1; SIZE])).unwrap();
+        }
+        assert!(matches!(sender.try_send(Message([2; SIZE])), Err(TrySendError::Full(_))));
+
+        let second = sender.clone();
+        drop(sender);
+        assert!(receiver.try_recv().is_ok());
+        drop(second);
+        assert_eq!(receiver.iter().count(), MSGS - 1, "queued messages outlive the senders");
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Disconnected)));
+
+        let (sender, receiver) = QueueingPort::new().into_mpsc_channel();
+        drop(receiver);
+        assert!(sender.send(Message([3; SIZE])).is_err());
+    }
+
+    #[test]
+    fn sending_on_a_closed_port_fails() {
+        let mut port = QueueingPort::new();
+        port.close_with_summary(&[]).unwrap();
+        let (sender, _receiver) = port.into_mpsc_channel();
+        assert!(matches!(sender.try_send(Message([1; SIZE])), Err(TrySendError::Disconnected(m)) if m.0 == [1; SIZE]));
+        assert!(matches!(sender.send(Message([2; SIZE])), Err(SendError(m)) if m.0 == [2; SIZE]));
+    }
+
+    #[test]
+    fn a_refused_message_is_handed_back() {
+        let mut port = QueueingPort::new();
+        port.set_validator(|message| match message.0[0] {
+            0 => Err(crate::ValidationError { reason: "tag 0" }),
+            _ => Ok(()),
+        });
+        let (sender, receiver) = port.into_mpsc_channel();
+        assert!(matches!(sender.try_send(Message([0; SIZE])), Err(TrySendError::Disconnected(m)) if m.0 == [0; SIZE]));
+        assert!(sender.send(Message([0; SIZE])).is_err());
+        sender.send(Message([1; SIZE])).unwrap();
+        assert_eq!(receiver.recv().unwrap().0, [1; SIZE]);
+    }
+}
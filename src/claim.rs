@@ -0,0 +1,152 @@
+//! Zero-copy access to slots: writing a message where it will be read, or
+//! reading it where it was written.
+//!
+//! A claimed write slot is invisible to the reader until `commit_write`:
+//! the reader only looks at slots `message_count` has handed over, so no
+//! per-slot in-progress marker is needed.
+//!
+//! A claim covers the slot the next enqueue (or dequeue) would use, so the
+//! handle's other enqueue (or dequeue) calls must not be mixed in between
+//! a claim and its commit. The port may change meanwhile, closed, reset or
+//! frozen, so `commit_write` makes the checks of `enqueue` again.
+
+use crate::{slot_index, QueueError, QueueingPort, MSGS, SIZE};
+use core::sync::atomic::Ordering;
+
+impl QueueingPort {
+    /// Reserves the next free slot and returns it for filling in place, e.g.
+    /// as a DMA target. The message is only enqueued by `commit_write`;
+    /// claiming again before that returns the same slot. Fails like
+    /// `enqueue` when there is no room.
+    pub fn claim_write_slice(&mut self) -> Result<&mut [u8; SIZE], QueueError> {
+        if !self.write_claimed {
+            self.admit_enqueue()?;
+            let header = &self.segment().header;
+            if self.len() >= MSGS {
+                header.rejected.fetch_add(header.byte_order(), 1, Ordering::Relaxed);
+                return Err(QueueError::FullBuffer);
+            }
+            self.write_claimed = true;
+        }
+        let segment = self.segment();
+        let index = slot_index(segment.header.write_index.load(segment.header.byte_order(), Ordering::Relaxed) as usize);
+        // Free slots belong to the writer, and this one stays free until
+        // `commit_write`, which needs `&mut self` and so ends the borrow.
+        Ok(unsafe { &mut *segment.slot(index).cast::<[u8; SIZE]>() })
+    }
+
+    /// Enqueues the slot returned by `claim_write_slice`, with whatever it
+    /// now holds. Does nothing if no slot is claimed. Fails like `enqueue`
+    /// where the port no longer takes messages or the validator refuses the
+    /// message; the claim is given up then, and claiming again, where that
+    /// succeeds, returns the slot as it was.
+    pub fn commit_write(&mut self) -> Result<(), QueueError> {
+        if !core::mem::take(&mut self.write_claimed) {
+            return Ok(());
+        }
+        let _hold = self.admit_enqueue()?;
+        let segment = self.segment();
+        self.validate_written(segment, 0)?;
+        // Stamps the sequence number; the bytes are already in place.
+        segment.fill_free_slot(0, |_| {});
+        segment.publish(1, self.enqueue_time(), self.clock());
+        self.wake_reader();
+        Ok(())
+    }
+
+    /// Returns the oldest queued message in place, without dequeueing it;
+    /// `commit_read` does that. The type filter does not apply. On a strict
+    /// FIFO port an out-of-order message is discarded with
+    /// `OrderViolation`, as by `dequeue`.
+    pub fn claim_read_slice(&mut self) -> Result<&[u8; SIZE], QueueError> {
+        if !self.read_claimed {
+            // Declining leaves the message queued, after the order check.
+            self.consume_front_if(|_| None::<()>)?;
+            self.read_claimed = true;
+        }
+        let segment = self.segment();
+        let index = slot_index(segment.header.read_index.load(segment.header.byte_order(), Ordering::Relaxed) as usize);
+        // Occupied slots belong to the reader until `commit_read`.
+        Ok(unsafe { &*segment.slot(index).cast::<[u8; SIZE]>() })
+    }
+
+    /// Dequeues the message returned by `claim_read_slice`. Does nothing if
+    /// none is claimed.
+    pub fn commit_read(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            self.hot_claim = None;
+        }
+        if core::mem::take(&mut self.read_claimed) {
+            // Already checked by the claim.
+            let _ = self.consume_front(|_| ());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeliveryOrder, Message, PortConfig};
+
+    #[test]
+    fn claimed_write_is_invisible_until_commit() {
+        let mut port = QueueingPort::new();
+        port.claim_write_slice().unwrap().fill(0x5a);
+        assert!(port.is_empty());
+        assert!(matches!(port.try_dequeue(), Err(QueueError::EmptyBuffer)));
+
+        // A second claim returns the same slot.
+        port.claim_write_slice().unwrap()[0] = 1;
+        port.commit_write().unwrap();
+        port.commit_write().unwrap();
+        assert_eq!(port.len(), 1);
+        let message = port.dequeue().unwrap();
+        assert_eq!((message.0[0], message.0[1]), (1, 0x5a));
+        assert_eq!(port.stats().enqueued, 1);
+    }
+
+    #[test]
+    fn claim_write_fails_when_full() {
+        let mut port = QueueingPort::new();
+        while port.enqueue(Message([0; SIZE])).is_ok() {}
+        assert!(matches!(port.claim_write_slice(), Err(QueueError::FullBuffer)));
+        port.commit_write().unwrap();
+        assert_eq!(port.len(), MSGS);
+    }
+
+    #[test]
+    fn claim_write_fails_once_closed() {
+        let mut port = QueueingPort::new();
+        port.close_with_summary(b"").unwrap();
+        assert!(matches!(port.claim_write_slice(), Err(QueueError::Closed)));
+    }
+
+    #[test]
+    fn commit_fails_on_a_port_reset_since_the_claim() {
+        let mut port = QueueingPort::new();
+        port.claim_write_slice().unwrap().fill(1);
+        port.generation = port.generation.wrapping_add(1);
+        assert!(matches!(port.commit_write(), Err(QueueError::StaleSegment)));
+        assert!(port.is_empty());
+        assert!(!port.write_claimed, "given up");
+    }
+
+    #[test]
+    fn claimed_read_is_consumed_on_commit() {
+        let config = PortConfig::new().delivery_order(DeliveryOrder::StrictFifo);
+        let mut port = QueueingPort::with_config(&config);
+        assert!(matches!(port.claim_read_slice(), Err(QueueError::EmptyBuffer)));
+        port.enqueue(Message([3; SIZE])).unwrap();
+        port.enqueue(Message([4; SIZE])).unwrap();
+
+        assert_eq!(port.claim_read_slice().unwrap(), &[3; SIZE]);
+        assert_eq!(port.claim_read_slice().unwrap(), &[3; SIZE]);
+        assert_eq!(port.len(), 2);
+        port.commit_read();
+        assert_eq!(port.len(), 1);
+        assert_eq!(port.dequeue().unwrap().0, [4; SIZE]);
+        port.commit_read();
+        assert_eq!(port.stats().dequeued, 2);
+    }
+}
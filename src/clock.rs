@@ -0,0 +1,336 @@
+//! Time sources for deadline-based operations.
+//!
+//! Every time-related feature of a port reads the same `Clock`, set through
+//! `PortConfig::clock` or `QueueingPort::set_clock`. Deadlines are absolute
+//! readings of that clock, so a task can compute one at activation and pass
+//! it to every call of that period.
+//!
+//! The clock also paces the waits of those operations: between two polls
+//! a port calls `Clock::pause`. Real clocks give up the CPU there; a
+//! `SimulatedClock` moves its own time on instead, so a test can run a
+//! ten-second timeout without sleeping at all.
+//!
+//! A port does not trust its clock to be monotonic. Each handle reads it
+//! through a `MonotonicClock`, which never returns less than it returned
+//! before: a reading that jumps backwards, as a tick counter reset on a
+//! clock-domain switch does, is clamped to the previous one until the
+//! source catches up. To the port, time then stands still, so no deadline
+//! or idle time is reached early and no age goes negative. A clock that
+//! stops has the same effect, except that a wait would never end: one that
+//! pauses `STALL_PAUSES` times without the clock moving is allowed to
+//! reach its deadline, and the handle's readings do not fall back behind
+//! it. Each backwards jump of the source and each wait so ended counts in
+//! `QueueStats::clock_anomalies`.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// A monotonic time source, in nanoseconds from an arbitrary origin.
+pub trait Clock: Send + Sync {
+    fn now_ns(&self) -> u64;
+
+    /// Called between two polls of an operation waiting until `deadline_ns`
+    /// at the latest. The default backs off for a moment of real time.
+    fn pause(&self, _deadline_ns: u64) {
+        crate::wait::backoff();
+    }
+}
+
+/// How a port holds its clock: shared ownership where there is an
+/// allocator, a static reference otherwise.
+#[cfg(feature = "alloc")]
+pub type ClockRef = alloc::sync::Arc<dyn Clock>;
+#[cfg(not(feature = "alloc"))]
+pub type ClockRef = &'static dyn Clock;
+
+/// Pauses without the clock moving after which a wait counts it as stopped
+/// and reaches its deadline, see the module documentation.
+pub const STALL_PAUSES: u32 = 1_000_000;
+
+/// How a handle reads its clock, see the module documentation.
+pub(crate) struct MonotonicClock {
+    /// `None` for the default clock.
+    source: Option<ClockRef>,
+    /// The source's last reading, and the highest reading returned.
+    last: AtomicU64,
+    floor: AtomicU64,
+    /// Pauses since the reading last moved.
+    stalled: AtomicU32,
+    anomalies: AtomicU32,
+}
+
+impl MonotonicClock {
+    pub(crate) const fn new(source: Option<ClockRef>) -> MonotonicClock {
+        MonotonicClock {
+            source,
+            last: AtomicU64::new(0),
+            floor: AtomicU64::new(0),
+            stalled: AtomicU32::new(0),
+            anomalies: AtomicU32::new(0),
+        }
+    }
+
+    /// Reads `source` from now on, starting over from its time.
+    pub(crate) fn set_source(&mut self, source: ClockRef) {
+        self.source = Some(source);
+        *self.last.get_mut() = 0;
+        *self.floor.get_mut() = 0;
+        *self.stalled.get_mut() = 0;
+    }
+
+    /// A clock on the same source for another handle.
+    #[cfg(feature = "shmem")]
+    pub(crate) fn fork(&self) -> MonotonicClock {
+        MonotonicClock::new(self.source.clone())
+    }
+
+    pub(crate) fn anomalies(&self) -> u32 {
+        self.anomalies.load(Ordering::Relaxed)
+    }
+
+    fn source(&self) -> &dyn Clock {
+        match &self.source {
+            Some(clock) => &**clock,
+            None => &DEFAULT_CLOCK,
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now_ns(&self) -> u64 {
+        let now = self.source().now_ns();
+        if now < self.last.swap(now, Ordering::Relaxed) {
+            self.anomalies.fetch_add(1, Ordering::Relaxed);
+        }
+        self.floor.fetch_max(now, Ordering::Relaxed).max(now)
+    }
+
+    fn pause(&self, deadline_ns: u64) {
+        let before = self.now_ns();
+        self.source().pause(deadline_ns);
+        if self.now_ns() > before {
+            self.stalled.store(0, Ordering::Relaxed);
+        } else if self.stalled.fetch_add(1, Ordering::Relaxed) + 1 >= STALL_PAUSES {
+            // Stopped, or still behind a backwards jump: let the wait end.
+            self.floor.fetch_max(deadline_ns, Ordering::Relaxed);
+            self.stalled.store(0, Ordering::Relaxed);
+            self.anomalies.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulation.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    offset: AtomicU64,
+}
+
+impl MockClock {
+    pub const fn new(start_ns: u64) -> MockClock {
+        MockClock {
+            offset: AtomicU64::new(start_ns),
+        }
+    }
+
+    pub fn set(&self, ns: u64) {
+        self.offset.store(ns, Ordering::Release);
+    }
+
+    pub fn advance(&self, ns: u64) {
+        self.offset.fetch_add(ns, Ordering::AcqRel);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ns(&self) -> u64 {
+        self.offset.load(Ordering::Acquire)
+    }
+}
+
+/// A clock for deterministic tests: time moves when told to, and by a
+/// fixed step every time a port waiting on it pauses between polls.
+///
+/// A wait for a deadline `n` steps away thus takes exactly `n` polls,
+/// however loaded the machine, and a timeout is reached without any real
+/// time passing. For the same reason the peer of a waiting port gets no
+/// more than those polls' worth of real time to act in, so concurrent tests
+/// should give waits that are meant to succeed a generous deadline.
+#[derive(Debug)]
+pub struct SimulatedClock {
+    now: AtomicU64,
+    step: u64,
+}
+
+impl SimulatedClock {
+    /// How far each pause moves the time, unless set with `with_step`.
+    pub const DEFAULT_STEP_NS: u64 = 10_000_000;
+
+    pub const fn new(start_ns: u64) -> SimulatedClock {
+        SimulatedClock::with_step(start_ns, SimulatedClock::DEFAULT_STEP_NS)
+    }
+
+    /// A clock whose pauses move the time by `step_ns`, at least 1.
+    pub const fn with_step(start_ns: u64, step_ns: u64) -> SimulatedClock {
+        SimulatedClock {
+            now: AtomicU64::new(start_ns),
+            step: if step_ns == 0 { 1 } else { step_ns },
+        }
+    }
+
+    pub fn advance(&self, ns: u64) {
+        self.now.fetch_add(ns, Ordering::AcqRel);
+    }
+
+    pub fn set(&self, ns: u64) {
+        self.now.store(ns, Ordering::Release);
+    }
+}
+
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        SimulatedClock::new(0)
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now_ns(&self) -> u64 {
+        self.now.load(Ordering::Acquire)
+    }
+
+    /// Moves the time one step on, but not past `deadline_ns`.
+    fn pause(&self, deadline_ns: u64) {
+        let now = self.now.load(Ordering::Acquire);
+        // Never backwards, if `advance` moved it meanwhile.
+        self.now.fetch_max(now.saturating_add(self.step).min(deadline_ns), Ordering::AcqRel);
+        core::hint::spin_loop();
+    }
+}
+
+/// `std::time::Instant`, measured from the first time any `StdClock` was
+/// read in this process. The default clock with std.
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdClock;
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now_ns(&self) -> u64 {
+        use std::sync::OnceLock;
+        use std::time::Instant;
+
+        static EPOCH: OnceLock<Instant> = OnceLock::new();
+        EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+    }
+}
+
+/// The default clock without std, where there is no time source to fall
+/// back on. It reads as the end of time, so every deadline has passed until
+/// a real clock is set.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoClock;
+
+impl Clock for NoClock {
+    fn now_ns(&self) -> u64 {
+        u64::MAX
+    }
+}
+
+#[cfg(feature = "std")]
+pub(crate) static DEFAULT_CLOCK: StdClock = StdClock;
+#[cfg(not(feature = "std"))]
+pub(crate) static DEFAULT_CLOCK: NoClock = NoClock;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, PortConfig, QueueError, QueueingPort, WireFeatures, SIZE};
+    use core::time::Duration;
+    use std::sync::Arc;
+
+    #[test]
+    fn mock_clock_moves_only_when_told() {
+        let clock = MockClock::new(5);
+        assert_eq!(clock.now_ns(), 5);
+        clock.advance(10);
+        assert_eq!(clock.now_ns(), 15);
+        clock.set(3);
+        assert_eq!(clock.now_ns(), 3);
+    }
+
+    #[test]
+    fn simulated_clock_moves_on_pause_up_to_the_deadline() {
+        let clock = SimulatedClock::with_step(0, 4);
+        clock.pause(10);
+        clock.pause(10);
+        assert_eq!(clock.now_ns(), 8);
+        clock.pause(10);
+        clock.pause(10);
+        assert_eq!(clock.now_ns(), 10, "capped at the deadline");
+        clock.advance(5);
+        clock.pause(10);
+        assert_eq!(clock.now_ns(), 15, "never backwards");
+    }
+
+    /// A port stamping and watching its own messages on `clock`.
+    fn timed_port(clock: &Arc<MockClock>) -> QueueingPort {
+        let features = WireFeatures::TIMESTAMPS | WireFeatures::PROGRESS;
+        QueueingPort::with_config(&PortConfig::new().wire_features(features).clock(clock.clone()))
+    }
+
+    #[test]
+    fn backwards_jumps_are_clamped() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut port = timed_port(&clock);
+        port.enqueue(Message([0; SIZE])).unwrap();
+        clock.set(1_600);
+        port.enqueue(Message([1; SIZE])).unwrap();
+        assert_eq!(port.consumer_idle(), Some(600));
+
+        // A counter reset: the port sees time stand still at 1600.
+        clock.set(10);
+        assert_eq!(port.clock().now_ns(), 1_600);
+        let mut ages = [0; 2];
+        assert_eq!(port.pending_ages(&mut ages), 2);
+        assert_eq!(ages, [600, 0]);
+        assert_eq!(port.consumer_idle(), Some(600));
+        assert!(!port.consumer_stalled(600));
+        // A deadline that had passed stays passed.
+        assert!(matches!(port.enqueue_by(Message([2; SIZE]), 1_500), Err(QueueError::DeadlineMissed)));
+        assert_eq!(port.dequeue_timed().unwrap().dequeue_time, 1_600);
+        assert_eq!(port.stats().clock_anomalies, 1, "one jump");
+
+        // And moves again once the source passes it.
+        clock.set(1_700);
+        assert_eq!(port.consumer_idle(), Some(100));
+        let received = port.dequeue_timed().unwrap();
+        assert_eq!((received.enqueue_time, received.dequeue_time), (1_600, 1_700));
+    }
+
+    #[test]
+    fn waits_on_a_stopped_clock_still_end() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut port = timed_port(&clock);
+        // No time passing: nothing becomes idle or overdue.
+        port.enqueue(Message([0; SIZE])).unwrap();
+        assert!(!port.consumer_stalled(0));
+        assert_eq!(port.stats().clock_anomalies, 0);
+
+        while port.enqueue(Message([0; SIZE])).is_ok() {}
+        let timeout = Duration::from_millis(5);
+        assert!(matches!(port.enqueue_timeout(Message([0; SIZE]), timeout), Err(QueueError::DeadlineMissed)));
+        assert_eq!(port.stats().clock_anomalies, 1);
+        assert!(port.clock().now_ns() >= 1_000 + 5_000_000, "not back behind the deadline");
+        assert_eq!(port.consumer_idle(), Some(5_000_000));
+
+        while port.try_dequeue().is_ok() {}
+        assert!(matches!(port.dequeue_timeout(timeout), Err(QueueError::EmptyBuffer)));
+        assert_eq!(port.stats().clock_anomalies, 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn std_clock_is_monotonic() {
+        let before = StdClock.now_ns();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert!(StdClock.now_ns() >= before + 2_000_000);
+    }
+}
@@ -0,0 +1,111 @@
+//! Aggregate health figures over many ports, for monitors polling often.
+
+use alloc::rc::{Rc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use crate::{QueueingPort, MSGS};
+
+/// What `StatsCollector::aggregate` found across the registered ports.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AggregateStats {
+    /// Registered ports that are still alive and were sampled.
+    pub ports: usize,
+    /// Registered ports that have been dropped since.
+    pub stale: usize,
+    /// Messages queued across all sampled ports.
+    pub pending: usize,
+    /// Highest `len / capacity` of any sampled port, 0 without ports.
+    pub worst_fill_ratio: f32,
+    /// Sampled ports holding more messages than their registered watermark.
+    pub above_watermark: usize,
+}
+
+/// Weak handles on ports, sampled together by `aggregate`.
+///
+/// The collector does not keep ports alive; a dropped port is skipped and
+/// counted as stale until `prune` removes it. Port handles are not `Sync`,
+/// so the collector lives on the thread that owns them, sharing each port
+/// through an `Rc`.
+#[derive(Default)]
+pub struct StatsCollector {
+    ports: Vec<(Weak<QueueingPort>, usize)>,
+}
+
+impl StatsCollector {
+    pub fn new() -> StatsCollector {
+        StatsCollector { ports: Vec::new() }
+    }
+
+    /// Adds `port`, counted in `above_watermark` whenever it holds more
+    /// than `watermark` messages.
+    pub fn register(&mut self, port: &Rc<QueueingPort>, watermark: usize) {
+        self.ports.push((Rc::downgrade(port), watermark));
+    }
+
+    /// Forgets dropped ports and returns how many there were.
+    pub fn prune(&mut self) -> usize {
+        let before = self.ports.len();
+        self.ports.retain(|(port, _)| port.strong_count() > 0);
+        before - self.ports.len()
+    }
+
+    /// Samples every registered port with a single relaxed load of its
+    /// message count. No port is locked or waited for, so the figures are
+    /// a snapshot that may mix slightly different moments.
+    pub fn aggregate(&self) -> AggregateStats {
+        let mut stats = AggregateStats::default();
+        for (port, watermark) in &self.ports {
+            let Some(port) = port.upgrade() else {
+                stats.stale += 1;
+                continue;
+            };
+            let len = (port.segment().header.message_count.load(port.byte_order(), Ordering::Relaxed) as usize).min(MSGS);
+            stats.ports += 1;
+            stats.pending += len;
+            stats.worst_fill_ratio = stats.worst_fill_ratio.max(len as f32 / MSGS as f32);
+            if len > *watermark {
+                stats.above_watermark += 1;
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+
+    fn port_with(len: usize) -> Rc<QueueingPort> {
+        let mut port = QueueingPort::new();
+        for _ in 0..len {
+            port.enqueue(Message([0; SIZE])).unwrap();
+        }
+        Rc::new(port)
+    }
+
+    #[test]
+    fn aggregates_and_counts_dropped_ports() {
+        let ports = [port_with(2), port_with(9), port_with(5), port_with(0)];
+        let mut collector = StatsCollector::new();
+        for port in &ports {
+            collector.register(port, 4);
+        }
+
+        let stats = collector.aggregate();
+        assert_eq!((stats.ports, stats.stale, stats.pending, stats.above_watermark), (4, 0, 16, 2));
+        assert!((stats.worst_fill_ratio - 0.9).abs() < 1e-6);
+
+        let [low, high, middle, empty] = ports;
+        drop(high);
+        let stats = collector.aggregate();
+        assert_eq!((stats.ports, stats.stale, stats.pending, stats.above_watermark), (3, 1, 7, 1));
+        assert!((stats.worst_fill_ratio - 0.5).abs() < 1e-6);
+
+        assert_eq!(collector.prune(), 1);
+        assert_eq!(collector.aggregate().stale, 0);
+        drop((low, middle, empty));
+        assert_eq!(collector.aggregate(), AggregateStats { stale: 3, ..Default::default() });
+    }
+}
@@ -0,0 +1,128 @@
+//! Moving the queued messages back to the start of the buffer.
+
+use core::ptr;
+use core::sync::atomic::Ordering;
+
+use crate::{slot_index, QueueingPort, MSGS, SLOT_STRIDE};
+
+impl QueueingPort {
+    /// Rewrites the queued messages into slots `0..len()`, oldest first, so
+    /// they occupy one contiguous run at the front of the buffer.
+    ///
+    /// Afterwards `read_index` is 0 and `write_index` is `len()`. While the
+    /// slots are being moved the header's `compacting` flag holds off any
+    /// `enqueue`/`dequeue` another handle starts; an operation already
+    /// under way is not waited for.
+    ///
+    /// # Safety
+    ///
+    /// No other handle on the segment, in this process or another, may be
+    /// inside an operation on the port when this is called: one would read
+    /// or write a slot while it is moved.
+    pub unsafe fn compact(&mut self) {
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        if header.compacting.swap(1, Ordering::AcqRel) != 0 {
+            // Another handle is already compacting this segment.
+            return;
+        }
+
+        let count = header.message_count.load(order, Ordering::Acquire) as usize;
+        let read_index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize);
+        // The queued messages are contiguous modulo MSGS starting at
+        // `read_index`, so rotating the whole buffer moves them to the front
+        // and the free slots, with their canaries, behind them.
+        //
+        // SAFETY: `compacting` keeps both sides out of the slots until it is
+        // cleared, and the caller guarantees neither was in them when it was
+        // set, so no reference to any of them exists, and the rotation
+        // is done on a copy rather than through a `&mut` to shared memory.
+        unsafe {
+            let buffer = segment.buffer.get();
+            let mut rotated = ptr::read_unaligned(buffer);
+            rotated.rotate_left(read_index * SLOT_STRIDE);
+            ptr::write_unaligned(buffer, rotated);
+        }
+        for words in [
+            &header.slot_sequence,
+            &header.slot_generation,
+            &header.slot_time_low,
+            &header.slot_time_high,
+        ] {
+            let mut rotated = [0; MSGS];
+            for (i, word) in rotated.iter_mut().enumerate() {
+                *word = words[slot_index(read_index + i)].load(order, Ordering::Relaxed);
+            }
+            for (slot, word) in words.iter().zip(rotated) {
+                slot.store(order, word, Ordering::Relaxed);
+            }
+        }
+        header.rotate_pins(read_index);
+        header.read_index.store(order, 0, Ordering::Relaxed);
+        header.write_index.store(order, slot_index(count) as u32, Ordering::Relaxed);
+
+        header.compacting.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DeliveryOrder, Message, PortConfig, QueueingPort, MSGS, SIZE};
+    use core::sync::atomic::Ordering;
+
+    // A message whose first `len` bytes carry `tag`.
+    fn message(tag: u8, len: usize) -> Message {
+        let mut bytes = [0; SIZE];
+        bytes[..len].fill(tag);
+        Message(bytes)
+    }
+
+    #[test]
+    fn compact_keeps_remaining_messages_readable() {
+        let mut port = QueueingPort::new();
+        let lengths = [10, 256, 1, 100, 37];
+        for (tag, &len) in lengths.iter().enumerate() {
+            port.enqueue(message(tag as u8 + 1, len)).unwrap();
+        }
+        port.dequeue().unwrap();
+        port.dequeue().unwrap();
+
+        // SAFETY: no other handle is attached.
+        unsafe { port.compact() };
+
+        let header = &port.segment().header;
+        let order = header.byte_order();
+        assert_eq!(header.read_index.load(order, Ordering::Relaxed), 0);
+        assert_eq!(header.write_index.load(order, Ordering::Relaxed), 3);
+        for (tag, &len) in lengths.iter().enumerate().skip(2) {
+            assert_eq!(port.dequeue().unwrap().0, message(tag as u8 + 1, len).0);
+        }
+        assert!(port.try_dequeue().is_err());
+    }
+
+    #[test]
+    fn compact_wrapped_queue() {
+        // Strict FIFO also checks that sequence numbers moved with the slots.
+        let config = PortConfig::new().delivery_order(DeliveryOrder::StrictFifo);
+        let mut port = QueueingPort::with_config(&config);
+        for tag in 0..MSGS as u8 {
+            port.enqueue(message(tag, SIZE)).unwrap();
+        }
+        for _ in 0..7 {
+            port.dequeue().unwrap();
+        }
+        // Slots 7, 8, 9 and then 0, 1 after wrapping.
+        port.enqueue(message(100, 5)).unwrap();
+        port.enqueue(message(101, 6)).unwrap();
+
+        // SAFETY: no other handle is attached.
+        unsafe { port.compact() };
+        let expected = [message(7, SIZE), message(8, SIZE), message(9, SIZE), message(100, 5), message(101, 6)];
+        for message in expected {
+            assert_eq!(port.dequeue().unwrap().0, message.0);
+        }
+        port.enqueue(message(1, 1)).unwrap();
+        assert_eq!(port.dequeue().unwrap().0, message(1, 1).0);
+    }
+}
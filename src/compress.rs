@@ -0,0 +1,443 @@
+//! Compressed messages, on ports declaring `WireFeatures::COMPRESSION`.
+//!
+//! On such a port `enqueue_bytes` stores a message behind a
+//! `COMPRESSED_HEADER_LEN`-byte header, little endian:
+//!
+//! ```text
+//! 0..2  length of the message
+//! 2..4  bytes stored after the header; bit 15 set if they are compressed
+//! ```
+//!
+//! The message is compressed if that makes it shorter, and stored as it is
+//! otherwise, so a message of up to `COMPRESSED_PAYLOAD` bytes always fits
+//! and one of up to `COMPRESSED_MAX_LEN` bytes fits if it compresses well
+//! enough. `dequeue_bytes` undoes either into the caller's buffer. The
+//! header takes the bytes where messages keep their type id, so type
+//! filters do not apply to these messages.
+//!
+//! The compressed bytes are a run of tokens, each a control byte `c` and:
+//!
+//! - for `c < 0x80`, `c + 1` bytes copied as they are;
+//! - otherwise a two-byte little-endian distance `d`, after which the
+//!   `(c & 0x7f) + 3` bytes starting `d` bytes back in the output are
+//!   copied, byte by byte, so a match may overlap what it produces.
+//!
+//! The compressor is a greedy LZ77 finding matches through a hash table of
+//! `HASH_ENTRIES` positions, kept in the handle: compressing takes no
+//! allocation and no more stack than one slot, and decompressing writes
+//! straight into the caller's buffer.
+
+use crate::{signal, trace, DequeuePort, EnqueuePort, Message, QueueError, QueueingPort, WireFeatures, SIZE};
+
+/// Bytes of a compressed-port message taken by its header.
+pub const COMPRESSED_HEADER_LEN: usize = 4;
+/// Bytes of a message that fit a slot uncompressed.
+pub const COMPRESSED_PAYLOAD: usize = SIZE - COMPRESSED_HEADER_LEN;
+/// Longest message `enqueue_bytes` takes on a compressed port; it must
+/// still compress to `COMPRESSED_PAYLOAD` bytes.
+pub const COMPRESSED_MAX_LEN: usize = u16::MAX as usize;
+
+const COMPRESSED_FLAG: u16 = 0x8000;
+const HASH_ENTRIES: usize = 256;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 0x7f;
+const MAX_LITERALS: usize = 0x80;
+
+/// What `enqueue_bytes` did with the messages of a compressed port, through
+/// one handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompressionStats {
+    /// Messages stored compressed.
+    pub compressed: u32,
+    /// Messages stored as they were, compression not making them shorter.
+    pub stored_raw: u32,
+    /// Bytes handed to `enqueue_bytes`.
+    pub bytes_in: u64,
+    /// Bytes those messages took in their slots, headers left out.
+    pub bytes_stored: u64,
+}
+
+/// The handle's compressor state.
+pub(crate) struct Compressor {
+    /// One more than the last position whose first bytes hashed here, 0 for
+    /// none.
+    table: [u16; HASH_ENTRIES],
+    stats: CompressionStats,
+}
+
+impl Compressor {
+    pub(crate) const fn new() -> Compressor {
+        Compressor {
+            table: [0; HASH_ENTRIES],
+            stats: CompressionStats { compressed: 0, stored_raw: 0, bytes_in: 0, bytes_stored: 0 },
+        }
+    }
+
+    /// Compresses `input`, of at most `COMPRESSED_MAX_LEN` bytes, into
+    /// `out`; `None` if it does not fit.
+    fn compress(&mut self, input: &[u8], out: &mut [u8]) -> Option<usize> {
+        self.table = [0; HASH_ENTRIES];
+        let mut written = 0;
+        let mut literals = 0;
+        let mut position = 0;
+        while position + MIN_MATCH <= input.len() {
+            let entry = &mut self.table[hash(&input[position..])];
+            let candidate = core::mem::replace(entry, position as u16 + 1) as usize;
+            let len = match candidate.checked_sub(1) {
+                Some(start) => (0..MAX_MATCH.min(input.len() - position))
+                    .take_while(|&i| input[start + i] == input[position + i])
+                    .count(),
+                None => 0,
+            };
+            if len < MIN_MATCH {
+                position += 1;
+                continue;
+            }
+            put_literals(&input[literals..position], out, &mut written)?;
+            let distance = ((position - (candidate - 1)) as u16).to_le_bytes();
+            put(&[0x80 | (len - MIN_MATCH) as u8, distance[0], distance[1]], out, &mut written)?;
+            position += len;
+            literals = position;
+        }
+        put_literals(&input[literals..], out, &mut written)?;
+        Some(written)
+    }
+
+    /// Lays `bytes` out in `slot` as the module documentation describes,
+    /// compressed if that makes them shorter; returns the bytes stored
+    /// after the header and whether they are compressed.
+    fn pack(&mut self, bytes: &[u8], slot: &mut [u8; SIZE]) -> Result<(usize, bool), QueueError> {
+        let too_large = QueueError::MessageTooLarge { len: bytes.len() };
+        if bytes.len() > COMPRESSED_MAX_LEN {
+            return Err(too_large);
+        }
+        let (stored, flag) = match self.compress(bytes, &mut slot[COMPRESSED_HEADER_LEN..]) {
+            Some(len) if len < bytes.len() => (len, COMPRESSED_FLAG),
+            _ if bytes.len() <= COMPRESSED_PAYLOAD => {
+                slot[COMPRESSED_HEADER_LEN..][..bytes.len()].copy_from_slice(bytes);
+                (bytes.len(), 0)
+            }
+            _ => return Err(too_large),
+        };
+        slot[0..2].copy_from_slice(&(bytes.len() as u16).to_le_bytes());
+        slot[2..4].copy_from_slice(&(stored as u16 | flag).to_le_bytes());
+        slot[COMPRESSED_HEADER_LEN + stored..].fill(0);
+        Ok((stored, flag != 0))
+    }
+
+    /// Counts a message `pack` laid out and that was then enqueued.
+    fn count(&mut self, len: usize, (stored, compressed): (usize, bool)) {
+        let stats = &mut self.stats;
+        if compressed {
+            stats.compressed += 1;
+        } else {
+            stats.stored_raw += 1;
+        }
+        stats.bytes_in += len as u64;
+        stats.bytes_stored += stored as u64;
+    }
+}
+
+/// The length of the message `pack` laid out in `slot`.
+fn unpacked_len(slot: &[u8; SIZE]) -> usize {
+    usize::from(u16::from_le_bytes([slot[0], slot[1]]))
+}
+
+/// Undoes `pack` into the front of `out`, returning the message's length;
+/// `None` if `out` is too short for it.
+fn unpack(slot: &[u8; SIZE], out: &mut [u8]) -> Option<Result<usize, QueueError>> {
+    let needed = unpacked_len(slot);
+    let stored = u16::from_le_bytes([slot[2], slot[3]]);
+    let dest = out.get_mut(..needed)?;
+    let body = slot[COMPRESSED_HEADER_LEN..].get(..usize::from(stored & !COMPRESSED_FLAG));
+    let decoded = match body {
+        Some(body) if stored & COMPRESSED_FLAG != 0 => decompress(body, dest),
+        Some(body) if body.len() == needed => {
+            dest.copy_from_slice(body);
+            Some(())
+        }
+        _ => None,
+    };
+    Some(decoded.map(|()| needed).ok_or(QueueError::Truncated))
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from(bytes[0]) | u32::from(bytes[1]) << 8 | u32::from(bytes[2]) << 16;
+    (word.wrapping_mul(0x9e37_79b1) >> 24) as usize
+}
+
+fn put(bytes: &[u8], out: &mut [u8], written: &mut usize) -> Option<()> {
+    out.get_mut(*written..*written + bytes.len())?.copy_from_slice(bytes);
+    *written += bytes.len();
+    Some(())
+}
+
+fn put_literals(literals: &[u8], out: &mut [u8], written: &mut usize) -> Option<()> {
+    for run in literals.chunks(MAX_LITERALS) {
+        put(&[run.len() as u8 - 1], out, written)?;
+        put(run, out, written)?;
+    }
+    Some(())
+}
+
+/// Decompresses `input` into all of `out`; `None` unless it makes exactly
+/// that many bytes.
+fn decompress(mut input: &[u8], out: &mut [u8]) -> Option<()> {
+    let mut written = 0;
+    while let Some((&control, rest)) = input.split_first() {
+        if control < 0x80 {
+            let len = usize::from(control) + 1;
+            out.get_mut(written..written + len)?.copy_from_slice(rest.get(..len)?);
+            written += len;
+            input = &rest[len..];
+        } else {
+            let len = usize::from(control & 0x7f) + MIN_MATCH;
+            let distance = usize::from(u16::from_le_bytes([*rest.first()?, *rest.get(1)?]));
+            if distance == 0 || distance > written || written + len > out.len() {
+                return None;
+            }
+            for at in written..written + len {
+                out[at] = out[at - distance];
+            }
+            written += len;
+            input = &rest[2..];
+        }
+    }
+    (written == out.len()).then_some(())
+}
+
+impl QueueingPort {
+    /// What `enqueue_bytes` did through this handle on a compressed port.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compressor.stats
+    }
+
+    /// `enqueue_bytes` on a compressed port, see the module documentation.
+    pub(crate) fn enqueue_compressed(&mut self, bytes: &[u8]) -> Result<(), QueueError> {
+        let mut slot = [0; SIZE];
+        let packed = self.compressor.pack(bytes, &mut slot)?;
+        self.enqueue(Message(slot))?;
+        self.compressor.count(bytes.len(), packed);
+        Ok(())
+    }
+
+    /// Dequeues a message written by `enqueue_bytes` into the front of
+    /// `out`, decompressing it if it was stored compressed, and returns its
+    /// length. On a port without `WireFeatures::COMPRESSION`, where
+    /// messages carry no length, that is all `SIZE` bytes of the slot, and
+    /// on any port 0 for a signal.
+    ///
+    /// If `out` is shorter than the message, fails with
+    /// `QueueError::BufferTooSmall` giving its length, and the message
+    /// stays queued. A compressed message that does not decode to its
+    /// length is consumed, and fails with `QueueError::Truncated`.
+    pub fn dequeue_bytes(&mut self, out: &mut [u8]) -> Result<usize, QueueError> {
+        let compressed_port = self.wire_features().contains(WireFeatures::COMPRESSION);
+        let mut needed = 0;
+        let taken = trace::dequeue(self, |port| {
+            port.consume_front_if(|slot| {
+                if signal::is_signal(slot) {
+                    port.signals.count_received(slot);
+                    return Some(Ok(0));
+                }
+                if !compressed_port {
+                    needed = SIZE;
+                    out.get_mut(..SIZE)?.copy_from_slice(slot);
+                    return Some(Ok(SIZE));
+                }
+                needed = unpacked_len(slot);
+                unpack(slot, out)
+            })
+        })?;
+        taken.unwrap_or(Err(QueueError::BufferTooSmall { needed }))
+    }
+}
+
+/// Any port carrying its messages compressed, laid out as on a port with
+/// `WireFeatures::COMPRESSION`, so either end can be a compressed
+/// `QueueingPort` instead.
+pub struct CompressedPort<P> {
+    inner: P,
+    compressor: Compressor,
+}
+
+impl<P: EnqueuePort + DequeuePort> CompressedPort<P> {
+    pub fn new(inner: P) -> CompressedPort<P> {
+        CompressedPort { inner, compressor: Compressor::new() }
+    }
+
+    /// Enqueues `bytes`, compressed if that makes them shorter. Fails with
+    /// `QueueError::MessageTooLarge` if they do not fit a slot either way.
+    pub fn enqueue_bytes(&mut self, bytes: &[u8]) -> Result<(), QueueError> {
+        let mut slot = [0; SIZE];
+        let packed = self.compressor.pack(bytes, &mut slot)?;
+        self.inner.enqueue(Message(slot))?;
+        self.compressor.count(bytes.len(), packed);
+        Ok(())
+    }
+
+    /// Dequeues a message into the front of `out` and returns its length.
+    /// The inner port cannot keep a message once dequeued, so one longer
+    /// than `out` is lost, failing with `QueueError::BufferTooSmall`; and
+    /// one that does not decode to its length with `QueueError::Truncated`.
+    pub fn dequeue_bytes(&mut self, out: &mut [u8]) -> Result<usize, QueueError> {
+        let message = self.inner.dequeue()?;
+        unpack(&message.0, out).unwrap_or(Err(QueueError::BufferTooSmall { needed: unpacked_len(&message.0) }))
+    }
+
+    /// Bytes stored over bytes enqueued, over every message so far; 1.0
+    /// before the first.
+    pub fn compression_ratio(&self) -> f32 {
+        let stats = self.compressor.stats;
+        if stats.bytes_in == 0 {
+            return 1.0;
+        }
+        stats.bytes_stored as f32 / stats.bytes_in as f32
+    }
+
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compressor.stats
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PortConfig;
+
+    fn compressed_port() -> QueueingPort {
+        QueueingPort::with_config(&PortConfig::new().wire_features(WireFeatures::COMPRESSION))
+    }
+
+    /// Diagnostics-like text, repetitive in the way JSON is.
+    fn diagnostics(records: usize) -> Vec<u8> {
+        (0..records)
+            .map(|i| format!(r#"{{"sensor":"temp{}","status":"ok","value":{}}},"#, i % 4, 20 + i % 3))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    /// Bytes no LZ finds a match in.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn codec_round_trips() {
+        let mut compressor = Compressor::new();
+        for input in [Vec::new(), b"ab".to_vec(), vec![7; 1000], diagnostics(40), noise(200)] {
+            let mut packed = vec![0; 2 * input.len() + 8];
+            let len = compressor.compress(&input, &mut packed).unwrap();
+            let mut output = vec![0; input.len()];
+            assert_eq!(decompress(&packed[..len], &mut output), Some(()));
+            assert_eq!(output, input);
+        }
+        let mut output = [0; 10];
+        assert_eq!(decompress(&[0x80, 1, 0], &mut output), None, "a match before any output");
+        assert_eq!(decompress(&[4, 1, 2], &mut output), None, "literals cut short");
+    }
+
+    #[test]
+    fn compressible_messages_take_fewer_bytes() {
+        let mut port = compressed_port();
+        let blob = diagnostics(20);
+        assert!(blob.len() > SIZE, "larger than a slot: {} bytes", blob.len());
+        port.enqueue_bytes(&blob).unwrap();
+        port.enqueue_bytes(b"{}").unwrap();
+
+        let stats = port.compression_stats();
+        assert_eq!((stats.compressed, stats.stored_raw), (1, 1));
+        assert_eq!(stats.bytes_in, blob.len() as u64 + 2);
+        assert!(stats.bytes_stored < (COMPRESSED_PAYLOAD + 2) as u64, "{:?}", stats);
+
+        let mut out = vec![0; 2048];
+        assert_eq!(port.dequeue_bytes(&mut out).unwrap(), blob.len());
+        assert_eq!(out[..blob.len()], blob[..]);
+        assert_eq!(port.dequeue_bytes(&mut out).unwrap(), 2);
+        assert_eq!(&out[..2], b"{}");
+    }
+
+    #[test]
+    fn incompressible_messages_are_stored_raw() {
+        let mut port = compressed_port();
+        let bytes = noise(COMPRESSED_PAYLOAD);
+        port.enqueue_bytes(&bytes).unwrap();
+        let stats = port.compression_stats();
+        assert_eq!((stats.compressed, stats.stored_raw, stats.bytes_stored), (0, 1, COMPRESSED_PAYLOAD as u64));
+        let mut out = [0; COMPRESSED_PAYLOAD];
+        assert_eq!(port.dequeue_bytes(&mut out).unwrap(), COMPRESSED_PAYLOAD);
+        assert_eq!(out[..], bytes[..]);
+
+        // Neither fits.
+        let too_large = noise(COMPRESSED_PAYLOAD + 1);
+        assert!(matches!(port.enqueue_bytes(&too_large), Err(QueueError::MessageTooLarge { len }) if len == too_large.len()));
+        assert!(port.is_empty());
+    }
+
+    #[test]
+    fn short_buffers_report_the_message_length() {
+        let mut port = compressed_port();
+        let blob = diagnostics(10);
+        port.enqueue_bytes(&blob).unwrap();
+        let mut out = vec![0; blob.len() - 1];
+        assert!(matches!(port.dequeue_bytes(&mut out), Err(QueueError::BufferTooSmall { needed }) if needed == blob.len()));
+        assert_eq!(port.len(), 1, "still queued");
+        out.push(0);
+        assert_eq!(port.dequeue_bytes(&mut out).unwrap(), blob.len());
+        assert_eq!(out, blob);
+    }
+
+    #[test]
+    fn compressed_port_over_any_port() {
+        let mut port = CompressedPort::new(QueueingPort::new());
+        assert_eq!(port.compression_ratio(), 1.0);
+        port.enqueue_bytes(&[0; 256]).unwrap();
+        let stats = port.compression_stats();
+        assert!(stats.bytes_stored < 20, "{:?}", stats);
+        assert!(port.compression_ratio() < 20.0 / 256.0);
+        let mut out = [1; 256];
+        assert_eq!(port.dequeue_bytes(&mut out).unwrap(), 256);
+        assert_eq!(out, [0; 256]);
+
+        // Incompressible ones go as they are, and the other end can be a
+        // compressed `QueueingPort`.
+        let bytes = noise(100);
+        port.enqueue_bytes(&bytes).unwrap();
+        assert_eq!(port.compression_stats().stored_raw, 1);
+        let mut reader = compressed_port();
+        reader.enqueue(port.into_inner().dequeue().unwrap()).unwrap();
+        assert_eq!(reader.dequeue_bytes(&mut out).unwrap(), 100);
+        assert_eq!(out[..100], bytes[..]);
+    }
+
+    #[test]
+    fn plain_ports_are_unaffected() {
+        let mut port = QueueingPort::new();
+        port.enqueue_bytes(&diagnostics(1)[..10]).unwrap();
+        assert_eq!(port.compression_stats(), CompressionStats::default());
+        let mut out = [0; SIZE];
+        assert_eq!(port.dequeue_bytes(&mut out).unwrap(), SIZE);
+        assert_eq!(out[..10], diagnostics(1)[..10]);
+        assert_eq!(out[10..], [0; SIZE - 10]);
+    }
+}
@@ -0,0 +1,369 @@
+//! Settings a port is created with.
+
+#[cfg(feature = "shmem")]
+use core::time::Duration;
+
+#[cfg(feature = "shmem")]
+use crate::{RetryPolicy, TeardownPolicy};
+
+use crate::{
+    peer, ByteOrder, ClockRef, ConcurrencyMode, NumaPolicy, PortError, QueueingPort, WireFeatures, HISTORY_PAYLOAD,
+    MSGS,
+};
+
+/// Size of the metadata area in the segment header.
+pub const METADATA_CAPACITY: usize = 128;
+
+/// The ordering a port promises its reader, recorded in the segment header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum DeliveryOrder {
+    /// No check; the queue is still FIFO by construction.
+    #[default]
+    Unordered = 0,
+    /// Every dequeue verifies that the message is the one enqueued right
+    /// after the previous one and fails with `QueueError::OrderViolation`
+    /// otherwise. Messages dropped by the type filter do not count as gaps.
+    StrictFifo = 1,
+}
+
+/// Options for `QueueingPort::with_config` and `QueueingPort::create_with_config`.
+#[derive(Clone)]
+pub struct PortConfig {
+    metadata: [u8; METADATA_CAPACITY],
+    metadata_len: usize,
+    clock: Option<ClockRef>,
+    delivery_order: DeliveryOrder,
+    wire_features: WireFeatures,
+    credit_limit: u32,
+    pin_limit: u32,
+    /// Payload bytes of each history record, if `history` was called.
+    history: Option<u32>,
+    require_receiver: bool,
+    byte_order: ByteOrder,
+    /// Set by the `Port<M>` constructors.
+    mode: ConcurrencyMode,
+    pub(crate) numa_policy: Option<NumaPolicy>,
+    #[cfg(feature = "shmem")]
+    pub(crate) handshake_timeout: Duration,
+    #[cfg(feature = "shmem")]
+    teardown: TeardownPolicy,
+    #[cfg(feature = "shmem")]
+    pub(crate) retry: RetryPolicy,
+}
+
+impl PortConfig {
+    pub const fn new() -> PortConfig {
+        PortConfig {
+            metadata: [0; METADATA_CAPACITY],
+            metadata_len: 0,
+            clock: None,
+            delivery_order: DeliveryOrder::Unordered,
+            wire_features: WireFeatures::empty(),
+            credit_limit: 0,
+            pin_limit: 0,
+            history: None,
+            require_receiver: false,
+            byte_order: ByteOrder::Native,
+            mode: ConcurrencyMode::Spsc,
+            numa_policy: None,
+            #[cfg(feature = "shmem")]
+            handshake_timeout: crate::named::HANDSHAKE_TIMEOUT,
+            #[cfg(feature = "shmem")]
+            teardown: TeardownPolicy::Owner,
+            #[cfg(feature = "shmem")]
+            retry: RetryPolicy::NONE,
+        }
+    }
+
+    /// Sets the blob stored in the segment header for anyone attaching to
+    /// the port to read, e.g. a schema id or a description. Fails with
+    /// `PortError::MetadataTooLarge` beyond `METADATA_CAPACITY` bytes.
+    pub fn metadata(mut self, bytes: &[u8]) -> Result<PortConfig, PortError> {
+        if bytes.len() > METADATA_CAPACITY {
+            return Err(PortError::MetadataTooLarge { len: bytes.len() });
+        }
+        self.metadata = [0; METADATA_CAPACITY];
+        self.metadata[..bytes.len()].copy_from_slice(bytes);
+        self.metadata_len = bytes.len();
+        Ok(self)
+    }
+
+    /// Sets the clock the port measures deadlines with, see
+    /// `QueueingPort::set_clock`.
+    pub fn clock(mut self, clock: ClockRef) -> PortConfig {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Sets the ordering contract checked by the reader. No queueing mode
+    /// that could break FIFO order exists yet, so every combination is
+    /// accepted.
+    pub fn delivery_order(mut self, order: DeliveryOrder) -> PortConfig {
+        self.delivery_order = order;
+        self
+    }
+
+    /// Declares the wire features the port's messages use, checked by
+    /// `open()` on the other side.
+    pub fn wire_features(mut self, features: WireFeatures) -> PortConfig {
+        self.wire_features = features;
+        self
+    }
+
+    /// Puts the port in credit mode: enqueueing needs credits granted by
+    /// the receiver with `QueueingPort::grant_credits`, at most `limit` at a
+    /// time. A limit of 0 turns credit mode off.
+    pub fn credit_limit(mut self, limit: u32) -> PortConfig {
+        self.credit_limit = limit;
+        self
+    }
+
+    /// Lets up to `limit` queued messages be pinned at once, see the `pin`
+    /// module; a quarter of `MSGS` leaves most of the queue to evictions.
+    /// A limit of 0, the default, turns pinning off, and one above `MSGS`
+    /// counts as `MSGS`.
+    pub fn pin_limit(mut self, limit: u32) -> PortConfig {
+        self.pin_limit = limit;
+        self
+    }
+
+    /// Keeps the last `HISTORY_CAPACITY` consumed messages in the segment,
+    /// each with the first `payload_bytes` of its payload, at most
+    /// `HISTORY_PAYLOAD`; see the `history` module. Setting
+    /// `WireFeatures::HISTORY` instead keeps `HISTORY_PAYLOAD` bytes.
+    pub fn history(mut self, payload_bytes: usize) -> PortConfig {
+        self.history = Some(payload_bytes.min(HISTORY_PAYLOAD) as u32);
+        self
+    }
+
+    /// Makes enqueueing fail with `QueueError::NoPeer` until a receiver has
+    /// announced itself, see `QueueingPort::announce`.
+    pub fn require_receiver(mut self) -> PortConfig {
+        self.require_receiver = true;
+        self
+    }
+
+    /// Stores the header's multi-byte fields in `order`, for a peer of
+    /// another endianness; see the `byteorder` module. Peers that predate
+    /// byte orders refuse a port with any but `ByteOrder::Native`.
+    pub fn byte_order(mut self, order: ByteOrder) -> PortConfig {
+        self.byte_order = order;
+        self
+    }
+
+    /// Places the segment of a named port on a NUMA node when it is
+    /// created, see `QueueingPort::bind_numa`.
+    pub fn numa_policy(mut self, policy: NumaPolicy) -> PortConfig {
+        self.numa_policy = Some(policy);
+        self
+    }
+
+    pub(crate) fn mode(mut self, mode: ConcurrencyMode) -> PortConfig {
+        self.mode = mode;
+        self
+    }
+
+    /// How long `create_with_config` waits for a reader.
+    #[cfg(feature = "shmem")]
+    pub fn handshake_timeout(mut self, timeout: Duration) -> PortConfig {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Which handle unlinks a named port's segment, see the `detach`
+    /// module. Defaults to `TeardownPolicy::Owner`.
+    #[cfg(feature = "shmem")]
+    pub fn teardown(mut self, policy: TeardownPolicy) -> PortConfig {
+        self.teardown = policy;
+        self
+    }
+
+    /// How `create_with_config` retries a creation that failed for a
+    /// passing reason, see the `retry` module. Defaults to
+    /// `RetryPolicy::NONE`.
+    #[cfg(feature = "shmem")]
+    pub fn retry(mut self, policy: RetryPolicy) -> PortConfig {
+        self.retry = policy;
+        self
+    }
+
+    /// Configures a port whose segment is not yet visible to a peer.
+    pub(crate) fn apply(&self, port: &mut QueueingPort) {
+        if let Some(clock) = &self.clock {
+            port.set_clock(ClockRef::clone(clock));
+        }
+        let header = &port.segment().header;
+        let order = self.byte_order;
+        // First, as it records the order the other fields are stored in.
+        let history = if self.history.is_some() { WireFeatures::HISTORY } else { WireFeatures::empty() };
+        header.features.store(
+            order,
+            (self.wire_features | history).bits() | order.mark(),
+            core::sync::atomic::Ordering::Relaxed,
+        );
+        // Bytes past the length are zero, whatever the memory held before.
+        unsafe { *header.metadata.get() = self.metadata };
+        header
+            .delivery_order
+            .store(self.delivery_order as u8, core::sync::atomic::Ordering::Relaxed);
+        header
+            .credit_limit
+            .store(order, self.credit_limit, core::sync::atomic::Ordering::Relaxed);
+        header.credits.store(order, 0, core::sync::atomic::Ordering::Relaxed);
+        header
+            .pin_limit
+            .store(order, self.pin_limit.min(MSGS as u32), core::sync::atomic::Ordering::Relaxed);
+        header.pinned.store(order, 0, core::sync::atomic::Ordering::Relaxed);
+        header.history_bytes.store(
+            order,
+            self.history.unwrap_or(HISTORY_PAYLOAD as u32),
+            core::sync::atomic::Ordering::Relaxed,
+        );
+        header.reset_history();
+        header.mode.store(self.mode as u8, core::sync::atomic::Ordering::Relaxed);
+        #[cfg(feature = "shmem")]
+        header
+            .teardown
+            .store(order, self.teardown.bits(), core::sync::atomic::Ordering::Relaxed);
+        let peers = if self.require_receiver { peer::REQUIRE_RECEIVER } else { 0 };
+        header.peers.store(order, peers, core::sync::atomic::Ordering::Relaxed);
+        header
+            .metadata_len
+            .store(order, self.metadata_len as u32, core::sync::atomic::Ordering::Release);
+    }
+}
+
+impl core::fmt::Debug for PortConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PortConfig")
+            .field("metadata", &&self.metadata[..self.metadata_len])
+            .field("custom_clock", &self.clock.is_some())
+            .field("delivery_order", &self.delivery_order)
+            .field("wire_features", &self.wire_features)
+            .field("credit_limit", &self.credit_limit)
+            .field("pin_limit", &self.pin_limit)
+            .field("history", &self.history)
+            .field("require_receiver", &self.require_receiver)
+            .field("byte_order", &self.byte_order)
+            .field("mode", &self.mode)
+            .field("numa_policy", &self.numa_policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for PortConfig {
+    fn default() -> Self {
+        PortConfig::new()
+    }
+}
+
+impl QueueingPort {
+    pub fn with_config(config: &PortConfig) -> QueueingPort {
+        let mut port = QueueingPort::new();
+        config.apply(&mut port);
+        port
+    }
+
+    pub fn delivery_order(&self) -> DeliveryOrder {
+        match self.segment().header.delivery_order.load(core::sync::atomic::Ordering::Relaxed) {
+            1 => DeliveryOrder::StrictFifo,
+            _ => DeliveryOrder::Unordered,
+        }
+    }
+
+    /// The metadata blob the port was created with, empty if none was set.
+    pub fn metadata(&self) -> &[u8] {
+        let header = &self.segment().header;
+        let len = header.metadata_len.load(header.byte_order(), core::sync::atomic::Ordering::Acquire) as usize;
+        // Written only before the segment is handed to a peer.
+        let metadata = unsafe { &*header.metadata.get() };
+        &metadata[..len.min(METADATA_CAPACITY)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, QueueError, MSGS, SIZE};
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    fn metadata_round_trip() {
+        let config = PortConfig::new().metadata(b"schema=7;sensor imu").unwrap();
+        assert_eq!(QueueingPort::with_config(&config).metadata(), b"schema=7;sensor imu");
+        assert_eq!(QueueingPort::new().metadata(), b"");
+        assert_eq!(QueueingPort::with_config(&PortConfig::new()).metadata(), b"");
+    }
+
+    #[test]
+    fn metadata_up_to_capacity() {
+        let full = [0xAB; METADATA_CAPACITY];
+        let port = QueueingPort::with_config(&PortConfig::new().metadata(&full).unwrap());
+        assert_eq!(port.metadata(), full);
+
+        let result = PortConfig::new().metadata(&[0; METADATA_CAPACITY + 1]);
+        assert!(matches!(result, Err(PortError::MetadataTooLarge { len }) if len == METADATA_CAPACITY + 1));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn clock_comes_from_config() {
+        let clock = alloc::sync::Arc::new(crate::MockClock::new(42));
+        let port = QueueingPort::with_config(&PortConfig::new().clock(clock.clone()));
+        assert_eq!(port.clock().now_ns(), 42);
+        clock.advance(8);
+        assert_eq!(port.clock().now_ns(), 50);
+    }
+
+    #[test]
+    fn strict_fifo_accepts_queue_order() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().delivery_order(DeliveryOrder::StrictFifo));
+        assert_eq!(port.delivery_order(), DeliveryOrder::StrictFifo);
+        port.set_type_filter(&[0]).unwrap();
+        for round in 0..3u8 {
+            for i in 0..MSGS as u8 {
+                let mut bytes = [i; SIZE];
+                bytes[4..6].copy_from_slice(&u16::from(i % 2).to_le_bytes());
+                port.enqueue(Message(bytes)).unwrap();
+            }
+            // Filtered messages are skipped without counting as a gap.
+            for i in (0..MSGS as u8).step_by(2) {
+                assert_eq!(port.dequeue().unwrap().0[0], i, "round {}", round);
+            }
+            assert!(matches!(port.try_dequeue(), Err(QueueError::EmptyBuffer)));
+        }
+    }
+
+    #[test]
+    fn strict_fifo_reports_out_of_order_slot() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().delivery_order(DeliveryOrder::StrictFifo));
+        for i in 0..3u8 {
+            port.enqueue(Message([i; SIZE])).unwrap();
+        }
+        // Pretend the writer had skipped a message before slot 1.
+        port.segment().header.slot_sequence[1].store(port.byte_order(), 7, Ordering::Relaxed);
+
+        assert_eq!(port.dequeue().unwrap().0, [0; SIZE]);
+        assert!(matches!(
+            port.try_dequeue(),
+            Err(QueueError::OrderViolation { expected: 1, got: 7 })
+        ));
+        assert_eq!(port.dequeue().unwrap().0, [2; SIZE]);
+
+        // The same corruption goes unchecked on an unordered port.
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([0; SIZE])).unwrap();
+        port.segment().header.slot_sequence[0].store(port.byte_order(), 7, Ordering::Relaxed);
+        assert!(port.try_dequeue().is_ok());
+    }
+
+    #[test]
+    fn shorter_metadata_clears_the_rest() {
+        let config = PortConfig::new().metadata(&[1; 64]).unwrap().metadata(&[2; 4]).unwrap();
+        let port = QueueingPort::with_config(&config);
+        assert_eq!(port.metadata(), [2; 4]);
+        let area = unsafe { &*port.segment().header.metadata.get() };
+        assert!(area[4..].iter().all(|&byte| byte == 0));
+    }
+}
@@ -0,0 +1,229 @@
+//! Checking message CRCs on ports that declare `WireFeatures::CRC`.
+//!
+//! A CRC port's messages end in a little-endian CRC-32 (IEEE) of the
+//! bytes before it, written by the sender with `Message::seal_crc`.
+//! `dequeue` checks it before the type filter, and what happens to a
+//! message that fails is the receiving handle's `CorruptionPolicy`.
+//!
+//! The policy belongs to the handle, like the type filter, and so do the
+//! skipped count and the quarantined messages. Skipping consumes the slot
+//! as an ordinary dequeue would, so it leaves no gap in the sequence
+//! numbers a `StrictFifo` port checks.
+
+use core::cell::Cell;
+
+use crate::{Message, QueueingPort, SIZE};
+
+/// Bytes at the end of a message taken by its CRC.
+pub const CRC_LEN: usize = 4;
+/// Bytes the CRC covers.
+pub const CRC_PAYLOAD: usize = SIZE - CRC_LEN;
+/// Most corrupted messages a handle keeps for `take_quarantined`.
+pub const QUARANTINE_CAPACITY: usize = 4;
+
+/// What `dequeue` does with a message whose CRC does not match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptionPolicy {
+    /// Leave the message queued and fail with `QueueError::Corrupted`, on
+    /// this call and every later one.
+    #[default]
+    Halt,
+    /// Discard the message, count it in `QueueStats::corrupted_skipped`
+    /// and go on with the next.
+    Skip,
+    /// Like `Skip`, but keep a copy for `take_quarantined` first.
+    Quarantine,
+}
+
+/// CRC-32 with the IEEE polynomial, as used by zlib and Ethernet.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg()))
+    })
+}
+
+pub(crate) fn crc_ok(slot: &[u8; SIZE]) -> bool {
+    let stored = u32::from_le_bytes(slot[CRC_PAYLOAD..].try_into().unwrap());
+    crc32(&slot[..CRC_PAYLOAD]) == stored
+}
+
+impl Message {
+    /// Writes the CRC of the first `CRC_PAYLOAD` bytes into the last
+    /// `CRC_LEN`, as a CRC port's receiver expects.
+    pub fn seal_crc(&mut self) {
+        let crc = crc32(&self.0[..CRC_PAYLOAD]);
+        self.0[CRC_PAYLOAD..].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    pub fn crc_ok(&self) -> bool {
+        crc_ok(&self.0)
+    }
+}
+
+pub(crate) struct CorruptionHandling {
+    pub(crate) policy: CorruptionPolicy,
+    skipped: Cell<u32>,
+    /// Quarantined slots, the oldest at `first`.
+    quarantine: [Cell<[u8; SIZE]>; QUARANTINE_CAPACITY],
+    first: Cell<usize>,
+    len: Cell<usize>,
+}
+
+impl CorruptionHandling {
+    pub(crate) fn new() -> CorruptionHandling {
+        CorruptionHandling {
+            policy: CorruptionPolicy::Halt,
+            skipped: Cell::new(0),
+            quarantine: core::array::from_fn(|_| Cell::new([0; SIZE])),
+            first: Cell::new(0),
+            len: Cell::new(0),
+        }
+    }
+
+    /// Counts a discarded slot, keeping a copy under `Quarantine`. A full
+    /// quarantine drops its oldest copy.
+    pub(crate) fn skip(&self, slot: &[u8; SIZE]) {
+        self.skipped.set(self.skipped.get().wrapping_add(1));
+        if self.policy != CorruptionPolicy::Quarantine {
+            return;
+        }
+        let (first, len) = (self.first.get(), self.len.get());
+        self.quarantine[(first + len) % QUARANTINE_CAPACITY].set(*slot);
+        if len == QUARANTINE_CAPACITY {
+            self.first.set((first + 1) % QUARANTINE_CAPACITY);
+        } else {
+            self.len.set(len + 1);
+        }
+    }
+
+    pub(crate) fn skipped(&self) -> u32 {
+        self.skipped.get()
+    }
+}
+
+impl QueueingPort {
+    /// Sets what `dequeue` does with messages that fail their CRC check.
+    /// Has no effect on a port without `WireFeatures::CRC`.
+    pub fn set_corruption_policy(&mut self, policy: CorruptionPolicy) {
+        self.corruption.policy = policy;
+    }
+
+    /// The oldest message set aside under `CorruptionPolicy::Quarantine`,
+    /// exactly as it was in its slot.
+    pub fn take_quarantined(&mut self) -> Option<Message> {
+        let corruption = &self.corruption;
+        let len = corruption.len.get();
+        if len == 0 {
+            return None;
+        }
+        let first = corruption.first.get();
+        corruption.first.set((first + 1) % QUARANTINE_CAPACITY);
+        corruption.len.set(len - 1);
+        Some(Message(corruption.quarantine[first].get()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeliveryOrder, PortConfig, QueueError, Segment, WireFeatures};
+    use core::ptr::NonNull;
+
+    fn sealed(fill: u8) -> Message {
+        let mut message = Message([fill; SIZE]);
+        message.seal_crc();
+        message
+    }
+
+    /// A CRC port holding sealed messages 1..=5, the third corrupted, and a
+    /// second handle on it for the receiver.
+    fn port_with_bad_middle_slot() -> (QueueingPort, QueueingPort) {
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let config = PortConfig::new()
+            .wire_features(WireFeatures::CRC)
+            .delivery_order(DeliveryOrder::StrictFifo);
+        let mut sender = unsafe { QueueingPort::attach(segment) };
+        config.apply(&mut sender);
+        for fill in 1..=5 {
+            let mut message = sealed(fill);
+            if fill == 3 {
+                message.0[10] ^= 0x40;
+            }
+            sender.enqueue(message).unwrap();
+        }
+        (sender, unsafe { QueueingPort::attach(segment) })
+    }
+
+    fn fills(port: &mut QueueingPort) -> Vec<u8> {
+        core::iter::from_fn(|| port.dequeue()).map(|message| message.0[0]).collect()
+    }
+
+    #[test]
+    fn crc32_known_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert!(sealed(7).crc_ok());
+        assert!(!Message([7; SIZE]).crc_ok());
+    }
+
+    #[test]
+    fn halt_keeps_returning_corrupted() {
+        let (_sender, mut receiver) = port_with_bad_middle_slot();
+        assert_eq!(receiver.dequeue().unwrap().0[0], 1);
+        assert_eq!(receiver.dequeue().unwrap().0[0], 2);
+        for _ in 0..2 {
+            assert!(matches!(receiver.try_dequeue(), Err(QueueError::Corrupted { sequence: 2 })));
+        }
+        assert_eq!(receiver.len(), 3, "the bad message stays queued");
+        assert_eq!(receiver.stats().corrupted_skipped, 0);
+
+        // Switching policy unblocks the port.
+        receiver.set_corruption_policy(CorruptionPolicy::Skip);
+        assert_eq!(fills(&mut receiver), [4, 5]);
+    }
+
+    #[test]
+    fn skip_delivers_the_rest_in_order() {
+        let (mut sender, mut receiver) = port_with_bad_middle_slot();
+        receiver.set_corruption_policy(CorruptionPolicy::Skip);
+        assert_eq!(fills(&mut receiver), [1, 2, 4, 5]);
+        assert_eq!(receiver.stats().corrupted_skipped, 1);
+        assert_eq!(receiver.stats().dequeued, 5);
+        assert!(receiver.take_quarantined().is_none());
+
+        // Strict FIFO saw no gap: later messages still arrive.
+        sender.enqueue(sealed(6)).unwrap();
+        assert_eq!(receiver.dequeue().unwrap().0[0], 6);
+    }
+
+    #[test]
+    fn quarantine_keeps_the_raw_slot() {
+        let (_sender, mut receiver) = port_with_bad_middle_slot();
+        receiver.set_corruption_policy(CorruptionPolicy::Quarantine);
+        assert_eq!(fills(&mut receiver), [1, 2, 4, 5]);
+
+        let mut expected = sealed(3);
+        expected.0[10] ^= 0x40;
+        assert_eq!(receiver.take_quarantined().unwrap().0, expected.0);
+        assert!(receiver.take_quarantined().is_none());
+        assert_eq!(receiver.stats().corrupted_skipped, 1);
+    }
+
+    #[test]
+    fn full_quarantine_drops_oldest() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().wire_features(WireFeatures::CRC));
+        port.set_corruption_policy(CorruptionPolicy::Quarantine);
+        for fill in 0..QUARANTINE_CAPACITY as u8 + 2 {
+            port.enqueue(Message([fill; SIZE])).unwrap();
+        }
+        assert!(matches!(port.try_dequeue(), Err(QueueError::EmptyBuffer)));
+        let kept: Vec<u8> = core::iter::from_fn(|| port.take_quarantined()).map(|m| m.0[0]).collect();
+        assert_eq!(kept, [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn ports_without_crc_are_not_checked() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([9; SIZE])).unwrap();
+        assert_eq!(port.dequeue().unwrap().0, [9; SIZE]);
+    }
+}
@@ -0,0 +1,152 @@
+//! Receiver-granted credits as a limit on enqueueing.
+//!
+//! A port created with `PortConfig::credit_limit` starts without credits.
+//! The receiver hands some out with `grant_credits`, and every enqueued
+//! message uses one up; with none left, enqueueing fails with
+//! `QueueError::NoCredit` even if slots are free. Credits live in the
+//! segment header, so the receiver and sender may be different processes.
+
+use core::sync::atomic::Ordering;
+
+use crate::{QueueingPort, MSGS};
+
+impl QueueingPort {
+    /// Whether the port was created in credit mode.
+    pub fn has_credit_limit(&self) -> bool {
+        self.segment().header.credit_limit.load(self.byte_order(), Ordering::Relaxed) != 0
+    }
+
+    /// Adds `n` credits, saturating at the port's credit limit, and returns
+    /// how many are now available. Does nothing, and returns 0, on a port
+    /// without credit mode.
+    pub fn grant_credits(&mut self, n: u32) -> u32 {
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let limit = header.credit_limit.load(order, Ordering::Relaxed);
+        if limit == 0 {
+            return 0;
+        }
+        // Several handles may grant at once, while the sender takes credits.
+        let mut current = header.credits.load(order, Ordering::Relaxed);
+        loop {
+            let granted = current.saturating_add(n).min(limit);
+            match header
+                .credits
+                .compare_exchange_weak(order, current, granted, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return granted,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Credits left for the sender, or `None` without credit mode.
+    pub fn credits_remaining(&self) -> Option<u32> {
+        self.has_credit_limit()
+            .then(|| self.segment().header.credits.load(self.byte_order(), Ordering::Acquire))
+    }
+
+    /// How many messages could be enqueued right now, the smaller of free
+    /// slots and credits, or 0 while the port waits for its receiver. Only
+    /// the receiver changes any of these, and only upwards, so the writer
+    /// can rely on the result.
+    pub(crate) fn room(&self) -> usize {
+        if !self.receiver_admits() {
+            return 0;
+        }
+        let free = MSGS - self.len().min(MSGS);
+        match self.credits_remaining() {
+            Some(credits) => free.min(credits as usize),
+            None => free,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, PortConfig, QueueError, Segment, SIZE};
+    use core::ptr::NonNull;
+    use std::time::Duration;
+
+    // The segments outlive the handles here.
+    fn credit_segment(limit: u32) -> NonNull<Segment> {
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut port = unsafe { QueueingPort::attach(segment) };
+        PortConfig::new().credit_limit(limit).apply(&mut port);
+        segment
+    }
+
+    #[test]
+    fn sender_without_credit_is_rejected() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().credit_limit(4));
+        assert_eq!(port.credits_remaining(), Some(0));
+        assert!(matches!(port.enqueue(Message([1; SIZE])), Err(QueueError::NoCredit)));
+        assert!(port.is_empty());
+        assert_eq!(port.stats().rejected, 0, "not a full-queue rejection");
+
+        assert_eq!(port.grant_credits(10), 4, "saturates at the limit");
+        for _ in 0..4 {
+            port.enqueue(Message([1; SIZE])).unwrap();
+        }
+        assert!(matches!(port.enqueue(Message([1; SIZE])), Err(QueueError::NoCredit)));
+        assert_eq!(port.credits_remaining(), Some(0));
+
+        // Batches are limited by credits as well.
+        port.grant_credits(2);
+        let (placed, leftover) = port.enqueue_from_iter((0..5).map(|i| Message([i; SIZE])));
+        assert_eq!((placed, leftover.map(|m| m.0[0])), (2, Some(2)));
+
+        let mut plain = QueueingPort::new();
+        assert_eq!((plain.credits_remaining(), plain.grant_credits(3)), (None, 0));
+    }
+
+    #[test]
+    fn grant_unblocks_waiting_sender() {
+        let segment = credit_segment(8);
+        let mut sender = unsafe { QueueingPort::attach(segment) };
+        let mut receiver = unsafe { QueueingPort::attach(segment) };
+
+        let granter = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            receiver.grant_credits(1);
+        });
+        assert!(sender.enqueue_by(Message([2; SIZE]), u64::MAX).is_ok());
+        granter.join().unwrap();
+        assert_eq!((sender.len(), sender.credits_remaining()), (1, Some(0)));
+    }
+
+    #[test]
+    fn racing_grants_respect_limit() {
+        const LIMIT: u32 = 50;
+        let segment = credit_segment(LIMIT);
+        let mut sender = unsafe { QueueingPort::attach(segment) };
+
+        let granters: Vec<_> = (0..4)
+            .map(|i| {
+                let mut granter = unsafe { QueueingPort::attach(segment) };
+                std::thread::spawn(move || {
+                    for n in 0..2000 {
+                        let now = granter.grant_credits(1 + (n + i) % 7);
+                        assert!(now <= LIMIT);
+                    }
+                })
+            })
+            .collect();
+        // Take credits meanwhile so the grants keep racing below the limit.
+        let mut taken = 0;
+        while granters.iter().any(|granter| !granter.is_finished()) {
+            assert!(sender.credits_remaining().unwrap() <= LIMIT);
+            if sender.enqueue(Message([0; SIZE])).is_ok() {
+                taken += 1;
+                sender.dequeue().unwrap();
+            }
+        }
+        for granter in granters {
+            granter.join().unwrap();
+        }
+        assert!(sender.credits_remaining().unwrap() <= LIMIT);
+        assert_eq!(sender.stats().enqueued, taken);
+        assert_eq!(sender.grant_credits(u32::MAX), LIMIT);
+    }
+}
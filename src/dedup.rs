@@ -0,0 +1,205 @@
+//! Receiver-side suppression of repeated messages.
+//!
+//! Like the type filter, the window belongs to a handle: it remembers the
+//! ids of the last few messages this handle delivered, and is forgotten
+//! when the handle is dropped.
+
+use core::cell::Cell;
+
+use crate::{QueueError, QueueingPort, SIZE};
+
+/// Most message ids a dedup window can remember.
+pub const DEDUP_WINDOW_CAPACITY: usize = 32;
+
+/// Where a message's id for de-duplication comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupKey {
+    /// The little-endian `u32` in bytes 0..4, where an envelope keeps its
+    /// message id. Retransmissions must reuse the id.
+    Envelope,
+    /// An FNV-1a hash of the whole message, for senders without ids. Two
+    /// identical messages are duplicates even if both were meant.
+    PayloadHash,
+}
+
+pub(crate) struct DedupWindow {
+    key: DedupKey,
+    /// Remembered ids, the newest at `next - 1` (wrapping at `len`).
+    ids: [Cell<u64>; DEDUP_WINDOW_CAPACITY],
+    len: usize,
+    next: Cell<usize>,
+    filled: Cell<usize>,
+    dropped: Cell<u32>,
+    /// The id the last admission overwrote and `filled` before it, for
+    /// `retract`.
+    last_admit: Cell<Option<(u64, usize)>>,
+}
+
+impl DedupWindow {
+    pub(crate) fn new() -> DedupWindow {
+        DedupWindow {
+            key: DedupKey::Envelope,
+            ids: core::array::from_fn(|_| Cell::new(0)),
+            len: 0,
+            next: Cell::new(0),
+            filled: Cell::new(0),
+            dropped: Cell::new(0),
+            last_admit: Cell::new(None),
+        }
+    }
+
+    /// Forgets the remembered ids, keeping the key and length.
+    pub(crate) fn forget(&self) {
+        self.next.set(0);
+        self.filled.set(0);
+        self.last_admit.set(None);
+    }
+
+    /// Whether the message in `slot` is new. New messages are remembered,
+    /// evicting the oldest id; repeats are counted as dropped.
+    pub(crate) fn admit(&self, slot: &[u8; SIZE]) -> bool {
+        if self.len == 0 {
+            return true;
+        }
+        let id = match self.key {
+            DedupKey::Envelope => u32::from_le_bytes([slot[0], slot[1], slot[2], slot[3]]).into(),
+            DedupKey::PayloadHash => fnv1a(slot),
+        };
+        if self.ids[..self.filled.get()].iter().any(|seen| seen.get() == id) {
+            self.dropped.set(self.dropped.get().wrapping_add(1));
+            self.last_admit.set(None);
+            return false;
+        }
+        let next = self.next.get();
+        self.last_admit.set(Some((self.ids[next].get(), self.filled.get())));
+        self.ids[next].set(id);
+        self.next.set((next + 1) % self.len);
+        self.filled.set((self.filled.get() + 1).min(self.len));
+        true
+    }
+
+    /// Undoes the last `admit` that returned true, as if that message had
+    /// never been seen. Only the last one can be undone.
+    #[cfg_attr(not(all(feature = "std", panic = "unwind")), allow(dead_code))]
+    pub(crate) fn retract(&self) {
+        if let Some((evicted, filled)) = self.last_admit.take() {
+            let next = (self.next.get() + self.len - 1) % self.len;
+            self.ids[next].set(evicted);
+            self.next.set(next);
+            self.filled.set(filled);
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> u32 {
+        self.dropped.get()
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME))
+}
+
+impl QueueingPort {
+    /// Makes `dequeue` discard, and count in `QueueStats::duplicates_dropped`,
+    /// any message whose id matches one of the last `len` delivered.
+    ///
+    /// A `len` of 0 turns de-duplication off. Either way the remembered ids
+    /// are forgotten. Fails with `WindowTooLarge`, leaving the current window
+    /// in place, beyond `DEDUP_WINDOW_CAPACITY`.
+    pub fn set_dedup_window(&mut self, len: usize, key: DedupKey) -> Result<(), QueueError> {
+        if len > DEDUP_WINDOW_CAPACITY {
+            return Err(QueueError::WindowTooLarge);
+        }
+        self.dedup.key = key;
+        self.dedup.len = len;
+        self.dedup.forget();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    fn with_id(id: u32, fill: u8) -> Message {
+        let mut bytes = [fill; SIZE];
+        bytes[..4].copy_from_slice(&id.to_le_bytes());
+        Message(bytes)
+    }
+
+    fn ids_delivered(port: &mut QueueingPort) -> Vec<u32> {
+        core::iter::from_fn(|| port.dequeue())
+            .map(|m| u32::from_le_bytes(m.0[..4].try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn fnv1a_known_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn exact_duplicate_is_suppressed() {
+        let mut port = QueueingPort::new();
+        port.set_dedup_window(4, DedupKey::Envelope).unwrap();
+        for id in [1, 2, 2, 3, 1] {
+            port.enqueue(with_id(id, 0)).unwrap();
+        }
+        assert_eq!(ids_delivered(&mut port), [1, 2, 3]);
+        assert_eq!(port.stats().duplicates_dropped, 2);
+        assert_eq!(port.stats().dequeued, 5);
+    }
+
+    #[test]
+    fn duplicate_outside_window_is_delivered() {
+        let mut port = QueueingPort::new();
+        port.set_dedup_window(2, DedupKey::Envelope).unwrap();
+        for id in [1, 2, 3, 1, 3] {
+            port.enqueue(with_id(id, 0)).unwrap();
+        }
+        // By the second 1, the window holds only 2 and 3.
+        assert_eq!(ids_delivered(&mut port), [1, 2, 3, 1]);
+        assert_eq!(port.stats().duplicates_dropped, 1);
+    }
+
+    #[test]
+    fn key_mode_ignores_payload_and_hash_mode_ignores_key() {
+        let mut port = QueueingPort::new();
+        port.set_dedup_window(8, DedupKey::Envelope).unwrap();
+        for (id, fill) in [(7, 1), (7, 2), (8, 1)] {
+            port.enqueue(with_id(id, fill)).unwrap();
+        }
+        assert_eq!(ids_delivered(&mut port), [7, 8], "same id, different payload");
+
+        port.set_dedup_window(8, DedupKey::PayloadHash).unwrap();
+        for (id, fill) in [(7, 1), (7, 2), (7, 1), (9, 1)] {
+            port.enqueue(with_id(id, fill)).unwrap();
+        }
+        assert_eq!(ids_delivered(&mut port), [7, 7, 9], "only the exact repeat is dropped");
+        assert_eq!(port.stats().duplicates_dropped, 2);
+    }
+
+    #[test]
+    fn window_can_be_turned_off() {
+        let mut port = QueueingPort::new();
+        port.set_dedup_window(4, DedupKey::Envelope).unwrap();
+        port.enqueue(with_id(1, 0)).unwrap();
+        port.enqueue(with_id(1, 0)).unwrap();
+        port.dequeue().unwrap();
+        port.set_dedup_window(0, DedupKey::Envelope).unwrap();
+        assert_eq!(ids_delivered(&mut port), [1]);
+        assert_eq!(port.stats().duplicates_dropped, 0);
+
+        assert!(matches!(
+            port.set_dedup_window(DEDUP_WINDOW_CAPACITY + 1, DedupKey::Envelope),
+            Err(QueueError::WindowTooLarge)
+        ));
+    }
+}
@@ -0,0 +1,350 @@
+//! Taking a named segment down once no handle uses it.
+//!
+//! Every handle on a named segment (sender, receiver, observer, or a
+//! `clone_reset` copy) holds one of the header's `ATTACH_SLOTS` attach
+//! slots, naming its process. A handle going away marks its slot
+//! `DETACHING` before its last operations on the segment, giving up its
+//! claims, and `DETACHED` after them. The segment's name is unlinked only
+//! by a handle that then finds every other slot detached, or held by a
+//! process that is no longer alive.
+//!
+//! Who may unlink is the `TeardownPolicy` the creator chose. Under
+//! `TeardownPolicy::Owner`, the default, the creator does when it leaves
+//! last. When it leaves first it dooms the segment instead: new opens fail
+//! with `PortError::SegmentDoomed`, and the handles still there carry on
+//! until the last of them, seeing the segment doomed, unlinks it. Under
+//! `TeardownPolicy::LastOut` the name stays openable until whichever handle
+//! leaves last unlinks it.
+//!
+//! Two handles leaving at once both see the other gone, so each sets the
+//! `DESTROYED` bit before unlinking and only the one that set it goes on;
+//! one that then finds a handle attached under it dooms the segment and
+//! leaves it to that one. A handle that was opening when the segment was
+//! doomed fails, and in leaving takes the segment down if it is the last.
+//! Slots and bits are all accessed sequentially consistently, so of a
+//! handle attaching or leaving and one leaving, at least one sees the
+//! other. A handle whose process died never leaves; its slot is reused by
+//! the next handle to find the table full, and on Linux it no longer
+//! counts as attached. Elsewhere only this process is known to be alive,
+//! and the mapping is the platform's to free anyway.
+
+#[cfg(feature = "shmem")]
+use core::sync::atomic::Ordering::SeqCst;
+
+use crate::byteorder::WireU32;
+#[cfg(feature = "shmem")]
+use crate::{Memory, PortError, QueueingPort, SegmentHeader};
+
+/// Handles a named segment can have at once.
+pub const ATTACH_SLOTS: usize = 8;
+
+// States of an attach slot.
+#[cfg(feature = "shmem")]
+const FREE: u32 = 0;
+#[cfg(feature = "shmem")]
+const ATTACHED: u32 = 1;
+#[cfg(feature = "shmem")]
+const DETACHING: u32 = 2;
+#[cfg(feature = "shmem")]
+const DETACHED: u32 = 3;
+
+// Bits of the header's `teardown` word.
+#[cfg(feature = "shmem")]
+const LAST_OUT: u32 = 1 << 0;
+#[cfg(feature = "shmem")]
+const DOOMED: u32 = 1 << 1;
+#[cfg(feature = "shmem")]
+const DESTROYED: u32 = 1 << 2;
+
+/// One handle's entry in the segment header.
+#[repr(C)]
+pub(crate) struct AttachSlot {
+    state: WireU32,
+    /// The handle's process; 0 while the slot is being taken or given up,
+    /// which counts as alive.
+    pid: WireU32,
+}
+
+impl AttachSlot {
+    pub(crate) const fn new() -> AttachSlot {
+        AttachSlot { state: WireU32::zero(), pid: WireU32::zero() }
+    }
+}
+
+/// Which handle unlinks a named segment; see the module documentation.
+#[cfg(feature = "shmem")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TeardownPolicy {
+    /// The creator, or the last handle out if it left first, and the
+    /// segment cannot be opened again once the creator has left.
+    #[default]
+    Owner,
+    /// Whichever handle leaves last.
+    LastOut,
+}
+
+#[cfg(feature = "shmem")]
+impl TeardownPolicy {
+    pub(crate) fn bits(self) -> u32 {
+        match self {
+            TeardownPolicy::Owner => 0,
+            TeardownPolicy::LastOut => LAST_OUT,
+        }
+    }
+}
+
+#[cfg(feature = "shmem")]
+impl SegmentHeader {
+    /// Whether the segment was doomed, or taken down.
+    fn is_doomed(&self) -> bool {
+        self.teardown.load(self.byte_order(), SeqCst) & (DOOMED | DESTROYED) != 0
+    }
+
+    /// Whether a handle other than the one in slot `mine` is attached.
+    fn others_attached(&self, mine: usize) -> bool {
+        let order = self.byte_order();
+        self.attach.iter().enumerate().any(|(index, slot)| {
+            index != mine
+                && matches!(slot.state.load(order, SeqCst), ATTACHED | DETACHING)
+                && alive(slot.pid.load(order, SeqCst))
+        })
+    }
+
+    /// Sets `DESTROYED` if no other handle than `mine` is attached, and
+    /// returns whether this call did.
+    fn claim_destruction(&self, mine: usize) -> bool {
+        let order = self.byte_order();
+        loop {
+            if self.others_attached(mine) || self.teardown.fetch_or(order, DESTROYED, SeqCst) & DESTROYED != 0 {
+                return false;
+            }
+            if !self.others_attached(mine) {
+                return true;
+            }
+            // One attached after the check; it takes the segment down, or
+            // this handle does if it left again meanwhile.
+            self.teardown.fetch_or(order, DOOMED, SeqCst);
+            self.teardown.fetch_and(order, !DESTROYED, SeqCst);
+        }
+    }
+}
+
+#[cfg(feature = "shmem")]
+fn alive(pid: u32) -> bool {
+    pid == 0 || crate::owner::process_alive(pid)
+}
+
+#[cfg(feature = "shmem")]
+impl QueueingPort {
+    /// Takes an attach slot for a handle on a named segment, once its byte
+    /// order is settled. Fails with `PortError::AttachTableFull` if every
+    /// slot is held by a live handle, and with `PortError::SegmentDoomed`
+    /// if the segment was doomed meanwhile.
+    pub(crate) fn take_attach_slot(&mut self) -> Result<(), PortError> {
+        let Memory::Named { buffer, handle } = &mut self.memory else { return Ok(()) };
+        if handle.attach.is_some() {
+            return Ok(());
+        }
+        let header = &buffer.segment().header;
+        let order = header.byte_order();
+        let pid = std::process::id();
+        let free = header.attach.iter().position(|slot| {
+            [FREE, DETACHED].into_iter().any(|state| {
+                slot.state.compare_exchange(order, state, ATTACHED, SeqCst, SeqCst).is_ok()
+            })
+        });
+        let mine = match free {
+            Some(index) => {
+                header.attach[index].pid.store(order, pid, SeqCst);
+                index
+            }
+            None => header
+                .attach
+                .iter()
+                .position(|slot| {
+                    let held = slot.pid.load(order, SeqCst);
+                    !alive(held) && slot.pid.compare_exchange(order, held, pid, SeqCst, SeqCst).is_ok()
+                })
+                .inspect(|&index| header.attach[index].state.store(order, ATTACHED, SeqCst))
+                .ok_or(PortError::AttachTableFull)?,
+        };
+        handle.attach = Some(mine);
+        // The unlinking is decided by the protocol from here on.
+        handle.shmem.set_owner(false);
+        if header.is_doomed() {
+            return Err(PortError::SegmentDoomed);
+        }
+        Ok(())
+    }
+
+    /// Fails with `PortError::SegmentDoomed` if the segment may not be
+    /// opened again.
+    pub(crate) fn check_not_doomed(&self) -> Result<(), PortError> {
+        if self.segment().header.is_doomed() {
+            return Err(PortError::SegmentDoomed);
+        }
+        Ok(())
+    }
+
+    /// Marks this handle as going away, before its last operations.
+    pub(crate) fn begin_detach(&self) {
+        if let Memory::Named { buffer, handle: crate::ShmemHandle { attach: Some(mine), .. } } = &self.memory {
+            let header = &buffer.segment().header;
+            header.attach[*mine].state.store(header.byte_order(), DETACHING, SeqCst);
+        }
+    }
+
+    /// Marks this handle as gone and unlinks the segment's name if the
+    /// protocol makes it this handle's to unlink, returning whether it
+    /// does; the name goes with the mapping, right after.
+    pub(crate) fn finish_detach(&mut self) -> bool {
+        let Memory::Named { buffer, handle } = &mut self.memory else { return false };
+        let Some(mine) = handle.attach.take() else { return false };
+        let header = &buffer.segment().header;
+        let order = header.byte_order();
+        let slot = &header.attach[mine];
+        slot.pid.store(order, 0, SeqCst);
+        slot.state.store(order, DETACHED, SeqCst);
+
+        let mut teardown = header.teardown.load(order, SeqCst);
+        if teardown & LAST_OUT == 0 && handle.creator {
+            teardown = header.teardown.fetch_or(order, DOOMED, SeqCst) | DOOMED;
+        }
+        let destroys = teardown & (LAST_OUT | DOOMED) != 0
+            && header.claim_destruction(mine)
+            && still_named(handle.shmem.get_os_id(), header);
+        handle.shmem.set_owner(destroys);
+        destroys
+    }
+
+    /// Detaches this handle, as dropping it does, and returns whether that
+    /// unlinked the segment's name. Always false for segments that are not
+    /// named.
+    pub fn detach(mut self) -> bool {
+        self.begin_detach();
+        self.release_claims();
+        self.finish_detach()
+    }
+}
+
+/// Whether the name `os_id` still refers to the segment of `header`,
+/// rather than having been removed, or created again, since.
+#[cfg(feature = "shmem")]
+fn still_named(os_id: &str, header: &SegmentHeader) -> bool {
+    let Ok(shmem) = crate::open_error::open_named(os_id, crate::SegmentGeometry::PORT) else { return false };
+    // Mapped, so the header may be read whatever its state.
+    let named = unsafe { &(*shmem.as_ptr().cast::<crate::Segment>()).header };
+    let generation = |header: &SegmentHeader| header.segment_generation.load(header.byte_order(), SeqCst);
+    generation(named) == generation(header)
+}
+
+#[cfg(all(test, feature = "shmem", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::{Message, PortConfig, QueueError, SIZE};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn port_name(test: &str) -> String {
+        format!("/qp_detach_{}_{}", test, std::process::id())
+    }
+
+    fn is_linked(name: &str) -> bool {
+        std::path::Path::new("/dev/shm").join(&name[1..]).exists()
+    }
+
+    /// A sender and receiver on `name`, created with `policy`.
+    fn connected(name: &str, policy: TeardownPolicy) -> (QueueingPort, QueueingPort) {
+        let creator = thread::spawn({
+            let name = name.to_owned();
+            move || QueueingPort::create_with_config(&name, &PortConfig::new().teardown(policy))
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let receiver = loop {
+            match QueueingPort::open(name) {
+                Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+                result => break result.unwrap(),
+            }
+        };
+        (creator.join().unwrap().unwrap(), receiver)
+    }
+
+    #[test]
+    fn owner_last_unlinks_at_once() {
+        let name = port_name("owner_last");
+        let (sender, receiver) = connected(&name, TeardownPolicy::Owner);
+        let observer = QueueingPort::open_observer(&name).unwrap();
+        assert!(!observer.detach());
+        assert!(!receiver.detach());
+        assert!(is_linked(&name));
+        assert!(sender.detach());
+        assert!(!is_linked(&name));
+    }
+
+    #[test]
+    fn owner_first_dooms_and_the_last_out_unlinks() {
+        let name = port_name("owner_first");
+        let (mut sender, mut receiver) = connected(&name, TeardownPolicy::Owner);
+        let observer = QueueingPort::open_observer(&name).unwrap();
+        sender.enqueue(Message([3; SIZE])).unwrap();
+        assert!(!sender.detach());
+
+        // The rest carry on, on a mapping that is still there.
+        assert!(is_linked(&name));
+        assert!(matches!(QueueingPort::open_observer(&name), Err(PortError::SegmentDoomed)));
+        assert!(matches!(QueueingPort::open(&name), Err(PortError::SegmentDoomed)));
+        assert_eq!(observer.peek_nth(0).unwrap().0, [3; SIZE]);
+        assert_eq!(receiver.dequeue().unwrap().0, [3; SIZE]);
+        assert!(matches!(receiver.try_dequeue(), Err(QueueError::EmptyBuffer)));
+        drop(receiver);
+        assert!(is_linked(&name), "the observer lingers");
+        drop(observer);
+        assert!(!is_linked(&name));
+    }
+
+    #[test]
+    fn last_out_keeps_the_name_openable() {
+        let name = port_name("last_out");
+        let (sender, receiver) = connected(&name, TeardownPolicy::LastOut);
+        assert!(!sender.detach());
+        let observer = QueueingPort::open_observer(&name).unwrap();
+        assert!(!receiver.detach());
+        assert!(is_linked(&name));
+        assert!(observer.detach());
+        assert!(!is_linked(&name));
+    }
+
+    #[test]
+    fn a_dead_peer_does_not_hold_the_segment() {
+        let name = port_name("dead_peer");
+        let (sender, receiver) = connected(&name, TeardownPolicy::Owner);
+        // The receiver's process is gone: reaped, so not even a zombie.
+        let dead = std::process::Command::new("true").spawn().unwrap();
+        let pid = dead.id();
+        dead.wait_with_output().unwrap();
+        let Memory::Named { handle, .. } = &receiver.memory else { unreachable!() };
+        let header = &receiver.segment().header;
+        header.attach[handle.attach.unwrap()].pid.store(header.byte_order(), pid, SeqCst);
+
+        assert!(sender.detach());
+        assert!(!is_linked(&name));
+        // Its mapping outlives the name.
+        assert!(receiver.is_empty());
+    }
+
+    #[test]
+    fn racing_leavers_unlink_exactly_once() {
+        for round in 0..20 {
+            let name = port_name(&format!("race_{}", round));
+            let (sender, receiver) = connected(&name, TeardownPolicy::LastOut);
+            let observers = [(); 3].map(|_| QueueingPort::open_observer(&name).unwrap());
+            let unlinked = thread::scope(|scope| {
+                let mut leavers = vec![scope.spawn(move || sender.detach()), scope.spawn(move || receiver.detach())];
+                leavers.extend(observers.map(|observer| scope.spawn(move || observer.detach())));
+                leavers.into_iter().map(|leaver| leaver.join().unwrap()).filter(|&unlinked| unlinked).count()
+            });
+            assert_eq!(unlinked, 1);
+            assert!(!is_linked(&name));
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Replicating a port to a backup by the slots that changed.
+//!
+//! A `SnapshotBlob` carries the whole queue, which is a lot to send to a
+//! backup on every update when only a message or two moved. A
+//! `QueueSnapshot` is a raw copy of the segment's slots, each with its
+//! sequence number, generation and enqueue time, and of the indices and
+//! statistics. `diff_snapshot` compares the port against an earlier one
+//! and returns a `DiffSnapshot` of just the slots that differ, and the
+//! header words as they are now; `apply_diff` writes those into a replica
+//! that was in the earlier state, which then matches the port.
+//!
+//! The settings `PortConfig` gave the port are not part of either, as they
+//! do not change once the port is created: the replica should be created
+//! from the same configuration. A snapshot taken while the peer is
+//! operating on the segment may mix states from before and after the
+//! peer's operation.
+
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use crate::snapshot::{stat_fields, STAT_COUNT};
+use crate::{QueueingPort, Segment, MSGS, SIZE};
+
+/// One slot's contents and its header words: sequence number, generation,
+/// and enqueue time, low word then high.
+type Slot = ([u8; SIZE], [u32; 4]);
+
+/// The raw state of a port, see the `diff` module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueSnapshot {
+    slots: Vec<Slot>,
+    indices: Indices,
+    stats: [u32; STAT_COUNT],
+}
+
+/// The slots of a port that changed since a `QueueSnapshot`, and its
+/// indices now; see the `diff` module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffSnapshot {
+    /// The index and contents of each slot that changed, in slot order.
+    pub changed_slots: Vec<(usize, [u8; SIZE])>,
+    pub write_index: u32,
+    pub read_index: u32,
+    pub message_count: u32,
+    /// The header words of the changed slots, in the same order.
+    slot_words: Vec<[u32; 4]>,
+    stats: [u32; STAT_COUNT],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Indices {
+    write_index: u32,
+    read_index: u32,
+    message_count: u32,
+}
+
+fn read_slot(segment: &Segment, index: usize) -> Slot {
+    let header = &segment.header;
+    let order = header.byte_order();
+    let mut bytes = [0; SIZE];
+    // As in `snapshot`, a slot the peer is writing reads as some mix.
+    unsafe { core::ptr::copy_nonoverlapping(segment.slot(index), bytes.as_mut_ptr(), SIZE) };
+    let words = [
+        header.slot_sequence[index].load(order, Ordering::Relaxed),
+        header.slot_generation[index].load(order, Ordering::Relaxed),
+        header.slot_time_low[index].load(order, Ordering::Relaxed),
+        header.slot_time_high[index].load(order, Ordering::Relaxed),
+    ];
+    (bytes, words)
+}
+
+impl QueueingPort {
+    /// A raw copy of the port's segment to compute a `diff_snapshot`
+    /// against later.
+    pub fn queue_snapshot(&self) -> QueueSnapshot {
+        let segment = self.segment();
+        // The count first: slots it covers were published before it.
+        let indices = self.indices();
+        QueueSnapshot {
+            slots: (0..MSGS).map(|index| read_slot(segment, index)).collect(),
+            indices,
+            stats: stat_fields(segment).map(|field| field.load(segment.header.byte_order(), Ordering::Relaxed)),
+        }
+    }
+
+    /// The slots that changed since `previous` was taken, and the header
+    /// words now.
+    pub fn diff_snapshot(&self, previous: &QueueSnapshot) -> DiffSnapshot {
+        let now = self.queue_snapshot();
+        let (changed_slots, slot_words) = now
+            .slots
+            .into_iter()
+            .enumerate()
+            .filter(|(index, slot)| previous.slots[*index] != *slot)
+            .map(|(index, (bytes, words))| ((index, bytes), words))
+            .unzip();
+        DiffSnapshot {
+            changed_slots,
+            write_index: now.indices.write_index,
+            read_index: now.indices.read_index,
+            message_count: now.indices.message_count,
+            slot_words,
+            stats: now.stats,
+        }
+    }
+
+    /// Brings a replica from the state `diff` was computed against to the
+    /// state of the port it was computed on. Neither end of the replica
+    /// may be in use meanwhile.
+    pub fn apply_diff(&mut self, diff: &DiffSnapshot) {
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        for (&(index, ref bytes), words) in diff.changed_slots.iter().zip(&diff.slot_words) {
+            // No handle is using the replica.
+            unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), segment.slot(index), SIZE) };
+            header.slot_sequence[index].store(order, words[0], Ordering::Relaxed);
+            header.slot_generation[index].store(order, words[1], Ordering::Relaxed);
+            header.slot_time_low[index].store(order, words[2], Ordering::Relaxed);
+            header.slot_time_high[index].store(order, words[3], Ordering::Relaxed);
+        }
+        for (field, value) in stat_fields(segment).iter().zip(diff.stats) {
+            field.store(order, value, Ordering::Relaxed);
+        }
+        header.write_index.store(order, diff.write_index, Ordering::Relaxed);
+        header.read_index.store(order, diff.read_index, Ordering::Relaxed);
+        header.message_count.store(order, diff.message_count, Ordering::Release);
+    }
+
+    fn indices(&self) -> Indices {
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let message_count = header.message_count.load(order, Ordering::Acquire);
+        Indices {
+            write_index: header.write_index.load(order, Ordering::Relaxed),
+            read_index: header.read_index.load(order, Ordering::Relaxed),
+            message_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, QueueError};
+
+    #[test]
+    fn three_enqueues_change_three_slots() {
+        let (mut source, mut replica) = (QueueingPort::new(), QueueingPort::new());
+        let base = source.queue_snapshot();
+        assert_eq!(base, replica.queue_snapshot());
+        for tag in 1..=3u8 {
+            source.enqueue(Message([tag; SIZE])).unwrap();
+        }
+        let diff = source.diff_snapshot(&base);
+        assert_eq!(diff.changed_slots.iter().map(|&(index, _)| index).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!((diff.write_index, diff.read_index, diff.message_count), (3, 0, 3));
+
+        replica.apply_diff(&diff);
+        assert_eq!(replica.queue_snapshot(), source.queue_snapshot());
+        assert_eq!(replica.stats(), source.stats());
+        assert_eq!(replica.check_invariants(), Ok(()));
+
+        // On from there: one dequeued, one more enqueued.
+        let base = source.queue_snapshot();
+        assert_eq!(source.dequeue().unwrap().0, [1; SIZE]);
+        source.enqueue(Message([4; SIZE])).unwrap();
+        let diff = source.diff_snapshot(&base);
+        assert_eq!(diff.changed_slots.iter().map(|&(index, _)| index).collect::<Vec<_>>(), [0, 3]);
+        replica.apply_diff(&diff);
+        assert_eq!(replica.queue_snapshot(), source.queue_snapshot());
+        for tag in 2..=4u8 {
+            assert_eq!(replica.dequeue().unwrap().0, [tag; SIZE]);
+        }
+        assert!(matches!(replica.try_dequeue(), Err(QueueError::EmptyBuffer)));
+    }
+
+    #[test]
+    fn an_unchanged_port_has_an_empty_diff() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([1; SIZE])).unwrap();
+        let diff = port.diff_snapshot(&port.queue_snapshot());
+        assert!(diff.changed_slots.is_empty());
+        assert_eq!(diff.message_count, 1);
+    }
+}
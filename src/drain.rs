@@ -0,0 +1,106 @@
+//! Draining a port into a caller-owned collection.
+//!
+//! Each message is dequeued on its own, so the peer is never held off for
+//! longer than a single dequeue while a drain is running.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::{Message, QueueingPort};
+
+impl QueueingPort {
+    /// Appends every queued message to `out`, returning how many were moved.
+    ///
+    /// Space for the messages queued at the start is reserved up front;
+    /// messages enqueued during the drain are picked up as well.
+    #[cfg(feature = "alloc")]
+    pub fn dequeue_all_into(&mut self, out: &mut Vec<Message>) -> usize {
+        out.reserve(self.len());
+        let mut moved = 0;
+        while let Ok(message) = self.try_dequeue() {
+            out.push(message);
+            moved += 1;
+        }
+        moved
+    }
+
+    /// Moves queued messages into `out` until the port is empty or `out` is
+    /// full.
+    ///
+    /// Returns how many messages were moved and whether any are still
+    /// queued. A message is only dequeued once there is room for it, so
+    /// stopping on a full `out` never drops one.
+    #[cfg(feature = "heapless")]
+    pub fn dequeue_all_into_heapless<const N: usize>(
+        &mut self,
+        out: &mut heapless::Vec<Message, N>,
+    ) -> (usize, bool) {
+        let mut moved = 0;
+        while !out.is_full() {
+            match self.try_dequeue() {
+                Ok(message) => {
+                    // Cannot fail: we checked for room above.
+                    let _ = out.push(message);
+                    moved += 1;
+                }
+                Err(_) => return (moved, false),
+            }
+        }
+        (moved, !self.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+
+    fn port_with(count: u8) -> QueueingPort {
+        let mut port = QueueingPort::new();
+        for i in 0..count {
+            port.enqueue(Message([i; SIZE])).unwrap();
+        }
+        port
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn drain_into_vec() {
+        let mut port = port_with(4);
+        let mut out = Vec::new();
+        assert_eq!(port.dequeue_all_into(&mut out), 4);
+        assert!(port.is_empty());
+        assert!(out.iter().enumerate().all(|(i, m)| m.0 == [i as u8; SIZE]));
+        assert_eq!(port.dequeue_all_into(&mut out), 0);
+        assert_eq!(out.len(), 4);
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn drain_into_exactly_sized_heapless_vec() {
+        let mut port = port_with(3);
+        let mut out = heapless::Vec::<Message, 3>::new();
+        assert_eq!(port.dequeue_all_into_heapless(&mut out), (3, false));
+        assert!(port.is_empty());
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn drain_into_undersized_heapless_vec_keeps_the_rest() {
+        let mut port = port_with(5);
+        let mut out = heapless::Vec::<Message, 2>::new();
+        assert_eq!(port.dequeue_all_into_heapless(&mut out), (2, true));
+        assert_eq!(out[1].0, [1; SIZE]);
+        assert_eq!(port.len(), 3);
+        assert_eq!(port.dequeue().unwrap().0, [2; SIZE]);
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn drain_empty_port_into_heapless_vec() {
+        let mut port = QueueingPort::new();
+        let mut out = heapless::Vec::<Message, 4>::new();
+        assert_eq!(port.dequeue_all_into_heapless(&mut out), (0, false));
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+//! Closing a port with a final summary message.
+//!
+//! `close_with_summary` appends an end-of-stream record and closes the
+//! port, so the stages of a pipeline can pass completion on without a
+//! separate signal. The record is a message laid out as a `MessageHeader`,
+//! little endian:
+//!
+//! ```text
+//! 0..4   zero (src_id, dst_id)
+//! 4..6   END_OF_STREAM_MSG_TYPE
+//! 6..8   flags, with END_OF_STREAM_FLAG set
+//! 8..10  summary length
+//! 10..   summary, zero-filled to the end of the slot
+//! ```
+//!
+//! It is recognised by both the type and the flag; ordinary messages
+//! should not use the reserved type. On a CRC port it is sealed like any
+//! other message, which is why the summary stops short of the trailer.
+//!
+//! The record needs a free slot like any message: on a full queue
+//! `close_with_summary` fails with `QueueError::FullBuffer` and leaves the
+//! port open, for the sender to try again once the reader has caught up.
+//! Making room would mean consuming from the sender's handle, racing the
+//! reader. A broadcast port, whose publisher drops the oldest message
+//! anyway, writes the record in place of it, counted in
+//! `QueueStats::evicted_on_close`, and so does a handle set to
+//! `OverflowPolicy::DropOldest`. Once the port is closed, enqueues fail with `QueueError::Closed`, and
+//! so do dequeues once the record and everything before it are consumed.
+//! Broadcast subscribers each read the record once, as they read every
+//! message, and go past the type mask for it.
+
+use core::sync::atomic::Ordering;
+
+use crate::{
+    Broadcast, Message, MessageHeader, OverflowPolicy, Port, QueueError, QueueingPort, Subscriber, WireFeatures, CLOSED,
+    CRC_PAYLOAD, MSGS, SIZE,
+};
+
+/// Message type id reserved for end-of-stream records.
+pub const END_OF_STREAM_MSG_TYPE: u16 = 0xfffe;
+/// `MessageHeader::flags` bit marking an end-of-stream record.
+pub const END_OF_STREAM_FLAG: u16 = 1 << 15;
+/// Most bytes a summary can hold.
+pub const SUMMARY_CAPACITY: usize = CRC_PAYLOAD - SUMMARY_OFFSET;
+
+const SUMMARY_OFFSET: usize = 10;
+
+/// A dequeued message, told apart from the end-of-stream record and from
+/// signals.
+#[derive(Debug)]
+pub enum Received {
+    Message(Message),
+    EndOfStream(StreamSummary),
+    /// See the `signal` module.
+    Signal,
+}
+
+impl From<Message> for Received {
+    fn from(message: Message) -> Received {
+        if is_end_of_stream(&message.0) {
+            Received::EndOfStream(StreamSummary(message))
+        } else if message.is_signal() {
+            Received::Signal
+        } else {
+            Received::Message(message)
+        }
+    }
+}
+
+/// The end-of-stream record, see `close_with_summary`.
+#[derive(Debug)]
+pub struct StreamSummary(Message);
+
+impl StreamSummary {
+    /// The summary passed to `close_with_summary`.
+    pub fn payload(&self) -> &[u8] {
+        let len = u16::from_le_bytes([self.0 .0[8], self.0 .0[9]]) as usize;
+        &self.0 .0[SUMMARY_OFFSET..SUMMARY_OFFSET + len.min(SUMMARY_CAPACITY)]
+    }
+}
+
+pub(crate) fn is_end_of_stream(slot: &[u8; SIZE]) -> bool {
+    let header = MessageHeader::read(slot);
+    header.msg_type == END_OF_STREAM_MSG_TYPE && header.flags & END_OF_STREAM_FLAG != 0
+}
+
+impl Message {
+    /// Whether this is the end-of-stream record of a closed port.
+    pub fn is_end_of_stream(&self) -> bool {
+        is_end_of_stream(&self.0)
+    }
+}
+
+impl QueueingPort {
+    /// Appends an end-of-stream record carrying `summary` and closes the
+    /// port; see the module documentation. Fails with
+    /// `QueueError::MessageTooLarge` beyond `SUMMARY_CAPACITY` bytes, with
+    /// `QueueError::FullBuffer` if there is no room and the handle does not
+    /// drop the oldest, and otherwise like
+    /// `enqueue`, with `QueueError::Closed` if the port is closed already.
+    pub fn close_with_summary(&mut self, summary: &[u8]) -> Result<(), QueueError> {
+        self.append_summary(summary, || match self.overflow_policy() {
+            OverflowPolicy::DropOldest => self.evict_unpinned(),
+            OverflowPolicy::Reject => self.handle_overflow(),
+        })
+    }
+
+    /// Like `dequeue`, telling the end-of-stream record and signals apart.
+    pub fn receive(&mut self) -> Result<Received, QueueError> {
+        self.try_dequeue().map(Received::from)
+    }
+
+    /// Whether the port was closed, by `close_with_summary` or `close`.
+    pub fn is_closed(&self) -> bool {
+        self.segment().header.state.load(Ordering::Acquire) == CLOSED
+    }
+
+    /// Writes the record, making room with `evict` if the queue is full,
+    /// then marks the port closed.
+    fn append_summary(&self, summary: &[u8], evict: impl FnOnce() -> Result<(), QueueError>) -> Result<(), QueueError> {
+        if summary.len() > SUMMARY_CAPACITY {
+            return Err(QueueError::MessageTooLarge { len: summary.len() });
+        }
+        let _hold = self.admit_enqueue()?;
+        let segment = self.segment();
+        let header = &segment.header;
+        if header.message_count.load(header.byte_order(), Ordering::Acquire) as usize >= MSGS {
+            evict()?;
+            self.evicted_on_close.set(self.evicted_on_close.get() + 1);
+        }
+        let crc = self.wire_features().contains(WireFeatures::CRC);
+        segment.fill_free_slot(0, |slot| {
+            let mut record = Message([0; SIZE]);
+            MessageHeader { msg_type: END_OF_STREAM_MSG_TYPE, flags: END_OF_STREAM_FLAG, ..Default::default() }
+                .write(&mut record.0);
+            record.0[8..10].copy_from_slice(&(summary.len() as u16).to_le_bytes());
+            record.0[SUMMARY_OFFSET..SUMMARY_OFFSET + summary.len()].copy_from_slice(summary);
+            if crc {
+                record.seal_crc();
+            }
+            *slot = record.0;
+        });
+        segment.publish(1, self.enqueue_time(), self.clock());
+        // After the record: a reader that sees the port closed sees it too.
+        header.state.store(CLOSED, Ordering::Release);
+        self.wake_reader();
+        Ok(())
+    }
+}
+
+impl Port<Broadcast> {
+    /// Publishes an end-of-stream record carrying `summary` and closes the
+    /// port, as `QueueingPort::close_with_summary`. Every subscriber reads
+    /// the record once, after the messages before it.
+    pub fn close_with_summary(&self, summary: &[u8]) -> Result<(), QueueError> {
+        let core = self.inner();
+        core.append_summary(summary, || core.evict_unpinned())
+    }
+}
+
+impl Subscriber<'_> {
+    /// Like `recv`, telling the end-of-stream record and signals apart.
+    pub fn receive(&mut self) -> Result<Received, QueueError> {
+        self.recv().map(Received::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CorruptionPolicy, PortConfig};
+
+    fn message(tag: u8) -> Message {
+        Message([tag; SIZE])
+    }
+
+    fn summary_of(received: Received) -> StreamSummary {
+        match received {
+            Received::EndOfStream(summary) => summary,
+            Received::Message(message) => panic!("an ordinary message {:?}", &message.0[..8]),
+            Received::Signal => panic!("a signal"),
+        }
+    }
+
+    #[test]
+    fn summary_follows_pending_messages() {
+        let mut port = QueueingPort::new();
+        for tag in 1..=3 {
+            port.enqueue(message(tag)).unwrap();
+        }
+        port.close_with_summary(b"3 records").unwrap();
+        assert!(port.is_closed());
+        assert!(matches!(port.enqueue(message(4)), Err(QueueError::Closed)));
+        assert!(matches!(port.close_with_summary(b"again"), Err(QueueError::Closed)));
+
+        for tag in 1..=3 {
+            match port.receive().unwrap() {
+                Received::Message(received) => assert_eq!(received.0, [tag; SIZE]),
+                Received::EndOfStream(_) | Received::Signal => panic!("summary before message {}", tag),
+            }
+        }
+        assert_eq!(summary_of(port.receive().unwrap()).payload(), b"3 records");
+        assert!(matches!(port.try_dequeue(), Err(QueueError::Closed)));
+        assert!(matches!(port.receive(), Err(QueueError::Closed)));
+        assert_eq!(port.stats().evicted_on_close, 0);
+    }
+
+    #[test]
+    fn summary_on_a_full_queue_waits_for_room() {
+        let mut port = QueueingPort::new();
+        for tag in 0..MSGS as u8 {
+            port.enqueue(message(tag)).unwrap();
+        }
+        assert!(matches!(port.close_with_summary(&[0xab; SUMMARY_CAPACITY]), Err(QueueError::FullBuffer)));
+        assert!(!port.is_closed());
+        let stats = port.stats();
+        assert_eq!((port.len(), stats.rejected, stats.evicted_on_close), (MSGS, 1, 0));
+
+        assert_eq!(port.dequeue().unwrap().0, [0; SIZE]);
+        port.close_with_summary(&[0xab; SUMMARY_CAPACITY]).unwrap();
+        for tag in 1..MSGS as u8 {
+            assert_eq!(port.dequeue().unwrap().0, [tag; SIZE]);
+        }
+        let end = port.dequeue().unwrap();
+        assert!(end.is_end_of_stream());
+        assert_eq!(summary_of(end.into()).payload(), &[0xab; SUMMARY_CAPACITY][..]);
+        assert!(matches!(port.try_dequeue(), Err(QueueError::Closed)));
+
+        let mut port = QueueingPort::new();
+        let too_long = [0; SUMMARY_CAPACITY + 1];
+        assert!(matches!(port.close_with_summary(&too_long), Err(QueueError::MessageTooLarge { .. })));
+        assert!(!port.is_closed());
+    }
+
+    #[test]
+    fn summary_passes_type_filters_and_crc_checks() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().wire_features(WireFeatures::CRC));
+        port.set_corruption_policy(CorruptionPolicy::Halt);
+        port.set_type_filter(&[7]).unwrap();
+        port.close_with_summary(b"done").unwrap();
+        assert_eq!(summary_of(port.receive().unwrap()).payload(), b"done");
+        assert_eq!(port.stats().filtered_out, 0);
+    }
+
+    #[test]
+    fn every_subscriber_sees_the_summary_once() {
+        let port = Port::<Broadcast>::new();
+        let mut early = port.subscribe();
+        for tag in 0..4 {
+            port.publish(message(tag)).unwrap();
+        }
+        let mut late = port.subscribe();
+        let mut typed = port.subscribe();
+        typed.subscribe_types(&[1]).unwrap();
+        port.close_with_summary(b"eos").unwrap();
+        assert!(matches!(port.publish(message(9)), Err(QueueError::Closed)));
+
+        for tag in 0..4 {
+            assert_eq!(early.recv().unwrap().0, [tag; SIZE]);
+        }
+        for subscriber in [&mut early, &mut late, &mut typed] {
+            assert_eq!(summary_of(subscriber.receive().unwrap()).payload(), b"eos");
+            assert!(matches!(subscriber.receive(), Err(QueueError::Closed)));
+            assert!(matches!(subscriber.recv(), Err(QueueError::Closed)));
+        }
+        assert!(matches!(port.subscribe().recv(), Err(QueueError::Closed)));
+    }
+
+    #[test]
+    fn broadcast_summary_on_a_full_port_lags_the_slow_subscriber() {
+        let port = Port::<Broadcast>::new();
+        let mut slow = port.subscribe();
+        for tag in 0..MSGS as u8 {
+            port.publish(message(tag)).unwrap();
+        }
+        port.close_with_summary(b"").unwrap();
+        assert_eq!(port.inner().stats().evicted_on_close, 1);
+        assert!(matches!(slow.recv(), Err(QueueError::Lagged { missed: 1 })));
+        for tag in 1..MSGS as u8 {
+            assert_eq!(slow.recv().unwrap().0, [tag; SIZE]);
+        }
+        assert_eq!(summary_of(slow.receive().unwrap()).payload(), b"");
+        assert!(matches!(slow.recv(), Err(QueueError::Closed)));
+    }
+}
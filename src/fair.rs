@@ -0,0 +1,221 @@
+//! Round-robin dequeue across the sources of an MPSC port.
+//!
+//! Several senders feeding one port share its slots first come, first
+//! served, so a chatty one can keep the queue full of its own messages
+//! while a quiet one's wait behind them. `dequeue_fair` serves the sources
+//! in turn instead: it looks at the `src_id` of the queued messages, in the
+//! place a `MessageHeader` keeps it, and takes the oldest message of the
+//! source that comes after the one served last, in the cyclic order of
+//! their ids.
+//!
+//! The look-ahead examines no more messages than the skip budget allows
+//! and tells at most `FAIR_SOURCE_CAPACITY` sources apart; sources beyond
+//! those wait for their messages to move into view. The chosen message is
+//! moved to the front of the queue, the ones before it shifting back one
+//! slot in their order, and dequeued from there as `dequeue` would. Moving
+//! occupied slots is the reader's privilege, like freeing them; an
+//! `Observer` or `dequeue_shared` copying a moved slot sees its generation
+//! change.
+
+use core::sync::atomic::Ordering;
+
+use crate::{slot_index, trace, DeliveryOrder, Message, QueueError, QueueingPort, MSGS, SIZE};
+
+/// Most distinct sources one `dequeue_fair` call tells apart.
+pub const FAIR_SOURCE_CAPACITY: usize = 8;
+
+fn source_of(slot: &[u8; SIZE]) -> u16 {
+    u16::from_le_bytes([slot[0], slot[1]])
+}
+
+impl QueueingPort {
+    /// Like `dequeue`, taking the oldest message of the next source in turn
+    /// rather than the oldest message. When only one source has messages in
+    /// view, on the first call, and on a `DeliveryOrder::StrictFifo` port,
+    /// this is plain FIFO.
+    pub fn dequeue_fair(&mut self) -> Result<Message, QueueError> {
+        let (source, message) = trace::dequeue(self, |port| {
+            port.bring_next_source_forward();
+            port.consume_filtered(
+                |msg_type| port.type_filter.allows(msg_type),
+                |slot| (source_of(slot), Message(*slot)),
+            )
+        })?;
+        self.last_source = Some(source);
+        Ok(message)
+    }
+
+    /// Moves the oldest message of the source after `last_source` to the
+    /// front of the queue.
+    fn bring_next_source_forward(&self) {
+        let Some(last) = self.last_source else { return };
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        header.wait_while_compacting();
+        if header.delivery_order.load(Ordering::Relaxed) == DeliveryOrder::StrictFifo as u8 {
+            return;
+        }
+        let count = header.message_count.load(order, Ordering::Acquire) as usize;
+        let read_index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize);
+
+        // Each source seen, with its oldest message's place in the queue.
+        let mut sources = [(0u16, 0usize); FAIR_SOURCE_CAPACITY];
+        let mut len = 0;
+        for ahead in 0..count.min(MSGS).min(self.skip_budget) {
+            // Occupied slots belong to the reader.
+            let slot = unsafe { &*segment.slot(slot_index(read_index + ahead)).cast::<[u8; SIZE]>() };
+            let source = source_of(slot);
+            if len < FAIR_SOURCE_CAPACITY && !sources[..len].iter().any(|&(seen, _)| seen == source) {
+                sources[len] = (source, ahead);
+                len += 1;
+            }
+        }
+        // The next id after `last`, wrapping; `last` itself comes last.
+        let next = sources[..len]
+            .iter()
+            .min_by_key(|&&(source, _)| source.wrapping_sub(last).wrapping_sub(1));
+        if let Some(&(_, ahead)) = next.filter(|_| len > 1) {
+            self.move_to_front(read_index, ahead);
+        }
+    }
+
+    /// Moves the message `ahead` places after `read_index` to `read_index`,
+    /// and the ones in between back by one slot.
+    pub(crate) fn move_to_front(&self, read_index: usize, ahead: usize) {
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        let index = |offset: usize| slot_index(read_index + offset);
+        let words = [&header.slot_sequence, &header.slot_time_low, &header.slot_time_high];
+        let mut chosen = [0; SIZE];
+        // The slots are occupied, so only this reader touches them.
+        unsafe { core::ptr::copy_nonoverlapping(segment.slot(index(ahead)), chosen.as_mut_ptr(), SIZE) };
+        let chosen_words = words.map(|words| words[index(ahead)].load(order, Ordering::Relaxed));
+        let chosen_pinned = header.is_pinned(index(ahead));
+        for offset in (0..ahead).rev() {
+            let (from, to) = (index(offset), index(offset + 1));
+            unsafe { core::ptr::copy_nonoverlapping(segment.slot(from), segment.slot(to), SIZE) };
+            for words in words {
+                words[to].store(order, words[from].load(order, Ordering::Relaxed), Ordering::Relaxed);
+            }
+            header.set_pinned(to, header.is_pinned(from));
+            header.slot_generation[to].fetch_add(order, 1, Ordering::Relaxed);
+        }
+        unsafe { core::ptr::copy_nonoverlapping(chosen.as_ptr(), segment.slot(read_index), SIZE) };
+        for (words, word) in words.iter().zip(chosen_words) {
+            words[read_index].store(order, word, Ordering::Relaxed);
+        }
+        header.set_pinned(read_index, chosen_pinned);
+        header.slot_generation[read_index].fetch_add(order, 1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageHeader, PortConfig};
+
+    fn from(source: u16, tag: u8) -> Message {
+        let mut bytes = [tag; SIZE];
+        MessageHeader { src_id: source, ..MessageHeader::default() }.write(&mut bytes);
+        Message(bytes)
+    }
+
+    fn port_with(messages: &[(u16, u8)]) -> QueueingPort {
+        let mut port = QueueingPort::new();
+        for &(source, tag) in messages {
+            port.enqueue(from(source, tag)).unwrap();
+        }
+        port
+    }
+
+    fn delivered(message: Message) -> (u16, u8) {
+        (message.header().src_id, message.0[8])
+    }
+
+    fn drain(port: &mut QueueingPort, dequeue: fn(&mut QueueingPort) -> Result<Message, QueueError>) -> Vec<(u16, u8)> {
+        core::iter::from_fn(|| dequeue(port).ok()).map(delivered).collect()
+    }
+
+    #[test]
+    fn fair_mode_alternates_while_both_have_backlog() {
+        let mut port = port_with(&[(1, 0), (1, 1), (1, 2), (1, 3), (1, 4), (1, 5), (2, 0), (2, 1), (2, 2)]);
+        let received = drain(&mut port, QueueingPort::dequeue_fair);
+        assert_eq!(
+            received,
+            [(1, 0), (2, 0), (1, 1), (2, 1), (1, 2), (2, 2), (1, 3), (1, 4), (1, 5)],
+            "alternating, then FIFO, each source in its own order"
+        );
+        assert_eq!(port.stats().dequeued, 9);
+    }
+
+    fn ten_to_one_sends() -> Vec<(u16, u8)> {
+        (0..3u8)
+            .flat_map(|round| (0..10).map(move |i| (1, round * 10 + i)).chain([(2, round)]))
+            .collect()
+    }
+
+    /// Source 1 sends ten messages for every one of source 2's, as fast as
+    /// the port has room, while the receiver takes one message at a time.
+    /// Returns what was delivered and how many other messages each of
+    /// source 2's waited behind.
+    fn ten_to_one(dequeue: fn(&mut QueueingPort) -> Result<Message, QueueError>) -> (Vec<(u16, u8)>, Vec<usize>) {
+        let sends = ten_to_one_sends();
+        let mut port = QueueingPort::new();
+        let (mut next, mut received, mut waits, mut queued_at) = (0, Vec::new(), Vec::new(), Vec::new());
+        while next < sends.len() || !port.is_empty() {
+            while next < sends.len() && port.len() < MSGS {
+                let (source, tag) = sends[next];
+                port.enqueue(from(source, tag)).unwrap();
+                if source == 2 {
+                    queued_at.push(received.len());
+                }
+                next += 1;
+            }
+            let message = delivered(dequeue(&mut port).unwrap());
+            if message.0 == 2 {
+                waits.push(received.len() - queued_at[message.1 as usize]);
+            }
+            received.push(message);
+        }
+        (received, waits)
+    }
+
+    #[test]
+    fn quiet_source_is_served_next_at_ten_to_one() {
+        let (received, waits) = ten_to_one(QueueingPort::dequeue_fair);
+        assert_eq!(waits, [0, 0, 0], "taken by the first dequeue after it arrived");
+        let ones: Vec<u8> = received.iter().filter(|m| m.0 == 1).map(|m| m.1).collect();
+        assert_eq!(ones, (0..30).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn plain_mode_keeps_arrival_order() {
+        let (received, waits) = ten_to_one(QueueingPort::try_dequeue);
+        assert!(waits.iter().all(|&wait| wait == MSGS - 1), "behind a full queue: {:?}", waits);
+        assert_eq!(received, ten_to_one_sends());
+    }
+
+    #[test]
+    fn look_ahead_is_bounded_by_the_skip_budget() {
+        let mut messages: Vec<(u16, u8)> = (0..9).map(|i| (1, i)).collect();
+        messages.push((2, 0));
+        let mut port = port_with(&messages);
+        port.set_skip_budget(5);
+        let sources: Vec<u16> = drain(&mut port, QueueingPort::dequeue_fair).iter().map(|m| m.0).collect();
+        // Source 2's message only comes into view once four of source 1's
+        // are left ahead of it.
+        assert_eq!(sources, [1, 1, 1, 1, 1, 2, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn strict_fifo_ports_are_not_reordered() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().delivery_order(DeliveryOrder::StrictFifo));
+        for message in [from(1, 0), from(1, 1), from(2, 2)] {
+            port.enqueue(message).unwrap();
+        }
+        let tags: Vec<u8> = drain(&mut port, QueueingPort::dequeue_fair).iter().map(|m| m.1).collect();
+        assert_eq!(tags, [0, 1, 2]);
+    }
+}
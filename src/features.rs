@@ -0,0 +1,164 @@
+//! Optional wire features recorded in the segment header.
+//!
+//! The creator of a port stores the features its messages use in the
+//! header's `features` word. Bits 0..16 are *required*: a peer that does not
+//! know one of them cannot make sense of the slots and `open()` refuses the
+//! port. Bits 16..32 are *optional*: they add information a peer may ignore,
+//! so an opener that does not know them still gets a port, and
+//! `negotiated_features()` tells it what it is actually using.
+//!
+//! Bit assignments are part of the wire format and never change; new
+//! features take new bits.
+
+use core::ops::{BitAnd, BitOr};
+use core::sync::atomic::Ordering;
+
+use crate::{QueueingPort, SegmentHeader};
+
+/// A set of wire features, see the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WireFeatures(u32);
+
+impl WireFeatures {
+    /// Messages start with an envelope header.
+    pub const ENVELOPE: WireFeatures = WireFeatures(1 << 0);
+    /// Messages end in a CRC over their payload.
+    pub const CRC: WireFeatures = WireFeatures(1 << 1);
+    /// Messages carry their own length and may be shorter than `SIZE`.
+    pub const VARIABLE_LENGTH: WireFeatures = WireFeatures(1 << 2);
+    /// Messages may be compressed, see the `compress` module. Only builds
+    /// with the `compress` feature understand it.
+    pub const COMPRESSION: WireFeatures = WireFeatures(1 << 3);
+    /// Messages carry a send timestamp.
+    pub const TIMESTAMPS: WireFeatures = WireFeatures(1 << 16);
+    /// Messages carry a priority hint.
+    pub const PRIORITY: WireFeatures = WireFeatures(1 << 17);
+    /// The header tracks when the consumer last made progress, see the
+    /// `watchdog` module.
+    pub const PROGRESS: WireFeatures = WireFeatures(1 << 18);
+    /// The header keeps the last consumed messages, see the `history`
+    /// module.
+    pub const HISTORY: WireFeatures = WireFeatures(1 << 19);
+
+    /// Every feature this build understands.
+    pub const KNOWN: WireFeatures = WireFeatures(
+        Self::ENVELOPE.0
+            | Self::CRC.0
+            | Self::VARIABLE_LENGTH.0
+            | Self::TIMESTAMPS.0
+            | Self::PRIORITY.0
+            | Self::PROGRESS.0
+            | Self::HISTORY.0
+            | if cfg!(feature = "compress") { Self::COMPRESSION.0 } else { 0 },
+    );
+    /// The bits a peer must understand to use the port.
+    pub const REQUIRED: WireFeatures = WireFeatures(0xffff);
+
+    pub const fn empty() -> WireFeatures {
+        WireFeatures(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> WireFeatures {
+        WireFeatures(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: WireFeatures) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The features in `self` that are not in `other`.
+    pub const fn difference(self, other: WireFeatures) -> WireFeatures {
+        WireFeatures(self.0 & !other.0)
+    }
+}
+
+impl BitOr for WireFeatures {
+    type Output = WireFeatures;
+
+    fn bitor(self, rhs: WireFeatures) -> WireFeatures {
+        WireFeatures(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for WireFeatures {
+    type Output = WireFeatures;
+
+    fn bitand(self, rhs: WireFeatures) -> WireFeatures {
+        WireFeatures(self.0 & rhs.0)
+    }
+}
+
+impl SegmentHeader {
+    pub(crate) fn wire_features(&self) -> WireFeatures {
+        let raw = self.features.load(self.byte_order(), Ordering::Relaxed);
+        // The byte order marks share the word but are not features.
+        WireFeatures(raw & !crate::byteorder::MARKS)
+    }
+}
+
+impl QueueingPort {
+    /// The features the port's creator declared.
+    pub fn wire_features(&self) -> WireFeatures {
+        self.segment().header.wire_features()
+    }
+
+    /// The declared features this handle understands. For an opened port
+    /// these are the ones left after dropping unknown optional features.
+    pub fn negotiated_features(&self) -> WireFeatures {
+        self.wire_features() & self.supported_features
+    }
+
+    /// The required features of the port that `supported` lacks, if any.
+    pub(crate) fn missing_features(&self, supported: WireFeatures) -> Option<WireFeatures> {
+        let missing = (self.wire_features() & WireFeatures::REQUIRED).difference(supported);
+        (!missing.is_empty()).then_some(missing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PortConfig;
+
+    #[test]
+    fn bit_assignments_are_stable() {
+        let table = [
+            (WireFeatures::ENVELOPE, 0x0000_0001, true),
+            (WireFeatures::CRC, 0x0000_0002, true),
+            (WireFeatures::VARIABLE_LENGTH, 0x0000_0004, true),
+            (WireFeatures::COMPRESSION, 0x0000_0008, true),
+            (WireFeatures::TIMESTAMPS, 0x0001_0000, false),
+            (WireFeatures::PRIORITY, 0x0002_0000, false),
+            (WireFeatures::PROGRESS, 0x0004_0000, false),
+            (WireFeatures::HISTORY, 0x0008_0000, false),
+        ];
+        let mut known = 0;
+        for (feature, bits, required) in table {
+            assert_eq!(feature.bits(), bits);
+            assert_eq!(WireFeatures::REQUIRED.contains(feature), required);
+            if feature != WireFeatures::COMPRESSION || cfg!(feature = "compress") {
+                known |= bits;
+            }
+        }
+        assert_eq!(WireFeatures::KNOWN.bits(), known);
+    }
+
+    #[test]
+    fn config_declares_features() {
+        let features = WireFeatures::CRC | WireFeatures::TIMESTAMPS;
+        let port = QueueingPort::with_config(&PortConfig::new().wire_features(features));
+        assert_eq!(port.wire_features(), features);
+        assert_eq!(port.negotiated_features(), features);
+        assert_eq!(port.missing_features(WireFeatures::KNOWN), None);
+        assert_eq!(port.missing_features(WireFeatures::TIMESTAMPS), Some(WireFeatures::CRC));
+        assert_eq!(QueueingPort::new().wire_features(), WireFeatures::empty());
+    }
+}
@@ -0,0 +1,307 @@
+//! A process-wide table of ports for C callers, addressed by integer id.
+//!
+//! The functions here are declared in `include/queueing_port.h`. They
+//! return `QP_OK` (0) or one of the negative `QP_E*` codes below. Every
+//! port still has the crate's geometry: `qp_create` accepts any message
+//! size and count up to `SIZE` and `MSGS`, and the registry enforces the
+//! smaller limits on top of the full-size slots.
+//!
+//! A port made on the Rust side can also be handed to C by pointer, outside
+//! the table: `QueueingPort::into_raw` gives up ownership of it, C keeps the
+//! pointer as an opaque `QueueingPort *`, and `QueueingPort::from_raw` on
+//! the Rust side, or `qp_drop` from C, takes the port back.
+
+use core::slice;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{signal_safe_enqueue, Message, OperatingMode, QueueError, QueueingPort, StartCondition, MSGS, SIZE};
+
+/// Number of port ids, `0..QP_MAX_PORTS`.
+pub const QP_MAX_PORTS: usize = 64;
+
+pub const QP_OK: i32 = 0;
+/// An id out of range, a null pointer, or a size the geometry cannot hold.
+pub const QP_EINVAL: i32 = -1;
+/// `qp_create` on an id that is already in use.
+pub const QP_EEXIST: i32 = -2;
+/// No port was created with this id.
+pub const QP_ENOENT: i32 = -3;
+pub const QP_EFULL: i32 = -4;
+pub const QP_EEMPTY: i32 = -5;
+/// The message is longer than the port's message size, or the receive
+/// buffer shorter; nothing was sent or received.
+pub const QP_ESIZE: i32 = -6;
+
+// `OperatingMode` and `StartCondition`, with their ARINC 653 values.
+pub const QP_IDLE: u8 = OperatingMode::Idle as u8;
+pub const QP_COLD_START: u8 = OperatingMode::ColdStart as u8;
+pub const QP_WARM_START: u8 = OperatingMode::WarmStart as u8;
+pub const QP_NORMAL: u8 = OperatingMode::Normal as u8;
+pub const QP_NORMAL_START: u8 = StartCondition::NormalStart as u8;
+pub const QP_PARTITION_RESTART: u8 = StartCondition::PartitionRestart as u8;
+pub const QP_HM_MODULE_RESTART: u8 = StartCondition::HmModuleRestart as u8;
+pub const QP_HM_PARTITION_RESTART: u8 = StartCondition::HmPartitionRestart as u8;
+
+struct Entry {
+    port: QueueingPort,
+    msg_size: usize,
+    msg_count: usize,
+}
+
+const VACANT: Option<Entry> = None;
+
+static REGISTRY: Mutex<[Option<Entry>; QP_MAX_PORTS]> = Mutex::new([VACANT; QP_MAX_PORTS]);
+
+fn registry() -> MutexGuard<'static, [Option<Entry>; QP_MAX_PORTS]> {
+    // As with the channel's mutex, the header is only changed through
+    // atomics, so a panic under the lock leaves nothing half-updated.
+    REGISTRY.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn with_entry(port_id: u32, f: impl FnOnce(&mut Entry) -> i32) -> i32 {
+    match registry().get_mut(port_id as usize) {
+        Some(Some(entry)) => f(entry),
+        Some(None) => QP_ENOENT,
+        None => QP_EINVAL,
+    }
+}
+
+/// Creates port `port_id`, holding up to `msg_count` messages of
+/// `msg_size` bytes.
+#[no_mangle]
+pub extern "C" fn qp_create(port_id: u32, msg_size: usize, msg_count: usize) -> i32 {
+    if msg_size == 0 || msg_size > SIZE || msg_count == 0 || msg_count > MSGS {
+        return QP_EINVAL;
+    }
+    match registry().get_mut(port_id as usize) {
+        Some(Some(_)) => QP_EEXIST,
+        Some(slot) => {
+            *slot = Some(Entry { port: QueueingPort::new(), msg_size, msg_count });
+            QP_OK
+        }
+        None => QP_EINVAL,
+    }
+}
+
+/// Enqueues the `len` bytes at `data`, zero-filled up to the slot size.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn qp_send(port_id: u32, data: *const u8, len: usize) -> i32 {
+    if data.is_null() {
+        return QP_EINVAL;
+    }
+    with_entry(port_id, |entry| {
+        if len > entry.msg_size {
+            return QP_ESIZE;
+        }
+        if entry.port.len() >= entry.msg_count {
+            return QP_EFULL;
+        }
+        let bytes = unsafe { slice::from_raw_parts(data, len) };
+        match entry.port.enqueue_vectored(&[bytes]) {
+            Ok(()) => QP_OK,
+            Err(QueueError::FullBuffer) => QP_EFULL,
+            Err(_) => QP_EINVAL,
+        }
+    })
+}
+
+/// Dequeues the next message into `data`, storing the number of bytes
+/// written, the port's message size, in `*actual`.
+///
+/// # Safety
+///
+/// `data` must point to `len` writable bytes and `actual` to a writable
+/// `size_t`.
+#[no_mangle]
+pub unsafe extern "C" fn qp_recv(port_id: u32, data: *mut u8, len: usize, actual: *mut usize) -> i32 {
+    if data.is_null() || actual.is_null() {
+        return QP_EINVAL;
+    }
+    with_entry(port_id, |entry| {
+        let msg_size = entry.msg_size;
+        if len < msg_size {
+            return QP_ESIZE;
+        }
+        let out = unsafe { slice::from_raw_parts_mut(data, msg_size) };
+        match entry.port.dequeue_with(|slot| out.copy_from_slice(&slot[..msg_size])) {
+            Ok(()) => {
+                unsafe { actual.write(msg_size) };
+                QP_OK
+            }
+            Err(QueueError::EmptyBuffer) => QP_EEMPTY,
+            Err(_) => QP_EINVAL,
+        }
+    })
+}
+
+/// Enqueues the `len` bytes at `data`, zero-filled up to the slot size,
+/// into the signal queue that `SignalQueue::drain_into` moves into a port.
+/// Unlike the functions above it takes no lock, so a signal handler may
+/// call it.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn qp_signal_enqueue(data: *const u8, len: usize) -> i32 {
+    if data.is_null() {
+        return QP_EINVAL;
+    }
+    if len > SIZE {
+        return QP_ESIZE;
+    }
+    let mut message = Message([0; SIZE]);
+    message.0[..len].copy_from_slice(unsafe { slice::from_raw_parts(data, len) });
+    match signal_safe_enqueue(message) {
+        Ok(()) => QP_OK,
+        Err(_) => QP_EFULL,
+    }
+}
+
+impl QueueingPort {
+    /// Moves the port to the heap and gives up ownership of it, for C code
+    /// to hold. `from_raw` or `qp_drop` takes it back.
+    pub fn into_raw(self) -> *mut QueueingPort {
+        Box::into_raw(Box::new(self))
+    }
+
+    /// Takes back a port given up with `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw`, and this, or `qp_drop`, must be
+    /// called on it exactly once: a second call frees the port again, and
+    /// none leaks it along with its segment. Nothing may use the pointer
+    /// afterwards, and C code given it must not have moved or copied the
+    /// port it points to.
+    pub unsafe fn from_raw(ptr: *mut QueueingPort) -> QueueingPort {
+        // SAFETY: `ptr` is the box `into_raw` leaked, which the caller
+        // hands back once.
+        *unsafe { Box::from_raw(ptr) }
+    }
+}
+
+/// Drops a port given up with `QueueingPort::into_raw`. A null pointer is
+/// ignored.
+///
+/// # Safety
+///
+/// As for `QueueingPort::from_raw`: the pointer is used up, and must not
+/// be passed to this or `from_raw` again.
+#[no_mangle]
+pub unsafe extern "C" fn qp_drop(ptr: *mut QueueingPort) {
+    if !ptr.is_null() {
+        drop(unsafe { QueueingPort::from_raw(ptr) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" {
+        // tests/c/queueing_port_test.c, built by build.rs. Returns 0, or
+        // the line of the first failed check.
+        fn qp_c_selftest(first_id: u32) -> i32;
+    }
+
+    // The registry is global and tests run in parallel, so every test
+    // uses its own ids.
+
+    #[test]
+    fn c_selftest_passes() {
+        assert_eq!(unsafe { qp_c_selftest(40) }, 0);
+    }
+
+    #[test]
+    fn round_trip_with_short_messages() {
+        assert_eq!(qp_create(1, 16, MSGS), QP_OK);
+        assert_eq!(qp_create(1, 16, MSGS), QP_EEXIST);
+        assert_eq!(unsafe { qp_send(1, b"hello".as_ptr(), 5) }, QP_OK);
+
+        let mut out = [0xffu8; 32];
+        let mut actual = 0;
+        assert_eq!(unsafe { qp_recv(1, out.as_mut_ptr(), out.len(), &mut actual) }, QP_OK);
+        assert_eq!(actual, 16);
+        assert_eq!(&out[..5], b"hello");
+        assert_eq!(out[5..16], [0; 11]);
+        assert_eq!(out[16], 0xff, "nothing past the message size is written");
+        assert_eq!(unsafe { qp_recv(1, out.as_mut_ptr(), out.len(), &mut actual) }, QP_EEMPTY);
+    }
+
+    #[test]
+    fn sizes_are_checked() {
+        assert_eq!(qp_create(2, SIZE + 1, MSGS), QP_EINVAL);
+        assert_eq!(qp_create(2, SIZE, MSGS + 1), QP_EINVAL);
+        assert_eq!(qp_create(QP_MAX_PORTS as u32, SIZE, MSGS), QP_EINVAL);
+        assert_eq!(qp_create(2, 8, 1), QP_OK);
+
+        let data = [1u8; 9];
+        assert_eq!(unsafe { qp_send(2, data.as_ptr(), 9) }, QP_ESIZE);
+        assert_eq!(unsafe { qp_send(2, data.as_ptr(), 8) }, QP_OK);
+        assert_eq!(unsafe { qp_send(2, data.as_ptr(), 8) }, QP_EFULL, "msg_count is 1");
+        let mut out = [0u8; 7];
+        let mut actual = 0;
+        assert_eq!(unsafe { qp_recv(2, out.as_mut_ptr(), out.len(), &mut actual) }, QP_ESIZE);
+        assert_eq!(unsafe { qp_recv(3, out.as_mut_ptr(), out.len(), &mut actual) }, QP_ENOENT);
+        assert_eq!(unsafe { qp_send(2, core::ptr::null(), 0) }, QP_EINVAL);
+    }
+
+    #[test]
+    fn full_port_reports_efull() {
+        assert_eq!(qp_create(4, SIZE, MSGS), QP_OK);
+        for _ in 0..MSGS {
+            assert_eq!(unsafe { qp_send(4, [7u8].as_ptr(), 1) }, QP_OK);
+        }
+        assert_eq!(unsafe { qp_send(4, [7u8].as_ptr(), 1) }, QP_EFULL);
+    }
+
+    #[test]
+    fn signal_enqueue_lands_in_the_signal_queue() {
+        // The only test using the global signal queue.
+        assert_eq!(unsafe { qp_signal_enqueue(b"sig".as_ptr(), 3) }, QP_OK);
+        assert_eq!(unsafe { qp_signal_enqueue(b"sig".as_ptr(), SIZE + 1) }, QP_ESIZE);
+        assert_eq!(unsafe { qp_signal_enqueue(core::ptr::null(), 0) }, QP_EINVAL);
+        let mut port = QueueingPort::new();
+        assert_eq!(crate::SIGNAL_QUEUE.drain_into(&mut port), 1);
+        assert_eq!(&port.dequeue().unwrap().0[..4], b"sig\0");
+    }
+
+    #[test]
+    fn partition_constants_have_the_arinc_values() {
+        assert_eq!([QP_IDLE, QP_COLD_START, QP_WARM_START, QP_NORMAL], [0, 1, 2, 3]);
+        let starts = [QP_NORMAL_START, QP_PARTITION_RESTART, QP_HM_MODULE_RESTART, QP_HM_PARTITION_RESTART];
+        assert_eq!(starts, [0, 1, 2, 3]);
+    }
+
+    /// Stands in for a C library that keeps the port for a callback.
+    extern "C" fn hand_back(port: *mut QueueingPort, keep: extern "C" fn(*mut QueueingPort)) -> *mut QueueingPort {
+        keep(port);
+        port
+    }
+
+    extern "C" fn send_from_callback(port: *mut QueueingPort) {
+        // The callback borrows the port, it does not own it.
+        let port = unsafe { &mut *port };
+        port.enqueue(Message([9; SIZE])).unwrap();
+    }
+
+    #[test]
+    fn raw_ports_survive_a_trip_through_c() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([8; SIZE])).unwrap();
+        let raw = hand_back(port.into_raw(), send_from_callback);
+        let mut port = unsafe { QueueingPort::from_raw(raw) };
+        assert_eq!(port.dequeue().unwrap().0, [8; SIZE]);
+        assert_eq!(port.dequeue().unwrap().0, [9; SIZE]);
+        port.enqueue(Message([10; SIZE])).unwrap();
+        assert_eq!(port.len(), 1);
+
+        unsafe { qp_drop(port.into_raw()) };
+        unsafe { qp_drop(core::ptr::null_mut()) };
+    }
+}
@@ -0,0 +1,164 @@
+//! Receiver-side allow-list of message types.
+//!
+//! The filter belongs to a handle, not to the segment: two readers of the
+//! same segment (one after the other) can filter differently.
+
+use crate::{QueueError, QueueingPort};
+
+/// Most message types a single filter can allow.
+pub const TYPE_FILTER_CAPACITY: usize = 16;
+
+#[derive(Clone)]
+pub(crate) struct TypeFilter {
+    allowed: [u16; TYPE_FILTER_CAPACITY],
+    len: usize,
+}
+
+impl TypeFilter {
+    pub(crate) const fn new() -> TypeFilter {
+        TypeFilter {
+            allowed: [0; TYPE_FILTER_CAPACITY],
+            len: 0,
+        }
+    }
+
+    /// A filter allowing the types in `allowed`, which must not have more
+    /// than `TYPE_FILTER_CAPACITY` entries.
+    pub(crate) fn from_slice(allowed: &[u16]) -> TypeFilter {
+        let mut filter = TypeFilter::new();
+        filter.allowed[..allowed.len()].copy_from_slice(allowed);
+        filter.len = allowed.len();
+        filter
+    }
+
+    /// An empty filter lets every type through.
+    pub(crate) fn allows(&self, msg_type: u16) -> bool {
+        self.len == 0 || self.allowed[..self.len].contains(&msg_type)
+    }
+}
+
+impl QueueingPort {
+    /// Restricts `dequeue` to messages whose type is in `allowed`; the others
+    /// are discarded and counted in `QueueStats::filtered_out`.
+    ///
+    /// An empty slice removes the filter. Fails with `FilterTooLarge`, leaving
+    /// the current filter in place, if `allowed` has more than
+    /// `TYPE_FILTER_CAPACITY` entries.
+    pub fn set_type_filter(&mut self, allowed: &[u16]) -> Result<(), QueueError> {
+        if allowed.len() > TYPE_FILTER_CAPACITY {
+            return Err(QueueError::FilterTooLarge);
+        }
+        self.type_filter = TypeFilter::from_slice(allowed);
+        Ok(())
+    }
+
+    /// Limits how many messages a single dequeue call may look at before it
+    /// gives up with `QueueError::BudgetExhausted`, bounding the time spent
+    /// discarding filtered messages. A budget of 0 is taken as 1, so every
+    /// call makes progress.
+    pub fn set_skip_budget(&mut self, n: usize) {
+        self.skip_budget = n.max(1);
+    }
+
+    /// Lifts the limit set by `set_skip_budget`.
+    pub fn clear_skip_budget(&mut self) {
+        self.skip_budget = usize::MAX;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+
+    fn typed(msg_type: u16, tag: u8) -> Message {
+        let mut bytes = [tag; SIZE];
+        bytes[4..6].copy_from_slice(&msg_type.to_le_bytes());
+        Message(bytes)
+    }
+
+    fn port_with(types: &[u16]) -> QueueingPort {
+        let mut port = QueueingPort::new();
+        for (tag, &msg_type) in types.iter().enumerate() {
+            port.enqueue(typed(msg_type, tag as u8)).unwrap();
+        }
+        port
+    }
+
+    #[test]
+    fn only_allowed_types_are_delivered() {
+        let mut port = port_with(&[1, 7, 2, 7, 3, 1]);
+        port.set_type_filter(&[1, 3]).unwrap();
+
+        let delivered: Vec<(u16, u8)> =
+            core::iter::from_fn(|| port.dequeue()).map(|m| (m.msg_type(), m.0[0])).collect();
+        assert_eq!(delivered, [(1, 0), (3, 4), (1, 5)]);
+        assert_eq!(port.stats().filtered_out, 3);
+        assert_eq!(port.stats().dequeued, 6);
+    }
+
+    #[test]
+    fn empty_filter_passes_everything() {
+        let mut port = port_with(&[1, 2, 3]);
+        port.set_type_filter(&[5]).unwrap();
+        port.set_type_filter(&[]).unwrap();
+        for msg_type in 1..=3 {
+            assert_eq!(port.dequeue().unwrap().msg_type(), msg_type);
+        }
+        assert_eq!(port.stats().filtered_out, 0);
+    }
+
+    #[test]
+    fn unfiltered_dequeue_sees_everything() {
+        let mut port = port_with(&[1, 2]);
+        port.set_type_filter(&[2]).unwrap();
+        assert_eq!(port.dequeue_unfiltered().unwrap().msg_type(), 1);
+        assert_eq!(port.dequeue().unwrap().msg_type(), 2);
+        assert_eq!(port.stats().filtered_out, 0);
+    }
+
+    #[test]
+    fn oversized_filter_is_rejected() {
+        let mut port = port_with(&[4]);
+        port.set_type_filter(&[4]).unwrap();
+        let too_many = [0u16; TYPE_FILTER_CAPACITY + 1];
+        assert!(matches!(port.set_type_filter(&too_many), Err(QueueError::FilterTooLarge)));
+        assert_eq!(port.dequeue().unwrap().msg_type(), 4);
+    }
+
+    #[test]
+    fn skip_budget_spreads_draining_over_calls() {
+        let mut port = port_with(&[9; 10]);
+        port.set_type_filter(&[1]).unwrap();
+        port.set_skip_budget(3);
+
+        let mut calls = 0;
+        loop {
+            calls += 1;
+            match port.try_dequeue() {
+                Err(QueueError::BudgetExhausted { examined }) => assert_eq!(examined, 3),
+                Err(QueueError::EmptyBuffer) => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        // ceil(10 / 3) calls drain the queue; the last one then finds it empty.
+        assert_eq!(calls, 4);
+        assert_eq!(port.stats().filtered_out, 10);
+    }
+
+    #[test]
+    fn skip_budget_keeps_progress_between_calls() {
+        let mut port = port_with(&[9, 9, 9, 9, 1, 9]);
+        port.set_type_filter(&[1]).unwrap();
+        port.set_skip_budget(3);
+
+        assert!(matches!(port.try_dequeue(), Err(QueueError::BudgetExhausted { examined: 3 })));
+        assert_eq!(port.len(), 3, "the skipped messages are not rescanned");
+        let message = port.dequeue().unwrap();
+        assert_eq!((message.msg_type(), message.0[0]), (1, 4));
+
+        port.clear_skip_budget();
+        assert!(matches!(port.try_dequeue(), Err(QueueError::EmptyBuffer)));
+        assert_eq!(port.stats().filtered_out, 5);
+    }
+}
@@ -0,0 +1,365 @@
+//! Queues laid out by a C or Ada partition.
+//!
+//! A peer not written against this crate keeps its own ring, typically
+//!
+//! ```text
+//! struct ring {
+//!     volatile uint32_t head;        /* next slot the writer fills */
+//!     volatile uint32_t tail;        /* next slot the reader empties */
+//!     uint8_t msgs[MSGS][SIZE];
+//! };
+//! ```
+//!
+//! A `ForeignLayout` says where such a ring keeps its two indices and its
+//! messages, and a `ForeignPort` enqueues and dequeues through it as the
+//! peer does. The ring has `MSGS` slots of `SIZE` bytes, `msg_stride` bytes
+//! apart from `msg_offset` on. The write index is at `index_offset` and the
+//! read index right after it, each `index_size` bytes, 1, 2, 4 or 8, in
+//! `order`. An index is a slot number below `MSGS`: the queue is empty when
+//! the two are equal and full when the write index is one slot behind the
+//! read index, so it holds at most `MSGS - 1` messages.
+//!
+//! Each index is read and written as one atomic access of its size, the
+//! writer storing its index after the message and the reader its index
+//! after copying the message out. The peer must do the same with release
+//! semantics, which a `volatile` store with a compiler barrier gives on
+//! x86 but not on weaker machines, where it needs a fence. An index the
+//! peer left at `MSGS` or beyond fails the call with
+//! `QueueError::BadIndex`, touching nothing.
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use crate::{ByteOrder, DequeuePort, EnqueuePort, Message, PortError, QueueError, MSGS, SIZE};
+
+/// Where a foreign ring keeps its indices and messages, see the module
+/// documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForeignLayout {
+    /// Offset of the write index; the read index follows it.
+    pub index_offset: usize,
+    /// Bytes in each index: 1, 2, 4 or 8.
+    pub index_size: u8,
+    /// Offset of the first slot.
+    pub msg_offset: usize,
+    /// Distance between the starts of two neighbouring slots, at least
+    /// `SIZE`.
+    pub msg_stride: usize,
+    /// How the indices are stored.
+    pub order: ByteOrder,
+}
+
+impl ForeignLayout {
+    /// Bytes a region needs to hold the ring.
+    pub fn region_len(&self) -> usize {
+        let indices = self.index_offset + 2 * self.index_size as usize;
+        let messages = self.msg_offset + (MSGS - 1) * self.msg_stride + SIZE;
+        indices.max(messages)
+    }
+
+    /// Fails with `PortError::LayoutMismatch` if the layout has no valid
+    /// index size, overlaps itself or does not fit in `len` bytes.
+    fn check(&self, len: usize) -> Result<(), PortError> {
+        let index_size = self.index_size as usize;
+        let indices = self.index_offset..self.index_offset + 2 * index_size;
+        let messages = self.msg_offset..self.msg_offset + (MSGS - 1) * self.msg_stride + SIZE;
+        if !matches!(index_size, 1 | 2 | 4 | 8)
+            || self.msg_stride < SIZE
+            || indices.start < messages.end && messages.start < indices.end
+            || self.region_len() > len
+        {
+            return Err(PortError::LayoutMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// A port on a foreign ring, see the module documentation.
+///
+/// Like `QueueingPort`, one handle writes and one reads.
+pub struct ForeignPort {
+    base: NonNull<u8>,
+    layout: ForeignLayout,
+    #[cfg(feature = "shmem")]
+    _mapping: Option<shared_memory::Shmem>,
+}
+
+impl ForeignPort {
+    /// A port on the ring laid out as `layout` in the `len` bytes at `base`.
+    ///
+    /// Fails with `PortError::LayoutMismatch` as `ForeignLayout` describes,
+    /// and with `PortError::Misaligned` if the indices are not aligned to
+    /// their size.
+    ///
+    /// # Safety
+    ///
+    /// The `len` bytes at `base` must stay mapped and writable for as long
+    /// as the port, and be changed by nothing but the peer's ring code.
+    pub unsafe fn attach(base: NonNull<u8>, len: usize, layout: ForeignLayout) -> Result<ForeignPort, PortError> {
+        layout.check(len)?;
+        if !(base.as_ptr() as usize + layout.index_offset).is_multiple_of(layout.index_size as usize) {
+            return Err(PortError::Misaligned);
+        }
+        Ok(ForeignPort {
+            base,
+            layout,
+            #[cfg(feature = "shmem")]
+            _mapping: None,
+        })
+    }
+
+    pub fn layout(&self) -> ForeignLayout {
+        self.layout
+    }
+
+    /// Messages queued, as of some moment during the call.
+    pub fn len(&self) -> usize {
+        match (self.index(WRITE), self.index(READ)) {
+            (Ok(write), Ok(read)) => (write + MSGS - read) % MSGS,
+            _ => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Most messages the ring holds.
+    pub fn capacity(&self) -> usize {
+        MSGS - 1
+    }
+
+    /// Loads index `which`, the write index or the read index.
+    fn index(&self, which: usize) -> Result<usize, QueueError> {
+        let ptr = self.index_ptr(which);
+        let order = self.layout.order;
+        // `attach` checked the index is in the region and aligned.
+        let raw = unsafe {
+            match self.layout.index_size {
+                1 => (*ptr.cast::<AtomicU8>()).load(Ordering::Acquire) as u64,
+                2 => from_order(order, (*ptr.cast::<AtomicU16>()).load(Ordering::Acquire), u16::from_le, u16::from_be)
+                    as u64,
+                4 => from_order(order, (*ptr.cast::<AtomicU32>()).load(Ordering::Acquire), u32::from_le, u32::from_be)
+                    as u64,
+                _ => from_order(order, (*ptr.cast::<AtomicU64>()).load(Ordering::Acquire), u64::from_le, u64::from_be),
+            }
+        };
+        if raw >= MSGS as u64 {
+            return Err(QueueError::BadIndex { index: raw });
+        }
+        Ok(raw as usize)
+    }
+
+    /// Stores `value`, below `MSGS`, to index `which`.
+    fn set_index(&self, which: usize, value: usize) {
+        let ptr = self.index_ptr(which);
+        let order = self.layout.order;
+        unsafe {
+            match self.layout.index_size {
+                1 => (*ptr.cast::<AtomicU8>()).store(value as u8, Ordering::Release),
+                2 => (*ptr.cast::<AtomicU16>())
+                    .store(from_order(order, value as u16, u16::to_le, u16::to_be), Ordering::Release),
+                4 => (*ptr.cast::<AtomicU32>())
+                    .store(from_order(order, value as u32, u32::to_le, u32::to_be), Ordering::Release),
+                _ => (*ptr.cast::<AtomicU64>())
+                    .store(from_order(order, value as u64, u64::to_le, u64::to_be), Ordering::Release),
+            }
+        }
+    }
+
+    fn index_ptr(&self, which: usize) -> *mut u8 {
+        unsafe { self.base.as_ptr().add(self.layout.index_offset + which * self.layout.index_size as usize) }
+    }
+
+    fn slot(&self, index: usize) -> *mut [u8; SIZE] {
+        unsafe { self.base.as_ptr().add(self.layout.msg_offset + index * self.layout.msg_stride) }.cast()
+    }
+}
+
+const WRITE: usize = 0;
+const READ: usize = 1;
+
+/// Converts between `order` and the machine's order; either way is the
+/// same swap.
+fn from_order<T>(order: ByteOrder, value: T, little: fn(T) -> T, big: fn(T) -> T) -> T {
+    match order {
+        ByteOrder::LittleEndian => little(value),
+        ByteOrder::BigEndian => big(value),
+        ByteOrder::Native => value,
+    }
+}
+
+impl EnqueuePort for ForeignPort {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        let write = self.index(WRITE)?;
+        let read = self.index(READ)?;
+        let next = (write + 1) % MSGS;
+        if next == read {
+            return Err(QueueError::FullBuffer);
+        }
+        // The reader leaves the slot alone until the index moves past it;
+        // slots need not be aligned.
+        unsafe { self.slot(write).write_unaligned(message.0) };
+        self.set_index(WRITE, next);
+        Ok(())
+    }
+}
+
+impl DequeuePort for ForeignPort {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        let read = self.index(READ)?;
+        let write = self.index(WRITE)?;
+        if read == write {
+            return Err(QueueError::EmptyBuffer);
+        }
+        let message = unsafe { self.slot(read).read_unaligned() };
+        self.set_index(READ, (read + 1) % MSGS);
+        Ok(Message(message))
+    }
+}
+
+#[cfg(feature = "shmem")]
+impl crate::QueueingPort {
+    /// Opens the named segment `name`, created by a partition that lays its
+    /// ring out as `layout`, see the `foreign` module.
+    ///
+    /// There is no handshake: the peer is expected to have set the indices
+    /// up before publishing the name. Fails as `ForeignPort::attach`, and
+    /// with `PortError::Open` if the name does not open or its segment is
+    /// shorter than `layout.region_len()`.
+    pub fn open_foreign(name: &str, layout: ForeignLayout) -> Result<ForeignPort, PortError> {
+        let geometry = crate::SegmentGeometry { segment_len: layout.region_len(), ..crate::SegmentGeometry::PORT };
+        let shmem = crate::open_error::open_named(name, geometry)?;
+        // The mapping lives as long as the port, which holds it.
+        let base = unsafe { NonNull::new_unchecked(shmem.as_ptr()) };
+        let mut port = unsafe { ForeignPort::attach(base, shmem.len(), layout) }?;
+        port._mapping = Some(shmem);
+        Ok(port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ring of the module documentation, as a C compiler lays it out.
+    #[repr(C)]
+    struct CRing {
+        head: u32,
+        tail: u32,
+        msgs: [[u8; SIZE]; MSGS],
+    }
+
+    const C_LAYOUT: ForeignLayout = ForeignLayout {
+        index_offset: 0,
+        index_size: 4,
+        msg_offset: 8,
+        msg_stride: SIZE,
+        order: ByteOrder::Native,
+    };
+
+    fn c_ring() -> Box<CRing> {
+        Box::new(CRing { head: 0, tail: 0, msgs: [[0; SIZE]; MSGS] })
+    }
+
+    #[test]
+    fn round_trips_through_a_c_ring() {
+        let mut ring = c_ring();
+        let base = NonNull::from(&mut *ring).cast::<u8>();
+        {
+            let mut port = unsafe { ForeignPort::attach(base, size_of::<CRing>(), C_LAYOUT) }.unwrap();
+            for lap in 0..3u8 {
+                for tag in 0..MSGS as u8 - 1 {
+                    port.enqueue(Message([lap * 16 + tag; SIZE])).unwrap();
+                }
+                assert!(matches!(port.enqueue(Message([0; SIZE])), Err(QueueError::FullBuffer)));
+                assert_eq!(port.len(), MSGS - 1);
+                for tag in 0..MSGS as u8 - 1 {
+                    assert_eq!(port.dequeue().unwrap().0, [lap * 16 + tag; SIZE]);
+                }
+                assert!(matches!(port.dequeue(), Err(QueueError::EmptyBuffer)));
+            }
+
+            port.enqueue(Message([0xAB; SIZE])).unwrap();
+        }
+        // What the C side sees, the port gone: the indices and the messages in place.
+        let slot = (3 * (MSGS - 1)) % MSGS;
+        assert_eq!((ring.head as usize, ring.tail as usize), ((slot + 1) % MSGS, slot));
+        assert_eq!(ring.msgs[slot], [0xAB; SIZE]);
+
+        // And the other way: the C side enqueues, Rust dequeues.
+        ring.msgs[ring.head as usize] = [0x5C; SIZE];
+        ring.head = (ring.head + 1) % MSGS as u32;
+        let mut port = unsafe { ForeignPort::attach(NonNull::from(&mut *ring).cast(), size_of::<CRing>(), C_LAYOUT) }
+            .unwrap();
+        assert_eq!(port.dequeue().unwrap().0, [0xAB; SIZE]);
+        assert_eq!(port.dequeue().unwrap().0, [0x5C; SIZE]);
+        assert!(port.is_empty());
+    }
+
+    #[test]
+    fn big_endian_short_indices_with_padded_slots() {
+        // An Ada record: 16-bit big-endian indices after a 4-byte tag, and
+        // each message padded to 264 bytes.
+        let layout = ForeignLayout {
+            index_offset: 4,
+            index_size: 2,
+            msg_offset: 16,
+            msg_stride: SIZE + 8,
+            order: ByteOrder::BigEndian,
+        };
+        let mut region = vec![0u64; layout.region_len().div_ceil(8)];
+        let len = region.len() * 8;
+        let bytes = NonNull::new(region.as_mut_ptr()).unwrap().cast::<u8>();
+        let mut port = unsafe { ForeignPort::attach(bytes, len, layout) }.unwrap();
+        for tag in 1..=3 {
+            port.enqueue(Message([tag; SIZE])).unwrap();
+        }
+        assert_eq!(port.dequeue().unwrap().0[0], 1);
+
+        let raw = unsafe { core::slice::from_raw_parts(bytes.as_ptr(), len) };
+        assert_eq!(raw[4..8], [0, 3, 0, 1], "write 3 and read 1, big endian");
+        assert_eq!(raw[16 + 2 * (SIZE + 8)], 3);
+        assert_eq!(raw[16 + SIZE + 8 - 1], 0, "padding untouched");
+
+        // An index past the ring is refused.
+        unsafe { *bytes.as_ptr().add(5) = MSGS as u8 };
+        assert!(matches!(port.dequeue(), Err(QueueError::BadIndex { index }) if index == MSGS as u64));
+    }
+
+    #[test]
+    fn bad_layouts_are_refused() {
+        let mut ring = c_ring();
+        let base = NonNull::from(&mut *ring).cast::<u8>();
+        let len = size_of::<CRing>();
+        let attach = |layout| unsafe { ForeignPort::attach(base, len, layout) };
+        assert!(matches!(attach(ForeignLayout { index_size: 3, ..C_LAYOUT }), Err(PortError::LayoutMismatch)));
+        assert!(matches!(attach(ForeignLayout { msg_stride: SIZE - 1, ..C_LAYOUT }), Err(PortError::LayoutMismatch)));
+        assert!(matches!(attach(ForeignLayout { msg_offset: 4, ..C_LAYOUT }), Err(PortError::LayoutMismatch)));
+        assert!(matches!(attach(ForeignLayout { msg_offset: 12, ..C_LAYOUT }), Err(PortError::LayoutMismatch)));
+        // 16-bit indices one byte into the ring.
+        let odd = unsafe { NonNull::new_unchecked(base.as_ptr().add(1)) };
+        let layout = ForeignLayout { index_size: 2, msg_offset: 4, ..C_LAYOUT };
+        assert!(matches!(unsafe { ForeignPort::attach(odd, len - 1, layout) }, Err(PortError::Misaligned)));
+    }
+
+    #[cfg(feature = "shmem")]
+    #[test]
+    fn opens_a_named_foreign_segment() {
+        let name = format!("/qp_foreign_{}", std::process::id());
+        let peer = shared_memory::ShmemConf::new().size(size_of::<CRing>()).os_id(&name).create().unwrap();
+        let ring = unsafe { &mut *peer.as_ptr().cast::<CRing>() };
+        ring.msgs[0] = [0x11; SIZE];
+        ring.head = 1;
+
+        let mut port = crate::QueueingPort::open_foreign(&name, C_LAYOUT).unwrap();
+        assert_eq!(port.dequeue().unwrap().0, [0x11; SIZE]);
+        port.enqueue(Message([0x22; SIZE])).unwrap();
+        assert_eq!((ring.head, ring.tail), (2, 1));
+        assert_eq!(ring.msgs[1], [0x22; SIZE]);
+
+        let small = ForeignLayout { msg_stride: 2 * SIZE, ..C_LAYOUT };
+        let too_short = crate::QueueingPort::open_foreign(&name, small);
+        assert!(matches!(too_short, Err(PortError::Open(error)) if !error.is_retryable()));
+    }
+}
@@ -0,0 +1,142 @@
+//! Moving a message from one port to another without an intermediate copy.
+
+use crate::{trace, QueueError, QueueingPort, FREED_SLOT_BYTE, MSGS, SIZE};
+
+impl QueueingPort {
+    /// Moves the oldest message of this port into `dst`, letting `f` patch
+    /// it in place on the way.
+    ///
+    /// The message is copied once, straight from this port's slot into a
+    /// free slot of `dst`, where `f` sees all `SIZE` bytes and returns the
+    /// length to keep; the rest is zeroed. Only after `dst` has published the
+    /// message is it consumed here, so on every error this port is left as
+    /// it was:
+    ///
+    /// - `EmptyBuffer` if there is nothing to forward,
+    /// - what `enqueue` on `dst` would fail with, `Closed` or `NoCredit`
+    ///   say, and `FullBuffer`, counted as rejected by `dst`,
+    /// - `MessageTooLarge` if `f` returns a length beyond `SIZE`, and
+    ///   `ValidationFailed` if `dst`'s validator refuses the patched
+    ///   message; nothing reaches `dst` then.
+    ///
+    /// The type filter does not apply. On a strict FIFO port an
+    /// out-of-order message is discarded with `OrderViolation`, as by
+    /// `dequeue`.
+    pub fn forward_with<F: FnOnce(&mut [u8]) -> usize>(&mut self, dst: &mut QueueingPort, f: F) -> Result<(), QueueError> {
+        trace::dequeue(self, |src| {
+            trace::enqueue(dst, |dst| {
+                if src.is_empty() {
+                    return Err(QueueError::EmptyBuffer);
+                }
+                let _hold = dst.admit_enqueue()?;
+                let target = dst.segment();
+                if dst.len() >= MSGS {
+                    target.header.rejected.fetch_add(target.header.byte_order(), 1, core::sync::atomic::Ordering::Relaxed);
+                    return Err(QueueError::FullBuffer);
+                }
+
+                let mut refused = None;
+                let forwarded = src.consume_front_if(|slot| {
+                    target.fill_free_slot(0, |out| {
+                        *out = *slot;
+                        match f(out) {
+                            len if len > SIZE => refused = Some(QueueError::MessageTooLarge { len }),
+                            len => out[len..].fill(0),
+                        }
+                    });
+                    if refused.is_none() {
+                        refused = dst.validate_written(target, 0).err();
+                    }
+                    if refused.is_some() {
+                        // The slot stays free; put it back the way free slots look.
+                        target.fill_free_slot(0, |out| out.fill(FREED_SLOT_BYTE));
+                        return None;
+                    }
+                    target.publish(1, dst.enqueue_time(), dst.clock());
+                    dst.wake_reader();
+                    Some(())
+                })?;
+                match forwarded {
+                    Some(()) => Ok(()),
+                    None => Err(refused.expect("declined only when refused")),
+                }
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, Segment};
+
+    fn in_segment(port: &QueueingPort, address: usize) -> bool {
+        let start = port.segment() as *const Segment as usize;
+        (start..start + core::mem::size_of::<Segment>()).contains(&address)
+    }
+
+    #[test]
+    fn forwards_with_single_in_place_copy() {
+        let mut src = QueueingPort::new();
+        let mut dst = QueueingPort::new();
+        src.enqueue(Message([7; SIZE])).unwrap();
+        src.enqueue(Message([8; SIZE])).unwrap();
+
+        let mut seen = 0;
+        src.forward_with(&mut dst, |bytes| {
+            seen = bytes.as_ptr() as usize;
+            assert_eq!(bytes, &[7; SIZE][..], "already copied when the closure runs");
+            bytes[4..6].copy_from_slice(&[0x34, 0x12]);
+            100
+        })
+        .unwrap();
+        assert!(in_segment(&dst, seen), "the closure patches the destination slot itself");
+
+        let (src_stats, dst_stats) = (src.stats(), dst.stats());
+        assert_eq!((src_stats.dequeued, dst_stats.enqueued), (1, 1));
+        let message = dst.dequeue().unwrap();
+        assert_eq!(message.msg_type(), 0x1234);
+        assert_eq!(&message.0[98..102], &[7, 7, 0, 0]);
+        assert_eq!(src.dequeue().unwrap().0, [8; SIZE]);
+    }
+
+    #[test]
+    fn failures_leave_source_intact() {
+        let mut src = QueueingPort::new();
+        let mut dst = QueueingPort::new();
+        assert!(matches!(src.forward_with(&mut dst, |_| 0), Err(QueueError::EmptyBuffer)));
+
+        src.enqueue(Message([1; SIZE])).unwrap();
+        assert!(matches!(
+            src.forward_with(&mut dst, |_| SIZE + 1),
+            Err(QueueError::MessageTooLarge { len }) if len == SIZE + 1
+        ));
+        assert!(dst.is_empty());
+
+        while dst.enqueue(Message([0; SIZE])).is_ok() {}
+        let rejected = dst.stats().rejected;
+        let mut called = false;
+        assert!(matches!(
+            src.forward_with(&mut dst, |_| {
+                called = true;
+                SIZE
+            }),
+            Err(QueueError::FullBuffer)
+        ));
+        assert!(!called);
+        assert_eq!(dst.stats().rejected, rejected + 1);
+
+        assert_eq!(src.stats().dequeued, 0);
+        assert_eq!(src.dequeue().unwrap().0, [1; SIZE]);
+    }
+
+    #[test]
+    fn a_closed_destination_takes_nothing() {
+        let mut src = QueueingPort::new();
+        let mut dst = QueueingPort::new();
+        src.enqueue(Message([1; SIZE])).unwrap();
+        dst.close_with_summary(b"").unwrap();
+        assert!(matches!(src.forward_with(&mut dst, |_| SIZE), Err(QueueError::Closed)));
+        assert_eq!((src.len(), dst.len()), (1, 1));
+    }
+}
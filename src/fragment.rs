@@ -0,0 +1,271 @@
+//! Blobs larger than a slot, sent as a run of fragments.
+//!
+//! Every fragment is an ordinary message of type `FRAGMENT_MSG_TYPE` whose
+//! first `FRAGMENT_HEADER_LEN` bytes are, little endian:
+//!
+//! ```text
+//! 0..4   message id, the `enqueued` count when the first fragment was written
+//! 4..6   FRAGMENT_MSG_TYPE
+//! 6..8   fragment index, from 0
+//! 8..10  total number of fragments
+//! 10..12 payload bytes in this fragment
+//! ```
+//!
+//! followed by the payload. The type id is what keeps fragments apart from
+//! other messages on the same port: `dequeue_large` refuses to start on
+//! anything else, and ordinary messages should not use the reserved type.
+
+use core::sync::atomic::Ordering;
+
+use crate::{slot_index, trace, wait, QueueError, QueueingPort, SIZE};
+
+/// Message type id reserved for fragments.
+pub const FRAGMENT_MSG_TYPE: u16 = 0xffff;
+/// Bytes of every fragment taken by its header.
+pub const FRAGMENT_HEADER_LEN: usize = 12;
+/// Blob bytes carried by one fragment.
+pub const FRAGMENT_PAYLOAD: usize = SIZE - FRAGMENT_HEADER_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FragmentHeader {
+    pub(crate) id: u32,
+    pub(crate) index: u16,
+    pub(crate) total: u16,
+    pub(crate) len: u16,
+}
+
+impl FragmentHeader {
+    pub(crate) fn parse(bytes: &[u8; FRAGMENT_HEADER_LEN]) -> Option<FragmentHeader> {
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        (u16_at(4) == FRAGMENT_MSG_TYPE).then(|| FragmentHeader {
+            id: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            index: u16_at(6),
+            total: u16_at(8),
+            len: u16_at(10),
+        })
+    }
+
+    pub(crate) fn write(&self, slot: &mut [u8; SIZE]) {
+        slot[0..4].copy_from_slice(&self.id.to_le_bytes());
+        slot[4..6].copy_from_slice(&FRAGMENT_MSG_TYPE.to_le_bytes());
+        slot[6..8].copy_from_slice(&self.index.to_le_bytes());
+        slot[8..10].copy_from_slice(&self.total.to_le_bytes());
+        slot[10..12].copy_from_slice(&self.len.to_le_bytes());
+    }
+
+    /// Whether `self` is the fragment that comes right after `previous`.
+    fn follows(&self, previous: &FragmentHeader) -> bool {
+        self.id == previous.id && self.total == previous.total && self.index == previous.index + 1
+    }
+}
+
+impl QueueingPort {
+    /// Enqueues `data` as consecutive fragments, `FRAGMENT_PAYLOAD` bytes
+    /// each; an empty blob still takes one.
+    ///
+    /// Waits while the queue is full, so a blob of more than `MSGS`
+    /// fragments needs a reader draining the port at the same time. Fails
+    /// with `QueueError::MessageTooLarge` beyond `u16::MAX` fragments.
+    pub fn enqueue_large(&mut self, data: &[u8]) -> Result<(), QueueError> {
+        let total = u16::try_from(data.len().div_ceil(FRAGMENT_PAYLOAD).max(1))
+            .map_err(|_| QueueError::MessageTooLarge { len: data.len() })?;
+        let id = self.segment().header.enqueued.load(self.byte_order(), Ordering::Relaxed);
+        for (index, chunk) in (0..total).zip(data.chunks(FRAGMENT_PAYLOAD).chain([&[][..]])) {
+            let header = FragmentHeader { id, index, total, len: chunk.len() as u16 };
+            // As in `enqueue_by`, room seen here stays available.
+            while self.room() == 0 {
+                wait::backoff();
+            }
+            self.enqueue_with(|slot| {
+                header.write(slot);
+                slot[FRAGMENT_HEADER_LEN..FRAGMENT_HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+                slot[FRAGMENT_HEADER_LEN + chunk.len()..].fill(0);
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Reassembles the blob whose fragments are at the front of the queue
+    /// into `out` and returns its length.
+    ///
+    /// Returns `QueueError::EmptyBuffer` if nothing is queued and
+    /// `QueueError::NotAFragment` if the front message is not a fragment; it
+    /// stays queued. Once the first fragment is taken, waits for the rest.
+    /// Fails with `QueueError::Truncated` when fragments are missing, and
+    /// when `out` is too small, after consuming the whole blob. Fragments
+    /// of a blob whose start was lost are discarded along with the error.
+    pub fn dequeue_large(&mut self, out: &mut [u8]) -> Result<usize, QueueError> {
+        let bytes = self.front_header()?;
+        let first = FragmentHeader::parse(&bytes).ok_or(QueueError::NotAFragment {
+            msg_type: u16::from_le_bytes([bytes[4], bytes[5]]),
+        })?;
+        if first.index != 0 {
+            let mut previous = self.take_fragment(|_| {})?;
+            while let Ok(bytes) = self.front_header() {
+                match FragmentHeader::parse(&bytes) {
+                    Some(next) if next.follows(&previous) => previous = self.take_fragment(|_| {})?,
+                    _ => break,
+                }
+            }
+            return Err(QueueError::Truncated);
+        }
+
+        let mut written = 0;
+        let mut fits = true;
+        let mut previous: Option<FragmentHeader> = None;
+        for _ in 0..first.total {
+            if let Some(previous) = &previous {
+                // The next blob's first fragment is left for the next call.
+                match FragmentHeader::parse(&self.wait_front_header()?) {
+                    Some(next) if next.follows(previous) => {}
+                    _ => return Err(QueueError::Truncated),
+                }
+            }
+            let fragment = self.take_fragment(|payload| match out.get_mut(written..written + payload.len()) {
+                Some(dest) if fits => {
+                    dest.copy_from_slice(payload);
+                    written += payload.len();
+                }
+                _ => fits = false,
+            })?;
+            previous = Some(fragment);
+        }
+        if fits {
+            Ok(written)
+        } else {
+            Err(QueueError::Truncated)
+        }
+    }
+
+    /// Dequeues the front message, known to be a fragment, passing its
+    /// payload to `payload`.
+    pub(crate) fn take_fragment(&mut self, payload: impl FnOnce(&[u8])) -> Result<FragmentHeader, QueueError> {
+        let fragment = trace::dequeue(self, |port| {
+            port.consume_front(|slot| {
+                let header = FragmentHeader::parse(slot[..FRAGMENT_HEADER_LEN].try_into().unwrap())?;
+                let len = (header.len as usize).min(FRAGMENT_PAYLOAD);
+                payload(&slot[FRAGMENT_HEADER_LEN..FRAGMENT_HEADER_LEN + len]);
+                Some(header)
+            })
+        })?;
+        fragment.ok_or(QueueError::Truncated)
+    }
+
+    /// Copies the header bytes of the front message without dequeueing it.
+    pub(crate) fn front_header(&self) -> Result<[u8; FRAGMENT_HEADER_LEN], QueueError> {
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        header.wait_while_compacting();
+        if header.message_count.load(order, Ordering::Acquire) == 0 {
+            return Err(QueueError::EmptyBuffer);
+        }
+        let read_index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize);
+        // Occupied slots belong to the reader.
+        let slot = unsafe { &*segment.slot(read_index).cast::<[u8; SIZE]>() };
+        Ok(slot[..FRAGMENT_HEADER_LEN].try_into().unwrap())
+    }
+
+    /// Like `front_header`, waiting for a message if the queue is empty.
+    fn wait_front_header(&self) -> Result<[u8; FRAGMENT_HEADER_LEN], QueueError> {
+        loop {
+            match self.front_header() {
+                Err(QueueError::EmptyBuffer) => {}
+                result => return result,
+            }
+            wait::wait(self.segment().header.message_count.raw(), 0).map_err(QueueError::WaitFailed)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, Segment};
+    use core::ptr::NonNull;
+
+    fn blob(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn blob_larger_than_queue_round_trips() {
+        // The segment outlives both handles here.
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut writer = unsafe { QueueingPort::attach(segment) };
+        let mut reader = unsafe { QueueingPort::attach(segment) };
+        let data = blob(3 * 1024);
+        assert_eq!(data.len().div_ceil(FRAGMENT_PAYLOAD), 13);
+
+        let consumer = std::thread::spawn(move || {
+            let mut out = vec![0; 4096];
+            loop {
+                match reader.dequeue_large(&mut out) {
+                    Err(QueueError::EmptyBuffer) => std::thread::yield_now(),
+                    result => break result.map(|len| out[..len].to_vec()),
+                }
+            }
+        });
+        writer.enqueue_large(&data).unwrap();
+        assert_eq!(consumer.join().unwrap().unwrap(), data);
+        assert_eq!(writer.stats().enqueued, 13);
+    }
+
+    #[test]
+    fn exact_multiples_round_trip() {
+        let mut port = QueueingPort::new();
+        for len in [3 * SIZE, 3 * FRAGMENT_PAYLOAD, 0] {
+            let data = blob(len);
+            port.enqueue_large(&data).unwrap();
+            assert_eq!(port.len(), len.div_ceil(FRAGMENT_PAYLOAD).max(1));
+            let mut out = vec![0; len];
+            assert_eq!(port.dequeue_large(&mut out).unwrap(), len);
+            assert_eq!(out, data);
+            assert!(port.is_empty());
+        }
+    }
+
+    #[test]
+    fn dropped_middle_fragment_is_detected() {
+        let mut port = QueueingPort::new();
+        port.enqueue_large(&blob(4 * FRAGMENT_PAYLOAD + 1)).unwrap();
+        // Lose fragment 2 of 5, as an overwriting queue would.
+        let fragments: Vec<Message> = (0..5).map(|_| port.dequeue_unfiltered().unwrap()).collect();
+        for (i, fragment) in fragments.into_iter().enumerate() {
+            if i != 2 {
+                port.enqueue(fragment).unwrap();
+            }
+        }
+        let intact = blob(10);
+        port.enqueue_large(&intact).unwrap();
+
+        let mut out = [0; 4096];
+        assert!(matches!(port.dequeue_large(&mut out), Err(QueueError::Truncated)));
+        // The rest of the broken blob goes with the next error.
+        assert!(matches!(port.dequeue_large(&mut out), Err(QueueError::Truncated)));
+        assert_eq!(port.dequeue_large(&mut out).unwrap(), 10);
+        assert_eq!(&out[..10], &intact[..]);
+    }
+
+    #[test]
+    fn short_buffer_consumes_blob() {
+        let mut port = QueueingPort::new();
+        port.enqueue_large(&blob(600)).unwrap();
+        let mut out = [0; 599];
+        assert!(matches!(port.dequeue_large(&mut out), Err(QueueError::Truncated)));
+        assert!(port.is_empty());
+    }
+
+    #[test]
+    fn ordinary_message_is_not_reassembled() {
+        let mut port = QueueingPort::new();
+        let mut bytes = [0; SIZE];
+        bytes[4..6].copy_from_slice(&7u16.to_le_bytes());
+        port.enqueue(Message(bytes)).unwrap();
+        assert!(matches!(
+            port.dequeue_large(&mut [0; 16]),
+            Err(QueueError::NotAFragment { msg_type: 7 })
+        ));
+        assert_eq!(port.dequeue().unwrap().msg_type(), 7);
+    }
+}
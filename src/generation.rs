@@ -0,0 +1,172 @@
+//! Telling a recreated segment from the one a handle attached to.
+//!
+//! `create()` writes a random, non-zero `segment_generation` into the
+//! header, and every handle keeps the value it found when it attached.
+//! `enqueue` and `dequeue` compare the two, a load from the header's first
+//! cache line that they touch anyway, and fail with
+//! `QueueError::StaleSegment` once the segment under the handle has been
+//! set up again.
+//!
+//! On unix, a creator that unlinks the name and creates it anew leaves the
+//! old handles on the old, unlinked mapping, whose generation never
+//! changes. Only looking the name up again shows the difference, which is
+//! what `is_stale` and `reattach` do; a supervisor can also compare the
+//! `segment_generation` of its handles.
+
+use core::sync::atomic::Ordering;
+
+use crate::{PortError, QueueError, QueueingPort};
+
+impl QueueingPort {
+    /// The generation of the segment this handle attached to; 0 for
+    /// segments not made by `create()`.
+    pub fn segment_generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Fails with `QueueError::StaleSegment` if the segment was set up
+    /// again in place since this handle attached.
+    pub(crate) fn check_generation(&self) -> Result<(), QueueError> {
+        if self.segment().header.segment_generation.load(self.byte_order(), Ordering::Relaxed) != self.generation {
+            return Err(QueueError::StaleSegment);
+        }
+        Ok(())
+    }
+
+    /// Writes a fresh generation into the header and takes it as this
+    /// handle's, as `create()` does before publishing the segment.
+    #[cfg(feature = "std")]
+    pub(crate) fn stamp_generation(&mut self) {
+        use std::hash::{BuildHasher, Hasher};
+
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let previous = header.segment_generation.load(order, Ordering::Relaxed);
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        if let Ok(since_epoch) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            hasher.write_u128(since_epoch.as_nanos());
+        }
+        let mut generation = hasher.finish() as u32;
+        if generation == 0 || generation == previous {
+            generation = previous.wrapping_add(1).max(1);
+        }
+        header.segment_generation.store(order, generation, Ordering::Relaxed);
+        self.generation = generation;
+    }
+
+    /// Whether the segment was recreated since this handle attached: for a
+    /// named port, whether the name now refers to a segment of another
+    /// generation.
+    pub fn is_stale(&self) -> Result<bool, PortError> {
+        #[cfg(feature = "shmem")]
+        if let Some(name) = self.memory.os_id() {
+            let shmem = crate::open_error::open_named(name, crate::SegmentGeometry::PORT)?;
+            // Mapped, so the header may be read whatever its state.
+            let segment = unsafe { &*shmem.as_ptr().cast::<crate::Segment>() };
+            return Ok(segment.header.segment_generation.load(segment.header.byte_order(), Ordering::Acquire) != self.generation);
+        }
+        Ok(self.check_generation().is_err())
+    }
+
+    /// Moves a stale handle over to the segment's new generation. Does
+    /// nothing if `is_stale` is false.
+    ///
+    /// A named port maps the name again and joins it as `open()` does,
+    /// with the handshake, the wire feature check and, if this handle's
+    /// segment had one, the same concurrency mode. A segment set up again
+    /// in place just has its new generation taken. Either way the handle
+    /// keeps its settings (filters, policies, clock) but forgets what it
+    /// was in the middle of: claimed slots, the `poll_hot` view, the dedup
+    /// window's history and the source `dequeue_fair` served last.
+    pub fn reattach(&mut self) -> Result<(), PortError> {
+        if !self.is_stale()? {
+            return Ok(());
+        }
+        #[cfg(feature = "shmem")]
+        if let Some(name) = self.memory.os_id() {
+            let name = alloc::string::String::from(name);
+            let mode = self.concurrency_mode();
+            let timeout = crate::named::HANDSHAKE_TIMEOUT;
+            let mut fresh = QueueingPort::open_supporting(&name, timeout, self.supported_features, mode)?;
+            // The old mapping is unmapped along with `fresh`.
+            core::mem::swap(&mut self.memory, &mut fresh.memory);
+            self.role = fresh.role;
+        }
+        self.generation = self.segment().header.segment_generation.load(self.byte_order(), Ordering::Acquire);
+        self.write_claimed = false;
+        self.read_claimed = false;
+        #[cfg(debug_assertions)]
+        {
+            self.hot_claim = None;
+        }
+        self.dedup.forget();
+        self.last_source = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, Segment, SIZE};
+    use core::ptr::NonNull;
+
+    /// Sets `segment` up again in place, as a restarted owner would.
+    fn recreate(segment: NonNull<Segment>) -> QueueingPort {
+        let mut owner = unsafe { QueueingPort::attach(segment) };
+        while owner.dequeue_unfiltered().is_ok() {}
+        owner.stamp_generation();
+        owner
+    }
+
+    #[test]
+    fn stale_handle_is_detected_and_reattached() {
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut first = recreate(segment);
+        let mut receiver = unsafe { QueueingPort::attach(segment) };
+        let generation = first.segment_generation();
+        assert_ne!(generation, 0);
+        assert_eq!(receiver.segment_generation(), generation);
+        first.enqueue(Message([1; SIZE])).unwrap();
+        assert_eq!(receiver.dequeue().unwrap().0, [1; SIZE]);
+        assert!(!receiver.is_stale().unwrap());
+
+        let mut second = recreate(segment);
+        assert_ne!(second.segment_generation(), generation);
+        second.enqueue(Message([2; SIZE])).unwrap();
+        assert!(receiver.is_stale().unwrap());
+        assert!(matches!(receiver.try_dequeue(), Err(QueueError::StaleSegment)));
+        assert!(matches!(first.enqueue(Message([3; SIZE])), Err(QueueError::StaleSegment)));
+        assert_eq!(second.len(), 1, "the stale handles changed nothing");
+
+        receiver.reattach().unwrap();
+        assert_eq!(receiver.segment_generation(), second.segment_generation());
+        assert!(!receiver.is_stale().unwrap());
+        assert_eq!(receiver.dequeue().unwrap().0, [2; SIZE]);
+    }
+
+    #[test]
+    fn reattach_forgets_claims() {
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut owner = recreate(segment);
+        let mut receiver = unsafe { QueueingPort::attach(segment) };
+        owner.enqueue(Message([1; SIZE])).unwrap();
+        assert!(receiver.poll_hot().is_some());
+
+        drop(recreate(segment));
+        receiver.reattach().unwrap();
+        // The old view's slot is not released into the new queue.
+        assert!(receiver.poll_hot().is_none());
+        assert!(receiver.is_empty());
+    }
+
+    #[test]
+    fn current_handle_reattach_is_a_no_op() {
+        let mut port = QueueingPort::new();
+        assert_eq!(port.segment_generation(), 0);
+        port.enqueue(Message([1; SIZE])).unwrap();
+        port.reattach().unwrap();
+        assert_eq!(port.dequeue().unwrap().0, [1; SIZE]);
+    }
+}
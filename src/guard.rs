@@ -0,0 +1,226 @@
+//! Ports with guard bytes between their slots, in place of guard pages.
+//!
+//! Where pages can be protected, an unmapped page after each slot turns an
+//! overrun into a fault at the instruction that made it. Where they cannot,
+//! or a page per slot is too much memory, a `GuardedQueueingPort` puts
+//! `GUARD_SIZE` bytes after each of its `MSG_COUNT` slots, filled with the
+//! bytes of `GUARD_PATTERN`, `DE AD BE EF DE AD BE EF ...`, and
+//! `check_guards` finds the slot whose guard was written to. Unlike the
+//! zeroed canaries of the `slot-poison` feature, an overrun writing zeroes
+//! is seen too.
+//!
+//! ```text
+//! 0                         slot 0, MSG_SIZE bytes
+//! MSG_SIZE                  guard 0, GUARD_SIZE bytes
+//! MSG_SIZE + GUARD_SIZE     slot 1, ...
+//! ```
+//!
+//! In debug builds `enqueue` and `dequeue` check the guards first and panic
+//! on a corrupt one, close to the overrun; release builds leave it to
+//! explicit calls.
+//!
+//! `MSG_SIZE` and `GUARD_SIZE` must be multiples of 8, so that every slot
+//! and every guard starts on an 8-byte boundary, as for `SIZE`:
+//!
+//! ```compile_fail
+//! # use ring_buffer::GuardedQueueingPort;
+//! let _ = GuardedQueueingPort::<7, 4, 8>::new();
+//! ```
+//!
+//! ```
+//! # use ring_buffer::GuardedQueueingPort;
+//! let port = GuardedQueueingPort::<64, 4, 8>::new();
+//! assert_eq!(port.check_guards(), Ok(()));
+//! ```
+
+use alloc::vec::Vec;
+
+use crate::index::{counter_distance, next_counter};
+use crate::QueueError;
+
+/// The guard bytes, repeated: byte `i` of a guard is byte `i % 8` of the
+/// pattern in big-endian order.
+pub const GUARD_PATTERN: u64 = 0xDEAD_BEEF_DEAD_BEEF;
+
+fn guard_byte(offset: usize) -> u8 {
+    GUARD_PATTERN.to_be_bytes()[offset % 8]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardError {
+    /// The guard after `slot` no longer holds the pattern.
+    Corrupt { slot: usize },
+}
+
+/// A port of `MSG_COUNT` slots of `MSG_SIZE` bytes, each followed by
+/// `GUARD_SIZE` guard bytes; see the `guard` module.
+pub struct GuardedQueueingPort<const MSG_SIZE: usize, const MSG_COUNT: usize, const GUARD_SIZE: usize> {
+    memory: Vec<u8>,
+    /// Messages enqueued and dequeued, wrapping.
+    enqueued: u32,
+    dequeued: u32,
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize, const GUARD_SIZE: usize>
+    GuardedQueueingPort<MSG_SIZE, MSG_COUNT, GUARD_SIZE>
+{
+    /// Distance between the starts of two neighbouring slots.
+    pub const fn slot_stride() -> usize {
+        MSG_SIZE + GUARD_SIZE
+    }
+
+    /// Bytes of memory the port holds.
+    pub const fn segment_len() -> usize {
+        MSG_COUNT * Self::slot_stride()
+    }
+
+    pub fn new() -> Self {
+        const { assert!(MSG_COUNT > 0 && MSG_COUNT <= u32::MAX as usize / 2, "MSG_COUNT out of range") };
+        const {
+            assert!(
+                MSG_SIZE.is_multiple_of(8) && GUARD_SIZE.is_multiple_of(8),
+                "MSG_SIZE and GUARD_SIZE must be multiples of 8 for alignment"
+            )
+        };
+        let mut memory = alloc::vec![0; Self::segment_len()];
+        for slot in 0..MSG_COUNT {
+            let guard = slot * Self::slot_stride() + MSG_SIZE;
+            for (offset, byte) in memory[guard..guard + GUARD_SIZE].iter_mut().enumerate() {
+                *byte = guard_byte(offset);
+            }
+        }
+        GuardedQueueingPort { memory, enqueued: 0, dequeued: 0 }
+    }
+
+    /// Start of slot `index` modulo `MSG_COUNT`, for writing a message in
+    /// place; bytes past `MSG_SIZE` are the slot's guard.
+    pub fn slot_ptr(&mut self, index: usize) -> *mut u8 {
+        let offset = (index % MSG_COUNT) * Self::slot_stride();
+        self.memory[offset..].as_mut_ptr()
+    }
+
+    /// Finds the first slot whose guard no longer holds the pattern.
+    pub fn check_guards(&self) -> Result<(), GuardError> {
+        for slot in 0..MSG_COUNT {
+            let guard = slot * Self::slot_stride() + MSG_SIZE;
+            let bytes = &self.memory[guard..guard + GUARD_SIZE];
+            if !bytes.iter().enumerate().all(|(offset, &byte)| byte == guard_byte(offset)) {
+                return Err(GuardError::Corrupt { slot });
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(debug_assertions)]
+    fn assert_guards(&self) {
+        if let Err(error) = self.check_guards() {
+            panic!("slot guard overwritten: {:?}", error);
+        }
+    }
+
+    /// Queues a copy of `message`, failing with `QueueError::FullBuffer` if
+    /// all slots are taken.
+    pub fn enqueue(&mut self, message: &[u8; MSG_SIZE]) -> Result<(), QueueError> {
+        #[cfg(debug_assertions)]
+        self.assert_guards();
+        if self.len() >= MSG_COUNT {
+            return Err(QueueError::FullBuffer);
+        }
+        let offset = (self.enqueued as usize % MSG_COUNT) * Self::slot_stride();
+        self.memory[offset..offset + MSG_SIZE].copy_from_slice(message);
+        self.enqueued = next_counter(self.enqueued, MSG_COUNT);
+        Ok(())
+    }
+
+    pub fn dequeue(&mut self) -> Result<[u8; MSG_SIZE], QueueError> {
+        #[cfg(debug_assertions)]
+        self.assert_guards();
+        if self.is_empty() {
+            return Err(QueueError::EmptyBuffer);
+        }
+        let offset = (self.dequeued as usize % MSG_COUNT) * Self::slot_stride();
+        let mut message = [0; MSG_SIZE];
+        message.copy_from_slice(&self.memory[offset..offset + MSG_SIZE]);
+        self.dequeued = next_counter(self.dequeued, MSG_COUNT);
+        Ok(message)
+    }
+
+    pub fn len(&self) -> usize {
+        counter_distance(self.dequeued, self.enqueued, MSG_COUNT)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity() -> usize {
+        MSG_COUNT
+    }
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize, const GUARD_SIZE: usize> Default
+    for GuardedQueueingPort<MSG_SIZE, MSG_COUNT, GUARD_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::counter_lap;
+
+    type Guarded = GuardedQueueingPort<24, 4, 16>;
+
+    #[test]
+    fn round_trip_leaves_the_guards_intact() {
+        let mut port = Guarded::new();
+        assert_eq!(Guarded::segment_len(), 4 * 40);
+        for round in 0..3u8 {
+            for tag in 0..4 {
+                port.enqueue(&[round * 4 + tag; 24]).unwrap();
+            }
+            assert!(matches!(port.enqueue(&[0; 24]), Err(QueueError::FullBuffer)));
+            for tag in 0..4 {
+                assert_eq!(port.dequeue().unwrap(), [round * 4 + tag; 24]);
+            }
+        }
+        assert!(matches!(port.dequeue(), Err(QueueError::EmptyBuffer)));
+        assert_eq!(port.check_guards(), Ok(()));
+        assert_eq!(port.memory[24..32], [0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn three_slots_stay_in_order_across_the_counter_wrap() {
+        let mut port = GuardedQueueingPort::<8, 3, 8>::new();
+        let start = (counter_lap(3) - 2) as u32;
+        (port.enqueued, port.dequeued) = (start, start);
+        for tag in 0..20u8 {
+            port.enqueue(&[tag; 8]).unwrap();
+            port.enqueue(&[tag; 8]).unwrap();
+            assert_eq!(port.len(), 2);
+            assert_eq!(port.dequeue().unwrap(), [tag; 8]);
+            assert_eq!(port.dequeue().unwrap(), [tag; 8]);
+        }
+        assert!(port.enqueued < 40, "wrapped");
+        assert_eq!(port.check_guards(), Ok(()));
+    }
+
+    #[test]
+    fn an_overrun_is_found_in_its_slot() {
+        let mut port = Guarded::new();
+        // One byte past slot 2, and a zero at that.
+        unsafe { *port.slot_ptr(2).add(24) = 0 };
+        assert_eq!(port.check_guards(), Err(GuardError::Corrupt { slot: 2 }));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Corrupt { slot: 3 }")]
+    fn enqueue_checks_the_guards() {
+        let mut port = Guarded::new();
+        unsafe { *port.slot_ptr(3).add(24 + 15) = 0xde };
+        let _ = port.enqueue(&[1; 24]);
+    }
+}
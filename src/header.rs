@@ -0,0 +1,141 @@
+//! Routing headers at the start of a message.
+//!
+//! A `MessageHeader` takes the first `MESSAGE_HEADER_LEN` bytes of a slot,
+//! little endian:
+//!
+//! ```text
+//! 0..2 src_id   2..4 dst_id   4..6 msg_type   6..8 flags
+//! ```
+//!
+//! `msg_type` sits where every message keeps its type id, so the type
+//! filter and `Message::msg_type` work on headed messages unchanged.
+
+use crate::{trace, DequeuePort, Message, QueueError, QueueingPort, SIZE};
+
+/// Bytes of a slot taken by the `MessageHeader`.
+pub const MESSAGE_HEADER_LEN: usize = 8;
+/// Payload bytes left after the header.
+pub const HEADER_PAYLOAD: usize = SIZE - MESSAGE_HEADER_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MessageHeader {
+    pub src_id: u16,
+    pub dst_id: u16,
+    pub msg_type: u16,
+    pub flags: u16,
+}
+
+impl MessageHeader {
+    /// Reads the header from the first bytes of a message.
+    pub fn read(bytes: &[u8; SIZE]) -> MessageHeader {
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        MessageHeader {
+            src_id: u16_at(0),
+            dst_id: u16_at(2),
+            msg_type: u16_at(4),
+            flags: u16_at(6),
+        }
+    }
+
+    pub fn write(&self, bytes: &mut [u8; SIZE]) {
+        for (at, value) in [(0, self.src_id), (2, self.dst_id), (4, self.msg_type), (6, self.flags)] {
+            bytes[at..at + 2].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+impl Message {
+    pub fn header(&self) -> MessageHeader {
+        MessageHeader::read(&self.0)
+    }
+}
+
+impl QueueingPort {
+    /// Enqueues `payload` behind `header`, zero-filled to the slot size.
+    /// Fails with `QueueError::MessageTooLarge` beyond `HEADER_PAYLOAD` bytes.
+    pub fn enqueue_with_header(&mut self, header: MessageHeader, payload: &[u8]) -> Result<(), QueueError> {
+        if payload.len() > HEADER_PAYLOAD {
+            return Err(QueueError::MessageTooLarge { len: payload.len() });
+        }
+        self.enqueue_with(|slot| {
+            header.write(slot);
+            slot[MESSAGE_HEADER_LEN..MESSAGE_HEADER_LEN + payload.len()].copy_from_slice(payload);
+            slot[MESSAGE_HEADER_LEN + payload.len()..].fill(0);
+        })
+    }
+
+    /// Like `dequeue`, with the message split into its header and payload.
+    pub fn dequeue_with_header(&mut self) -> Result<(MessageHeader, [u8; HEADER_PAYLOAD]), QueueError> {
+        self.dequeue_with(|slot| (MessageHeader::read(slot), slot[MESSAGE_HEADER_LEN..].try_into().unwrap()))
+    }
+
+    /// A view of the port whose `dequeue` only delivers messages of type
+    /// `msg_type`, discarding (and counting as filtered out) the others.
+    /// The port's own type filter and skip budget still apply.
+    pub fn filter_by_type(&mut self, msg_type: u16) -> impl DequeuePort + '_ {
+        ByType { port: self, msg_type }
+    }
+}
+
+struct ByType<'a> {
+    port: &'a mut QueueingPort,
+    msg_type: u16,
+}
+
+impl DequeuePort for ByType<'_> {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        let msg_type = self.msg_type;
+        trace::dequeue(self.port, |port| {
+            port.consume_filtered(
+                |found| found == msg_type && port.type_filter.allows(found),
+                |slot| Message(*slot),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(msg_type: u16) -> MessageHeader {
+        MessageHeader { src_id: 1, dst_id: 0x0203, msg_type, flags: 0x8000 }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut port = QueueingPort::new();
+        port.enqueue_with_header(header(42), b"payload").unwrap();
+        let (received, payload) = port.dequeue_with_header().unwrap();
+        assert_eq!(received, header(42));
+        assert_eq!(&payload[..8], b"payload\0");
+
+        let too_long = [0; HEADER_PAYLOAD + 1];
+        assert!(matches!(
+            port.enqueue_with_header(header(42), &too_long),
+            Err(QueueError::MessageTooLarge { len }) if len == HEADER_PAYLOAD + 1
+        ));
+    }
+
+    #[test]
+    fn header_layout_keeps_type_id_in_place() {
+        let mut bytes = [0; SIZE];
+        header(0x0a0b).write(&mut bytes);
+        assert_eq!(&bytes[..8], &[1, 0, 3, 2, 0x0b, 0x0a, 0, 0x80]);
+        assert_eq!(Message(bytes).msg_type(), 0x0a0b);
+    }
+
+    #[test]
+    fn filter_by_type_skips_other_types() {
+        let mut port = QueueingPort::new();
+        for msg_type in [1, 2, 1, 3, 2] {
+            port.enqueue_with_header(header(msg_type), &[msg_type as u8]).unwrap();
+        }
+        let mut twos = port.filter_by_type(2);
+        assert_eq!(twos.dequeue().unwrap().header().msg_type, 2);
+        assert_eq!(twos.dequeue().unwrap().header().msg_type, 2);
+        assert!(matches!(twos.dequeue(), Err(QueueError::EmptyBuffer)));
+        drop(twos);
+        assert_eq!(port.stats().filtered_out, 3);
+    }
+}
@@ -0,0 +1,158 @@
+//! A JSON health summary of a port, for diagnostics endpoints and files.
+//!
+//! One object per port, on one line:
+//!
+//! ```text
+//! {"port":"sensor","state":"open","len":7,"capacity":10,"enqueued":10,
+//!  "dequeued":3,"rejected":1,"high_watermark":10,"filtered_out":0,
+//!  "deadline_misses":0}
+//! ```
+//!
+//! `state` is the handshake state of PROTOCOL.md; ports that never went
+//! through a handshake, such as in-memory ones, stay `"uninit"`. The
+//! counters are the ones in the segment header, as in `QueueStats`.
+
+use std::fmt::Write;
+use std::string::String;
+use std::sync::atomic::Ordering;
+
+use crate::QueueingPort;
+
+impl QueueingPort {
+    /// Appends this port's health object to `out`, named `port_name`.
+    pub fn render_health_json(&self, port_name: &str, out: &mut String) {
+        let stats = self.stats();
+        out.push_str("{\"port\":\"");
+        push_json_string(out, port_name);
+        // Writing into a String cannot fail.
+        let _ = write!(
+            out,
+            "\",\"state\":\"{}\",\"len\":{},\"capacity\":{},\"enqueued\":{},\"dequeued\":{},\"rejected\":{},\
+             \"high_watermark\":{},\"filtered_out\":{},\"deadline_misses\":{}}}",
+            state_name(self.segment().header.state.load(Ordering::Acquire)),
+            self.len(),
+            self.capacity(),
+            stats.enqueued,
+            stats.dequeued,
+            stats.rejected,
+            stats.high_watermark,
+            stats.filtered_out,
+            stats.deadline_misses,
+        );
+    }
+}
+
+/// The handshake states of PROTOCOL.md, by their byte.
+fn state_name(state: u8) -> &'static str {
+    match state {
+        0 => "uninit",
+        1 => "writer_ready",
+        2 => "reader_ready",
+        3 => "open",
+        4 => "closed",
+        5 => "initializing",
+        _ => "unknown",
+    }
+}
+
+/// Escapes `value` as the contents of a JSON string.
+fn push_json_string(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+/// Parses a flat JSON object of string and integer members, enough to
+/// check what `render_health_json` wrote.
+#[cfg(test)]
+pub(crate) fn parse_flat_object(text: &str) -> std::collections::BTreeMap<String, String> {
+    let mut members = std::collections::BTreeMap::new();
+    let mut chars = text.trim().strip_prefix('{').and_then(|t| t.strip_suffix('}')).expect("an object").chars();
+    let string = |chars: &mut std::str::Chars<'_>| {
+        let mut value = String::new();
+        loop {
+            match chars.next().expect("unterminated string") {
+                '"' => return value,
+                '\\' => match chars.next().unwrap() {
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        value.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                    }
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+    };
+    while let Some(c) = chars.next() {
+        assert_eq!(c, '"', "a member name");
+        let name = string(&mut chars);
+        assert_eq!(chars.next(), Some(':'));
+        let rest = chars.as_str();
+        let value = if let Some(rest) = rest.strip_prefix('"') {
+            chars = rest.chars();
+            string(&mut chars)
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let number = &rest[..end];
+            number.parse::<u64>().expect("an integer");
+            chars = rest[end..].chars();
+            number.to_owned()
+        };
+        members.insert(name, value);
+        match chars.next() {
+            Some(',') | None => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    members
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+
+    #[test]
+    fn health_object_has_the_counters() {
+        let mut port = QueueingPort::new();
+        for _ in 0..port.capacity() {
+            port.enqueue(Message([0; SIZE])).unwrap();
+        }
+        assert!(port.enqueue(Message([0; SIZE])).is_err());
+        for _ in 0..3 {
+            port.dequeue().unwrap();
+        }
+        let mut out = String::new();
+        port.render_health_json("sensor", &mut out);
+        assert_eq!(
+            out,
+            "{\"port\":\"sensor\",\"state\":\"uninit\",\"len\":7,\"capacity\":10,\"enqueued\":10,\"dequeued\":3,\
+             \"rejected\":1,\"high_watermark\":10,\"filtered_out\":0,\"deadline_misses\":0}"
+        );
+        let members = parse_flat_object(&out);
+        assert_eq!(members["len"], "7");
+        assert_eq!(members.len(), 10);
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        let mut out = String::new();
+        QueueingPort::new().render_health_json("a\"b\\c\nd\u{1}", &mut out);
+        assert!(out.starts_with("{\"port\":\"a\\\"b\\\\c\\nd\\u0001\","));
+        assert_eq!(parse_flat_object(&out)["port"], "a\"b\\c\nd\u{1}");
+    }
+}
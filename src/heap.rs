@@ -0,0 +1,341 @@
+//! A priority queue kept as a binary max-heap, for ports where the memory
+//! of one sub-queue per priority level is too much.
+//!
+//! The segment holds the message slots followed by the heap: one
+//! `HeapEntry` per slot, ordered by priority and then by sequence number,
+//! so messages of equal priority leave in the order they came. Entries
+//! past the heap's length record the free slots, which keeps the two
+//! arrays the same size and needs no separate free list.
+//!
+//! `enqueue_priority` appends an entry and sifts it up: O(log n) in the
+//! worst case, and O(1) on average for priorities that are not correlated
+//! with arrival order, since half the entries of a heap are leaves. The
+//! `dequeue` takes the root and sifts the last entry down in O(log n).
+//!
+//! A heap cannot be updated with single atomic stores like a ring, so
+//! every operation holds the segment's `lock` word; the sender and the
+//! receiver spin on it while the other is inside.
+//!
+//! The slots follow each other without padding, so `MSG_SIZE` must be a
+//! multiple of 8 to keep each on an 8-byte boundary, as for `SIZE`:
+//!
+//! ```compile_fail
+//! # use ring_buffer::HeapQueueingPort;
+//! let _ = HeapQueueingPort::<7, 4>::new();
+//! ```
+//!
+//! ```
+//! # use ring_buffer::HeapQueueingPort;
+//! let port = HeapQueueingPort::<64, 4>::new();
+//! assert!(port.is_empty());
+//! ```
+
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::QueueError;
+
+/// One message's place in the heap.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapEntry {
+    pub priority: u8,
+    /// Arrival order, wrapping; only differences between entries matter.
+    pub seq: u32,
+    /// The slot holding the message.
+    pub slot: u32,
+}
+
+impl HeapEntry {
+    /// Whether `self` leaves before `other`: higher priority first, then
+    /// the older one.
+    fn before(&self, other: &HeapEntry) -> bool {
+        self.priority > other.priority
+            || (self.priority == other.priority && (self.seq.wrapping_sub(other.seq) as i32) < 0)
+    }
+}
+
+/// The shared part of a `HeapQueueingPort`: the lock and counters, the
+/// slots, then the heap.
+#[repr(C)]
+pub struct HeapSegment<const MSG_SIZE: usize, const MSG_COUNT: usize> {
+    /// 1 while a handle is updating the segment.
+    lock: AtomicU32,
+    /// Entries in the heap, i.e. queued messages.
+    len: AtomicU32,
+    /// The `seq` of the next message enqueued.
+    next_seq: AtomicU32,
+    slots: UnsafeCell<[[u8; MSG_SIZE]; MSG_COUNT]>,
+    heap: UnsafeCell<[HeapEntry; MSG_COUNT]>,
+}
+
+unsafe impl<const MSG_SIZE: usize, const MSG_COUNT: usize> Sync for HeapSegment<MSG_SIZE, MSG_COUNT> {}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize> HeapSegment<MSG_SIZE, MSG_COUNT> {
+    pub const fn new() -> Self {
+        const { assert!(MSG_COUNT <= u32::MAX as usize, "MSG_COUNT does not fit a slot index") };
+        const { assert!(MSG_SIZE.is_multiple_of(8), "MSG_SIZE must be a multiple of 8 for alignment") };
+        let mut heap = [HeapEntry { priority: 0, seq: 0, slot: 0 }; MSG_COUNT];
+        let mut slot = 0;
+        while slot < MSG_COUNT {
+            heap[slot].slot = slot as u32;
+            slot += 1;
+        }
+        HeapSegment {
+            lock: AtomicU32::new(0),
+            len: AtomicU32::new(0),
+            next_seq: AtomicU32::new(0),
+            slots: UnsafeCell::new([[0; MSG_SIZE]; MSG_COUNT]),
+            heap: UnsafeCell::new(heap),
+        }
+    }
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize> Default for HeapSegment<MSG_SIZE, MSG_COUNT> {
+    fn default() -> Self {
+        HeapSegment::new()
+    }
+}
+
+enum HeapMemory<const MSG_SIZE: usize, const MSG_COUNT: usize> {
+    Owned(HeapSegment<MSG_SIZE, MSG_COUNT>),
+    Attached(NonNull<HeapSegment<MSG_SIZE, MSG_COUNT>>),
+}
+
+/// A port handing out `MSG_SIZE`-byte messages highest priority first,
+/// up to `MSG_COUNT` at a time.
+pub struct HeapQueueingPort<const MSG_SIZE: usize, const MSG_COUNT: usize> {
+    memory: HeapMemory<MSG_SIZE, MSG_COUNT>,
+}
+
+// The segment is only touched under its lock.
+unsafe impl<const MSG_SIZE: usize, const MSG_COUNT: usize> Send for HeapQueueingPort<MSG_SIZE, MSG_COUNT> {}
+
+/// The segment's contents while its lock is held.
+struct Locked<'a, const MSG_SIZE: usize, const MSG_COUNT: usize> {
+    segment: &'a HeapSegment<MSG_SIZE, MSG_COUNT>,
+    slots: &'a mut [[u8; MSG_SIZE]; MSG_COUNT],
+    heap: &'a mut [HeapEntry; MSG_COUNT],
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize> Drop for Locked<'_, MSG_SIZE, MSG_COUNT> {
+    fn drop(&mut self) {
+        self.segment.lock.store(0, Ordering::Release);
+    }
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize> HeapQueueingPort<MSG_SIZE, MSG_COUNT> {
+    pub fn new() -> Self {
+        HeapQueueingPort { memory: HeapMemory::Owned(HeapSegment::new()) }
+    }
+
+    /// Creates a port operating on a segment that lives elsewhere, typically
+    /// in memory shared with another process.
+    ///
+    /// # Safety
+    ///
+    /// `segment` must point to a `HeapSegment` set up by `HeapSegment::new`
+    /// that outlives the returned port.
+    pub unsafe fn attach(segment: NonNull<HeapSegment<MSG_SIZE, MSG_COUNT>>) -> Self {
+        const { assert!(MSG_SIZE.is_multiple_of(8), "MSG_SIZE must be a multiple of 8 for alignment") };
+        HeapQueueingPort { memory: HeapMemory::Attached(segment) }
+    }
+
+    fn segment(&self) -> &HeapSegment<MSG_SIZE, MSG_COUNT> {
+        match &self.memory {
+            HeapMemory::Owned(segment) => segment,
+            HeapMemory::Attached(segment) => unsafe { segment.as_ref() },
+        }
+    }
+
+    fn lock(&self) -> Locked<'_, MSG_SIZE, MSG_COUNT> {
+        let segment = self.segment();
+        while segment
+            .lock
+            .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // The lock makes this handle the only one looking at either array.
+        Locked {
+            segment,
+            slots: unsafe { &mut *segment.slots.get() },
+            heap: unsafe { &mut *segment.heap.get() },
+        }
+    }
+
+    /// Queues `message` behind every queued message of `priority` or
+    /// higher, failing with `QueueError::FullBuffer` if all slots are taken.
+    pub fn enqueue_priority(&mut self, priority: u8, message: &[u8; MSG_SIZE]) -> Result<(), QueueError> {
+        let locked = self.lock();
+        let len = locked.segment.len.load(Ordering::Relaxed) as usize;
+        if len == MSG_COUNT {
+            return Err(QueueError::FullBuffer);
+        }
+        let seq = locked.segment.next_seq.load(Ordering::Relaxed);
+        locked.segment.next_seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        // The entry past the end names a free slot.
+        let slot = locked.heap[len].slot;
+        locked.slots[slot as usize % MSG_COUNT] = *message;
+        locked.heap[len] = HeapEntry { priority, seq, slot };
+        sift_up(&mut locked.heap[..=len]);
+        locked.segment.len.store(len as u32 + 1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Takes the queued message of the highest priority, the oldest of
+    /// them if several share it.
+    pub fn dequeue(&mut self) -> Result<[u8; MSG_SIZE], QueueError> {
+        self.dequeue_with_priority().map(|(_, message)| message)
+    }
+
+    /// Like `dequeue`, also returning the message's priority.
+    pub fn dequeue_with_priority(&mut self) -> Result<(u8, [u8; MSG_SIZE]), QueueError> {
+        let locked = self.lock();
+        let len = (locked.segment.len.load(Ordering::Relaxed) as usize).min(MSG_COUNT);
+        if len == 0 {
+            return Err(QueueError::EmptyBuffer);
+        }
+        let top = locked.heap[0];
+        let message = locked.slots[top.slot as usize % MSG_COUNT];
+        // The root's entry moves past the end, handing back its slot.
+        locked.heap.swap(0, len - 1);
+        sift_down(&mut locked.heap[..len - 1]);
+        locked.segment.len.store(len as u32 - 1, Ordering::Relaxed);
+        Ok((top.priority, message))
+    }
+
+    /// The priority of the message `dequeue` would return next.
+    pub fn peek_priority(&self) -> Option<u8> {
+        let locked = self.lock();
+        (locked.segment.len.load(Ordering::Relaxed) > 0).then(|| locked.heap[0].priority)
+    }
+
+    pub fn len(&self) -> usize {
+        self.segment().len.load(Ordering::Relaxed) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity() -> usize {
+        MSG_COUNT
+    }
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize> Default for HeapQueueingPort<MSG_SIZE, MSG_COUNT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Restores the heap order after the last entry of `heap` was added.
+fn sift_up(heap: &mut [HeapEntry]) {
+    let mut child = heap.len() - 1;
+    while child > 0 {
+        let parent = (child - 1) / 2;
+        if !heap[child].before(&heap[parent]) {
+            break;
+        }
+        heap.swap(child, parent);
+        child = parent;
+    }
+}
+
+/// Restores the heap order after the first entry of `heap` was replaced.
+fn sift_down(heap: &mut [HeapEntry]) {
+    let mut parent = 0;
+    loop {
+        let mut first = parent;
+        for child in [2 * parent + 1, 2 * parent + 2] {
+            if child < heap.len() && heap[child].before(&heap[first]) {
+                first = child;
+            }
+        }
+        if first == parent {
+            return;
+        }
+        heap.swap(parent, first);
+        parent = first;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn dequeue_is_in_descending_priority() {
+        let mut port = HeapQueueingPort::<8, 32>::new();
+        // A fixed xorshift sequence, so a failure can be reproduced.
+        let mut state = 0x2545_f491u32;
+        let mut priorities = Vec::new();
+        for i in 0..20u32 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let priority = (state % 8) as u8;
+            priorities.push(priority);
+            port.enqueue_priority(priority, &u64::from(i).to_le_bytes()).unwrap();
+        }
+        assert_eq!(port.len(), 20);
+
+        let mut received = Vec::new();
+        while let Ok((priority, message)) = port.dequeue_with_priority() {
+            received.push((priority, u64::from_le_bytes(message) as u32));
+        }
+        let mut expected: Vec<(u8, u32)> = priorities.iter().copied().zip(0..).collect();
+        // Descending priority, ties in the order they were enqueued.
+        expected.sort_by_key(|&(priority, i)| (core::cmp::Reverse(priority), i));
+        assert_eq!(received, expected);
+        assert!(port.is_empty());
+    }
+
+    #[test]
+    fn slots_are_reused_once_full() {
+        let mut port = HeapQueueingPort::<8, 4>::default();
+        for i in 0..4 {
+            port.enqueue_priority(i, &[i; 8]).unwrap();
+        }
+        assert!(matches!(port.enqueue_priority(9, &[9; 8]), Err(QueueError::FullBuffer)));
+        assert_eq!(port.dequeue().unwrap(), [3; 8]);
+        port.enqueue_priority(9, &[9; 8]).unwrap();
+        assert_eq!(port.peek_priority(), Some(9));
+        let drained: Vec<u8> = core::iter::from_fn(|| port.dequeue().ok()).map(|m| m[0]).collect();
+        assert_eq!(drained, [9, 2, 1, 0]);
+        assert!(matches!(port.dequeue(), Err(QueueError::EmptyBuffer)));
+    }
+
+    #[test]
+    fn sender_and_receiver_on_one_segment() {
+        let segment = NonNull::from(Box::leak(Box::new(HeapSegment::<8, 16>::new())));
+        let mut sender = unsafe { HeapQueueingPort::attach(segment) };
+        let mut receiver = unsafe { HeapQueueingPort::attach(segment) };
+        let producer = thread::spawn(move || {
+            for i in 0..1000u64 {
+                while sender.enqueue_priority((i % 3) as u8, &i.to_le_bytes()).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut seen = vec![false; 1000];
+        let mut count = 0;
+        while count < 1000 {
+            match receiver.dequeue() {
+                Ok(message) => {
+                    let i = u64::from_le_bytes(message) as usize;
+                    assert!(!seen[i], "message {} delivered twice", i);
+                    seen[i] = true;
+                    count += 1;
+                }
+                Err(_) => thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+        assert!(receiver.is_empty());
+    }
+}
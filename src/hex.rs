@@ -0,0 +1,109 @@
+//! Hex dumps of messages for logs, without allocating.
+
+use core::fmt;
+use core::ops::Deref;
+
+use crate::{Message, SIZE};
+
+/// Two digits per byte and a separator after every byte but the last.
+const HEX_LEN: usize = SIZE * 3 - 1;
+
+/// A message formatted as space-separated upper case hex bytes, from
+/// `Message::to_hex_string`.
+#[derive(Clone)]
+pub struct HexString {
+    bytes: [u8; HEX_LEN],
+}
+
+impl Deref for HexString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        // Only ASCII hex digits and spaces are ever written.
+        core::str::from_utf8(&self.bytes).unwrap()
+    }
+}
+
+impl fmt::Display for HexString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self)
+    }
+}
+
+impl fmt::Debug for HexString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+fn write_hex(bytes: &[u8; SIZE], out: &mut [u8; HEX_LEN], digits: &[u8; 16]) {
+    for (i, byte) in bytes.iter().enumerate() {
+        out[i * 3] = digits[usize::from(byte >> 4)];
+        out[i * 3 + 1] = digits[usize::from(byte & 0xf)];
+        if let Some(separator) = out.get_mut(i * 3 + 2) {
+            *separator = b' ';
+        }
+    }
+}
+
+const UPPER: &[u8; 16] = b"0123456789ABCDEF";
+const LOWER: &[u8; 16] = b"0123456789abcdef";
+
+impl Message {
+    /// The whole message as hex bytes separated by spaces, e.g.
+    /// `"DE AD BE EF 00 ..."`.
+    pub fn to_hex_string(&self) -> HexString {
+        let mut hex = HexString { bytes: [0; HEX_LEN] };
+        write_hex(&self.0, &mut hex.bytes, UPPER);
+        hex
+    }
+
+    /// Writes the same text as `to_hex_string` to `f`.
+    pub fn fmt_hex(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex_string())
+    }
+}
+
+/// `{:x}` gives the `to_hex_string` layout with lower case digits.
+impl fmt::LowerHex for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut hex = HexString { bytes: [0; HEX_LEN] };
+        write_hex(&self.0, &mut hex.bytes, LOWER);
+        f.write_str(&hex)
+    }
+}
+
+/// `{:X}` is the same as `fmt_hex`.
+impl fmt::UpperHex for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_hex(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deadbeef() -> Message {
+        let mut bytes = [0; SIZE];
+        bytes[..4].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        bytes[SIZE - 1] = 0x0a;
+        Message(bytes)
+    }
+
+    #[test]
+    fn formats_bytes_separated_by_spaces() {
+        let hex = deadbeef().to_hex_string();
+        assert!(hex.starts_with("DE AD BE EF 00 "));
+        assert!(hex.ends_with(" 00 0A"));
+        assert_eq!(hex.len(), HEX_LEN);
+        assert_eq!(hex.split(' ').count(), SIZE);
+    }
+
+    #[test]
+    fn hex_format_traits() {
+        let message = deadbeef();
+        assert_eq!(format!("{:X}", message), &*message.to_hex_string());
+        assert!(format!("{:x}", message).starts_with("de ad be ef 00 "));
+    }
+}
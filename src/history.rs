@@ -0,0 +1,256 @@
+//! The most recently consumed messages, kept for replay after a fault.
+//!
+//! On a port created with `PortConfig::history`, which sets
+//! `WireFeatures::HISTORY`, every message taken off the queue (delivered,
+//! discarded by a filter, or evicted) has its `MessageHeader` and the
+//! first `history_bytes()` bytes of payload copied into a ring of
+//! `HISTORY_CAPACITY` records in the segment header, overwriting the
+//! oldest. That is a few dozen word stores per dequeue whatever the queue
+//! holds, and a single load of the features word without the feature.
+//!
+//! Any handle, or an `Observer`, copies the records out newest first with
+//! `history`. Each record carries a generation, odd while it is being
+//! written and moved on by 2 per write, so a reader taking a copy while
+//! the consumer overwrites it notices, and stops at the first record
+//! rewritten under it.
+
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+use crate::{MessageHeader, QueueingPort, Segment, SegmentHeader, WireFeatures, WireU32, MESSAGE_HEADER_LEN, SIZE};
+
+/// Consumed messages the history keeps.
+pub const HISTORY_CAPACITY: usize = 10;
+/// Most payload bytes a record keeps, and the default.
+pub const HISTORY_PAYLOAD: usize = 64;
+
+const RECORD_BYTES: usize = MESSAGE_HEADER_LEN + HISTORY_PAYLOAD;
+
+/// One record of the ring, in the segment header.
+#[repr(C)]
+pub(crate) struct HistoryRecord {
+    generation: WireU32,
+    sequence: WireU32,
+    /// The first bytes of the message, as they were in the slot.
+    bytes: [AtomicU32; RECORD_BYTES / 4],
+}
+
+impl HistoryRecord {
+    pub(crate) const fn new() -> HistoryRecord {
+        HistoryRecord {
+            generation: WireU32::zero(),
+            sequence: WireU32::zero(),
+            bytes: [const { AtomicU32::new(0) }; RECORD_BYTES / 4],
+        }
+    }
+}
+
+/// A consumed message, as `history` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// The message's sequence number, as `dequeue_timed` reports it.
+    pub sequence: u32,
+    pub header: MessageHeader,
+    payload: [u8; HISTORY_PAYLOAD],
+    payload_len: usize,
+}
+
+impl HistoryEntry {
+    pub const fn new() -> HistoryEntry {
+        let header = MessageHeader { src_id: 0, dst_id: 0, msg_type: 0, flags: 0 };
+        HistoryEntry { sequence: 0, header, payload: [0; HISTORY_PAYLOAD], payload_len: 0 }
+    }
+
+    /// The start of the payload, `history_bytes()` long.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.payload_len]
+    }
+}
+
+impl Default for HistoryEntry {
+    fn default() -> Self {
+        HistoryEntry::new()
+    }
+}
+
+impl SegmentHeader {
+    /// Empties the history of a segment no peer sees yet.
+    pub(crate) fn reset_history(&self) {
+        let order = self.byte_order();
+        self.history_written.store(order, 0, Ordering::Relaxed);
+        for record in &self.history {
+            record.generation.store(order, 0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Segment {
+    /// Copies the message in slot `index` into the history, if the port
+    /// keeps one. Called by the consumer before it frees the slot.
+    pub(crate) fn record_history(&self, index: usize) {
+        let header = &self.header;
+        if !header.wire_features().contains(WireFeatures::HISTORY) {
+            return;
+        }
+        let order = header.byte_order();
+        let written = header.history_written.load(order, Ordering::Relaxed);
+        let record = &header.history[written as usize % HISTORY_CAPACITY];
+        record.generation.fetch_add(order, 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        // SAFETY: occupied slots belong to the consumer, which calls this.
+        let slot = unsafe { self.read_slot(index) };
+        for (word, bytes) in record.bytes.iter().zip(slot.chunks_exact(4)) {
+            word.store(u32::from_ne_bytes(bytes.try_into().unwrap()), Ordering::Relaxed);
+        }
+        record.sequence.store(order, header.slot_sequence[index].load(order, Ordering::Relaxed), Ordering::Relaxed);
+        record.generation.fetch_add(order, 1, Ordering::Release);
+        header.history_written.store(order, written.wrapping_add(1), Ordering::Release);
+    }
+}
+
+impl QueueingPort {
+    /// Payload bytes each history record keeps; 0 without history.
+    pub fn history_bytes(&self) -> usize {
+        let header = &self.segment().header;
+        if !header.wire_features().contains(WireFeatures::HISTORY) {
+            return 0;
+        }
+        (header.history_bytes.load(self.byte_order(), Ordering::Relaxed) as usize).min(HISTORY_PAYLOAD)
+    }
+
+    /// Copies the most recently consumed messages into `out`, newest first,
+    /// and returns how many: at most `HISTORY_CAPACITY`, and none without
+    /// history. See the module documentation.
+    pub fn history(&self, out: &mut [HistoryEntry]) -> usize {
+        if !self.wire_features().contains(WireFeatures::HISTORY) {
+            return 0;
+        }
+        let payload_len = self.history_bytes();
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let written = header.history_written.load(order, Ordering::Acquire);
+        let available = (written as usize).min(HISTORY_CAPACITY);
+        for (age, entry) in out.iter_mut().take(available).enumerate() {
+            let position = written.wrapping_sub(1 + age as u32);
+            let record = &header.history[position as usize % HISTORY_CAPACITY];
+            // What the record's generation is once position's write is done.
+            let expected = (position / HISTORY_CAPACITY as u32).wrapping_add(1).wrapping_mul(2);
+            if record.generation.load(order, Ordering::Acquire) != expected {
+                return age;
+            }
+            let mut bytes = [0; RECORD_BYTES];
+            for (chunk, word) in bytes.chunks_exact_mut(4).zip(&record.bytes) {
+                chunk.copy_from_slice(&word.load(Ordering::Relaxed).to_ne_bytes());
+            }
+            let sequence = record.sequence.load(order, Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            if record.generation.load(order, Ordering::Relaxed) != expected {
+                return age;
+            }
+            let mut slot = [0; SIZE];
+            slot[..RECORD_BYTES].copy_from_slice(&bytes);
+            entry.sequence = sequence;
+            entry.header = MessageHeader::read(&slot);
+            entry.payload = [0; HISTORY_PAYLOAD];
+            entry.payload[..payload_len].copy_from_slice(&bytes[MESSAGE_HEADER_LEN..][..payload_len]);
+            entry.payload_len = payload_len;
+        }
+        available.min(out.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, Observer, PortConfig, MSGS};
+    use core::ptr::NonNull;
+    use std::thread;
+
+    fn command(n: u8) -> Message {
+        let mut bytes = [n; SIZE];
+        MessageHeader { src_id: 1, dst_id: 2, msg_type: 3, flags: u16::from(n) }.write(&mut bytes);
+        Message(bytes)
+    }
+
+    #[test]
+    fn history_holds_the_consumed_messages_newest_first() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().history(16));
+        assert_eq!(port.history_bytes(), 16);
+        let mut out = [HistoryEntry::new(); HISTORY_CAPACITY];
+        assert_eq!(port.history(&mut out), 0);
+
+        for n in 0..4 {
+            port.enqueue(command(n)).unwrap();
+        }
+        port.dequeue().unwrap();
+        port.dequeue().unwrap();
+        assert_eq!(port.history(&mut out), 2);
+        assert_eq!((out[0].sequence, out[1].sequence), (1, 0));
+        assert_eq!(out[0].header, MessageHeader { src_id: 1, dst_id: 2, msg_type: 3, flags: 1 });
+        assert_eq!(out[0].payload(), &[1; 16]);
+
+        // Only as many as `out` holds, and still queued ones are not in it.
+        assert_eq!(port.history(&mut out[..1]), 1);
+        assert_eq!(out[0].sequence, 1);
+        assert_eq!(port.len(), 2);
+
+        let mut plain = QueueingPort::new();
+        plain.enqueue(command(0)).unwrap();
+        plain.dequeue().unwrap();
+        assert_eq!((plain.history_bytes(), plain.history(&mut out)), (0, 0));
+    }
+
+    #[test]
+    fn history_wraps_keeping_the_newest() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().wire_features(WireFeatures::HISTORY));
+        assert_eq!(port.history_bytes(), HISTORY_PAYLOAD, "the default");
+        let consumed = 3 * HISTORY_CAPACITY as u32 + 4;
+        for n in 0..consumed {
+            port.enqueue(command(n as u8)).unwrap();
+            port.dequeue().unwrap();
+        }
+        let mut out = [HistoryEntry::new(); HISTORY_CAPACITY + 2];
+        assert_eq!(port.history(&mut out), HISTORY_CAPACITY);
+        for (age, entry) in out[..HISTORY_CAPACITY].iter().enumerate() {
+            let sequence = consumed - 1 - age as u32;
+            assert_eq!(entry.sequence, sequence);
+            assert_eq!(entry.payload(), &[sequence as u8; HISTORY_PAYLOAD]);
+        }
+    }
+
+    #[test]
+    fn observer_reads_history_during_traffic() {
+        const MESSAGES: u32 = 20_000;
+        let segment = Box::new(Segment::new());
+        let mut port = unsafe { QueueingPort::attach(NonNull::from(&*segment)) };
+        PortConfig::new().history(8).apply(&mut port);
+        let observer = unsafe { Observer::attach(NonNull::from(&*segment)) };
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for n in 0..MESSAGES {
+                    while port.len() >= MSGS {
+                        port.dequeue().unwrap();
+                    }
+                    port.enqueue(command(n as u8)).unwrap();
+                }
+                while port.try_dequeue().is_ok() {}
+            });
+            let mut out = [HistoryEntry::new(); HISTORY_CAPACITY];
+            let mut last_newest = 0;
+            while last_newest + 1 < MESSAGES {
+                let count = observer.history(&mut out);
+                // Whatever was caught is consecutive, newest first, and intact.
+                for pair in out[..count].windows(2) {
+                    assert_eq!(pair[0].sequence, pair[1].sequence + 1);
+                }
+                for entry in &out[..count] {
+                    assert_eq!(entry.header.flags, entry.sequence as u8 as u16);
+                    assert_eq!(entry.payload(), &[entry.sequence as u8; 8]);
+                }
+                if count > 0 {
+                    assert!(out[0].sequence >= last_newest);
+                    last_newest = out[0].sequence;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,135 @@
+//! A busy-polling receive path for the lowest latencies.
+//!
+//! `poll_hot` does as little as possible between a message being published
+//! and the caller seeing it: one acquire load of `message_count`, and a
+//! borrowed view of the slot instead of a copy. Freeing the slot, with its
+//! release store, is left to the next call, so it happens after the caller
+//! has finished with the message rather than before. While the queue is
+//! empty, every call prefetches the slot the next message will land in.
+//!
+//! The view is the slot itself, so it is only sound while nothing else
+//! writes the slot:
+//!
+//! - the writer never touches a slot `message_count` has handed over, and
+//!   this one is not handed back until the next `poll_hot` (or
+//!   `commit_read`), which needs `&mut self` and so ends the borrow;
+//! - no other handle may dequeue from the segment meanwhile, as for every
+//!   reader; debug builds check the slot's generation on release and panic
+//!   if it was freed under the view.
+
+use core::sync::atomic::Ordering;
+
+use crate::{slot_index, QueueingPort, SIZE};
+
+/// Cache lines of a slot, assuming 64-byte lines.
+const SLOT_LINES: usize = SIZE.div_ceil(64);
+
+/// Hints the CPU to load the slot at `slot` into cache. A no-op on targets
+/// without a prefetch instruction here.
+#[inline(always)]
+fn prefetch(slot: *const u8) {
+    for line in 0..SLOT_LINES {
+        let _address = slot.wrapping_add(line * 64);
+        #[cfg(all(target_arch = "x86_64", target_feature = "sse"))]
+        // Prefetching never faults, whatever the address.
+        unsafe {
+            core::arch::x86_64::_mm_prefetch::<{ core::arch::x86_64::_MM_HINT_T0 }>(_address.cast())
+        };
+        #[cfg(target_arch = "aarch64")]
+        // As above: `prfm` is a hint and never faults.
+        unsafe {
+            core::arch::asm!("prfm pldl1keep, [{0}]", in(reg) _address, options(nostack, preserves_flags, readonly))
+        };
+    }
+}
+
+impl QueueingPort {
+    /// Frees the message returned by the previous call, then returns the
+    /// next one in place, or `None` if none is queued yet.
+    ///
+    /// Like `claim_read_slice`, which it shares its claim with, this
+    /// bypasses the type filter, the dedup window and CRC checks. On a
+    /// strict FIFO port the order is checked on release, where an
+    /// out-of-order message is consumed without a report.
+    pub fn poll_hot(&mut self) -> Option<&[u8]> {
+        #[cfg(debug_assertions)]
+        self.check_hot_claim();
+        self.commit_read();
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        let index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize);
+        let slot = segment.slot(index);
+        if header.message_count.load(order, Ordering::Acquire) == 0 {
+            prefetch(slot);
+            return None;
+        }
+        #[cfg(debug_assertions)]
+        {
+            self.hot_claim = Some((index, header.slot_generation[index].load(order, Ordering::Relaxed)));
+        }
+        self.read_claimed = true;
+        // Occupied slots belong to the reader until the next call frees it.
+        Some(unsafe { core::slice::from_raw_parts(slot, SIZE) })
+    }
+
+    /// Panics if the slot of the last `poll_hot` view changed generation.
+    #[cfg(debug_assertions)]
+    fn check_hot_claim(&self) {
+        let Some((index, generation)) = self.hot_claim else { return };
+        assert_eq!(
+            self.segment().header.slot_generation[index].load(self.byte_order(), Ordering::Relaxed),
+            generation,
+            "slot {} was freed while poll_hot's view of it was live",
+            index
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, Segment};
+    use core::ptr::NonNull;
+
+    #[test]
+    fn messages_are_freed_on_the_next_poll() {
+        let mut port = QueueingPort::new();
+        assert!(port.poll_hot().is_none());
+        port.enqueue(Message([1; SIZE])).unwrap();
+        port.enqueue(Message([2; SIZE])).unwrap();
+
+        assert_eq!(port.poll_hot().unwrap(), &[1; SIZE]);
+        assert_eq!(port.len(), 2, "still queued while it is being read");
+        assert_eq!(port.poll_hot().unwrap(), &[2; SIZE]);
+        assert_eq!(port.len(), 1);
+        assert!(port.poll_hot().is_none());
+        assert!(port.is_empty());
+        assert_eq!(port.stats().dequeued, 2);
+    }
+
+    #[test]
+    fn commit_read_releases_the_view() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([1; SIZE])).unwrap();
+        port.poll_hot().unwrap();
+        port.commit_read();
+        assert!(port.is_empty());
+        assert!(port.poll_hot().is_none());
+        assert_eq!(port.stats().dequeued, 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "freed while poll_hot's view of it was live")]
+    fn second_reader_is_caught_in_debug_builds() {
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut hot = unsafe { QueueingPort::attach(segment) };
+        let mut other = unsafe { QueueingPort::attach(segment) };
+        hot.enqueue(Message([1; SIZE])).unwrap();
+        hot.poll_hot().unwrap();
+        // Breaks the contract: a second reader frees the slot.
+        other.dequeue().unwrap();
+        hot.poll_hot();
+    }
+}
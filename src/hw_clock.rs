@@ -0,0 +1,150 @@
+//! A clock on the CPU's cycle counter, for latencies below a microsecond.
+//!
+//! `HwClock` reads the time stamp counter on x86-64 and the virtual
+//! counter `CNTVCT_EL0` on AArch64: one instruction, no system call, and a
+//! resolution of a cycle or a few. The counter is converted to nanoseconds
+//! with a rate calibrated against `std::time::Instant` the first time any
+//! `HwClock` is used in the process, which takes about 10 ms. Readings are
+//! placed on the same time base as `StdClock`, so the two can be mixed
+//! within a process, give or take the calibration error.
+//!
+//! On x86-64 this assumes an invariant TSC, one that ticks at a constant
+//! rate and in step on every core, as on all x86-64 CPUs of the last
+//! fifteen years or so; across sockets of older machines readings may not
+//! agree. On other architectures `HwClock` is `StdClock`.
+
+use std::sync::OnceLock;
+
+use crate::{Clock, StdClock};
+
+/// How long the calibration watches the counter.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const CALIBRATION: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// The cycle-counter clock, see the module documentation.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HwClock;
+
+/// The rate and a reading of the counter taken at `StdClock` time
+/// `origin_ns`.
+struct Calibration {
+    cycles_per_ns: f64,
+    origin_cycles: u64,
+    origin_ns: u64,
+}
+
+impl HwClock {
+    /// Counter cycles per nanosecond, as calibrated; 1.0 where `HwClock`
+    /// falls back to `StdClock`.
+    pub fn cycles_per_ns() -> f64 {
+        calibration().cycles_per_ns
+    }
+}
+
+impl Clock for HwClock {
+    fn now_ns(&self) -> u64 {
+        // Calibrated first, so the reading is never older than the origin.
+        let calibration = calibration();
+        let Some(cycles) = counter() else {
+            return StdClock.now_ns();
+        };
+        // A core whose counter lags another's by a few cycles reads as the
+        // origin rather than wrapping.
+        let elapsed = cycles.saturating_sub(calibration.origin_cycles) as f64 / calibration.cycles_per_ns;
+        calibration.origin_ns.saturating_add(elapsed as u64)
+    }
+}
+
+fn calibration() -> &'static Calibration {
+    static CALIBRATION_RESULT: OnceLock<Calibration> = OnceLock::new();
+    CALIBRATION_RESULT.get_or_init(calibrate)
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn calibrate() -> Calibration {
+    use std::time::Instant;
+
+    let origin_ns = StdClock.now_ns();
+    let started = Instant::now();
+    let origin_cycles = counter().unwrap();
+    // Spun rather than slept, so the core stays awake and the counter is
+    // read right after `Instant` in both pairs.
+    let (elapsed, cycles) = loop {
+        let elapsed = started.elapsed();
+        let cycles = counter().unwrap();
+        if elapsed >= CALIBRATION {
+            break (elapsed, cycles);
+        }
+        core::hint::spin_loop();
+    };
+    let cycles_per_ns = cycles.wrapping_sub(origin_cycles) as f64 / elapsed.as_nanos() as f64;
+    Calibration { cycles_per_ns, origin_cycles, origin_ns }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn calibrate() -> Calibration {
+    Calibration { cycles_per_ns: 1.0, origin_cycles: 0, origin_ns: 0 }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn counter() -> Option<u64> {
+    Some(rdtsc())
+}
+
+#[cfg(target_arch = "aarch64")]
+fn counter() -> Option<u64> {
+    Some(cntvct())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn counter() -> Option<u64> {
+    None
+}
+
+/// The time stamp counter.
+#[cfg(target_arch = "x86_64")]
+fn rdtsc() -> u64 {
+    // Available on every x86-64 CPU.
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// The virtual counter, after an `isb` so it is not read early.
+#[cfg(target_arch = "aarch64")]
+fn cntvct() -> u64 {
+    let cycles: u64;
+    // Readable from EL0 wherever Linux and macOS run.
+    unsafe { core::arch::asm!("isb", "mrs {}, cntvct_el0", out(reg) cycles, options(nomem, nostack)) };
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn tracks_the_std_clock() {
+        let clock = HwClock;
+        let rate = HwClock::cycles_per_ns();
+        assert!(rate.is_finite() && rate > 0.0, "{}", rate);
+
+        let (hw_start, std_start) = (clock.now_ns(), StdClock.now_ns());
+        std::thread::sleep(Duration::from_millis(50));
+        let (hw_end, std_end) = (clock.now_ns(), StdClock.now_ns());
+        let (hw, std) = ((hw_end - hw_start) as f64, (std_end - std_start) as f64);
+        assert!((hw - std).abs() < std * 0.2, "{} ns against {} ns", hw, std);
+        // Same time base, within what a loaded test machine allows.
+        assert!(hw_start.abs_diff(std_start) < 5_000_000, "{} against {}", hw_start, std_start);
+    }
+
+    #[test]
+    fn never_goes_backwards() {
+        let clock = HwClock;
+        let mut last = clock.now_ns();
+        for _ in 0..100_000 {
+            let now = clock.now_ns();
+            assert!(now >= last, "{} after {}", now, last);
+            last = now;
+        }
+    }
+}
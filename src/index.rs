@@ -0,0 +1,161 @@
+//! Turning a position in the ring into a slot index.
+//!
+//! With a power-of-two slot count the index is the position's low bits, a
+//! mask; otherwise it takes a division, which on cores without a hardware
+//! divide (Cortex-M0+, for one) is a library call on every enqueue and
+//! dequeue. The slot count is a constant everywhere in the crate, so the
+//! choice is made at compile time and the other path is never built.
+//!
+//! `QueueingPort` has `MSGS` slots and `AlignedQueueingPort` its
+//! `MSG_COUNT`; `index_math()` on either tells which path it uses, and
+//! `Observer::dump` prints it. `mask_slots` rounds a wanted depth up to a
+//! count that masks, for a port that should never divide:
+//!
+//! ```
+//! # use ring_buffer::{mask_slots, AlignedQueueingPort, IndexMath};
+//! type Port = AlignedQueueingPort<64, { mask_slots(6) }, 64>;
+//! assert_eq!(Port::capacity(), 8);
+//! assert_eq!(Port::index_math(), IndexMath::Mask);
+//! ```
+//!
+//! The aligned and guarded ports keep free-running `u32` counters. With
+//! the mask they run through all of `u32` and wrap; a count that does not
+//! divide 2^32 would then jump slots at the wrap, so with the division
+//! they wrap at the largest multiple of the count that fits, `counter_lap`,
+//! and the slot after the wrap follows the one before it either way.
+
+use core::fmt;
+
+/// How positions become slot indices, see the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexMath {
+    /// `position & (count - 1)`, for a power-of-two count.
+    Mask,
+    /// `position % count`.
+    Modulo,
+}
+
+impl IndexMath {
+    /// The path for a ring of `count` slots.
+    pub const fn for_slots(count: usize) -> IndexMath {
+        if count.is_power_of_two() {
+            IndexMath::Mask
+        } else {
+            IndexMath::Modulo
+        }
+    }
+}
+
+impl fmt::Display for IndexMath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IndexMath::Mask => "mask",
+            IndexMath::Modulo => "modulo",
+        })
+    }
+}
+
+/// The smallest slot count of at least `depth` slots that takes the mask.
+pub const fn mask_slots(depth: usize) -> usize {
+    depth.next_power_of_two()
+}
+
+/// The slot of `position` in a ring of `count` slots.
+#[inline(always)]
+pub(crate) const fn wrap(position: usize, count: usize) -> usize {
+    match IndexMath::for_slots(count) {
+        IndexMath::Mask => position & (count - 1),
+        IndexMath::Modulo => position % count,
+    }
+}
+
+/// Where the counters of a ring of `count` slots wrap, see the module
+/// documentation: 2^32 for a count that masks.
+pub(crate) const fn counter_lap(count: usize) -> u64 {
+    let span = u32::MAX as u64 + 1;
+    span - span % count as u64
+}
+
+/// The counter after `counter` in a ring of `count` slots.
+#[inline(always)]
+pub(crate) const fn next_counter(counter: u32, count: usize) -> u32 {
+    match IndexMath::for_slots(count) {
+        IndexMath::Mask => counter.wrapping_add(1),
+        IndexMath::Modulo if counter as u64 + 1 == counter_lap(count) => 0,
+        IndexMath::Modulo => counter + 1,
+    }
+}
+
+/// How many counters `to` is past `from` in a ring of `count` slots.
+#[inline(always)]
+pub(crate) const fn counter_distance(from: u32, to: u32, count: usize) -> usize {
+    match IndexMath::for_slots(count) {
+        IndexMath::Mask => to.wrapping_sub(from) as usize,
+        IndexMath::Modulo if to >= from => (to - from) as usize,
+        IndexMath::Modulo => (to as u64 + counter_lap(count) - from as u64) as usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Positions across the ring's own wrap and across the wrap of a
+    /// `u32` counter.
+    fn positions() -> impl Iterator<Item = usize> {
+        let top = u32::MAX as usize;
+        (0..1000).chain(top - 1000..=top).chain((top + 1)..top + 1000)
+    }
+
+    #[test]
+    fn mask_and_modulo_agree() {
+        for count in [1, 2, 4, 8, 16, 64, 1024, 1 << 20] {
+            assert_eq!(IndexMath::for_slots(count), IndexMath::Mask, "{}", count);
+            for position in positions() {
+                assert_eq!(wrap(position, count), position % count, "{} of {}", position, count);
+            }
+        }
+        for count in [3, 5, 6, 10, 100] {
+            assert_eq!(IndexMath::for_slots(count), IndexMath::Modulo, "{}", count);
+        }
+    }
+
+    #[test]
+    fn masked_counters_run_on_across_the_u32_wrap() {
+        // A free-running u32 counter, as the aligned port keeps.
+        let counters: Vec<u32> = (0..40).map(|i| (u32::MAX - 20).wrapping_add(i)).collect();
+        for count in [1, 4, 8, 16] {
+            let indices: Vec<usize> = counters.iter().map(|&counter| wrap(counter as usize, count)).collect();
+            assert!(indices.windows(2).all(|w| w[1] == (w[0] + 1) % count), "{} slots: {:?}", count, indices);
+            let modulo: Vec<usize> = counters.iter().map(|&counter| counter as usize % count).collect();
+            assert_eq!(indices, modulo);
+        }
+    }
+
+    #[test]
+    fn divided_counters_wrap_at_a_multiple_of_the_count() {
+        for count in [3, 5, 6, 10, 100] {
+            let lap = counter_lap(count);
+            assert!(lap.is_multiple_of(count as u64) && lap <= 1 << 32 && lap + count as u64 > 1 << 32, "{}", count);
+            let mut counter = (lap - 20) as u32;
+            let mut indices = Vec::new();
+            for _ in 0..40 {
+                indices.push(wrap(counter as usize, count));
+                counter = next_counter(counter, count);
+            }
+            assert!(indices.windows(2).all(|w| w[1] == (w[0] + 1) % count), "{} slots: {:?}", count, indices);
+            assert_eq!(counter, 20);
+            assert_eq!(counter_distance((lap - 3) as u32, 4, count), 7);
+            assert_eq!(counter_distance(4, 4, count), 0);
+        }
+        assert_eq!(counter_lap(8), 1 << 32);
+        assert_eq!((next_counter(u32::MAX, 8), counter_distance(u32::MAX, 2, 8)), (0, 3));
+    }
+
+    #[test]
+    fn depths_round_up_to_a_mask() {
+        assert_eq!((mask_slots(1), mask_slots(6), mask_slots(8), mask_slots(10)), (1, 8, 8, 16));
+        assert_eq!(IndexMath::for_slots(mask_slots(10)), IndexMath::Mask);
+        assert_eq!(IndexMath::Modulo.to_string(), "modulo");
+    }
+}
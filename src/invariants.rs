@@ -0,0 +1,159 @@
+//! Consistency checks over a port's segment, for tests and debugging.
+
+use core::sync::atomic::Ordering;
+
+use crate::{slot_index, QueueingPort, MSGS};
+#[cfg(feature = "slot-poison")]
+use crate::{CANARY_LEN, SIZE};
+
+/// The first inconsistency `check_invariants` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// `message_count` exceeds the number of slots.
+    CountOutOfRange { count: u32 },
+    /// An index is not the index of a slot.
+    IndexOutOfRange { read_index: u32, write_index: u32 },
+    /// `write_index` is not `message_count` slots after `read_index`.
+    IndexMismatch { read_index: u32, write_index: u32, count: u32 },
+    /// The guard bytes after `slot` were overwritten, typically by an
+    /// `enqueue_with` closure writing past the end of its slice.
+    CanaryCorrupted { slot: usize },
+}
+
+impl QueueingPort {
+    /// Checks that the header is self-consistent and, with the `slot-poison`
+    /// feature, that no write ran past the end of a slot.
+    ///
+    /// Only meaningful while the peer is not in the middle of an operation.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        self.check_indices()?;
+
+        // Canaries are zero, like the rest of a fresh segment, so memory
+        // needs no extra set-up. An overrun that writes zeroes goes unseen.
+        #[cfg(feature = "slot-poison")]
+        for slot in 0..MSGS {
+            let segment = self.segment();
+            let canary = unsafe { core::slice::from_raw_parts(segment.slot(slot).add(SIZE), CANARY_LEN) };
+            if canary.iter().any(|&byte| byte != 0) {
+                return Err(InvariantViolation::CanaryCorrupted { slot });
+            }
+        }
+        Ok(())
+    }
+
+    /// The header half of `check_invariants`: the indices and count.
+    fn check_indices(&self) -> Result<(), InvariantViolation> {
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let count = header.message_count.load(order, Ordering::Acquire);
+        let read_index = header.read_index.load(order, Ordering::Relaxed);
+        let write_index = header.write_index.load(order, Ordering::Relaxed);
+        if count as usize > MSGS {
+            return Err(InvariantViolation::CountOutOfRange { count });
+        }
+        if read_index as usize >= MSGS || write_index as usize >= MSGS {
+            return Err(InvariantViolation::IndexOutOfRange { read_index, write_index });
+        }
+        if slot_index(read_index as usize + count as usize) != slot_index(write_index as usize) {
+            return Err(InvariantViolation::IndexMismatch {
+                read_index,
+                write_index,
+                count,
+            });
+        }
+        Ok(())
+    }
+
+    /// Panics, naming the violation, unless the indices and count are
+    /// consistent as `check_invariants` checks them; the slot canaries are
+    /// left to that. Debug builds call it after every enqueue and dequeue
+    /// on a port that owns its segment, where no peer can be in the middle
+    /// of one.
+    #[cfg(debug_assertions)]
+    pub fn assert_invariants(&self) {
+        if let Err(violation) = self.check_indices() {
+            panic!("port invariants violated: {:?}", violation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+
+    #[test]
+    fn fresh_and_used_ports_are_consistent() {
+        let mut port = QueueingPort::new();
+        assert_eq!(port.check_invariants(), Ok(()));
+        for _ in 0..MSGS + 3 {
+            port.enqueue(Message([1; SIZE])).unwrap();
+            port.dequeue().unwrap();
+        }
+        port.enqueue(Message([2; SIZE])).unwrap();
+        assert_eq!(port.check_invariants(), Ok(()));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn empty_half_full_and_full_ports_pass() {
+        let mut port = QueueingPort::new();
+        port.assert_invariants();
+        for _ in 0..MSGS / 2 {
+            port.enqueue(Message([1; SIZE])).unwrap();
+        }
+        port.assert_invariants();
+        while port.enqueue(Message([2; SIZE])).is_ok() {}
+        assert_eq!(port.len(), MSGS);
+        port.assert_invariants();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "IndexOutOfRange")]
+    fn enqueue_asserts_the_invariants() {
+        let mut port = QueueingPort::new();
+        port.segment().header.read_index.store(port.byte_order(), MSGS as u32 + 1, Ordering::Relaxed);
+        let _ = port.enqueue(Message([1; SIZE]));
+    }
+
+    #[test]
+    fn index_mismatch_is_reported() {
+        let port = QueueingPort::new();
+        port.segment().header.write_index.store(port.byte_order(), 3, Ordering::Relaxed);
+        assert_eq!(
+            port.check_invariants(),
+            Err(InvariantViolation::IndexMismatch {
+                read_index: 0,
+                write_index: 3,
+                count: 0
+            })
+        );
+    }
+
+    #[cfg(feature = "slot-poison")]
+    #[test]
+    fn overrun_from_enqueue_with_hits_the_canary() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([1; SIZE])).unwrap();
+        port.enqueue_with(|slot| {
+            slot.fill(2);
+            // One byte too far: the first guard byte after slot 1.
+            unsafe { slot.as_mut_ptr().add(SIZE).write(2) };
+        })
+        .unwrap();
+        assert_eq!(port.check_invariants(), Err(InvariantViolation::CanaryCorrupted { slot: 1 }));
+    }
+
+    #[cfg(feature = "slot-poison")]
+    #[test]
+    fn dequeued_slots_are_poisoned() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([7; SIZE])).unwrap();
+        let stale = port.dequeue_with(|slot| slot.as_ptr()).unwrap();
+        // Reading through the kept pointer is exactly the bug being caught.
+        let after = unsafe { core::slice::from_raw_parts(stale, SIZE) };
+        assert!(after.iter().all(|&byte| byte == 0xDE));
+        assert_eq!(port.check_invariants(), Ok(()));
+    }
+}
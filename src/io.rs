@@ -0,0 +1,184 @@
+//! `std::io::Read` and `Write` on a port, for queues used as byte streams.
+//!
+//! Every `write` puts its bytes into as many messages as there is room
+//! for, each a complete one-fragment blob in the format of the `fragment`
+//! module, so `dequeue_large` can take them too. `read` hands them out
+//! again in order, ignoring where one message ends and the next begins.
+//! A message only partly read is trimmed in its slot and left at the
+//! front of the queue for the next call.
+//!
+//! Both are non-blocking: `write` on a full queue and `read` on an empty
+//! one fail with `io::ErrorKind::WouldBlock`, so the stream has no end and
+//! `read` never returns `Ok(0)` for a non-empty buffer.
+
+use core::sync::atomic::Ordering;
+use std::io;
+
+use crate::fragment::FragmentHeader;
+use crate::{slot_index, QueueError, QueueingPort, FRAGMENT_HEADER_LEN, FRAGMENT_PAYLOAD, SIZE};
+
+/// `WouldBlock` for the errors that go away once the peer catches up.
+fn io_error(error: QueueError) -> io::Error {
+    match error {
+        QueueError::FullBuffer | QueueError::EmptyBuffer | QueueError::NoCredit | QueueError::NoPeer => {
+            io::ErrorKind::WouldBlock.into()
+        }
+        QueueError::NotAFragment { .. } | QueueError::Truncated => {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", error))
+        }
+        error => io::Error::other(format!("{:?}", error)),
+    }
+}
+
+impl io::Write for QueueingPort {
+    /// Enqueues as much of `buf` as the free slots hold, failing with
+    /// `WouldBlock` if there are none.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut written = 0;
+        for chunk in buf.chunks(FRAGMENT_PAYLOAD) {
+            let id = self.segment().header.enqueued.load(self.byte_order(), Ordering::Relaxed);
+            let header = FragmentHeader { id, index: 0, total: 1, len: chunk.len() as u16 };
+            let result = self.enqueue_with(|slot| {
+                header.write(slot);
+                slot[FRAGMENT_HEADER_LEN..FRAGMENT_HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+                slot[FRAGMENT_HEADER_LEN + chunk.len()..].fill(0);
+            });
+            match result {
+                Ok(()) => written += chunk.len(),
+                // What fitted is reported; the rest is for the next call.
+                Err(_) if written > 0 => break,
+                Err(error) => return Err(io_error(error)),
+            }
+        }
+        Ok(written)
+    }
+
+    /// Messages are handed over as they are written.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Read for QueueingPort {
+    /// Copies queued bytes into `buf`, failing with `WouldBlock` if there
+    /// are none and with `InvalidData` if the front message is not a
+    /// fragment, which stays queued.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.read_front(&mut buf[read..]) {
+                Ok(len) => read += len,
+                Err(_) if read > 0 => break,
+                Err(error) => return Err(io_error(error)),
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl QueueingPort {
+    /// Moves the first bytes of the front fragment into `out`, dequeueing
+    /// it once it is empty.
+    fn read_front(&mut self, out: &mut [u8]) -> Result<usize, QueueError> {
+        let bytes = self.front_header()?;
+        let header = FragmentHeader::parse(&bytes).ok_or(QueueError::NotAFragment {
+            msg_type: u16::from_le_bytes([bytes[4], bytes[5]]),
+        })?;
+        let len = (header.len as usize).min(FRAGMENT_PAYLOAD);
+        if len <= out.len() {
+            self.take_fragment(|payload| out[..len].copy_from_slice(payload))?;
+            return Ok(len);
+        }
+        let segment = self.segment();
+        let index = slot_index(segment.header.read_index.load(segment.header.byte_order(), Ordering::Relaxed) as usize);
+        // Occupied slots belong to the reader until it dequeues them.
+        let slot = unsafe { &mut *segment.slot(index).cast::<[u8; SIZE]>() };
+        let payload = &mut slot[FRAGMENT_HEADER_LEN..FRAGMENT_HEADER_LEN + len];
+        out.copy_from_slice(&payload[..out.len()]);
+        payload.copy_within(out.len().., 0);
+        FragmentHeader { len: (len - out.len()) as u16, ..header }.write(slot);
+        Ok(out.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, Segment, MSGS};
+    use core::ptr::NonNull;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    #[test]
+    fn pipe_4096_bytes_between_threads() {
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut writer = unsafe { QueueingPort::attach(segment) };
+        let mut reader = unsafe { QueueingPort::attach(segment) };
+        let data: Vec<u8> = (0..4096).map(|i| (i * 31 % 251) as u8).collect();
+
+        let producer = thread::spawn({
+            let data = data.clone();
+            move || {
+                let mut sent = 0;
+                while sent < data.len() {
+                    match writer.write(&data[sent..]) {
+                        Ok(len) => sent += len,
+                        Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                        Err(error) => panic!("{}", error),
+                    }
+                }
+            }
+        });
+        let mut received = Vec::new();
+        // An odd size, so messages are split between reads.
+        let mut buf = [0; 100];
+        while received.len() < data.len() {
+            match reader.read(&mut buf) {
+                Ok(len) => received.extend_from_slice(&buf[..len]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(error) => panic!("{}", error),
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn full_and_empty_would_block() {
+        let mut port = QueueingPort::new();
+        let mut buf = [0; 8];
+        assert_eq!(port.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(port.write(&[]).unwrap(), 0);
+
+        let data = vec![7; MSGS * FRAGMENT_PAYLOAD + 10];
+        assert_eq!(port.write(&data).unwrap(), MSGS * FRAGMENT_PAYLOAD, "as much as fits");
+        assert_eq!(port.write(&data).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        port.flush().unwrap();
+    }
+
+    #[test]
+    fn partial_read_leaves_the_rest_queued() {
+        let mut port = QueueingPort::new();
+        port.write_all(b"hello, world").unwrap();
+        let mut buf = [0; 5];
+        port.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(port.len(), 1);
+
+        // The trimmed message is still a whole blob.
+        let mut rest = [0; 16];
+        assert_eq!(port.dequeue_large(&mut rest).unwrap(), 7);
+        assert_eq!(&rest[..7], b", world");
+    }
+
+    #[test]
+    fn non_fragments_are_invalid_data() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([1; SIZE])).unwrap();
+        assert_eq!(port.read(&mut [0; 4]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(port.len(), 1);
+    }
+}
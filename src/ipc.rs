@@ -0,0 +1,89 @@
+//! Bounds for code generic over the kind of queue it is given.
+//!
+//! `IpcQueue` is every queue that can be both enqueued into and dequeued
+//! from and moved to another thread, implemented for all of them at once.
+//! It does not ask for `Sync`: a `QueueingPort` keeps per-handle state in
+//! `Cell`s and is not, and threads share one behind a `Mutex`, which is
+//! `Sync` for any `Send` queue.
+//!
+//! `SingleReader` and `MultiReader` say how the messages are taken out.
+//! A `SingleReader` queue has the one reader, which gets every message in
+//! order; several threads may dequeue from a `MultiReader` queue at once,
+//! each message going to one of them. Code that hands a queue to a pool of
+//! workers can ask for the latter:
+//!
+//! ```compile_fail
+//! # use ring_buffer::{MultiReader, QueueingPort};
+//! fn share_out<Q: MultiReader>(queue: Q) {}
+//! share_out(QueueingPort::new());
+//! ```
+
+use crate::{BoundedQueueingPort, DequeuePort, EnqueuePort, MpmcQueue, QueueingPort};
+
+/// A queue generic code can enqueue into, dequeue from and send to other
+/// threads, see the `ipc` module.
+pub trait IpcQueue: EnqueuePort + DequeuePort + Send + 'static {}
+
+impl<Q: EnqueuePort + DequeuePort + Send + 'static> IpcQueue for Q {}
+
+/// A queue with one reader, which gets every message in order.
+pub trait SingleReader {}
+
+/// A queue several readers take messages from at once, each message going
+/// to one of them.
+pub trait MultiReader {}
+
+impl SingleReader for QueueingPort {}
+#[cfg(feature = "alloc")]
+impl SingleReader for crate::LocalQueue {}
+impl<const MAX_DEPTH: usize> SingleReader for BoundedQueueingPort<MAX_DEPTH> {}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize> MultiReader for MpmcQueue<MSG_SIZE, MSG_COUNT> {}
+#[cfg(feature = "std")]
+impl<const TAG: u64> MultiReader for crate::TypedPortHandle<TAG> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+    use std::sync::{Arc, Mutex};
+
+    /// Echoes every queued message back into the queue, incremented.
+    fn process<Q: IpcQueue>(queue: &Mutex<Q>) -> usize {
+        let mut queue = queue.lock().unwrap();
+        let mut processed = 0;
+        while let Ok(mut message) = queue.dequeue() {
+            message.0[0] += 1;
+            queue.enqueue(message).unwrap();
+            processed += 1;
+            if processed == 3 {
+                break;
+            }
+        }
+        processed
+    }
+
+    fn single_reader<Q: IpcQueue + SingleReader>(queue: Q) -> Q {
+        queue
+    }
+
+    fn multi_reader<Q: IpcQueue + MultiReader>(queue: Q) -> Q {
+        queue
+    }
+
+    #[test]
+    fn shared_ports_are_ipc_queues() {
+        let port = Arc::new(Mutex::new(single_reader(QueueingPort::new())));
+        port.lock().unwrap().enqueue(Message([1; SIZE])).unwrap();
+        let worker = std::thread::spawn({
+            let port = port.clone();
+            move || process(&port)
+        });
+        assert_eq!(worker.join().unwrap(), 3);
+        assert_eq!(port.lock().unwrap().dequeue().unwrap().0[0], 4);
+
+        let mpmc = Mutex::new(multi_reader(MpmcQueue::<SIZE, 4>::new()));
+        mpmc.lock().unwrap().enqueue(Message([1; SIZE])).unwrap();
+        assert_eq!(process(&mpmc), 3);
+    }
+}
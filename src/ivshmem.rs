@@ -0,0 +1,363 @@
+//! Ports in ivshmem device memory, for queues between virtual machines.
+//!
+//! QEMU's ivshmem device exposes a shared-memory region to each guest as a
+//! PCI BAR; on the host the same region is a file, e.g. in `/dev/shm`. The
+//! segment is laid out at a configurable offset inside that region, in the
+//! usual layout, so the host and any number of guests can each take one end.
+//!
+//! A host-backed region without interrupts only needs a memory backend:
+//!
+//! ```text
+//! -object memory-backend-file,id=hostmem,share=on,mem-path=/dev/shm/ivshmem,size=4M
+//! -device ivshmem-plain,memdev=hostmem
+//! ```
+//!
+//! Interrupts need `ivshmem-server` on the host and the doorbell device in
+//! each guest:
+//!
+//! ```text
+//! ivshmem-server -S /tmp/ivshmem_socket -M ivshmem -l 4M -n 1
+//! -chardev socket,path=/tmp/ivshmem_socket,id=ivsh
+//! -device ivshmem-doorbell,chardev=ivsh,vectors=1
+//! ```
+//!
+//! In the guest the shared memory is BAR2 and the registers BAR0 of the
+//! device, e.g. `/sys/bus/pci/devices/0000:00:04.0/resource2` and
+//! `resource0`. The writer rings the peer's doorbell after each enqueue;
+//! the reader waits for the interrupt on the device's UIO node
+//! (`/dev/uioN`, with the `uio_pci_generic` or `uio_ivshmem` driver bound).
+//! Without those, `no_interrupt()` makes both ends poll. At high rates
+//! `notify_policy` coalesces the doorbells, see `NotifyPolicy`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr::{self, NonNull};
+
+use crate::notify::Coalescer;
+use crate::{wait, DequeuePort, EnqueuePort, Memory, Message, NotifyPolicy, PortError, QueueError, QueueingPort, Segment,
+    ShmemBuffer};
+
+/// Offset of the doorbell register in BAR0 of an ivshmem-doorbell device.
+const DOORBELL_REGISTER: usize = 0x0c;
+/// BAR0 is 256 bytes of registers.
+const REGISTERS_LEN: usize = 256;
+
+/// Builder for an [`IvshmemPort`].
+#[derive(Debug, Clone)]
+pub struct IvshmemConfig {
+    path: PathBuf,
+    offset: usize,
+    doorbell: Option<Doorbell>,
+    notify_policy: NotifyPolicy,
+    interrupt_device: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+struct Doorbell {
+    registers: PathBuf,
+    peer_id: u16,
+    vector: u16,
+}
+
+impl IvshmemConfig {
+    /// Shared memory at `/dev/shm/ivshmem`, segment at offset 0, polling.
+    pub fn new() -> IvshmemConfig {
+        IvshmemConfig {
+            path: PathBuf::from("/dev/shm/ivshmem"),
+            offset: 0,
+            doorbell: None,
+            notify_policy: NotifyPolicy::EveryMessage,
+            interrupt_device: None,
+        }
+    }
+
+    /// The file or device (e.g. a `resource2` BAR file) holding the region.
+    pub fn path(mut self, path: impl AsRef<Path>) -> IvshmemConfig {
+        self.path = path.as_ref().to_owned();
+        self
+    }
+
+    /// Where the segment starts inside the region; must be 4-byte aligned.
+    pub fn offset(mut self, offset: usize) -> IvshmemConfig {
+        self.offset = offset;
+        self
+    }
+
+    /// Rings interrupt `vector` of peer `peer_id` through the register BAR
+    /// at `registers` after every enqueue.
+    pub fn doorbell(mut self, registers: impl AsRef<Path>, peer_id: u16, vector: u16) -> IvshmemConfig {
+        self.doorbell = Some(Doorbell {
+            registers: registers.as_ref().to_owned(),
+            peer_id,
+            vector,
+        });
+        self
+    }
+
+    /// When enqueues ring the doorbell; every one by default. A writer
+    /// under another policy calls `IvshmemPort::poll_notify` or
+    /// `flush_notify` when it goes quiet.
+    pub fn notify_policy(mut self, policy: NotifyPolicy) -> IvshmemConfig {
+        self.notify_policy = policy;
+        self
+    }
+
+    /// Makes `dequeue_blocking` sleep on the UIO node `device` until the
+    /// peer rings this VM's doorbell.
+    pub fn interrupt_device(mut self, device: impl AsRef<Path>) -> IvshmemConfig {
+        self.interrupt_device = Some(device.as_ref().to_owned());
+        self
+    }
+
+    /// Drops any doorbell and interrupt device: the writer does not notify
+    /// and the reader polls.
+    pub fn no_interrupt(mut self) -> IvshmemConfig {
+        self.doorbell = None;
+        self.interrupt_device = None;
+        self
+    }
+
+    /// Maps the region and returns a port on the segment inside it. The
+    /// segment is used as it is; an all-zero region is an empty port.
+    pub fn open(&self) -> Result<IvshmemPort, PortError> {
+        if !self.offset.is_multiple_of(core::mem::align_of::<Segment>()) {
+            return Err(PortError::Misaligned);
+        }
+        let file = open_rw(&self.path)?;
+        let len = self.offset + core::mem::size_of::<Segment>();
+        if (file.metadata().map_err(PortError::Io)?.len() as usize) < len {
+            return Err(PortError::LayoutMismatch);
+        }
+        let region = Mapping::new(&file, len).map_err(PortError::Io)?;
+        let doorbell = match &self.doorbell {
+            Some(doorbell) => Some((
+                Mapping::new(&open_rw(&doorbell.registers)?, REGISTERS_LEN).map_err(PortError::Io)?,
+                u32::from(doorbell.peer_id) << 16 | u32::from(doorbell.vector),
+            )),
+            None => None,
+        };
+        let interrupt = match &self.interrupt_device {
+            Some(device) => Some(open_rw(device)?),
+            None => None,
+        };
+
+        // The mapping is page aligned, the offset 4-byte aligned, and the
+        // region covers the whole segment; it lives as long as the port.
+        let segment = unsafe { NonNull::new_unchecked(region.ptr.as_ptr().add(self.offset)) }.cast::<Segment>();
+        Ok(IvshmemPort {
+            port: QueueingPort::from_memory(Memory::Attached(ShmemBuffer::new(segment))),
+            _region: region,
+            doorbell,
+            coalescer: Coalescer::new(self.notify_policy),
+            interrupt,
+        })
+    }
+}
+
+impl Default for IvshmemConfig {
+    fn default() -> Self {
+        IvshmemConfig::new()
+    }
+}
+
+fn open_rw(path: &Path) -> Result<File, PortError> {
+    OpenOptions::new().read(true).write(true).open(path).map_err(PortError::Io)
+}
+
+/// A shared `mmap` of the first `len` bytes of a file.
+struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: NonNull::new(ptr.cast()).expect("mmap returned null"),
+            len,
+        })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+/// A port on ivshmem memory, see [`IvshmemConfig`].
+///
+/// Dereferences to the underlying `QueueingPort`; `enqueue` and
+/// `dequeue_blocking` are overridden to use the doorbell and interrupts.
+pub struct IvshmemPort {
+    // Declared before the mapping it points into.
+    port: QueueingPort,
+    _region: Mapping,
+    /// Register mapping and the value that rings the peer.
+    doorbell: Option<(Mapping, u32)>,
+    coalescer: Coalescer,
+    interrupt: Option<File>,
+}
+
+impl IvshmemPort {
+    /// Enqueues and, with a doorbell configured, interrupts the peer as the
+    /// notify policy says.
+    pub fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        self.port.enqueue(message)?;
+        if self.coalescer.enqueued(self.port.len(), self.port.clock().now_ns()) {
+            self.notify_peer();
+        }
+        Ok(())
+    }
+
+    /// Rings the doorbell if a `NotifyPolicy::Deadline` has passed for an
+    /// enqueue not yet notified, returning whether it did.
+    pub fn poll_notify(&mut self) -> bool {
+        let rang = self.coalescer.poll(self.port.clock().now_ns());
+        if rang {
+            self.notify_peer();
+        }
+        rang
+    }
+
+    /// Rings the doorbell if any enqueue is not yet notified, returning
+    /// whether it did.
+    pub fn flush_notify(&mut self) -> bool {
+        let rang = self.coalescer.flush();
+        if rang {
+            self.notify_peer();
+        }
+        rang
+    }
+
+    /// Rings the configured doorbell; does nothing without one.
+    pub fn notify_peer(&self) {
+        if let Some((registers, value)) = &self.doorbell {
+            let register = unsafe { registers.ptr.as_ptr().add(DOORBELL_REGISTER) }.cast::<u32>();
+            unsafe { ptr::write_volatile(register, *value) };
+        }
+    }
+
+    /// Dequeues a message, sleeping on the interrupt device between
+    /// attempts if one is configured and polling otherwise.
+    pub fn dequeue_blocking(&mut self) -> Result<Message, QueueError> {
+        loop {
+            match self.port.try_dequeue() {
+                Err(QueueError::EmptyBuffer) => {}
+                result => return result,
+            }
+            match &mut self.interrupt {
+                Some(device) => {
+                    // Re-arm, then look again so a message that raced the
+                    // re-arm is not left waiting for the next interrupt.
+                    device.write_all(&1u32.to_ne_bytes()).map_err(wait_failed)?;
+                    if !self.port.is_empty() {
+                        continue;
+                    }
+                    let mut count = [0; 4];
+                    device.read_exact(&mut count).map_err(wait_failed)?;
+                }
+                None => wait::backoff(),
+            }
+        }
+    }
+}
+
+fn wait_failed(error: io::Error) -> QueueError {
+    QueueError::WaitFailed(error.raw_os_error().unwrap_or(0))
+}
+
+impl core::ops::Deref for IvshmemPort {
+    type Target = QueueingPort;
+
+    fn deref(&self) -> &QueueingPort {
+        &self.port
+    }
+}
+
+impl core::ops::DerefMut for IvshmemPort {
+    fn deref_mut(&mut self) -> &mut QueueingPort {
+        &mut self.port
+    }
+}
+
+impl EnqueuePort for IvshmemPort {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        IvshmemPort::enqueue(self, message)
+    }
+}
+
+impl DequeuePort for IvshmemPort {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.port.try_dequeue()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+
+    // A regular file standing in for the device memory or register BAR.
+    fn region_file(test: &str, len: u64) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("qp_ivshmem_{}_{}", test, std::process::id()));
+        File::create(&path).unwrap().set_len(len).unwrap();
+        path
+    }
+
+    #[test]
+    fn both_ends_share_the_region_at_offset() {
+        let path = region_file("offset", 1 << 16);
+        let config = IvshmemConfig::new().path(&path).offset(4096).no_interrupt();
+        let mut writer = config.open().unwrap();
+        let mut reader = config.open().unwrap();
+
+        writer.enqueue(Message([3; SIZE])).unwrap();
+        assert_eq!(reader.dequeue_blocking().unwrap().0, [3; SIZE]);
+
+        let mut raw = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut raw).unwrap();
+        assert!(raw[..4096].iter().all(|&byte| byte == 0), "nothing written before the offset");
+        assert_eq!(u32::from_ne_bytes(raw[4096 + 12..4096 + 16].try_into().unwrap()), 1, "enqueued");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn enqueue_rings_the_doorbell() {
+        let path = region_file("doorbell_mem", 1 << 16);
+        let registers = region_file("doorbell_bar0", REGISTERS_LEN as u64);
+        let mut writer = IvshmemConfig::new().path(&path).doorbell(&registers, 2, 1).open().unwrap();
+
+        writer.enqueue(Message([1; SIZE])).unwrap();
+        let mut bar = Vec::new();
+        File::open(&registers).unwrap().read_to_end(&mut bar).unwrap();
+        let value = u32::from_ne_bytes(bar[DOORBELL_REGISTER..DOORBELL_REGISTER + 4].try_into().unwrap());
+        assert_eq!(value, 2 << 16 | 1);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(registers).unwrap();
+    }
+
+    #[test]
+    fn open_checks_region_size_and_alignment() {
+        let path = region_file("small", 1024);
+        assert!(matches!(IvshmemConfig::new().path(&path).open(), Err(PortError::LayoutMismatch)));
+        assert!(matches!(IvshmemConfig::new().path(&path).offset(2).open(), Err(PortError::Misaligned)));
+        assert!(matches!(IvshmemConfig::new().path("/nonexistent/ivshmem").open(), Err(PortError::Io(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! How late messages are sent against their schedule.
+//!
+//! A periodic task that should send at some instant sends a little after,
+//! by however long it took to be scheduled and get there, and a hard
+//! real-time design needs a bound on that. A `JitterMonitor` wraps a port
+//! and reads a `Clock` as each message is enqueued through it; the jitter
+//! is the reading less the time the message was due, 0 for one sent early.
+//!
+//! Minimum, maximum, mean and a histogram are kept in atomics, so a
+//! monitoring task can take a `report` while the sender goes on. The
+//! histogram has `JITTER_BUCKETS` buckets, exponentially spaced up to 1ms:
+//! bucket `i` counts jitter below `JitterReport::bucket_bound_ns(i)`, and
+//! the last one everything from half a millisecond up, later than a
+//! millisecond too. Only sent messages count; a refused one was not sent.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{ClockRef, Message, QueueError, QueueingPort};
+
+/// Buckets in a jitter histogram.
+pub const JITTER_BUCKETS: usize = 16;
+
+/// The end of the histogram's last bounded bucket.
+const JITTER_SPAN_NS: u64 = 1_000_000;
+
+/// A port whose enqueues are timed against their schedule, see the
+/// `jitter` module.
+pub struct JitterMonitor {
+    port: QueueingPort,
+    clock: ClockRef,
+    count: AtomicU64,
+    sum_ns: AtomicU64,
+    min_ns: AtomicU64,
+    max_ns: AtomicU64,
+    histogram: [AtomicU64; JITTER_BUCKETS],
+}
+
+/// The jitter of the messages sent so far, see `JitterMonitor::report`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JitterReport {
+    pub count: u64,
+    /// 0 while nothing has been sent, as are `max_ns` and `mean_ns`.
+    pub min_ns: u64,
+    pub max_ns: u64,
+    pub mean_ns: u64,
+    pub histogram: [u64; JITTER_BUCKETS],
+}
+
+impl JitterReport {
+    /// The jitter bucket `index` counts up to (exclusive); the last bucket
+    /// has no bound, and `u64::MAX` is returned for it.
+    pub const fn bucket_bound_ns(index: usize) -> u64 {
+        if index + 1 >= JITTER_BUCKETS {
+            u64::MAX
+        } else {
+            JITTER_SPAN_NS >> (JITTER_BUCKETS - 1 - index)
+        }
+    }
+
+    fn bucket_of(jitter_ns: u64) -> usize {
+        (0..JITTER_BUCKETS - 1)
+            .find(|&index| jitter_ns < JitterReport::bucket_bound_ns(index))
+            .unwrap_or(JITTER_BUCKETS - 1)
+    }
+}
+
+impl JitterMonitor {
+    pub fn new(port: QueueingPort, clock: ClockRef) -> JitterMonitor {
+        JitterMonitor {
+            port,
+            clock,
+            count: AtomicU64::new(0),
+            sum_ns: AtomicU64::new(0),
+            min_ns: AtomicU64::new(u64::MAX),
+            max_ns: AtomicU64::new(0),
+            histogram: [const { AtomicU64::new(0) }; JITTER_BUCKETS],
+        }
+    }
+
+    /// Enqueues `message`, due at `expected_ns` on the monitor's clock, and
+    /// records how late it is. Fails as `enqueue`, recording nothing.
+    pub fn enqueue(&mut self, message: Message, expected_ns: u64) -> Result<(), QueueError> {
+        let actual_ns = self.clock.now_ns();
+        self.port.enqueue(message)?;
+        self.record(actual_ns.saturating_sub(expected_ns));
+        Ok(())
+    }
+
+    fn record(&self, jitter_ns: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ns.fetch_add(jitter_ns, Ordering::Relaxed);
+        self.min_ns.fetch_min(jitter_ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(jitter_ns, Ordering::Relaxed);
+        self.histogram[JitterReport::bucket_of(jitter_ns)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The statistics so far. Taken while another thread records, the
+    /// fields may be a message apart.
+    pub fn report(&self) -> JitterReport {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return JitterReport::default();
+        }
+        JitterReport {
+            count,
+            min_ns: self.min_ns.load(Ordering::Relaxed),
+            max_ns: self.max_ns.load(Ordering::Relaxed),
+            mean_ns: self.sum_ns.load(Ordering::Relaxed) / count,
+            histogram: core::array::from_fn(|index| self.histogram[index].load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn port(&self) -> &QueueingPort {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut QueueingPort {
+        &mut self.port
+    }
+
+    pub fn into_inner(self) -> QueueingPort {
+        self.port
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockClock, MSGS, SIZE};
+    use std::sync::Arc;
+
+    #[test]
+    fn bucket_bounds_double_up_to_a_millisecond() {
+        assert_eq!(JitterReport::bucket_bound_ns(JITTER_BUCKETS - 2), JITTER_SPAN_NS / 2);
+        assert_eq!(JitterReport::bucket_bound_ns(0), JITTER_SPAN_NS >> 15);
+        assert_eq!(JitterReport::bucket_of(0), 0);
+        assert_eq!(JitterReport::bucket_of(JITTER_SPAN_NS / 2 - 1), JITTER_BUCKETS - 2);
+        assert_eq!(JitterReport::bucket_of(u64::MAX), JITTER_BUCKETS - 1);
+    }
+
+    #[test]
+    fn a_thousand_sends_fill_the_histogram() {
+        const PERIOD_NS: u64 = 1_000_000;
+        let clock = Arc::new(MockClock::new(0));
+        let mut monitor = JitterMonitor::new(QueueingPort::new(), clock.clone());
+        assert_eq!(monitor.report(), JitterReport::default());
+        for period in 0..1000u64 {
+            let due = period * PERIOD_NS;
+            // Late by a spread of delays up to 2ms, and sometimes early.
+            let delay = (period * 7919) % 2_000_000;
+            clock.set(if period % 10 == 0 { due.saturating_sub(5) } else { due + delay });
+            monitor.enqueue(Message([0; SIZE]), due).unwrap();
+            monitor.port_mut().dequeue().unwrap();
+        }
+        let report = monitor.report();
+        assert_eq!(report.count, 1000);
+        assert_eq!(report.histogram.iter().sum::<u64>(), 1000);
+        assert_eq!(report.min_ns, 0, "early counts as on time");
+        assert!(report.max_ns >= JITTER_SPAN_NS && report.max_ns < 2_000_000);
+        assert!(report.min_ns <= report.mean_ns && report.mean_ns <= report.max_ns);
+        assert!(report.histogram[0] >= 100);
+        assert!(report.histogram[JITTER_BUCKETS - 1] > 0);
+
+        for _ in 0..MSGS {
+            monitor.port_mut().enqueue(Message([0; SIZE])).unwrap();
+        }
+        assert!(matches!(monitor.enqueue(Message([0; SIZE]), 0), Err(QueueError::FullBuffer)));
+        assert_eq!(monitor.report().count, 1000, "a refused message was not sent");
+    }
+}
@@ -0,0 +1,1831 @@
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+use core::cell::{Cell, UnsafeCell};
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{fence, AtomicU8, Ordering};
+
+use byteorder::WireU32;
+use owner::OwnerField;
+
+use corruption::CorruptionHandling;
+use dedup::DedupWindow;
+use filter::TypeFilter;
+use relaxed::Handoff;
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+mod ack;
+mod aligned;
+#[cfg(feature = "shmem")]
+mod arena;
+mod auth;
+mod batch;
+mod bloom;
+mod bookmark;
+mod bounded;
+mod buffer;
+mod byteorder;
+#[cfg(feature = "std")]
+mod channel;
+mod claim;
+mod clock;
+#[cfg(feature = "alloc")]
+mod collector;
+mod compact;
+#[cfg(feature = "compress")]
+mod compress;
+mod config;
+mod corruption;
+mod credit;
+mod dedup;
+#[cfg(feature = "alloc")]
+mod diff;
+mod detach;
+mod eos;
+#[cfg(any(feature = "alloc", feature = "heapless"))]
+mod drain;
+mod fair;
+mod features;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod filter;
+mod foreign;
+mod header;
+mod heap;
+mod hot;
+mod index;
+#[cfg(feature = "std")]
+mod hw_clock;
+mod forward;
+mod fragment;
+mod generation;
+#[cfg(feature = "alloc")]
+mod guard;
+mod hex;
+mod history;
+mod invariants;
+mod ipc;
+mod jitter;
+#[cfg(feature = "alloc")]
+mod local;
+mod logging;
+#[cfg(feature = "std")]
+mod io;
+#[cfg(all(feature = "ivshmem", unix))]
+pub mod ivshmem;
+#[cfg(feature = "std")]
+mod health;
+#[cfg(feature = "std")]
+mod metrics;
+#[cfg(feature = "std")]
+mod migrate;
+mod mirror;
+mod mode;
+mod mpmc;
+#[cfg(feature = "shmem")]
+mod named;
+mod notify;
+mod numa;
+mod observer;
+mod overflow;
+#[cfg(feature = "shmem")]
+mod open_error;
+#[cfg(feature = "shmem")]
+mod open_limit;
+mod owner;
+mod pacing;
+mod partition;
+mod partition_status;
+mod peer;
+mod pingpong;
+mod pin;
+#[cfg(feature = "std")]
+mod pipe;
+mod pipeline;
+#[cfg(feature = "std")]
+mod port_id;
+mod poison;
+mod prefetch;
+#[cfg(all(feature = "process-mutex", target_os = "linux"))]
+mod psm;
+#[cfg(feature = "shmem")]
+mod quota;
+mod relaxed;
+mod report;
+#[cfg(feature = "std")]
+mod resize;
+#[cfg(feature = "shmem")]
+mod retry;
+#[cfg(feature = "std")]
+mod rwport;
+mod selftest;
+mod sha256;
+mod signal;
+mod sigsafe;
+mod sized;
+mod slab;
+mod snapshot;
+mod stream;
+mod tee;
+#[cfg(all(feature = "alloc", any(test, feature = "test-utils")))]
+pub mod testing;
+mod timing;
+#[cfg(feature = "alloc")]
+mod topic;
+mod typed;
+#[cfg(feature = "std")]
+mod uprobe;
+mod trace;
+#[cfg(all(feature = "tsx", target_arch = "x86_64"))]
+mod tsx;
+#[cfg(all(feature = "linux-fuse", target_os = "linux"))]
+pub mod procfs;
+#[cfg(all(feature = "linux-io-uring", target_os = "linux"))]
+pub mod uring;
+mod validate;
+mod vectored;
+#[cfg(feature = "alloc")]
+mod visualize;
+mod wait;
+mod watchdog;
+mod watermark;
+#[cfg(feature = "alloc")]
+mod weighted;
+
+pub use aligned::AlignedQueueingPort;
+#[cfg(feature = "shmem")]
+pub use arena::{ArenaError, EpochSnapshot, FreezePolicy, SharedMemoryArena, MAX_PORTS};
+pub use auth::{AuthenticatedPort, AUTH_MAC_LEN, AUTH_PAYLOAD};
+pub use bloom::DeduplicatingPort;
+pub use bookmark::{Bookmark, BookmarkIter};
+pub use bounded::BoundedQueueingPort;
+pub use buffer::BufferPort;
+pub use byteorder::ByteOrder;
+#[cfg(feature = "std")]
+pub use channel::{PortReceiver, PortSender};
+#[cfg(feature = "std")]
+pub use clock::StdClock;
+pub use clock::{Clock, ClockRef, MockClock, NoClock, SimulatedClock, STALL_PAUSES};
+#[cfg(feature = "std")]
+pub use hw_clock::HwClock;
+#[cfg(feature = "alloc")]
+pub use collector::{AggregateStats, StatsCollector};
+pub use dedup::{DedupKey, DEDUP_WINDOW_CAPACITY};
+#[cfg(feature = "alloc")]
+pub use diff::{DiffSnapshot, QueueSnapshot};
+pub use detach::ATTACH_SLOTS;
+#[cfg(feature = "shmem")]
+pub use detach::TeardownPolicy;
+pub use eos::{Received, StreamSummary, END_OF_STREAM_FLAG, END_OF_STREAM_MSG_TYPE, SUMMARY_CAPACITY};
+#[cfg(feature = "compress")]
+pub use compress::{CompressedPort, CompressionStats, COMPRESSED_HEADER_LEN, COMPRESSED_MAX_LEN, COMPRESSED_PAYLOAD};
+pub use config::{DeliveryOrder, PortConfig, METADATA_CAPACITY};
+pub use corruption::{CorruptionPolicy, CRC_LEN, CRC_PAYLOAD, QUARANTINE_CAPACITY};
+pub use fair::FAIR_SOURCE_CAPACITY;
+pub use foreign::{ForeignLayout, ForeignPort};
+pub use features::WireFeatures;
+pub use filter::TYPE_FILTER_CAPACITY;
+pub use header::{MessageHeader, HEADER_PAYLOAD, MESSAGE_HEADER_LEN};
+pub use heap::{HeapEntry, HeapQueueingPort, HeapSegment};
+pub use fragment::{FRAGMENT_HEADER_LEN, FRAGMENT_MSG_TYPE, FRAGMENT_PAYLOAD};
+#[cfg(feature = "alloc")]
+pub use guard::{GuardError, GuardedQueueingPort, GUARD_PATTERN};
+pub use hex::HexString;
+pub use history::{HistoryEntry, HISTORY_CAPACITY, HISTORY_PAYLOAD};
+pub use index::{mask_slots, IndexMath};
+pub use invariants::InvariantViolation;
+pub use ipc::{IpcQueue, MultiReader, SingleReader};
+pub use jitter::{JitterMonitor, JitterReport, JITTER_BUCKETS};
+#[cfg(feature = "alloc")]
+pub use local::LocalQueue;
+pub use logging::{
+    target_hash, LogDrainer, LogLevel, LogRecord, QueueLogger, LOG_RECORD_TYPE, LOG_TEXT_CAPACITY, LOG_TRUNCATED,
+};
+#[cfg(feature = "std")]
+pub use migrate::{migrate_v1_to_v2, MigrationError};
+pub use mirror::{FailoverEvent, FailoverHook, MirroredPort};
+pub use notify::{Notifier, NotifyPolicy, NotifyingPort};
+pub use numa::NumaPolicy;
+pub use mpmc::MpmcQueue;
+pub use ring_buffer_macros::queue_channel;
+pub use observer::Observer;
+pub use overflow::OverflowPolicy;
+#[cfg(feature = "shmem")]
+pub use open_error::{OpenFailure, PortOpenError, SegmentGeometry};
+#[cfg(feature = "shmem")]
+pub use open_limit::{OpenPortLimit, MAX_OPEN_ENV};
+pub use owner::OwnerId;
+pub use pacing::PacedPort;
+pub use mode::{Broadcast, ConcurrencyMode, Consumer, Mode, Mpsc, Port, Producer, Spsc, Subscriber};
+pub use partition::PartitionScheduler;
+pub use partition_status::{OperatingMode, PartitionStatus, StartCondition};
+pub use peer::PeerRole;
+pub use pingpong::{PingPongBuffer, PingPongReader, PingPongWriter};
+pub use prefetch::PrefetchingReceiver;
+#[cfg(all(feature = "process-mutex", target_os = "linux"))]
+pub use psm::{ProcessSharedMutex, PsmGuard};
+#[cfg(feature = "std")]
+pub use port_id::{port_tag, PortId, TypedPortHandle};
+#[cfg(feature = "std")]
+pub use pipe::{Pipe, PipeHandle};
+pub use pipeline::{Pipeline, PipelineOut, Stages, Then, Transform, TransformError};
+#[cfg(feature = "shmem")]
+pub use quota::ShmemQuota;
+pub use report::MemoryReport;
+#[cfg(feature = "std")]
+pub use resize::ResizableQueue;
+#[cfg(feature = "shmem")]
+pub use retry::RetryPolicy;
+#[cfg(feature = "std")]
+pub use rwport::RwQueueingPort;
+#[cfg(feature = "alloc")]
+pub use snapshot::SnapshotBlob;
+pub use selftest::{SelfTestCheck, SelfTestReport};
+pub use signal::{SIGNAL_FLAG, SIGNAL_MSG_TYPE};
+pub use sigsafe::{signal_safe_enqueue, SignalQueue, SIGNAL_QUEUE, SIGNAL_QUEUE_CAPACITY};
+pub use sized::TypedMessage;
+pub use slab::{MessageSlab, SlabMessage};
+pub use snapshot::SnapshotError;
+#[cfg(feature = "std")]
+pub use stream::AsyncQueueStream;
+pub use tee::{TeePort, TeeResult};
+pub use timing::{EventSink, QueueEvent, ReceivedMessage};
+#[cfg(feature = "alloc")]
+pub use topic::{Publisher, Registry, Topic, TOPIC_CAPACITY};
+#[cfg(all(feature = "tsx", target_arch = "x86_64"))]
+pub use tsx::{SpinMutex, SpinMutexGuard, TsxMutex, TsxMutexGuard, TSX_RETRIES};
+pub use typed::{Pod, SlotRef};
+pub use validate::{ValidationError, Validator};
+#[cfg(feature = "alloc")]
+pub use weighted::WeightedMergePort;
+
+/// Byte layout of a segment, for implementations in other languages.
+#[doc = include_str!("../PROTOCOL.md")]
+pub mod protocol {}
+
+#[cfg(feature = "std")]
+pub use metrics::render_metrics_all;
+#[cfg(feature = "shmem")]
+pub use named::{HANDSHAKE_TIMEOUT, INIT_TIMEOUT};
+
+// Constants for the ring buffer
+pub const SIZE: usize = 256;
+pub const MSGS: usize = 10;
+
+// Slots follow each other at `SLOT_STRIDE`, so with a message size that is
+// not a multiple of 8 every other slot would start off an 8-byte boundary.
+// The const-generic ports check their own geometry the same way, in their
+// constructors.
+const _: () = assert!(SIZE.is_multiple_of(8), "SIZE must be a multiple of 8 for alignment");
+
+/// `n` rounded up to the next multiple of 8, for laying out message sizes
+/// that keep every slot 8-byte aligned.
+pub const fn aligned_size(n: usize) -> usize {
+    (n + 7) & !7
+}
+
+/// Guard bytes after every slot with the `slot-poison` feature, checked by
+/// `check_invariants`.
+#[cfg(feature = "slot-poison")]
+const CANARY_LEN: usize = 8;
+#[cfg(not(feature = "slot-poison"))]
+const CANARY_LEN: usize = 0;
+/// Distance between the starts of two neighbouring slots.
+const SLOT_STRIDE: usize = SIZE + CANARY_LEN;
+
+/// The slot of ring position `position`, with a mask if `MSGS` is a power
+/// of two; see the `index` module.
+#[inline(always)]
+const fn slot_index(position: usize) -> usize {
+    index::wrap(position, MSGS)
+}
+
+/// What a slot is filled with once its message has been dequeued. The
+/// poison pattern makes reads through a stale pointer stand out.
+#[cfg(feature = "slot-poison")]
+const FREED_SLOT_BYTE: u8 = 0xDE;
+#[cfg(not(feature = "slot-poison"))]
+const FREED_SLOT_BYTE: u8 = 0;
+
+#[derive(Debug)]
+pub enum QueueError {
+    FullBuffer,
+    EmptyBuffer,
+    /// A pipeline stage rejected the message, which was discarded.
+    Transform(TransformError),
+    /// The OS wait primitive failed with the given errno.
+    WaitFailed(i32),
+    /// More types were passed to `set_type_filter` than it can hold.
+    FilterTooLarge,
+    /// `set_dedup_window` was asked for more than `DEDUP_WINDOW_CAPACITY`.
+    WindowTooLarge,
+    /// On a CRC port under `CorruptionPolicy::Halt`, the message with
+    /// sequence number `sequence` failed its CRC check. It stays queued.
+    Corrupted { sequence: u32 },
+    /// A `BoundedQueueingPort` already holds its maximum depth of messages.
+    DepthExceeded,
+    /// The deadline passed before the message could be enqueued; it was not.
+    DeadlineMissed,
+    /// The destination buffers hold fewer than the `needed` bytes; the
+    /// message stays queued.
+    BufferTooSmall { needed: usize },
+    /// The source slices add up to `len` bytes, more than a slot holds.
+    MessageTooLarge { len: usize },
+    /// On a `DeliveryOrder::StrictFifo` port, the message taken from the
+    /// queue carried sequence number `got` instead of `expected`. The message
+    /// is discarded.
+    OrderViolation { expected: u32, got: u32 },
+    /// A dequeue examined (and discarded) `examined` messages, its whole
+    /// skip budget, without finding one to deliver. Calling again continues
+    /// with the next message.
+    BudgetExhausted { examined: usize },
+    /// A fragmented blob could not be reassembled: fragments are missing or
+    /// it does not fit the destination buffer. Also a compressed message
+    /// that does not decode to its length.
+    Truncated,
+    /// `dequeue_large` found a message of type `msg_type` instead of a
+    /// fragment; it stays queued.
+    NotAFragment { msg_type: u16 },
+    /// The port is in credit mode and the receiver has not granted the
+    /// credit an enqueue needs.
+    NoCredit,
+    /// The port only accepts messages once a receiver has attached, and
+    /// none has; or `wait_for_peer` timed out.
+    NoPeer,
+    /// A broadcast subscriber or an `Observer` fell behind and `missed`
+    /// messages were dropped or consumed before it read them.
+    Lagged { missed: u32 },
+    /// The segment was recreated since this handle attached to it; see
+    /// `QueueingPort::reattach`.
+    StaleSegment,
+    /// A closure passed to `dequeue_with` panicked through this handle; see
+    /// `QueueingPort::clear_poison`.
+    Poisoned,
+    /// The kernel failed an `IoUringPort` copy with the given errno.
+    CopyFailed(i32),
+    /// `dequeue_ref` was asked for an `expected`-byte type, and the message
+    /// is `found` bytes long; it stays queued.
+    LengthMismatch { expected: usize, found: usize },
+    /// `dequeue_ref` found the slot not aligned to the `align` bytes the
+    /// type needs; the message stays queued.
+    Misaligned { align: usize },
+    /// `self_test` needs an empty port, and this one holds `len` messages.
+    NotEmpty { len: usize },
+    /// The port was closed: no more enqueues, and no more dequeues once
+    /// the messages queued before closing are consumed.
+    Closed,
+    /// A strict `TeePort` did not commit the message on this side because
+    /// the other side failed.
+    RolledBack,
+    /// Another handle, `owner`, holds the port's writer claim; see
+    /// `QueueingPort::claim_writer`.
+    WriterBusy { owner: OwnerId },
+    /// Another handle, `owner`, holds the port's reader claim; see
+    /// `QueueingPort::claim_reader`.
+    ReaderBusy { owner: OwnerId },
+    /// A `Registry` already holds `TOPIC_CAPACITY` topics and cannot add
+    /// another.
+    TooManyTopics,
+    /// An `AuthenticatedPort` dequeued a message whose MAC did not match;
+    /// the message was consumed.
+    AuthFailed,
+    /// The port's `SharedMemoryArena` is frozen for a snapshot, under
+    /// `FreezePolicy::Fail`; nothing was enqueued or dequeued.
+    Frozen,
+    /// `enqueue_pinned` found `limit` messages pinned already, see
+    /// `PortConfig::pin_limit`.
+    TooManyPinned { limit: u32 },
+    /// The queue is full of pinned messages, so none could be dropped to
+    /// make room; see the `pin` module.
+    AllPinned,
+    /// A `ForeignPort` found `index` in one of the ring's indices, past its
+    /// last slot; nothing was enqueued or dequeued.
+    BadIndex { index: u64 },
+    /// `with_prefetch` found a read claim outstanding: its message would be
+    /// acknowledged by `commit_read` after later ones had been staged.
+    ReadClaimed,
+    /// `send_until_acked` sent the message and every retry without an
+    /// acknowledgement arriving. The copies sent stay queued.
+    MaxRetriesExceeded,
+    /// The port's validator rejected the message, which was not queued;
+    /// see `QueueingPort::set_validator`.
+    ValidationFailed(ValidationError),
+}
+
+/// A port messages can be enqueued into.
+pub trait EnqueuePort {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError>;
+}
+
+/// A port messages can be dequeued from.
+pub trait DequeuePort {
+    fn dequeue(&mut self) -> Result<Message, QueueError>;
+}
+
+/// Errors from setting up a port.
+#[derive(Debug)]
+pub enum PortError {
+    /// Creating or mapping the named segment failed, see `PortOpenError`.
+    #[cfg(feature = "shmem")]
+    Open(PortOpenError),
+    /// Opening or mapping the backing file failed.
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+    /// The segment is smaller than a port needs.
+    LayoutMismatch,
+    /// The segment does not start at a suitably aligned address.
+    Misaligned,
+    /// The segment is not in a state that allows the handshake to proceed,
+    /// e.g. it already has a reader or was closed.
+    NotReady,
+    /// The peer did not complete the handshake in time.
+    HandshakeTimeout,
+    /// The metadata passed to `PortConfig::metadata` is `len` bytes long,
+    /// more than `METADATA_CAPACITY`.
+    MetadataTooLarge { len: usize },
+    /// The port uses required wire features this build does not know.
+    IncompatibleLayout { missing_features: WireFeatures },
+    /// The port was created in concurrency mode `found` (`None` if the byte
+    /// is not a known mode), not the `expected` one.
+    ModeMismatch { expected: ConcurrencyMode, found: Option<ConcurrencyMode> },
+    /// Binding the segment to a NUMA node under a strict `NumaPolicy`
+    /// failed with the given errno.
+    NumaBind(i32),
+    /// The port is not backed by a named segment that could be reopened.
+    Unnamed,
+    /// Another creator has the name, or won the race to set the segment up.
+    AlreadyExists,
+    /// The segment's header has stayed half-written for longer than the
+    /// opener's bound; its creator probably died while setting it up.
+    StaleInit,
+    /// `restore_from_bytes` was given a blob it cannot restore.
+    Snapshot(SnapshotError),
+    /// Creating the segment would take the process past the limit set with
+    /// `ShmemQuota::set_max_bytes`.
+    QuotaExceeded,
+    /// Creating the named segment failed `attempts` times under the port's
+    /// `RetryPolicy`, the last time with `last`; see the `retry` module.
+    #[cfg(feature = "shmem")]
+    AllocationFailed { attempts: u32, last: PortOpenError },
+    /// The process already has as many named ports open as `OpenPortLimit`
+    /// allows.
+    #[cfg(feature = "shmem")]
+    TooManyOpenPorts,
+    /// The named segment is being taken down and cannot be opened again;
+    /// see the `detach` module.
+    #[cfg(feature = "shmem")]
+    SegmentDoomed,
+    /// The named segment already has `ATTACH_SLOTS` handles.
+    #[cfg(feature = "shmem")]
+    AttachTableFull,
+}
+
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct Message(pub [u8; SIZE]);
+
+impl Message {
+    /// The message type id, stored little endian in bytes 4..6.
+    pub fn msg_type(&self) -> u16 {
+        type_of(&self.0)
+    }
+}
+
+fn type_of(bytes: &[u8; SIZE]) -> u16 {
+    u16::from_le_bytes([bytes[4], bytes[5]])
+}
+
+/// Why `consume_filtered` discarded a message.
+enum Skipped {
+    Filtered,
+    Duplicate,
+    Corrupted,
+}
+
+/// Counters kept in the segment header, visible to both ends of a port,
+/// and the handle's own `duplicates_dropped`, `corrupted_skipped`,
+/// `evicted_on_close`, `dropped_oldest`, signal counts and clock anomalies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueStats {
+    pub enqueued: u32,
+    pub dequeued: u32,
+    /// Enqueues refused because the queue was full.
+    pub rejected: u32,
+    /// Highest number of messages queued at once.
+    pub high_watermark: u32,
+    /// Messages discarded by the reader's type filter.
+    pub filtered_out: u32,
+    /// `enqueue_by` calls that gave up because their deadline passed.
+    pub deadline_misses: u32,
+    /// Messages this handle's dedup window discarded as repeats.
+    pub duplicates_dropped: u32,
+    /// Messages this handle discarded for a bad CRC, see
+    /// `set_corruption_policy`.
+    pub corrupted_skipped: u32,
+    /// Messages this handle's `close_with_summary` dropped to make room
+    /// for the end-of-stream record; only a broadcast port drops any.
+    pub evicted_on_close: u32,
+    /// Messages this handle's enqueues dropped to make room, see
+    /// `set_overflow_policy`.
+    pub dropped_oldest: u32,
+    /// Signals this handle enqueued, see the `signal` module; also in
+    /// `enqueued`.
+    pub signals_sent: u32,
+    /// `enqueue_signal` calls of this handle that coalescing dropped.
+    pub signals_coalesced: u32,
+    /// Signals this handle dequeued; also in `dequeued`.
+    pub signals_received: u32,
+    /// Times this handle's clock jumped backwards, and waits it ended on a
+    /// stopped clock; see the `clock` module.
+    pub clock_anomalies: u32,
+}
+
+// Handshake states of `SegmentHeader::state`, see PROTOCOL.md. Only named
+// ports go through the handshake, but any port can be closed.
+#[cfg(feature = "shmem")]
+pub(crate) const UNINIT: u8 = 0;
+#[cfg(feature = "shmem")]
+pub(crate) const WRITER_READY: u8 = 1;
+#[cfg(feature = "shmem")]
+pub(crate) const READER_READY: u8 = 2;
+#[cfg(feature = "shmem")]
+pub(crate) const OPEN: u8 = 3;
+pub(crate) const CLOSED: u8 = 4;
+#[cfg(feature = "shmem")]
+pub(crate) const INITIALIZING: u8 = 5;
+
+/// Control fields shared by the writer and the reader of a port.
+///
+/// Every field except the metadata, which is written once before the
+/// segment is shared, is an atomic so the header can live in memory mapped
+/// by several processes at once.
+#[repr(C)]
+struct SegmentHeader {
+    write_index: WireU32,
+    read_index: WireU32,
+    message_count: WireU32,
+    enqueued: WireU32,
+    dequeued: WireU32,
+    rejected: WireU32,
+    high_watermark: WireU32,
+    filtered_out: WireU32,
+    /// Handshake state, see the `named` module, and whether the port was
+    /// closed.
+    state: AtomicU8,
+    /// Non-zero while `compact()` moves slots around.
+    compacting: AtomicU8,
+    /// A `DeliveryOrder`, fixed when the port is created.
+    delivery_order: AtomicU8,
+    /// A `ConcurrencyMode`, fixed when the port is created.
+    mode: AtomicU8,
+    deadline_misses: WireU32,
+    /// The `WireFeatures` the creator declared.
+    features: WireU32,
+    /// Most credits the receiver may grant; 0 without credit mode.
+    credit_limit: WireU32,
+    /// Credits granted by the receiver and not yet used by the writer.
+    credits: WireU32,
+    /// Length of the metadata blob, see `PortConfig::metadata`.
+    metadata_len: WireU32,
+    /// Written by `create()`, so handles can tell a recreated segment from
+    /// the one they attached to; see the `generation` module.
+    segment_generation: WireU32,
+    metadata: UnsafeCell<[u8; METADATA_CAPACITY]>,
+    /// Which ends have attached, and whether enqueueing waits for the
+    /// receiver; see `peer.rs`.
+    peers: WireU32,
+    /// Sequence number of the message in each slot: the `enqueued` count at
+    /// the time it was written.
+    slot_sequence: [WireU32; MSGS],
+    /// Bumped when a slot is published and again when it is freed, so it
+    /// is odd while the slot holds a message; an `Observer` reading a slot
+    /// checks it did not change meanwhile.
+    slot_generation: [WireU32; MSGS],
+    /// Enqueue time of the message in each slot, low and high word, on a
+    /// port with `WireFeatures::TIMESTAMPS`; see the `timing` module.
+    slot_time_low: [WireU32; MSGS],
+    slot_time_high: [WireU32; MSGS],
+    /// The handles holding the writer and reader claims, see the `owner`
+    /// module.
+    writer_owner: OwnerField,
+    reader_owner: OwnerField,
+    /// When the receiver last dequeued, and when the sender last enqueued
+    /// into an empty queue, on a port with `WireFeatures::PROGRESS`; see
+    /// the `watchdog` module.
+    last_dequeue: watchdog::TimeField,
+    backlog_since: watchdog::TimeField,
+    /// Bit `i` set while slot `i` holds a pinned message, see the `pin`
+    /// module.
+    pinned: WireU32,
+    /// Most messages pinned at once; 0 without pinning.
+    pin_limit: WireU32,
+    /// Sequence number of the message the receiver acknowledged last, and
+    /// how many acknowledgements there have been; see the `ack` module.
+    acked: WireU32,
+    acks: WireU32,
+    /// Payload bytes each history record keeps, and the records written,
+    /// on a port with `WireFeatures::HISTORY`; see the `history` module.
+    history_bytes: WireU32,
+    history_written: WireU32,
+    history: [history::HistoryRecord; HISTORY_CAPACITY],
+    /// The creator's `TeardownPolicy`, and whether the segment is doomed
+    /// or taken down; see the `detach` module.
+    teardown: WireU32,
+    /// One entry per handle on a named segment.
+    attach: [detach::AttachSlot; ATTACH_SLOTS],
+    /// The ARINC 653 status of the port's partition, see the
+    /// `partition_status` module.
+    partition_status: WireU32,
+}
+
+impl SegmentHeader {
+    /// The order of the multi-byte fields, see the `byteorder` module.
+    fn byte_order(&self) -> ByteOrder {
+        ByteOrder::from_features(self.features.raw().load(Ordering::Relaxed))
+    }
+
+    fn wait_while_compacting(&self) {
+        while self.compacting.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The memory a port operates on: the header followed by the message slots.
+///
+/// All-zero memory is a valid, empty segment, so freshly mapped shared memory
+/// can be attached to without further initialisation. The byte layout is
+/// specified in [`protocol`].
+#[repr(C)]
+pub struct Segment {
+    header: SegmentHeader,
+    buffer: UnsafeCell<[u8; SLOT_STRIDE * MSGS]>,
+}
+
+// Keep in sync with PROTOCOL.md.
+const _: () = {
+    use core::mem::{align_of, offset_of, size_of};
+    assert!(offset_of!(SegmentHeader, write_index) == 0);
+    assert!(offset_of!(SegmentHeader, read_index) == 4);
+    assert!(offset_of!(SegmentHeader, message_count) == 8);
+    assert!(offset_of!(SegmentHeader, enqueued) == 12);
+    assert!(offset_of!(SegmentHeader, dequeued) == 16);
+    assert!(offset_of!(SegmentHeader, rejected) == 20);
+    assert!(offset_of!(SegmentHeader, high_watermark) == 24);
+    assert!(offset_of!(SegmentHeader, filtered_out) == 28);
+    assert!(offset_of!(SegmentHeader, state) == 32);
+    assert!(offset_of!(SegmentHeader, compacting) == 33);
+    assert!(offset_of!(SegmentHeader, delivery_order) == 34);
+    assert!(offset_of!(SegmentHeader, mode) == 35);
+    assert!(offset_of!(SegmentHeader, deadline_misses) == 36);
+    assert!(offset_of!(SegmentHeader, features) == 40);
+    assert!(offset_of!(SegmentHeader, credit_limit) == 44);
+    assert!(offset_of!(SegmentHeader, credits) == 48);
+    assert!(offset_of!(SegmentHeader, metadata_len) == 52);
+    assert!(offset_of!(SegmentHeader, segment_generation) == 56);
+    assert!(offset_of!(SegmentHeader, metadata) == 60);
+    assert!(offset_of!(SegmentHeader, peers) == 188);
+    assert!(offset_of!(SegmentHeader, slot_sequence) == 192);
+    assert!(offset_of!(SegmentHeader, slot_generation) == 192 + 4 * MSGS);
+    assert!(offset_of!(SegmentHeader, slot_time_low) == 192 + 8 * MSGS);
+    assert!(offset_of!(SegmentHeader, slot_time_high) == 192 + 12 * MSGS);
+    assert!(offset_of!(SegmentHeader, writer_owner) == 192 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, reader_owner) == 200 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, last_dequeue) == 208 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, backlog_since) == 216 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, pinned) == 224 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, pin_limit) == 228 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, acked) == 232 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, acks) == 236 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, history_bytes) == 240 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, history_written) == 244 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, history) == 248 + 16 * MSGS);
+    assert!(offset_of!(SegmentHeader, teardown) == 248 + 16 * MSGS + 80 * HISTORY_CAPACITY);
+    assert!(offset_of!(SegmentHeader, attach) == 252 + 16 * MSGS + 80 * HISTORY_CAPACITY);
+    assert!(offset_of!(SegmentHeader, partition_status) == 252 + 16 * MSGS + 80 * HISTORY_CAPACITY + 8 * ATTACH_SLOTS);
+    assert!(offset_of!(Segment, buffer) == 256 + 16 * MSGS + 80 * HISTORY_CAPACITY + 8 * ATTACH_SLOTS);
+    assert!(size_of::<Segment>() == 256 + 16 * MSGS + 80 * HISTORY_CAPACITY + 8 * ATTACH_SLOTS + SLOT_STRIDE * MSGS);
+    assert!(align_of::<Segment>() == 4);
+};
+
+// The buffer is only touched by the single writer (free slots) and the single
+// reader (occupied slots); `message_count` hands slots over between them.
+unsafe impl Sync for Segment {}
+
+impl Segment {
+    pub const fn new() -> Segment {
+        Segment {
+            header: SegmentHeader {
+                write_index: WireU32::zero(),
+                read_index: WireU32::zero(),
+                message_count: WireU32::zero(),
+                enqueued: WireU32::zero(),
+                dequeued: WireU32::zero(),
+                rejected: WireU32::zero(),
+                high_watermark: WireU32::zero(),
+                filtered_out: WireU32::zero(),
+                state: AtomicU8::new(0),
+                compacting: AtomicU8::new(0),
+                delivery_order: AtomicU8::new(0),
+                mode: AtomicU8::new(0),
+                deadline_misses: WireU32::zero(),
+                features: WireU32::zero(),
+                credit_limit: WireU32::zero(),
+                credits: WireU32::zero(),
+                metadata_len: WireU32::zero(),
+                segment_generation: WireU32::zero(),
+                metadata: UnsafeCell::new([0; METADATA_CAPACITY]),
+                peers: WireU32::zero(),
+                slot_sequence: [const { WireU32::zero() }; MSGS],
+                slot_generation: [const { WireU32::zero() }; MSGS],
+                slot_time_low: [const { WireU32::zero() }; MSGS],
+                slot_time_high: [const { WireU32::zero() }; MSGS],
+                writer_owner: OwnerField::new(),
+                reader_owner: OwnerField::new(),
+                last_dequeue: watchdog::TimeField::new(),
+                backlog_since: watchdog::TimeField::new(),
+                pinned: WireU32::zero(),
+                pin_limit: WireU32::zero(),
+                acked: WireU32::zero(),
+                acks: WireU32::zero(),
+                history_bytes: WireU32::zero(),
+                history_written: WireU32::zero(),
+                history: [const { history::HistoryRecord::new() }; HISTORY_CAPACITY],
+                teardown: WireU32::zero(),
+                attach: [const { detach::AttachSlot::new() }; ATTACH_SLOTS],
+                partition_status: WireU32::zero(),
+            },
+            buffer: UnsafeCell::new([0; SLOT_STRIDE * MSGS]),
+        }
+    }
+
+    /// The address of slot `index`.
+    ///
+    /// The buffer is reached only through this, and never
+    /// through a reference to all of it: another handle, in this process or
+    /// another, owns the slots on the other side of `message_count`, and a
+    /// `&mut` over the whole buffer would alias them. A reference to one
+    /// slot is made only while the protocol gives it to the side making it,
+    /// and does not outlive that.
+    fn slot(&self, index: usize) -> *mut u8 {
+        // `index` is always below MSGS, so the offset stays inside the buffer.
+        unsafe { self.buffer.get().cast::<u8>().add(index * SLOT_STRIDE) }
+    }
+
+    /// Copies the message out of slot `index` without a reference to it.
+    ///
+    /// # Safety
+    ///
+    /// The slot must be occupied, and the caller its reader.
+    unsafe fn read_slot(&self, index: usize) -> [u8; SIZE] {
+        // SAFETY: the slot is the caller's to read and in bounds, and
+        // `[u8; SIZE]` has no alignment to keep.
+        unsafe { ptr::read_unaligned(self.slot(index).cast::<[u8; SIZE]>()) }
+    }
+
+    /// Writes the free slot `ahead` places after `write_index` with `fill`.
+    /// The caller has checked that at least `ahead + 1` slots are free.
+    fn fill_free_slot(&self, ahead: usize, fill: impl FnOnce(&mut [u8; SIZE])) {
+        self.write_free_slot(ahead, |slot| fill_slot(slot, fill));
+    }
+
+    /// Like `fill_free_slot`, handing `write` the slot's address rather
+    /// than a reference to it.
+    fn write_free_slot(&self, ahead: usize, write: impl FnOnce(*mut [u8; SIZE])) {
+        let header = &self.header;
+        let order = header.byte_order();
+        let index = slot_index(header.write_index.load(order, Ordering::Relaxed) as usize + ahead);
+        write(self.slot(index).cast::<[u8; SIZE]>());
+        let sequence = header.enqueued.load(order, Ordering::Relaxed).wrapping_add(ahead as u32);
+        header.slot_sequence[index].store(order, sequence, Ordering::Relaxed);
+    }
+
+    /// Hands the `written` slots filled since the last call over to the
+    /// reader with a single update of `message_count`, with `enqueue_time`
+    /// recorded for each if given. `clock` is the sender's, for the
+    /// `backlog_since` of the `watchdog` module.
+    fn publish(&self, written: usize, enqueue_time: Option<u64>, clock: &dyn Clock) {
+        self.publish_as(Handoff::Ordered, written, enqueue_time, clock);
+    }
+
+    /// Like `publish`, with the ordering `handoff` asks for.
+    fn publish_as(&self, handoff: Handoff, written: usize, enqueue_time: Option<u64>, clock: &dyn Clock) {
+        if written == 0 {
+            return;
+        }
+        let header = &self.header;
+        let order = header.byte_order();
+        let write_index = slot_index(header.write_index.load(order, Ordering::Relaxed) as usize);
+        if let Some(time) = enqueue_time {
+            for i in 0..written {
+                let index = slot_index(write_index + i);
+                header.slot_time_low[index].store(order, time as u32, Ordering::Relaxed);
+                header.slot_time_high[index].store(order, (time >> 32) as u32, Ordering::Relaxed);
+            }
+        }
+        for i in 0..written {
+            header.slot_generation[slot_index(write_index + i)].fetch_add(order, 1, handoff.generation_ordering());
+        }
+        header
+            .write_index
+            .store(order, slot_index(write_index + written) as u32, Ordering::Relaxed);
+        header.enqueued.fetch_add(order, written as u32, Ordering::Relaxed);
+        if header.credit_limit.load(order, Ordering::Relaxed) != 0 {
+            // Checked against the credits before writing.
+            header.credits.fetch_sub(order, written as u32, Ordering::Relaxed);
+        }
+        let progress = header.wire_features().contains(WireFeatures::PROGRESS);
+        if progress && header.message_count.load(order, Ordering::Relaxed) == 0 {
+            // Before the messages are visible, so no handle sees them with
+            // an old `backlog_since`.
+            header.backlog_since.store(order, clock.now_ns());
+        }
+        let previous = header.message_count.fetch_add(order, written as u32, Ordering::Release);
+        header.high_watermark.fetch_max(order, previous + written as u32, Ordering::Relaxed);
+        if previous == 0 {
+            handoff.crossed_zero();
+            // A blocked reader only ever waits on an empty queue.
+            wait::wake(header.message_count.raw());
+        }
+    }
+}
+
+impl Default for Segment {
+    fn default() -> Self {
+        Segment::new()
+    }
+}
+
+/// Runs `fill` on the free slot at `slot`, poisoning the port if it panics.
+fn fill_slot(slot: *mut [u8; SIZE], fill: impl FnOnce(&mut [u8; SIZE])) {
+    let guard = poison::FillGuard(slot);
+    // SAFETY: free slots belong to the writer until `message_count` hands
+    // them over, so nothing reads or writes the slot while `fill` holds the
+    // reference, and the reference does not outlive the call.
+    fill(unsafe { &mut *slot });
+    guard.disarm();
+}
+
+// Owned segments are stored inline so `new()` needs no allocator.
+#[allow(clippy::large_enum_variant)]
+enum Memory {
+    Owned(Segment),
+    Attached(ShmemBuffer),
+    /// A named mapping, and the handle that unmaps it on drop.
+    #[cfg(feature = "shmem")]
+    Named { buffer: ShmemBuffer, handle: ShmemHandle },
+    /// A slot of a `SharedMemoryArena`, and the arena's mapping.
+    #[cfg(feature = "shmem")]
+    Arena { buffer: ShmemBuffer, mapping: alloc::sync::Arc<ShmemHandle> },
+}
+
+impl Memory {
+    /// A named mapping this process opened, holding its place under the
+    /// `OpenPortLimit` until dropped.
+    #[cfg(feature = "shmem")]
+    fn named(shmem: shared_memory::Shmem, open: open_limit::OpenPortCharge) -> Memory {
+        Memory::Named {
+            buffer: ShmemBuffer::mapped(&shmem),
+            handle: ShmemHandle { shmem, _quota: None, _open: Some(open), attach: None, creator: false },
+        }
+    }
+
+    /// A named mapping this process created, holding `quota` and its place
+    /// under the `OpenPortLimit` until dropped.
+    #[cfg(feature = "shmem")]
+    fn created(shmem: shared_memory::Shmem, quota: quota::QuotaCharge, open: open_limit::OpenPortCharge) -> Memory {
+        Memory::Named {
+            buffer: ShmemBuffer::mapped(&shmem),
+            handle: ShmemHandle { shmem, _quota: Some(quota), _open: Some(open), attach: None, creator: true },
+        }
+    }
+
+    /// The OS name of a named mapping.
+    #[cfg(feature = "shmem")]
+    fn os_id(&self) -> Option<&str> {
+        match self {
+            Memory::Named { handle, .. } => Some(handle.shmem.get_os_id()),
+            _ => None,
+        }
+    }
+}
+
+/// The start and length of a segment that lives outside the port.
+///
+/// Only the address is kept, never a reference: the memory is shared with
+/// other handles and processes, so no handle has it to itself for any
+/// lifetime. Everything in it is reached through `segment`, whose header
+/// is atomics and whose slots are `UnsafeCell`, and slots are only read or
+/// written while the protocol gives them to the side doing so.
+pub(crate) struct ShmemBuffer(pub(crate) NonNull<u8>, pub(crate) usize);
+
+impl ShmemBuffer {
+    pub(crate) fn new(segment: NonNull<Segment>) -> ShmemBuffer {
+        ShmemBuffer(segment.cast(), size_of::<Segment>())
+    }
+
+    #[cfg(feature = "shmem")]
+    fn mapped(shmem: &shared_memory::Shmem) -> ShmemBuffer {
+        let start = NonNull::new(shmem.as_ptr()).expect("a mapping never starts at address 0");
+        ShmemBuffer(start, shmem.len())
+    }
+
+    fn segment(&self) -> &Segment {
+        debug_assert!(self.1 >= size_of::<Segment>());
+        // SAFETY: the buffer is at least a segment long and suitably
+        // aligned, checked where it was made, and valid while the port
+        // holding it lives; every bit pattern is a valid segment, and a
+        // shared reference to one only allows atomic and `UnsafeCell`
+        // access, so other handles changing it meanwhile is fine.
+        unsafe { self.0.cast::<Segment>().as_ref() }
+    }
+}
+
+// SAFETY: the pointer may be sent to and shared with other threads because
+// 1. only one `QueueingPort` owns a given `ShmemBuffer`, and at most one
+//    port per segment enqueues and one dequeues (the contract of `attach`
+//    and of the handshake);
+// 2. the ports share the segment only through the header atomics, which
+//    hand each slot to one side at a time; the port itself is not `Sync`,
+//    so a handle reaching several threads is behind a lock;
+// 3. the pointer is valid for as long as the `ShmemHandle` stored beside it,
+//    or, for attached segments, as the caller of `attach` promised or the
+//    borrow held by `BufferPort` ensures.
+unsafe impl Send for ShmemBuffer {}
+unsafe impl Sync for ShmemBuffer {}
+
+#[cfg(feature = "shmem")]
+struct ShmemHandle {
+    shmem: shared_memory::Shmem,
+    /// Given back after the mapping is gone; only set for the creator.
+    _quota: Option<quota::QuotaCharge>,
+    /// Likewise, the port's place under the `OpenPortLimit`; not set for
+    /// an arena's mapping.
+    _open: Option<open_limit::OpenPortCharge>,
+    /// This handle's attach slot, until it detaches; see the `detach`
+    /// module.
+    attach: Option<usize>,
+    creator: bool,
+}
+
+// The handle is only kept to be dropped, and for its name: unmapping works
+// from any thread, and reading the name from several at once.
+#[cfg(feature = "shmem")]
+unsafe impl Send for ShmemHandle {}
+#[cfg(feature = "shmem")]
+unsafe impl Sync for ShmemHandle {}
+
+pub struct QueueingPort {
+    memory: Memory,
+    /// Message types this handle's `dequeue` lets through.
+    type_filter: TypeFilter,
+    /// Ids of recently delivered messages, see `set_dedup_window`.
+    dedup: DedupWindow,
+    /// What `dequeue` does with bad CRCs, see `set_corruption_policy`.
+    corruption: CorruptionHandling,
+    /// Most slots one dequeue call examines, see `set_skip_budget`.
+    skip_budget: usize,
+    /// The source `dequeue_fair` served last.
+    last_source: Option<u16>,
+    /// Time source for deadlines, read monotonically.
+    clock: clock::MonotonicClock,
+    /// Wire features this handle understands, see `negotiated_features`.
+    supported_features: WireFeatures,
+    /// A slot is held by `claim_write_slice` / `claim_read_slice`.
+    write_claimed: bool,
+    read_claimed: bool,
+    /// Slot and generation of the view `poll_hot` returned last.
+    #[cfg(debug_assertions)]
+    hot_claim: Option<(usize, u32)>,
+    /// The end this handle announced itself as, see `announce`.
+    role: Option<PeerRole>,
+    /// `segment_generation` of the segment when this handle attached.
+    generation: u32,
+    /// Nonces of this handle's writer and reader claims, 0 for none.
+    owner_nonces: [u32; 2],
+    /// Told about timed dequeues and stalls, see `set_event_sink`.
+    event_sink: Option<EventSink>,
+    /// `consumer_stalled` reported the current stall, see `watchdog`.
+    stall_reported: Cell<bool>,
+    /// A `dequeue_with` closure panicked, see `clear_poison`.
+    poisoned: Cell<bool>,
+    /// Messages dropped by `close_with_summary`.
+    evicted_on_close: Cell<u32>,
+    /// What enqueues do on a full queue, see `set_overflow_policy`.
+    overflow_policy: OverflowPolicy,
+    /// Messages enqueues dropped under `OverflowPolicy::DropOldest`.
+    dropped_oldest: Cell<u32>,
+    /// Signal counts and `set_coalesce_signals`.
+    signals: signal::Signals,
+    /// Woken by the next enqueue through this handle, see `stream`.
+    reader_waker: Cell<Option<core::task::Waker>>,
+    /// Checks each message before it is published, see `set_validator`.
+    validator: Option<validate::Validator>,
+    /// Thresholds and callbacks, see `set_high_watermark`.
+    watermarks: watermark::Watermarks,
+    /// Hash table and counts of `enqueue_bytes` on compressed ports.
+    #[cfg(feature = "compress")]
+    compressor: compress::Compressor,
+    /// `visualize_unicode` colours its output, see `enable_ansi_color`.
+    #[cfg(feature = "alloc")]
+    ansi_color: bool,
+    /// Reported as `port.name` in trace spans.
+    #[cfg(feature = "tracing")]
+    name: alloc::string::String,
+}
+
+impl QueueingPort {
+    pub fn new() -> QueueingPort {
+        QueueingPort::from_memory(Memory::Owned(Segment::new()))
+    }
+
+    /// A port on a `SimulatedClock` starting at 0, and the clock, for tests
+    /// whose timeouts should not depend on real time.
+    #[cfg(feature = "alloc")]
+    pub fn new_simulated() -> (QueueingPort, alloc::sync::Arc<SimulatedClock>) {
+        let clock = alloc::sync::Arc::new(SimulatedClock::new(0));
+        let mut port = QueueingPort::new();
+        port.set_clock(clock.clone());
+        (port, clock)
+    }
+
+    fn from_memory(memory: Memory) -> QueueingPort {
+        let mut port = QueueingPort {
+            memory,
+            type_filter: TypeFilter::new(),
+            dedup: DedupWindow::new(),
+            corruption: CorruptionHandling::new(),
+            skip_budget: usize::MAX,
+            last_source: None,
+            clock: clock::MonotonicClock::new(None),
+            supported_features: WireFeatures::KNOWN,
+            write_claimed: false,
+            read_claimed: false,
+            #[cfg(debug_assertions)]
+            hot_claim: None,
+            role: None,
+            generation: 0,
+            owner_nonces: [0; 2],
+            event_sink: None,
+            stall_reported: Cell::new(false),
+            poisoned: Cell::new(false),
+            evicted_on_close: Cell::new(0),
+            overflow_policy: OverflowPolicy::Reject,
+            dropped_oldest: Cell::new(0),
+            signals: signal::Signals::new(),
+            reader_waker: Cell::new(None),
+            validator: None,
+            watermarks: watermark::Watermarks::new(),
+            #[cfg(feature = "compress")]
+            compressor: compress::Compressor::new(),
+            #[cfg(feature = "alloc")]
+            ansi_color: false,
+            #[cfg(feature = "tracing")]
+            name: alloc::string::String::new(),
+        };
+        port.generation = port.segment().header.segment_generation.load(port.byte_order(), Ordering::Acquire);
+        port
+    }
+
+    /// Creates a port operating on a segment that lives elsewhere, typically
+    /// in memory shared with another process.
+    ///
+    /// # Safety
+    ///
+    /// `segment` must point to a valid `Segment` that outlives the returned
+    /// port, and among all ports attached to it at most one may enqueue and
+    /// at most one may dequeue.
+    pub unsafe fn attach(segment: NonNull<Segment>) -> QueueingPort {
+        QueueingPort::from_memory(Memory::Attached(ShmemBuffer::new(segment)))
+    }
+
+    /// `assert_invariants`, on a port no peer shares the segment of.
+    #[cfg(debug_assertions)]
+    fn assert_invariants_if_owned(&self) {
+        if let Memory::Owned(_) = self.memory {
+            self.assert_invariants();
+        }
+    }
+
+    fn segment(&self) -> &Segment {
+        match &self.memory {
+            Memory::Owned(segment) => segment,
+            Memory::Attached(buffer) => buffer.segment(),
+            #[cfg(feature = "shmem")]
+            Memory::Named { buffer, .. } | Memory::Arena { buffer, .. } => buffer.segment(),
+        }
+    }
+
+    pub fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        trace::enqueue(self, |port| {
+            port.produce_back(|slot| {
+                // SAFETY: `produce_back` hands over a free slot, which is the
+                // writer's alone until `message_count` gives it to the reader,
+                // so no other reference to it exists; `slot` is in bounds, and
+                // writing through the pointer makes no reference to shared
+                // memory.
+                unsafe { ptr::write_unaligned(slot, message.0) }
+            })
+        })
+    }
+
+    /// Enqueues a message written by `fill` directly into the next free
+    /// slot, without building a `Message` first.
+    ///
+    /// The slot still holds whatever was there before, so `fill` should
+    /// write all of it. Nothing is enqueued, and `fill` is not called, if the
+    /// queue is full.
+    pub fn enqueue_with(&mut self, fill: impl FnOnce(&mut [u8; SIZE])) -> Result<(), QueueError> {
+        trace::enqueue(self, |port| port.produce_back(|slot| fill_slot(slot, fill)))
+    }
+
+    /// The checks every enqueue makes before writing a slot, whether the
+    /// queue has room aside. The result holds off a freeze of the port's
+    /// arena until dropped, so it should live until the message is
+    /// published.
+    pub(crate) fn admit_enqueue(&self) -> Result<impl Sized + '_, QueueError> {
+        #[cfg(feature = "shmem")]
+        let hold = self.hold_off_freeze()?;
+        #[cfg(not(feature = "shmem"))]
+        let hold = ();
+        let header = &self.segment().header;
+        header.wait_while_compacting();
+        self.check_generation()?;
+        self.check_poison()?;
+        self.check_owner(PeerRole::Sender)?;
+        if header.state.load(Ordering::Acquire) == CLOSED {
+            return Err(QueueError::Closed);
+        }
+        if !self.receiver_admits() {
+            return Err(QueueError::NoPeer);
+        }
+        if self.credits_remaining() == Some(0) {
+            return Err(QueueError::NoCredit);
+        }
+        Ok(hold)
+    }
+
+    /// Writes the next message with `write`, given the free slot's address,
+    /// and hands it to the reader. A full queue is up to the overflow policy.
+    fn produce_back(&self, write: impl FnOnce(*mut [u8; SIZE])) -> Result<(), QueueError> {
+        self.produce_back_as(Handoff::Ordered, write)
+    }
+
+    /// Like `produce_back`, with the ordering `handoff` asks for.
+    fn produce_back_as(&self, handoff: Handoff, write: impl FnOnce(*mut [u8; SIZE])) -> Result<(), QueueError> {
+        let _hold = self.admit_enqueue()?;
+        let segment = self.segment();
+        let header = &segment.header;
+        if header.message_count.load(header.byte_order(), Ordering::Acquire) as usize >= MSGS {
+            self.handle_overflow()?;
+        }
+
+        segment.write_free_slot(0, write);
+        self.validate_written(segment, 0)?;
+        segment.publish_as(handoff, 1, self.enqueue_time(), self.clock());
+        self.wake_reader();
+        Ok(())
+    }
+
+    /// Dequeues the oldest message that passes the type filter, discarding
+    /// (and counting) the ones before it that don't. `None` if there is
+    /// none, or the dequeue failed; `try_dequeue` tells which.
+    pub fn dequeue(&mut self) -> Option<Message> {
+        self.try_dequeue().ok()
+    }
+
+    /// Like `dequeue`, failing with `QueueError::EmptyBuffer` where there
+    /// is no message and with the reason where the dequeue failed. The
+    /// `DequeuePort` form.
+    pub fn try_dequeue(&mut self) -> Result<Message, QueueError> {
+        self.dequeue_with(|slot| Message(*slot))
+    }
+
+    /// Like `dequeue`, but hands `read` the message in place instead of
+    /// copying it out. The slot is recycled as soon as `read` returns, so no
+    /// reference or pointer into it may be kept.
+    pub fn dequeue_with<R>(&mut self, read: impl FnOnce(&[u8; SIZE]) -> R) -> Result<R, QueueError> {
+        trace::dequeue(self, |port| port.consume_filtered(|msg_type| port.type_filter.allows(msg_type), read))
+    }
+
+    /// Dequeues the oldest message whose type passes `keep` and that the
+    /// dedup window has not seen, discarding and counting the ones before
+    /// it, within the skip budget. On a CRC port, messages that fail the
+    /// check are handled first, by the corruption policy.
+    fn consume_filtered<R>(
+        &self,
+        keep: impl Fn(u16) -> bool,
+        read: impl FnOnce(&[u8; SIZE]) -> R,
+    ) -> Result<R, QueueError> {
+        self.consume_filtered_as(Handoff::Ordered, keep, read)
+    }
+
+    /// Like `consume_filtered`, with the ordering `handoff` asks for.
+    fn consume_filtered_as<R>(
+        &self,
+        handoff: Handoff,
+        keep: impl Fn(u16) -> bool,
+        read: impl FnOnce(&[u8; SIZE]) -> R,
+    ) -> Result<R, QueueError> {
+        let mut read = Some(read);
+        let mut examined = 0;
+        let check_crc = self.wire_features().contains(WireFeatures::CRC);
+        loop {
+            if examined >= self.skip_budget {
+                // Discarded messages are gone, so the next call resumes here.
+                return Err(QueueError::BudgetExhausted { examined });
+            }
+            examined += 1;
+            let result = self.consume_front_if_raw(handoff, |slot| {
+                // SAFETY: as in `consume_front_if`.
+                let slot = unsafe { &*slot };
+                Some(if check_crc && !corruption::crc_ok(slot) {
+                    if self.corruption.policy == CorruptionPolicy::Halt {
+                        return None;
+                    }
+                    self.corruption.skip(slot);
+                    Err(Skipped::Corrupted)
+                } else if !keep(type_of(slot)) && !eos::is_end_of_stream(slot) && !signal::is_signal(slot) {
+                    Err(Skipped::Filtered)
+                } else if !signal::is_signal(slot) && !self.dedup.admit(slot) {
+                    // Signals are all alike, and none is a repeat.
+                    Err(Skipped::Duplicate)
+                } else {
+                    self.signals.count_received(slot);
+                    let guard = poison::ReadGuard { poisoned: &self.poisoned, dedup: &self.dedup };
+                    let result = read.take().map(|read| read(slot));
+                    guard.disarm();
+                    Ok(result)
+                })
+            })?;
+            match result {
+                Some(Ok(Some(result))) => return Ok(result),
+                Some(Ok(None)) => unreachable!("read is used once"),
+                Some(Err(Skipped::Filtered)) => {
+                    self.segment().header.filtered_out.fetch_add(self.byte_order(), 1, Ordering::Relaxed);
+                }
+                // Counted by the window and the corruption handling.
+                Some(Err(Skipped::Duplicate | Skipped::Corrupted)) => {}
+                None => {
+                    let sequence = self.segment().header.dequeued.load(self.byte_order(), Ordering::Relaxed);
+                    return Err(QueueError::Corrupted { sequence });
+                }
+            }
+        }
+    }
+
+    /// Dequeues the oldest message regardless of the type filter.
+    pub fn dequeue_unfiltered(&mut self) -> Result<Message, QueueError> {
+        trace::dequeue(self, |port| {
+            port.consume_front_if_raw(Handoff::Ordered, |slot| {
+                // SAFETY: `consume_front_if_raw` hands over the oldest
+                // occupied slot, which the writer leaves alone until
+                // `message_count` gives it back after this returns; reading
+                // through the pointer makes no reference to shared memory.
+                Some(Message(unsafe { ptr::read(slot) }))
+            })
+        })
+        .map(Option::unwrap)
+    }
+
+    /// Copies the queued message with sequence number `sequence` (the
+    /// `enqueued` count when it was written) without dequeueing it. `None`
+    /// if it was already dequeued or not yet enqueued.
+    ///
+    /// Only the reader frees slots, so this must not run concurrently with
+    /// a dequeue through another handle on the same segment.
+    pub fn dequeue_shared(&self, sequence: u32) -> Option<Message> {
+        // Occupied slots belong to the reader, and the caller is it.
+        self.shared_slot(sequence).map(|slot| Message(unsafe { *slot }))
+    }
+
+    /// The slot holding message `sequence`, under the conditions of
+    /// `dequeue_shared`.
+    pub(crate) fn shared_slot(&self, sequence: u32) -> Option<*const [u8; SIZE]> {
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        header.wait_while_compacting();
+        let count = header.message_count.load(order, Ordering::Acquire) as usize;
+        let ahead = sequence.wrapping_sub(header.dequeued.load(order, Ordering::Relaxed)) as usize;
+        if ahead >= count.min(MSGS) {
+            return None;
+        }
+        let index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize + ahead);
+        if header.slot_sequence[index].load(order, Ordering::Relaxed) != sequence {
+            return None;
+        }
+        Some(segment.slot(index).cast::<[u8; SIZE]>().cast_const())
+    }
+
+    /// Passes the oldest queued message to `read` and frees its slot.
+    fn consume_front<R>(&self, read: impl FnOnce(&[u8; SIZE]) -> R) -> Result<R, QueueError> {
+        // `read` never declines, so the message is always consumed.
+        self.consume_front_if(|slot| Some(read(slot))).map(Option::unwrap)
+    }
+
+    /// Like `consume_front`, except that the message stays queued, and
+    /// `Ok(None)` is returned, when `read` declines it with `None`.
+    fn consume_front_if<R>(&self, read: impl FnOnce(&[u8; SIZE]) -> Option<R>) -> Result<Option<R>, QueueError> {
+        // SAFETY: the slot is the reader's for the whole of `read`, see
+        // `consume_front_if_raw`, and the reference does not outlive it.
+        self.consume_front_if_raw(Handoff::Ordered, |slot| read(unsafe { &*slot }))
+    }
+
+    /// Like `consume_front_if`, handing `read` the slot's address rather
+    /// than a reference to it, with the ordering `handoff` asks for.
+    fn consume_front_if_raw<R>(
+        &self,
+        handoff: Handoff,
+        read: impl FnOnce(*const [u8; SIZE]) -> Option<R>,
+    ) -> Result<Option<R>, QueueError> {
+        #[cfg(feature = "shmem")]
+        let _hold = self.hold_off_freeze()?;
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        header.wait_while_compacting();
+        self.check_generation()?;
+        self.check_poison()?;
+        self.check_owner(PeerRole::Receiver)?;
+        // Closing follows the last message, so loaded first.
+        let closed = header.state.load(Ordering::Acquire) == CLOSED;
+        if header.message_count.load(order, Ordering::Acquire) == 0 {
+            return Err(if closed { QueueError::Closed } else { QueueError::EmptyBuffer });
+        }
+
+        let read_index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize);
+        let slot = segment.slot(read_index);
+        let expected = header.dequeued.load(order, Ordering::Relaxed);
+        let got = header.slot_sequence[read_index].load(order, Ordering::Relaxed);
+        let strict = header.delivery_order.load(Ordering::Relaxed) == DeliveryOrder::StrictFifo as u8;
+        let in_order = !strict || got == expected;
+        #[cfg(feature = "slot-poison")]
+        let generation = expected;
+        // Occupied slots belong to the reader until `message_count` gives
+        // them back, which happens only below.
+        let result = match in_order.then(|| read(slot.cast::<[u8; SIZE]>().cast_const())) {
+            Some(None) => return Ok(None),
+            Some(Some(result)) => Some(result),
+            None => None,
+        };
+        // Only a second reader on the segment can move `dequeued` meanwhile.
+        #[cfg(feature = "slot-poison")]
+        assert_eq!(
+            header.dequeued.load(order, Ordering::Relaxed),
+            generation,
+            "slot {} was recycled while being read",
+            read_index
+        );
+        // An observer copying the slot right now sees the generation move.
+        segment.record_history(read_index);
+        header.slot_generation[read_index].fetch_add(order, 1, Ordering::Relaxed);
+        if handoff == Handoff::Ordered {
+            fence(Ordering::Release);
+        }
+        unsafe { ptr::write_bytes(slot, FREED_SLOT_BYTE, SIZE) };
+
+        header
+            .read_index
+            .store(order, slot_index(read_index + 1) as u32, Ordering::Relaxed);
+        header.dequeued.fetch_add(order, 1, Ordering::Relaxed);
+        // A pin ends with its message, and must be gone before the writer
+        // can reuse the slot.
+        header.set_pinned(read_index, false);
+        // Before the slot is given back, so an empty queue never has an
+        // older `last_dequeue` than its last message was taken at.
+        self.note_dequeue();
+        if header.message_count.fetch_sub(order, 1, Ordering::Release) == 1 {
+            handoff.crossed_zero();
+        }
+        result.map(Some).ok_or(QueueError::OrderViolation { expected, got })
+    }
+
+    /// Number of messages currently queued.
+    pub fn len(&self) -> usize {
+        self.segment().header.message_count.load(self.byte_order(), Ordering::Acquire) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of messages the port can hold.
+    pub fn capacity(&self) -> usize {
+        MSGS
+    }
+
+    /// Whether slot indices take a mask or a division, which depends on
+    /// whether `MSGS` is a power of two; see the `index` module.
+    pub const fn index_math() -> IndexMath {
+        IndexMath::for_slots(MSGS)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        QueueStats {
+            enqueued: header.enqueued.load(order, Ordering::Relaxed),
+            dequeued: header.dequeued.load(order, Ordering::Relaxed),
+            rejected: header.rejected.load(order, Ordering::Relaxed),
+            high_watermark: header.high_watermark.load(order, Ordering::Relaxed),
+            filtered_out: header.filtered_out.load(order, Ordering::Relaxed),
+            deadline_misses: header.deadline_misses.load(order, Ordering::Relaxed),
+            duplicates_dropped: self.dedup.dropped(),
+            corrupted_skipped: self.corruption.skipped(),
+            evicted_on_close: self.evicted_on_close.get(),
+            dropped_oldest: self.dropped_oldest.get(),
+            signals_sent: self.signals.sent.get(),
+            signals_coalesced: self.signals.coalesced.get(),
+            signals_received: self.signals.received.get(),
+            clock_anomalies: self.clock.anomalies(),
+        }
+    }
+
+    /// Sets the clock deadlines refer to. The default is `StdClock` with std
+    /// and `NoClock` without, which makes every deadline a miss.
+    ///
+    /// The port reads it as a `MonotonicClock`, see the `clock` module.
+    pub fn set_clock(&mut self, clock: ClockRef) {
+        self.clock.set_source(clock);
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        &self.clock
+    }
+
+    /// Enqueues `message`, waiting for space (and, in credit mode, for a
+    /// credit) until the port's clock reaches `deadline_ns`.
+    ///
+    /// A deadline that has already passed fails without an attempt. On
+    /// failure the message is dropped, `QueueError::DeadlineMissed` returned
+    /// and the miss counted in `QueueStats::deadline_misses`; waiting for
+    /// space does not count as rejected enqueues.
+    pub fn enqueue_by(&mut self, message: Message, deadline_ns: u64) -> Result<(), QueueError> {
+        loop {
+            if self.clock().now_ns() >= deadline_ns {
+                self.segment().header.deadline_misses.fetch_add(self.byte_order(), 1, Ordering::Relaxed);
+                return Err(QueueError::DeadlineMissed);
+            }
+            // Only the reader changes the count now, and only downwards, so
+            // a free slot (and credit) seen here is still there in `enqueue`.
+            if self.room() > 0 {
+                return self.enqueue(message);
+            }
+            self.clock().pause(deadline_ns);
+        }
+    }
+
+    /// Like `enqueue_by`, with the deadline `timeout` from now on the
+    /// port's clock.
+    pub fn enqueue_timeout(&mut self, message: Message, timeout: core::time::Duration) -> Result<(), QueueError> {
+        let deadline_ns = self.clock().now_ns().saturating_add(timeout.as_nanos() as u64);
+        self.enqueue_by(message, deadline_ns)
+    }
+
+    /// Dequeues a message, waiting for one until the port's clock reaches
+    /// `deadline_ns`, and failing with `QueueError::EmptyBuffer` if none
+    /// came. The port is looked at at least once, even for a deadline that
+    /// has passed.
+    pub fn dequeue_by(&mut self, deadline_ns: u64) -> Result<Message, QueueError> {
+        loop {
+            match self.try_dequeue() {
+                Err(QueueError::EmptyBuffer) if self.clock().now_ns() < deadline_ns => {}
+                result => return result,
+            }
+            self.clock().pause(deadline_ns);
+        }
+    }
+
+    /// Like `dequeue_by`, with the deadline `timeout` from now on the
+    /// port's clock.
+    pub fn dequeue_timeout(&mut self, timeout: core::time::Duration) -> Result<Message, QueueError> {
+        let deadline_ns = self.clock().now_ns().saturating_add(timeout.as_nanos() as u64);
+        self.dequeue_by(deadline_ns)
+    }
+
+    /// Dequeues a message, sleeping until one is available.
+    ///
+    /// With the `linux-futex` feature the caller is parked in the kernel on
+    /// `message_count` and woken by `enqueue`; otherwise this spins. The futex
+    /// is a shared (not process-private) one, keyed by the backing memory
+    /// object and offset, so a writer in another process only wakes us if the
+    /// segment is the same shared mapping on both sides — the header has to
+    /// live in the shared memory itself, never in a per-process copy.
+    pub fn dequeue_blocking(&mut self) -> Result<Message, QueueError> {
+        loop {
+            match self.try_dequeue() {
+                Err(QueueError::EmptyBuffer) => {}
+                result => return result,
+            }
+            wait::wait(self.segment().header.message_count.raw(), 0).map_err(QueueError::WaitFailed)?;
+        }
+    }
+}
+
+impl EnqueuePort for QueueingPort {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        QueueingPort::enqueue(self, message)
+    }
+}
+
+impl DequeuePort for QueueingPort {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.try_dequeue()
+    }
+}
+
+impl<P: EnqueuePort + ?Sized> EnqueuePort for &mut P {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        (**self).enqueue(message)
+    }
+}
+
+impl<P: DequeuePort + ?Sized> DequeuePort for &mut P {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        (**self).dequeue()
+    }
+}
+
+impl Default for QueueingPort {
+    fn default() -> Self {
+        QueueingPort::new()
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn enqueue_dequeue_test() {
+        let mut qp = QueueingPort::new();
+        let message = Message([1; SIZE]);
+
+        assert!(qp.enqueue(message).is_ok(), "Enqueue is successful");
+        let result = qp.dequeue();
+
+        assert!(result.is_some(), "Dequeue returning some message");
+        assert_eq!(result.unwrap().0, [1; SIZE], "Dequeued message should match the enqueued message");
+    }
+
+    fn fill(port: &mut QueueingPort) {
+        while port.enqueue(Message([0; SIZE])).is_ok() {}
+    }
+
+    #[test]
+    fn aligned_size_rounds_up_to_8() {
+        assert_eq!([0, 1, 7, 8, 9, 64, 100].map(aligned_size), [0, 8, 8, 8, 16, 64, 104]);
+        assert_eq!(aligned_size(SIZE), SIZE);
+        // Every slot of an owned port starts on an 8-byte boundary.
+        let port = QueueingPort::new();
+        let first = port.segment().slot(0) as usize;
+        assert!((0..MSGS).all(|index| (port.segment().slot(index) as usize - first).is_multiple_of(8)));
+    }
+
+    #[test]
+    fn enqueue_by_past_deadline_makes_no_attempt() {
+        let mut port = QueueingPort::new();
+        port.set_clock(Arc::new(MockClock::new(100)));
+
+        assert!(matches!(port.enqueue_by(Message([1; SIZE]), 100), Err(QueueError::DeadlineMissed)));
+        assert!(port.is_empty(), "the queue had room, but the deadline had passed");
+        assert_eq!(port.stats().deadline_misses, 1);
+        assert!(port.enqueue_by(Message([1; SIZE]), 101).is_ok());
+    }
+
+    #[test]
+    fn port_can_be_shared_behind_a_mutex() {
+        fn assert_send<T: Send>() {}
+        assert_send::<QueueingPort>();
+
+        let port = Arc::new(std::sync::Mutex::new(QueueingPort::new()));
+        let producers: Vec<_> = (0..2u8)
+            .map(|i| {
+                let port = Arc::clone(&port);
+                std::thread::spawn(move || port.lock().unwrap().enqueue(Message([i; SIZE])).unwrap())
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut port = port.lock().unwrap();
+        let mut tags = [port.dequeue().unwrap().0[0], port.dequeue().unwrap().0[0]];
+        tags.sort();
+        assert_eq!(tags, [0, 1]);
+    }
+
+    #[test]
+    fn enqueue_by_succeeds_once_consumer_frees_space() {
+        // The segment outlives both handles here.
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut writer = unsafe { QueueingPort::attach(segment) };
+        let mut reader = unsafe { QueueingPort::attach(segment) };
+        writer.set_clock(Arc::new(MockClock::new(0)));
+        fill(&mut writer);
+
+        let consumer = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            reader.dequeue().unwrap();
+        });
+        assert!(writer.enqueue_by(Message([2; SIZE]), 10).is_ok());
+        consumer.join().unwrap();
+
+        let stats = writer.stats();
+        assert_eq!((stats.rejected, stats.deadline_misses), (1, 0), "only the fill was rejected");
+    }
+
+    /// Runs a writer and a reader on their own threads for 500ms, each
+    /// sleeping its period between operations, then drains the queue.
+    /// Returns the enqueues accepted and refused, and the messages read.
+    fn run_at_speeds(writer_period: u64, reader_period: u64) -> (u32, u32, u32) {
+        use std::sync::atomic::AtomicBool;
+        use std::time::{Duration, Instant};
+
+        let segment = Box::new(Segment::new());
+        let mut writer = unsafe { QueueingPort::attach(NonNull::from(&*segment)) };
+        let mut reader = unsafe { QueueingPort::attach(NonNull::from(&*segment)) };
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let received = scope.spawn(|| {
+                let mut received = 0u32;
+                loop {
+                    // Loaded before trying, so nothing sent before is missed.
+                    let finished = done.load(Ordering::Acquire);
+                    match reader.try_dequeue() {
+                        Ok(message) => {
+                            assert_eq!(message.0[..4], received.to_le_bytes(), "in order, none lost");
+                            received += 1;
+                        }
+                        Err(QueueError::EmptyBuffer) if finished => return received,
+                        Err(err) => assert!(matches!(err, QueueError::EmptyBuffer), "{:?}", err),
+                    }
+                    if !finished {
+                        std::thread::sleep(Duration::from_millis(reader_period));
+                    }
+                }
+            });
+            let (mut accepted, mut refused) = (0u32, 0);
+            let started = Instant::now();
+            while started.elapsed() < Duration::from_millis(500) {
+                let mut bytes = [0; SIZE];
+                bytes[..4].copy_from_slice(&accepted.to_le_bytes());
+                match writer.enqueue(Message(bytes)) {
+                    Ok(()) => accepted += 1,
+                    Err(err) => {
+                        assert!(matches!(err, QueueError::FullBuffer), "{:?}", err);
+                        refused += 1;
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(writer_period));
+            }
+            done.store(true, Ordering::Release);
+            (accepted, refused, received.join().unwrap())
+        })
+    }
+
+    #[test]
+    fn unequal_speeds_a_fast_writer_is_refused_and_nothing_is_lost() {
+        let (accepted, refused, received) = run_at_speeds(10, 50);
+        assert!(refused > 0, "the queue filled up");
+        assert_eq!(received, accepted);
+
+        let (accepted, refused, received) = run_at_speeds(50, 10);
+        assert_eq!(refused, 0, "the reader keeps up");
+        assert!(accepted >= 5);
+        assert_eq!(received, accepted);
+    }
+
+    #[test]
+    fn unequal_speeds_a_fast_writer_drops_the_oldest() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Mutex;
+        use std::time::{Duration, Instant};
+
+        // Dropping reads from the front, so both ends share the handle.
+        let mut port = QueueingPort::new();
+        port.set_overflow_policy(OverflowPolicy::DropOldest);
+        let port = Mutex::new(port);
+        let done = AtomicBool::new(false);
+        let (sent, received) = std::thread::scope(|scope| {
+            let reader = scope.spawn(|| {
+                let (mut received, mut last) = (0u32, None);
+                while !done.load(Ordering::Acquire) {
+                    if let Ok(message) = port.lock().unwrap().try_dequeue() {
+                        let sequence = u32::from_le_bytes(message.0[..4].try_into().unwrap());
+                        assert!(last < Some(sequence), "{} after {:?}", sequence, last);
+                        (received, last) = (received + 1, Some(sequence));
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                received
+            });
+            let mut sent = 0u32;
+            let started = Instant::now();
+            while started.elapsed() < Duration::from_millis(500) {
+                let mut bytes = [0; SIZE];
+                bytes[..4].copy_from_slice(&sent.to_le_bytes());
+                port.lock().unwrap().enqueue(Message(bytes)).unwrap();
+                sent += 1;
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            done.store(true, Ordering::Release);
+            (sent, reader.join().unwrap())
+        });
+        let port = port.into_inner().unwrap();
+        let stats = port.stats();
+        assert!(stats.dropped_oldest > 0, "the queue filled up");
+        assert_eq!((stats.enqueued, stats.rejected), (sent, 0));
+        assert_eq!(sent, received + stats.dropped_oldest + port.len() as u32, "only the dropped are missing");
+    }
+
+    #[test]
+    fn enqueue_by_misses_deadline_while_full() {
+        let clock = Arc::new(SimulatedClock::with_step(0, 1));
+        let mut port = QueueingPort::new();
+        port.set_clock(clock.clone());
+        fill(&mut port);
+
+        assert!(matches!(port.enqueue_by(Message([3; SIZE]), 5), Err(QueueError::DeadlineMissed)));
+        assert_eq!(clock.now_ns(), 5, "five polls, one step each");
+        assert_eq!(port.len(), MSGS);
+        assert_eq!(port.stats().deadline_misses, 1);
+    }
+
+    #[test]
+    fn ten_second_timeouts_take_no_real_time() {
+        use core::time::Duration;
+
+        let (mut port, clock) = QueueingPort::new_simulated();
+        let started = std::time::Instant::now();
+        assert!(matches!(port.dequeue_timeout(Duration::from_secs(10)), Err(QueueError::EmptyBuffer)));
+        assert_eq!(clock.now_ns(), 10_000_000_000);
+        fill(&mut port);
+        assert!(matches!(port.enqueue_timeout(Message([3; SIZE]), Duration::from_secs(10)), Err(QueueError::DeadlineMissed)));
+        assert_eq!(clock.now_ns(), 20_000_000_000);
+        assert!(started.elapsed() < Duration::from_millis(1), "took {:?}", started.elapsed());
+
+        assert_eq!(port.dequeue_timeout(Duration::from_secs(10)).unwrap().0, [0; SIZE]);
+        assert_eq!(clock.now_ns(), 20_000_000_000, "no wait for a queued message");
+    }
+
+    #[cfg(all(feature = "linux-futex", target_os = "linux"))]
+    #[test]
+    fn dequeue_blocking_sleeps_until_writer_enqueues() {
+        use std::time::{Duration, Instant};
+
+        // utime + stime of the calling thread, in clock ticks. The per-thread
+        // view keeps other tests running in parallel out of the measurement.
+        fn cpu_ticks() -> u64 {
+            let stat = std::fs::read_to_string("/proc/thread-self/stat").unwrap();
+            let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+            fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+        }
+
+        let size = core::mem::size_of::<Segment>();
+        let memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(memory, libc::MAP_FAILED);
+        let segment = NonNull::new(memory.cast::<Segment>()).unwrap();
+        let mut reader = unsafe { QueueingPort::attach(segment) };
+        let mut writer = unsafe { QueueingPort::attach(segment) };
+
+        let child = unsafe { libc::fork() };
+        assert!(child >= 0);
+        if child == 0 {
+            let delay = libc::timespec { tv_sec: 1, tv_nsec: 0 };
+            unsafe { libc::nanosleep(&delay, ptr::null_mut()) };
+            let status = if writer.enqueue(Message([7; SIZE])).is_ok() { 0 } else { 1 };
+            unsafe { libc::_exit(status) };
+        }
+
+        let started = Instant::now();
+        let ticks_before = cpu_ticks();
+        let message = reader.dequeue_blocking().unwrap();
+        let ticks_spent = cpu_ticks() - ticks_before;
+
+        let mut status = 0;
+        unsafe { libc::waitpid(child, &mut status, 0) };
+        // Dropping a handle releases its claims in the header.
+        drop((reader, writer));
+        unsafe { libc::munmap(memory, size) };
+
+        assert_eq!(message.0, [7; SIZE]);
+        assert_eq!(status, 0, "writer process failed to enqueue");
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        // A spinning reader would have burnt roughly 100 ticks in that second.
+        assert!(ticks_spent <= 10, "reader used {} ticks while blocked", ticks_spent);
+    }
+}
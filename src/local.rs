@@ -0,0 +1,97 @@
+//! Ports for queues inside one process, on the heap.
+//!
+//! `QueueingPort::new` keeps the segment inline, so the port is as big as
+//! the segment and moving it copies every slot; a named port maps shared
+//! memory, which costs a file descriptor, a handshake and a few system
+//! calls to set up and tear down. `QueueingPort::boxed` allocates the port
+//! with `Box` instead: a pointer's worth to move, and the segment stays
+//! put whatever the queue is moved into, an `Arc<Mutex<_>>` between
+//! threads for instance.
+//!
+//! The result is a `LocalQueue` rather than a plain port, so signatures
+//! say that its segment never leaves the process: there is no name to open
+//! it by, and its address means nothing elsewhere.
+
+use alloc::boxed::Box;
+use core::ops::{Deref, DerefMut};
+
+use crate::{DequeuePort, EnqueuePort, Message, QueueError, QueueingPort};
+
+/// A port allocated on the heap, see `QueueingPort::boxed`.
+pub struct LocalQueue {
+    port: Box<QueueingPort>,
+}
+
+impl QueueingPort {
+    /// A port allocated on the heap, for queues between the threads of one
+    /// process; see the `local` module.
+    pub fn boxed() -> LocalQueue {
+        LocalQueue { port: Box::default() }
+    }
+}
+
+impl LocalQueue {
+    /// The port, for APIs that take one by value.
+    pub fn into_inner(self) -> Box<QueueingPort> {
+        self.port
+    }
+}
+
+impl Deref for LocalQueue {
+    type Target = QueueingPort;
+
+    fn deref(&self) -> &QueueingPort {
+        &self.port
+    }
+}
+
+impl DerefMut for LocalQueue {
+    fn deref_mut(&mut self) -> &mut QueueingPort {
+        &mut self.port
+    }
+}
+
+impl EnqueuePort for LocalQueue {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        self.port.enqueue(message)
+    }
+}
+
+impl DequeuePort for LocalQueue {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.port.try_dequeue()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Segment, MSGS, SIZE};
+    use core::mem::size_of;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn behaves_as_an_owned_port() {
+        let mut queue = QueueingPort::boxed();
+        assert!(size_of::<LocalQueue>() < size_of::<Segment>() / 2, "the segment is not inline");
+        assert!(matches!(queue.dequeue(), Err(QueueError::EmptyBuffer)));
+        for tag in 0..MSGS as u8 {
+            queue.enqueue(Message([tag; SIZE])).unwrap();
+        }
+        assert!(matches!(queue.enqueue(Message([0; SIZE])), Err(QueueError::FullBuffer)));
+        for tag in 0..MSGS as u8 {
+            assert_eq!(queue.dequeue().unwrap().0, [tag; SIZE]);
+        }
+        let stats = queue.stats();
+        assert_eq!((stats.enqueued, stats.dequeued, stats.rejected), (MSGS as u32, MSGS as u32, 1));
+        assert_eq!(queue.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn moves_between_threads() {
+        let queue = Arc::new(Mutex::new(QueueingPort::boxed().into_inner()));
+        let writer = Arc::clone(&queue);
+        std::thread::spawn(move || writer.lock().unwrap().enqueue_bytes(b"hello").unwrap()).join().unwrap();
+        assert_eq!(&queue.lock().unwrap().dequeue().unwrap().0[..5], b"hello");
+    }
+}
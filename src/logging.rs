@@ -0,0 +1,356 @@
+//! Log records through a port, from a partition that must never block on
+//! logging to a drainer on the host.
+//!
+//! `QueueLogger` formats each record into one message, type
+//! `LOG_RECORD_TYPE`:
+//!
+//! ```text
+//! 0..4 target hash   4..6 msg_type   6 level   7 flags   8..10 text length
+//! 10.. text, UTF-8, at most LOG_TEXT_CAPACITY bytes
+//! ```
+//!
+//! The target is sent as its FNV-1a hash, `target_hash`, which the host
+//! maps back to a name from its own list of targets. Text past the
+//! capacity is cut at a character boundary and the record flagged
+//! `LOG_TRUNCATED`, which `LogRecord` reports and prints as a trailing
+//! `…`.
+//!
+//! Logging never fails and never waits. When the port is full the logger
+//! drops the oldest record to make room, as `Port<Broadcast>::publish`
+//! does; that moves the reader's side of the header, so the drainer must
+//! not run at the same moment, as with partitions scheduled in turn on
+//! one core. A record logged while the logger is already formatting or
+//! enqueueing one, e.g. from a `Display` impl in the arguments or from an
+//! event sink called on the way, is dropped and counted instead of
+//! re-entering the port.
+
+use core::cell::{Cell, RefCell};
+use core::fmt;
+
+use crate::{QueueError, QueueingPort, SIZE};
+
+/// Message type of an encoded log record.
+pub const LOG_RECORD_TYPE: u16 = 0x4c47;
+/// Bytes of formatted text a record holds.
+pub const LOG_TEXT_CAPACITY: usize = SIZE - TEXT_AT;
+/// Record flag: the text was cut short at `LOG_TEXT_CAPACITY`.
+pub const LOG_TRUNCATED: u8 = 1;
+
+const TEXT_AT: usize = 10;
+
+/// Severity of a record, numbered as in the `log` crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    fn from_u8(value: u8) -> Option<LogLevel> {
+        match value {
+            1 => Some(LogLevel::Error),
+            2 => Some(LogLevel::Warn),
+            3 => Some(LogLevel::Info),
+            4 => Some(LogLevel::Debug),
+            5 => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+}
+
+/// The 32-bit FNV-1a hash a record carries in place of its target.
+pub const fn target_hash(target: &str) -> u32 {
+    let bytes = target.as_bytes();
+    let mut hash = 0x811c_9dc5u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+/// Collects formatted text up to the capacity, noting what did not fit.
+struct TextWriter {
+    text: [u8; LOG_TEXT_CAPACITY],
+    len: usize,
+    truncated: bool,
+}
+
+impl fmt::Write for TextWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = LOG_TEXT_CAPACITY - self.len;
+        let mut take = s.len().min(room);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.text[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        // Keep formatting the rest, so the arguments see no error.
+        self.truncated |= take < s.len();
+        Ok(())
+    }
+}
+
+/// The sending end; see the module documentation.
+pub struct QueueLogger {
+    port: RefCell<QueueingPort>,
+    /// Set while a record is being formatted and enqueued.
+    busy: Cell<bool>,
+    overwritten: Cell<u32>,
+    reentrant: Cell<u32>,
+}
+
+impl QueueLogger {
+    pub fn new(port: QueueingPort) -> QueueLogger {
+        QueueLogger {
+            port: RefCell::new(port),
+            busy: Cell::new(false),
+            overwritten: Cell::new(0),
+            reentrant: Cell::new(0),
+        }
+    }
+
+    /// Formats and enqueues a record, dropping the oldest queued one if
+    /// the port is full.
+    pub fn log(&self, level: LogLevel, target: &str, args: fmt::Arguments<'_>) {
+        if self.busy.replace(true) {
+            self.reentrant.set(self.reentrant.get().wrapping_add(1));
+            return;
+        }
+        let mut writer = TextWriter { text: [0; LOG_TEXT_CAPACITY], len: 0, truncated: false };
+        let _ = fmt::write(&mut writer, args);
+
+        let mut record = [0; SIZE];
+        record[0..4].copy_from_slice(&target_hash(target).to_le_bytes());
+        record[4..6].copy_from_slice(&LOG_RECORD_TYPE.to_le_bytes());
+        record[6] = level as u8;
+        record[7] = if writer.truncated { LOG_TRUNCATED } else { 0 };
+        record[8..10].copy_from_slice(&(writer.len as u16).to_le_bytes());
+        record[TEXT_AT..TEXT_AT + writer.len].copy_from_slice(&writer.text[..writer.len]);
+
+        let mut port = self.port.borrow_mut();
+        let bytes = &record[..TEXT_AT + writer.len];
+        if let Err(QueueError::FullBuffer) = port.enqueue_bytes(bytes) {
+            if port.consume_front(|_| ()).is_ok() {
+                self.overwritten.set(self.overwritten.get().wrapping_add(1));
+            }
+            let _ = port.enqueue_bytes(bytes);
+        }
+        // Other failures, such as a port out of credits, lose the record:
+        // they are not the application's problem either.
+        drop(port);
+        self.busy.set(false);
+    }
+
+    /// Records dropped to make room for newer ones.
+    pub fn overwritten(&self) -> u32 {
+        self.overwritten.get()
+    }
+
+    /// Records dropped because they were logged from inside `log`.
+    pub fn reentrant_dropped(&self) -> u32 {
+        self.reentrant.get()
+    }
+
+    pub fn into_port(self) -> QueueingPort {
+        self.port.into_inner()
+    }
+}
+
+/// A decoded log record.
+#[derive(Clone)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    pub target_hash: u32,
+    /// The text was cut short at `LOG_TEXT_CAPACITY`.
+    pub truncated: bool,
+    text: [u8; LOG_TEXT_CAPACITY],
+    len: usize,
+}
+
+impl LogRecord {
+    /// Decodes a log record message; `None` for other messages and
+    /// malformed records.
+    pub fn decode(bytes: &[u8; SIZE]) -> Option<LogRecord> {
+        if u16::from_le_bytes([bytes[4], bytes[5]]) != LOG_RECORD_TYPE {
+            return None;
+        }
+        let level = LogLevel::from_u8(bytes[6])?;
+        let len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        if len > LOG_TEXT_CAPACITY {
+            return None;
+        }
+        let text = &bytes[TEXT_AT..TEXT_AT + len];
+        core::str::from_utf8(text).ok()?;
+        let mut record = LogRecord {
+            level,
+            target_hash: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            truncated: bytes[7] & LOG_TRUNCATED != 0,
+            text: [0; LOG_TEXT_CAPACITY],
+            len,
+        };
+        record.text[..len].copy_from_slice(text);
+        Some(record)
+    }
+
+    pub fn text(&self) -> &str {
+        // Checked by `decode`.
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogRecord")
+            .field("level", &self.level)
+            .field("target_hash", &self.target_hash)
+            .field("truncated", &self.truncated)
+            .field("text", &self.text())
+            .finish()
+    }
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} [{:08x}] {}", self.level, self.target_hash, self.text())?;
+        if self.truncated {
+            f.write_str("…")?;
+        }
+        Ok(())
+    }
+}
+
+/// The receiving end, taking log records off a port.
+pub struct LogDrainer {
+    port: QueueingPort,
+}
+
+impl LogDrainer {
+    /// Drains `port`, skipping messages that are not log records.
+    pub fn new(mut port: QueueingPort) -> LogDrainer {
+        // One type always fits the filter.
+        let _ = port.set_type_filter(&[LOG_RECORD_TYPE]);
+        LogDrainer { port }
+    }
+
+    /// The oldest record, `None` when there is none. Malformed records are
+    /// skipped.
+    pub fn next_record(&mut self) -> Option<LogRecord> {
+        loop {
+            let message = self.port.dequeue()?;
+            if let Some(record) = LogRecord::decode(&message.0) {
+                return Some(record);
+            }
+        }
+    }
+
+    pub fn into_port(self) -> QueueingPort {
+        self.port
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ptr::NonNull;
+
+    /// A logger and a drainer on one segment.
+    fn pair(segment: &crate::Segment) -> (QueueLogger, LogDrainer) {
+        let attach = || unsafe { QueueingPort::attach(NonNull::from(segment)) };
+        (QueueLogger::new(attach()), LogDrainer::new(attach()))
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let segment = crate::Segment::new();
+        let (logger, mut drainer) = pair(&segment);
+        logger.log(LogLevel::Error, "io", format_args!("read failed: errno {}", 5));
+        logger.log(LogLevel::Info, "sched::tick", format_args!("{:>5}|{:.2}", "ok", 1.0f32 / 3.0));
+        logger.log(LogLevel::Trace, "", format_args!(""));
+        logger.log(LogLevel::Warn, "ünï", format_args!("naïve café"));
+
+        let records: Vec<(LogLevel, u32, String)> = core::iter::from_fn(|| drainer.next_record())
+            .map(|r| (r.level, r.target_hash, r.text().to_owned()))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (LogLevel::Error, target_hash("io"), "read failed: errno 5".to_owned()),
+                (LogLevel::Info, target_hash("sched::tick"), "   ok|0.33".to_owned()),
+                (LogLevel::Trace, target_hash(""), String::new()),
+                (LogLevel::Warn, target_hash("ünï"), "naïve café".to_owned()),
+            ]
+        );
+        // The reference values of FNV-1a.
+        assert_eq!((target_hash(""), target_hash("a")), (0x811c_9dc5, 0xe40c_292c));
+    }
+
+    #[test]
+    fn long_text_is_truncated_with_a_marker() {
+        let segment = crate::Segment::new();
+        let (logger, mut drainer) = pair(&segment);
+        let long = "é".repeat(LOG_TEXT_CAPACITY);
+        logger.log(LogLevel::Debug, "t", format_args!("{}", long));
+        let record = drainer.next_record().unwrap();
+        assert!(record.truncated);
+        // Two bytes per character, cut at a character boundary.
+        assert_eq!(record.text(), "é".repeat(LOG_TEXT_CAPACITY / 2));
+        assert!(record.to_string().ends_with("é…"));
+
+        logger.log(LogLevel::Debug, "t", format_args!("{}", &long[..LOG_TEXT_CAPACITY]));
+        let record = drainer.next_record().unwrap();
+        assert!(!record.truncated, "exactly the capacity fits");
+        assert_eq!(record.text().len(), LOG_TEXT_CAPACITY);
+    }
+
+    #[test]
+    fn a_storm_keeps_the_newest_records() {
+        let segment = crate::Segment::new();
+        let (logger, mut drainer) = pair(&segment);
+        for i in 0..1000 {
+            logger.log(LogLevel::Info, "storm", format_args!("record {}", i));
+        }
+        assert_eq!(logger.overwritten(), 1000 - crate::MSGS as u32);
+        let texts: Vec<String> = core::iter::from_fn(|| drainer.next_record()).map(|r| r.text().to_owned()).collect();
+        let expected: Vec<String> = (1000 - crate::MSGS..1000).map(|i| format!("record {}", i)).collect();
+        assert_eq!(texts, expected);
+    }
+
+    #[test]
+    fn logging_from_inside_log_is_dropped() {
+        struct LogsWhenFormatted<'a>(&'a QueueLogger);
+
+        impl fmt::Display for LogsWhenFormatted<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.log(LogLevel::Error, "inner", format_args!("nested"));
+                f.write_str("outer text")
+            }
+        }
+
+        let segment = crate::Segment::new();
+        let (logger, mut drainer) = pair(&segment);
+        logger.log(LogLevel::Info, "outer", format_args!("{}", LogsWhenFormatted(&logger)));
+        assert_eq!(logger.reentrant_dropped(), 1);
+        assert_eq!(drainer.next_record().unwrap().text(), "outer text");
+        assert!(drainer.next_record().is_none());
+
+        logger.log(LogLevel::Info, "after", format_args!("still logging"));
+        assert_eq!(drainer.next_record().unwrap().text(), "still logging");
+    }
+
+    #[test]
+    fn other_messages_are_skipped() {
+        let segment = crate::Segment::new();
+        let (logger, mut drainer) = pair(&segment);
+        let mut other = unsafe { QueueingPort::attach(NonNull::from(&segment)) };
+        other.enqueue_bytes(&[1, 2, 3, 4, 9, 9]).unwrap();
+        logger.log(LogLevel::Warn, "t", format_args!("kept"));
+        assert_eq!(drainer.next_record().unwrap().text(), "kept");
+    }
+}
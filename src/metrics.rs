@@ -0,0 +1,150 @@
+//! Prometheus text-format rendering of port metrics.
+//!
+//! Only the exposition text is produced here; serving it is up to the caller.
+
+use std::fmt::Write;
+use std::string::String;
+
+use crate::QueueingPort;
+
+enum Kind {
+    Gauge,
+    Counter,
+}
+
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    value: fn(&QueueingPort) -> u64,
+}
+
+const FAMILIES: &[Family] = &[
+    Family {
+        name: "qp_len",
+        help: "Messages currently queued.",
+        kind: Kind::Gauge,
+        value: |port| port.len() as u64,
+    },
+    Family {
+        name: "qp_capacity",
+        help: "Maximum number of messages the port can hold.",
+        kind: Kind::Gauge,
+        value: |port| port.capacity() as u64,
+    },
+    Family {
+        name: "qp_enqueued_total",
+        help: "Messages enqueued since the segment was created.",
+        kind: Kind::Counter,
+        value: |port| port.stats().enqueued.into(),
+    },
+    Family {
+        name: "qp_dequeued_total",
+        help: "Messages dequeued since the segment was created.",
+        kind: Kind::Counter,
+        value: |port| port.stats().dequeued.into(),
+    },
+    Family {
+        name: "qp_rejected_total",
+        help: "Enqueues rejected because the port was full.",
+        kind: Kind::Counter,
+        value: |port| port.stats().rejected.into(),
+    },
+    Family {
+        name: "qp_high_watermark",
+        help: "Highest number of messages queued at once.",
+        kind: Kind::Gauge,
+        value: |port| port.stats().high_watermark.into(),
+    },
+];
+
+impl QueueingPort {
+    /// Appends this port's metrics to `out`, labelled with `port="<port_name>"`.
+    ///
+    /// Each call emits complete metric families, so use
+    /// [`render_metrics_all`] when several ports share one exposition.
+    pub fn render_metrics(&self, port_name: &str, out: &mut String) {
+        render_metrics_all(&[(port_name, self)], out);
+    }
+}
+
+/// Appends the metrics of several ports to `out`, keeping the samples of
+/// each family together as the text format requires.
+pub fn render_metrics_all(ports: &[(&str, &QueueingPort)], out: &mut String) {
+    for family in FAMILIES {
+        let kind = match family.kind {
+            Kind::Gauge => "gauge",
+            Kind::Counter => "counter",
+        };
+        // Writing into a String cannot fail.
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family.name, kind);
+        for (name, port) in ports {
+            out.push_str(family.name);
+            out.push_str("{port=\"");
+            push_label_value(out, name);
+            let _ = writeln!(out, "\"}} {}", (family.value)(port));
+        }
+    }
+}
+
+/// Escapes a label value: backslash, double quote and line feed are the
+/// only characters the text format requires escaping.
+fn push_label_value(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+
+    // Full queue, one rejected enqueue, three messages drained again.
+    fn scripted_port() -> QueueingPort {
+        let mut port = QueueingPort::new();
+        for i in 0..port.capacity() {
+            port.enqueue(Message([i as u8; SIZE])).unwrap();
+        }
+        assert!(port.enqueue(Message([0; SIZE])).is_err());
+        for _ in 0..3 {
+            port.dequeue().unwrap();
+        }
+        port
+    }
+
+    #[test]
+    fn render_matches_fixture() {
+        let port = scripted_port();
+        let mut out = String::new();
+        port.render_metrics("sensor_data", &mut out);
+        assert_eq!(out, include_str!("../tests/fixtures/metrics.prom"));
+    }
+
+    #[test]
+    fn render_groups_families_across_ports() {
+        let busy = scripted_port();
+        let idle = QueueingPort::new();
+        let mut out = String::new();
+        render_metrics_all(&[("busy", &busy), ("idle", &idle)], &mut out);
+        assert_eq!(
+            out,
+            include_str!("../tests/fixtures/metrics_two_ports.prom")
+        );
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let port = QueueingPort::new();
+        let mut out = String::new();
+        port.render_metrics("odd \"port\"\\a\nb", &mut out);
+        assert!(out.contains("qp_len{port=\"odd \\\"port\\\"\\\\a\\nb\"} 0\n"));
+        assert_eq!(out.lines().count(), 3 * FAMILIES.len());
+    }
+}
@@ -0,0 +1,186 @@
+//! Upgrading snapshot files written in format version 1 to version 2.
+//!
+//! Version 1 snapshots carry the messages and statistics only; version 2
+//! adds the port's settings and each message's sequence number, see the
+//! `snapshot` module. `migrate_v1_to_v2` turns a persisted version 1 file
+//! into a version 2 one for a CRC port: the messages are numbered in order
+//! from the `dequeued` count the file records (from zero if it has no
+//! statistics), and each is sealed with its CRC, as `Message::seal_crc`
+//! does.
+//!
+//! The CRC takes the last `CRC_LEN` bytes of every message, which version
+//! 1 writers may have used. Migration checks they are all zero and fails
+//! with `MigrationError::TrailerInUse` otherwise, rather than overwrite
+//! message bytes.
+
+use std::io;
+use std::path::Path;
+
+use crate::corruption::crc32;
+use crate::{QueueingPort, SnapshotError, WireFeatures, CRC_PAYLOAD, MSGS, SIZE};
+
+/// The snapshot format version migration reads.
+const SOURCE_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// Reading the source or writing the destination failed.
+    Io(io::Error),
+    /// The source is a snapshot of format version `found`, newer than the
+    /// `supported` one migration reads.
+    VersionTooNew { found: u16, supported: u16 },
+    /// The source is truncated or not a snapshot.
+    Corrupt,
+    /// The source was taken from a port with another slot size or count.
+    IncompatibleLayout,
+    /// Message `index` (0 the oldest) has data where its CRC would go.
+    TrailerInUse { index: usize },
+}
+
+impl From<io::Error> for MigrationError {
+    fn from(error: io::Error) -> Self {
+        MigrationError::Io(error)
+    }
+}
+
+/// Reads the version 1 snapshot at `src_path` and writes it as version 2,
+/// with sequence numbers and CRCs, to `dst_path`. Returns the number of
+/// messages migrated. Nothing is written if migration fails.
+pub fn migrate_v1_to_v2(src_path: &Path, dst_path: &Path) -> Result<usize, MigrationError> {
+    let blob = std::fs::read(src_path)?;
+    if blob.len() < 6 || blob[0..4] != *b"QPSN" {
+        return Err(MigrationError::Corrupt);
+    }
+    match u16::from_le_bytes([blob[4], blob[5]]) {
+        SOURCE_VERSION => {}
+        found if found > SOURCE_VERSION => {
+            return Err(MigrationError::VersionTooNew { found, supported: SOURCE_VERSION })
+        }
+        _ => return Err(MigrationError::Corrupt),
+    }
+    let port = QueueingPort::restore(&blob).map_err(|error| match error {
+        SnapshotError::IncompatibleLayout => MigrationError::IncompatibleLayout,
+        _ => MigrationError::Corrupt,
+    })?;
+
+    let segment = port.segment();
+    let count = port.len();
+    // A restored port starts at slot 0, and is not shared yet.
+    for index in 0..count.min(MSGS) {
+        let slot = unsafe { &mut *segment.slot(index).cast::<[u8; SIZE]>() };
+        if slot[CRC_PAYLOAD..].iter().any(|&byte| byte != 0) {
+            return Err(MigrationError::TrailerInUse { index });
+        }
+        let crc = crc32(&slot[..CRC_PAYLOAD]);
+        slot[CRC_PAYLOAD..].copy_from_slice(&crc.to_le_bytes());
+    }
+    segment.header.features.fetch_or(segment.header.byte_order(), WireFeatures::CRC.bits(), core::sync::atomic::Ordering::Relaxed);
+
+    std::fs::write(dst_path, port.snapshot_to_bytes())?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, QueueError};
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("qp_migrate_{}_{}", name, std::process::id()))
+    }
+
+    /// A version 1 snapshot of `messages`, built by hand so the test does
+    /// not depend on what `snapshot` writes today.
+    fn v1_blob(messages: &[[u8; SIZE]], stats: Option<[u32; 6]>) -> Vec<u8> {
+        let mut blob = b"QPSN".to_vec();
+        blob.extend_from_slice(&1u16.to_le_bytes());
+        blob.extend_from_slice(&(if stats.is_some() { 6u16 } else { 0 }).to_le_bytes());
+        for word in [SIZE as u32, MSGS as u32, messages.len() as u32] {
+            blob.extend_from_slice(&word.to_le_bytes());
+        }
+        for stat in stats.iter().flatten() {
+            blob.extend_from_slice(&stat.to_le_bytes());
+        }
+        for message in messages {
+            blob.extend_from_slice(message);
+        }
+        blob
+    }
+
+    fn message(tag: u8) -> [u8; SIZE] {
+        let mut bytes = [tag; SIZE];
+        bytes[CRC_PAYLOAD..].fill(0);
+        bytes
+    }
+
+    fn migrate(name: &str, blob: &[u8]) -> (Result<usize, MigrationError>, PathBuf) {
+        let (src, dst) = (temp_path(&format!("{}_v1", name)), temp_path(&format!("{}_v2", name)));
+        std::fs::write(&src, blob).unwrap();
+        let _ = std::fs::remove_file(&dst);
+        let result = migrate_v1_to_v2(&src, &dst);
+        std::fs::remove_file(&src).unwrap();
+        (result, dst)
+    }
+
+    #[test]
+    fn v1_messages_open_as_a_v2_crc_port() {
+        let messages = [message(1), message(2), message(3)];
+        let (result, dst) = migrate("ok", &v1_blob(&messages, Some([12, 9, 0, 4, 0, 0])));
+        assert_eq!(result.unwrap(), 3);
+
+        let mut port = QueueingPort::restore_from_bytes(&std::fs::read(&dst).unwrap()).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+        assert!(port.wire_features().contains(WireFeatures::CRC));
+        assert_eq!((port.stats().enqueued, port.stats().dequeued), (12, 9));
+        for (tag, original) in (1..).zip(&messages) {
+            let received = port.dequeue_timed().unwrap();
+            assert_eq!(received.message.0[..CRC_PAYLOAD], original[..CRC_PAYLOAD], "message {}", tag);
+            assert!(received.message.crc_ok());
+            assert_eq!(received.sequence, 9 + tag - 1, "numbered from the dequeued count");
+        }
+        assert!(matches!(port.try_dequeue(), Err(QueueError::EmptyBuffer)));
+    }
+
+    #[test]
+    fn numbering_starts_at_zero_without_stats() {
+        let (result, dst) = migrate("nostats", &v1_blob(&[message(5), message(6)], None));
+        assert_eq!(result.unwrap(), 2);
+        let mut port = QueueingPort::restore_from_bytes(&std::fs::read(&dst).unwrap()).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+        assert_eq!(port.dequeue_timed().unwrap().sequence, 0);
+        assert_eq!(port.dequeue_timed().unwrap().sequence, 1);
+    }
+
+    #[test]
+    fn newer_and_broken_sources_are_refused() {
+        let v2 = QueueingPort::new().snapshot_to_bytes();
+        let (result, dst) = migrate("v2", &v2);
+        assert!(matches!(result, Err(MigrationError::VersionTooNew { found: 2, supported: 1 })));
+        assert!(!dst.exists(), "nothing written");
+
+        let blob = v1_blob(&[message(1), message(2)], None);
+        assert!(matches!(migrate("short", &blob[..blob.len() - 1]).0, Err(MigrationError::Corrupt)));
+        assert!(matches!(migrate("garbage", b"not a snapshot").0, Err(MigrationError::Corrupt)));
+
+        let mut used = message(2);
+        used[SIZE - 1] = 0xff;
+        let (result, dst) = migrate("trailer", &v1_blob(&[message(1), used], None));
+        assert!(matches!(result, Err(MigrationError::TrailerInUse { index: 1 })));
+        assert!(!dst.exists());
+
+        let missing = migrate_v1_to_v2(&temp_path("missing"), &temp_path("missing_v2"));
+        assert!(matches!(missing, Err(MigrationError::Io(e)) if e.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn migrated_messages_survive_a_crc_check() {
+        let (result, dst) = migrate("sealed", &v1_blob(&[message(7)], None));
+        result.unwrap();
+        let mut port = QueueingPort::restore_from_bytes(&std::fs::read(&dst).unwrap()).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+        let mut expected = Message(message(7));
+        expected.seal_crc();
+        assert_eq!(port.dequeue().unwrap().0, expected.0);
+    }
+}
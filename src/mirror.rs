@@ -0,0 +1,155 @@
+//! Ports that keep a second copy of every message in a backup segment.
+//!
+//! The two halves are ordinary ports, so they can sit in different named
+//! segments, on different NUMA nodes or behind a remote mapping. Reads come
+//! from the primary; when it cannot deliver and the backup still holds
+//! messages, the mirror fails over and reads from the backup instead.
+
+use crate::{DequeuePort, EnqueuePort, Message, QueueError, QueueingPort};
+
+/// A change between reading the primary and reading the backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverEvent {
+    /// The primary failed and reads now come from the backup.
+    Failover,
+    /// The primary delivered again.
+    Recovered,
+}
+
+/// Called on every `FailoverEvent`, see `MirroredPort::set_failover_hook`.
+pub type FailoverHook = fn(FailoverEvent);
+
+pub struct MirroredPort {
+    pub primary: QueueingPort,
+    pub backup: QueueingPort,
+    failover: bool,
+    hook: Option<FailoverHook>,
+}
+
+impl MirroredPort {
+    pub fn new(primary: QueueingPort, backup: QueueingPort) -> MirroredPort {
+        MirroredPort {
+            primary,
+            backup,
+            failover: false,
+            hook: None,
+        }
+    }
+
+    pub fn set_failover_hook(&mut self, hook: FailoverHook) {
+        self.hook = Some(hook);
+    }
+
+    /// Whether the last dequeue was served by the backup.
+    pub fn is_in_failover(&self) -> bool {
+        self.failover
+    }
+
+    /// Writes `message` to both segments. Succeeds if either accepted it;
+    /// if both refuse, returns the primary's error.
+    pub fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        let backup = self.backup.enqueue(Message(message.0));
+        match self.primary.enqueue(message) {
+            Err(error) if backup.is_err() => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Dequeues from the primary and drops the backup's copy of what it
+    /// consumed. If the primary fails while the backup is not empty, the
+    /// message comes from the backup instead.
+    pub fn dequeue(&mut self) -> Result<Message, QueueError> {
+        match self.primary.try_dequeue() {
+            Ok(message) => {
+                // Both got the same messages, so the backup's copies of
+                // everything the primary consumed (or filtered out) are at
+                // its front. A faster backup means it missed a write.
+                while self.backup.len() > self.primary.len() {
+                    let _ = self.backup.dequeue_unfiltered();
+                }
+                self.switch(false);
+                Ok(message)
+            }
+            Err(error) if self.backup.is_empty() => Err(error),
+            // Anything the failed primary discarded is still in the backup.
+            Err(_) => {
+                self.switch(true);
+                self.backup.try_dequeue()
+            }
+        }
+    }
+
+    fn switch(&mut self, failover: bool) {
+        if self.failover == failover {
+            return;
+        }
+        self.failover = failover;
+        if let Some(hook) = self.hook {
+            hook(if failover { FailoverEvent::Failover } else { FailoverEvent::Recovered });
+        }
+    }
+}
+
+impl EnqueuePort for MirroredPort {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        MirroredPort::enqueue(self, message)
+    }
+}
+
+impl DequeuePort for MirroredPort {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        MirroredPort::dequeue(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeliveryOrder, PortConfig, SIZE};
+    use core::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    static EVENTS: Mutex<Vec<FailoverEvent>> = Mutex::new(Vec::new());
+
+    fn record(event: FailoverEvent) {
+        EVENTS.lock().unwrap().push(event);
+    }
+
+    #[test]
+    fn writes_reach_both_and_reads_stay_in_step() {
+        let mut mirror = MirroredPort::new(QueueingPort::new(), QueueingPort::new());
+        for i in 0..3 {
+            mirror.enqueue(Message([i; SIZE])).unwrap();
+        }
+        assert_eq!((mirror.primary.len(), mirror.backup.len()), (3, 3));
+        assert_eq!(mirror.dequeue().unwrap().0, [0; SIZE]);
+        assert_eq!((mirror.primary.len(), mirror.backup.len()), (2, 2));
+        assert!(!mirror.is_in_failover());
+    }
+
+    #[test]
+    fn reads_fail_over_to_backup() {
+        // A strict FIFO primary rejects the message written out of sequence.
+        let primary = QueueingPort::with_config(&PortConfig::new().delivery_order(DeliveryOrder::StrictFifo));
+        let mut mirror = MirroredPort::new(primary, QueueingPort::new());
+        mirror.set_failover_hook(record);
+        for i in 0..3 {
+            mirror.enqueue(Message([i; SIZE])).unwrap();
+        }
+        // Make the primary see sequence 0 where it expects 5: it discards the
+        // message and fails.
+        let dequeued = &mirror.primary.segment().header.dequeued;
+        dequeued.store(mirror.primary.byte_order(), 5, Ordering::Relaxed);
+        assert_eq!(mirror.dequeue().unwrap().0, [0; SIZE]);
+        assert!(mirror.is_in_failover());
+        assert_eq!(*EVENTS.lock().unwrap(), [FailoverEvent::Failover]);
+
+        // Back in sequence, the primary delivers again.
+        let dequeued = &mirror.primary.segment().header.dequeued;
+        dequeued.store(mirror.primary.byte_order(), 1, Ordering::Relaxed);
+        assert_eq!(mirror.dequeue().unwrap().0, [1; SIZE]);
+        assert!(!mirror.is_in_failover());
+        assert_eq!(*EVENTS.lock().unwrap(), [FailoverEvent::Failover, FailoverEvent::Recovered]);
+        assert_eq!((mirror.primary.len(), mirror.backup.len()), (1, 1));
+    }
+}
@@ -0,0 +1,540 @@
+//! Ports whose concurrency mode is part of their type.
+//!
+//! `Port<Spsc>`, `Port<Mpsc>` and `Port<Broadcast>` share the `QueueingPort`
+//! core and only differ in the handles they hand out:
+//!
+//! - `Port<Spsc>::split` gives the one producer and the one consumer,
+//! - `Port<Mpsc>::add_producer` gives any number of producers, and the port
+//!   itself is the consumer,
+//! - `Port<Broadcast>::subscribe` gives readers that each see every message
+//!   published after they subscribed, or with `subscribe_types` only the
+//!   message types they asked for.
+//!
+//! The mode is recorded in the segment header, so a named port created as
+//! one mode cannot be opened as another. Handles are not `Send`, so every
+//! producer of an `Mpsc` port runs on one thread; several producer
+//! *processes* on one segment are not supported.
+//!
+//! Handles a mode does not offer do not exist for it:
+//!
+//! ```compile_fail
+//! # use ring_buffer::{Mpsc, Port};
+//! let mut port = Port::<Mpsc>::new();
+//! let _ = port.split();
+//! ```
+//!
+//! ```compile_fail
+//! # use ring_buffer::{Port, Spsc};
+//! let port = Port::<Spsc>::new();
+//! let _ = port.add_producer();
+//! ```
+//!
+//! ```compile_fail
+//! # use ring_buffer::{Broadcast, Port};
+//! let port = Port::<Broadcast>::new();
+//! let _ = port.add_producer();
+//! ```
+//!
+//! ```compile_fail
+//! # use ring_buffer::{Mpsc, Port};
+//! let port = Port::<Mpsc>::new();
+//! let _ = port.subscribe();
+//! ```
+//!
+//! ```compile_fail
+//! # use ring_buffer::{Mpsc, Port};
+//! let port = Port::<Mpsc>::new();
+//! let producer = port.add_producer();
+//! std::thread::scope(|s| {
+//!     s.spawn(move || drop(producer));
+//! });
+//! ```
+
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::Ordering;
+
+use crate::{
+    eos, signal, type_of, Message, PortConfig, QueueError, QueueingPort, TypeFilter, MSGS, TYPE_FILTER_CAPACITY,
+};
+
+/// The mode byte stored in the segment header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum ConcurrencyMode {
+    /// One producer, one consumer; what an untyped `QueueingPort` is.
+    #[default]
+    Spsc = 0,
+    Mpsc = 1,
+    Broadcast = 2,
+}
+
+impl ConcurrencyMode {
+    pub(crate) fn from_u8(value: u8) -> Option<ConcurrencyMode> {
+        match value {
+            0 => Some(ConcurrencyMode::Spsc),
+            1 => Some(ConcurrencyMode::Mpsc),
+            2 => Some(ConcurrencyMode::Broadcast),
+            _ => None,
+        }
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A marker type selecting a `ConcurrencyMode` at compile time.
+pub trait Mode: sealed::Sealed {
+    const MODE: ConcurrencyMode;
+}
+
+/// Marker for `Port<Spsc>`.
+pub enum Spsc {}
+/// Marker for `Port<Mpsc>`.
+pub enum Mpsc {}
+/// Marker for `Port<Broadcast>`.
+pub enum Broadcast {}
+
+impl sealed::Sealed for Spsc {}
+impl sealed::Sealed for Mpsc {}
+impl sealed::Sealed for Broadcast {}
+
+impl Mode for Spsc {
+    const MODE: ConcurrencyMode = ConcurrencyMode::Spsc;
+}
+
+impl Mode for Mpsc {
+    const MODE: ConcurrencyMode = ConcurrencyMode::Mpsc;
+}
+
+impl Mode for Broadcast {
+    const MODE: ConcurrencyMode = ConcurrencyMode::Broadcast;
+}
+
+/// A port in mode `M`; see the module documentation.
+pub struct Port<M: Mode> {
+    core: QueueingPort,
+    _mode: PhantomData<M>,
+}
+
+impl<M: Mode> Port<M> {
+    pub fn new() -> Port<M> {
+        Port::with_config(&PortConfig::new())
+    }
+
+    pub fn with_config(config: &PortConfig) -> Port<M> {
+        Port::from_core(QueueingPort::with_config(&config.clone().mode(M::MODE)))
+    }
+
+    fn from_core(core: QueueingPort) -> Port<M> {
+        Port { core, _mode: PhantomData }
+    }
+
+    /// Like `QueueingPort::create_with_config`, recording mode `M`.
+    #[cfg(feature = "shmem")]
+    pub fn create(name: &str, config: &PortConfig) -> Result<Port<M>, crate::PortError> {
+        QueueingPort::create_with_config(name, &config.clone().mode(M::MODE)).map(Port::from_core)
+    }
+
+    /// Like `QueueingPort::open`, failing with `PortError::ModeMismatch`
+    /// if the port was created in another mode.
+    #[cfg(feature = "shmem")]
+    pub fn open(name: &str) -> Result<Port<M>, crate::PortError> {
+        QueueingPort::open_typed(name, M::MODE).map(Port::from_core)
+    }
+
+    /// Length, stats and the other read-only views of the core port.
+    pub fn inner(&self) -> &QueueingPort {
+        &self.core
+    }
+
+    /// A second handle on the core's segment, living as long as the borrow.
+    fn handle(&self) -> QueueingPort {
+        // The segment outlives the handle through the borrow of `self`, and
+        // each mode's API keeps to one consumer.
+        unsafe { QueueingPort::attach(NonNull::from(self.core.segment())) }
+    }
+}
+
+impl<M: Mode> Default for Port<M> {
+    fn default() -> Self {
+        Port::new()
+    }
+}
+
+impl Port<Spsc> {
+    /// The port's producer and consumer.
+    pub fn split(&mut self) -> (Producer<'_>, Consumer<'_>) {
+        (
+            Producer { port: self.handle(), _borrow: PhantomData },
+            Consumer { port: self.handle(), _borrow: PhantomData },
+        )
+    }
+}
+
+impl Port<Mpsc> {
+    pub fn add_producer(&self) -> Producer<'_> {
+        Producer { port: self.handle(), _borrow: PhantomData }
+    }
+
+    /// Dequeues as `QueueingPort::dequeue`, alongside the producers.
+    pub fn dequeue(&self) -> Result<Message, QueueError> {
+        let core = &self.core;
+        core.consume_filtered(|msg_type| core.type_filter.allows(msg_type), |slot| Message(*slot))
+    }
+}
+
+impl Port<Broadcast> {
+    /// A reader of every message published from now on.
+    pub fn subscribe(&self) -> Subscriber<'_> {
+        Subscriber {
+            port: &self.core,
+            next: self.core.segment().header.enqueued.load(self.core.byte_order(), Ordering::Relaxed),
+            types: TypeFilter::new(),
+            skipped: 0,
+        }
+    }
+
+    /// Publishes `message`, dropping the oldest unpinned one if the port
+    /// is full; subscribers that had not read it yet see
+    /// `QueueError::Lagged`. Fails with `QueueError::AllPinned` if every
+    /// queued message is pinned, see the `pin` module.
+    pub fn publish(&self, message: Message) -> Result<(), QueueError> {
+        self.make_room()?;
+        // SAFETY: as in `QueueingPort::enqueue`.
+        self.core.produce_back(|slot| unsafe { ptr::write(slot, message.0) })
+    }
+
+    /// Drops the oldest unpinned message if the port is full.
+    pub(crate) fn make_room(&self) -> Result<(), QueueError> {
+        if self.core.len() >= MSGS {
+            // Other failures fail the enqueue that follows, too.
+            if let Err(error @ QueueError::AllPinned) = self.core.evict_unpinned() {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A producer handle of a `Port<Spsc>` or `Port<Mpsc>`.
+pub struct Producer<'a> {
+    port: QueueingPort,
+    // The pointer keeps the handle on its thread: the producers of an
+    // `Mpsc` port must not run concurrently.
+    _borrow: PhantomData<&'a *const ()>,
+}
+
+impl Producer<'_> {
+    pub fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        self.port.enqueue(message)
+    }
+}
+
+impl Deref for Producer<'_> {
+    type Target = QueueingPort;
+
+    fn deref(&self) -> &QueueingPort {
+        &self.port
+    }
+}
+
+/// The consumer handle of a `Port<Spsc>`.
+pub struct Consumer<'a> {
+    port: QueueingPort,
+    _borrow: PhantomData<&'a *const ()>,
+}
+
+impl Consumer<'_> {
+    pub fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.port.try_dequeue()
+    }
+}
+
+impl Deref for Consumer<'_> {
+    type Target = QueueingPort;
+
+    fn deref(&self) -> &QueueingPort {
+        &self.port
+    }
+}
+
+/// A reader of a `Port<Broadcast>` with its own position in the stream.
+///
+/// The position and the subscriber's type mask are the handle's own: the
+/// publisher drops the oldest message when full whatever the subscribers
+/// have read, so neither holds slots back, and changing the mask between
+/// two `recv` calls affects nothing but which messages the next one
+/// returns.
+pub struct Subscriber<'a> {
+    port: &'a QueueingPort,
+    /// Sequence number of the next message to read.
+    next: u32,
+    /// Which message types `recv` returns, see `subscribe_types`.
+    types: TypeFilter,
+    /// Messages `recv` passed over for their type.
+    skipped: u32,
+}
+
+impl Subscriber<'_> {
+    /// Copies the next message without consuming it for other subscribers.
+    /// If it was dropped to make room, fails with `QueueError::Lagged` and
+    /// continues at the oldest message still queued.
+    ///
+    /// Messages whose type the subscriber did not ask for are passed over
+    /// on the way, reading only their type id. Once the end-of-stream
+    /// record of a closed port has been read, fails with
+    /// `QueueError::Closed`.
+    pub fn recv(&mut self) -> Result<Message, QueueError> {
+        // Closing follows the last message, so loaded first.
+        let closed = self.port.is_closed();
+        while let Some(slot) = self.port.shared_slot(self.next) {
+            self.next = self.next.wrapping_add(1);
+            // Occupied slots belong to the reader, and the subscriber is it.
+            let slot = unsafe { &*slot };
+            if self.types.allows(type_of(slot)) || eos::is_end_of_stream(slot) || signal::is_signal(slot) {
+                return Ok(Message(*slot));
+            }
+            self.skipped = self.skipped.wrapping_add(1);
+        }
+        let oldest = self.port.segment().header.dequeued.load(self.port.byte_order(), Ordering::Relaxed);
+        let missed = oldest.wrapping_sub(self.next);
+        // Sequence numbers wrap, so "behind" means less than half the range.
+        if missed != 0 && missed < u32::MAX / 2 {
+            self.next = oldest;
+            return Err(QueueError::Lagged { missed });
+        }
+        Err(if closed { QueueError::Closed } else { QueueError::EmptyBuffer })
+    }
+
+    /// Restricts `recv` to messages whose type is in `types`, from the next
+    /// call on; an empty slice subscribes to every type again. Fails with
+    /// `FilterTooLarge`, leaving the mask as it was, if `types` has more
+    /// than `TYPE_FILTER_CAPACITY` entries.
+    pub fn subscribe_types(&mut self, types: &[u16]) -> Result<(), QueueError> {
+        if types.len() > TYPE_FILTER_CAPACITY {
+            return Err(QueueError::FilterTooLarge);
+        }
+        self.types = TypeFilter::from_slice(types);
+        Ok(())
+    }
+
+    /// How many messages `recv` has passed over for their type.
+    pub fn skipped(&self) -> u32 {
+        self.skipped
+    }
+}
+
+impl QueueingPort {
+    /// The mode the port was created in.
+    pub fn concurrency_mode(&self) -> Option<ConcurrencyMode> {
+        ConcurrencyMode::from_u8(self.segment().header.mode.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+
+    #[test]
+    fn spsc_split_pair() {
+        let mut port = Port::<Spsc>::new();
+        let (mut producer, mut consumer) = port.split();
+        producer.enqueue(Message([1; SIZE])).unwrap();
+        assert_eq!(consumer.len(), 1);
+        assert_eq!(consumer.dequeue().unwrap().0, [1; SIZE]);
+        assert_eq!(port.inner().concurrency_mode(), Some(ConcurrencyMode::Spsc));
+    }
+
+    #[test]
+    fn mpsc_producers_share_the_port() {
+        let port = Port::<Mpsc>::new();
+        let mut first = port.add_producer();
+        let mut second = port.add_producer();
+        first.enqueue(Message([1; SIZE])).unwrap();
+        second.enqueue(Message([2; SIZE])).unwrap();
+        first.enqueue(Message([3; SIZE])).unwrap();
+        for tag in [1, 2, 3] {
+            assert_eq!(port.dequeue().unwrap().0, [tag; SIZE]);
+        }
+        assert_eq!(port.inner().concurrency_mode(), Some(ConcurrencyMode::Mpsc));
+    }
+
+    #[test]
+    fn broadcast_subscribers_see_every_message() {
+        let port = Port::<Broadcast>::new();
+        let mut early = port.subscribe();
+        port.publish(Message([1; SIZE])).unwrap();
+        let mut late = port.subscribe();
+        port.publish(Message([2; SIZE])).unwrap();
+
+        assert_eq!(early.recv().unwrap().0, [1; SIZE]);
+        assert_eq!(early.recv().unwrap().0, [2; SIZE]);
+        assert!(matches!(early.recv(), Err(QueueError::EmptyBuffer)));
+        assert_eq!(late.recv().unwrap().0, [2; SIZE]);
+
+        // Overrun the slow subscriber.
+        for tag in 0..MSGS as u8 + 2 {
+            port.publish(Message([tag; SIZE])).unwrap();
+        }
+        assert!(matches!(late.recv(), Err(QueueError::Lagged { missed: 2 })));
+        assert_eq!(late.recv().unwrap().0, [2; SIZE]);
+    }
+
+    /// Publishes every `writer_period` ms of 500 and has a subscriber read
+    /// one message every `reader_period` ms, and the rest at the end, on
+    /// one thread, as handles are not `Send`; time is the loop counter. Returns the messages published, read and reported lost.
+    fn broadcast_at_speeds(writer_period: u32, reader_period: u32) -> (u32, u32, u32) {
+        let port = Port::<Broadcast>::new();
+        let mut subscriber = port.subscribe();
+        let (mut published, mut received, mut lagged) = (0u32, 0, 0);
+        let mut next = 0u32;
+        for ms in 0..=500 {
+            if ms % writer_period == 0 && ms < 500 {
+                let mut bytes = [0; SIZE];
+                bytes[..4].copy_from_slice(&published.to_le_bytes());
+                // Dropping the oldest, publishing never fails.
+                port.publish(Message(bytes)).unwrap();
+                published += 1;
+            }
+            // One message a turn, and all that is left at the end.
+            let mut turns = usize::from(ms % reader_period == 0);
+            if ms == 500 {
+                turns = usize::MAX;
+            }
+            while turns > 0 {
+                match subscriber.recv() {
+                    Ok(message) => {
+                        assert_eq!(message.0[..4], next.to_le_bytes(), "in order past the dropped ones");
+                        (next, received, turns) = (next + 1, received + 1, turns - 1);
+                    }
+                    Err(QueueError::Lagged { missed }) => (next, lagged) = (next + missed, lagged + missed),
+                    Err(err) => {
+                        assert!(matches!(err, QueueError::EmptyBuffer), "{:?}", err);
+                        break;
+                    }
+                }
+            }
+        }
+        (published, received, lagged)
+    }
+
+    #[test]
+    fn unequal_speeds_broadcast_drops_the_oldest() {
+        let (published, received, lagged) = broadcast_at_speeds(10, 50);
+        assert_eq!(published, 50);
+        assert!(lagged > 0, "the slow subscriber was overrun");
+        assert_eq!(received + lagged, published, "each message read or reported lost");
+
+        let (published, received, lagged) = broadcast_at_speeds(50, 10);
+        assert_eq!((published, received, lagged), (10, 10, 0));
+    }
+
+    fn typed(msg_type: u16, tag: u8) -> Message {
+        let mut bytes = [tag; SIZE];
+        bytes[4..6].copy_from_slice(&msg_type.to_le_bytes());
+        Message(bytes)
+    }
+
+    fn recv_all(subscriber: &mut Subscriber<'_>) -> Vec<(u16, u8)> {
+        core::iter::from_fn(|| subscriber.recv().ok()).map(|m| (m.msg_type(), m.0[0])).collect()
+    }
+
+    #[test]
+    fn subscribers_see_only_their_types() {
+        let port = Port::<Broadcast>::new();
+        let mut subscribers = [port.subscribe(), port.subscribe(), port.subscribe()];
+        for (subscriber, msg_type) in subscribers.iter_mut().zip(1..) {
+            subscriber.subscribe_types(&[msg_type]).unwrap();
+        }
+        for (tag, msg_type) in [1, 2, 3, 1, 1, 3, 2, 1, 3].into_iter().enumerate() {
+            port.publish(typed(msg_type, tag as u8)).unwrap();
+        }
+        let [ones, twos, threes] = &mut subscribers;
+        assert_eq!(recv_all(ones), [(1, 0), (1, 3), (1, 4), (1, 7)]);
+        assert_eq!(recv_all(twos), [(2, 1), (2, 6)]);
+        assert_eq!(recv_all(threes), [(3, 2), (3, 5), (3, 8)]);
+        assert_eq!(subscribers.map(|s| s.skipped()), [5, 7, 6]);
+    }
+
+    #[test]
+    fn skipped_messages_are_reclaimed_without_lagging() {
+        let port = Port::<Broadcast>::new();
+        let mut subscriber = port.subscribe();
+        subscriber.subscribe_types(&[1]).unwrap();
+        for tag in 0..MSGS as u8 {
+            port.publish(typed(2, tag)).unwrap();
+        }
+        assert!(matches!(subscriber.recv(), Err(QueueError::EmptyBuffer)));
+        assert_eq!(subscriber.skipped(), MSGS as u32);
+
+        // The subscriber's cursor is past every queued message, so the
+        // publisher reclaiming their slots costs it nothing.
+        for tag in 0..3 {
+            port.publish(typed(1, tag)).unwrap();
+        }
+        assert_eq!(recv_all(&mut subscriber), [(1, 0), (1, 1), (1, 2)]);
+
+        // Changing the mask between messages.
+        port.publish(typed(2, 9)).unwrap();
+        subscriber.subscribe_types(&[]).unwrap();
+        port.publish(typed(2, 10)).unwrap();
+        assert_eq!(recv_all(&mut subscriber), [(2, 9), (2, 10)]);
+        assert!(matches!(subscriber.subscribe_types(&[0; TYPE_FILTER_CAPACITY + 1]), Err(QueueError::FilterTooLarge)));
+    }
+
+    #[cfg(feature = "shmem")]
+    #[test]
+    fn open_rejects_other_mode() {
+        use crate::PortError;
+        use std::time::{Duration, Instant};
+
+        let name = format!("/qp_mode_{}", std::process::id());
+        let creator = std::thread::spawn({
+            let name = name.clone();
+            move || {
+                let config = PortConfig::new().handshake_timeout(Duration::from_millis(300));
+                Port::<Mpsc>::create(&name, &config).map(|_| ())
+            }
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let result = loop {
+            match Port::<Spsc>::open(&name) {
+                Err(PortError::Open(_)) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(1)),
+                result => break result.map(|_| ()),
+            }
+        };
+        assert!(matches!(
+            result,
+            Err(PortError::ModeMismatch { expected: ConcurrencyMode::Spsc, found: Some(ConcurrencyMode::Mpsc) })
+        ));
+        assert!(matches!(creator.join().unwrap(), Err(PortError::HandshakeTimeout)));
+    }
+
+    #[cfg(feature = "shmem")]
+    #[test]
+    fn open_accepts_same_mode() {
+        use std::time::{Duration, Instant};
+
+        let name = format!("/qp_mode_same_{}", std::process::id());
+        let opener = std::thread::spawn({
+            let name = name.clone();
+            move || {
+                let deadline = Instant::now() + Duration::from_secs(5);
+                loop {
+                    match Port::<Broadcast>::open(&name) {
+                        Err(crate::PortError::Open(_)) if Instant::now() < deadline => {
+                            std::thread::sleep(Duration::from_millis(1))
+                        }
+                        result => break result.map(|port| port.inner().concurrency_mode()),
+                    }
+                }
+            }
+        });
+        let _port = Port::<Broadcast>::create(&name, &PortConfig::new()).unwrap();
+        assert_eq!(opener.join().unwrap().unwrap(), Some(ConcurrencyMode::Broadcast));
+    }
+}
@@ -0,0 +1,239 @@
+//! A bounded queue any number of threads push to and pop from.
+//!
+//! This is Dmitry Vyukov's bounded MPMC queue. Every slot has a sequence
+//! number next to its message, and producers and consumers each claim
+//! positions from their own counter with a compare-and-swap:
+//!
+//! - slot `pos % MSG_COUNT` is free for the producer of position `pos`
+//!   when its sequence number is `pos`; the producer writes the message
+//!   and stores `pos + 1`,
+//! - it is full for the consumer of position `pos` when its sequence
+//!   number is `pos + 1`; the consumer copies the message out and stores
+//!   `pos + MSG_COUNT`, freeing the slot for the next lap.
+//!
+//! A sequence number behind the claiming side's position means the queue
+//! is full, or empty, and the call fails rather than waiting. The stores
+//! to a sequence number release the message written or read before them,
+//! and the loads acquire it, so the position counters themselves need no
+//! ordering: a claim only decides which thread goes on to the slot, never
+//! what the slot holds.
+//!
+//! Unlike `Port<Mpsc>` the queue is a plain in-process object, shared by
+//! reference between threads, with no segment layout or handshake.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{DequeuePort, EnqueuePort, Message, Pod, QueueError, SIZE};
+
+struct Slot<const MSG_SIZE: usize> {
+    sequence: AtomicUsize,
+    message: UnsafeCell<[u8; MSG_SIZE]>,
+}
+
+/// A lock-free queue of up to `MSG_COUNT` messages of `MSG_SIZE` bytes, see
+/// the module documentation.
+pub struct MpmcQueue<const MSG_SIZE: usize, const MSG_COUNT: usize> {
+    /// Position the next push claims.
+    enqueue_pos: AtomicUsize,
+    /// Position the next pop claims.
+    dequeue_pos: AtomicUsize,
+    slots: [Slot<MSG_SIZE>; MSG_COUNT],
+}
+
+// A slot's message is only touched by the thread whose claim matched its
+// sequence number, until it stores the next one.
+unsafe impl<const MSG_SIZE: usize, const MSG_COUNT: usize> Sync for MpmcQueue<MSG_SIZE, MSG_COUNT> {}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize> MpmcQueue<MSG_SIZE, MSG_COUNT> {
+    pub const fn new() -> Self {
+        const { assert!(MSG_COUNT > 0, "an MpmcQueue needs a slot") };
+        let mut slots =
+            [const { Slot { sequence: AtomicUsize::new(0), message: UnsafeCell::new([0; MSG_SIZE]) } }; MSG_COUNT];
+        let mut index = 0;
+        while index < MSG_COUNT {
+            slots[index].sequence = AtomicUsize::new(index);
+            index += 1;
+        }
+        MpmcQueue { enqueue_pos: AtomicUsize::new(0), dequeue_pos: AtomicUsize::new(0), slots }
+    }
+
+    /// Queues `message`, failing with `QueueError::FullBuffer` if every
+    /// slot is taken.
+    pub fn push(&self, message: [u8; MSG_SIZE]) -> Result<(), QueueError> {
+        let mut pos = self.enqueue_pos.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = &self.slots[pos % MSG_COUNT];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(pos as isize) {
+                0 => match self.enqueue_pos.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => break slot,
+                    Err(current) => pos = current,
+                },
+                // The consumer of the last lap has not freed it yet.
+                behind if behind < 0 => return Err(QueueError::FullBuffer),
+                // Another producer claimed it since `pos` was loaded.
+                _ => pos = self.enqueue_pos.load(Ordering::Relaxed),
+            }
+        };
+        // The claim made this thread the slot's only user until the store.
+        unsafe { *slot.message.get() = message };
+        slot.sequence.store(pos + 1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Takes the oldest message, failing with `QueueError::EmptyBuffer` if
+    /// there is none.
+    pub fn pop(&self) -> Result<[u8; MSG_SIZE], QueueError> {
+        let mut pos = self.dequeue_pos.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = &self.slots[pos % MSG_COUNT];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(pos.wrapping_add(1) as isize) {
+                0 => match self.dequeue_pos.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => break slot,
+                    Err(current) => pos = current,
+                },
+                // No producer has filled it yet.
+                behind if behind < 0 => return Err(QueueError::EmptyBuffer),
+                // Another consumer took it since `pos` was loaded.
+                _ => pos = self.dequeue_pos.load(Ordering::Relaxed),
+            }
+        };
+        let message = unsafe { *slot.message.get() };
+        slot.sequence.store(pos.wrapping_add(MSG_COUNT), Ordering::Release);
+        Ok(message)
+    }
+
+    /// Queues the bytes of `value`, which must be `MSG_SIZE` long, failing
+    /// as `push`. This is what `queue_channel!`'s `send_` functions call.
+    pub fn push_value<T: Pod>(&self, value: T) -> Result<(), QueueError> {
+        const { assert!(size_of::<T>() == MSG_SIZE, "the value does not fill a message") };
+        // A `Pod` has no padding, so all its bytes are initialised.
+        self.push(unsafe { core::mem::transmute_copy(&value) })
+    }
+
+    /// Takes the oldest message as a `T`, failing as `pop`.
+    pub fn pop_value<T: Pod>(&self) -> Result<T, QueueError> {
+        const { assert!(size_of::<T>() == MSG_SIZE, "the value does not fill a message") };
+        // Any bytes are a valid `Pod`, and `transmute_copy` reads unaligned.
+        self.pop().map(|message| unsafe { core::mem::transmute_copy(&message) })
+    }
+
+    /// Messages queued, as of some moment during the call.
+    pub fn len(&self) -> usize {
+        let dequeued = self.dequeue_pos.load(Ordering::Relaxed);
+        let enqueued = self.enqueue_pos.load(Ordering::Relaxed);
+        enqueued.wrapping_sub(dequeued).min(MSG_COUNT)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        MSG_COUNT
+    }
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize> Default for MpmcQueue<MSG_SIZE, MSG_COUNT> {
+    fn default() -> Self {
+        MpmcQueue::new()
+    }
+}
+
+impl<const MSG_COUNT: usize> EnqueuePort for MpmcQueue<SIZE, MSG_COUNT> {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        self.push(message.0)
+    }
+}
+
+impl<const MSG_COUNT: usize> DequeuePort for MpmcQueue<SIZE, MSG_COUNT> {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.pop().map(Message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn fills_empties_and_wraps() {
+        let queue = MpmcQueue::<4, 3>::new();
+        assert!(matches!(queue.pop(), Err(QueueError::EmptyBuffer)));
+        for lap in 0..4u8 {
+            for i in 0..3 {
+                queue.push([lap * 3 + i; 4]).unwrap();
+            }
+            assert!(matches!(queue.push([0; 4]), Err(QueueError::FullBuffer)));
+            assert_eq!(queue.len(), 3);
+            for i in 0..3 {
+                assert_eq!(queue.pop().unwrap(), [lap * 3 + i; 4]);
+            }
+            assert!(queue.is_empty());
+        }
+    }
+
+    #[test]
+    fn two_producers_two_consumers() {
+        const PER_PRODUCER: u32 = 50_000;
+        let queue = MpmcQueue::<8, 16>::new();
+        let popped: Vec<Vec<[u8; 8]>> = thread::scope(|scope| {
+            for producer in 0..2u32 {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for n in 0..PER_PRODUCER {
+                        let mut message = [0; 8];
+                        message[..4].copy_from_slice(&producer.to_le_bytes());
+                        message[4..].copy_from_slice(&n.to_le_bytes());
+                        while queue.push(message).is_err() {
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+            let consumers: Vec<_> = (0..2)
+                .map(|_| {
+                    let queue = &queue;
+                    scope.spawn(move || {
+                        let mut popped = Vec::new();
+                        while popped.len() < PER_PRODUCER as usize {
+                            match queue.pop() {
+                                Ok(message) => popped.push(message),
+                                Err(_) => thread::yield_now(),
+                            }
+                        }
+                        popped
+                    })
+                })
+                .collect();
+            consumers.into_iter().map(|consumer| consumer.join().unwrap()).collect()
+        });
+
+        // Every message came out once, and each consumer saw each
+        // producer's messages in the order they were pushed.
+        let mut seen = vec![vec![false; PER_PRODUCER as usize]; 2];
+        for messages in &popped {
+            let mut last = [None; 2];
+            for message in messages {
+                let producer = u32::from_le_bytes(message[..4].try_into().unwrap()) as usize;
+                let n = u32::from_le_bytes(message[4..].try_into().unwrap());
+                assert!(last[producer] < Some(n), "out of order");
+                last[producer] = Some(n);
+                assert!(!seen[producer][n as usize], "popped twice");
+                seen[producer][n as usize] = true;
+            }
+        }
+        assert!(seen.iter().flatten().all(|&seen| seen));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn works_as_a_port() {
+        let mut queue = MpmcQueue::<SIZE, 2>::default();
+        queue.enqueue(Message([5; SIZE])).unwrap();
+        assert_eq!(queue.dequeue().unwrap().0, [5; SIZE]);
+    }
+}
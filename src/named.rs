@@ -0,0 +1,653 @@
+//! Ports backed by named shared-memory segments.
+//!
+//! The writer creates the segment and the reader opens it by name. Before
+//! either side gets a usable port they go through a handshake on the
+//! header's `state` byte:
+//!
+//! ```text
+//! create():  UNINIT -> INITIALIZING -> WRITER_READY ........ READER_READY -> OPEN
+//! open():                               WRITER_READY -> READER_READY ....... OPEN
+//! ```
+//!
+//! so a reader can never observe a segment the writer has not finished
+//! setting up. Only the creator whose compare-and-swap takes the state from
+//! `UNINIT` to `INITIALIZING` writes the header; any other that gets that
+//! far fails with `PortError::AlreadyExists`. An opener that finds the state
+//! stuck at `INITIALIZING`, the creator having died half way, fails with
+//! `PortError::StaleInit` so a supervisor can recover the segment.
+//!
+//! The name is unlinked once no handle uses the segment any more, by the
+//! creator or the last handle out as the `TeardownPolicy` says; see the
+//! `detach` module. On Windows the mapping is a kernel object that lives as
+//! long as any handle to it whatever the policy. Segments are always
+//! created readable and writable by the current user only; there are no
+//! permission options to diverge.
+
+use core::sync::atomic::{fence, AtomicU8, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+use crate::open_error::{create_named, open_named};
+use crate::open_limit::OpenPortCharge;
+use crate::quota::QuotaCharge;
+use crate::retry;
+use crate::{
+    ConcurrencyMode, Memory, PeerRole, PortConfig, PortError, QueueingPort, Segment, SegmentGeometry, WireFeatures,
+    CLOSED, INITIALIZING, OPEN, READER_READY, UNINIT, WRITER_READY,
+};
+
+/// How long `create()` waits for a reader and `open()` waits for the writer.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the header may stay `INITIALIZING` before `open()` takes the
+/// creator for dead, and how long a losing `create()` waits for the winner.
+pub const INIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+impl QueueingPort {
+    /// Creates the named segment `name` and waits for a reader to `open()` it.
+    pub fn create(name: &str) -> Result<QueueingPort, PortError> {
+        QueueingPort::create_with_timeout(name, HANDSHAKE_TIMEOUT)
+    }
+
+    pub fn create_with_timeout(name: &str, timeout: Duration) -> Result<QueueingPort, PortError> {
+        QueueingPort::create_with_config(name, &PortConfig::new().handshake_timeout(timeout))
+    }
+
+    /// Like `create()`, with the metadata and handshake timeout taken from
+    /// `config`.
+    ///
+    /// Fails with `PortError::Open` if the segment cannot be created, of
+    /// kind `OpenFailure::NameInUse` if the name is taken; with
+    /// `PortError::AlreadyExists` if another creator set the segment up
+    /// first; with `PortError::QuotaExceeded` if the segment does not fit
+    /// in the process's `ShmemQuota`; and with `PortError::TooManyOpenPorts`
+    /// if the process has as many named ports open as `OpenPortLimit`
+    /// allows.
+    pub fn create_with_config(name: &str, config: &PortConfig) -> Result<QueueingPort, PortError> {
+        let open = OpenPortCharge::reserve()?;
+        let quota = QuotaCharge::reserve(core::mem::size_of::<Segment>())?;
+        let shmem = retry::with_retries(&config.retry, || create_named(name, SegmentGeometry::PORT))?;
+        #[allow(unused_mut)]
+        let mut port = QueueingPort::from_memory(Memory::created(shmem, quota, open));
+        #[cfg(feature = "tracing")]
+        port.set_name(name);
+        port.initialize(config)
+    }
+
+    /// The creator's side of the handshake, on a mapped segment.
+    pub(crate) fn initialize(mut self, config: &PortConfig) -> Result<QueueingPort, PortError> {
+        if let Some(policy) = config.numa_policy {
+            // Before the header is written, so no page is faulted in yet.
+            self.bind_numa(policy)?;
+        }
+        let state = &self.segment().header.state;
+        if state
+            .compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Acquire)
+            .is_err()
+        {
+            // Another creator won. Once it is done the segment can be opened.
+            let _ = wait_for(state, INIT_TIMEOUT, |current| current != INITIALIZING);
+            return Err(PortError::AlreadyExists);
+        }
+
+        config.apply(&mut self);
+        self.take_attach_slot()?;
+        self.stamp_generation();
+        self.announce(PeerRole::Sender);
+        // Publishes the configured header fields along with the state.
+        let state = &self.segment().header.state;
+        state.store(WRITER_READY, Ordering::Release);
+        wait_for(state, config.handshake_timeout, |current| {
+            current == READER_READY
+                && state
+                    .compare_exchange(READER_READY, OPEN, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+        })?;
+        Ok(self)
+    }
+
+    /// Opens the named segment `name` created by a writer's `create()`.
+    pub fn open(name: &str) -> Result<QueueingPort, PortError> {
+        QueueingPort::open_with_timeout(name, HANDSHAKE_TIMEOUT)
+    }
+
+    /// Like `open()` with a custom handshake timeout.
+    ///
+    /// Fails with `PortError::IncompatibleLayout` if the creator declared
+    /// required wire features this build does not know.
+    pub fn open_with_timeout(name: &str, timeout: Duration) -> Result<QueueingPort, PortError> {
+        QueueingPort::open_supporting(name, timeout, WireFeatures::KNOWN, None)
+    }
+
+    /// Like `open_with_timeout`, failing with `PortError::StaleInit` once
+    /// the header has been `INITIALIZING` for `init_timeout`, rather than
+    /// `INIT_TIMEOUT`.
+    pub fn open_with_timeouts(name: &str, timeout: Duration, init_timeout: Duration) -> Result<QueueingPort, PortError> {
+        QueueingPort::map_named(name)?.join(timeout, init_timeout, None)
+    }
+
+    /// Like `open()`, also requiring the port to be in `mode`.
+    pub(crate) fn open_typed(name: &str, mode: ConcurrencyMode) -> Result<QueueingPort, PortError> {
+        QueueingPort::open_supporting(name, HANDSHAKE_TIMEOUT, WireFeatures::KNOWN, Some(mode))
+    }
+
+    pub(crate) fn open_supporting(
+        name: &str,
+        timeout: Duration,
+        supported: WireFeatures,
+        mode: Option<ConcurrencyMode>,
+    ) -> Result<QueueingPort, PortError> {
+        let mut port = QueueingPort::map_named(name)?;
+        port.supported_features = supported;
+        port.join(timeout, INIT_TIMEOUT, mode)
+    }
+
+    fn map_named(name: &str) -> Result<QueueingPort, PortError> {
+        let open = OpenPortCharge::reserve()?;
+        let shmem = open_named(name, SegmentGeometry::PORT)?;
+        #[allow(unused_mut)]
+        let mut port = QueueingPort::from_memory(Memory::named(shmem, open));
+        #[cfg(feature = "tracing")]
+        port.set_name(name);
+        Ok(port)
+    }
+
+    /// The opener's side of the handshake, on a mapped segment.
+    pub(crate) fn join(
+        mut self,
+        timeout: Duration,
+        init_timeout: Duration,
+        mode: Option<ConcurrencyMode>,
+    ) -> Result<QueueingPort, PortError> {
+        let supported = self.supported_features;
+        let deadline = Instant::now() + timeout;
+        let mut initializing_since = None;
+        self.check_not_doomed()?;
+        loop {
+            match self.segment().header.state.load(Ordering::Acquire) {
+                // The features were published along with WRITER_READY; a
+                // port we cannot use is left for another reader.
+                WRITER_READY => {
+                    if let Some(missing_features) = self.missing_features(supported) {
+                        return Err(PortError::IncompatibleLayout { missing_features });
+                    }
+                    let found = self.concurrency_mode();
+                    if let Some(expected) = mode.filter(|&expected| found != Some(expected)) {
+                        return Err(PortError::ModeMismatch { expected, found });
+                    }
+                    self.take_attach_slot()?;
+                    if self
+                        .segment()
+                        .header
+                        .state
+                        .compare_exchange(WRITER_READY, READER_READY, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                    {
+                        break;
+                    }
+                }
+                // The writer has the segment but has not announced itself yet.
+                UNINIT | INITIALIZING if Instant::now() >= deadline => return Err(PortError::HandshakeTimeout),
+                UNINIT => thread::sleep(POLL_INTERVAL),
+                INITIALIZING => {
+                    let since = *initializing_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= init_timeout {
+                        return Err(PortError::StaleInit);
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+                _ => return Err(PortError::NotReady),
+            }
+        }
+        // Published along with WRITER_READY, like the features.
+        self.generation = self.segment().header.segment_generation.load(self.byte_order(), Ordering::Relaxed);
+        // Before OPEN, so the creator sees the receiver once `create` returns.
+        self.announce(PeerRole::Receiver);
+        let state = &self.segment().header.state;
+        wait_for(state, deadline.saturating_duration_since(Instant::now()), |current| {
+            current == OPEN
+        })?;
+        Ok(self)
+    }
+
+    /// Marks the port as closed; later `open()` calls on it fail with
+    /// `PortError::NotReady`. Gives up this handle's writer and reader
+    /// claims.
+    pub fn close(&mut self) {
+        self.release_claims();
+        self.segment().header.state.store(CLOSED, Ordering::Release);
+    }
+
+    /// Reopens this port's named segment, empties it, and returns the new
+    /// handle, for a partition restarting without its peer re-creating
+    /// the port.
+    ///
+    /// Everything fixed at creation (metadata, wire features, delivery
+    /// order, mode) and the handshake state are kept; the queued messages
+    /// are discarded and the buffer zeroed, and the counters behind
+    /// `stats()` start over. The new handle takes this one's type filter,
+    /// skip budget, clock and corruption policy. The reset holds the
+    /// header's `compacting` flag, as `compact()` does, so the peer's next
+    /// operation waits for it; one the peer is already inside of is not
+    /// waited for.
+    ///
+    /// Fails with `PortError::Unnamed` if the port is not backed by a
+    /// named segment.
+    pub fn clone_reset(&self) -> Result<QueueingPort, PortError> {
+        let os_id = self.memory.os_id().ok_or(PortError::Unnamed)?;
+        let open = OpenPortCharge::reserve()?;
+        let shmem = open_named(os_id, SegmentGeometry::PORT)?;
+        let mut port = QueueingPort::from_memory(Memory::named(shmem, open));
+        port.check_not_doomed()?;
+        port.take_attach_slot()?;
+        port.type_filter = self.type_filter.clone();
+        port.skip_budget = self.skip_budget;
+        port.clock = self.clock.fork();
+        port.supported_features = self.supported_features;
+        port.corruption.policy = self.corruption.policy;
+        port.role = self.role;
+        #[cfg(feature = "tracing")]
+        port.set_name(&self.name);
+        reset(port.segment());
+        Ok(port)
+    }
+}
+
+/// Empties `segment` under its `compacting` flag.
+fn reset(segment: &Segment) {
+    let header = &segment.header;
+    let order = header.byte_order();
+    while header
+        .compacting
+        .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    for counter in [
+        &header.write_index,
+        &header.read_index,
+        &header.enqueued,
+        &header.dequeued,
+        &header.rejected,
+        &header.high_watermark,
+        &header.filtered_out,
+        &header.deadline_misses,
+    ] {
+        counter.store(order, 0, Ordering::Relaxed);
+    }
+    for (sequence, generation) in header.slot_sequence.iter().zip(&header.slot_generation) {
+        sequence.store(order, 0, Ordering::Relaxed);
+        // Freed, and changed, so an observer copying the slot retries.
+        let current = generation.load(order, Ordering::Relaxed);
+        generation.store(order, current.wrapping_add(2 - (current & 1)), Ordering::Relaxed);
+    }
+    fence(Ordering::Release);
+    // SAFETY: the queue is being emptied under `compacting`, so no slot
+    // belongs to the peer and no reference to one exists.
+    unsafe { core::ptr::write_bytes(segment.buffer.get(), 0, 1) };
+    header.message_count.store(order, 0, Ordering::Relaxed);
+    header.compacting.store(0, Ordering::Release);
+}
+
+fn wait_for(state: &AtomicU8, timeout: Duration, mut done: impl FnMut(u8) -> bool) -> Result<(), PortError> {
+    let deadline = Instant::now() + timeout;
+    while !done(state.load(Ordering::Acquire)) {
+        if Instant::now() >= deadline {
+            return Err(PortError::HandshakeTimeout);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+    use std::process::Command;
+
+    fn port_name(test: &str) -> String {
+        format!("/qp_{}_{}", test, std::process::id())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn handshake_between_processes() {
+        let name = port_name("handshake");
+
+        let child = unsafe { libc::fork() };
+        assert!(child >= 0);
+        if child == 0 {
+            // The writer may not have created the segment yet.
+            let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+            let status = loop {
+                match QueueingPort::open(&name) {
+                    Ok(mut reader) => match reader.dequeue_blocking() {
+                        Ok(message) if message.0 == [9; SIZE] => break 0,
+                        _ => break 2,
+                    },
+                    Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                    Err(_) => break 1,
+                }
+            };
+            unsafe { libc::_exit(status) };
+        }
+
+        let mut writer = QueueingPort::create(&name).unwrap();
+        assert_eq!(writer.segment().header.state.load(Ordering::Acquire), OPEN);
+        assert!(writer.peer_present(), "open() announces the receiver");
+        writer.enqueue(Message([9; SIZE])).unwrap();
+
+        let mut status = 0;
+        unsafe { libc::waitpid(child, &mut status, 0) };
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0, "reader side of the handshake failed");
+    }
+
+    // Set in the child process started by `handshake_with_child_process`.
+    const CHILD_ENV: &str = "QP_HANDSHAKE_CHILD_PORT";
+
+    /// Same exchange as above, with the reader in a re-executed copy of the
+    /// test binary so it runs where fork() is not available.
+    #[test]
+    fn handshake_with_child_process() {
+        if let Ok(name) = std::env::var(CHILD_ENV) {
+            let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+            let status = loop {
+                match QueueingPort::open(&name) {
+                    Ok(mut reader) => match reader.dequeue_blocking() {
+                        Ok(message) if message.0 == [5; SIZE] => break 0,
+                        _ => break 2,
+                    },
+                    Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                    Err(_) => break 1,
+                }
+            };
+            std::process::exit(status);
+        }
+
+        let name = port_name("child_process");
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "named::tests::handshake_with_child_process", "--test-threads=1"])
+            .env(CHILD_ENV, &name)
+            .spawn()
+            .unwrap();
+
+        let mut writer = QueueingPort::create(&name).unwrap();
+        writer.enqueue(Message([5; SIZE])).unwrap();
+        let status = child.wait().unwrap();
+        assert!(status.success(), "reader process failed: {}", status);
+    }
+
+    const RESET_CHILD_ENV: &str = "QP_CLONE_RESET_CHILD_PORT";
+
+    #[test]
+    fn clone_reset_from_child_process() {
+        if let Ok(name) = std::env::var(RESET_CHILD_ENV) {
+            let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+            let status = loop {
+                match QueueingPort::open(&name) {
+                    Ok(reader) => {
+                        while reader.len() < 3 && Instant::now() < deadline {
+                            thread::sleep(POLL_INTERVAL);
+                        }
+                        if reader.len() < 3 {
+                            break 2;
+                        }
+                        match reader.clone_reset() {
+                            Ok(fresh) if fresh.is_empty() && reader.is_empty() && fresh.stats().enqueued == 0 => break 0,
+                            _ => break 3,
+                        }
+                    }
+                    Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                    Err(_) => break 1,
+                }
+            };
+            std::process::exit(status);
+        }
+
+        let name = port_name("clone_reset");
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "named::tests::clone_reset_from_child_process", "--test-threads=1"])
+            .env(RESET_CHILD_ENV, &name)
+            .spawn()
+            .unwrap();
+
+        let config = PortConfig::new().metadata(b"partition=2").unwrap();
+        let mut writer = QueueingPort::create_with_config(&name, &config).unwrap();
+        for fill in 1..=3 {
+            writer.enqueue(Message([fill; SIZE])).unwrap();
+        }
+        let status = child.wait().unwrap();
+        assert!(status.success(), "resetting process failed: {}", status);
+
+        assert!(matches!(writer.try_dequeue(), Err(crate::QueueError::EmptyBuffer)));
+        assert_eq!(writer.metadata(), b"partition=2", "the configuration survives");
+        writer.enqueue(Message([4; SIZE])).unwrap();
+        assert_eq!(writer.dequeue().unwrap().0, [4; SIZE]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reattach_follows_a_recreated_name() {
+        let name = port_name("reattach");
+        let create = || {
+            let name = name.clone();
+            thread::spawn(move || QueueingPort::create(&name).unwrap())
+        };
+        let open = || {
+            let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+            loop {
+                match QueueingPort::open(&name) {
+                    Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                    result => break result.unwrap(),
+                }
+            }
+        };
+        let creator = create();
+        let mut receiver = open();
+        let writer = creator.join().unwrap();
+        let generation = writer.segment_generation();
+        assert_eq!(receiver.segment_generation(), generation);
+        assert!(!receiver.is_stale().unwrap());
+
+        // The writer leaving first dooms the segment. A supervisor setting
+        // the port up again removes the name; the receiver keeps the old
+        // mapping, and leaving it later does not unlink the new one.
+        drop(writer);
+        assert!(matches!(QueueingPort::open(&name), Err(PortError::SegmentDoomed)));
+        let os_name = std::ffi::CString::new(name.as_str()).unwrap();
+        assert_eq!(unsafe { libc::shm_unlink(os_name.as_ptr()) }, 0);
+        assert!(matches!(receiver.is_stale(), Err(PortError::Open(error)) if error.kind == crate::OpenFailure::NotFound));
+        let creator = create();
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        while receiver.is_stale().map_or(Instant::now() < deadline, |stale| !stale) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert!(receiver.is_stale().unwrap());
+        receiver.reattach().unwrap();
+        let mut writer = creator.join().unwrap();
+        assert_ne!(writer.segment_generation(), generation);
+        assert_eq!(receiver.segment_generation(), writer.segment_generation());
+
+        writer.enqueue(Message([6; SIZE])).unwrap();
+        assert_eq!(receiver.dequeue().unwrap().0, [6; SIZE]);
+        assert!(QueueingPort::open_observer(&name).is_ok(), "the new segment is still named");
+    }
+
+    #[test]
+    fn clone_reset_needs_a_named_segment() {
+        assert!(matches!(QueueingPort::new().clone_reset(), Err(PortError::Unnamed)));
+    }
+
+    #[test]
+    fn create_times_out_without_reader() {
+        let name = port_name("no_reader");
+        let result = QueueingPort::create_with_timeout(&name, Duration::from_millis(50));
+        assert!(matches!(result, Err(PortError::HandshakeTimeout)));
+    }
+
+    // Opens `name` from another thread once the creator is up, and returns
+    // the metadata the opener saw.
+    fn open_in_thread(name: &str) -> thread::JoinHandle<Result<Vec<u8>, PortError>> {
+        let name = name.to_owned();
+        thread::spawn(move || {
+            let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+            loop {
+                match QueueingPort::open(&name) {
+                    Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                    result => break result.map(|port| port.metadata().to_vec()),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn metadata_is_visible_to_opener() {
+        for (test, metadata) in [("meta", &b"schema=3"[..]), ("meta_empty", b""), ("meta_full", &[7; 128])] {
+            let name = port_name(test);
+            let opener = open_in_thread(&name);
+            let config = PortConfig::new().metadata(metadata).unwrap();
+            let writer = QueueingPort::create_with_config(&name, &config).unwrap();
+            assert_eq!(writer.metadata(), metadata);
+            assert_eq!(opener.join().unwrap().unwrap(), metadata);
+        }
+    }
+
+    #[test]
+    fn open_rejects_unknown_required_features() {
+        let name = port_name("features_required");
+        let creator = thread::spawn({
+            let name = name.clone();
+            move || {
+                let config = PortConfig::new()
+                    .wire_features(WireFeatures::ENVELOPE | WireFeatures::CRC)
+                    .handshake_timeout(Duration::from_millis(300));
+                QueueingPort::create_with_config(&name, &config).map(|_| ())
+            }
+        });
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        // An older build that predates CRC.
+        let result = loop {
+            match QueueingPort::open_supporting(&name, HANDSHAKE_TIMEOUT, WireFeatures::ENVELOPE, None) {
+                Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                result => break result,
+            }
+        };
+        assert!(matches!(
+            result,
+            Err(PortError::IncompatibleLayout { missing_features }) if missing_features == WireFeatures::CRC
+        ));
+        // The writer never saw a reader.
+        assert!(matches!(creator.join().unwrap(), Err(PortError::HandshakeTimeout)));
+    }
+
+    #[test]
+    fn open_tolerates_unknown_optional_features() {
+        let name = port_name("features_optional");
+        let opener = thread::spawn({
+            let name = name.clone();
+            move || {
+                let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+                // An older build that knows CRC but not timestamps.
+                loop {
+                    match QueueingPort::open_supporting(&name, HANDSHAKE_TIMEOUT, WireFeatures::CRC, None) {
+                        Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                        result => break result.map(|port| (port.wire_features(), port.negotiated_features())),
+                    }
+                }
+            }
+        });
+        let declared = WireFeatures::CRC | WireFeatures::TIMESTAMPS;
+        let writer = QueueingPort::create_with_config(&name, &PortConfig::new().wire_features(declared)).unwrap();
+        assert_eq!(writer.negotiated_features(), declared);
+        assert_eq!(opener.join().unwrap().unwrap(), (declared, WireFeatures::CRC));
+    }
+
+    #[test]
+    fn open_rejects_closed_port() {
+        let name = port_name("closed");
+        let opener = thread::spawn({
+            let name = name.clone();
+            move || {
+                let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+                loop {
+                    match QueueingPort::open(&name) {
+                        Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                        result => break result.map(|_| ()),
+                    }
+                }
+            }
+        });
+        let mut writer = QueueingPort::create(&name).unwrap();
+        opener.join().unwrap().unwrap();
+
+        writer.close();
+        assert!(matches!(QueueingPort::open(&name), Err(PortError::NotReady)));
+    }
+
+    #[test]
+    fn observer_opens_port_with_reader() {
+        let name = port_name("observer");
+        let opener = thread::spawn({
+            let name = name.clone();
+            move || {
+                let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+                loop {
+                    match QueueingPort::open(&name) {
+                        Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                        result => break result,
+                    }
+                }
+            }
+        });
+        let mut writer = QueueingPort::create(&name).unwrap();
+        let mut reader = opener.join().unwrap().unwrap();
+
+        let mut observer = QueueingPort::open_observer(&name).unwrap();
+        writer.enqueue(Message([9; SIZE])).unwrap();
+        assert_eq!(observer.observe_new().unwrap().unwrap().0, [9; SIZE]);
+        assert_eq!(reader.dequeue().unwrap().0, [9; SIZE], "the observer took nothing");
+        assert!(observer.observe_new().unwrap().is_none());
+    }
+
+    #[test]
+    fn racing_creators_have_one_winner() {
+        for _ in 0..50 {
+            let segment = core::ptr::NonNull::from(Box::leak(Box::new(crate::Segment::new())));
+            let creators = [1u8, 2].map(|fill| {
+                let port = unsafe { QueueingPort::attach(segment) };
+                let config = PortConfig::new().metadata(&[fill; crate::METADATA_CAPACITY]).unwrap();
+                thread::spawn(move || port.initialize(&config).map(|port| (fill, port)))
+            });
+            let opener = unsafe { QueueingPort::attach(segment) };
+            let opener = thread::spawn(move || opener.join(HANDSHAKE_TIMEOUT, INIT_TIMEOUT, None));
+
+            let results = creators.map(|creator| creator.join().unwrap());
+            let reader = opener.join().unwrap().unwrap();
+            let winners: Vec<u8> = results.iter().filter_map(|result| result.as_ref().ok().map(|(fill, _)| *fill)).collect();
+            assert_eq!(winners.len(), 1, "{:?}", results.iter().map(|r| r.as_ref().err()).collect::<Vec<_>>());
+            assert!(results.iter().any(|result| matches!(result, Err(PortError::AlreadyExists))));
+            assert_eq!(reader.metadata(), [winners[0]; crate::METADATA_CAPACITY], "the whole header is published");
+            assert_eq!(reader.segment_generation(), results.iter().flatten().next().unwrap().1.segment_generation());
+        }
+    }
+
+    #[test]
+    fn header_stuck_initializing_is_stale() {
+        let segment = core::ptr::NonNull::from(Box::leak(Box::new(crate::Segment::new())));
+        let port = unsafe { QueueingPort::attach(segment) };
+        // A creator that died after winning the race.
+        port.segment().header.state.store(INITIALIZING, Ordering::Release);
+        let started = Instant::now();
+        let result = port.join(HANDSHAKE_TIMEOUT, Duration::from_millis(20), None);
+        assert!(matches!(result, Err(PortError::StaleInit)));
+        assert!(started.elapsed() < HANDSHAKE_TIMEOUT);
+
+        let late = unsafe { QueueingPort::attach(segment) };
+        assert!(matches!(late.initialize(&PortConfig::new()), Err(PortError::AlreadyExists)));
+    }
+}
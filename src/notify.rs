@@ -0,0 +1,302 @@
+//! Fewer wakeups for a receiver that is already awake.
+//!
+//! A sender notifying its receiver after every message, with a doorbell or
+//! an eventfd, spends more on the notifications than on the messages once
+//! the rate is high. A `NotifyPolicy` lets it skip some:
+//!
+//! - `EveryMessage` notifies after every enqueue,
+//! - `EveryN(n)` notifies once the messages not yet notified reach `n`,
+//! - `Deadline(ns)` notifies on the first enqueue or `poll` at least `ns`
+//!   of the port's clock after the oldest message not yet notified.
+//!
+//! Under every policy an enqueue that finds the queue empty notifies at
+//! once: the receiver may be asleep. Enqueues into a queue that already
+//! holds messages are the ones coalesced, as the receiver has been woken
+//! for the first of them and drains the rest before it sleeps again.
+//!
+//! Messages not yet notified stay counted until a notification covers
+//! them, so none is lost when the traffic stops: `poll` sends a trailing
+//! notification once a deadline has passed, and `flush` sends one for
+//! whatever is pending, under any policy. A sender going quiet calls
+//! either. Whether the queue was empty is judged from its length after the
+//! enqueue, which is exact with one sender per queue.
+
+use crate::{EnqueuePort, Message, QueueError, QueueingPort};
+
+/// When a sender notifies its receiver, see the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotifyPolicy {
+    #[default]
+    EveryMessage,
+    /// At most once per `n` enqueues into a non-empty queue; `0` is taken
+    /// as `1`.
+    EveryN(u32),
+    /// Within this many nanoseconds of the port's clock after the oldest
+    /// enqueue not yet notified, given enqueues or `poll`s to notice it.
+    Deadline(u64),
+}
+
+/// Something that wakes a receiver: a doorbell, an eventfd, a condition
+/// variable. Closures are notifiers.
+pub trait Notifier {
+    fn notify(&mut self);
+}
+
+impl<F: FnMut()> Notifier for F {
+    fn notify(&mut self) {
+        self()
+    }
+}
+
+/// The bookkeeping behind a `NotifyPolicy`: each method returns whether to
+/// notify now.
+#[derive(Debug)]
+pub(crate) struct Coalescer {
+    policy: NotifyPolicy,
+    /// Enqueues since the last notification.
+    unnotified: u32,
+    /// Clock reading at the first of them.
+    first_unnotified_ns: u64,
+}
+
+impl Coalescer {
+    pub(crate) fn new(policy: NotifyPolicy) -> Coalescer {
+        Coalescer { policy, unnotified: 0, first_unnotified_ns: 0 }
+    }
+
+    pub(crate) fn policy(&self) -> NotifyPolicy {
+        self.policy
+    }
+
+    pub(crate) fn pending(&self) -> u32 {
+        self.unnotified
+    }
+
+    /// After an enqueue that left `len` messages queued.
+    pub(crate) fn enqueued(&mut self, len: usize, now_ns: u64) -> bool {
+        if len == 0 {
+            // The receiver took everything already, so it is awake.
+            self.unnotified = 0;
+            return false;
+        }
+        if self.unnotified == 0 {
+            self.first_unnotified_ns = now_ns;
+        }
+        self.unnotified += 1;
+        let due = match self.policy {
+            _ if len == 1 => true,
+            NotifyPolicy::EveryMessage => true,
+            NotifyPolicy::EveryN(n) => self.unnotified >= n.max(1),
+            NotifyPolicy::Deadline(_) => self.deadline_passed(now_ns),
+        };
+        self.notified_if(due)
+    }
+
+    pub(crate) fn poll(&mut self, now_ns: u64) -> bool {
+        let due = self.unnotified > 0 && self.deadline_passed(now_ns);
+        self.notified_if(due)
+    }
+
+    pub(crate) fn flush(&mut self) -> bool {
+        let due = self.unnotified > 0;
+        self.notified_if(due)
+    }
+
+    fn deadline_passed(&self, now_ns: u64) -> bool {
+        match self.policy {
+            NotifyPolicy::Deadline(ns) => now_ns.saturating_sub(self.first_unnotified_ns) >= ns,
+            _ => false,
+        }
+    }
+
+    fn notified_if(&mut self, due: bool) -> bool {
+        if due {
+            self.unnotified = 0;
+        }
+        due
+    }
+}
+
+/// A port that notifies its receiver through `N` as its `NotifyPolicy`
+/// says, see the module documentation.
+pub struct NotifyingPort<N> {
+    port: QueueingPort,
+    notifier: N,
+    coalescer: Coalescer,
+}
+
+impl QueueingPort {
+    /// This port, with enqueues through the returned one notifying
+    /// `notifier` under `policy`.
+    pub fn with_notifier<N: Notifier>(self, notifier: N, policy: NotifyPolicy) -> NotifyingPort<N> {
+        NotifyingPort { port: self, notifier, coalescer: Coalescer::new(policy) }
+    }
+}
+
+impl<N: Notifier> NotifyingPort<N> {
+    /// Enqueues `message` and notifies if the policy says so; a message
+    /// that was not queued is not counted.
+    pub fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        self.port.enqueue(message)?;
+        let now = self.port.clock().now_ns();
+        if self.coalescer.enqueued(self.port.len(), now) {
+            self.notifier.notify();
+        }
+        Ok(())
+    }
+
+    /// Sends the trailing notification of a `Deadline` policy if it is due,
+    /// returning whether it did.
+    pub fn poll(&mut self) -> bool {
+        let now = self.port.clock().now_ns();
+        let notified = self.coalescer.poll(now);
+        if notified {
+            self.notifier.notify();
+        }
+        notified
+    }
+
+    /// Notifies if any enqueue is not yet covered by a notification,
+    /// returning whether it did.
+    pub fn flush(&mut self) -> bool {
+        let notified = self.coalescer.flush();
+        if notified {
+            self.notifier.notify();
+        }
+        notified
+    }
+
+    /// Enqueues since the last notification.
+    pub fn pending(&self) -> u32 {
+        self.coalescer.pending()
+    }
+
+    pub fn policy(&self) -> NotifyPolicy {
+        self.coalescer.policy()
+    }
+
+    pub fn port(&self) -> &QueueingPort {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut QueueingPort {
+        &mut self.port
+    }
+
+    pub fn notifier(&self) -> &N {
+        &self.notifier
+    }
+
+    /// The port and notifier, dropping any pending notification; `flush`
+    /// first to send it.
+    pub fn into_parts(self) -> (QueueingPort, N) {
+        (self.port, self.notifier)
+    }
+}
+
+impl<N: Notifier> EnqueuePort for NotifyingPort<N> {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        NotifyingPort::enqueue(self, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockClock, SIZE};
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct Counter(u32);
+
+    impl Notifier for Counter {
+        fn notify(&mut self) {
+            self.0 += 1;
+        }
+    }
+
+    fn notifying(policy: NotifyPolicy) -> (NotifyingPort<Counter>, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new(0));
+        let mut port = QueueingPort::new();
+        port.set_clock(clock.clone());
+        (port.with_notifier(Counter::default(), policy), clock)
+    }
+
+    fn send(port: &mut NotifyingPort<Counter>, count: usize) {
+        for _ in 0..count {
+            port.enqueue(Message([0; SIZE])).unwrap();
+        }
+    }
+
+    fn drain(port: &mut NotifyingPort<Counter>) {
+        while port.port_mut().try_dequeue().is_ok() {}
+    }
+
+    fn notified(port: &NotifyingPort<Counter>) -> u32 {
+        port.notifier().0
+    }
+
+    #[test]
+    fn every_message_notifies_each_enqueue() {
+        let (mut port, _) = notifying(NotifyPolicy::EveryMessage);
+        send(&mut port, 6);
+        assert_eq!(notified(&port), 6);
+        assert!(!port.flush());
+        assert_eq!(port.pending(), 0);
+    }
+
+    #[test]
+    fn every_n_coalesces_but_wakes_on_the_empty_edge() {
+        let (mut port, _) = notifying(NotifyPolicy::EveryN(3));
+        // The first finds the queue empty, then every third after it.
+        send(&mut port, 7);
+        assert_eq!(notified(&port), 3);
+        send(&mut port, 2);
+        assert_eq!((notified(&port), port.pending()), (3, 2));
+
+        // Drained, the next enqueue wakes the receiver whatever is pending.
+        drain(&mut port);
+        send(&mut port, 1);
+        assert_eq!((notified(&port), port.pending()), (4, 0));
+        send(&mut port, 2);
+        assert!(!port.poll(), "no deadline to pass");
+        assert!(port.flush());
+        assert!(!port.flush());
+        assert_eq!(notified(&port), 5);
+
+        // A failed enqueue counts for nothing.
+        send(&mut port, 7);
+        assert_eq!((notified(&port), port.pending()), (7, 1));
+        assert!(port.enqueue(Message([0; SIZE])).is_err());
+        assert_eq!((notified(&port), port.pending()), (7, 1));
+    }
+
+    #[test]
+    fn deadline_fires_the_trailing_notification_after_a_burst() {
+        let (mut port, clock) = notifying(NotifyPolicy::Deadline(100));
+        send(&mut port, 1);
+        assert_eq!(notified(&port), 1, "the empty edge");
+        for _ in 0..3 {
+            clock.advance(10);
+            send(&mut port, 1);
+        }
+        assert_eq!((notified(&port), port.pending()), (1, 3));
+        // Due 100 after the enqueue at 10.
+        clock.set(109);
+        send(&mut port, 1);
+        assert_eq!(notified(&port), 1);
+        clock.set(110);
+        send(&mut port, 1);
+        assert_eq!((notified(&port), port.pending()), (2, 0));
+
+        // A burst, then silence: only `poll` is left to notice.
+        clock.set(200);
+        send(&mut port, 4);
+        clock.set(299);
+        assert!(!port.poll());
+        clock.set(300);
+        assert!(port.poll());
+        assert!(!port.poll());
+        assert_eq!((notified(&port), port.port().len()), (3, 10));
+    }
+}
@@ -0,0 +1,166 @@
+//! Placing a segment's memory on a NUMA node.
+//!
+//! Binding needs the `linux-numa` feature on Linux. Everywhere else the
+//! policy is reported with a warning (through `tracing`, if enabled) and
+//! otherwise ignored, so the same configuration runs on any machine.
+
+use crate::{trace, PortError, QueueingPort};
+
+/// Where a port's segment should live, see `PortConfig::numa_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumaPolicy {
+    pub node_id: u8,
+    /// Fail with `PortError::NumaBind` if the memory cannot be bound to, or
+    /// moved onto, the node, instead of warning and carrying on.
+    pub strict: bool,
+}
+
+#[cfg(all(feature = "linux-numa", target_os = "linux"))]
+mod imp {
+    use super::NumaPolicy;
+
+    const MPOL_BIND: i32 = 2;
+    const MPOL_MF_STRICT: u32 = 1 << 0;
+    const MPOL_MF_MOVE: u32 = 1 << 1;
+    const NODE_BITS: usize = 256;
+
+    fn page_range(start: usize, len: usize) -> (usize, usize) {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let first = start & !(page - 1);
+        (first, (start + len).next_multiple_of(page) - first)
+    }
+
+    fn errno() -> i32 {
+        unsafe { *libc::__errno_location() }
+    }
+
+    /// Binds the pages covering `start..start + len` to the policy's node,
+    /// moving pages already faulted in.
+    pub(super) fn bind(start: usize, len: usize, policy: NumaPolicy) -> Result<(), i32> {
+        let (first, len) = page_range(start, len);
+        let mut nodemask = [0u64; NODE_BITS / 64];
+        nodemask[policy.node_id as usize / 64] |= 1 << (policy.node_id % 64);
+        let flags = MPOL_MF_MOVE | if policy.strict { MPOL_MF_STRICT } else { 0 };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                first,
+                len,
+                MPOL_BIND,
+                nodemask.as_ptr(),
+                // The kernel ignores the last bit it is given.
+                NODE_BITS + 1,
+                flags,
+            )
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno())
+        }
+    }
+
+    /// The node holding the page at `address`, faulting it in if needed.
+    pub(super) fn node_of(address: usize) -> Option<u8> {
+        let (page, _) = page_range(address, 1);
+        // Touch the page so move_pages has something to report.
+        unsafe { core::ptr::read_volatile(address as *const u8) };
+        let pages = [page as *const libc::c_void];
+        let mut status = [-1i32];
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_move_pages,
+                0,
+                1usize,
+                pages.as_ptr(),
+                core::ptr::null::<i32>(),
+                status.as_mut_ptr(),
+                0,
+            )
+        };
+        (ret == 0).then(|| u8::try_from(status[0]).ok()).flatten()
+    }
+}
+
+impl QueueingPort {
+    /// Applies `policy` to the memory of this port's segment. Named ports
+    /// created with `PortConfig::numa_policy` do this on their own.
+    pub fn bind_numa(&self, policy: NumaPolicy) -> Result<(), PortError> {
+        #[cfg(all(feature = "linux-numa", target_os = "linux"))]
+        {
+            let segment = self.segment() as *const crate::Segment as usize;
+            match imp::bind(segment, core::mem::size_of::<crate::Segment>(), policy) {
+                Ok(()) => Ok(()),
+                Err(errno) if policy.strict => Err(PortError::NumaBind(errno)),
+                Err(_) => {
+                    trace::warn("mbind failed, segment left on its current NUMA node");
+                    Ok(())
+                }
+            }
+        }
+        #[cfg(not(all(feature = "linux-numa", target_os = "linux")))]
+        {
+            let _ = (self, policy);
+            trace::warn("NUMA placement needs the linux-numa feature on Linux, ignored");
+            Ok(())
+        }
+    }
+
+    /// The NUMA node the start of the segment is on, if that can be found
+    /// out (with the `linux-numa` feature on Linux).
+    pub fn numa_node(&self) -> Option<u8> {
+        #[cfg(all(feature = "linux-numa", target_os = "linux"))]
+        {
+            imp::node_of(self.segment() as *const crate::Segment as usize)
+        }
+        #[cfg(not(all(feature = "linux-numa", target_os = "linux")))]
+        {
+            None
+        }
+    }
+}
+
+#[cfg(all(test, feature = "linux-numa", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::Segment;
+    use core::ptr::{self, NonNull};
+
+    #[test]
+    fn binds_segment_to_node() {
+        let size = core::mem::size_of::<Segment>();
+        let memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(memory, libc::MAP_FAILED);
+        let port = unsafe { QueueingPort::attach(NonNull::new(memory.cast::<Segment>()).unwrap()) };
+
+        // Node 0 exists on every Linux system, NUMA or not.
+        let policy = NumaPolicy { node_id: 0, strict: true };
+        match port.bind_numa(policy) {
+            Ok(()) => {}
+            // Kernels built without NUMA support, or sandboxes, refuse.
+            Err(PortError::NumaBind(libc::ENOSYS | libc::EPERM)) => return,
+            Err(error) => panic!("bind failed: {:?}", error),
+        }
+        assert_eq!(port.numa_node(), Some(0));
+        let maps = std::fs::read_to_string("/proc/self/numa_maps").unwrap();
+        let entry = format!("{:x} bind:0", memory as usize);
+        assert!(maps.lines().any(|line| line.starts_with(&entry)), "{} not in numa_maps", entry);
+
+        // A node that does not exist cannot be bound strictly.
+        let missing = NumaPolicy { node_id: 255, strict: true };
+        assert!(matches!(port.bind_numa(missing), Err(PortError::NumaBind(_))));
+        assert!(port.bind_numa(NumaPolicy { strict: false, ..missing }).is_ok());
+
+        drop(port);
+        unsafe { libc::munmap(memory, size) };
+    }
+}
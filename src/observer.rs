@@ -0,0 +1,257 @@
+//! Watching a port's traffic without taking part in it.
+//!
+//! An `Observer` maps the segment like a reader but never writes to it: it
+//! only loads header fields and copies slots. Its cursor is private, so it
+//! can only see messages that are still queued; a copy is kept only if
+//! the slot's generation did not move while it was taken, so a slot freed
+//! or reused under the observer is never returned as a message.
+
+use core::fmt;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::{ClockRef, HistoryEntry, Message, QueueError, QueueStats, QueueingPort, Segment, MSGS, SIZE};
+
+/// A read-only handle on a port, see the module documentation.
+pub struct Observer {
+    // Only `&self` methods that leave the segment untouched are called.
+    port: QueueingPort,
+    /// Sequence number of the next message `observe_new` returns.
+    next: u32,
+}
+
+impl Observer {
+    /// Observes the segment at `segment`, starting with the oldest message
+    /// queued there now.
+    ///
+    /// # Safety
+    ///
+    /// `segment` must point to a valid `Segment` that outlives the observer.
+    pub unsafe fn attach(segment: NonNull<Segment>) -> Observer {
+        Observer::new(QueueingPort::attach(segment))
+    }
+
+    fn new(port: QueueingPort) -> Observer {
+        let next = port.segment().header.dequeued.load(port.byte_order(), Ordering::Acquire);
+        Observer { port, next }
+    }
+
+    pub fn len(&self) -> usize {
+        self.port.len()
+    }
+
+    /// Detaches the observer, as dropping it does, and returns whether
+    /// that unlinked the segment's name; see `QueueingPort::detach`.
+    #[cfg(feature = "shmem")]
+    pub fn detach(self) -> bool {
+        self.port.detach()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.port.is_empty()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.port.stats()
+    }
+
+    /// Sets the clock `consumer_idle` reads, as `QueueingPort::set_clock`.
+    pub fn set_clock(&mut self, clock: ClockRef) {
+        self.port.set_clock(clock);
+    }
+
+    /// See `QueueingPort::consumer_idle`.
+    pub fn consumer_idle(&self) -> Option<u64> {
+        self.port.consumer_idle()
+    }
+
+    /// See `QueueingPort::consumer_stalled`; an observer reports no events.
+    pub fn consumer_stalled(&self, max_idle_ticks: u64) -> bool {
+        self.port.consumer_stalled(max_idle_ticks)
+    }
+
+    /// See `QueueingPort::history`.
+    pub fn history(&self, out: &mut [HistoryEntry]) -> usize {
+        self.port.history(out)
+    }
+
+    /// Copies the `n`th queued message, counting from the oldest. `None` if
+    /// fewer are queued, or the message was consumed while being copied.
+    pub fn peek_nth(&self, n: usize) -> Option<Message> {
+        let oldest = self.port.segment().header.dequeued.load(self.port.byte_order(), Ordering::Acquire);
+        self.copy_sequence(oldest.wrapping_add(n as u32))
+    }
+
+    /// Writes a line `# <slots> slots, <index math> index` and then one
+    /// line per queued message, `<sequence>: <hex bytes>`, oldest first.
+    pub fn dump(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(out, "# {} slots, {} index", MSGS, QueueingPort::index_math())?;
+        let oldest = self.port.segment().header.dequeued.load(self.port.byte_order(), Ordering::Acquire);
+        for n in 0..self.len().min(MSGS) as u32 {
+            let sequence = oldest.wrapping_add(n);
+            if let Some(message) = self.copy_sequence(sequence) {
+                writeln!(out, "{}: {:x}", sequence, message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The next message published since the last call, or `None` if there
+    /// is none yet. If the consumer took messages before the observer saw
+    /// them, fails once with `QueueError::Lagged` giving how many, and
+    /// carries on from the oldest message still queued.
+    pub fn observe_new(&mut self) -> Result<Option<Message>, QueueError> {
+        let header = &self.port.segment().header;
+        let order = header.byte_order();
+        header.wait_while_compacting();
+        let enqueued = header.enqueued.load(order, Ordering::Acquire);
+        if self.next == enqueued {
+            return Ok(None);
+        }
+        let message = self.copy_sequence(self.next);
+        // Checked after the copy: a failed one means the message was
+        // consumed meanwhile, or is not visible yet.
+        let oldest = header.dequeued.load(order, Ordering::Acquire);
+        let missed = oldest.wrapping_sub(self.next);
+        if missed != 0 && missed <= enqueued.wrapping_sub(self.next) {
+            self.next = oldest;
+            return Err(QueueError::Lagged { missed });
+        }
+        if message.is_some() {
+            self.next = self.next.wrapping_add(1);
+        }
+        Ok(message)
+    }
+
+    /// Copies the message with sequence number `sequence` if a slot holds
+    /// it, and kept holding it for the whole copy.
+    fn copy_sequence(&self, sequence: u32) -> Option<Message> {
+        let segment = self.port.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        (0..MSGS).find_map(|index| {
+            let generation = header.slot_generation[index].load(order, Ordering::Acquire);
+            if generation.is_multiple_of(2) || header.slot_sequence[index].load(order, Ordering::Relaxed) != sequence {
+                return None;
+            }
+            // Only the generation check below makes the copy trustworthy.
+            let bytes = unsafe { core::ptr::read_volatile(segment.slot(index).cast::<[u8; SIZE]>()) };
+            fence(Ordering::Acquire);
+            (header.slot_generation[index].load(order, Ordering::Relaxed) == generation).then_some(Message(bytes))
+        })
+    }
+}
+
+#[cfg(feature = "shmem")]
+impl QueueingPort {
+    /// Maps the named segment `name` for observing. Unlike `open()` this
+    /// takes no part in the handshake, so it works on a port that already
+    /// has its reader; it fails with `PortError::NotReady` only if the
+    /// creator has not set the segment up yet.
+    pub fn open_observer(name: &str) -> Result<Observer, crate::PortError> {
+        use crate::{Memory, PortError, INITIALIZING, UNINIT};
+
+        let open = crate::open_limit::OpenPortCharge::reserve()?;
+        let shmem = crate::open_error::open_named(name, crate::SegmentGeometry::PORT)?;
+        let mut port = QueueingPort::from_memory(Memory::named(shmem, open));
+        if matches!(port.segment().header.state.load(Ordering::Acquire), UNINIT | INITIALIZING) {
+            return Err(PortError::NotReady);
+        }
+        port.check_not_doomed()?;
+        port.take_attach_slot()?;
+        Ok(Observer::new(port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(sequence: u32) -> Message {
+        let mut bytes = [sequence as u8; SIZE];
+        bytes[..4].copy_from_slice(&sequence.to_le_bytes());
+        Message(bytes)
+    }
+
+    fn shared_segment() -> NonNull<Segment> {
+        // Leaked, so it outlives the handles.
+        NonNull::from(Box::leak(Box::new(Segment::new())))
+    }
+
+    #[test]
+    fn observer_sees_what_consumer_sees() {
+        let segment = shared_segment();
+        let mut port = unsafe { QueueingPort::attach(segment) };
+        let mut observer = unsafe { Observer::attach(segment) };
+
+        let mut observed = Vec::new();
+        let mut consumed = Vec::new();
+        for round in 0..5u32 {
+            for i in 0..4 {
+                port.enqueue(numbered(round * 4 + i)).unwrap();
+            }
+            while let Some(message) = observer.observe_new().unwrap() {
+                observed.push(message.0);
+            }
+            while let Ok(message) = port.try_dequeue() {
+                consumed.push(message.0);
+            }
+        }
+        assert_eq!(observed.len(), 20);
+        assert_eq!(observed, consumed);
+        assert_eq!(observer.stats().dequeued, 20);
+    }
+
+    #[test]
+    fn stalled_observer_is_lapped() {
+        let segment = shared_segment();
+        let mut port = unsafe { QueueingPort::attach(segment) };
+        let mut observer = unsafe { Observer::attach(segment) };
+
+        for sequence in 0..25 {
+            port.enqueue(numbered(sequence)).unwrap();
+            if sequence < 22 {
+                port.dequeue().unwrap();
+            }
+        }
+        // Messages 0..22 were consumed, and their slots reused for later ones.
+        assert!(matches!(observer.observe_new(), Err(QueueError::Lagged { missed: 22 })));
+        let rest: Vec<u8> = core::iter::from_fn(|| observer.observe_new().unwrap()).map(|m| m.0[0]).collect();
+        assert_eq!(rest, [22, 23, 24]);
+        assert_eq!(port.len(), 3, "the observer consumed nothing");
+    }
+
+    #[test]
+    fn freed_slot_is_not_returned() {
+        let segment = shared_segment();
+        let mut port = unsafe { QueueingPort::attach(segment) };
+        let observer = unsafe { Observer::attach(segment) };
+        port.enqueue(numbered(0)).unwrap();
+        assert_eq!(observer.peek_nth(0).unwrap().0, numbered(0).0);
+
+        // The slot still carries sequence 0, but it is free now.
+        port.dequeue().unwrap();
+        assert!(observer.peek_nth(0).is_none());
+    }
+
+    #[test]
+    fn peek_and_dump_leave_queue_alone() {
+        let mut port = QueueingPort::new();
+        port.enqueue(numbered(0)).unwrap();
+        port.enqueue(numbered(1)).unwrap();
+        port.dequeue().unwrap();
+        port.enqueue(numbered(2)).unwrap();
+        let observer = unsafe { Observer::attach(NonNull::from(port.segment())) };
+
+        assert_eq!(observer.peek_nth(1).unwrap().0[0], 2);
+        assert!(observer.peek_nth(2).is_none());
+        let mut text = String::new();
+        observer.dump(&mut text).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "# 10 slots, modulo index");
+        assert!(lines[1].starts_with("1: 01 00 00 00 01"));
+        assert!(lines[2].starts_with("2: 02 00 00 00 02"));
+        assert_eq!(port.len(), 2);
+    }
+}
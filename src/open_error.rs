@@ -0,0 +1,339 @@
+//! Why a named segment could not be created or opened.
+//!
+//! The shared-memory backend reports an OS error code and little else.
+//! `PortOpenError` classifies it into what an operator acts on (the name
+//! is taken, it does not exist yet, no permission, no memory left) and
+//! carries the name and the geometry that were asked for, so a logged
+//! error reads on its own. `is_retryable` says whether trying again, or
+//! trying `open()` where `create()` found the name taken, may succeed.
+//!
+//! The codes are errno values on unix and Win32 error codes on Windows,
+//! each classified on its own path below.
+
+use std::fmt;
+
+use shared_memory::{Shmem, ShmemConf, ShmemError};
+
+use crate::{PortError, Segment, MSGS, SIZE};
+
+/// The size and shape of segment a `create()` or `open()` asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentGeometry {
+    pub msg_size: usize,
+    pub msg_count: usize,
+    /// Bytes the segment needs.
+    pub segment_len: usize,
+}
+
+impl SegmentGeometry {
+    /// A `QueueingPort`'s segment.
+    pub const PORT: SegmentGeometry =
+        SegmentGeometry { msg_size: SIZE, msg_count: MSGS, segment_len: core::mem::size_of::<Segment>() };
+}
+
+/// What went wrong, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpenFailure {
+    /// `create()` found a segment of that name already; `open()` it, or
+    /// wait for its owner to go.
+    NameInUse,
+    /// No segment of that name, perhaps not yet created.
+    NotFound,
+    /// The segment exists but this user may not map it.
+    PermissionDenied,
+    /// The system had no memory or space for `requested` bytes; with
+    /// `available_hint` set, it had about that many.
+    InsufficientSpace { requested: usize, available_hint: Option<usize> },
+    /// The backend failed otherwise, with this OS error code if it gave
+    /// one.
+    MappingFailed { os_error: Option<u32> },
+    /// The segment under that name is not one this port can use.
+    IncompatibleLayout { details: String },
+}
+
+/// A failed `create()` or `open()` of a named segment, see the module
+/// documentation.
+#[derive(Debug)]
+pub struct PortOpenError {
+    pub kind: OpenFailure,
+    pub name: String,
+    pub geometry: SegmentGeometry,
+    source: Option<ShmemError>,
+}
+
+impl PortOpenError {
+    pub fn new(kind: OpenFailure, name: &str, geometry: SegmentGeometry) -> PortOpenError {
+        PortOpenError { kind, name: name.into(), geometry, source: None }
+    }
+
+    /// Classifies a backend error from mapping `name`.
+    pub fn from_shmem(error: ShmemError, name: &str, geometry: SegmentGeometry) -> PortOpenError {
+        let kind = match &error {
+            ShmemError::MappingIdExists | ShmemError::LinkExists => OpenFailure::NameInUse,
+            ShmemError::LinkDoesNotExist => OpenFailure::NotFound,
+            ShmemError::MapCreateFailed(code) | ShmemError::MapOpenFailed(code) | ShmemError::UnknownOsError(code) => {
+                classify_os_error(*code, geometry.segment_len)
+            }
+            ShmemError::LinkCreateFailed(io)
+            | ShmemError::LinkWriteFailed(io)
+            | ShmemError::LinkOpenFailed(io)
+            | ShmemError::LinkReadFailed(io) => match io.raw_os_error() {
+                Some(code) => classify_os_error(code as u32, geometry.segment_len),
+                None => OpenFailure::MappingFailed { os_error: None },
+            },
+            ShmemError::MapSizeZero | ShmemError::NoLinkOrOsId | ShmemError::FlinkInvalidOsId => {
+                OpenFailure::MappingFailed { os_error: None }
+            }
+        };
+        PortOpenError { source: Some(error), ..PortOpenError::new(kind, name, geometry) }
+    }
+
+    /// Whether the same call may succeed later, or, for
+    /// `OpenFailure::NameInUse`, an `open()` of the name now.
+    pub fn is_retryable(&self) -> bool {
+        match &self.kind {
+            OpenFailure::NameInUse | OpenFailure::NotFound | OpenFailure::InsufficientSpace { .. } => true,
+            OpenFailure::PermissionDenied | OpenFailure::IncompatibleLayout { .. } => false,
+            OpenFailure::MappingFailed { os_error } => os_error.is_some_and(is_transient),
+        }
+    }
+
+    /// The backend's own error, if the failure came from it.
+    pub fn shmem_error(&self) -> Option<&ShmemError> {
+        self.source.as_ref()
+    }
+}
+
+impl fmt::Display for PortOpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let geometry = self.geometry;
+        write!(
+            f,
+            "port {:?} ({} messages of {} bytes, {} bytes): ",
+            self.name, geometry.msg_count, geometry.msg_size, geometry.segment_len
+        )?;
+        match &self.kind {
+            OpenFailure::NameInUse => f.write_str("the name is in use"),
+            OpenFailure::NotFound => f.write_str("no such segment"),
+            OpenFailure::PermissionDenied => f.write_str("permission denied"),
+            OpenFailure::InsufficientSpace { requested, available_hint: Some(available) } => {
+                write!(f, "{} bytes requested, about {} available", requested, available)
+            }
+            OpenFailure::InsufficientSpace { requested, available_hint: None } => {
+                write!(f, "no space for {} bytes", requested)
+            }
+            OpenFailure::MappingFailed { os_error: Some(code) } => write!(f, "mapping failed, os error {}", code),
+            OpenFailure::MappingFailed { os_error: None } => f.write_str("mapping failed"),
+            OpenFailure::IncompatibleLayout { details } => write!(f, "incompatible layout: {}", details),
+        }
+    }
+}
+
+impl std::error::Error for PortOpenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|error| error as _)
+    }
+}
+
+#[cfg(not(windows))]
+mod codes {
+    // The same on Linux, the BSDs and macOS.
+    pub const EPERM: u32 = 1;
+    pub const ENOENT: u32 = 2;
+    pub const EINTR: u32 = 4;
+    pub const ENOMEM: u32 = 12;
+    pub const EACCES: u32 = 13;
+    pub const EEXIST: u32 = 17;
+    pub const ENFILE: u32 = 23;
+    pub const EMFILE: u32 = 24;
+    pub const EFBIG: u32 = 27;
+    pub const ENOSPC: u32 = 28;
+    /// 11 on Linux, 35 on the BSDs and macOS.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub const EAGAIN: u32 = 11;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub const EAGAIN: u32 = 35;
+}
+
+#[cfg(windows)]
+mod codes {
+    pub const ERROR_FILE_NOT_FOUND: u32 = 2;
+    pub const ERROR_PATH_NOT_FOUND: u32 = 3;
+    pub const ERROR_ACCESS_DENIED: u32 = 5;
+    pub const ERROR_NOT_ENOUGH_MEMORY: u32 = 8;
+    pub const ERROR_OUTOFMEMORY: u32 = 14;
+    pub const ERROR_SHARING_VIOLATION: u32 = 32;
+    pub const ERROR_LOCK_VIOLATION: u32 = 33;
+    pub const ERROR_DISK_FULL: u32 = 112;
+    pub const ERROR_ALREADY_EXISTS: u32 = 183;
+    pub const ERROR_NO_SYSTEM_RESOURCES: u32 = 1450;
+    pub const ERROR_COMMITMENT_LIMIT: u32 = 1455;
+}
+
+/// An errno from `shm_open`, `ftruncate`, `fstat` or `mmap`.
+#[cfg(not(windows))]
+fn classify_os_error(code: u32, requested: usize) -> OpenFailure {
+    use codes::*;
+    match code {
+        EEXIST => OpenFailure::NameInUse,
+        ENOENT => OpenFailure::NotFound,
+        EACCES | EPERM => OpenFailure::PermissionDenied,
+        ENOMEM | ENOSPC | EFBIG => OpenFailure::InsufficientSpace { requested, available_hint: None },
+        code => OpenFailure::MappingFailed { os_error: Some(code) },
+    }
+}
+
+/// A Win32 error code from `CreateFileMapping`, `OpenFileMapping` or
+/// `MapViewOfFile`.
+#[cfg(windows)]
+fn classify_os_error(code: u32, requested: usize) -> OpenFailure {
+    use codes::*;
+    match code {
+        ERROR_ALREADY_EXISTS => OpenFailure::NameInUse,
+        ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND => OpenFailure::NotFound,
+        ERROR_ACCESS_DENIED => OpenFailure::PermissionDenied,
+        ERROR_NOT_ENOUGH_MEMORY | ERROR_OUTOFMEMORY | ERROR_DISK_FULL | ERROR_NO_SYSTEM_RESOURCES
+        | ERROR_COMMITMENT_LIMIT => OpenFailure::InsufficientSpace { requested, available_hint: None },
+        code => OpenFailure::MappingFailed { os_error: Some(code) },
+    }
+}
+
+/// Whether a failure with this code is worth retrying as it is.
+fn is_transient(code: u32) -> bool {
+    use codes::*;
+    #[cfg(not(windows))]
+    return matches!(code, EINTR | EAGAIN | EMFILE | ENFILE);
+    #[cfg(windows)]
+    return matches!(code, ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION);
+}
+
+/// Creates the named segment `name` of `geometry.segment_len` bytes.
+pub(crate) fn create_named(name: &str, geometry: SegmentGeometry) -> Result<Shmem, PortError> {
+    let shmem = ShmemConf::new()
+        .size(geometry.segment_len)
+        .os_id(name)
+        .create()
+        .map_err(|error| PortError::Open(PortOpenError::from_shmem(error, name, geometry)))?;
+    #[cfg(target_os = "linux")]
+    if let Err(error) = reserve_pages(name, geometry.segment_len) {
+        let kind = match error.raw_os_error() {
+            Some(code) => classify_os_error(code as u32, geometry.segment_len),
+            None => OpenFailure::MappingFailed { os_error: None },
+        };
+        // Dropping `shmem` unlinks the name again.
+        return Err(PortError::Open(PortOpenError::new(kind, name, geometry)));
+    }
+    Ok(shmem)
+}
+
+/// Makes tmpfs allocate every page of the new segment `name` now.
+///
+/// The backend only sizes the file, and tmpfs allocates a page when it is
+/// first touched: on a full `/dev/shm` the creation would succeed and the
+/// first write to the header raise SIGBUS. Writing the segment's bytes,
+/// zero as they already are, through the file fails with `ENOSPC` instead.
+/// No opener writes to the segment before the creator has set it up.
+#[cfg(target_os = "linux")]
+fn reserve_pages(name: &str, len: usize) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let path = format!("/dev/shm/{}", name.trim_start_matches('/'));
+    std::fs::OpenOptions::new().write(true).open(path)?.write_all(&vec![0; len])
+}
+
+/// Opens the named segment `name`, requiring at least
+/// `geometry.segment_len` bytes.
+pub(crate) fn open_named(name: &str, geometry: SegmentGeometry) -> Result<Shmem, PortError> {
+    let shmem = ShmemConf::new()
+        .os_id(name)
+        .open()
+        .map_err(|error| PortError::Open(PortOpenError::from_shmem(error, name, geometry)))?;
+    if shmem.len() < geometry.segment_len {
+        let details = format!("the segment is {} bytes, {} needed", shmem.len(), geometry.segment_len);
+        return Err(PortError::Open(PortOpenError::new(OpenFailure::IncompatibleLayout { details }, name, geometry)));
+    }
+    Ok(shmem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classified(error: ShmemError) -> PortOpenError {
+        PortOpenError::from_shmem(error, "/qp_classify", SegmentGeometry::PORT)
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn errnos_are_classified() {
+        let requested = SegmentGeometry::PORT.segment_len;
+        let no_space = OpenFailure::InsufficientSpace { requested, available_hint: None };
+        let cases = [
+            (ShmemError::MappingIdExists, OpenFailure::NameInUse, true),
+            (ShmemError::MapCreateFailed(17), OpenFailure::NameInUse, true),
+            (ShmemError::MapOpenFailed(2), OpenFailure::NotFound, true),
+            (ShmemError::MapOpenFailed(13), OpenFailure::PermissionDenied, false),
+            (ShmemError::MapCreateFailed(1), OpenFailure::PermissionDenied, false),
+            (ShmemError::UnknownOsError(28), no_space.clone(), true),
+            (ShmemError::MapCreateFailed(12), no_space.clone(), true),
+            (ShmemError::MapCreateFailed(24), OpenFailure::MappingFailed { os_error: Some(24) }, true),
+            (ShmemError::MapOpenFailed(5), OpenFailure::MappingFailed { os_error: Some(5) }, false),
+            (ShmemError::MapSizeZero, OpenFailure::MappingFailed { os_error: None }, false),
+            (
+                ShmemError::LinkOpenFailed(std::io::Error::from_raw_os_error(13)),
+                OpenFailure::PermissionDenied,
+                false,
+            ),
+        ];
+        for (error, kind, retryable) in cases {
+            let what = format!("{:?}", error);
+            let error = classified(error);
+            assert_eq!((&error.kind, error.is_retryable()), (&kind, retryable), "{}", what);
+            assert_eq!(error.name, "/qp_classify");
+            assert_eq!(error.geometry, SegmentGeometry::PORT);
+            assert!(error.shmem_error().is_some());
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn win32_codes_are_classified() {
+        let requested = SegmentGeometry::PORT.segment_len;
+        let no_space = OpenFailure::InsufficientSpace { requested, available_hint: None };
+        let cases = [
+            (ShmemError::MapCreateFailed(183), OpenFailure::NameInUse, true),
+            (ShmemError::MapOpenFailed(2), OpenFailure::NotFound, true),
+            (ShmemError::MapOpenFailed(5), OpenFailure::PermissionDenied, false),
+            (ShmemError::MapCreateFailed(1455), no_space.clone(), true),
+            (ShmemError::MapOpenFailed(32), OpenFailure::MappingFailed { os_error: Some(32) }, true),
+            (ShmemError::UnknownOsError(87), OpenFailure::MappingFailed { os_error: Some(87) }, false),
+        ];
+        for (error, kind, retryable) in cases {
+            let error = classified(error);
+            assert_eq!((&error.kind, error.is_retryable()), (&kind, retryable));
+        }
+    }
+
+    #[test]
+    fn display_names_the_port_and_geometry() {
+        let error = classified(ShmemError::MappingIdExists);
+        let text = format!("{}", error);
+        assert!(text.starts_with("port \"/qp_classify\" (10 messages of 256 bytes"), "{}", text);
+        assert!(text.ends_with("the name is in use"), "{}", text);
+    }
+
+    #[test]
+    fn opening_a_missing_or_short_segment() {
+        let name = format!("/qp_open_error_{}", std::process::id());
+        let missing = open_named(&name, SegmentGeometry::PORT).err().unwrap();
+        let PortError::Open(missing) = missing else { panic!("{:?}", missing) };
+        assert!(missing.kind == OpenFailure::NotFound && missing.is_retryable());
+
+        let short = SegmentGeometry { segment_len: 64, ..SegmentGeometry::PORT };
+        let _owner = create_named(&name, short).unwrap();
+        let taken = create_named(&name, short).err().unwrap();
+        assert!(matches!(&taken, PortError::Open(error) if error.kind == OpenFailure::NameInUse));
+        let PortError::Open(error) = open_named(&name, SegmentGeometry::PORT).err().unwrap() else { panic!() };
+        assert!(matches!(&error.kind, OpenFailure::IncompatibleLayout { .. }) && !error.is_retryable(), "{}", error);
+    }
+}
@@ -0,0 +1,75 @@
+//! A process-wide limit on the named ports open at once.
+//!
+//! Every named handle, made by `create()`, `open()`, `clone_reset` or
+//! `open_observer`, holds a mapping and a file descriptor until dropped,
+//! and a process that keeps thousands can run the system out of either.
+//! Each one takes a place under the limit before its segment is mapped,
+//! and fails with `PortError::TooManyOpenPorts` if there is none, and gives
+//! it back when dropped, so closing a port frees its place too.
+//!
+//! The limit is read from the environment variable `QUEUEING_PORT_MAX_OPEN`
+//! when the first named port is made, or set with `OpenPortLimit::set_max`;
+//! without either there is none. A value that is not a number is ignored.
+//! Lowering the limit below the ports already open leaves them alone and
+//! only refuses new ones.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+
+use crate::PortError;
+
+/// The environment variable the limit is read from.
+pub const MAX_OPEN_ENV: &str = "QUEUEING_PORT_MAX_OPEN";
+
+static MAX_OPEN_PORTS: AtomicUsize = AtomicUsize::new(usize::MAX);
+static OPEN_PORT_COUNT: AtomicUsize = AtomicUsize::new(0);
+static READ_ENV: Once = Once::new();
+
+fn max_open_ports() -> &'static AtomicUsize {
+    READ_ENV.call_once(|| {
+        if let Some(max) = std::env::var(MAX_OPEN_ENV).ok().and_then(|max| max.trim().parse().ok()) {
+            MAX_OPEN_PORTS.store(max, Ordering::Relaxed);
+        }
+    });
+    &MAX_OPEN_PORTS
+}
+
+/// The process's limit on open named ports, see the module documentation.
+pub struct OpenPortLimit;
+
+impl OpenPortLimit {
+    /// Limits the named ports the process may have open at once, in place
+    /// of `QUEUEING_PORT_MAX_OPEN`.
+    pub fn set_max(n: usize) {
+        max_open_ports().store(n, Ordering::Relaxed);
+    }
+
+    /// The named ports the process has open.
+    pub fn open_ports() -> usize {
+        OPEN_PORT_COUNT.load(Ordering::Relaxed)
+    }
+
+    /// The limit, `usize::MAX` if there is none.
+    pub fn max() -> usize {
+        max_open_ports().load(Ordering::Relaxed)
+    }
+}
+
+/// An open port's place under the limit, given back when dropped.
+pub(crate) struct OpenPortCharge(());
+
+impl OpenPortCharge {
+    pub(crate) fn reserve() -> Result<OpenPortCharge, PortError> {
+        let max = max_open_ports().load(Ordering::Relaxed);
+        OPEN_PORT_COUNT
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| (open < max).then_some(open + 1))
+            .map(|_| OpenPortCharge(()))
+            .map_err(|_| PortError::TooManyOpenPorts)
+    }
+}
+
+impl Drop for OpenPortCharge {
+    fn drop(&mut self) {
+        OPEN_PORT_COUNT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,120 @@
+//! What an enqueue does when the queue is full.
+//!
+//! By default it fails with `QueueError::FullBuffer`, counted in
+//! `QueueStats::rejected`, and the message stays the sender's to retry or
+//! give up. Under `OverflowPolicy::DropOldest`, set on the sending handle
+//! with `set_overflow_policy`, it makes room by dropping the oldest unpinned
+//! message instead, as a broadcast port always does, and counts it in the
+//! handle's `QueueStats::dropped_oldest`: the enqueue then succeeds unless
+//! it fails for another reason, or with `QueueError::AllPinned`. So does
+//! `close_with_summary`, counting the message in `evicted_on_close`. The
+//! batch enqueues, claimed slots and `forward_with` still stop at a full
+//! queue.
+//!
+//! Dropping consumes the oldest message as a dequeue would, so the handle
+//! does it as the port's reader, and fails with `QueueError::ReaderBusy`
+//! where another handle claimed that end. The two ends of a queue are
+//! otherwise not synchronized against each other, so while a handle drops,
+//! no other may dequeue: the policy suits a port one handle both writes and
+//! reads, or one whose handles take turns under a lock.
+
+use core::sync::atomic::Ordering;
+
+use crate::{QueueError, QueueingPort};
+
+/// What an enqueue on a full queue does, see the `overflow` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Fail with `QueueError::FullBuffer`.
+    #[default]
+    Reject,
+    /// Drop the oldest unpinned message to make room.
+    DropOldest,
+}
+
+impl QueueingPort {
+    /// Sets what this handle's enqueues do on a full queue; see the
+    /// `overflow` module.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow_policy = policy;
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    /// Makes room in the full queue under `DropOldest`, or counts the
+    /// enqueue rejected and fails it.
+    pub(crate) fn handle_overflow(&self) -> Result<(), QueueError> {
+        match self.overflow_policy {
+            OverflowPolicy::Reject => {
+                let header = &self.segment().header;
+                header.rejected.fetch_add(header.byte_order(), 1, Ordering::Relaxed);
+                Err(QueueError::FullBuffer)
+            }
+            OverflowPolicy::DropOldest => {
+                self.evict_unpinned()?;
+                self.dropped_oldest.set(self.dropped_oldest.get() + 1);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, PortConfig, MSGS, SIZE};
+
+    fn tagged(tag: u8) -> Message {
+        Message([tag; SIZE])
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_messages() {
+        let mut port = QueueingPort::new();
+        port.set_overflow_policy(OverflowPolicy::DropOldest);
+        for tag in 0..MSGS as u8 + 5 {
+            port.enqueue(tagged(tag)).unwrap();
+        }
+        let stats = port.stats();
+        assert_eq!((port.len(), stats.dropped_oldest, stats.rejected, stats.dequeued), (MSGS, 5, 0, 5));
+        for tag in 5..MSGS as u8 + 5 {
+            assert_eq!(port.dequeue().unwrap().0, [tag; SIZE]);
+        }
+
+        port.set_overflow_policy(OverflowPolicy::Reject);
+        while port.enqueue(tagged(0)).is_ok() {}
+        assert_eq!(port.stats().rejected, 1);
+    }
+
+    #[test]
+    fn drop_oldest_passes_over_pins() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().pin_limit(1));
+        port.set_overflow_policy(OverflowPolicy::DropOldest);
+        port.enqueue_pinned(tagged(0)).unwrap();
+        for tag in 1..MSGS as u8 + 1 {
+            port.enqueue(tagged(tag)).unwrap();
+        }
+        assert_eq!(port.dequeue().unwrap().0, [0; SIZE], "the pinned message stays first");
+        assert_eq!(port.dequeue().unwrap().0, [2; SIZE], "1 was dropped");
+    }
+
+    #[test]
+    fn dropping_needs_the_reader_end() {
+        let segment = Box::new(crate::Segment::new());
+        let base = core::ptr::NonNull::from(&*segment);
+        let mut writer = unsafe { QueueingPort::attach(base) };
+        let mut reader = unsafe { QueueingPort::attach(base) };
+        reader.claim_reader(false).unwrap();
+        writer.set_overflow_policy(OverflowPolicy::DropOldest);
+        while writer.len() < MSGS {
+            writer.enqueue(tagged(1)).unwrap();
+        }
+        assert!(matches!(writer.enqueue(tagged(2)), Err(QueueError::ReaderBusy { .. })));
+        assert_eq!(writer.stats().dropped_oldest, 0);
+        drop(reader);
+        writer.enqueue(tagged(2)).unwrap();
+        assert_eq!(writer.stats().dropped_oldest, 1);
+    }
+}
@@ -0,0 +1,321 @@
+//! Making sure a port has one writer and one reader.
+//!
+//! `claim_writer` stores a token naming the handle, its process id and a
+//! random nonce, in the header's `writer_owner` field; `claim_reader` does
+//! the same in `reader_owner`. While an end is claimed, other handles fail
+//! to use it with `QueueError::WriterBusy` or `QueueError::ReaderBusy`,
+//! which name the owner, and so do their own claims. Ends nobody claimed
+//! stay open to every handle, as before claims existed. The writer of a
+//! broadcast port may still drop the oldest message, as such a port does
+//! on its own.
+//!
+//! A claim is given up by `release_writer` / `release_reader`, by `close`
+//! and when the handle is dropped. One left by a process that died can be
+//! taken over with `force`, which also moves the segment to a new
+//! generation: every other handle then fails with
+//! `QueueError::StaleSegment`, the old owner for good and the peer until
+//! it calls `reattach`. A claim is only taken over if its owner is not
+//! known to be alive. On Linux that is asked of `/proc`; elsewhere only a
+//! handle in this process is known to be alive, and `force` takes over
+//! from any other.
+//!
+//! The nonce is claimed first and the process id written after it, so a
+//! token with process id 0 is a claim being made or given up and counts
+//! as alive.
+
+use core::sync::atomic::Ordering;
+
+use crate::byteorder::WireU32;
+use crate::{PeerRole, QueueError, QueueingPort};
+
+/// One end's claim in the segment header.
+#[repr(C)]
+pub(crate) struct OwnerField {
+    /// Random and non-zero while the end is claimed.
+    nonce: WireU32,
+    pid: WireU32,
+}
+
+impl OwnerField {
+    pub(crate) const fn new() -> OwnerField {
+        OwnerField { nonce: WireU32::zero(), pid: WireU32::zero() }
+    }
+}
+
+/// The handle holding one end of a port, see `claim_writer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OwnerId {
+    pub pid: u32,
+    pub nonce: u32,
+}
+
+impl QueueingPort {
+    /// Makes this handle the port's only writer; see the module
+    /// documentation. Fails with `QueueError::WriterBusy` if another handle
+    /// holds the claim, unless `force` is set and that handle is not known
+    /// to be alive. Claiming again through the owner does nothing.
+    #[cfg(feature = "std")]
+    pub fn claim_writer(&mut self, force: bool) -> Result<(), QueueError> {
+        self.claim(PeerRole::Sender, force)
+    }
+
+    /// Makes this handle the port's only reader, as `claim_writer` does for
+    /// the writer; fails with `QueueError::ReaderBusy`.
+    #[cfg(feature = "std")]
+    pub fn claim_reader(&mut self, force: bool) -> Result<(), QueueError> {
+        self.claim(PeerRole::Receiver, force)
+    }
+
+    /// Gives up this handle's writer claim, if it has one.
+    pub fn release_writer(&mut self) {
+        self.release(PeerRole::Sender);
+    }
+
+    /// Gives up this handle's reader claim, if it has one.
+    pub fn release_reader(&mut self) {
+        self.release(PeerRole::Receiver);
+    }
+
+    /// The handle holding the writer claim, if any.
+    pub fn writer_owner(&self) -> Option<OwnerId> {
+        self.owner(PeerRole::Sender)
+    }
+
+    /// The handle holding the reader claim, if any.
+    pub fn reader_owner(&self) -> Option<OwnerId> {
+        self.owner(PeerRole::Receiver)
+    }
+
+    /// Fails if another handle claimed the `role` end. The writer gets past
+    /// the reader's claim, to drop the oldest message.
+    pub(crate) fn check_owner(&self, role: PeerRole) -> Result<(), QueueError> {
+        if role == PeerRole::Receiver && self.owns(PeerRole::Sender) {
+            return Ok(());
+        }
+        match self.owner(role) {
+            Some(owner) if owner.nonce != self.nonce(role) => Err(busy(role, owner)),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn claim(&mut self, role: PeerRole, force: bool) -> Result<(), QueueError> {
+        let field = self.owner_field(role);
+        let order = self.byte_order();
+        let mine = match self.nonce(role) {
+            0 => fresh_nonce(),
+            nonce => nonce,
+        };
+        match field.nonce.compare_exchange(order, 0, mine, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => field.pid.store(order, std::process::id(), Ordering::Release),
+            Err(nonce) if nonce == mine => return Ok(()),
+            Err(nonce) => {
+                let pid = field.pid.load(order, Ordering::Acquire);
+                let owner = OwnerId { pid, nonce };
+                // Taking the process id away settles a race between two
+                // handles forcing at once.
+                if !force
+                    || pid == 0
+                    || process_alive(pid)
+                    || field.pid.compare_exchange(order, pid, 0, Ordering::AcqRel, Ordering::Relaxed).is_err()
+                {
+                    return Err(busy(role, owner));
+                }
+                field.nonce.store(order, mine, Ordering::Release);
+                field.pid.store(order, std::process::id(), Ordering::Release);
+                self.stamp_generation();
+            }
+        }
+        self.owner_nonces[role_index(role)] = mine;
+        Ok(())
+    }
+
+    fn release(&mut self, role: PeerRole) {
+        let mine = core::mem::take(&mut self.owner_nonces[role_index(role)]);
+        let field = self.owner_field(role);
+        let order = self.byte_order();
+        if mine != 0 && field.nonce.load(order, Ordering::Relaxed) == mine {
+            // Process id first, so the claim never names a process it is
+            // not held by.
+            field.pid.store(order, 0, Ordering::Relaxed);
+            field.nonce.store(order, 0, Ordering::Release);
+        }
+    }
+
+    /// Gives up both of this handle's claims.
+    pub(crate) fn release_claims(&mut self) {
+        self.release(PeerRole::Sender);
+        self.release(PeerRole::Receiver);
+    }
+
+    fn owner(&self, role: PeerRole) -> Option<OwnerId> {
+        let field = self.owner_field(role);
+        let order = self.byte_order();
+        let nonce = field.nonce.load(order, Ordering::Acquire);
+        (nonce != 0).then(|| OwnerId { pid: field.pid.load(order, Ordering::Acquire), nonce })
+    }
+
+    fn owner_field(&self, role: PeerRole) -> &OwnerField {
+        let header = &self.segment().header;
+        match role {
+            PeerRole::Sender => &header.writer_owner,
+            PeerRole::Receiver => &header.reader_owner,
+        }
+    }
+
+    fn owns(&self, role: PeerRole) -> bool {
+        self.nonce(role) != 0 && self.owner(role).is_some_and(|owner| owner.nonce == self.nonce(role))
+    }
+
+    fn nonce(&self, role: PeerRole) -> u32 {
+        self.owner_nonces[role_index(role)]
+    }
+}
+
+impl Drop for QueueingPort {
+    fn drop(&mut self) {
+        // Before the mapping goes, which happens after this.
+        #[cfg(feature = "shmem")]
+        self.begin_detach();
+        self.release_claims();
+        #[cfg(feature = "shmem")]
+        self.finish_detach();
+    }
+}
+
+fn role_index(role: PeerRole) -> usize {
+    match role {
+        PeerRole::Sender => 0,
+        PeerRole::Receiver => 1,
+    }
+}
+
+fn busy(role: PeerRole, owner: OwnerId) -> QueueError {
+    match role {
+        PeerRole::Sender => QueueError::WriterBusy { owner },
+        PeerRole::Receiver => QueueError::ReaderBusy { owner },
+    }
+}
+
+#[cfg(feature = "std")]
+fn fresh_nonce() -> u32 {
+    use std::hash::{BuildHasher, Hasher};
+
+    // Every `RandomState` is keyed afresh.
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    (hasher.finish() as u32).max(1)
+}
+
+/// Whether process `pid` is known to be running.
+#[cfg(feature = "std")]
+pub(crate) fn process_alive(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return true;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        // A zombie has exited; only its parent has not collected it yet.
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat.rsplit_once(") ").is_some_and(|(_, rest)| rest.starts_with('Z')),
+            Err(_) => false,
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, Segment, MSGS, SIZE};
+    use core::ptr::NonNull;
+
+    fn shared_segment() -> NonNull<Segment> {
+        // Leaked, so it outlives the handles.
+        NonNull::from(Box::leak(Box::new(Segment::new())))
+    }
+
+    #[test]
+    fn second_writer_is_rejected() {
+        let segment = shared_segment();
+        let mut writer = unsafe { QueueingPort::attach(segment) };
+        let mut intruder = unsafe { QueueingPort::attach(segment) };
+        let mut reader = unsafe { QueueingPort::attach(segment) };
+        writer.claim_writer(false).unwrap();
+        writer.claim_writer(false).unwrap();
+        let owner = writer.writer_owner().unwrap();
+        assert_eq!(owner.pid, std::process::id());
+        assert_eq!(intruder.writer_owner(), Some(owner));
+
+        assert!(matches!(intruder.claim_writer(false), Err(QueueError::WriterBusy { owner: o }) if o == owner));
+        assert!(matches!(intruder.claim_writer(true), Err(QueueError::WriterBusy { .. })), "the owner is alive");
+        assert!(matches!(intruder.enqueue(Message([1; SIZE])), Err(QueueError::WriterBusy { .. })));
+        assert!(matches!(intruder.claim_write_slice(), Err(QueueError::WriterBusy { .. })));
+        assert_eq!(intruder.enqueue_from_iter([Message([1; SIZE])].into_iter()).0, 0);
+        writer.enqueue(Message([2; SIZE])).unwrap();
+
+        reader.claim_reader(false).unwrap();
+        assert!(matches!(intruder.try_dequeue(), Err(QueueError::ReaderBusy { .. })));
+        assert_eq!(reader.dequeue().unwrap().0, [2; SIZE]);
+        assert_eq!(reader.stats().enqueued, 1, "the intruder wrote nothing");
+
+        // The summary waits for the reader to make room rather than take
+        // its place.
+        for tag in 0..MSGS as u8 {
+            writer.enqueue(Message([tag; SIZE])).unwrap();
+        }
+        assert!(matches!(writer.close_with_summary(b"done"), Err(QueueError::FullBuffer)));
+        assert_eq!(reader.dequeue().unwrap().0, [0; SIZE]);
+        writer.close_with_summary(b"done").unwrap();
+
+        writer.release_writer();
+        assert_eq!(writer.writer_owner(), None);
+        intruder.claim_writer(false).unwrap();
+        drop(intruder);
+        drop(reader);
+        assert_eq!((writer.writer_owner(), writer.reader_owner()), (None, None), "released on drop");
+    }
+
+    /// A process id that no longer names a process.
+    #[cfg(unix)]
+    fn dead_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dead_owner_is_taken_over() {
+        let segment = shared_segment();
+        let mut old = unsafe { QueueingPort::attach(segment) };
+        let mut reader = unsafe { QueueingPort::attach(segment) };
+        old.claim_writer(false).unwrap();
+        old.enqueue(Message([1; SIZE])).unwrap();
+        // As if `old` lived in a process that died.
+        let dead = dead_pid();
+        old.owner_field(PeerRole::Sender).pid.store(old.byte_order(), dead, Ordering::Relaxed);
+
+        let mut new = unsafe { QueueingPort::attach(segment) };
+        assert!(matches!(new.claim_writer(false), Err(QueueError::WriterBusy { owner }) if owner.pid == dead));
+        new.claim_writer(true).unwrap();
+        assert_eq!(new.writer_owner().unwrap().pid, std::process::id());
+        assert_ne!(new.segment_generation(), old.segment_generation());
+        new.enqueue(Message([2; SIZE])).unwrap();
+
+        // The old writer is locked out, and the reader must move on.
+        assert!(matches!(old.enqueue(Message([3; SIZE])), Err(QueueError::StaleSegment)));
+        assert!(matches!(reader.try_dequeue(), Err(QueueError::StaleSegment)));
+        reader.reattach().unwrap();
+        assert_eq!(reader.dequeue().unwrap().0, [1; SIZE]);
+        assert_eq!(reader.dequeue().unwrap().0, [2; SIZE]);
+        old.reattach().unwrap();
+        assert!(matches!(old.enqueue(Message([3; SIZE])), Err(QueueError::WriterBusy { .. })));
+        drop(old);
+        assert!(new.writer_owner().is_some(), "a stale handle cannot release the new claim");
+    }
+}
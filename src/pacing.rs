@@ -0,0 +1,146 @@
+//! Dequeueing at a fixed rate, for consumers run as a periodic loop.
+//!
+//! A `PacedPort` wraps a `DequeuePort` and lets its `dequeue` through at
+//! most `rate_hz` times a second: each call has a slot on the schedule, one
+//! period after the one before, and a call made before its slot waits for
+//! it, pausing on the `Clock` between polls as the port's waits do. The
+//! first call sets the schedule going.
+//!
+//! A call made after its slot goes through at once. How late it is counts
+//! as jitter, and so does how far past its slot a waiting call woke;
+//! `deadline_jitter_ns` is the most seen. A call a whole period late or
+//! more missed its deadline, the next slot: it counts in `miss_count`, and
+//! the schedule starts over from it rather than letting the calls after it
+//! through back to back to catch up.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{ClockRef, DequeuePort, Message, QueueError};
+
+/// A port dequeued from no faster than a set rate, see the `pacing` module.
+pub struct PacedPort<P> {
+    port: P,
+    clock: ClockRef,
+    period_ns: u64,
+    /// The slot of the next call, `None` before the first.
+    next_ns: Option<u64>,
+    misses: AtomicU64,
+    max_jitter_ns: AtomicU64,
+}
+
+impl<P: DequeuePort> PacedPort<P> {
+    /// Paces `port` to `rate_hz` dequeues a second on `clock`; `rate_hz`
+    /// must not be 0.
+    pub fn new(port: P, clock: ClockRef, rate_hz: u32) -> PacedPort<P> {
+        assert!(rate_hz > 0, "a paced port needs a rate");
+        PacedPort {
+            port,
+            clock,
+            period_ns: 1_000_000_000 / u64::from(rate_hz),
+            next_ns: None,
+            misses: AtomicU64::new(0),
+            max_jitter_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Calls that came a period or more after their slot.
+    pub fn miss_count(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// The furthest past its slot a call has gone through so far.
+    pub fn deadline_jitter_ns(&self) -> u64 {
+        self.max_jitter_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn period_ns(&self) -> u64 {
+        self.period_ns
+    }
+
+    pub fn port(&self) -> &P {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+
+    /// Waits for the slot of this call and moves the schedule on.
+    fn pace(&mut self) {
+        let mut now = self.clock.now_ns();
+        let slot = *self.next_ns.get_or_insert(now);
+        while now < slot {
+            self.clock.pause(slot);
+            now = self.clock.now_ns();
+        }
+        let jitter = now - slot;
+        self.max_jitter_ns.fetch_max(jitter, Ordering::Relaxed);
+        let next = if jitter >= self.period_ns {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            now
+        } else {
+            slot
+        };
+        self.next_ns = Some(next.saturating_add(self.period_ns));
+    }
+}
+
+impl<P: DequeuePort> DequeuePort for PacedPort<P> {
+    /// Waits for the call's slot, then dequeues from the inner port; its
+    /// errors are passed on, and the call still took its slot.
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.pace();
+        self.port.dequeue()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, MockClock, QueueingPort, SimulatedClock, SIZE};
+    use std::sync::Arc;
+
+    const PERIOD_NS: u64 = 10_000_000;
+
+    #[test]
+    fn a_100hz_loop_on_time_misses_nothing() {
+        let clock = Arc::new(MockClock::new(5_000));
+        let mut paced = PacedPort::new(QueueingPort::new(), clock.clone(), 100);
+        assert_eq!(paced.period_ns(), PERIOD_NS);
+        for cycle in 0..1000u32 {
+            paced.port_mut().enqueue(Message([cycle as u8; SIZE])).unwrap();
+            assert_eq!(paced.dequeue().unwrap().0[0], cycle as u8);
+            clock.advance(PERIOD_NS);
+        }
+        assert_eq!(paced.miss_count(), 0);
+        assert_eq!(paced.deadline_jitter_ns(), 0);
+    }
+
+    #[test]
+    fn early_calls_wait_and_late_ones_miss() {
+        let clock = Arc::new(SimulatedClock::with_step(0, 1_000_000));
+        let mut paced = PacedPort::new(QueueingPort::new(), clock.clone(), 100);
+        assert!(matches!(paced.dequeue(), Err(QueueError::EmptyBuffer)), "the inner port's error");
+        // Called again at once, so held until its slot.
+        let _ = paced.dequeue();
+        assert_eq!(clock.now_ns(), PERIOD_NS);
+
+        clock.advance(PERIOD_NS + 3_000);
+        let _ = paced.dequeue();
+        assert_eq!(paced.miss_count(), 0, "late, but before the next slot");
+        assert_eq!(paced.deadline_jitter_ns(), 3_000);
+
+        clock.advance(3 * PERIOD_NS);
+        let _ = paced.dequeue();
+        assert_eq!(paced.miss_count(), 1);
+        // The schedule starts over from the late call.
+        let late = clock.now_ns();
+        let _ = paced.dequeue();
+        assert_eq!(clock.now_ns(), late + PERIOD_NS);
+        assert_eq!(paced.miss_count(), 1);
+    }
+}
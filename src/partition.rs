@@ -0,0 +1,147 @@
+//! Messages held for the partition whose time window they belong to, as
+//! under ARINC 653 partitioned scheduling.
+//!
+//! The partition whose window is open is kept in a process-wide word,
+//! moved on by `PartitionScheduler::advance_partition`, which stands in for
+//! the module's scheduler. A `PartitionScheduler` wraps a port and tags
+//! every message enqueued through it with the partition current at the
+//! time, in its `MessageHeader`'s `dst_id`; partition ids are therefore at
+//! most `u16::MAX`.
+//!
+//! `dequeue_for_partition` takes the oldest message tagged for the given
+//! partition, moving it to the front of the queue past the others as
+//! `dequeue_fair` does, and leaves the rest queued for their own windows.
+//! It looks no further than the skip budget, and on a
+//! `DeliveryOrder::StrictFifo` port only at the front. The message is
+//! dequeued as by `dequeue_unfiltered`: the partition is the filter.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{slot_index, trace, DeliveryOrder, Message, MessageHeader, QueueError, QueueingPort, MSGS, SIZE};
+
+/// The partition whose window is open.
+static CURRENT_PARTITION: AtomicU32 = AtomicU32::new(0);
+
+fn tag_of(slot: &[u8; SIZE]) -> u16 {
+    MessageHeader::read(slot).dst_id
+}
+
+/// A port whose messages are tagged with, and dequeued by, partition; see
+/// the `partition` module.
+pub struct PartitionScheduler {
+    port: QueueingPort,
+}
+
+impl PartitionScheduler {
+    pub fn new(port: QueueingPort) -> PartitionScheduler {
+        PartitionScheduler { port }
+    }
+
+    /// Opens the window of partition `id`. Panics if `id` does not fit a
+    /// message header's `dst_id`.
+    pub fn advance_partition(id: u32) {
+        assert!(id <= u32::from(u16::MAX), "partition ids are at most u16::MAX");
+        CURRENT_PARTITION.store(id, Ordering::Release);
+    }
+
+    /// The partition whose window is open; 0 until the first
+    /// `advance_partition`.
+    pub fn current_partition() -> u32 {
+        CURRENT_PARTITION.load(Ordering::Acquire)
+    }
+
+    /// Enqueues `message` tagged with the current partition, overwriting
+    /// its header's `dst_id`.
+    pub fn enqueue(&mut self, mut message: Message) -> Result<(), QueueError> {
+        let mut header = MessageHeader::read(&message.0);
+        header.dst_id = PartitionScheduler::current_partition() as u16;
+        header.write(&mut message.0);
+        self.port.enqueue(message)
+    }
+
+    /// The oldest message tagged for `partition_id`, leaving the others
+    /// queued. Fails with `QueueError::EmptyBuffer` if there is none in
+    /// view.
+    pub fn dequeue_for_partition(&mut self, partition_id: u32) -> Result<Message, QueueError> {
+        let Ok(tag) = u16::try_from(partition_id) else {
+            return Err(QueueError::EmptyBuffer);
+        };
+        trace::dequeue(&mut self.port, |port| {
+            if !port.bring_partition_forward(tag) {
+                return Err(QueueError::EmptyBuffer);
+            }
+            port.consume_front(|slot| Message(*slot))
+        })
+    }
+
+    pub fn port(&self) -> &QueueingPort {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut QueueingPort {
+        &mut self.port
+    }
+
+    pub fn into_inner(self) -> QueueingPort {
+        self.port
+    }
+}
+
+impl QueueingPort {
+    /// Moves the oldest message tagged `tag` to the front of the queue;
+    /// false if there is none in view.
+    fn bring_partition_forward(&self, tag: u16) -> bool {
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        header.wait_while_compacting();
+        let strict = header.delivery_order.load(Ordering::Relaxed) == DeliveryOrder::StrictFifo as u8;
+        let count = header.message_count.load(order, Ordering::Acquire) as usize;
+        let in_view = if strict { 1 } else { self.skip_budget };
+        let read_index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize);
+        let found = (0..count.min(MSGS).min(in_view)).find(|&ahead| {
+            // Occupied slots belong to the reader.
+            let slot = unsafe { &*segment.slot(slot_index(read_index + ahead)).cast::<[u8; SIZE]>() };
+            tag_of(slot) == tag
+        });
+        match found {
+            Some(0) => true,
+            Some(ahead) => {
+                self.move_to_front(read_index, ahead);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_wait_for_their_partition_window() {
+        let mut port = PartitionScheduler::new(QueueingPort::new());
+        PartitionScheduler::advance_partition(1);
+        port.enqueue(Message([1; SIZE])).unwrap();
+        PartitionScheduler::advance_partition(2);
+        port.enqueue(Message([2; SIZE])).unwrap();
+        port.enqueue(Message([3; SIZE])).unwrap();
+        PartitionScheduler::advance_partition(1);
+        port.enqueue(Message([4; SIZE])).unwrap();
+
+        // Partition 1's reader passes over partition 2's messages.
+        let tags = |message: Message| (MessageHeader::read(&message.0).dst_id, message.0[SIZE - 1]);
+        assert_eq!(tags(port.dequeue_for_partition(1).unwrap()), (1, 1));
+        assert_eq!(tags(port.dequeue_for_partition(1).unwrap()), (1, 4));
+        assert!(matches!(port.dequeue_for_partition(1), Err(QueueError::EmptyBuffer)));
+        assert_eq!(port.port().len(), 2, "held for partition 2");
+
+        PartitionScheduler::advance_partition(2);
+        assert_eq!(PartitionScheduler::current_partition(), 2);
+        assert_eq!(tags(port.dequeue_for_partition(2).unwrap()), (2, 2));
+        assert_eq!(tags(port.dequeue_for_partition(2).unwrap()), (2, 3));
+        assert!(port.port().is_empty());
+        assert!(matches!(port.dequeue_for_partition(u32::MAX), Err(QueueError::EmptyBuffer)));
+    }
+}
@@ -0,0 +1,167 @@
+//! The ARINC 653 status of the partition a port belongs to.
+//!
+//! ARINC 653 has a port report on the health of its partition: the
+//! partition's operating mode, its lock level and how it was last started.
+//! The segment header keeps them in the `partition_status` word, which the
+//! layer that runs the partition, a hypervisor or the OS, sets with
+//! `set_partition_status` and any handle on the port reads with
+//! `partition_status`. All three change together in one atomic store, so a
+//! reader never sees half of an update. An all-zero header reads as an idle
+//! partition, lock level 0, started normally.
+//!
+//! The values are the ones of ARINC 653 Part 1, `OPERATING_MODE_TYPE` and
+//! `START_CONDITION_TYPE`; the `ffi` module has them as `QP_*` constants.
+
+use core::sync::atomic::Ordering;
+
+use crate::QueueingPort;
+
+/// A partition's operating mode, `OPERATING_MODE_TYPE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum OperatingMode {
+    /// Not running, shut down or not yet started.
+    #[default]
+    Idle = 0,
+    /// Being initialized after a cold start.
+    ColdStart = 1,
+    /// Being initialized after a warm start, with memory kept.
+    WarmStart = 2,
+    /// Initialized and scheduled.
+    Normal = 3,
+}
+
+/// Why a partition was last started, `START_CONDITION_TYPE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum StartCondition {
+    /// At power-on.
+    #[default]
+    NormalStart = 0,
+    /// By the partition itself, through `SET_PARTITION_MODE`.
+    PartitionRestart = 1,
+    /// By health monitoring restarting the module.
+    HmModuleRestart = 2,
+    /// By health monitoring restarting the partition.
+    HmPartitionRestart = 3,
+}
+
+/// A partition's status, see the `partition_status` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PartitionStatus {
+    pub operating_mode: OperatingMode,
+    /// How many times the partition has preemption disabled; 0 when it may
+    /// be preempted.
+    pub lock_level: u8,
+    pub start_condition: StartCondition,
+}
+
+impl OperatingMode {
+    fn from_bits(bits: u8) -> Option<OperatingMode> {
+        Some(match bits {
+            0 => OperatingMode::Idle,
+            1 => OperatingMode::ColdStart,
+            2 => OperatingMode::WarmStart,
+            3 => OperatingMode::Normal,
+            _ => return None,
+        })
+    }
+}
+
+impl StartCondition {
+    fn from_bits(bits: u8) -> Option<StartCondition> {
+        Some(match bits {
+            0 => StartCondition::NormalStart,
+            1 => StartCondition::PartitionRestart,
+            2 => StartCondition::HmModuleRestart,
+            3 => StartCondition::HmPartitionRestart,
+            _ => return None,
+        })
+    }
+}
+
+impl PartitionStatus {
+    /// The mode in bits 0..8, the lock level in 8..16 and the start
+    /// condition in 16..24, as in the header word.
+    fn to_word(self) -> u32 {
+        self.operating_mode as u32 | (self.lock_level as u32) << 8 | (self.start_condition as u32) << 16
+    }
+
+    /// `None` for a mode or start condition this build does not know, or
+    /// anything set in the top byte.
+    fn from_word(word: u32) -> Option<PartitionStatus> {
+        let [mode, lock_level, start, 0] = word.to_le_bytes() else {
+            return None;
+        };
+        Some(PartitionStatus {
+            operating_mode: OperatingMode::from_bits(mode)?,
+            lock_level,
+            start_condition: StartCondition::from_bits(start)?,
+        })
+    }
+}
+
+impl QueueingPort {
+    /// The status of the port's partition, set by `set_partition_status`.
+    /// `None` if the header holds values this build does not know, written
+    /// by another implementation.
+    pub fn partition_status(&self) -> Option<PartitionStatus> {
+        let header = &self.segment().header;
+        PartitionStatus::from_word(header.partition_status.load(header.byte_order(), Ordering::Acquire))
+    }
+
+    /// Records the status of the port's partition for every handle on it
+    /// to read. For the layer that runs the partition, which calls it as
+    /// the partition changes mode.
+    pub fn set_partition_status(&mut self, status: PartitionStatus) {
+        let header = &self.segment().header;
+        header.partition_status.store(header.byte_order(), status.to_word(), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [OperatingMode; 4] =
+        [OperatingMode::Idle, OperatingMode::ColdStart, OperatingMode::WarmStart, OperatingMode::Normal];
+    const STARTS: [StartCondition; 4] = [
+        StartCondition::NormalStart,
+        StartCondition::PartitionRestart,
+        StartCondition::HmModuleRestart,
+        StartCondition::HmPartitionRestart,
+    ];
+
+    #[test]
+    fn every_status_round_trips_through_the_header() {
+        let mut port = QueueingPort::new();
+        assert_eq!(port.partition_status(), Some(PartitionStatus::default()));
+        for operating_mode in MODES {
+            for start_condition in STARTS {
+                for lock_level in [0, 1, u8::MAX] {
+                    let status = PartitionStatus { operating_mode, lock_level, start_condition };
+                    port.set_partition_status(status);
+                    assert_eq!(port.partition_status(), Some(status));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_values_read_as_none() {
+        let port = QueueingPort::new();
+        let header = &port.segment().header;
+        let order = header.byte_order();
+        for word in [4, 4 << 16, 1 << 24] {
+            header.partition_status.store(order, word, Ordering::Relaxed);
+            assert_eq!(port.partition_status(), None, "{:#x}", word);
+        }
+        header.partition_status.store(order, 3 | 2 << 8 | 1 << 16, Ordering::Relaxed);
+        let status = PartitionStatus {
+            operating_mode: OperatingMode::Normal,
+            lock_level: 2,
+            start_condition: StartCondition::PartitionRestart,
+        };
+        assert_eq!(port.partition_status(), Some(status));
+    }
+}
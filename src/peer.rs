@@ -0,0 +1,146 @@
+//! Knowing whether the other end of a port has attached.
+//!
+//! Each end announces itself by setting its bit in the header's `peers`
+//! word; named ports do so in `create` (sender) and `open` (receiver), and
+//! handles on attached segments call `announce`. The bits are never
+//! cleared, so they record that an end attached at least once, not that it
+//! is still there.
+//!
+//! A port created with `PortConfig::require_receiver` refuses enqueues
+//! with `QueueError::NoPeer` until its receiver has announced itself, so
+//! nothing is queued before anyone listens.
+
+use core::sync::atomic::Ordering;
+
+use crate::{QueueError, QueueingPort};
+
+pub(crate) const SENDER_ATTACHED: u32 = 1 << 0;
+pub(crate) const RECEIVER_ATTACHED: u32 = 1 << 1;
+pub(crate) const REQUIRE_RECEIVER: u32 = 1 << 8;
+
+/// Which end of a port a handle is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerRole {
+    Sender,
+    Receiver,
+}
+
+impl PeerRole {
+    fn bit(self) -> u32 {
+        match self {
+            PeerRole::Sender => SENDER_ATTACHED,
+            PeerRole::Receiver => RECEIVER_ATTACHED,
+        }
+    }
+
+    fn peer(self) -> PeerRole {
+        match self {
+            PeerRole::Sender => PeerRole::Receiver,
+            PeerRole::Receiver => PeerRole::Sender,
+        }
+    }
+}
+
+impl QueueingPort {
+    /// Marks this handle as the port's `role` end in the segment header,
+    /// releasing a peer blocked in `wait_for_peer`.
+    pub fn announce(&mut self, role: PeerRole) {
+        self.role = Some(role);
+        self.segment().header.peers.fetch_or(self.byte_order(), role.bit(), Ordering::Release);
+    }
+
+    /// Whether the other end has attached. A handle that has not announced
+    /// a role counts as the sender.
+    pub fn peer_present(&self) -> bool {
+        let peer = self.role.unwrap_or(PeerRole::Sender).peer();
+        self.segment().header.peers.load(self.byte_order(), Ordering::Acquire) & peer.bit() != 0
+    }
+
+    /// Waits until `peer_present` or the port's clock reaches `deadline_ns`,
+    /// failing with `QueueError::NoPeer` in the latter case.
+    pub fn wait_for_peer(&self, deadline_ns: u64) -> Result<(), QueueError> {
+        loop {
+            if self.peer_present() {
+                return Ok(());
+            }
+            if self.clock().now_ns() >= deadline_ns {
+                return Err(QueueError::NoPeer);
+            }
+            self.clock().pause(deadline_ns);
+        }
+    }
+
+    /// False while a port created with `require_receiver` has no receiver.
+    pub(crate) fn receiver_admits(&self) -> bool {
+        let peers = self.segment().header.peers.load(self.byte_order(), Ordering::Acquire);
+        peers & REQUIRE_RECEIVER == 0 || peers & RECEIVER_ATTACHED != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, MockClock, PortConfig, Segment, SIZE};
+    use core::ptr::NonNull;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn segment(config: &PortConfig) -> NonNull<Segment> {
+        // Leaked, so it outlives the handles.
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        config.apply(&mut unsafe { QueueingPort::attach(segment) });
+        segment
+    }
+
+    #[test]
+    fn sender_waits_until_receiver_attaches() {
+        let segment = segment(&PortConfig::new());
+        let mut sender = unsafe { QueueingPort::attach(segment) };
+        sender.announce(PeerRole::Sender);
+        assert!(!sender.peer_present());
+
+        let mut receiver = unsafe { QueueingPort::attach(segment) };
+        let attacher = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            receiver.announce(PeerRole::Receiver);
+            assert!(receiver.peer_present());
+        });
+        sender.wait_for_peer(u64::MAX).unwrap();
+        assert!(sender.peer_present());
+        attacher.join().unwrap();
+    }
+
+    #[test]
+    fn wait_times_out_without_peer() {
+        let clock = Arc::new(MockClock::new(0));
+        let mut port = QueueingPort::with_config(&PortConfig::new().clock(clock.clone()));
+        port.announce(PeerRole::Receiver);
+        assert!(matches!(port.wait_for_peer(0), Err(QueueError::NoPeer)));
+
+        clock.advance(100);
+        assert!(matches!(port.wait_for_peer(50), Err(QueueError::NoPeer)));
+        port.announce(PeerRole::Sender);
+        assert!(port.wait_for_peer(50).is_ok(), "a present peer needs no time");
+    }
+
+    #[test]
+    fn enqueue_rejected_until_receiver_attaches() {
+        let segment = segment(&PortConfig::new().require_receiver());
+        let mut sender = unsafe { QueueingPort::attach(segment) };
+        assert!(matches!(sender.enqueue(Message([1; SIZE])), Err(QueueError::NoPeer)));
+        assert_eq!(sender.enqueue_from_iter(core::iter::once(Message([1; SIZE]))).0, 0);
+        assert!(sender.is_empty());
+        assert!(matches!(sender.claim_write_slice(), Err(QueueError::NoPeer)));
+        let mut other = QueueingPort::new();
+        other.enqueue(Message([3; SIZE])).unwrap();
+        assert!(matches!(other.forward_with(&mut sender, |_| SIZE), Err(QueueError::NoPeer)));
+        assert_eq!(other.len(), 1, "the message stays at the source");
+        assert_eq!(sender.stats().rejected, 0, "not a full-queue rejection");
+
+        let mut receiver = unsafe { QueueingPort::attach(segment) };
+        receiver.announce(PeerRole::Receiver);
+        drop(receiver);
+        sender.enqueue(Message([2; SIZE])).unwrap();
+        assert_eq!(sender.len(), 1);
+    }
+}
@@ -0,0 +1,260 @@
+//! Keeping chosen messages through drop-oldest evictions.
+//!
+//! `enqueue_pinned`, and `Port<Broadcast>::publish_pinned`, queue a message
+//! with its slot's bit set in the header's `pinned` mask. Where the port
+//! drops its oldest message to make room, in `Port<Broadcast>::publish` and
+//! `Port<Broadcast>::close_with_summary` on a full port, and in the enqueues
+//! of a handle set to `OverflowPolicy::DropOldest`, it drops the oldest
+//! unpinned one instead: the pinned messages before it move back one slot
+//! each, in their order, and the slot at the front is freed. A pin lasts until its
+//! message is dequeued, or until `unpin_all`; a broadcast port dequeues
+//! nothing, so its pins last until then.
+//!
+//! At most `PortConfig::pin_limit` messages are pinned at once, a limit
+//! fixed when the port is created; beyond it pinning fails with
+//! `QueueError::TooManyPinned`, before anything is queued or dropped, so
+//! with a limit below `MSGS` pins never take the whole queue. A queue full
+//! of pinned messages has nothing to drop: the eviction fails with
+//! `QueueError::AllPinned` and the message that needed the room is not
+//! queued.
+//!
+//! Sequence numbers stay with the slots, as `dequeued` counts from the
+//! front: a pinned message moved by an eviction takes the number of the
+//! slot it moved to. A `Subscriber` that had read it before the move reads
+//! it again, in place of the dropped message, and one that had not sees
+//! `QueueError::Lagged` as for any dropped message. Snapshots keep neither
+//! the pins nor the limit.
+
+use core::ptr;
+use core::sync::atomic::Ordering;
+
+use crate::{slot_index, trace, Broadcast, Message, Port, QueueError, QueueingPort, SegmentHeader, MSGS, SIZE};
+
+// One bit of `pinned` per slot.
+const _: () = assert!(MSGS <= 32);
+
+impl SegmentHeader {
+    pub(crate) fn is_pinned(&self, index: usize) -> bool {
+        self.pinned.load(self.byte_order(), Ordering::Relaxed) & (1 << index) != 0
+    }
+
+    pub(crate) fn set_pinned(&self, index: usize, pinned: bool) {
+        let order = self.byte_order();
+        if pinned {
+            self.pinned.fetch_or(order, 1 << index, Ordering::Relaxed);
+        } else {
+            self.pinned.fetch_and(order, !(1 << index), Ordering::Relaxed);
+        }
+    }
+
+    /// Moves the pins along with slots rotated left by `by`, as `compact`
+    /// rotates them.
+    pub(crate) fn rotate_pins(&self, by: usize) {
+        let order = self.byte_order();
+        let pins = self.pinned.load(order, Ordering::Relaxed);
+        let rotated = (0..MSGS)
+            .filter(|&i| pins & (1 << slot_index(by + i)) != 0)
+            .fold(0, |rotated, i| rotated | 1 << i);
+        self.pinned.store(order, rotated, Ordering::Relaxed);
+    }
+}
+
+impl QueueingPort {
+    /// Enqueues `message` pinned, see the module documentation. Fails with
+    /// `QueueError::TooManyPinned` if `pin_limit` messages are pinned
+    /// already, and otherwise as `enqueue`.
+    pub fn enqueue_pinned(&mut self, message: Message) -> Result<(), QueueError> {
+        trace::enqueue(self, |port| port.produce_pinned(message))
+    }
+
+    /// Number of queued messages that are pinned.
+    pub fn pinned_len(&self) -> usize {
+        self.segment().header.pinned.load(self.byte_order(), Ordering::Relaxed).count_ones() as usize
+    }
+
+    /// Most messages pinned at once, see `PortConfig::pin_limit`.
+    pub fn pin_limit(&self) -> usize {
+        self.segment().header.pin_limit.load(self.byte_order(), Ordering::Relaxed) as usize
+    }
+
+    /// Unpins every queued message, returning how many were pinned.
+    pub fn unpin_all(&self) -> usize {
+        let header = &self.segment().header;
+        header.pinned.fetch_and(header.byte_order(), 0, Ordering::Relaxed).count_ones() as usize
+    }
+
+    pub(crate) fn check_pin_limit(&self) -> Result<(), QueueError> {
+        let limit = self.pin_limit();
+        if self.pinned_len() >= limit {
+            return Err(QueueError::TooManyPinned { limit: limit as u32 });
+        }
+        Ok(())
+    }
+
+    fn produce_pinned(&self, message: Message) -> Result<(), QueueError> {
+        self.check_pin_limit()?;
+        let header = &self.segment().header;
+        let mut pinned = None;
+        let result = self.produce_back(|slot| {
+            // SAFETY: as in `QueueingPort::enqueue`.
+            unsafe { ptr::write(slot, message.0) };
+            // The slot is the writer's until it is published, so the reader
+            // never sees the message unpinned.
+            let index = slot_index(header.write_index.load(header.byte_order(), Ordering::Relaxed) as usize);
+            header.set_pinned(index, true);
+            pinned = Some(index);
+        });
+        if let (Err(_), Some(index)) = (&result, pinned) {
+            // Written but refused, by the validator: the slot stays free.
+            header.set_pinned(index, false);
+        }
+        result
+    }
+
+    /// Drops the oldest unpinned message of a full queue, see the module
+    /// documentation.
+    pub(crate) fn evict_unpinned(&self) -> Result<(), QueueError> {
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        header.wait_while_compacting();
+        let count = header.message_count.load(order, Ordering::Acquire) as usize;
+        let read_index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize);
+        let index = |offset: usize| slot_index(read_index + offset);
+        let Some(oldest) = (0..count.min(MSGS)).find(|&offset| !header.is_pinned(index(offset))) else {
+            return Err(QueueError::AllPinned);
+        };
+        for offset in (0..oldest).rev() {
+            let (from, to) = (index(offset), index(offset + 1));
+            // The slots are occupied, so only the reader, which evicting
+            // makes this handle, touches them.
+            unsafe { core::ptr::copy_nonoverlapping(segment.slot(from), segment.slot(to), SIZE) };
+            for words in [&header.slot_time_low, &header.slot_time_high] {
+                words[to].store(order, words[from].load(order, Ordering::Relaxed), Ordering::Relaxed);
+            }
+            header.set_pinned(to, true);
+            // By two, so an observer copying the slot sees it change, and
+            // the slot stays occupied.
+            header.slot_generation[to].fetch_add(order, 2, Ordering::Relaxed);
+        }
+        // The front slot goes as the oldest message would, its pin with it.
+        self.consume_front(|_| ())
+    }
+}
+
+impl Port<Broadcast> {
+    /// Publishes `message` pinned: no newer message drops it, see the
+    /// module documentation. Fails with `QueueError::TooManyPinned` as
+    /// `QueueingPort::enqueue_pinned`, and otherwise as `publish`.
+    pub fn publish_pinned(&self, message: Message) -> Result<(), QueueError> {
+        let core = self.inner();
+        core.check_pin_limit()?;
+        self.make_room()?;
+        core.produce_pinned(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeliveryOrder, Observer, PortConfig};
+    use core::ptr::NonNull;
+
+    fn tagged(tag: u8) -> Message {
+        Message([tag; SIZE])
+    }
+
+    /// The tags of the queued messages, oldest first, leaving them queued.
+    fn queued(port: &QueueingPort) -> Vec<u8> {
+        let observer = unsafe { Observer::attach(NonNull::from(port.segment())) };
+        (0..port.len()).map(|n| observer.peek_nth(n).unwrap().0[0]).collect()
+    }
+
+    #[test]
+    fn pinned_messages_survive_a_flood() {
+        let port = Port::<Broadcast>::with_config(&PortConfig::new().pin_limit(MSGS as u32 / 4));
+        port.publish(tagged(1)).unwrap();
+        port.publish_pinned(tagged(2)).unwrap();
+        port.publish(tagged(3)).unwrap();
+        port.publish_pinned(tagged(4)).unwrap();
+        let mut subscriber = port.subscribe();
+        for tag in 10..10 + 3 * MSGS as u8 {
+            port.publish(tagged(tag)).unwrap();
+        }
+
+        let newest = (10 + 3 * MSGS as u8 - (MSGS as u8 - 2))..10 + 3 * MSGS as u8;
+        let expected: Vec<u8> = [2, 4].into_iter().chain(newest.clone()).collect();
+        assert_eq!(queued(port.inner()), expected);
+        assert_eq!(port.inner().pinned_len(), 2);
+        // The pinned messages took the numbers of the last two dropped.
+        let missed = 3 * MSGS as u32 - MSGS as u32;
+        assert!(matches!(subscriber.recv(), Err(QueueError::Lagged { missed: m }) if m == missed));
+        let read: Vec<u8> = core::iter::from_fn(|| subscriber.recv().ok()).map(|m| m.0[0]).collect();
+        assert_eq!(read, expected);
+
+        assert_eq!(port.inner().unpin_all(), 2);
+        port.publish(tagged(99)).unwrap();
+        assert_eq!(queued(port.inner())[..2], [4, newest.start], "unpinned, the oldest goes first again");
+    }
+
+    #[test]
+    fn pin_limit_is_enforced() {
+        assert!(matches!(QueueingPort::new().enqueue_pinned(tagged(1)), Err(QueueError::TooManyPinned { limit: 0 })));
+
+        let config = PortConfig::new().pin_limit(2).delivery_order(DeliveryOrder::StrictFifo);
+        let mut port = QueueingPort::with_config(&config);
+        port.enqueue_pinned(tagged(1)).unwrap();
+        port.enqueue(tagged(2)).unwrap();
+        port.enqueue_pinned(tagged(3)).unwrap();
+        assert!(matches!(port.enqueue_pinned(tagged(4)), Err(QueueError::TooManyPinned { limit: 2 })));
+        assert_eq!(port.len(), 3, "nothing was queued");
+        port.enqueue(tagged(4)).unwrap();
+
+        // Dequeueing clears the pin.
+        assert_eq!(port.dequeue().unwrap().0[0], 1);
+        assert_eq!(port.pinned_len(), 1);
+        assert_eq!(port.dequeue().unwrap().0[0], 2);
+        port.enqueue_pinned(tagged(5)).unwrap();
+        for _ in 0..MSGS - 3 {
+            port.enqueue(tagged(6)).unwrap();
+        }
+        // Closing the full queue drops nothing, pinned or not, unless the
+        // handle drops the oldest.
+        assert!(matches!(port.close_with_summary(b"done"), Err(QueueError::FullBuffer)));
+        port.set_overflow_policy(crate::OverflowPolicy::DropOldest);
+        port.close_with_summary(b"done").unwrap();
+        assert_eq!(port.stats().evicted_on_close, 1);
+        let tags: Vec<u8> = core::iter::from_fn(|| port.dequeue()).map(|m| m.0[0]).collect();
+        assert_eq!(tags[..3], [3, 5, 6]);
+        assert_eq!(tags.len(), MSGS);
+        assert_eq!(port.pinned_len(), 0);
+        assert_eq!(port.pin_limit(), 2);
+    }
+
+    #[test]
+    fn a_refused_pinned_message_leaves_no_pin() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().pin_limit(1));
+        port.set_validator(|message| match message.0[0] {
+            0 => Err(crate::ValidationError { reason: "tag 0" }),
+            _ => Ok(()),
+        });
+        assert!(matches!(port.enqueue_pinned(tagged(0)), Err(QueueError::ValidationFailed(_))));
+        assert_eq!(port.pinned_len(), 0);
+        port.enqueue_pinned(tagged(1)).unwrap();
+        assert_eq!(port.pinned_len(), 1);
+    }
+
+    #[test]
+    fn a_full_queue_of_pins_refuses_more() {
+        let port = Port::<Broadcast>::with_config(&PortConfig::new().pin_limit(u32::MAX));
+        assert_eq!(port.inner().pin_limit(), MSGS);
+        for tag in 0..MSGS as u8 {
+            port.publish_pinned(tagged(tag)).unwrap();
+        }
+        assert!(matches!(port.publish(tagged(100)), Err(QueueError::AllPinned)));
+        assert!(matches!(port.publish_pinned(tagged(100)), Err(QueueError::TooManyPinned { .. })));
+        assert!(matches!(port.close_with_summary(b""), Err(QueueError::AllPinned)));
+        assert!(!port.inner().is_closed());
+        assert_eq!(queued(port.inner()), (0..MSGS as u8).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,175 @@
+//! A two-slot buffer holding the latest complete sample.
+//!
+//! Unlike a queue, the writer never waits and the reader never sees a
+//! backlog: each commit replaces the previous sample. The writer fills the
+//! slot readers are not looking at and then publishes it by bumping a
+//! generation counter, whose low bit selects the current slot. Readers copy
+//! the current slot and retry if the generation moved during the copy, which
+//! is the only way the writer can have reused that slot underneath them.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+pub struct PingPongBuffer<const MSG_SIZE: usize> {
+    // A full u32 rather than a one-byte selector, so a reader preempted for
+    // many commits cannot mistake a wrapped value for an unchanged one.
+    generation: AtomicU32,
+    slots: UnsafeCell<[[u8; MSG_SIZE]; 2]>,
+}
+
+unsafe impl<const MSG_SIZE: usize> Sync for PingPongBuffer<MSG_SIZE> {}
+
+/// The single writer of a [`PingPongBuffer`], see `PingPongBuffer::split`.
+pub struct PingPongWriter<'a, const MSG_SIZE: usize> {
+    buffer: &'a PingPongBuffer<MSG_SIZE>,
+    // Not Sync: the slot handed out by write_begin belongs to one thread.
+    _not_sync: PhantomData<*mut ()>,
+}
+
+unsafe impl<const MSG_SIZE: usize> Send for PingPongWriter<'_, MSG_SIZE> {}
+
+/// A reader of a [`PingPongBuffer`]; any number may exist.
+#[derive(Clone, Copy)]
+pub struct PingPongReader<'a, const MSG_SIZE: usize> {
+    buffer: &'a PingPongBuffer<MSG_SIZE>,
+}
+
+impl<const MSG_SIZE: usize> PingPongBuffer<MSG_SIZE> {
+    pub const fn new() -> Self {
+        PingPongBuffer {
+            generation: AtomicU32::new(0),
+            slots: UnsafeCell::new([[0; MSG_SIZE]; 2]),
+        }
+    }
+
+    /// Hands out the writer and a reader. Borrowing `self` mutably makes the
+    /// writer unique; further readers are copies of the returned one.
+    pub fn split(&mut self) -> (PingPongWriter<'_, MSG_SIZE>, PingPongReader<'_, MSG_SIZE>) {
+        let buffer = &*self;
+        (
+            PingPongWriter {
+                buffer,
+                _not_sync: PhantomData,
+            },
+            PingPongReader { buffer },
+        )
+    }
+
+    fn slot(&self, generation: u32) -> *mut [u8; MSG_SIZE] {
+        let slots = self.slots.get().cast::<[u8; MSG_SIZE]>();
+        unsafe { slots.add((generation & 1) as usize) }
+    }
+}
+
+impl<const MSG_SIZE: usize> Default for PingPongBuffer<MSG_SIZE> {
+    fn default() -> Self {
+        PingPongBuffer::new()
+    }
+}
+
+impl<const MSG_SIZE: usize> PingPongWriter<'_, MSG_SIZE> {
+    /// Returns the inactive slot for the next sample. It still holds the
+    /// sample from two commits ago.
+    pub fn write_begin(&mut self) -> &mut [u8] {
+        let generation = self.buffer.generation.load(Ordering::Relaxed);
+        // Orders the previous commit before the writes into the slot it
+        // retired; pairs with the fence in `read`.
+        fence(Ordering::Release);
+        unsafe { &mut *self.buffer.slot(generation.wrapping_add(1)) }
+    }
+
+    /// Publishes the slot filled since `write_begin` as the current sample.
+    pub fn write_commit(&mut self) {
+        let generation = self.buffer.generation.load(Ordering::Relaxed);
+        self.buffer
+            .generation
+            .store(generation.wrapping_add(1), Ordering::Release);
+    }
+}
+
+impl<const MSG_SIZE: usize> PingPongReader<'_, MSG_SIZE> {
+    /// Copies out the last committed sample, all zeroes before the first
+    /// commit.
+    ///
+    /// Returns a copy rather than a reference: a reference into the slot
+    /// would be overwritten by the writer two commits later.
+    pub fn read(&self) -> [u8; MSG_SIZE] {
+        loop {
+            let before = self.buffer.generation.load(Ordering::Acquire);
+            // Volatile because the writer may be storing into this slot
+            // concurrently; such a copy is thrown away below.
+            let sample = unsafe { ptr::read_volatile(self.buffer.slot(before)) };
+            fence(Ordering::Acquire);
+            if self.buffer.generation.load(Ordering::Relaxed) == before {
+                return sample;
+            }
+        }
+    }
+
+    /// Number of commits so far.
+    pub fn generation(&self) -> u32 {
+        self.buffer.generation.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn read_returns_last_commit() {
+        let mut buffer = PingPongBuffer::<8>::new();
+        let (mut writer, reader) = buffer.split();
+        assert_eq!(reader.read(), [0; 8]);
+
+        writer.write_begin().fill(1);
+        assert_eq!(reader.read(), [0; 8], "uncommitted writes are invisible");
+        writer.write_commit();
+        assert_eq!(reader.read(), [1; 8]);
+
+        let slot = writer.write_begin();
+        assert_eq!(slot, [0; 8], "the slot from two commits ago");
+        slot.fill(2);
+        writer.write_commit();
+        assert_eq!(reader.read(), [2; 8]);
+        assert_eq!(reader.generation(), 2);
+    }
+
+    #[test]
+    fn concurrent_reads_are_never_torn() {
+        const COMMITS: u32 = 200_000;
+        let mut buffer = PingPongBuffer::<256>::new();
+        let (mut writer, reader) = buffer.split();
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for i in 1..=COMMITS {
+                    let slot = writer.write_begin();
+                    for chunk in slot.chunks_exact_mut(4) {
+                        chunk.copy_from_slice(&i.to_le_bytes());
+                    }
+                    writer.write_commit();
+                }
+            });
+            for _ in 0..2 {
+                scope.spawn(move || {
+                    let mut last = 0;
+                    while last < COMMITS {
+                        let sample = reader.read();
+                        let value = u32::from_le_bytes(sample[..4].try_into().unwrap());
+                        assert!(
+                            sample.chunks_exact(4).all(|chunk| chunk == value.to_le_bytes()),
+                            "torn read of sample {}",
+                            value
+                        );
+                        assert!(value >= last, "sample went backwards");
+                        last = value;
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,187 @@
+//! Connecting two ports with a thread, as a shell pipe connects commands.
+//!
+//! `QueueingPort::pipe_to` spawns a bridge thread that dequeues from one
+//! port and enqueues what it got into another until stopped; a `Pipe`
+//! built with `with_transform` first passes each message through one
+//! function after another, any of which may change it or drop it by
+//! returning `None`. Ports piped into each other make a pipeline, a thread
+//! per stage.
+//!
+//! The bridge holds a port's lock only for one dequeue or enqueue, so
+//! other handles keep the use of both ports. A full destination is waited
+//! on with the message in hand, none is dropped for it; an empty source is
+//! polled. Any other error ends the bridge, and `PipeHandle::stop` returns
+//! it. A message in hand when the pipe is stopped is lost.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use crate::{wait, Message, QueueError, QueueingPort};
+
+/// A pipe from one port to another, before its thread is started.
+pub struct Pipe {
+    src: Arc<Mutex<QueueingPort>>,
+    dst: Arc<Mutex<QueueingPort>>,
+    /// Applied in order; `None` drops the message.
+    transforms: Vec<fn(Message) -> Option<Message>>,
+}
+
+/// A running pipe, see the `pipe` module.
+pub struct PipeHandle {
+    thread: JoinHandle<Result<u64, QueueError>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl QueueingPort {
+    /// Moves every message from `src` to `dst` on a new thread until the
+    /// returned handle is stopped.
+    pub fn pipe_to(src: Arc<Mutex<QueueingPort>>, dst: Arc<Mutex<QueueingPort>>) -> PipeHandle {
+        Pipe::new(src, dst).spawn()
+    }
+}
+
+fn lock(port: &Mutex<QueueingPort>) -> MutexGuard<'_, QueueingPort> {
+    port.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Pipe {
+    pub fn new(src: Arc<Mutex<QueueingPort>>, dst: Arc<Mutex<QueueingPort>>) -> Pipe {
+        Pipe { src, dst, transforms: Vec::new() }
+    }
+
+    /// Passes each message through `f` on the way, after the transforms
+    /// added before.
+    pub fn with_transform(mut self, f: fn(Message) -> Option<Message>) -> Pipe {
+        self.transforms.push(f);
+        self
+    }
+
+    /// Starts the bridge thread.
+    pub fn spawn(self) -> PipeHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stop = stop.clone();
+            move || self.run(&stop)
+        });
+        PipeHandle { thread, stop }
+    }
+
+    /// Moves messages until `stop` is set or a port fails, returning how
+    /// many reached `dst`.
+    fn run(self, stop: &AtomicBool) -> Result<u64, QueueError> {
+        let mut moved = 0;
+        while !stop.load(Ordering::Acquire) {
+            let message = match lock(&self.src).try_dequeue() {
+                Ok(message) => message,
+                Err(QueueError::EmptyBuffer) => {
+                    wait::backoff();
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            let Some(message) = self.transforms.iter().try_fold(message, |message, f| f(message)) else {
+                continue;
+            };
+            loop {
+                // A failed enqueue consumes its message; retry with a copy.
+                match lock(&self.dst).enqueue(Message(message.0)) {
+                    Ok(()) => break,
+                    Err(QueueError::FullBuffer) if !stop.load(Ordering::Acquire) => wait::backoff(),
+                    Err(QueueError::FullBuffer) => return Ok(moved),
+                    Err(error) => return Err(error),
+                }
+            }
+            moved += 1;
+        }
+        Ok(moved)
+    }
+}
+
+impl PipeHandle {
+    /// Stops the bridge and waits for its thread. Returns how many messages
+    /// it moved, or the error that ended it before.
+    pub fn stop(self) -> Result<u64, QueueError> {
+        self.stop.store(true, Ordering::Release);
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+
+    /// Whether the bridge has ended on an error, or panicked.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+    use std::time::{Duration, Instant};
+
+    fn shared() -> Arc<Mutex<QueueingPort>> {
+        Arc::new(Mutex::new(QueueingPort::new()))
+    }
+
+    fn negate(message: Message) -> Option<Message> {
+        Some(Message(message.0.map(|byte| !byte)))
+    }
+
+    fn drop_odd(message: Message) -> Option<Message> {
+        message.0[0].is_multiple_of(2).then_some(message)
+    }
+
+    /// Dequeues `count` messages from `port`, waiting for each.
+    fn receive(port: &Mutex<QueueingPort>, count: usize) -> Vec<Message> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut received = Vec::new();
+        while received.len() < count {
+            assert!(Instant::now() < deadline, "got {} of {} messages", received.len(), count);
+            match lock(port).try_dequeue() {
+                Ok(message) => received.push(message),
+                Err(_) => thread::yield_now(),
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn a_three_stage_pipeline_negates_in_flight() {
+        let (a, b, c) = (shared(), shared(), shared());
+        let negating = Pipe::new(a.clone(), b.clone()).with_transform(negate).spawn();
+        let plain = QueueingPort::pipe_to(b.clone(), c.clone());
+
+        // More than the ports hold, so the bridges have to keep up.
+        let sender = thread::spawn(move || {
+            for n in 0..100u8 {
+                while lock(&a).enqueue(Message([n; SIZE])).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+        let received = receive(&c, 100);
+        sender.join().unwrap();
+        for (n, message) in received.iter().enumerate() {
+            assert_eq!(message.0, [!(n as u8); SIZE]);
+        }
+
+        assert_eq!(negating.stop().unwrap(), 100);
+        assert_eq!(plain.stop().unwrap(), 100);
+        assert!(lock(&b).is_empty());
+    }
+
+    #[test]
+    fn transforms_chain_and_may_drop() {
+        let (a, b) = (shared(), shared());
+        for n in 0..6u8 {
+            lock(&a).enqueue(Message([n; SIZE])).unwrap();
+        }
+        // Negated first, so the messages sent odd are the ones left.
+        let pipe = Pipe::new(a.clone(), b.clone()).with_transform(negate).with_transform(drop_odd).spawn();
+        let received: Vec<u8> = receive(&b, 3).iter().map(|message| !message.0[0]).collect();
+        assert_eq!(received, [1, 3, 5]);
+        assert_eq!(pipe.stop().unwrap(), 3);
+    }
+}
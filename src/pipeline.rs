@@ -0,0 +1,238 @@
+//! Chains of per-message transforms in front of a port.
+//!
+//! ```
+//! # use ring_buffer::{DequeuePort, Message, Pipeline, QueueingPort, TransformError};
+//! fn check_magic(message: &mut Message) -> Result<(), TransformError> {
+//!     if message.0[0] == 0xA5 { Ok(()) } else { Err(TransformError("bad magic")) }
+//! }
+//! let mut port = Pipeline::new(QueueingPort::new()).map(check_magic).build();
+//! # let _ = port.dequeue();
+//! ```
+
+use crate::{DequeuePort, EnqueuePort, Message, QueueError};
+
+/// Why a transform rejected a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransformError(pub &'static str);
+
+/// One pipeline stage. It rewrites the message in place or rejects it.
+pub type Transform = fn(&mut Message) -> Result<(), TransformError>;
+
+/// A sequence of transforms applied in the order they were added.
+pub trait Stages {
+    fn apply(&self, message: &mut Message) -> Result<(), TransformError>;
+}
+
+impl Stages for () {
+    fn apply(&self, _message: &mut Message) -> Result<(), TransformError> {
+        Ok(())
+    }
+}
+
+/// The stages `S` followed by one more transform.
+pub struct Then<S> {
+    first: S,
+    then: Transform,
+}
+
+impl<S: Stages> Stages for Then<S> {
+    fn apply(&self, message: &mut Message) -> Result<(), TransformError> {
+        self.first.apply(message)?;
+        (self.then)(message)
+    }
+}
+
+/// Builder for a read-side pipeline: every dequeued message runs through
+/// the transforms before it is returned.
+pub struct Pipeline<P, S = ()> {
+    port: P,
+    stages: S,
+}
+
+impl<P: DequeuePort> Pipeline<P> {
+    pub fn new(port: P) -> Pipeline<P> {
+        Pipeline { port, stages: () }
+    }
+}
+
+impl<P: DequeuePort, S: Stages> Pipeline<P, S> {
+    pub fn map(self, transform: Transform) -> Pipeline<P, Then<S>> {
+        Pipeline {
+            port: self.port,
+            stages: Then {
+                first: self.stages,
+                then: transform,
+            },
+        }
+    }
+
+    pub fn build(self) -> impl DequeuePort {
+        Dequeuing {
+            port: self.port,
+            stages: self.stages,
+        }
+    }
+}
+
+struct Dequeuing<P, S> {
+    port: P,
+    stages: S,
+}
+
+impl<P: DequeuePort, S: Stages> DequeuePort for Dequeuing<P, S> {
+    /// A message rejected by any stage is discarded and the stage's error is
+    /// returned as `QueueError::Transform`.
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        let mut message = self.port.dequeue()?;
+        self.stages.apply(&mut message).map_err(QueueError::Transform)?;
+        Ok(message)
+    }
+}
+
+/// Builder for a write-side pipeline: every message runs through the
+/// transforms before it is enqueued.
+pub struct PipelineOut<P, S = ()> {
+    port: P,
+    stages: S,
+}
+
+impl<P: EnqueuePort> PipelineOut<P> {
+    pub fn new(port: P) -> PipelineOut<P> {
+        PipelineOut { port, stages: () }
+    }
+}
+
+impl<P: EnqueuePort, S: Stages> PipelineOut<P, S> {
+    pub fn map(self, transform: Transform) -> PipelineOut<P, Then<S>> {
+        PipelineOut {
+            port: self.port,
+            stages: Then {
+                first: self.stages,
+                then: transform,
+            },
+        }
+    }
+
+    pub fn build(self) -> impl EnqueuePort {
+        Enqueuing {
+            port: self.port,
+            stages: self.stages,
+        }
+    }
+}
+
+struct Enqueuing<P, S> {
+    port: P,
+    stages: S,
+}
+
+impl<P: EnqueuePort, S: Stages> EnqueuePort for Enqueuing<P, S> {
+    /// A message rejected by any stage is not enqueued.
+    fn enqueue(&mut self, mut message: Message) -> Result<(), QueueError> {
+        self.stages.apply(&mut message).map_err(QueueError::Transform)?;
+        self.port.enqueue(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{QueueingPort, SIZE};
+
+    // Run-length coding of a whole message: a length byte pair followed by
+    // (count, byte) pairs.
+    fn compress(message: &mut Message) -> Result<(), TransformError> {
+        let mut out = [0u8; SIZE];
+        let mut len = 2;
+        let mut i = 0;
+        while i < SIZE {
+            let byte = message.0[i];
+            let mut run = 1;
+            while i + run < SIZE && message.0[i + run] == byte && run < 255 {
+                run += 1;
+            }
+            if len + 2 > SIZE {
+                return Err(TransformError("incompressible"));
+            }
+            out[len] = run as u8;
+            out[len + 1] = byte;
+            len += 2;
+            i += run;
+        }
+        out[..2].copy_from_slice(&(len as u16).to_le_bytes());
+        message.0 = out;
+        Ok(())
+    }
+
+    fn decompress(message: &mut Message) -> Result<(), TransformError> {
+        let len = u16::from_le_bytes([message.0[0], message.0[1]]) as usize;
+        let mut out = [0u8; SIZE];
+        let mut written = 0;
+        for pair in message.0[2..len].chunks_exact(2) {
+            let run = pair[0] as usize;
+            if written + run > SIZE {
+                return Err(TransformError("corrupt run"));
+            }
+            out[written..written + run].fill(pair[1]);
+            written += run;
+        }
+        message.0 = out;
+        Ok(())
+    }
+
+    fn mark(message: &mut Message) -> Result<(), TransformError> {
+        message.0[SIZE - 1] = 0xEE;
+        Ok(())
+    }
+
+    fn reject(_message: &mut Message) -> Result<(), TransformError> {
+        Err(TransformError("rejected"))
+    }
+
+    #[test]
+    fn compress_then_decompress_round_trips() {
+        let mut original = [0u8; SIZE];
+        original[..4].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        original[100..120].fill(7);
+        let mut port = QueueingPort::new();
+
+        PipelineOut::new(&mut port)
+            .map(compress)
+            .build()
+            .enqueue(Message(original))
+            .unwrap();
+
+        let stored = port.dequeue_unfiltered().unwrap();
+        assert!(u16::from_le_bytes([stored.0[0], stored.0[1]]) < 32, "stored compressed");
+        port.enqueue(stored).unwrap();
+
+        let mut reader = Pipeline::new(&mut port).map(decompress).build();
+        assert_eq!(reader.dequeue().unwrap().0, original);
+    }
+
+    #[test]
+    fn stages_run_in_order() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([3; SIZE])).unwrap();
+        // `mark` runs after `compress`, so decoding the result would fail if
+        // the order were reversed.
+        let mut reader = Pipeline::new(port).map(compress).map(mark).build();
+        let message = reader.dequeue().unwrap();
+        assert_eq!(&message.0[..6], &[6, 0, 255, 3, 1, 3]);
+        assert_eq!(message.0[SIZE - 1], 0xEE);
+    }
+
+    #[test]
+    fn failing_stage_discards_message() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([1; SIZE])).unwrap();
+        port.enqueue(Message([2; SIZE])).unwrap();
+        let mut reader = Pipeline::new(port).map(reject).build();
+        assert!(matches!(reader.dequeue(), Err(QueueError::Transform(TransformError("rejected")))));
+        assert!(matches!(reader.dequeue(), Err(QueueError::Transform(_))));
+        assert!(matches!(reader.dequeue(), Err(QueueError::EmptyBuffer)));
+
+        let mut writer = PipelineOut::new(QueueingPort::new()).map(mark).map(reject).build();
+        assert!(matches!(writer.enqueue(Message([1; SIZE])), Err(QueueError::Transform(_))));
+    }
+}
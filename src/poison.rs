@@ -0,0 +1,176 @@
+//! Keeping a port consistent when a closure passed to it panics.
+//!
+//! `enqueue_with`, `dequeue_with` and `forward_with` run the caller's code
+//! while a slot is in their hands. Nothing is published or freed until the
+//! closure has returned, so a panic never leaves the header inconsistent;
+//! what is left to undo is what the closure had started:
+//!
+//! - a panic while filling a free slot (in `enqueue_with`, or in the
+//!   destination of `forward_with`) has the slot put back the way free
+//!   slots look. Nothing else changed, so the port carries on as before.
+//! - a panic while reading a message in `dequeue_with` leaves the message
+//!   queued, and the dedup window forgets having seen it. But the closure
+//!   may have acted on part of the message, which only the caller can
+//!   judge, so the handle is poisoned: every enqueue and dequeue through
+//!   it fails with `QueueError::Poisoned` until `clear_poison()`.
+//!
+//! The cleanup is done by guards that only run while unwinding, so it
+//! exists only in std builds with `panic = "unwind"`; elsewhere a panic
+//! cannot be survived anyway, and the guards compile to nothing.
+
+use crate::{QueueError, QueueingPort};
+
+impl QueueingPort {
+    /// Whether a `dequeue_with` closure panicked through this handle since
+    /// the last `clear_poison()`.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.get()
+    }
+
+    /// Lets operations through the handle proceed again after a panic. The
+    /// message whose closure panicked is still at the front of the queue.
+    pub fn clear_poison(&mut self) {
+        self.poisoned.set(false);
+    }
+
+    pub(crate) fn check_poison(&self) -> Result<(), QueueError> {
+        if self.poisoned.get() {
+            return Err(QueueError::Poisoned);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "std", panic = "unwind"))]
+mod imp {
+    use crate::{DedupWindow, FREED_SLOT_BYTE, SIZE};
+    use core::cell::Cell;
+
+    /// Refills a free slot with the free-slot pattern if dropped during a
+    /// panic in the closure filling it.
+    pub(crate) struct FillGuard(pub(crate) *mut [u8; SIZE]);
+
+    impl FillGuard {
+        /// Gives up the guard once the closure has returned.
+        pub(crate) fn disarm(self) {
+            core::mem::forget(self);
+        }
+    }
+
+    impl Drop for FillGuard {
+        fn drop(&mut self) {
+            if std::thread::panicking() {
+                // Still a free slot of the writer's.
+                unsafe { (*self.0).fill(FREED_SLOT_BYTE) };
+            }
+        }
+    }
+
+    /// Poisons the handle, and retracts the dedup window's admission of the
+    /// message, if dropped during a panic in the closure reading it.
+    pub(crate) struct ReadGuard<'a> {
+        pub(crate) poisoned: &'a Cell<bool>,
+        pub(crate) dedup: &'a DedupWindow,
+    }
+
+    impl ReadGuard<'_> {
+        pub(crate) fn disarm(self) {
+            core::mem::forget(self);
+        }
+    }
+
+    impl Drop for ReadGuard<'_> {
+        fn drop(&mut self) {
+            if std::thread::panicking() {
+                self.dedup.retract();
+                self.poisoned.set(true);
+            }
+        }
+    }
+}
+
+#[cfg(not(all(feature = "std", panic = "unwind")))]
+mod imp {
+    use crate::{DedupWindow, SIZE};
+    use core::cell::Cell;
+
+    pub(crate) struct FillGuard(#[allow(dead_code)] pub(crate) *mut [u8; SIZE]);
+
+    impl FillGuard {
+        pub(crate) fn disarm(self) {}
+    }
+
+    pub(crate) struct ReadGuard<'a> {
+        #[allow(dead_code)]
+        pub(crate) poisoned: &'a Cell<bool>,
+        #[allow(dead_code)]
+        pub(crate) dedup: &'a DedupWindow,
+    }
+
+    impl ReadGuard<'_> {
+        pub(crate) fn disarm(self) {}
+    }
+}
+
+pub(crate) use imp::{FillGuard, ReadGuard};
+
+#[cfg(all(test, panic = "unwind"))]
+mod tests {
+    use super::*;
+    use crate::{DedupKey, Message, SIZE};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[test]
+    fn enqueue_panic_leaves_the_port_usable() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([1; SIZE])).unwrap();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            port.enqueue_with(|slot| {
+                slot[..8].fill(2);
+                panic!("half way");
+            })
+        }));
+        assert!(result.is_err());
+        assert!(!port.is_poisoned(), "nothing left to undo");
+        assert_eq!(port.check_invariants(), Ok(()));
+        assert_eq!((port.len(), port.stats().enqueued), (1, 1));
+
+        port.enqueue(Message([3; SIZE])).unwrap();
+        assert_eq!(port.dequeue().unwrap().0, [1; SIZE]);
+        assert_eq!(port.dequeue().unwrap().0, [3; SIZE]);
+    }
+
+    #[test]
+    fn dequeue_panic_poisons_until_cleared() {
+        let mut port = QueueingPort::new();
+        port.set_dedup_window(4, DedupKey::Envelope).unwrap();
+        port.enqueue(Message([1; SIZE])).unwrap();
+        let result = catch_unwind(AssertUnwindSafe(|| port.dequeue_with(|_| panic!("while reading"))));
+        assert!(result.is_err());
+        assert!(port.is_poisoned());
+        assert_eq!(port.check_invariants(), Ok(()));
+        assert_eq!(port.len(), 1, "the message stays queued");
+        assert!(matches!(port.try_dequeue(), Err(QueueError::Poisoned)));
+        assert!(matches!(port.enqueue(Message([2; SIZE])), Err(QueueError::Poisoned)));
+
+        port.clear_poison();
+        assert_eq!(port.dequeue().unwrap().0, [1; SIZE], "not taken for a duplicate");
+        assert_eq!(port.stats().duplicates_dropped, 0);
+    }
+
+    #[test]
+    fn forward_panic_leaves_both_ports_as_they_were() {
+        let mut src = QueueingPort::new();
+        let mut dst = QueueingPort::new();
+        src.enqueue(Message([1; SIZE])).unwrap();
+        let result = catch_unwind(AssertUnwindSafe(|| src.forward_with(&mut dst, |_| panic!("while patching"))));
+        assert!(result.is_err());
+        assert!(!src.is_poisoned() && !dst.is_poisoned());
+        assert_eq!(src.check_invariants(), Ok(()));
+        assert_eq!(dst.check_invariants(), Ok(()));
+        assert_eq!((src.len(), dst.len()), (1, 0));
+
+        src.forward_with(&mut dst, |_| SIZE).unwrap();
+        assert_eq!(dst.dequeue().unwrap().0, [1; SIZE]);
+    }
+}
@@ -0,0 +1,122 @@
+//! Port handles typed by which port they are.
+//!
+//! A system with several ports passes handles to all of them around, and
+//! nothing stops one reaching a function meant for another: they are all
+//! `QueueingPort`s. A `TypedPortHandle<TAG>` carries a tag in its type, so
+//! a function taking the telemetry handle does not accept the command one:
+//!
+//! ```compile_fail
+//! # use ring_buffer::{QueueingPort, TypedPortHandle};
+//! fn send_command(handle: &TypedPortHandle<0x2>) {}
+//! let telemetry = TypedPortHandle::<0x1>::new(QueueingPort::new());
+//! send_command(&telemetry);
+//! ```
+//!
+//! Tags are any `u64`; `port_tag` hashes a name into one, so a tag can be
+//! spelled `TypedPortHandle<{ port_tag("telemetry") }>`. A handle shares
+//! its port behind a mutex, and clones of it are handles to the same port.
+
+use core::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::{DequeuePort, EnqueuePort, Message, QueueError, QueueingPort};
+
+/// The tag of a name, FNV-1a over its bytes, for use as a `PortId` or
+/// `TypedPortHandle` tag.
+pub const fn port_tag(name: &str) -> u64 {
+    let bytes = name.as_bytes();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        i += 1;
+    }
+    hash
+}
+
+/// The identity of the port tagged `TAG`, with nothing at run time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PortId<const TAG: u64>(PhantomData<()>);
+
+impl<const TAG: u64> PortId<TAG> {
+    pub const fn new() -> PortId<TAG> {
+        PortId(PhantomData)
+    }
+
+    pub const fn tag(self) -> u64 {
+        TAG
+    }
+}
+
+/// A shared handle to the port tagged `TAG`, see the `port_id` module.
+pub struct TypedPortHandle<const TAG: u64> {
+    port: Arc<Mutex<QueueingPort>>,
+}
+
+impl<const TAG: u64> TypedPortHandle<TAG> {
+    pub fn new(port: QueueingPort) -> TypedPortHandle<TAG> {
+        TypedPortHandle { port: Arc::new(Mutex::new(port)) }
+    }
+
+    pub fn id(&self) -> PortId<TAG> {
+        PortId::new()
+    }
+
+    /// The port, for everything the handle does not forward.
+    pub fn lock(&self) -> MutexGuard<'_, QueueingPort> {
+        // As for the channel adapters: the header is only changed through
+        // atomics, so a panic holding the lock leaves nothing half-updated.
+        self.port.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn enqueue(&self, message: Message) -> Result<(), QueueError> {
+        self.lock().enqueue(message)
+    }
+
+    pub fn dequeue(&self) -> Result<Message, QueueError> {
+        self.lock().try_dequeue()
+    }
+}
+
+impl<const TAG: u64> Clone for TypedPortHandle<TAG> {
+    fn clone(&self) -> Self {
+        TypedPortHandle { port: Arc::clone(&self.port) }
+    }
+}
+
+impl<const TAG: u64> EnqueuePort for TypedPortHandle<TAG> {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        TypedPortHandle::enqueue(self, message)
+    }
+}
+
+impl<const TAG: u64> DequeuePort for TypedPortHandle<TAG> {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        TypedPortHandle::dequeue(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+
+    const TELEMETRY: u64 = port_tag("telemetry");
+
+    fn send(handle: &TypedPortHandle<TELEMETRY>, tag: u8) {
+        handle.enqueue(Message([tag; SIZE])).unwrap();
+    }
+
+    #[test]
+    fn clones_share_the_port() {
+        let handle = TypedPortHandle::<TELEMETRY>::new(QueueingPort::new());
+        let writer = handle.clone();
+        std::thread::spawn(move || send(&writer, 7)).join().unwrap();
+        assert_eq!(handle.dequeue().unwrap().0, [7; SIZE]);
+        assert!(matches!(handle.dequeue(), Err(QueueError::EmptyBuffer)));
+        assert_eq!(handle.id().tag(), TELEMETRY);
+        assert_ne!(port_tag("telemetry"), port_tag("command"));
+        assert_eq!(port_tag(""), 0xcbf2_9ce4_8422_2325);
+    }
+}
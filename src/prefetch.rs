@@ -0,0 +1,309 @@
+//! A receiver that takes messages from the segment in batches.
+//!
+//! Every dequeue reads the shared header and writes it back, which costs a
+//! receiver on another NUMA node from the segment a remote round trip each
+//! time. A `PrefetchingReceiver` moves up to its depth of messages at once
+//! into a staging area of its own, one copy run and a single update of the
+//! read index and `message_count`, and serves `dequeue` from there until
+//! the area is empty. The area is either allocated, by `with_prefetch`, or
+//! lent by the caller, `with_prefetch_buffer`.
+//!
+//! Staged messages have left the segment: the sender sees their slots free,
+//! and `QueueStats::dequeued` counts them. `len` counts them with the ones
+//! still queued, being what this receiver has yet to deliver;
+//! `shared_len` and `staged_len` split the two. `peek` shows the message
+//! the next `dequeue` returns, staging it if need be.
+//!
+//! A staged message cannot be acknowledged in the segment any more, so the
+//! receiver has no `claim_read_slice`/`commit_read`, and refuses a port
+//! with a read claim outstanding with `QueueError::ReadClaimed`. Messages
+//! are served as by `dequeue_unfiltered`: the type filter, dedup window and
+//! corruption policy do not apply. Messages still staged when the receiver
+//! is dropped are lost, so drain it first.
+
+#[cfg(feature = "alloc")]
+use alloc::{vec, vec::Vec};
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::{
+    slot_index, trace, DeliveryOrder, DequeuePort, Message, PeerRole, QueueError, QueueingPort, CLOSED,
+    FREED_SLOT_BYTE, SIZE,
+};
+
+enum Staging<'a> {
+    #[cfg(feature = "alloc")]
+    Owned(Vec<[u8; SIZE]>),
+    Borrowed(&'a mut [[u8; SIZE]]),
+}
+
+impl Staging<'_> {
+    fn slots(&mut self) -> &mut [[u8; SIZE]] {
+        match self {
+            #[cfg(feature = "alloc")]
+            Staging::Owned(slots) => slots,
+            Staging::Borrowed(slots) => slots,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            #[cfg(feature = "alloc")]
+            Staging::Owned(slots) => slots.len(),
+            Staging::Borrowed(slots) => slots.len(),
+        }
+    }
+}
+
+/// A receiver on a port, dequeueing through a staging area; see the module
+/// documentation.
+pub struct PrefetchingReceiver<'a> {
+    port: &'a mut QueueingPort,
+    staging: Staging<'a>,
+    /// The staged messages are `staging[next..end]`.
+    next: usize,
+    end: usize,
+    refills: u32,
+}
+
+impl QueueingPort {
+    /// A receiver on this port staging up to `depth` messages, in an area
+    /// it allocates. Fails with `QueueError::DepthExceeded` if `depth` is 0
+    /// and with `QueueError::ReadClaimed` if a read claim is outstanding.
+    #[cfg(feature = "alloc")]
+    pub fn with_prefetch(&mut self, depth: usize) -> Result<PrefetchingReceiver<'_>, QueueError> {
+        if depth == 0 {
+            return Err(QueueError::DepthExceeded);
+        }
+        PrefetchingReceiver::new(self, Staging::Owned(vec![[0; SIZE]; depth]))
+    }
+
+    /// Like `with_prefetch`, staging up to `buffer.len()` messages in
+    /// `buffer`.
+    pub fn with_prefetch_buffer<'a>(
+        &'a mut self,
+        buffer: &'a mut [[u8; SIZE]],
+    ) -> Result<PrefetchingReceiver<'a>, QueueError> {
+        if buffer.is_empty() {
+            return Err(QueueError::DepthExceeded);
+        }
+        PrefetchingReceiver::new(self, Staging::Borrowed(buffer))
+    }
+
+    /// Copies up to `max` messages from the front of the queue to `copy`,
+    /// in order, and dequeues them with one index update. Stops before an
+    /// out-of-order message on a strict FIFO port; one at the front is
+    /// discarded with `OrderViolation`, as by `dequeue`.
+    fn consume_front_batch(&self, max: usize, mut copy: impl FnMut(&[u8; SIZE])) -> Result<usize, QueueError> {
+        #[cfg(feature = "shmem")]
+        let hold = self.hold_off_freeze()?;
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        header.wait_while_compacting();
+        self.check_generation()?;
+        self.check_poison()?;
+        self.check_owner(PeerRole::Receiver)?;
+        // Closing follows the last message, so loaded first.
+        let closed = header.state.load(Ordering::Acquire) == CLOSED;
+        let queued = header.message_count.load(order, Ordering::Acquire) as usize;
+        if queued == 0 {
+            return Err(if closed { QueueError::Closed } else { QueueError::EmptyBuffer });
+        }
+
+        let read_index = header.read_index.load(order, Ordering::Relaxed) as usize;
+        let expected = header.dequeued.load(order, Ordering::Relaxed);
+        let strict = header.delivery_order.load(Ordering::Relaxed) == DeliveryOrder::StrictFifo as u8;
+        let mut taken = 0;
+        while taken < queued.min(max) {
+            let index = slot_index(read_index + taken);
+            let got = header.slot_sequence[index].load(order, Ordering::Relaxed);
+            if strict && got != expected.wrapping_add(taken as u32) {
+                break;
+            }
+            // Occupied slots belong to the reader until `message_count`
+            // gives them back, which happens only below.
+            copy(unsafe { &*segment.slot(index).cast::<[u8; SIZE]>() });
+            taken += 1;
+        }
+        if taken == 0 {
+            #[cfg(feature = "shmem")]
+            drop(hold);
+            return self.consume_front(|_| 0);
+        }
+        // Only a second reader on the segment can move `dequeued` meanwhile.
+        #[cfg(feature = "slot-poison")]
+        assert_eq!(header.dequeued.load(order, Ordering::Relaxed), expected, "slots were recycled while being read");
+        for index in (0..taken).map(|i| slot_index(read_index + i)) {
+            segment.record_history(index);
+            header.slot_generation[index].fetch_add(order, 1, Ordering::Relaxed);
+        }
+        fence(Ordering::Release);
+        for index in (0..taken).map(|i| slot_index(read_index + i)) {
+            unsafe { ptr::write_bytes(segment.slot(index), FREED_SLOT_BYTE, SIZE) };
+            header.set_pinned(index, false);
+        }
+
+        header
+            .read_index
+            .store(order, slot_index(read_index + taken) as u32, Ordering::Relaxed);
+        header.dequeued.fetch_add(order, taken as u32, Ordering::Relaxed);
+        self.note_dequeue();
+        header.message_count.fetch_sub(order, taken as u32, Ordering::Release);
+        Ok(taken)
+    }
+}
+
+impl<'a> PrefetchingReceiver<'a> {
+    fn new(port: &'a mut QueueingPort, staging: Staging<'a>) -> Result<PrefetchingReceiver<'a>, QueueError> {
+        if port.read_claimed {
+            return Err(QueueError::ReadClaimed);
+        }
+        Ok(PrefetchingReceiver { port, staging, next: 0, end: 0, refills: 0 })
+    }
+
+    /// The oldest message, from the staging area, which is refilled first
+    /// if it is empty. Fails like `dequeue_unfiltered` when nothing is
+    /// staged or queued.
+    pub fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.refill_if_empty()?;
+        let message = Message(self.staging.slots()[self.next]);
+        self.next += 1;
+        Ok(message)
+    }
+
+    /// The message the next `dequeue` returns, staging it if need be.
+    pub fn peek(&mut self) -> Result<&[u8; SIZE], QueueError> {
+        self.refill_if_empty()?;
+        Ok(&self.staging.slots()[self.next])
+    }
+
+    /// Messages not yet delivered: those staged and those still queued.
+    pub fn len(&self) -> usize {
+        self.staged_len() + self.shared_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Messages still queued in the segment, as the port's `len`.
+    pub fn shared_len(&self) -> usize {
+        self.port.len()
+    }
+
+    /// Messages taken from the segment and not yet delivered.
+    pub fn staged_len(&self) -> usize {
+        self.end - self.next
+    }
+
+    /// Most messages one refill takes.
+    pub fn depth(&self) -> usize {
+        self.staging.len()
+    }
+
+    /// Batches taken from the segment, each with one index update.
+    pub fn refills(&self) -> u32 {
+        self.refills
+    }
+
+    pub fn port(&self) -> &QueueingPort {
+        self.port
+    }
+
+    fn refill_if_empty(&mut self) -> Result<(), QueueError> {
+        if self.next < self.end {
+            return Ok(());
+        }
+        let slots = self.staging.slots();
+        let mut filled = 0;
+        let taken = trace::dequeue(self.port, |port| {
+            port.consume_front_batch(slots.len(), |slot| {
+                slots[filled] = *slot;
+                filled += 1;
+            })
+        })?;
+        // None on an order violation, which is returned above.
+        debug_assert!(taken > 0);
+        (self.next, self.end) = (0, taken);
+        self.refills += 1;
+        Ok(())
+    }
+}
+
+impl DequeuePort for PrefetchingReceiver<'_> {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        PrefetchingReceiver::dequeue(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Segment, MSGS};
+    use core::ptr::NonNull;
+
+    fn numbered(n: u8) -> Message {
+        Message([n; SIZE])
+    }
+
+    #[test]
+    fn order_holds_across_refills() {
+        let segment = Box::new(Segment::new());
+        let (mut writer, mut reader) =
+            unsafe { (QueueingPort::attach(NonNull::from(&*segment)), QueueingPort::attach(NonNull::from(&*segment))) };
+        let mut receiver = reader.with_prefetch(4).unwrap();
+        let mut sent = 0;
+        for expected in 0..200 {
+            while sent < 200 && writer.len() < MSGS {
+                writer.enqueue(numbered(sent)).unwrap();
+                sent += 1;
+            }
+            assert_eq!(receiver.dequeue().unwrap().0, [expected; SIZE]);
+        }
+        assert!(matches!(receiver.dequeue(), Err(QueueError::EmptyBuffer)));
+        assert_eq!(receiver.port().stats().dequeued, 200);
+        assert!(receiver.refills() >= 50, "at most 4 a refill");
+    }
+
+    #[test]
+    fn a_refill_is_one_batch() {
+        let mut port = QueueingPort::new();
+        for n in 0..MSGS as u8 {
+            port.enqueue(numbered(n)).unwrap();
+        }
+        let mut buffer = [[0; SIZE]; 4];
+        let mut receiver = port.with_prefetch_buffer(&mut buffer).unwrap();
+        assert_eq!(receiver.peek().unwrap(), &[0; SIZE]);
+        assert_eq!((receiver.refills(), receiver.staged_len(), receiver.shared_len()), (1, 4, 6));
+        assert_eq!(receiver.port().stats().dequeued, 4, "the staged ones left the segment");
+        for n in 0..4 {
+            assert_eq!(receiver.dequeue().unwrap().0, [n; SIZE]);
+        }
+        assert_eq!((receiver.refills(), receiver.len()), (1, 6));
+
+        // The next takes 4 more, and the last what is left.
+        assert_eq!(receiver.dequeue().unwrap().0, [4; SIZE]);
+        assert_eq!((receiver.refills(), receiver.staged_len(), receiver.shared_len()), (2, 3, 2));
+        for n in 5..MSGS as u8 {
+            assert_eq!(receiver.dequeue().unwrap().0, [n; SIZE]);
+        }
+        assert_eq!((receiver.refills(), receiver.len()), (3, 0));
+        assert_eq!(receiver.port().check_invariants(), Ok(()));
+        assert!(matches!(receiver.peek(), Err(QueueError::EmptyBuffer)));
+        assert!(matches!(port.with_prefetch_buffer(&mut []), Err(QueueError::DepthExceeded)));
+    }
+
+    #[test]
+    fn refused_with_a_read_claim_outstanding() {
+        let mut port = QueueingPort::new();
+        port.enqueue(numbered(1)).unwrap();
+        port.enqueue(numbered(2)).unwrap();
+        port.claim_read_slice().unwrap();
+        assert!(matches!(port.with_prefetch(4), Err(QueueError::ReadClaimed)));
+        port.commit_read();
+
+        let mut receiver = port.with_prefetch(4).unwrap();
+        assert_eq!(receiver.dequeue().unwrap().0, [2; SIZE]);
+    }
+}
@@ -0,0 +1,417 @@
+//! A `/proc`-style directory of health files, one per port, served over
+//! FUSE.
+//!
+//! A user-space process cannot add entries to `/proc` itself, so
+//! `ProcFsExporter` mounts a FUSE filesystem at a directory of the
+//! caller's choosing, e.g. `/run/queueing_ports`, where each port appears
+//! as a read-only file `<name>` whose contents are the port's
+//! `render_health_json` object and a newline. Like a `seq_file`, the
+//! contents are rendered afresh when the file is opened, so a reader sees
+//! the port as it is, and one read sees one consistent rendering.
+//!
+//! Port handles are not `Send`, so the exporter does not serve in a thread
+//! of its own: `process` answers the kernel's requests on the caller's
+//! thread, for the ports passed in, and is meant for the loop that owns
+//! them. A reader of a file blocks until the next `process` call.
+//!
+//! Mounting takes `CAP_SYS_ADMIN`: there is no `fusermount` helper here.
+//! The `fuser` crate is not a dependency either; the handful of requests a
+//! read-only directory gets are answered with `libc` below, as the `uring`
+//! module does for io_uring.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::QueueingPort;
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/// Reads past the size the file had when looked up still reach `READ`.
+const FOPEN_DIRECT_IO: u32 = 1;
+
+const ROOT_ID: u64 = 1;
+/// Node ids of port files are the port's place in the list plus this.
+const FIRST_PORT_ID: u64 = 2;
+
+const IN_HEADER_LEN: usize = 40;
+const OUT_HEADER_LEN: usize = 16;
+const MAX_WRITE: u32 = 64 * 1024;
+/// The kernel wants room for a whole write request, headers included.
+const BUFFER_LEN: usize = MAX_WRITE as usize + 4096;
+
+/// How long the kernel may cache names and attributes, in seconds.
+const TTL_SECS: u64 = 1;
+
+/// A FUSE filesystem of port health files; see the module documentation.
+pub struct ProcFsExporter {
+    device: File,
+    mount_point: PathBuf,
+    /// Renderings of open files, by file handle.
+    open_files: HashMap<u64, Vec<u8>>,
+    next_handle: u64,
+}
+
+impl ProcFsExporter {
+    /// Mounts an empty exporter at `mount_point`, an existing directory.
+    pub fn mount(mount_point: &Path) -> io::Result<ProcFsExporter> {
+        let device = std::fs::OpenOptions::new().read(true).write(true).open("/dev/fuse")?;
+        let target = CString::new(mount_point.as_os_str().as_bytes())?;
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let options = CString::new(format!(
+            "fd={},rootmode=40000,user_id={},group_id={},allow_other",
+            device.as_raw_fd(),
+            uid,
+            gid
+        ))?;
+        let result = unsafe {
+            libc::mount(
+                c"ring_buffer".as_ptr(),
+                target.as_ptr(),
+                c"fuse.ring_buffer".as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RDONLY,
+                options.as_ptr().cast(),
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ProcFsExporter {
+            device,
+            mount_point: mount_point.to_owned(),
+            open_files: HashMap::new(),
+            next_handle: 1,
+        })
+    }
+
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    /// Answers the kernel's requests for up to `timeout`, presenting
+    /// `ports` as files named by the strings. Returns how many requests
+    /// were answered. Pass the same list every time, in the same order:
+    /// the kernel refers to files by their place in it.
+    pub fn process(&mut self, ports: &[(&str, &QueueingPort)], timeout: Duration) -> io::Result<usize> {
+        let mut answered = 0;
+        let mut wait = timeout;
+        let mut buffer = vec![0u8; BUFFER_LEN];
+        loop {
+            let mut poll = libc::pollfd { fd: self.device.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            let millis = wait.as_millis().min(i32::MAX as u128) as i32;
+            match unsafe { libc::poll(&mut poll, 1, millis) } {
+                0 => return Ok(answered),
+                n if n < 0 => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(error);
+                }
+                _ => {}
+            }
+            let len = match self.device.read(&mut buffer) {
+                Ok(len) => len,
+                // The request was interrupted before it was read.
+                Err(error) if error.raw_os_error() == Some(libc::ENOENT) => continue,
+                // Unmounted from outside.
+                Err(error) if error.raw_os_error() == Some(libc::ENODEV) => return Ok(answered),
+                Err(error) => return Err(error),
+            };
+            self.answer(&buffer[..len], ports)?;
+            answered += 1;
+            // Only the first request is waited for.
+            wait = Duration::ZERO;
+        }
+    }
+
+    fn answer(&mut self, request: &[u8], ports: &[(&str, &QueueingPort)]) -> io::Result<()> {
+        if request.len() < IN_HEADER_LEN {
+            return Ok(());
+        }
+        let opcode = u32_at(request, 4);
+        let unique = u64_at(request, 8);
+        let node = u64_at(request, 16);
+        let body = &request[IN_HEADER_LEN..];
+        let port = node.checked_sub(FIRST_PORT_ID).and_then(|index| ports.get(index as usize));
+
+        let reply: Result<Vec<u8>, i32> = match opcode {
+            FUSE_INIT => Ok(init_reply(body)),
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return Ok(()),
+            FUSE_DESTROY => Ok(Vec::new()),
+            FUSE_LOOKUP if node == ROOT_ID => {
+                let name = body.split(|&b| b == 0).next().unwrap_or_default();
+                match ports.iter().position(|(port_name, _)| port_name.as_bytes() == name) {
+                    Some(index) => {
+                        let id = FIRST_PORT_ID + index as u64;
+                        Ok(entry_reply(id, &file_attr(id, rendering(ports[index]).len())))
+                    }
+                    None => Err(libc::ENOENT),
+                }
+            }
+            FUSE_GETATTR if node == ROOT_ID => Ok(attr_reply(&dir_attr())),
+            FUSE_GETATTR => match port {
+                Some(&entry) => Ok(attr_reply(&file_attr(node, rendering(entry).len()))),
+                None => Err(libc::ENOENT),
+            },
+            FUSE_OPENDIR if node == ROOT_ID => Ok(open_reply(0, 0)),
+            FUSE_READDIR if node == ROOT_ID => Ok(readdir_reply(ports, u64_at(body, 8), u32_at(body, 16))),
+            FUSE_RELEASEDIR => Ok(Vec::new()),
+            FUSE_OPEN => match port {
+                Some(&entry) => {
+                    let handle = self.next_handle;
+                    self.next_handle += 1;
+                    self.open_files.insert(handle, rendering(entry));
+                    Ok(open_reply(handle, FOPEN_DIRECT_IO))
+                }
+                None => Err(libc::ENOENT),
+            },
+            FUSE_READ => match self.open_files.get(&u64_at(body, 0)) {
+                Some(contents) => {
+                    let offset = (u64_at(body, 8) as usize).min(contents.len());
+                    let end = offset.saturating_add(u32_at(body, 16) as usize).min(contents.len());
+                    Ok(contents[offset..end].to_vec())
+                }
+                None => Err(libc::EBADF),
+            },
+            FUSE_FLUSH => Ok(Vec::new()),
+            FUSE_RELEASE => {
+                self.open_files.remove(&u64_at(body, 0));
+                Ok(Vec::new())
+            }
+            FUSE_LOOKUP | FUSE_OPENDIR | FUSE_READDIR => Err(libc::ENOTDIR),
+            _ => Err(libc::ENOSYS),
+        };
+
+        let (error, payload) = match reply {
+            Ok(payload) => (0, payload),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut out = Vec::with_capacity(OUT_HEADER_LEN + payload.len());
+        out.extend_from_slice(&((OUT_HEADER_LEN + payload.len()) as u32).to_ne_bytes());
+        out.extend_from_slice(&error.to_ne_bytes());
+        out.extend_from_slice(&unique.to_ne_bytes());
+        out.extend_from_slice(&payload);
+        match self.device.write(&out) {
+            Ok(_) => Ok(()),
+            // The request was interrupted and is gone.
+            Err(error) if error.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl Drop for ProcFsExporter {
+    fn drop(&mut self) {
+        if let Ok(target) = CString::new(self.mount_point.as_os_str().as_bytes()) {
+            // Detached, so readers still holding a file do not keep the
+            // mount busy; they see the device go away.
+            unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+        }
+    }
+}
+
+/// The contents of a port's file.
+fn rendering((name, port): (&str, &QueueingPort)) -> Vec<u8> {
+    let mut json = String::new();
+    port.render_health_json(name, &mut json);
+    json.push('\n');
+    json.into_bytes()
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    bytes.get(at..at + 4).map_or(0, |b| u32::from_ne_bytes(b.try_into().unwrap()))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    bytes.get(at..at + 8).map_or(0, |b| u64::from_ne_bytes(b.try_into().unwrap()))
+}
+
+/// `struct fuse_attr`.
+struct Attr {
+    ino: u64,
+    size: u64,
+    mode: u32,
+    nlink: u32,
+}
+
+fn dir_attr() -> Attr {
+    Attr { ino: ROOT_ID, size: 0, mode: libc::S_IFDIR | 0o555, nlink: 2 }
+}
+
+fn file_attr(ino: u64, size: usize) -> Attr {
+    Attr { ino, size: size as u64, mode: libc::S_IFREG | 0o444, nlink: 1 }
+}
+
+fn push_attr(out: &mut Vec<u8>, attr: &Attr) {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    for word in [attr.ino, attr.size, attr.size.div_ceil(512), 0, 0, 0] {
+        out.extend_from_slice(&word.to_ne_bytes());
+    }
+    // Nanoseconds of the three times, mode, nlink, uid, gid, rdev,
+    // blksize and flags.
+    for word in [0, 0, 0, attr.mode, attr.nlink, uid, gid, 0, 4096, 0] {
+        out.extend_from_slice(&word.to_ne_bytes());
+    }
+}
+
+/// `struct fuse_init_out`, agreeing to the kernel's major version.
+fn init_reply(body: &[u8]) -> Vec<u8> {
+    let minor = u32_at(body, 4).min(FUSE_KERNEL_MINOR_VERSION);
+    let mut out = Vec::with_capacity(64);
+    for word in [FUSE_KERNEL_VERSION, minor, 0, 0] {
+        out.extend_from_slice(&word.to_ne_bytes());
+    }
+    // max_background and congestion_threshold.
+    out.extend_from_slice(&16u16.to_ne_bytes());
+    out.extend_from_slice(&12u16.to_ne_bytes());
+    // max_write and time_gran.
+    out.extend_from_slice(&MAX_WRITE.to_ne_bytes());
+    out.extend_from_slice(&1u32.to_ne_bytes());
+    out.resize(64, 0);
+    out
+}
+
+/// `struct fuse_entry_out`.
+fn entry_reply(id: u64, attr: &Attr) -> Vec<u8> {
+    let mut out = Vec::with_capacity(128);
+    for word in [id, 0, TTL_SECS, TTL_SECS] {
+        out.extend_from_slice(&word.to_ne_bytes());
+    }
+    out.extend_from_slice(&[0; 8]);
+    push_attr(&mut out, attr);
+    out
+}
+
+/// `struct fuse_attr_out`.
+fn attr_reply(attr: &Attr) -> Vec<u8> {
+    let mut out = Vec::with_capacity(104);
+    out.extend_from_slice(&TTL_SECS.to_ne_bytes());
+    out.extend_from_slice(&[0; 8]);
+    push_attr(&mut out, attr);
+    out
+}
+
+/// `struct fuse_open_out`.
+fn open_reply(handle: u64, flags: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16);
+    out.extend_from_slice(&handle.to_ne_bytes());
+    out.extend_from_slice(&flags.to_ne_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out
+}
+
+/// `struct fuse_dirent`s from entry `offset` on, as many as fit `size`.
+fn readdir_reply(ports: &[(&str, &QueueingPort)], offset: u64, size: u32) -> Vec<u8> {
+    let entries = [(ROOT_ID, ".".as_bytes(), libc::DT_DIR), (ROOT_ID, "..".as_bytes(), libc::DT_DIR)]
+        .into_iter()
+        .chain(ports.iter().enumerate().map(|(index, (name, _))| {
+            (FIRST_PORT_ID + index as u64, name.as_bytes(), libc::DT_REG)
+        }));
+    let mut out = Vec::new();
+    for (index, (ino, name, kind)) in entries.enumerate().skip(offset as usize) {
+        let len = (24 + name.len()).next_multiple_of(8);
+        if out.len() + len > size as usize {
+            break;
+        }
+        out.extend_from_slice(&ino.to_ne_bytes());
+        // The offset of the next entry.
+        out.extend_from_slice(&(index as u64 + 1).to_ne_bytes());
+        out.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+        out.extend_from_slice(&u32::from(kind).to_ne_bytes());
+        out.extend_from_slice(name);
+        out.resize(out.len().next_multiple_of(8), 0);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::parse_flat_object;
+    use crate::{Message, SIZE};
+    use std::thread;
+
+    fn mount_or_skip(dir: &Path) -> Option<ProcFsExporter> {
+        std::fs::create_dir_all(dir).unwrap();
+        match ProcFsExporter::mount(dir) {
+            Ok(exporter) => Some(exporter),
+            Err(error) => {
+                eprintln!("skipped: cannot mount FUSE here ({})", error);
+                None
+            }
+        }
+    }
+
+    /// Runs `read` on another thread while answering its requests.
+    fn serve_while<T: Send + 'static>(
+        exporter: &mut ProcFsExporter,
+        ports: &[(&str, &QueueingPort)],
+        read: impl FnOnce() -> T + Send + 'static,
+    ) -> T {
+        let reader = thread::spawn(read);
+        while !reader.is_finished() {
+            exporter.process(ports, Duration::from_millis(10)).unwrap();
+        }
+        reader.join().unwrap()
+    }
+
+    #[test]
+    fn health_file_reads_as_json() {
+        let dir = std::env::temp_dir().join(format!("qp_procfs_{}", std::process::id()));
+        let Some(mut exporter) = mount_or_skip(&dir) else { return };
+        let mut sensor = QueueingPort::new();
+        for i in 0..3 {
+            sensor.enqueue(Message([i; SIZE])).unwrap();
+        }
+        let idle = QueueingPort::new();
+        let ports = [("sensor", &sensor), ("idle", &idle)];
+
+        let path = dir.join("sensor");
+        let text = serve_while(&mut exporter, &ports, move || std::fs::read_to_string(path).unwrap());
+        let members = parse_flat_object(&text);
+        assert_eq!((members["port"].as_str(), members["len"].as_str()), ("sensor", "3"));
+        assert_eq!(members["capacity"], crate::MSGS.to_string());
+
+        let listed = serve_while(&mut exporter, &ports, {
+            let dir = dir.clone();
+            move || {
+                let mut names: Vec<String> =
+                    std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+                names.sort();
+                names
+            }
+        });
+        assert_eq!(listed, ["idle", "sensor"]);
+
+        let missing = dir.join("nope");
+        let error = serve_while(&mut exporter, &ports, move || std::fs::read_to_string(missing).unwrap_err());
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        drop(exporter);
+        let _ = std::fs::remove_dir(&dir);
+    }
+}
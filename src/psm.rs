@@ -0,0 +1,162 @@
+//! A mutex several processes lock, with the `process-mutex` feature.
+//!
+//! `std::sync::Mutex` and the like only exclude threads of one process.
+//! A `ProcessSharedMutex` wraps a `pthread_mutex_t` initialized with
+//! `PTHREAD_PROCESS_SHARED`, and is meant to be placed, with `init`, in
+//! memory every process maps: a named port's segment region beyond the
+//! port, an `mmap(MAP_SHARED)` region inherited over `fork`, and so on.
+//! It is not part of the segment header, whose layout is the same on every
+//! platform while `pthread_mutex_t` is not.
+//!
+//! The mutex is also robust: if a process dies holding it, the next
+//! `lock` gets it, marks it consistent again and reports
+//! `PsmGuard::owner_died`, so the value it protects can be checked instead
+//! of every other process deadlocking.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use std::io;
+
+/// A value behind a process-shared, robust pthread mutex; see the module
+/// documentation.
+#[repr(C)]
+pub struct ProcessSharedMutex<T> {
+    raw: UnsafeCell<libc::pthread_mutex_t>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for ProcessSharedMutex<T> {}
+unsafe impl<T: Send> Sync for ProcessSharedMutex<T> {}
+
+/// `ProcessSharedMutex::lock` held; unlocks when dropped.
+pub struct PsmGuard<'a, T> {
+    mutex: &'a ProcessSharedMutex<T>,
+    owner_died: bool,
+}
+
+fn check(code: libc::c_int) -> io::Result<()> {
+    match code {
+        0 => Ok(()),
+        code => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+impl<T> ProcessSharedMutex<T> {
+    /// Initializes a mutex holding `value` in `place`, which the caller
+    /// then shares, e.g. by mapping the memory it lives in into other
+    /// processes, and returns it.
+    ///
+    /// # Safety
+    ///
+    /// `place` must not hold an initialized mutex another process may be
+    /// using, and must stay mapped at the same address in this process for
+    /// `'a`. A pthread mutex must not be moved once initialized, so the
+    /// mutex is only ever used through references to `place`. `T` must be
+    /// valid in every process that maps it: no pointers into one process's
+    /// memory.
+    pub unsafe fn init(place: &mut MaybeUninit<ProcessSharedMutex<T>>, value: T) -> io::Result<&ProcessSharedMutex<T>> {
+        let mutex = place.as_mut_ptr();
+        let mut attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+        check(libc::pthread_mutexattr_init(attr.as_mut_ptr()))?;
+        let result = check(libc::pthread_mutexattr_setpshared(attr.as_mut_ptr(), libc::PTHREAD_PROCESS_SHARED))
+            .and_then(|()| check(libc::pthread_mutexattr_setrobust(attr.as_mut_ptr(), libc::PTHREAD_MUTEX_ROBUST)))
+            .and_then(|()| check(libc::pthread_mutex_init(UnsafeCell::raw_get(&raw const (*mutex).raw), attr.as_ptr())));
+        libc::pthread_mutexattr_destroy(attr.as_mut_ptr());
+        result?;
+        UnsafeCell::raw_get(&raw const (*mutex).value).write(value);
+        Ok(place.assume_init_ref())
+    }
+
+    /// Locks the mutex, waiting for whichever process holds it. Panics if
+    /// the mutex cannot be locked at all, e.g. because this thread holds it
+    /// already or it became unusable after an owner died unrecovered.
+    pub fn lock(&self) -> PsmGuard<'_, T> {
+        let owner_died = match unsafe { libc::pthread_mutex_lock(self.raw.get()) } {
+            0 => false,
+            libc::EOWNERDEAD => {
+                // The dead owner's changes are the new owner's to check,
+                // which the guard tells it to.
+                check(unsafe { libc::pthread_mutex_consistent(self.raw.get()) }).expect("marking the mutex consistent");
+                true
+            }
+            code => panic!("locking a process-shared mutex: {}", io::Error::from_raw_os_error(code)),
+        };
+        PsmGuard { mutex: self, owner_died }
+    }
+
+    /// Like `lock`, but returns `None` right away if another thread or
+    /// process holds the mutex.
+    pub fn try_lock(&self) -> Option<PsmGuard<'_, T>> {
+        let owner_died = match unsafe { libc::pthread_mutex_trylock(self.raw.get()) } {
+            0 => false,
+            libc::EBUSY => return None,
+            libc::EOWNERDEAD => {
+                check(unsafe { libc::pthread_mutex_consistent(self.raw.get()) }).expect("marking the mutex consistent");
+                true
+            }
+            code => panic!("locking a process-shared mutex: {}", io::Error::from_raw_os_error(code)),
+        };
+        Some(PsmGuard { mutex: self, owner_died })
+    }
+
+    /// Drops the value and destroys the mutex, for the last process using
+    /// it, which tears down the memory it lives in afterwards.
+    ///
+    /// # Safety
+    ///
+    /// No process may use the mutex, or lock it, afterwards, and `init`
+    /// must have initialized it.
+    pub unsafe fn destroy(&self) {
+        core::ptr::drop_in_place(self.value.get());
+        libc::pthread_mutex_destroy(self.raw.get());
+    }
+}
+
+impl<T> PsmGuard<'_, T> {
+    /// The previous owner died holding the mutex, so the value may be
+    /// half updated.
+    pub fn owner_died(&self) -> bool {
+        self.owner_died
+    }
+}
+
+impl<T> Deref for PsmGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // The mutex is held, so no other thread or process touches the value.
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for PsmGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for PsmGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_mutex_unlock(self.mutex.raw.get()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_try_lock_and_destroy() {
+        let mut place = MaybeUninit::uninit();
+        let mutex = unsafe { ProcessSharedMutex::init(&mut place, vec![1]).unwrap() };
+        {
+            let mut guard = mutex.lock();
+            assert!(!guard.owner_died());
+            guard.push(2);
+            assert!(mutex.try_lock().is_none(), "held");
+        }
+        assert_eq!(*mutex.try_lock().unwrap(), [1, 2]);
+        unsafe { mutex.destroy() };
+    }
+}
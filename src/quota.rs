@@ -0,0 +1,57 @@
+//! A process-wide limit on the shared memory named ports create.
+//!
+//! Every `create()` reserves the size of its segment against the limit
+//! before the mapping is made, and fails with `PortError::QuotaExceeded`
+//! if the reservation does not fit. The bytes are given back when the
+//! creating port is dropped. Segments a process only opens are charged to
+//! their creator, not to it. There is no limit until `set_max_bytes` sets
+//! one; lowering it below the current usage leaves existing ports alone
+//! and only refuses new ones.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::PortError;
+
+static MAX_BYTES: AtomicUsize = AtomicUsize::new(usize::MAX);
+static USAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// The process's shared-memory quota, see the module documentation.
+pub struct ShmemQuota;
+
+impl ShmemQuota {
+    /// Limits the bytes of shared memory the process's ports may hold.
+    pub fn set_max_bytes(n: usize) {
+        MAX_BYTES.store(n, Ordering::Relaxed);
+    }
+
+    /// The bytes held by the segments this process created and still has.
+    pub fn current_usage() -> usize {
+        USAGE.load(Ordering::Relaxed)
+    }
+
+    /// The limit set by `set_max_bytes`, `usize::MAX` if there is none.
+    pub fn max_usage() -> usize {
+        MAX_BYTES.load(Ordering::Relaxed)
+    }
+}
+
+/// Bytes reserved against the quota, given back when dropped.
+pub(crate) struct QuotaCharge(usize);
+
+impl QuotaCharge {
+    pub(crate) fn reserve(bytes: usize) -> Result<QuotaCharge, PortError> {
+        let max = MAX_BYTES.load(Ordering::Relaxed);
+        USAGE
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                usage.checked_add(bytes).filter(|&total| total <= max)
+            })
+            .map(|_| QuotaCharge(bytes))
+            .map_err(|_| PortError::QuotaExceeded)
+    }
+}
+
+impl Drop for QuotaCharge {
+    fn drop(&mut self) {
+        USAGE.fetch_sub(self.0, Ordering::Relaxed);
+    }
+}
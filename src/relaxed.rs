@@ -0,0 +1,413 @@
+//! Enqueueing and dequeueing with only `message_count` ordered.
+//!
+//! A message changes hands through `message_count` alone, as PROTOCOL.md
+//! sets out: the writer's release increment publishes the slot, the
+//! reader's release decrement gives it back, both ends load the count with
+//! acquire ordering, and each accesses its own index relaxed. What
+//! `enqueue` and `dequeue` order beyond that is for observers: the release
+//! increment of the slot's generation and the release fence before the
+//! slot is freed let an `Observer`, `dequeue_shared` or `dequeue_timed`
+//! copy a slot and tell whether it changed meanwhile.
+//!
+//! `enqueue_relaxed` and `dequeue_relaxed` leave those two relaxed.
+//! Otherwise they are `enqueue` and `dequeue`, with the same checks,
+//! policies and errors, and mix freely with them on a port. Without the
+//! release, though, a copy of a slot made outside a dequeue can be torn
+//! while its generation reads as unchanged, so the two are `unsafe`: they
+//! are for ports on which nothing makes such copies.
+//!
+//! Where `message_count` crosses zero, from empty to not or back, they
+//! also fence with `SeqCst` after the update. That is the store-load
+//! ordering release and acquire do not give: none of the thread's later
+//! loads is served before the crossing, for a thread elsewhere that pairs
+//! it with a `SeqCst` fence or operation of its own, as in Dekker's
+//! pattern. The handover itself needs no such fence.
+//!
+//! Loom is not among the crate's dependencies, so the tests check the
+//! handover with a model of their own: every interleaving of a writer and
+//! a reader under release/acquire semantics.
+
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::{trace, Message, QueueError, QueueingPort};
+
+/// How a slot changes hands, see the `relaxed` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Handoff {
+    /// Ordered for observers too, as `enqueue` and `dequeue` do.
+    Ordered,
+    /// Ordered by `message_count` alone.
+    Relaxed,
+}
+
+impl Handoff {
+    /// The ordering of the writer's increment of the slot's generation.
+    pub(crate) fn generation_ordering(self) -> Ordering {
+        match self {
+            Handoff::Ordered => Ordering::Release,
+            Handoff::Relaxed => Ordering::Relaxed,
+        }
+    }
+
+    /// Called right after `message_count` moved from 0 or to 0.
+    pub(crate) fn crossed_zero(self) {
+        if self == Handoff::Relaxed {
+            fence(Ordering::SeqCst);
+        }
+    }
+}
+
+impl QueueingPort {
+    /// Like `enqueue`, ordering only `message_count`; see the `relaxed`
+    /// module.
+    ///
+    /// # Safety
+    ///
+    /// While the message is queued, and after, nothing may copy its slot
+    /// outside a dequeue: no `Observer` may be attached to the segment, and
+    /// no handle may call `dequeue_shared`, directly or as an
+    /// `RwQueueingPort` reader, or read enqueue times with `dequeue_timed`.
+    pub unsafe fn enqueue_relaxed(&mut self, message: Message) -> Result<(), QueueError> {
+        trace::enqueue(self, |port| {
+            port.produce_back_as(Handoff::Relaxed, |slot| {
+                // SAFETY: as in `enqueue`.
+                unsafe { ptr::write_unaligned(slot, message.0) }
+            })
+        })
+    }
+
+    /// Like `dequeue`, ordering only `message_count`; see the `relaxed`
+    /// module.
+    ///
+    /// # Safety
+    ///
+    /// As for `enqueue_relaxed`, for the slot the message is dequeued from.
+    pub unsafe fn dequeue_relaxed(&mut self) -> Result<Message, QueueError> {
+        trace::dequeue(self, |port| {
+            port.consume_filtered_as(Handoff::Relaxed, |msg_type| port.type_filter.allows(msg_type), |slot| {
+                Message(*slot)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Segment, MSGS, SIZE};
+    use core::ptr::NonNull;
+    use std::collections::HashSet;
+
+    // Nothing copies the slots of the ports here outside a dequeue, which
+    // makes the relaxed calls sound.
+
+    fn numbered(number: u32) -> Message {
+        let mut message = Message([number as u8; SIZE]);
+        message.0[..4].copy_from_slice(&number.to_le_bytes());
+        message
+    }
+
+    #[test]
+    fn relaxed_and_ordered_calls_mix() {
+        let mut port = QueueingPort::new();
+        unsafe { port.enqueue_relaxed(numbered(0)) }.unwrap();
+        port.enqueue(numbered(1)).unwrap();
+        assert_eq!(port.dequeue().unwrap().0, numbered(0).0);
+        assert_eq!(unsafe { port.dequeue_relaxed() }.unwrap().0, numbered(1).0);
+        assert!(matches!(unsafe { port.dequeue_relaxed() }, Err(QueueError::EmptyBuffer)));
+
+        for number in 0..MSGS as u32 {
+            unsafe { port.enqueue_relaxed(numbered(number)) }.unwrap();
+        }
+        assert!(matches!(unsafe { port.enqueue_relaxed(numbered(0)) }, Err(QueueError::FullBuffer)));
+        let stats = port.stats();
+        assert_eq!((stats.enqueued, stats.dequeued, stats.rejected), (MSGS as u32 + 2, 2, 1));
+        assert_eq!(port.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn dequeue_relaxed_applies_the_type_filter() {
+        let mut port = QueueingPort::new();
+        port.set_type_filter(&[7]).unwrap();
+        for msg_type in [3u16, 7] {
+            let mut message = Message([0; SIZE]);
+            message.0[4..6].copy_from_slice(&msg_type.to_le_bytes());
+            unsafe { port.enqueue_relaxed(message) }.unwrap();
+        }
+        assert_eq!(unsafe { port.dequeue_relaxed() }.unwrap().msg_type(), 7);
+        assert_eq!(port.stats().filtered_out, 1);
+    }
+
+    #[test]
+    fn two_threads_pass_messages_relaxed() {
+        const MESSAGES: u32 = 20_000;
+        let segment = Box::new(Segment::new());
+        let mut writer = unsafe { QueueingPort::attach(NonNull::from(&*segment)) };
+        let mut reader = unsafe { QueueingPort::attach(NonNull::from(&*segment)) };
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                let mut number = 0;
+                while number < MESSAGES {
+                    match unsafe { writer.enqueue_relaxed(numbered(number)) } {
+                        Ok(()) => number += 1,
+                        Err(QueueError::FullBuffer) => std::thread::yield_now(),
+                        Err(err) => panic!("enqueue failed: {:?}", err),
+                    }
+                }
+            });
+            let mut expected = 0;
+            while expected < MESSAGES {
+                match unsafe { reader.dequeue_relaxed() } {
+                    Ok(message) => {
+                        assert_eq!(message.0, numbered(expected).0, "whole and in order");
+                        expected += 1;
+                    }
+                    Err(QueueError::EmptyBuffer) => std::thread::yield_now(),
+                    Err(err) => panic!("dequeue failed: {:?}", err),
+                }
+            }
+        });
+    }
+
+    // The model of the handover: a writer and a reader passing `MESSAGES`
+    // messages through `SLOTS` slots, one step per access the relaxed calls
+    // make, under the view semantics of release/acquire atomics. Every
+    // location keeps the values written to it with the view each write
+    // carries: a load may return any of them not older than the thread's
+    // view of the location, and an acquire load takes the write's view on.
+    // The slots are plain memory, which a thread must have seen the last
+    // write to before it accesses them: anything else is a data race. The
+    // reader's filling of the slot with `FREED_SLOT_BYTE` shows the write
+    // after it whether it has finished reading.
+
+    const SLOTS: u32 = 2;
+    const MESSAGES: u32 = 4;
+    /// Times each end finds the queue full or empty before the run is cut.
+    const RETRIES: u32 = 3;
+    const FREED: u32 = u32::MAX;
+
+    const COUNT: usize = 0;
+    const WRITE_INDEX: usize = 1;
+    const READ_INDEX: usize = 2;
+    const SLOT: usize = 3;
+    const LOCATIONS: usize = SLOT + SLOTS as usize;
+    const DONE: u8 = u8::MAX;
+
+    /// For each location, the latest of its writes a thread has seen.
+    type View = [usize; LOCATIONS];
+
+    fn join(view: &mut View, other: &View) {
+        for (time, other) in view.iter_mut().zip(other) {
+            *time = (*time).max(*other);
+        }
+    }
+
+    /// The orderings of the increment and the decrement of `message_count`.
+    #[derive(Clone, Copy)]
+    struct Orderings {
+        publish: Ordering,
+        free: Ordering,
+    }
+
+    #[derive(Clone, PartialEq, Eq, Hash)]
+    struct Thread {
+        view: View,
+        step: u8,
+        message: u32,
+        index: u32,
+        retries: u32,
+    }
+
+    #[derive(Clone, PartialEq, Eq, Hash)]
+    struct State {
+        /// Each location's writes, oldest first, with the views they carry.
+        writes: [Vec<(u32, View)>; LOCATIONS],
+        /// The view the last `SeqCst` fence left.
+        fenced: View,
+        threads: [Thread; 2],
+    }
+
+    impl State {
+        fn new() -> State {
+            let thread = Thread { view: [0; LOCATIONS], step: 0, message: 0, index: 0, retries: 0 };
+            State {
+                writes: core::array::from_fn(|_| vec![(0, [0; LOCATIONS])]),
+                fenced: [0; LOCATIONS],
+                threads: [thread.clone(), thread],
+            }
+        }
+
+        /// The states after each value thread `who` may load from `location`.
+        fn loads(&self, who: usize, location: usize, ordering: Ordering) -> Vec<(u32, State)> {
+            let writes = &self.writes[location];
+            (self.threads[who].view[location]..writes.len())
+                .map(|time| {
+                    let mut state = self.clone();
+                    let view = &mut state.threads[who].view;
+                    view[location] = time;
+                    if ordering == Ordering::Acquire {
+                        join(view, &writes[time].1);
+                    }
+                    (writes[time].0, state)
+                })
+                .collect()
+        }
+
+        fn store(&mut self, who: usize, location: usize, value: u32, ordering: Ordering) {
+            let view = &mut self.threads[who].view;
+            view[location] = self.writes[location].len();
+            let mut carried = [0; LOCATIONS];
+            carried[location] = view[location];
+            if ordering == Ordering::Release {
+                carried = *view;
+            }
+            self.writes[location].push((value, carried));
+        }
+
+        /// Adds `delta` to the latest value, returning the one before. The
+        /// write carries the view of the one it read, continuing its release
+        /// sequence.
+        fn fetch_add(&mut self, who: usize, location: usize, delta: u32, ordering: Ordering) -> u32 {
+            let (previous, mut carried) = *self.writes[location].last().unwrap();
+            let view = &mut self.threads[who].view;
+            view[location] = self.writes[location].len();
+            carried[location] = view[location];
+            if ordering == Ordering::Release {
+                join(&mut carried, view);
+            }
+            self.writes[location].push((previous.wrapping_add(delta), carried));
+            previous
+        }
+
+        fn fence(&mut self, who: usize) {
+            let view = &mut self.threads[who].view;
+            join(view, &self.fenced);
+            self.fenced = *view;
+        }
+
+        /// The value of slot `index`, if thread `who` may access it.
+        fn plain(&self, who: usize, index: u32) -> Result<u32, String> {
+            let writes = &self.writes[SLOT + index as usize];
+            match self.threads[who].view[SLOT + index as usize] == writes.len() - 1 {
+                true => Ok(writes[writes.len() - 1].0),
+                false => Err(format!("{} raced on slot {}", ["the writer", "the reader"][who], index)),
+            }
+        }
+
+        /// Moves thread `who` on to its next message, or to its end.
+        fn finish(&mut self, who: usize) {
+            let thread = &mut self.threads[who];
+            thread.message += 1;
+            thread.step = if thread.message == MESSAGES { DONE } else { 0 };
+        }
+
+        /// The states one step of thread `who` can lead to, none once it
+        /// has retried too often.
+        fn step(&self, who: usize, orderings: Orderings) -> Result<Vec<State>, String> {
+            let Thread { step, message, index, .. } = self.threads[who];
+            let mut next = self.clone();
+            match (who, step) {
+                // Both ends load the count and then their index; the writer
+                // goes on while there is room, the reader while there is a
+                // message.
+                (_, 0) => {
+                    let ready = |count: u32| if who == 0 { count < SLOTS } else { count > 0 };
+                    let states = self.loads(who, COUNT, Ordering::Acquire).into_iter();
+                    return Ok(states
+                        .filter_map(|(count, mut state)| {
+                            let thread = &mut state.threads[who];
+                            match ready(count) {
+                                true => thread.step = 1,
+                                false => thread.retries += 1,
+                            }
+                            (thread.retries <= RETRIES).then_some(state)
+                        })
+                        .collect());
+                }
+                (_, 1) => {
+                    let states = self.loads(who, [WRITE_INDEX, READ_INDEX][who], Ordering::Relaxed).into_iter();
+                    return Ok(states
+                        .map(|(index, mut state)| {
+                            (state.threads[who].index, state.threads[who].step) = (index, 2);
+                            state
+                        })
+                        .collect());
+                }
+                // The writer fills the slot, moves its index and publishes.
+                (0, 2) => {
+                    self.plain(who, index)?;
+                    next.store(who, SLOT + index as usize, message + 1, Ordering::Relaxed);
+                    next.threads[who].step = 3;
+                }
+                (0, 3) => {
+                    next.store(who, WRITE_INDEX, (index + 1) % SLOTS, Ordering::Relaxed);
+                    next.threads[who].step = 4;
+                }
+                (0, 4) => match next.fetch_add(who, COUNT, 1, orderings.publish) {
+                    0 => next.threads[who].step = 5,
+                    _ => next.finish(who),
+                },
+                // The reader reads and frees the slot, moves its index and
+                // gives the slot back.
+                (1, 2) => {
+                    let value = self.plain(who, index)?;
+                    if value != message + 1 {
+                        return Err(format!("read {} in place of message {}", value, message + 1));
+                    }
+                    next.store(who, SLOT + index as usize, FREED, Ordering::Relaxed);
+                    next.threads[who].step = 3;
+                }
+                (1, 3) => {
+                    next.store(who, READ_INDEX, (index + 1) % SLOTS, Ordering::Relaxed);
+                    next.threads[who].step = 4;
+                }
+                (1, 4) => match next.fetch_add(who, COUNT, u32::MAX, orderings.free) {
+                    1 => next.threads[who].step = 5,
+                    _ => next.finish(who),
+                },
+                // Either end, once the count crossed zero.
+                (_, 5) => {
+                    next.fence(who);
+                    next.finish(who);
+                }
+                (_, step) => unreachable!("no step {} of thread {}", step, who),
+            }
+            Ok(vec![next])
+        }
+    }
+
+    /// Runs every interleaving, visiting each state they reach once, and
+    /// returns the number of states in which both ends finished.
+    fn model_check(orderings: Orderings) -> Result<usize, String> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![State::new()];
+        let mut finished = 0;
+        while let Some(state) = pending.pop() {
+            if !seen.insert(state.clone()) {
+                continue;
+            }
+            if state.threads.iter().all(|thread| thread.step == DONE) {
+                finished += 1;
+            }
+            for who in (0..2).filter(|&who| state.threads[who].step != DONE) {
+                pending.extend(state.step(who, orderings)?);
+            }
+        }
+        Ok(finished)
+    }
+
+    #[test]
+    fn the_relaxed_handover_holds_in_every_interleaving() {
+        let finished = model_check(Orderings { publish: Ordering::Release, free: Ordering::Release }).unwrap();
+        assert!(finished > 100, "only {} ends reached", finished);
+    }
+
+    #[test]
+    fn the_model_catches_a_count_without_release() {
+        let unpublished = model_check(Orderings { publish: Ordering::Relaxed, free: Ordering::Release });
+        assert!(matches!(&unpublished, Err(race) if race.starts_with("the reader raced")), "{:?}", unpublished);
+        let unfreed = model_check(Orderings { publish: Ordering::Release, free: Ordering::Relaxed });
+        assert!(matches!(&unfreed, Err(race) if race.starts_with("the writer raced")), "{:?}", unfreed);
+    }
+}
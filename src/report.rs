@@ -0,0 +1,122 @@
+//! How the memory of a segment is spent.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, AtomicU8};
+
+use crate::detach::AttachSlot;
+use crate::history::HistoryRecord;
+use crate::{QueueingPort, Segment, ATTACH_SLOTS, CANARY_LEN, HISTORY_CAPACITY, METADATA_CAPACITY, MSGS, SIZE};
+
+/// Byte breakdown of one segment, see `QueueingPort::memory_report`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// Size of the whole segment.
+    pub total_bytes: usize,
+    /// Header fields: indices, counters and state.
+    pub header_bytes: usize,
+    /// Per-slot bookkeeping, i.e. the guard bytes of the `slot-poison` feature.
+    pub metadata_bytes: usize,
+    /// Message slots.
+    pub payload_bytes: usize,
+    /// Alignment padding in and after the header.
+    pub wasted_bytes: usize,
+}
+
+// Thirty-one u32 words, four of them the writer and reader claims and four the
+// progress times, four state bytes, the metadata area, a sequence number,
+// generation and two-word enqueue time per slot, the history records and
+// the attach slots; keep in sync with `SegmentHeader`.
+const HEADER_FIELD_BYTES: usize = 31 * size_of::<AtomicU32>()
+    + 4 * size_of::<AtomicU8>()
+    + METADATA_CAPACITY
+    + 4 * MSGS * size_of::<AtomicU32>()
+    + HISTORY_CAPACITY * size_of::<HistoryRecord>()
+    + ATTACH_SLOTS * size_of::<AttachSlot>();
+
+impl QueueingPort {
+    /// Reports the memory a segment of this build's geometry takes. The
+    /// handle itself adds `size_of::<QueueingPort>()` minus the segment for
+    /// owned ports, and only its own fields for attached and named ones.
+    pub const fn memory_report() -> MemoryReport {
+        let total_bytes = size_of::<Segment>();
+        let metadata_bytes = CANARY_LEN * MSGS;
+        let payload_bytes = SIZE * MSGS;
+        MemoryReport {
+            total_bytes,
+            header_bytes: HEADER_FIELD_BYTES,
+            metadata_bytes,
+            payload_bytes,
+            wasted_bytes: total_bytes - HEADER_FIELD_BYTES - metadata_bytes - payload_bytes,
+        }
+    }
+}
+
+impl QueueingPort {
+    /// Bytes of the segment: every slot in full with the header, guard
+    /// bytes and alignment padding, not just the `SIZE * MSGS` of payload.
+    /// Named ports map at least this much; the OS may round the mapping up
+    /// to whole pages.
+    pub const fn capacity_bytes(&self) -> usize {
+        size_of::<Segment>()
+    }
+
+    /// Bytes of the queued messages, `SIZE` for each whatever it holds.
+    pub fn utilization_bytes(&self) -> usize {
+        self.len() * SIZE
+    }
+
+    /// Share of `capacity_bytes` not taken by queued messages, header and
+    /// padding included: 1.0 minus `utilization_bytes / capacity_bytes`.
+    pub fn fragmentation_ratio(&self) -> f32 {
+        1.0 - self.utilization_bytes() as f32 / self.capacity_bytes() as f32
+    }
+}
+
+impl MemoryReport {
+    /// Share of the segment that holds message bytes.
+    pub fn effective_utilization(&self) -> f32 {
+        self.payload_bytes as f32 / self.total_bytes as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SegmentHeader;
+
+    #[test]
+    fn report_for_default_geometry() {
+        let report = QueueingPort::memory_report();
+        assert_eq!((SIZE, MSGS), (256, 10));
+        assert_eq!(report.header_bytes, 1280);
+        assert_eq!(report.payload_bytes, 2560);
+        assert_eq!(report.wasted_bytes, 0, "the state bytes fill their word");
+        assert_eq!(
+            report.total_bytes,
+            size_of::<SegmentHeader>() + 10 * 256 + report.metadata_bytes
+        );
+        assert_eq!(
+            report.total_bytes,
+            report.header_bytes + report.metadata_bytes + report.payload_bytes + report.wasted_bytes
+        );
+        #[cfg(not(feature = "slot-poison"))]
+        assert!((report.effective_utilization() - 2560.0 / 3840.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn byte_usage_of_a_port() {
+        let mut port = QueueingPort::new();
+        let capacity = port.capacity_bytes();
+        assert_eq!(capacity, QueueingPort::memory_report().total_bytes);
+        #[cfg(not(feature = "slot-poison"))]
+        assert_eq!(capacity, 3840);
+        assert_eq!((port.utilization_bytes(), port.fragmentation_ratio()), (0, 1.0));
+
+        for tag in 0..3 {
+            port.enqueue(crate::Message([tag; SIZE])).unwrap();
+        }
+        assert_eq!(port.utilization_bytes(), 3 * 256);
+        let expected = 1.0 - 768.0 / capacity as f32;
+        assert!((port.fragmentation_ratio() - expected).abs() < 1e-6, "{}", port.fragmentation_ratio());
+    }
+}
@@ -0,0 +1,289 @@
+//! A queue whose capacity can change while it is in use.
+//!
+//! A segment keeps the size it was created with, so `ResizableQueue` moves
+//! to a new one instead. `resize` sets the port up at the new capacity as
+//! the pending port, drains the current port into it in order, and swaps
+//! the `AtomicPtr` that every `enqueue` and `dequeue` loads to the pending
+//! one, which becomes current. The old port is freed.
+//!
+//! To drain a port nothing else may change it. Every operation registers
+//! itself before loading the pointer and looks at the resizing flag after;
+//! `resize` raises the flag and waits for the registered operations to
+//! finish, both with sequentially consistent ordering, so either side sees
+//! the other. Operations starting while `is_resizing` wait for the swap and
+//! go to the new port. No message is lost or reordered by a resize.
+//!
+//! Every segment has `MSGS` slots, so the capacity is a limit on the
+//! queue's depth at most `MSGS`, as for `BoundedQueueingPort`. Any number of
+//! threads may enqueue and dequeue: the writer and the reader side each
+//! have a handle behind their own mutex. The pointer swap is within the
+//! process, so the queue is for threads, not for processes.
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::boxed::Box;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{wait, DequeuePort, EnqueuePort, Message, QueueError, QueueingPort, Segment, MSGS};
+
+/// One segment of a `ResizableQueue`, with its handles.
+struct Generation {
+    // Declared before the segment they point into.
+    writer: Mutex<QueueingPort>,
+    reader: Mutex<QueueingPort>,
+    capacity: usize,
+    _segment: Box<Segment>,
+}
+
+impl Generation {
+    fn new(capacity: usize) -> Generation {
+        let segment = Box::new(Segment::new());
+        let handle = || unsafe { QueueingPort::attach(NonNull::from(&*segment)) };
+        // The segment is boxed, so it stays put, and outlives both handles.
+        Generation { writer: Mutex::new(handle()), reader: Mutex::new(handle()), capacity, _segment: segment }
+    }
+}
+
+fn lock(port: &Mutex<QueueingPort>) -> MutexGuard<'_, QueueingPort> {
+    // As with the channel's mutex, the header is only changed through
+    // atomics, so a panic under the lock leaves nothing half-updated.
+    port.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A queue of up to `capacity()` messages that `resize` changes in place,
+/// see the module documentation.
+pub struct ResizableQueue {
+    current: AtomicPtr<Generation>,
+    resizing: AtomicBool,
+    /// Operations between loading `current` and being done with it.
+    in_flight: AtomicUsize,
+    /// Keeps resizes one at a time.
+    resizer: Mutex<()>,
+}
+
+/// An operation registered in `in_flight`, on the generation it loaded.
+struct InFlight<'a> {
+    queue: &'a ResizableQueue,
+    generation: &'a Generation,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.queue.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ResizableQueue {
+    /// An empty queue holding up to `capacity` messages, failing with
+    /// `QueueError::DepthExceeded` if `capacity` is 0 or above `MSGS`.
+    pub fn new(capacity: usize) -> Result<ResizableQueue, QueueError> {
+        check_capacity(capacity)?;
+        Ok(ResizableQueue {
+            current: AtomicPtr::new(Box::into_raw(Box::new(Generation::new(capacity)))),
+            resizing: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            resizer: Mutex::new(()),
+        })
+    }
+
+    /// Enqueues `message`, failing with `QueueError::FullBuffer` if
+    /// `capacity()` messages are queued.
+    pub fn enqueue(&self, message: Message) -> Result<(), QueueError> {
+        let op = self.enter();
+        let mut writer = lock(&op.generation.writer);
+        if writer.len() >= op.generation.capacity {
+            return Err(QueueError::FullBuffer);
+        }
+        writer.enqueue(message)
+    }
+
+    pub fn dequeue(&self) -> Result<Message, QueueError> {
+        let op = self.enter();
+        let result = lock(&op.generation.reader).try_dequeue();
+        result
+    }
+
+    /// Messages queued, as of some moment during the call.
+    pub fn len(&self) -> usize {
+        lock(&self.enter().generation.reader).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.enter().generation.capacity
+    }
+
+    /// Whether a `resize` is between raising its flag and the swap.
+    pub fn is_resizing(&self) -> bool {
+        self.resizing.load(Ordering::Acquire)
+    }
+
+    /// Moves the queue to a port of capacity `new_count`, keeping its
+    /// messages in order; see the module documentation. Fails with
+    /// `QueueError::DepthExceeded`, leaving the queue as it was, if
+    /// `new_count` is 0 or above `MSGS`, or below the messages queued.
+    pub fn resize(&self, new_count: usize) -> Result<(), QueueError> {
+        check_capacity(new_count)?;
+        let _resizer = self.resizer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.resizing.store(true, Ordering::SeqCst);
+        while self.in_flight.load(Ordering::SeqCst) != 0 {
+            wait::backoff();
+        }
+        // Nothing is on the current port now, and nothing starts on it.
+        let current = unsafe { &*self.current.load(Ordering::Acquire) };
+        let mut reader = lock(&current.reader);
+        if reader.len() > new_count {
+            drop(reader);
+            self.resizing.store(false, Ordering::Release);
+            return Err(QueueError::DepthExceeded);
+        }
+        let pending = Generation::new(new_count);
+        {
+            let mut writer = lock(&pending.writer);
+            while let Ok(message) = reader.try_dequeue() {
+                writer.enqueue(message).expect("the pending port holds every queued message");
+            }
+        }
+        drop(reader);
+        let old = self.current.swap(Box::into_raw(Box::new(pending)), Ordering::AcqRel);
+        // No operation holds the old generation: they all finished before
+        // the drain, and those since load the new one.
+        drop(unsafe { Box::from_raw(old) });
+        self.resizing.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Registers an operation on the current generation, waiting out a
+    /// resize.
+    fn enter(&self) -> InFlight<'_> {
+        loop {
+            self.in_flight.fetch_add(1, Ordering::SeqCst);
+            if !self.resizing.load(Ordering::SeqCst) {
+                // Registered with no resize under way: the generation stays
+                // until the guard is dropped.
+                let generation = unsafe { &*self.current.load(Ordering::Acquire) };
+                return InFlight { queue: self, generation };
+            }
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            while self.resizing.load(Ordering::Acquire) {
+                wait::backoff();
+            }
+        }
+    }
+}
+
+fn check_capacity(capacity: usize) -> Result<(), QueueError> {
+    if capacity == 0 || capacity > MSGS {
+        return Err(QueueError::DepthExceeded);
+    }
+    Ok(())
+}
+
+impl Drop for ResizableQueue {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(*self.current.get_mut()) });
+    }
+}
+
+impl EnqueuePort for ResizableQueue {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        ResizableQueue::enqueue(self, message)
+    }
+}
+
+impl DequeuePort for ResizableQueue {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        ResizableQueue::dequeue(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+    use std::thread;
+
+    fn numbered(writer: u8, n: u32) -> Message {
+        let mut bytes = [writer; SIZE];
+        bytes[..4].copy_from_slice(&n.to_le_bytes());
+        Message(bytes)
+    }
+
+    #[test]
+    fn resize_keeps_the_messages_in_order() {
+        let queue = ResizableQueue::new(4).unwrap();
+        for n in 0..4 {
+            queue.enqueue(numbered(0, n)).unwrap();
+        }
+        assert!(matches!(queue.enqueue(numbered(0, 4)), Err(QueueError::FullBuffer)));
+        queue.resize(8).unwrap();
+        assert_eq!(queue.capacity(), 8);
+        for n in 4..8 {
+            queue.enqueue(numbered(0, n)).unwrap();
+        }
+        assert!(matches!(queue.enqueue(numbered(0, 8)), Err(QueueError::FullBuffer)));
+
+        assert!(matches!(queue.resize(4), Err(QueueError::DepthExceeded)), "8 queued");
+        assert!(matches!(queue.resize(MSGS + 1), Err(QueueError::DepthExceeded)));
+        assert!(!queue.is_resizing());
+        assert_eq!(queue.capacity(), 8);
+        for n in 0..8 {
+            assert_eq!(queue.dequeue().unwrap().0, numbered(0, n).0);
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn no_message_is_lost_resizing_under_concurrent_writers() {
+        const WRITERS: u8 = 3;
+        const PER_WRITER: u32 = 2_000;
+        let queue = ResizableQueue::new(4).unwrap();
+        let received = thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for n in 0..PER_WRITER {
+                        while queue.enqueue(numbered(writer, n)).is_err() {
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+            let reader = scope.spawn(|| {
+                let mut received = Vec::new();
+                while received.len() < WRITERS as usize * PER_WRITER as usize {
+                    match queue.dequeue() {
+                        Ok(message) => received.push(message),
+                        Err(_) => thread::yield_now(),
+                    }
+                }
+                received
+            });
+            // From 4 to 8, and back and forth while the writers run; a
+            // shrink finding more than 4 queued is refused and retried.
+            let mut resizes = 0;
+            while !reader.is_finished() {
+                let count = if resizes % 2 == 0 { 8 } else { 4 };
+                if queue.resize(count).is_ok() {
+                    resizes += 1;
+                }
+                thread::yield_now();
+            }
+            assert!(resizes > 0);
+            reader.join().unwrap()
+        });
+
+        let mut next = [0u32; WRITERS as usize];
+        for message in received {
+            let writer = message.0[SIZE - 1] as usize;
+            let n = u32::from_le_bytes(message.0[..4].try_into().unwrap());
+            assert_eq!(n, next[writer], "writer {}'s messages in order, none lost", writer);
+            next[writer] += 1;
+        }
+        assert_eq!(next, [PER_WRITER; WRITERS as usize]);
+        assert!(queue.is_empty());
+    }
+}
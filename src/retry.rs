@@ -0,0 +1,188 @@
+//! Retrying the creation of a named segment that failed for a passing
+//! reason.
+//!
+//! Making the mapping can fail while `/dev/shm` is briefly full, or the
+//! process briefly out of descriptors, and succeed a moment later. A
+//! `RetryPolicy` set with `PortConfig::retry` has `create_with_config` try
+//! again after such a failure, waiting `backoff_ms` before the second
+//! attempt and twice as long before each one after, plus up to `jitter_ms`
+//! chosen at random so that processes started together do not all retry
+//! at once. Only failures that may pass are retried: no space
+//! (`OpenFailure::InsufficientSpace`) and transient OS errors such as
+//! `EAGAIN` or `EMFILE`. A name in use or a permission denied fails at
+//! once, as does anything other than the mapping itself, like the quota.
+//!
+//! After `max_attempts` failed attempts the error is
+//! `PortError::AllocationFailed`, with the number made and the last
+//! failure. The default policy makes one attempt and returns its error
+//! unchanged.
+
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{OpenFailure, PortError, PortOpenError};
+
+/// How `create_with_config` retries, see the `retry` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in all, the first included; 0 is taken as 1.
+    pub max_attempts: u32,
+    /// The wait before the second attempt, doubled for each one after.
+    pub backoff_ms: u64,
+    /// The most added to each wait at random.
+    pub jitter_ms: u64,
+}
+
+impl RetryPolicy {
+    /// One attempt, no retry.
+    pub const NONE: RetryPolicy = RetryPolicy { max_attempts: 1, backoff_ms: 0, jitter_ms: 0 };
+
+    /// The wait before attempt `attempt + 1`, having made `attempt`.
+    fn delay(&self, attempt: u32, rng: &mut XorShift) -> Duration {
+        let backoff = self.backoff_ms.saturating_mul(1 << (attempt - 1).min(32));
+        let jitter = match self.jitter_ms {
+            0 => 0,
+            jitter => rng.next() % (jitter + 1),
+        };
+        Duration::from_millis(backoff.saturating_add(jitter))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::NONE
+    }
+}
+
+/// Whether a failed creation may succeed if tried again shortly.
+fn is_transient(error: &PortOpenError) -> bool {
+    match error.kind {
+        OpenFailure::InsufficientSpace { .. } => true,
+        OpenFailure::MappingFailed { .. } => error.is_retryable(),
+        _ => false,
+    }
+}
+
+/// Calls `attempt` until it succeeds, fails for good or has been called
+/// `policy.max_attempts` times.
+pub(crate) fn with_retries<T>(
+    policy: &RetryPolicy,
+    mut attempt: impl FnMut() -> Result<T, PortError>,
+) -> Result<T, PortError> {
+    let max_attempts = policy.max_attempts.max(1);
+    let mut rng = XorShift::seeded();
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let error = match attempt() {
+            Err(PortError::Open(error)) if is_transient(&error) => error,
+            result => return result,
+        };
+        if attempts == max_attempts {
+            return Err(match attempts {
+                1 => PortError::Open(error),
+                _ => PortError::AllocationFailed { attempts, last: error },
+            });
+        }
+        thread::sleep(policy.delay(attempts, &mut rng));
+    }
+}
+
+/// Xorshift64, for jitter that differs between processes.
+struct XorShift(u64);
+
+impl XorShift {
+    fn seeded() -> XorShift {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+        // Never 0, which xorshift would stay at.
+        XorShift((time ^ u64::from(std::process::id()).rotate_left(32)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::open_error::create_named;
+    use crate::SegmentGeometry;
+    use std::time::Instant;
+
+    fn no_space() -> PortError {
+        let requested = SegmentGeometry::PORT.segment_len;
+        let kind = OpenFailure::InsufficientSpace { requested, available_hint: None };
+        PortError::Open(PortOpenError::new(kind, "retried", SegmentGeometry::PORT))
+    }
+
+    #[test]
+    fn creation_succeeds_on_the_third_attempt() {
+        let name = format!("qp_retry_{}", std::process::id());
+        let policy = RetryPolicy { max_attempts: 3, backoff_ms: 5, jitter_ms: 5 };
+        let mut attempts = 0;
+        let start = Instant::now();
+        let shmem = with_retries(&policy, || {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(no_space()),
+                _ => create_named(&name, SegmentGeometry::PORT),
+            }
+        })
+        .unwrap();
+        assert_eq!(attempts, 3);
+        assert_eq!(shmem.len(), SegmentGeometry::PORT.segment_len);
+        // 5ms, then 10ms, each with its jitter.
+        assert!(start.elapsed() >= Duration::from_millis(15));
+    }
+
+    #[test]
+    fn the_last_failure_is_returned_with_the_attempts() {
+        let policy = RetryPolicy { max_attempts: 3, backoff_ms: 1, jitter_ms: 0 };
+        let mut attempts = 0;
+        let exhausted = with_retries(&policy, || -> Result<(), PortError> {
+            attempts += 1;
+            Err(no_space())
+        });
+        assert_eq!(attempts, 3);
+        assert!(matches!(
+            exhausted,
+            Err(PortError::AllocationFailed { attempts: 3, last })
+                if matches!(last.kind, OpenFailure::InsufficientSpace { .. })
+        ));
+
+        // Without retries the error is the attempt's own.
+        let once = with_retries(&RetryPolicy::NONE, || -> Result<(), _> { Err(no_space()) });
+        assert!(matches!(once, Err(PortError::Open(_))));
+    }
+
+    #[test]
+    fn lasting_failures_are_not_retried() {
+        let policy = RetryPolicy { max_attempts: 5, backoff_ms: 1_000, jitter_ms: 0 };
+        let mut attempts = 0;
+        let taken = with_retries(&policy, || -> Result<(), PortError> {
+            attempts += 1;
+            Err(PortError::Open(PortOpenError::new(OpenFailure::NameInUse, "taken", SegmentGeometry::PORT)))
+        });
+        assert_eq!(attempts, 1);
+        assert!(matches!(taken, Err(PortError::Open(error)) if error.kind == OpenFailure::NameInUse));
+        let quota = with_retries(&policy, || -> Result<(), _> { Err(PortError::QuotaExceeded) });
+        assert!(matches!(quota, Err(PortError::QuotaExceeded)));
+    }
+
+    #[test]
+    fn waits_double_and_jitter_stays_in_bounds() {
+        let policy = RetryPolicy { max_attempts: 10, backoff_ms: 10, jitter_ms: 3 };
+        let mut rng = XorShift::seeded();
+        for attempt in 1..5 {
+            let delay = policy.delay(attempt, &mut rng).as_millis() as u64;
+            let backoff = 10 << (attempt - 1);
+            assert!((backoff..=backoff + 3).contains(&delay), "{} after attempt {}", delay, attempt);
+        }
+    }
+}
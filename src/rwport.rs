@@ -0,0 +1,113 @@
+//! A port shared between threads behind a reader-writer lock.
+//!
+//! Destructive operations need the write lock, like the port's own `&mut`
+//! methods. Any number of threads can hold the read lock at once and look at
+//! queued messages with `dequeue_shared`, which leaves the queue untouched.
+
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::QueueingPort;
+
+/// A `QueueingPort` that several threads can read concurrently; see the
+/// module documentation.
+pub struct RwQueueingPort {
+    port: RwLock<QueueingPort>,
+}
+
+// The port is only reached through the lock. Its `&self` methods touch the
+// header through atomics and only read occupied slots, which no one can
+// free while a read guard is held.
+unsafe impl Send for RwQueueingPort {}
+unsafe impl Sync for RwQueueingPort {}
+
+impl RwQueueingPort {
+    pub fn new(port: QueueingPort) -> RwQueueingPort {
+        RwQueueingPort { port: RwLock::new(port) }
+    }
+
+    /// Shared access, for `dequeue_shared`, `len`, `stats` and the like.
+    pub fn read(&self) -> RwLockReadGuard<'_, QueueingPort> {
+        // As with the channel's mutex, the header is only changed through
+        // atomics, so a panic under the lock leaves nothing half-updated.
+        self.port.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Exclusive access, for `enqueue` and `dequeue`.
+    pub fn write(&self) -> RwLockWriteGuard<'_, QueueingPort> {
+        self.port.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn into_inner(self) -> QueueingPort {
+        self.port.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, MSGS, SIZE};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn numbered(sequence: u32) -> Message {
+        let mut bytes = [sequence as u8; SIZE];
+        bytes[..4].copy_from_slice(&sequence.to_le_bytes());
+        Message(bytes)
+    }
+
+    #[test]
+    fn dequeue_shared_peeks_by_sequence() {
+        let mut port = QueueingPort::new();
+        for sequence in 0..3 {
+            port.enqueue(numbered(sequence)).unwrap();
+        }
+        port.dequeue().unwrap();
+        assert!(port.dequeue_shared(0).is_none(), "already dequeued");
+        assert_eq!(port.dequeue_shared(2).unwrap().0, numbered(2).0);
+        assert_eq!(port.dequeue_shared(1).unwrap().0, numbered(1).0);
+        assert!(port.dequeue_shared(3).is_none(), "not yet enqueued");
+        assert_eq!(port.len(), 2);
+    }
+
+    #[test]
+    fn readers_run_alongside_writer() {
+        let shared = RwQueueingPort::new(QueueingPort::new());
+        let stop = AtomicBool::new(false);
+        let deadline = Instant::now() + Duration::from_millis(500);
+
+        std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut seen = 0u64;
+                        while !stop.load(Ordering::Relaxed) {
+                            let port = shared.read();
+                            let first = port.stats().dequeued;
+                            for sequence in first..first + port.len() as u32 {
+                                let message = port.dequeue_shared(sequence).expect("queued under the read lock");
+                                assert_eq!(message.0, numbered(sequence).0);
+                                seen += 1;
+                            }
+                        }
+                        seen
+                    })
+                })
+                .collect();
+
+            let mut sequence = 0;
+            while Instant::now() < deadline {
+                let mut port = shared.write();
+                if port.len() == MSGS {
+                    port.dequeue().unwrap();
+                }
+                port.enqueue(numbered(sequence)).unwrap();
+                sequence += 1;
+            }
+            stop.store(true, Ordering::Relaxed);
+            for reader in readers {
+                assert!(reader.join().unwrap() > 0);
+            }
+        });
+        assert_eq!(shared.into_inner().len(), MSGS);
+    }
+}
@@ -0,0 +1,358 @@
+//! A startup check that the memory a port runs on behaves like memory.
+//!
+//! Misconfigured MPU or cache settings can leave a "shared" segment
+//! non-coherent: writes get lost, or reach the other side late, and the
+//! queue then fails in ways far from the cause. `self_test` runs a fixed
+//! script on an empty port before it is put to use:
+//!
+//! - every slot is written with a pattern of its own and read back, then
+//!   again with the complement, so each bit is seen both ways;
+//! - the ring is driven around its end several times, one message in and
+//!   one out, checking each comes back in order and intact;
+//! - the empty and full boundaries are probed: nothing to dequeue when
+//!   empty, no room when full, room again after one dequeue.
+//!
+//! `self_test_with_peer` adds a check across two handles on the same
+//! segment, typically two mappings of it: each side publishes a message
+//! the other must see, intact, within the timeout, through the same
+//! release/acquire pairs on `message_count` the queue itself relies on.
+//! A peer that never sees the message fails the check once the timeout
+//! passes instead of waiting forever.
+//!
+//! Reads compare the slot bytes with volatile loads, so they go to the
+//! memory rather than to values the compiler remembers writing.
+//!
+//! The script bypasses the handle's type filter, dedup window, CRC, credit
+//! and peer checks: it tests the memory, not the port's configuration.
+//! Afterwards the header is put back as it was found, counters included,
+//! pass or fail, so the port is empty and its statistics untouched. No
+//! handle other than the given peer may use the segment meanwhile.
+
+use core::ptr;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::{QueueError, QueueingPort, Segment, FREED_SLOT_BYTE, MSGS, SIZE};
+
+/// The outcome of one check of `self_test`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTestCheck {
+    pub passed: bool,
+    /// Time the check took on the port's clock.
+    pub elapsed_ns: u64,
+}
+
+/// What `self_test` found, check by check; see the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub slot_patterns: SelfTestCheck,
+    pub wrap_around: SelfTestCheck,
+    pub boundaries: SelfTestCheck,
+    /// `None` unless run by `self_test_with_peer`.
+    pub cross_handle: Option<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    /// Whether every check that ran passed.
+    pub fn passed(&self) -> bool {
+        self.slot_patterns.passed
+            && self.wrap_around.passed
+            && self.boundaries.passed
+            && self.cross_handle.is_none_or(|check| check.passed)
+    }
+}
+
+impl QueueingPort {
+    /// Runs the single-handle checks of the module documentation on this
+    /// port, which must be empty (`QueueError::NotEmpty` otherwise).
+    pub fn self_test(&mut self) -> Result<SelfTestReport, QueueError> {
+        self.check_self_testable()?;
+        let saved = SavedHeader::of(self.segment());
+        let report = self.run_checks();
+        saved.restore(self.segment());
+        Ok(report)
+    }
+
+    /// Like `self_test`, and then checks that `peer`, a second handle on
+    /// the same segment, and this one each see what the other publishes
+    /// within `timeout`. Both must be empty.
+    pub fn self_test_with_peer(
+        &mut self,
+        peer: &mut QueueingPort,
+        timeout: Duration,
+    ) -> Result<SelfTestReport, QueueError> {
+        self.check_self_testable()?;
+        peer.check_self_testable()?;
+        let (saved, peer_saved) = (SavedHeader::of(self.segment()), SavedHeader::of(peer.segment()));
+        let mut report = self.run_checks();
+        saved.restore(self.segment());
+        let start = self.clock().now_ns();
+        let passed = self.visible_to(peer, timeout, 0x5a) && peer.visible_to(self, timeout, 0xa5);
+        report.cross_handle = Some(SelfTestCheck { passed, elapsed_ns: self.clock().now_ns().wrapping_sub(start) });
+        // If the peer is not on the same memory after all, it has its own
+        // header to put back.
+        saved.restore(self.segment());
+        peer_saved.restore(peer.segment());
+        Ok(report)
+    }
+
+    fn check_self_testable(&self) -> Result<(), QueueError> {
+        self.check_generation()?;
+        self.check_poison()?;
+        match self.len() {
+            0 => Ok(()),
+            len => Err(QueueError::NotEmpty { len }),
+        }
+    }
+
+    fn run_checks(&self) -> SelfTestReport {
+        SelfTestReport {
+            slot_patterns: self.timed(|port| port.check_slot_patterns()),
+            wrap_around: self.timed(|port| port.check_wrap_around()),
+            boundaries: self.timed(|port| port.check_boundaries()),
+            cross_handle: None,
+        }
+    }
+
+    fn timed(&self, check: impl FnOnce(&QueueingPort) -> bool) -> SelfTestCheck {
+        let start = self.clock().now_ns();
+        let passed = check(self);
+        SelfTestCheck { passed, elapsed_ns: self.clock().now_ns().wrapping_sub(start) }
+    }
+
+    fn check_slot_patterns(&self) -> bool {
+        (0..2u32).all(|round| {
+            let seed = |slot: usize| (slot as u32) << 1 | round;
+            (0..MSGS).all(|slot| put(self.segment(), seed(slot)).is_ok())
+                && (0..MSGS).all(|slot| self.take_matching(seed(slot)))
+        })
+    }
+
+    fn check_wrap_around(&self) -> bool {
+        let ahead = MSGS / 2;
+        let steps = 3 * MSGS;
+        let seed = |step: usize| 0x100 + step as u32;
+        (0..ahead).all(|step| put(self.segment(), seed(step)).is_ok())
+            && (0..steps).all(|step| put(self.segment(), seed(step + ahead)).is_ok() && self.take_matching(seed(step)))
+            && (steps..steps + ahead).all(|step| self.take_matching(seed(step)))
+    }
+
+    fn check_boundaries(&self) -> bool {
+        let segment = self.segment();
+        let seed = |step: usize| 0x200 + step as u32;
+        self.is_empty()
+            && matches!(self.consume_front(|_| ()), Err(QueueError::EmptyBuffer))
+            && (0..MSGS).all(|step| put(segment, seed(step)).is_ok())
+            && self.len() == MSGS
+            && matches!(put(segment, seed(MSGS)), Err(QueueError::FullBuffer))
+            && self.take_matching(seed(0))
+            && put(segment, seed(MSGS)).is_ok()
+            && (1..=MSGS).all(|step| self.take_matching(seed(step)))
+            && self.is_empty()
+            && matches!(self.consume_front(|_| ()), Err(QueueError::EmptyBuffer))
+    }
+
+    /// Publishes a message through this handle and checks that `peer` sees
+    /// it intact, and that this handle then sees `peer` consume it, each
+    /// within `timeout`.
+    fn visible_to(&self, peer: &QueueingPort, timeout: Duration, seed: u32) -> bool {
+        let count = |port: &QueueingPort| port.segment().header.message_count.load(port.byte_order(), Ordering::Acquire);
+        put(self.segment(), seed).is_ok()
+            && self.wait_until(timeout, || count(peer) == 1)
+            && peer.take_matching(seed)
+            && self.wait_until(timeout, || count(self) == 0)
+    }
+
+    /// Polls `done` on the port's clock until it holds or `timeout` passes,
+    /// looking at least once.
+    fn wait_until(&self, timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+        let deadline = self.clock().now_ns().saturating_add(timeout.as_nanos() as u64);
+        loop {
+            if done() {
+                return true;
+            }
+            if self.clock().now_ns() >= deadline {
+                return false;
+            }
+            self.clock().pause(deadline);
+        }
+    }
+
+    /// Dequeues the oldest message, checking it holds pattern `seed`.
+    fn take_matching(&self, seed: u32) -> bool {
+        self.consume_front(|slot| matches_pattern(slot, seed)).unwrap_or(false)
+    }
+}
+
+/// Enqueues pattern `seed` into `segment` as the writer, without any of
+/// the handle's checks but a full queue.
+fn put(segment: &Segment, seed: u32) -> Result<(), QueueError> {
+    if segment.header.message_count.load(segment.header.byte_order(), Ordering::Acquire) as usize >= MSGS {
+        return Err(QueueError::FullBuffer);
+    }
+    segment.fill_free_slot(0, |slot| {
+        for (offset, byte) in slot.iter_mut().enumerate() {
+            *byte = pattern_byte(seed, offset);
+        }
+    });
+    segment.publish(1, None, &crate::NoClock);
+    Ok(())
+}
+
+/// Byte `offset` of pattern `seed`. Seeds differing only in the lowest
+/// bit are complements of each other.
+fn pattern_byte(seed: u32, offset: usize) -> u8 {
+    let mixed = (seed >> 1).wrapping_mul(0x9e37_79b9).wrapping_add((offset as u32).wrapping_mul(0x85eb_ca6b));
+    let byte = (mixed >> 24) as u8 ^ offset as u8;
+    if seed & 1 == 0 {
+        byte
+    } else {
+        !byte
+    }
+}
+
+fn matches_pattern(slot: &[u8; SIZE], seed: u32) -> bool {
+    // Volatile, so every byte is loaded from the slot itself.
+    (0..SIZE).all(|offset| unsafe { ptr::read_volatile(slot.as_ptr().add(offset)) } == pattern_byte(seed, offset))
+}
+
+/// The header words the script changes, to put back afterwards.
+struct SavedHeader {
+    write_index: u32,
+    read_index: u32,
+    enqueued: u32,
+    dequeued: u32,
+    rejected: u32,
+    high_watermark: u32,
+    credits: u32,
+}
+
+impl SavedHeader {
+    fn of(segment: &Segment) -> SavedHeader {
+        let header = &segment.header;
+        let order = header.byte_order();
+        SavedHeader {
+            write_index: header.write_index.load(order, Ordering::Relaxed),
+            read_index: header.read_index.load(order, Ordering::Relaxed),
+            enqueued: header.enqueued.load(order, Ordering::Relaxed),
+            dequeued: header.dequeued.load(order, Ordering::Relaxed),
+            rejected: header.rejected.load(order, Ordering::Relaxed),
+            high_watermark: header.high_watermark.load(order, Ordering::Relaxed),
+            credits: header.credits.load(order, Ordering::Relaxed),
+        }
+    }
+
+    /// Empties the queue and restores the saved words, also after a failed
+    /// check left messages behind.
+    fn restore(&self, segment: &Segment) {
+        let header = &segment.header;
+        let order = header.byte_order();
+        for index in 0..MSGS {
+            unsafe { ptr::write_bytes(segment.slot(index), FREED_SLOT_BYTE, SIZE) };
+        }
+        header.write_index.store(order, self.write_index, Ordering::Relaxed);
+        header.read_index.store(order, self.read_index, Ordering::Relaxed);
+        header.enqueued.store(order, self.enqueued, Ordering::Relaxed);
+        header.dequeued.store(order, self.dequeued, Ordering::Relaxed);
+        header.rejected.store(order, self.rejected, Ordering::Relaxed);
+        header.high_watermark.store(order, self.high_watermark, Ordering::Relaxed);
+        header.credits.store(order, self.credits, Ordering::Relaxed);
+        header.message_count.store(order, 0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, Message, QueueStats};
+    use core::ptr::NonNull;
+
+    fn assert_clean(report: &SelfTestReport) {
+        assert!(report.passed(), "{:?}", report);
+    }
+
+    #[test]
+    fn owned_port_passes_and_is_left_as_found() {
+        let mut port = QueueingPort::new();
+        for _ in 0..3 {
+            port.enqueue(Message([1; SIZE])).unwrap();
+            port.dequeue().unwrap();
+        }
+        let before = port.stats();
+        let report = port.self_test().unwrap();
+        assert_clean(&report);
+        assert_eq!(report.cross_handle, None);
+        assert_eq!(port.stats(), before);
+        assert!(port.is_empty());
+
+        port.enqueue(Message([7; SIZE])).unwrap();
+        let received = port.dequeue_timed().unwrap();
+        assert_eq!(received.message.0, [7; SIZE]);
+        assert_eq!(received.sequence, 3, "numbering carries on");
+    }
+
+    #[test]
+    fn non_empty_port_is_refused() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message([1; SIZE])).unwrap();
+        assert!(matches!(port.self_test(), Err(QueueError::NotEmpty { len: 1 })));
+        assert_eq!(port.dequeue().unwrap().0, [1; SIZE], "left alone");
+    }
+
+    #[test]
+    fn two_handles_on_one_segment_see_each_other() {
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut writer = unsafe { QueueingPort::attach(segment) };
+        let mut reader = unsafe { QueueingPort::attach(segment) };
+        let report = writer.self_test_with_peer(&mut reader, Duration::from_secs(1)).unwrap();
+        assert_clean(&report);
+        assert!(report.cross_handle.is_some());
+        assert_eq!(reader.stats(), QueueStats::default());
+        drop((writer, reader));
+        drop(unsafe { Box::from_raw(segment.as_ptr()) });
+    }
+
+    #[test]
+    fn incoherent_peer_fails_within_the_timeout() {
+        // A "peer" on memory of its own never sees the writes, as with a
+        // mapping whose cache is not kept coherent.
+        let (mut port, clock) = QueueingPort::new_simulated();
+        let mut elsewhere = QueueingPort::new();
+        let report = port.self_test_with_peer(&mut elsewhere, Duration::from_millis(5)).unwrap();
+        assert!(!report.passed());
+        assert!(report.slot_patterns.passed && report.wrap_around.passed && report.boundaries.passed);
+        let cross = report.cross_handle.unwrap();
+        assert!(!cross.passed);
+        assert_eq!((cross.elapsed_ns, clock.now_ns()), (5_000_000, 5_000_000), "gave up at the timeout");
+        assert!(port.is_empty() && elsewhere.is_empty());
+        assert_eq!((port.stats(), elsewhere.stats()), (QueueStats::default(), QueueStats::default()));
+    }
+
+    #[cfg(feature = "shmem")]
+    #[test]
+    fn named_port_mappings_see_each_other() {
+        use std::thread;
+        use std::time::Instant;
+
+        let name = format!("/qp_selftest_{}", std::process::id());
+        let opener = thread::spawn({
+            let name = name.clone();
+            move || {
+                let deadline = Instant::now() + crate::HANDSHAKE_TIMEOUT;
+                loop {
+                    match QueueingPort::open(&name) {
+                        Ok(port) => return port,
+                        Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+                        Err(error) => panic!("open failed: {:?}", error),
+                    }
+                }
+            }
+        });
+        let mut writer = QueueingPort::create(&name).unwrap();
+        let mut reader = opener.join().unwrap();
+        assert_clean(&reader.self_test().unwrap());
+        assert_clean(&writer.self_test_with_peer(&mut reader, Duration::from_secs(1)).unwrap());
+        writer.enqueue(Message([3; SIZE])).unwrap();
+        assert_eq!(reader.dequeue().unwrap().0, [3; SIZE]);
+    }
+}
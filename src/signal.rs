@@ -0,0 +1,219 @@
+//! Zero-length messages, for doorbells.
+//!
+//! `enqueue_signal` queues a message with no payload, "something changed,
+//! go look", where a full slot of bytes would say nothing more. Like the
+//! end-of-stream record it is a `MessageHeader` and nothing else, with
+//! every other byte zero:
+//!
+//! ```text
+//! 0..4   zero (src_id, dst_id)
+//! 4..6   SIGNAL_MSG_TYPE
+//! 6..8   flags, SIGNAL_FLAG and nothing else
+//! ```
+//!
+//! On a CRC port it is sealed like any other message; on a compressed one
+//! it reads as a message of length 0. A signal takes a slot and a sequence
+//! number, counts in `enqueued` and `dequeued`, keeps its place between
+//! the messages around it and is dropped for a newer message where the
+//! port drops the oldest; `Message::signal` makes the record for
+//! `Port<Broadcast>::publish`. Type filters and the dedup window let every
+//! signal through. It comes out as `Received::Signal` from `receive`, as 0
+//! bytes from `dequeue_bytes`, with `MessageHeader::is_signal` from
+//! `dequeue_with_header`, and as the record from `dequeue`.
+//!
+//! With `set_coalesce_signals(true)`, a handle's `enqueue_signal` does
+//! nothing while the last signal that handle queued is still the newest
+//! message in the queue, so a burst of doorbells the receiver has not got
+//! to yet rings once. `QueueStats` counts the signals each handle sent,
+//! coalesced and received.
+
+use core::cell::Cell;
+use core::sync::atomic::Ordering;
+
+use crate::{Message, MessageHeader, QueueError, QueueingPort, WireFeatures, SIZE};
+
+/// Message type id reserved for signals.
+pub const SIGNAL_MSG_TYPE: u16 = 0xfffd;
+/// `MessageHeader::flags` bit marking a signal.
+pub const SIGNAL_FLAG: u16 = 1 << 14;
+
+const SIGNAL_HEADER: MessageHeader =
+    MessageHeader { src_id: 0, dst_id: 0, msg_type: SIGNAL_MSG_TYPE, flags: SIGNAL_FLAG };
+
+pub(crate) fn is_signal(slot: &[u8; SIZE]) -> bool {
+    MessageHeader::read(slot) == SIGNAL_HEADER
+}
+
+impl Message {
+    /// A signal, unsealed; see the module documentation.
+    pub fn signal() -> Message {
+        let mut record = Message([0; SIZE]);
+        SIGNAL_HEADER.write(&mut record.0);
+        record
+    }
+
+    pub fn is_signal(&self) -> bool {
+        is_signal(&self.0)
+    }
+}
+
+impl MessageHeader {
+    /// Whether this is the header of a signal, which has no payload.
+    pub fn is_signal(&self) -> bool {
+        *self == SIGNAL_HEADER
+    }
+}
+
+/// A handle's signal counts and coalescing state.
+pub(crate) struct Signals {
+    pub(crate) coalesce: bool,
+    /// The `enqueued` count the last signal this handle queued took.
+    last_sequence: Cell<Option<u32>>,
+    pub(crate) sent: Cell<u32>,
+    pub(crate) coalesced: Cell<u32>,
+    pub(crate) received: Cell<u32>,
+}
+
+impl Signals {
+    pub(crate) const fn new() -> Signals {
+        Signals {
+            coalesce: false,
+            last_sequence: Cell::new(None),
+            sent: Cell::new(0),
+            coalesced: Cell::new(0),
+            received: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn count_received(&self, slot: &[u8; SIZE]) {
+        if is_signal(slot) {
+            self.received.set(self.received.get().wrapping_add(1));
+        }
+    }
+}
+
+impl QueueingPort {
+    /// Enqueues a signal, see the module documentation. Fails as `enqueue`
+    /// does, but never while coalescing drops it.
+    pub fn enqueue_signal(&mut self) -> Result<(), QueueError> {
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let enqueued = header.enqueued.load(order, Ordering::Relaxed);
+        let signals = &self.signals;
+        // With `enqueued` where that signal left it, the signal is still
+        // the newest message, and queued if anything is.
+        if signals.coalesce
+            && signals.last_sequence.get() == Some(enqueued.wrapping_sub(1))
+            && header.message_count.load(order, Ordering::Acquire) != 0
+        {
+            signals.coalesced.set(signals.coalesced.get().wrapping_add(1));
+            return Ok(());
+        }
+        let mut record = Message::signal();
+        if self.wire_features().contains(WireFeatures::CRC) {
+            record.seal_crc();
+        }
+        self.enqueue(record)?;
+        self.signals.last_sequence.set(Some(enqueued));
+        self.signals.sent.set(self.signals.sent.get().wrapping_add(1));
+        Ok(())
+    }
+
+    /// Makes `enqueue_signal` collapse a run of signals into the first, see
+    /// the module documentation. Off by default.
+    pub fn set_coalesce_signals(&mut self, coalesce: bool) {
+        self.signals.coalesce = coalesce;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Broadcast, Port, PortConfig, QueueStats, Received, MSGS};
+
+    fn data(tag: u8) -> Message {
+        let mut message = Message([tag; SIZE]);
+        message.seal_crc();
+        message
+    }
+
+    /// `receive` until empty, signals as `None` and data as its tag.
+    fn drain(port: &mut QueueingPort) -> Vec<Option<u8>> {
+        core::iter::from_fn(|| match port.receive() {
+            Ok(Received::Signal) => Some(None),
+            Ok(Received::Message(message)) => Some(Some(message.0[0])),
+            Ok(Received::EndOfStream(_)) => panic!("end of stream"),
+            Err(_) => None,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn signals_keep_their_place_among_messages() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().wire_features(WireFeatures::CRC));
+        port.enqueue_signal().unwrap();
+        port.enqueue(data(1)).unwrap();
+        port.enqueue_signal().unwrap();
+        port.enqueue_signal().unwrap();
+        port.enqueue(data(2)).unwrap();
+        assert_eq!(drain(&mut port), [None, Some(1), None, None, Some(2)]);
+
+        let stats = port.stats();
+        assert_eq!((stats.enqueued, stats.dequeued), (5, 5), "signals count as messages");
+        let QueueStats { signals_sent, signals_coalesced, signals_received, .. } = stats;
+        assert_eq!((signals_sent, signals_coalesced, signals_received), (3, 0, 3));
+    }
+
+    #[test]
+    fn every_api_tells_a_signal() {
+        let mut port = QueueingPort::new();
+        port.set_type_filter(&[7]).unwrap();
+        for _ in 0..3 {
+            port.enqueue_signal().unwrap();
+        }
+        assert!(port.dequeue().unwrap().is_signal(), "passes the type filter");
+        let (header, payload) = port.dequeue_with_header().unwrap();
+        assert!(header.is_signal());
+        assert_eq!(payload, [0; SIZE - 8]);
+        assert!(!MessageHeader { msg_type: SIGNAL_MSG_TYPE, ..Default::default() }.is_signal());
+        #[cfg(feature = "compress")]
+        assert_eq!(port.dequeue_bytes(&mut [0; SIZE]).unwrap(), 0);
+    }
+
+    #[test]
+    fn coalescing_collapses_runs_of_signals() {
+        let mut port = QueueingPort::new();
+        port.set_coalesce_signals(true);
+        for _ in 0..4 {
+            port.enqueue_signal().unwrap();
+        }
+        port.enqueue(data(1)).unwrap();
+        port.enqueue_signal().unwrap();
+        port.enqueue_signal().unwrap();
+        assert_eq!(drain(&mut port), [None, Some(1), None]);
+
+        // Taken by the receiver, so the next one rings again.
+        port.enqueue_signal().unwrap();
+        assert_eq!(drain(&mut port), [None]);
+        port.enqueue_signal().unwrap();
+        port.enqueue_signal().unwrap();
+        assert_eq!(drain(&mut port), [None]);
+        let stats = port.stats();
+        assert_eq!((stats.signals_sent, stats.signals_coalesced, stats.signals_received), (4, 5, 4));
+    }
+
+    #[test]
+    fn a_full_broadcast_port_drops_the_oldest_for_a_signal() {
+        let port = Port::<Broadcast>::new();
+        let mut subscriber = port.subscribe();
+        for tag in 0..MSGS as u8 {
+            port.publish(data(tag)).unwrap();
+        }
+        port.publish(Message::signal()).unwrap();
+        assert!(matches!(subscriber.recv(), Err(QueueError::Lagged { missed: 1 })));
+        for tag in 1..MSGS as u8 {
+            assert_eq!(subscriber.recv().unwrap().0[0], tag);
+        }
+        assert!(matches!(subscriber.receive(), Ok(Received::Signal)));
+    }
+}
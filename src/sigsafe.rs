@@ -0,0 +1,210 @@
+//! Enqueueing from inside a Unix signal handler.
+//!
+//! A signal handler may interrupt any code between two instructions, the
+//! port's `enqueue` included, and may only call what is async-signal-safe:
+//! nothing that allocates, takes a lock or could wait for the code it
+//! interrupted. `signal_safe_enqueue` stays within that. It copies the
+//! message into `SIGNAL_QUEUE`, a `SignalQueue` of `SIGNAL_QUEUE_CAPACITY`
+//! slots managed with `AtomicUsize` operations alone, and never waits: a
+//! full queue refuses the message with `QueueError::FullBuffer`.
+//!
+//! An ordinary thread then calls `drain_into` now and then, which moves
+//! the messages, oldest first, into the port the rest of the program
+//! reads. A message the port refuses, because it is full for instance,
+//! stays first in the signal queue for the next drain.
+//!
+//! Any number of handlers, on any threads and nested in each other, may
+//! enqueue at once. Each slot carries a stamp: even while it is free for
+//! its next lap around the ring, odd once that lap's message is written.
+//! A handler claims a free slot by moving the tail on, writes, then stamps
+//! the slot, so one interrupted between the two holds up the drain at its
+//! slot, and nothing else. Only one thread drains at a time; a second
+//! call meanwhile drains nothing.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{Message, QueueError, QueueingPort, SIZE};
+
+/// Messages `SIGNAL_QUEUE`, or any `SignalQueue`, holds before refusing
+/// more.
+pub const SIGNAL_QUEUE_CAPACITY: usize = 16;
+
+/// The queue `signal_safe_enqueue` enqueues into.
+pub static SIGNAL_QUEUE: SignalQueue = SignalQueue::new();
+
+struct Slot {
+    /// Twice the slot's lap, plus one once that lap's message is written.
+    stamp: AtomicUsize,
+    message: UnsafeCell<Message>,
+}
+
+/// A lock-free queue to enqueue into from signal handlers, see the
+/// `sigsafe` module.
+pub struct SignalQueue {
+    slots: [Slot; SIGNAL_QUEUE_CAPACITY],
+    /// The next position to claim, and to drain.
+    tail: AtomicUsize,
+    head: AtomicUsize,
+    draining: AtomicBool,
+}
+
+// A slot's message is only written by the handler that claimed it and only
+// read by the drainer once stamped, see the module documentation.
+unsafe impl Sync for SignalQueue {}
+
+/// The stamp of the slot for `position` while free for it; the written
+/// stamp is one more.
+fn free_stamp(position: usize) -> usize {
+    (position / SIGNAL_QUEUE_CAPACITY).wrapping_mul(2)
+}
+
+/// Enqueues `message` into `SIGNAL_QUEUE`; async-signal-safe, see the
+/// `sigsafe` module.
+pub fn signal_safe_enqueue(message: Message) -> Result<(), QueueError> {
+    SIGNAL_QUEUE.enqueue(message)
+}
+
+impl SignalQueue {
+    pub const fn new() -> SignalQueue {
+        SignalQueue {
+            slots: [const { Slot { stamp: AtomicUsize::new(0), message: UnsafeCell::new(Message([0; SIZE])) } };
+                SIGNAL_QUEUE_CAPACITY],
+            tail: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Enqueues `message` without blocking, allocating or locking; fails
+    /// with `QueueError::FullBuffer` if the queue is full.
+    pub fn enqueue(&self, message: Message) -> Result<(), QueueError> {
+        let mut position = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position % SIGNAL_QUEUE_CAPACITY];
+            let ahead = slot.stamp.load(Ordering::Acquire).wrapping_sub(free_stamp(position)) as isize;
+            if ahead < 0 {
+                // Still holding the message of the lap before.
+                return Err(QueueError::FullBuffer);
+            }
+            if ahead > 0 {
+                // Claimed, and the tail moved on, since it was loaded.
+                position = self.tail.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.tail.compare_exchange_weak(position, position + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    // SAFETY: the claim makes the slot this call's until
+                    // stamped; the drainer does not read it before.
+                    unsafe { ptr::write(slot.message.get(), message) };
+                    slot.stamp.store(free_stamp(position) + 1, Ordering::Release);
+                    return Ok(());
+                }
+                Err(now) => position = now,
+            }
+        }
+    }
+
+    /// Moves the queued messages, oldest first, into `port` until it
+    /// refuses one, which stays queued. Returns how many were moved; 0
+    /// also while another thread is draining.
+    pub fn drain_into(&self, port: &mut QueueingPort) -> usize {
+        if self.draining.swap(true, Ordering::Acquire) {
+            return 0;
+        }
+        let mut drained = 0;
+        loop {
+            let position = self.head.load(Ordering::Relaxed);
+            let slot = &self.slots[position % SIGNAL_QUEUE_CAPACITY];
+            if slot.stamp.load(Ordering::Acquire) != free_stamp(position) + 1 {
+                break;
+            }
+            // SAFETY: stamped, so written and left to the drainer, which
+            // this thread alone is.
+            let message = unsafe { ptr::read(slot.message.get()) };
+            if port.enqueue(message).is_err() {
+                break;
+            }
+            self.head.store(position + 1, Ordering::Relaxed);
+            slot.stamp.store(free_stamp(position + SIGNAL_QUEUE_CAPACITY), Ordering::Release);
+            drained += 1;
+        }
+        self.draining.store(false, Ordering::Release);
+        drained
+    }
+
+    /// Messages claimed and not yet drained, some maybe still being
+    /// written.
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Relaxed).wrapping_sub(self.head.load(Ordering::Relaxed))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SignalQueue {
+    fn default() -> Self {
+        SignalQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MSGS;
+
+    #[test]
+    fn a_full_port_leaves_the_rest_queued() {
+        let queue = SignalQueue::new();
+        for tag in 0..SIGNAL_QUEUE_CAPACITY as u8 {
+            queue.enqueue(Message([tag; SIZE])).unwrap();
+        }
+        assert!(matches!(queue.enqueue(Message([0; SIZE])), Err(QueueError::FullBuffer)));
+
+        let mut port = QueueingPort::new();
+        assert_eq!(queue.drain_into(&mut port), MSGS);
+        assert_eq!(queue.len(), SIGNAL_QUEUE_CAPACITY - MSGS);
+        for tag in 0..MSGS as u8 {
+            assert_eq!(port.dequeue().unwrap().0, [tag; SIZE]);
+        }
+        // Around the ring again, in order.
+        queue.enqueue(Message([0xff; SIZE])).unwrap();
+        assert_eq!(queue.drain_into(&mut port), SIGNAL_QUEUE_CAPACITY - MSGS + 1);
+        for tag in MSGS as u8..SIGNAL_QUEUE_CAPACITY as u8 {
+            assert_eq!(port.dequeue().unwrap().0, [tag; SIZE]);
+        }
+        assert_eq!(port.dequeue().unwrap().0, [0xff; SIZE]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn concurrent_enqueuers_lose_nothing() {
+        static QUEUE: SignalQueue = SignalQueue::new();
+        const PER_THREAD: usize = 1000;
+        let mut port = QueueingPort::new();
+        let mut seen = [0usize; 4];
+        std::thread::scope(|scope| {
+            for thread in 0..4u8 {
+                scope.spawn(move || {
+                    for _ in 0..PER_THREAD {
+                        while QUEUE.enqueue(Message([thread; SIZE])).is_err() {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+            while seen.iter().sum::<usize>() < 4 * PER_THREAD {
+                QUEUE.drain_into(&mut port);
+                while let Ok(message) = port.try_dequeue() {
+                    assert!(message.0.iter().all(|&byte| byte == message.0[0]), "a torn message");
+                    seen[usize::from(message.0[0])] += 1;
+                }
+                std::thread::yield_now();
+            }
+        });
+        assert_eq!(seen, [PER_THREAD; 4]);
+    }
+}
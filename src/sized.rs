@@ -0,0 +1,94 @@
+//! Messages whose size is part of their type.
+//!
+//! `Message` is always `SIZE` bytes, so it says nothing about which port it
+//! fits once ports of other sizes are about, such as `AlignedQueueingPort`s
+//! of their own `MSG_SIZE`. A `TypedMessage<N>` is `N` bytes, and
+//! `enqueue_sized` on a port takes only the size that port has, so a
+//! message built for another port is a type error rather than a truncated
+//! copy or a `QueueError::MessageTooLarge` at run time:
+//!
+//! ```compile_fail
+//! # use ring_buffer::{QueueingPort, TypedMessage};
+//! let mut port = QueueingPort::new();
+//! port.enqueue_sized(TypedMessage::<512>([0; 512]));
+//! ```
+//!
+//! ```compile_fail
+//! # use ring_buffer::{AlignedQueueingPort, TypedMessage};
+//! let mut port = AlignedQueueingPort::<64, 4, 64>::new();
+//! port.enqueue_sized(&TypedMessage::<128>([0; 128]));
+//! ```
+//!
+//! The size a port takes is `SIZE` for a `QueueingPort`:
+//!
+//! ```
+//! # use ring_buffer::{QueueingPort, TypedMessage, SIZE};
+//! let mut port = QueueingPort::new();
+//! port.enqueue_sized(TypedMessage::<SIZE>::zeroed()).unwrap();
+//! ```
+
+use crate::{AlignedQueueingPort, Message, QueueError, QueueingPort, SIZE};
+
+/// A message of `N` bytes, see the `sized` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct TypedMessage<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> TypedMessage<N> {
+    pub const fn zeroed() -> TypedMessage<N> {
+        TypedMessage([0; N])
+    }
+}
+
+impl From<Message> for TypedMessage<SIZE> {
+    fn from(message: Message) -> Self {
+        TypedMessage(message.0)
+    }
+}
+
+impl From<TypedMessage<SIZE>> for Message {
+    fn from(message: TypedMessage<SIZE>) -> Self {
+        Message(message.0)
+    }
+}
+
+impl QueueingPort {
+    /// Enqueues `message`, as `enqueue`; it can only be `SIZE` bytes.
+    pub fn enqueue_sized(&mut self, message: TypedMessage<SIZE>) -> Result<(), QueueError> {
+        self.enqueue(message.into())
+    }
+
+    /// Dequeues the next message, as `dequeue`.
+    pub fn dequeue_sized(&mut self) -> Result<TypedMessage<SIZE>, QueueError> {
+        self.try_dequeue().map(TypedMessage::from)
+    }
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize, const ALIGN: usize> AlignedQueueingPort<MSG_SIZE, MSG_COUNT, ALIGN> {
+    /// Enqueues `message`, as `enqueue`; it can only be `MSG_SIZE` bytes.
+    pub fn enqueue_sized(&mut self, message: &TypedMessage<MSG_SIZE>) -> Result<(), QueueError> {
+        self.enqueue(&message.0)
+    }
+
+    /// Dequeues the next message, as `dequeue`.
+    pub fn dequeue_sized(&mut self) -> Result<TypedMessage<MSG_SIZE>, QueueError> {
+        self.dequeue().map(TypedMessage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_port_takes_its_own_size() {
+        let mut port = QueueingPort::new();
+        port.enqueue_sized(TypedMessage([3; SIZE])).unwrap();
+        assert_eq!(port.dequeue_sized().unwrap(), TypedMessage([3; SIZE]));
+
+        let mut dma = AlignedQueueingPort::<64, 4, 64>::new();
+        dma.enqueue_sized(&TypedMessage([4; 64])).unwrap();
+        assert_eq!(dma.dequeue_sized().unwrap(), TypedMessage([4; 64]));
+        assert!(matches!(dma.dequeue_sized(), Err(QueueError::EmptyBuffer)));
+    }
+}
@@ -0,0 +1,167 @@
+//! A fixed pool of messages to build in place, instead of on the stack.
+//!
+//! A `Message` is `SIZE` bytes; building one in a local and handing it to
+//! `enqueue` copies it at least twice on the way, and a few in a deep call
+//! chain take a good part of a small thread stack. A `MessageSlab` holds
+//! `N` messages, at most 64, often in a `static`. `alloc` lends one out as
+//! a `SlabMessage` to fill in place, and `enqueue_from_slab` copies it
+//! from the slab straight into its slot, then gives it back.
+//!
+//! Which messages are lent out is a bitmask in one atomic word, so `alloc`
+//! and giving back never block and work from any thread.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{trace, Message, QueueError, QueueingPort, SIZE};
+
+/// `N` messages to lend out, see the `slab` module.
+pub struct MessageSlab<const N: usize> {
+    messages: [UnsafeCell<Message>; N],
+    /// Bit `i` is set while message `i` is lent out.
+    lent: AtomicU64,
+}
+
+// A message is only reached through the one `SlabMessage` lending it.
+unsafe impl<const N: usize> Sync for MessageSlab<N> {}
+
+/// A message lent out by `MessageSlab::alloc`, given back when dropped.
+pub struct SlabMessage<'a> {
+    message: &'a mut Message,
+    lent: &'a AtomicU64,
+    bit: u64,
+}
+
+impl<const N: usize> MessageSlab<N> {
+    pub const fn new() -> MessageSlab<N> {
+        const { assert!(N <= 64, "a slab holds at most 64 messages") };
+        MessageSlab { messages: [const { UnsafeCell::new(Message([0; SIZE])) }; N], lent: AtomicU64::new(0) }
+    }
+
+    /// Lends out a message, as it was last left, or `None` if all `N` are.
+    pub fn alloc(&self) -> Option<SlabMessage<'_>> {
+        let mut lent = self.lent.load(Ordering::Relaxed);
+        loop {
+            let index = lent.trailing_ones() as usize;
+            if index >= N {
+                return None;
+            }
+            let bit = 1 << index;
+            match self.lent.compare_exchange_weak(lent, lent | bit, Ordering::Acquire, Ordering::Relaxed) {
+                // The bit was clear, so no other `SlabMessage` has the message.
+                Ok(_) => {
+                    let message = unsafe { &mut *self.messages[index].get() };
+                    return Some(SlabMessage { message, lent: &self.lent, bit });
+                }
+                Err(now) => lent = now,
+            }
+        }
+    }
+
+    /// Messages lent out now.
+    pub fn lent(&self) -> usize {
+        self.lent.load(Ordering::Relaxed).count_ones() as usize
+    }
+}
+
+impl<const N: usize> Default for MessageSlab<N> {
+    fn default() -> Self {
+        MessageSlab::new()
+    }
+}
+
+impl Deref for SlabMessage<'_> {
+    type Target = Message;
+
+    fn deref(&self) -> &Message {
+        self.message
+    }
+}
+
+impl DerefMut for SlabMessage<'_> {
+    fn deref_mut(&mut self) -> &mut Message {
+        self.message
+    }
+}
+
+impl Drop for SlabMessage<'_> {
+    fn drop(&mut self) {
+        self.lent.fetch_and(!self.bit, Ordering::Release);
+    }
+}
+
+impl QueueingPort {
+    /// Enqueues `message`, copied from the slab straight into its slot, and
+    /// gives it back to the slab. Fails as `enqueue`, giving it back too.
+    pub fn enqueue_from_slab(&mut self, message: SlabMessage<'_>) -> Result<(), QueueError> {
+        trace::enqueue(self, |port| {
+            port.produce_back(|slot| {
+                // SAFETY: as in `enqueue`; the slab message is a different
+                // allocation from the segment.
+                unsafe { ptr::copy_nonoverlapping(&message.0, slot, 1) }
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MSGS;
+    use std::thread;
+
+    #[test]
+    fn lends_each_message_once() {
+        let slab = MessageSlab::<3>::new();
+        let a = slab.alloc().unwrap();
+        let b = slab.alloc().unwrap();
+        let c = slab.alloc().unwrap();
+        assert!(slab.alloc().is_none());
+        assert_eq!(slab.lent(), 3);
+        drop(b);
+        let mut again = slab.alloc().unwrap();
+        again.0[0] = 9;
+        assert!(slab.alloc().is_none());
+        drop((a, c, again));
+        assert_eq!(slab.lent(), 0);
+    }
+
+    #[test]
+    fn enqueue_from_slab_gives_the_message_back() {
+        static SLAB: MessageSlab<2> = MessageSlab::new();
+        let mut port = QueueingPort::new();
+        for tag in 0..MSGS as u8 {
+            let mut message = SLAB.alloc().unwrap();
+            message.0 = [tag; SIZE];
+            port.enqueue_from_slab(message).unwrap();
+            assert_eq!(SLAB.lent(), 0);
+        }
+        let full = SLAB.alloc().unwrap();
+        assert!(matches!(port.enqueue_from_slab(full), Err(QueueError::FullBuffer)));
+        assert_eq!(SLAB.lent(), 0);
+        for tag in 0..MSGS as u8 {
+            assert_eq!(port.dequeue().unwrap().0, [tag; SIZE]);
+        }
+    }
+
+    #[test]
+    fn threads_never_share_a_message() {
+        static SLAB: MessageSlab<64> = MessageSlab::new();
+        thread::scope(|scope| {
+            for thread in 0..4u8 {
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        if let Some(mut message) = SLAB.alloc() {
+                            message.0[..8].fill(thread);
+                            thread::yield_now();
+                            assert_eq!(message.0[..8], [thread; 8]);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(SLAB.lent(), 0);
+    }
+}
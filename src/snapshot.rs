@@ -0,0 +1,380 @@
+//! Capturing a port's contents into a byte blob and restoring it later.
+//!
+//! The blob format, all integers little endian:
+//!
+//! ```text
+//! 0   4  magic "QPSN"
+//! 4   2  format version (1, or 2 for a full snapshot)
+//! 6   2  number of u32 statistics that follow the fixed part (0 = none)
+//! 8   4  slot size in bytes
+//! 12  4  slot count
+//! 16  4  number of queued messages
+//! 20     statistics: enqueued, dequeued, rejected, high_watermark, filtered_out,
+//!        deadline_misses
+//! ..     version 2 only, the port's settings from the segment header:
+//!        0   4  features
+//!        4   4  credit limit
+//!        8   4  credits
+//!        12  1  delivery order
+//!        13  1  concurrency mode
+//!        14  2  metadata length
+//!        16  METADATA_CAPACITY bytes of metadata
+//! ..     the queued messages, oldest first, one slot size each; in version 2
+//!        each preceded by its sequence number (u32) and enqueue time (u64)
+//! ```
+//!
+//! `snapshot_into` and `snapshot` write version 1, which carries what is
+//! needed to pick up the messages elsewhere. `snapshot_to_bytes` writes
+//! version 2, which also carries the settings `PortConfig` gave the port,
+//! so the restored port behaves as the original did. `restore` reads
+//! either. The handshake state, the peers and the segment generation are
+//! not captured: they describe the segment, and a restored port is a new
+//! in-memory one. Neither is the header's byte order, so the restored port
+//! uses the native one.
+//!
+//! A snapshot taken while the peer is operating on the segment may mix
+//! states from before and after the peer's operation.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use crate::byteorder::WireU32;
+use crate::{slot_index, PortError, QueueingPort, Segment, METADATA_CAPACITY, MSGS, SIZE};
+
+const MAGIC: [u8; 4] = *b"QPSN";
+const VERSION: u16 = 1;
+const FULL_VERSION: u16 = 2;
+const FIXED_LEN: usize = 20;
+pub(crate) const STAT_COUNT: usize = 6;
+const SETTINGS_LEN: usize = 16 + METADATA_CAPACITY;
+/// Sequence number and enqueue time before each message of a full snapshot.
+const SLOT_WORDS_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The output buffer cannot hold the snapshot.
+    BufferTooSmall { needed: usize },
+    /// The blob is truncated or not a snapshot.
+    Malformed,
+    /// The blob was taken from a port with a different slot size or count,
+    /// or one using required wire features this build does not know.
+    IncompatibleLayout,
+}
+
+/// A snapshot held in an owned buffer.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotBlob(Vec<u8>);
+
+#[cfg(feature = "alloc")]
+impl SnapshotBlob {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+pub(crate) fn stat_fields(segment: &Segment) -> [&WireU32; STAT_COUNT] {
+    let header = &segment.header;
+    [
+        &header.enqueued,
+        &header.dequeued,
+        &header.rejected,
+        &header.high_watermark,
+        &header.filtered_out,
+        &header.deadline_misses,
+    ]
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+impl QueueingPort {
+    /// Size in bytes of a snapshot of the port's current contents.
+    pub fn snapshot_len(&self, include_stats: bool) -> usize {
+        let stats = if include_stats { STAT_COUNT * 4 } else { 0 };
+        FIXED_LEN + stats + self.len().min(MSGS) * SIZE
+    }
+
+    /// Writes a snapshot into `out`, returning the number of bytes used.
+    pub fn snapshot_into(&self, out: &mut [u8], include_stats: bool) -> Result<usize, SnapshotError> {
+        let needed = self.snapshot_len(include_stats);
+        if out.len() < needed {
+            return Err(SnapshotError::BufferTooSmall { needed });
+        }
+        Ok(self.write_snapshot(out, include_stats, false))
+    }
+
+    /// Writes a version 1 snapshot, or a full one, into `out`, which must
+    /// be large enough for the contents `out.len()` was computed from.
+    fn write_snapshot(&self, out: &mut [u8], include_stats: bool, full: bool) -> usize {
+        let segment = self.segment();
+        let header = &segment.header;
+        let order = header.byte_order();
+        let count = (header.message_count.load(order, Ordering::Acquire) as usize).min(MSGS);
+        let read_index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize);
+        let stat_count = if include_stats { STAT_COUNT } else { 0 };
+        let version = if full { FULL_VERSION } else { VERSION };
+
+        out[0..4].copy_from_slice(&MAGIC);
+        out[4..6].copy_from_slice(&version.to_le_bytes());
+        out[6..8].copy_from_slice(&(stat_count as u16).to_le_bytes());
+        out[8..12].copy_from_slice(&(SIZE as u32).to_le_bytes());
+        out[12..16].copy_from_slice(&(MSGS as u32).to_le_bytes());
+        out[16..20].copy_from_slice(&(count as u32).to_le_bytes());
+        let mut offset = FIXED_LEN;
+        for field in &stat_fields(segment)[..stat_count] {
+            out[offset..offset + 4].copy_from_slice(&field.load(segment.header.byte_order(), Ordering::Relaxed).to_le_bytes());
+            offset += 4;
+        }
+        if full {
+            let settings = &mut out[offset..offset + SETTINGS_LEN];
+            settings[0..4].copy_from_slice(&self.wire_features().bits().to_le_bytes());
+            settings[4..8].copy_from_slice(&header.credit_limit.load(order, Ordering::Relaxed).to_le_bytes());
+            settings[8..12].copy_from_slice(&header.credits.load(order, Ordering::Relaxed).to_le_bytes());
+            settings[12] = header.delivery_order.load(Ordering::Relaxed);
+            settings[13] = header.mode.load(Ordering::Relaxed);
+            let metadata_len = (header.metadata_len.load(order, Ordering::Acquire) as usize).min(METADATA_CAPACITY);
+            settings[14..16].copy_from_slice(&(metadata_len as u16).to_le_bytes());
+            // Written once by the creator, before `metadata_len`.
+            settings[16..].copy_from_slice(unsafe { &*header.metadata.get() });
+            offset += SETTINGS_LEN;
+        }
+        for i in 0..count {
+            let index = slot_index(read_index + i);
+            if full {
+                let words = &mut out[offset..offset + SLOT_WORDS_LEN];
+                words[0..4].copy_from_slice(&header.slot_sequence[index].load(order, Ordering::Relaxed).to_le_bytes());
+                words[4..8].copy_from_slice(&header.slot_time_low[index].load(order, Ordering::Relaxed).to_le_bytes());
+                words[8..12].copy_from_slice(&header.slot_time_high[index].load(order, Ordering::Relaxed).to_le_bytes());
+                offset += SLOT_WORDS_LEN;
+            }
+            let dst = &mut out[offset..offset + SIZE];
+            unsafe { core::ptr::copy_nonoverlapping(segment.slot(index), dst.as_mut_ptr(), SIZE) };
+            offset += SIZE;
+        }
+        offset
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn snapshot(&self, include_stats: bool) -> SnapshotBlob {
+        let mut bytes = alloc::vec![0; self.snapshot_len(include_stats)];
+        // The length was computed from the same count. If the peer enqueued
+        // since, the extra messages are simply not part of the snapshot.
+        let len = self.snapshot_into(&mut bytes, include_stats).unwrap_or(0);
+        bytes.truncate(len);
+        SnapshotBlob(bytes)
+    }
+
+    /// A full snapshot: the statistics, the settings from the segment
+    /// header, and the queued messages with their sequence numbers and
+    /// enqueue times. See `restore_from_bytes`.
+    #[cfg(feature = "alloc")]
+    pub fn snapshot_to_bytes(&self) -> Vec<u8> {
+        let count = self.len().min(MSGS);
+        let mut bytes = alloc::vec![0; FIXED_LEN + STAT_COUNT * 4 + SETTINGS_LEN + count * (SLOT_WORDS_LEN + SIZE)];
+        // As in `snapshot`, messages enqueued since `len()` are left out.
+        let len = self.write_snapshot(&mut bytes, true, true);
+        bytes.truncate(len);
+        bytes
+    }
+
+    /// Creates an in-memory port from a snapshot, as `restore` does, with
+    /// a setup error like the other constructors.
+    pub fn restore_from_bytes(data: &[u8]) -> Result<QueueingPort, PortError> {
+        QueueingPort::restore(data).map_err(PortError::Snapshot)
+    }
+
+    /// Creates a port holding the messages (and, if the snapshot has them,
+    /// the statistics) of `blob`.
+    pub fn restore(blob: &[u8]) -> Result<QueueingPort, SnapshotError> {
+        if blob.len() < FIXED_LEN || blob[0..4] != MAGIC {
+            return Err(SnapshotError::Malformed);
+        }
+        let full = match u16::from_le_bytes([blob[4], blob[5]]) {
+            VERSION => false,
+            FULL_VERSION => true,
+            _ => return Err(SnapshotError::Malformed),
+        };
+        let stat_count = u16::from_le_bytes([blob[6], blob[7]]) as usize;
+        if read_u32(blob, 8) as usize != SIZE || read_u32(blob, 12) as usize != MSGS {
+            return Err(SnapshotError::IncompatibleLayout);
+        }
+        let count = read_u32(blob, 16) as usize;
+        let settings_at = FIXED_LEN + stat_count * 4;
+        let (messages_at, record_len) = match full {
+            false => (settings_at, SIZE),
+            true => (settings_at + SETTINGS_LEN, SLOT_WORDS_LEN + SIZE),
+        };
+        if count > MSGS || blob.len() < messages_at + count * record_len {
+            return Err(SnapshotError::Malformed);
+        }
+
+        let port = QueueingPort::new();
+        let segment = port.segment();
+        // Statistics this build does not know about are skipped.
+        for (i, field) in stat_fields(segment).iter().enumerate().take(stat_count) {
+            field.store(segment.header.byte_order(), read_u32(blob, FIXED_LEN + i * 4), Ordering::Relaxed);
+        }
+        let header = &segment.header;
+        let order = header.byte_order();
+        if full {
+            let settings = &blob[settings_at..settings_at + SETTINGS_LEN];
+            let metadata_len = u16::from_le_bytes([settings[14], settings[15]]) as usize;
+            if metadata_len > METADATA_CAPACITY {
+                return Err(SnapshotError::Malformed);
+            }
+            header.features.store(order, read_u32(settings, 0), Ordering::Relaxed);
+            if port.missing_features(port.supported_features).is_some() {
+                return Err(SnapshotError::IncompatibleLayout);
+            }
+            header.credit_limit.store(order, read_u32(settings, 4), Ordering::Relaxed);
+            header.credits.store(order, read_u32(settings, 8), Ordering::Relaxed);
+            header.delivery_order.store(settings[12], Ordering::Relaxed);
+            header.mode.store(settings[13], Ordering::Relaxed);
+            // The port is not shared yet.
+            unsafe { (*header.metadata.get()).copy_from_slice(&settings[16..]) };
+            header.metadata_len.store(order, metadata_len as u32, Ordering::Relaxed);
+        }
+        for i in 0..count {
+            let record = &blob[messages_at + i * record_len..messages_at + (i + 1) * record_len];
+            let src = &record[record_len - SIZE..];
+            unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), segment.slot(i), SIZE) };
+            if full {
+                header.slot_sequence[i].store(order, read_u32(record, 0), Ordering::Relaxed);
+                header.slot_time_low[i].store(order, read_u32(record, 4), Ordering::Relaxed);
+                header.slot_time_high[i].store(order, read_u32(record, 8), Ordering::Relaxed);
+            }
+        }
+        if stat_count == 0 {
+            // Keep `enqueued - dequeued` equal to the queue length, which
+            // sequence numbers rely on.
+            header.enqueued.store(order, count as u32, Ordering::Relaxed);
+        }
+        let first_sequence = header.dequeued.load(order, Ordering::Relaxed);
+        for (i, sequence) in header.slot_sequence.iter().take(count).enumerate() {
+            if !full {
+                sequence.store(order, first_sequence.wrapping_add(i as u32), Ordering::Relaxed);
+            }
+            header.slot_generation[i].store(order, 1, Ordering::Relaxed);
+        }
+        header.write_index.store(order, slot_index(count) as u32, Ordering::Relaxed);
+        header.message_count.store(order, count as u32, Ordering::Release);
+        Ok(port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeliveryOrder, Message, PortConfig, PortError, QueueError, WireFeatures};
+
+    // Wrapped around: slots 7, 8, 9, 0, 1 hold messages 7..12.
+    fn wrapped_port() -> QueueingPort {
+        let mut port = QueueingPort::new();
+        for tag in 0..12u8 {
+            if port.len() == 7 {
+                for _ in 0..7 {
+                    port.dequeue().unwrap();
+                }
+            }
+            port.enqueue(Message([tag; SIZE])).unwrap();
+        }
+        port
+    }
+
+    #[test]
+    fn round_trip_preserves_order_and_counters() {
+        let mut original = wrapped_port();
+        let blob = original.snapshot(true);
+        assert_eq!(blob.as_bytes().len(), FIXED_LEN + STAT_COUNT * 4 + 5 * SIZE);
+
+        let mut restored = QueueingPort::restore(blob.as_bytes()).unwrap();
+        assert_eq!(restored.stats(), original.stats());
+        assert_eq!(restored.len(), original.len());
+        loop {
+            match (original.try_dequeue(), restored.try_dequeue()) {
+                (Ok(a), Ok(b)) => assert_eq!(a.0, b.0),
+                (Err(QueueError::EmptyBuffer), Err(QueueError::EmptyBuffer)) => break,
+                other => panic!("ports diverged: {:?}", other),
+            }
+        }
+        assert_eq!(restored.stats(), original.stats());
+    }
+
+    #[test]
+    fn snapshot_without_stats() {
+        let port = wrapped_port();
+        let mut buffer = [0u8; 2048];
+        let len = port.snapshot_into(&mut buffer, false).unwrap();
+        let mut restored = QueueingPort::restore(&buffer[..len]).unwrap();
+        assert_eq!(restored.stats().enqueued, 5, "the restored messages");
+        assert_eq!(restored.dequeue().unwrap().0, [7; SIZE]);
+        assert_eq!(restored.len(), 4);
+    }
+
+    #[test]
+    fn caller_buffer_too_small() {
+        let port = wrapped_port();
+        let mut buffer = [0u8; 64];
+        let needed = port.snapshot_len(true);
+        assert_eq!(
+            port.snapshot_into(&mut buffer, true),
+            Err(SnapshotError::BufferTooSmall { needed })
+        );
+    }
+
+    #[test]
+    fn different_geometry_is_incompatible() {
+        let mut blob = wrapped_port().snapshot(false).into_bytes();
+        blob[12..16].copy_from_slice(&(MSGS as u32 * 2).to_le_bytes());
+        assert!(matches!(QueueingPort::restore(&blob), Err(SnapshotError::IncompatibleLayout)));
+        blob[8..12].copy_from_slice(&128u32.to_le_bytes());
+        assert!(matches!(QueueingPort::restore(&blob), Err(SnapshotError::IncompatibleLayout)));
+        assert!(matches!(QueueingPort::restore(&blob[..10]), Err(SnapshotError::Malformed)));
+    }
+
+    #[test]
+    fn full_snapshot_round_trip() {
+        let config = PortConfig::new()
+            .delivery_order(DeliveryOrder::StrictFifo)
+            .wire_features(WireFeatures::TIMESTAMPS)
+            .metadata(b"schema 3")
+            .unwrap();
+        let mut original = QueueingPort::with_config(&config);
+        original.enqueue(Message([0xee; SIZE])).unwrap();
+        original.dequeue().unwrap();
+        for tag in 1..=5u8 {
+            original.enqueue(Message([tag; SIZE])).unwrap();
+        }
+        let bytes = original.snapshot_to_bytes();
+        assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), FULL_VERSION);
+
+        let mut restored = QueueingPort::restore_from_bytes(&bytes).unwrap();
+        assert_eq!(restored.stats(), original.stats());
+        assert_eq!(restored.wire_features(), WireFeatures::TIMESTAMPS);
+        assert_eq!(restored.metadata(), b"schema 3");
+        assert_eq!(restored.check_invariants(), Ok(()));
+        for tag in 1..=5u8 {
+            let (a, b) = (original.dequeue_timed().unwrap(), restored.dequeue_timed().unwrap());
+            assert_eq!(b.message.0, [tag; SIZE]);
+            assert_eq!((b.sequence, b.enqueue_time), (a.sequence, a.enqueue_time));
+        }
+        assert!(matches!(restored.try_dequeue(), Err(QueueError::EmptyBuffer)));
+    }
+
+    #[test]
+    fn unreadable_bytes_are_a_port_error() {
+        let mut bytes = QueueingPort::new().snapshot_to_bytes();
+        bytes[4..6].copy_from_slice(&7u16.to_le_bytes());
+        assert!(matches!(
+            QueueingPort::restore_from_bytes(&bytes),
+            Err(PortError::Snapshot(SnapshotError::Malformed))
+        ));
+    }
+}
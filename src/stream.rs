@@ -0,0 +1,149 @@
+//! Receiving messages as an async stream.
+//!
+//! A handle keeps one waker slot, for a task waiting on it to receive.
+//! Every successful enqueue through the handle wakes that task, so the
+//! stream works where the sender enqueues through the same handle: the
+//! halves of `into_mpsc_channel`, which share one port. A sender in
+//! another process, with its own handle, wakes nobody.
+//!
+//! `futures-core` is not a dependency, so `AsyncQueueStream` has the
+//! `poll_next` of its `Stream` trait as an inherent method. With the
+//! `futures` crate, `stream::poll_fn(|cx| Pin::new(&mut s).poll_next(cx))`
+//! makes it a `Stream`.
+
+#[cfg(feature = "std")]
+use core::future::{self, Future};
+#[cfg(feature = "std")]
+use core::pin::Pin;
+#[cfg(feature = "std")]
+use core::task::{Context, Poll, Waker};
+
+#[cfg(feature = "std")]
+use crate::{Message, PortReceiver, QueueError};
+use crate::QueueingPort;
+
+impl QueueingPort {
+    /// Has the next enqueue through this handle wake `waker`, replacing
+    /// any waker registered before.
+    #[cfg(feature = "std")]
+    pub(crate) fn register_reader_waker(&self, waker: &Waker) {
+        let waker = match self.reader_waker.take() {
+            Some(current) if current.will_wake(waker) => current,
+            _ => waker.clone(),
+        };
+        self.reader_waker.set(Some(waker));
+    }
+
+    /// Wakes the registered waker, if any, after a publish.
+    pub(crate) fn wake_reader(&self) {
+        if let Some(waker) = self.reader_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The messages of a `PortReceiver`, see `PortReceiver::stream`.
+#[cfg(feature = "std")]
+pub struct AsyncQueueStream<'a> {
+    receiver: &'a PortReceiver,
+}
+
+#[cfg(feature = "std")]
+impl PortReceiver {
+    /// An async stream of the messages sent, ending once every sender
+    /// is gone and the port drained. Dequeue errors other than an
+    /// empty port are yielded as items.
+    pub fn stream(&self) -> AsyncQueueStream<'_> {
+        AsyncQueueStream { receiver: self }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> AsyncQueueStream<'a> {
+    /// The next message, `Pending` while the port is empty and a
+    /// sender remains, as `futures_core::Stream::poll_next`.
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Message, QueueError>>> {
+        self.receiver.poll_recv(cx.waker())
+    }
+
+    /// The next message, as `StreamExt::next` would return it.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> impl Future<Output = Option<Result<Message, QueueError>>> + 'a {
+        let receiver = self.receiver;
+        future::poll_fn(move |cx| receiver.poll_recv(cx.waker()))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::thread::{self, Thread};
+
+    struct Unpark(Thread, AtomicUsize);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = core::pin::pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current(), AtomicUsize::new(0))));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn stream_collects_messages_from_another_thread() {
+        let (sender, receiver) = QueueingPort::new().into_mpsc_channel();
+        let producer = thread::spawn(move || {
+            for i in 0..25u8 {
+                sender.send(Message([i; SIZE])).unwrap();
+            }
+        });
+        let received = block_on(async {
+            let mut stream = receiver.stream();
+            let mut received = Vec::new();
+            while let Some(message) = stream.next().await {
+                received.push(message.unwrap().0[0]);
+            }
+            received
+        });
+        producer.join().unwrap();
+        assert_eq!(received, (0..25).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn send_wakes_pending_stream() {
+        let (sender, receiver) = QueueingPort::new().into_mpsc_channel();
+        let unpark = Arc::new(Unpark(thread::current(), AtomicUsize::new(0)));
+        let waker = Waker::from(unpark.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut stream = receiver.stream();
+
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+        assert_eq!(unpark.1.load(Ordering::Relaxed), 0);
+        sender.send(Message([7; SIZE])).unwrap();
+        assert_eq!(unpark.1.load(Ordering::Relaxed), 1, "woken by the send");
+        assert!(matches!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(Some(Ok(_)))));
+
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+        drop(sender);
+        assert_eq!(unpark.1.load(Ordering::Relaxed), 2, "woken by the last sender leaving");
+        assert!(matches!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(None)));
+    }
+}
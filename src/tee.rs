@@ -0,0 +1,188 @@
+//! Copying every message enqueued into a port to a second sink as well.
+//!
+//! `QueueingPort::tee` wraps a port and any `EnqueuePort`, like the Unix
+//! command of that name. `TeePort::enqueue` reports what each side did in
+//! a `TeeResult`. By default the two are independent: the message stays in
+//! whichever side accepted it, so a failing secondary never costs the
+//! primary a message.
+//!
+//! `strict_tee` makes the message all or nothing. The primary's slot is
+//! claimed first and only committed once the secondary has accepted the
+//! copy, so either failure leaves both sides as they were, the side that
+//! did not fail reporting `QueueError::RolledBack`. The secondary is
+//! written last because a generic `EnqueuePort` cannot take a message back.
+
+use crate::{EnqueuePort, Message, QueueError, QueueingPort};
+
+/// What each side of a `TeePort` did with a message.
+#[derive(Debug)]
+pub struct TeeResult {
+    pub primary: Result<(), QueueError>,
+    pub secondary: Result<(), QueueError>,
+}
+
+impl TeeResult {
+    /// Whether both sides hold the message.
+    pub fn is_ok(&self) -> bool {
+        self.primary.is_ok() && self.secondary.is_ok()
+    }
+}
+
+/// A port that also writes its messages to `S`; see the module
+/// documentation.
+pub struct TeePort<S> {
+    primary: QueueingPort,
+    secondary: S,
+    strict: bool,
+}
+
+impl QueueingPort {
+    /// This port, with every message enqueued through the returned one
+    /// also written to `secondary`.
+    pub fn tee<S: EnqueuePort>(self, secondary: S) -> TeePort<S> {
+        TeePort { primary: self, secondary, strict: false }
+    }
+}
+
+impl<S: EnqueuePort> TeePort<S> {
+    /// The same tee, committing each message to both sides or neither.
+    pub fn strict_tee(self) -> TeePort<S> {
+        TeePort { strict: true, ..self }
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Writes `message` to the primary and the secondary.
+    pub fn enqueue(&mut self, message: Message) -> TeeResult {
+        if self.strict {
+            return self.enqueue_strict(message);
+        }
+        TeeResult { secondary: self.secondary.enqueue(Message(message.0)), primary: self.primary.enqueue(message) }
+    }
+
+    fn enqueue_strict(&mut self, message: Message) -> TeeResult {
+        match self.primary.claim_write_slice() {
+            Ok(slot) => *slot = message.0,
+            Err(error) => return TeeResult { primary: Err(error), secondary: Err(QueueError::RolledBack) },
+        }
+        match self.secondary.enqueue(message) {
+            // A commit fails only where the port changed since the claim.
+            Ok(()) => TeeResult { primary: self.primary.commit_write(), secondary: Ok(()) },
+            Err(error) => {
+                // Never committed, so the slot is simply free again.
+                self.primary.write_claimed = false;
+                TeeResult { primary: Err(QueueError::RolledBack), secondary: Err(error) }
+            }
+        }
+    }
+
+    pub fn primary(&self) -> &QueueingPort {
+        &self.primary
+    }
+
+    /// The primary port, e.g. to dequeue from. Messages enqueued through
+    /// it directly do not reach the secondary.
+    pub fn primary_mut(&mut self) -> &mut QueueingPort {
+        &mut self.primary
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+
+    pub fn secondary_mut(&mut self) -> &mut S {
+        &mut self.secondary
+    }
+
+    pub fn into_parts(self) -> (QueueingPort, S) {
+        (self.primary, self.secondary)
+    }
+}
+
+impl<S: EnqueuePort> EnqueuePort for TeePort<S> {
+    /// The primary's result; in a strict tee the error of the side that
+    /// failed.
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        let result = TeePort::enqueue(self, message);
+        match (result.primary, result.secondary) {
+            (Err(QueueError::RolledBack), secondary) => secondary,
+            (primary, _) => primary,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::testing::InMemoryQueueingPort;
+    use crate::{DequeuePort, MSGS, SIZE};
+
+    fn message(tag: u8) -> Message {
+        Message([tag; SIZE])
+    }
+
+    #[test]
+    fn both_sides_get_every_message() {
+        let mut tee = QueueingPort::new().tee(InMemoryQueueingPort::new());
+        for tag in 0..10 {
+            assert!(tee.enqueue(message(tag)).is_ok());
+        }
+        let (mut primary, secondary) = tee.into_parts();
+        let copies = secondary.into_messages();
+        assert_eq!(copies.len(), 10);
+        for copy in copies {
+            assert_eq!(primary.dequeue().unwrap().0, copy.0);
+        }
+        assert!(primary.is_empty());
+    }
+
+    #[test]
+    fn failing_secondary_keeps_the_primary_copy() {
+        let mut tee = QueueingPort::new().tee(InMemoryQueueingPort::with_capacity(3));
+        for tag in 0..5 {
+            let result = tee.enqueue(message(tag));
+            assert!(result.primary.is_ok());
+            assert_eq!(result.secondary.is_ok(), tag < 3);
+        }
+        assert!(matches!(EnqueuePort::enqueue(&mut tee, message(5)), Ok(())));
+        assert_eq!((tee.primary().len(), tee.secondary().len()), (6, 3));
+    }
+
+    #[test]
+    fn strict_tee_rolls_back_either_failure() {
+        let mut tee = QueueingPort::new().tee(InMemoryQueueingPort::with_capacity(3)).strict_tee();
+        assert!(tee.is_strict());
+        for tag in 0..3 {
+            assert!(tee.enqueue(message(tag)).is_ok());
+        }
+        let result = tee.enqueue(message(3));
+        assert!(matches!(result.primary, Err(QueueError::RolledBack)));
+        assert!(matches!(result.secondary, Err(QueueError::FullBuffer)));
+        assert_eq!((tee.primary().len(), tee.secondary().len()), (3, 3));
+        assert_eq!(tee.primary().stats().enqueued, 3);
+
+        // The primary refusing leaves the secondary alone.
+        let mut tee = QueueingPort::new().tee(InMemoryQueueingPort::new()).strict_tee();
+        for tag in 0..MSGS as u8 {
+            tee.enqueue(message(tag));
+        }
+        let result = tee.enqueue(message(0xff));
+        assert!(matches!(result.primary, Err(QueueError::FullBuffer)));
+        assert!(matches!(result.secondary, Err(QueueError::RolledBack)));
+        assert!(matches!(EnqueuePort::enqueue(&mut tee, message(0xff)), Err(QueueError::FullBuffer)));
+        assert_eq!(tee.secondary().len(), MSGS);
+
+        // After a rollback the next message takes the freed slot.
+        let mut tee = QueueingPort::new().tee(InMemoryQueueingPort::with_capacity(1)).strict_tee();
+        tee.enqueue(message(1));
+        tee.enqueue(message(2));
+        tee.secondary_mut().dequeue().unwrap();
+        assert!(tee.enqueue(message(3)).is_ok());
+        let primary = tee.primary_mut();
+        assert_eq!(primary.dequeue().unwrap().0, [1; SIZE]);
+        assert_eq!(primary.dequeue().unwrap().0, [3; SIZE]);
+        assert!(primary.is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+//! Test doubles for code written against `EnqueuePort` and `DequeuePort`.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::{DequeuePort, EnqueuePort, Message, QueueError};
+
+/// A port kept entirely in process memory, with the same `FullBuffer` and
+/// `EmptyBuffer` behaviour as `QueueingPort`.
+///
+/// It has no segment, statistics or type filter; it is meant for unit tests of
+/// application logic that only needs the port traits.
+#[derive(Debug, Default)]
+pub struct InMemoryQueueingPort {
+    messages: VecDeque<Message>,
+    /// `None` for an unbounded port.
+    capacity: Option<usize>,
+}
+
+impl InMemoryQueueingPort {
+    /// An unbounded port.
+    pub fn new() -> InMemoryQueueingPort {
+        InMemoryQueueingPort::default()
+    }
+
+    /// A port that refuses enqueues once it holds `n` messages.
+    pub fn with_capacity(n: usize) -> InMemoryQueueingPort {
+        InMemoryQueueingPort {
+            messages: VecDeque::with_capacity(n),
+            capacity: Some(n),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// The queued messages, oldest first.
+    pub fn into_messages(self) -> Vec<Message> {
+        self.messages.into()
+    }
+}
+
+impl EnqueuePort for InMemoryQueueingPort {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        if self.capacity.is_some_and(|capacity| self.messages.len() >= capacity) {
+            return Err(QueueError::FullBuffer);
+        }
+        self.messages.push_back(message);
+        Ok(())
+    }
+}
+
+impl DequeuePort for InMemoryQueueingPort {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.messages.pop_front().ok_or(QueueError::EmptyBuffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+
+    #[test]
+    fn bounded_port_reports_full_and_empty() {
+        let mut port = InMemoryQueueingPort::with_capacity(2);
+        assert!(matches!(port.dequeue(), Err(QueueError::EmptyBuffer)));
+        port.enqueue(Message([1; SIZE])).unwrap();
+        port.enqueue(Message([2; SIZE])).unwrap();
+        assert!(matches!(port.enqueue(Message([3; SIZE])), Err(QueueError::FullBuffer)));
+
+        assert_eq!(port.dequeue().unwrap().0, [1; SIZE]);
+        port.enqueue(Message([3; SIZE])).unwrap();
+        let rest: Vec<u8> = port.into_messages().iter().map(|message| message.0[0]).collect();
+        assert_eq!(rest, [2, 3]);
+    }
+
+    #[test]
+    fn stands_in_for_a_port_in_generic_code() {
+        fn forward(from: &mut impl DequeuePort, to: &mut impl EnqueuePort) -> usize {
+            let mut moved = 0;
+            while let Ok(message) = from.dequeue() {
+                to.enqueue(message).unwrap();
+                moved += 1;
+            }
+            moved
+        }
+
+        let mut source = InMemoryQueueingPort::new();
+        for i in 0..100u8 {
+            source.enqueue(Message([i; SIZE])).unwrap();
+        }
+        let mut sink = InMemoryQueueingPort::new();
+        assert_eq!(forward(&mut source, &mut sink), 100);
+        assert!(source.is_empty());
+        assert_eq!(sink.len(), 100);
+    }
+}
@@ -0,0 +1,307 @@
+//! Enqueue and dequeue times of messages, for tracing where delay builds up.
+//!
+//! On a port created with `WireFeatures::TIMESTAMPS`, every enqueue reads
+//! the sender handle's clock and stores the time beside the slot, in the
+//! header. `dequeue_timed` returns it with the time read from the receiver
+//! handle's clock when the message was taken, and reports the pair to the
+//! handle's `EventSink`. On other ports neither clock is read and both
+//! times are 0; the feature is fixed when the port is created, so a port
+//! that does not want them pays one load of the `features` word.
+//!
+//! The two times come from the two ends' clocks, so their difference only
+//! means something if those clocks share a time base. Handles in one
+//! process on the default `StdClock` do, and so do handles on `HwClock`,
+//! which resolves single nanoseconds where `StdClock` may not; across
+//! processes, give both ends a clock on a system-wide base such as
+//! `CLOCK_MONOTONIC`. The port cannot tell, and never compares the two
+//! itself.
+//!
+//! `pending_ages` lists how long the queued messages have been waiting,
+//! for telling a stalled consumer from a busy one. An age is the reading
+//! handle's clock minus the sender's enqueue time, so the same caveat
+//! applies.
+
+use core::sync::atomic::{fence, Ordering};
+
+use crate::{slot_index, trace, Message, QueueError, QueueingPort, WireFeatures, MSGS, SIZE};
+
+/// A message with its sequence number and times, see `dequeue_timed`.
+#[derive(Debug)]
+pub struct ReceivedMessage {
+    pub message: Message,
+    /// The `enqueued` count when the message was written.
+    pub sequence: u32,
+    /// When it was enqueued, on the sender's clock; 0 without timestamps.
+    pub enqueue_time: u64,
+    /// When it was dequeued, on the receiver's clock; 0 without timestamps.
+    pub dequeue_time: u64,
+}
+
+/// What a port reports to its `EventSink`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueEvent {
+    /// `dequeue_timed` took `len` bytes, times as in `ReceivedMessage`.
+    Dequeued { sequence: u32, enqueue_time: u64, dequeue_time: u64, len: usize },
+    /// `consumer_stalled` found the consumer idle for `idle` nanoseconds
+    /// with messages queued, see the `watchdog` module.
+    ConsumerStalled { idle: u64 },
+}
+
+/// Called on every `QueueEvent`, see `QueueingPort::set_event_sink`.
+pub type EventSink = fn(QueueEvent);
+
+impl QueueingPort {
+    /// Has `sink` called for every message this handle takes with
+    /// `dequeue_timed`, and for every stall its `consumer_stalled` finds.
+    pub fn set_event_sink(&mut self, sink: EventSink) {
+        self.event_sink = Some(sink);
+    }
+
+    /// The time to store with messages enqueued now; `None` on ports
+    /// without timestamps, which then never read the clock.
+    pub(crate) fn enqueue_time(&self) -> Option<u64> {
+        self.wire_features()
+            .contains(WireFeatures::TIMESTAMPS)
+            .then(|| self.clock().now_ns())
+    }
+
+    /// Like `dequeue`, with the message's sequence number and, on a port
+    /// with `WireFeatures::TIMESTAMPS`, its enqueue and dequeue times.
+    pub fn dequeue_timed(&mut self) -> Result<ReceivedMessage, QueueError> {
+        let timed = self.wire_features().contains(WireFeatures::TIMESTAMPS);
+        let (message, sequence, enqueue_time) = trace::dequeue(self, |port| {
+            port.consume_filtered(
+                |msg_type| port.type_filter.allows(msg_type),
+                |slot| {
+                    let header = &port.segment().header;
+                    let order = header.byte_order();
+                    // The slot being read is still the front one.
+                    let index = slot_index(header.read_index.load(order, Ordering::Relaxed) as usize);
+                    let time = || {
+                        let low = header.slot_time_low[index].load(order, Ordering::Relaxed);
+                        let high = header.slot_time_high[index].load(order, Ordering::Relaxed);
+                        u64::from(high) << 32 | u64::from(low)
+                    };
+                    let sequence = header.slot_sequence[index].load(order, Ordering::Relaxed);
+                    (Message(*slot), sequence, if timed { time() } else { 0 })
+                },
+            )
+        })?;
+        let dequeue_time = if timed { self.clock().now_ns() } else { 0 };
+        if let Some(sink) = self.event_sink {
+            sink(QueueEvent::Dequeued { sequence, enqueue_time, dequeue_time, len: SIZE });
+        }
+        Ok(ReceivedMessage { message, sequence, enqueue_time, dequeue_time })
+    }
+
+    /// Writes the age of every queued message, in clock nanoseconds, oldest
+    /// first, into `out`, and returns how many it wrote. Needs
+    /// `WireFeatures::TIMESTAMPS`; other ports report nothing.
+    ///
+    /// Takes no lock, so a consumer may go on meanwhile: messages consumed
+    /// before their age was read are skipped. A message enqueued after the
+    /// clock was read counts as age 0.
+    pub fn pending_ages(&self, out: &mut [u64]) -> usize {
+        if !self.wire_features().contains(WireFeatures::TIMESTAMPS) {
+            return 0;
+        }
+        let now = self.clock().now_ns();
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let oldest = header.dequeued.load(order, Ordering::Acquire);
+        let depth = (header.message_count.load(order, Ordering::Acquire) as usize).min(MSGS);
+        let mut written = 0;
+        for n in 0..depth as u32 {
+            if written == out.len() {
+                break;
+            }
+            if let Some(time) = self.enqueue_time_of(oldest.wrapping_add(n)) {
+                out[written] = now.saturating_sub(time);
+                written += 1;
+            }
+        }
+        written
+    }
+
+    /// How long the oldest queued message has been waiting, see
+    /// `pending_ages`.
+    pub fn oldest_age(&self) -> Option<u64> {
+        let mut ages = [0; MSGS];
+        let written = self.pending_ages(&mut ages);
+        ages[..written].first().copied()
+    }
+
+    /// How long the newest queued message has been waiting, see
+    /// `pending_ages`.
+    pub fn newest_age(&self) -> Option<u64> {
+        let mut ages = [0; MSGS];
+        let written = self.pending_ages(&mut ages);
+        ages[..written].last().copied()
+    }
+
+    /// The enqueue time of message `sequence`, if a slot holds it and kept
+    /// holding it while the time was read.
+    fn enqueue_time_of(&self, sequence: u32) -> Option<u64> {
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        (0..MSGS).find_map(|index| {
+            let generation = header.slot_generation[index].load(order, Ordering::Acquire);
+            if generation.is_multiple_of(2) || header.slot_sequence[index].load(order, Ordering::Relaxed) != sequence {
+                return None;
+            }
+            let low = header.slot_time_low[index].load(order, Ordering::Relaxed);
+            let high = header.slot_time_high[index].load(order, Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            (header.slot_generation[index].load(order, Ordering::Relaxed) == generation)
+                .then_some(u64::from(high) << 32 | u64::from(low))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, MockClock, PortConfig, Segment};
+    use core::ptr::NonNull;
+    use std::sync::{Arc, Mutex};
+
+    static EVENTS: Mutex<Vec<QueueEvent>> = Mutex::new(Vec::new());
+
+    fn record(event: QueueEvent) {
+        EVENTS.lock().unwrap().push(event);
+    }
+
+    #[test]
+    fn times_are_recorded_on_both_ends() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let config = PortConfig::new()
+            .wire_features(WireFeatures::TIMESTAMPS)
+            .clock(clock.clone());
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut sender = unsafe { QueueingPort::attach(segment) };
+        config.apply(&mut sender);
+        let mut receiver = unsafe { QueueingPort::attach(segment) };
+        receiver.set_clock(clock.clone());
+        receiver.set_event_sink(record);
+
+        sender.enqueue(Message([1; SIZE])).unwrap();
+        clock.advance(500);
+        sender.enqueue(Message([2; SIZE])).unwrap();
+        clock.set(10_000);
+        let first = receiver.dequeue_timed().unwrap();
+        clock.advance(5_000_000_000);
+        let second = receiver.dequeue_timed().unwrap();
+
+        assert_eq!((first.message.0[0], first.sequence, first.enqueue_time, first.dequeue_time), (1, 0, 1_000, 10_000));
+        assert_eq!((second.sequence, second.enqueue_time), (1, 1_500));
+        assert_eq!(second.dequeue_time, 5_000_010_000, "past 32 bits");
+        let events: Vec<_> = EVENTS.lock().unwrap().drain(..).collect();
+        assert_eq!(
+            events,
+            [
+                QueueEvent::Dequeued { sequence: 0, enqueue_time: 1_000, dequeue_time: 10_000, len: SIZE },
+                QueueEvent::Dequeued { sequence: 1, enqueue_time: 1_500, dequeue_time: 5_000_010_000, len: SIZE },
+            ]
+        );
+    }
+
+    struct Unread;
+
+    impl Clock for Unread {
+        fn now_ns(&self) -> u64 {
+            panic!("the clock was read");
+        }
+    }
+
+    #[test]
+    fn ports_without_timestamps_record_nothing() {
+        let mut port = QueueingPort::with_config(&PortConfig::new().clock(Arc::new(Unread)));
+        port.enqueue(Message([1; SIZE])).unwrap();
+        port.enqueue_from_iter([Message([2; SIZE])].into_iter());
+        let received = port.dequeue_timed().unwrap();
+        assert_eq!((received.sequence, received.enqueue_time, received.dequeue_time), (0, 0, 0));
+        let header = &port.segment().header;
+        let order = header.byte_order();
+        assert!(header.slot_time_low.iter().chain(&header.slot_time_high).all(|word| word.load(order, Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn batches_and_claims_are_stamped() {
+        let clock = Arc::new(MockClock::new(7));
+        let config = PortConfig::new().wire_features(WireFeatures::TIMESTAMPS).clock(clock.clone());
+        let mut port = QueueingPort::with_config(&config);
+        port.enqueue_from_iter([Message([1; SIZE]), Message([2; SIZE])].into_iter());
+        clock.set(9);
+        port.claim_write_slice().unwrap().fill(3);
+        port.commit_write().unwrap();
+        let times: Vec<u64> = core::iter::from_fn(|| port.dequeue_timed().ok()).map(|r| r.enqueue_time).collect();
+        assert_eq!(times, [7, 7, 9]);
+    }
+
+    #[test]
+    fn pending_ages_oldest_first() {
+        let clock = Arc::new(MockClock::new(100));
+        let config = PortConfig::new().wire_features(WireFeatures::TIMESTAMPS).clock(clock.clone());
+        let mut port = QueueingPort::with_config(&config);
+        assert_eq!((port.oldest_age(), port.newest_age()), (None, None));
+        for tag in 0..4 {
+            port.enqueue(Message([tag; SIZE])).unwrap();
+            clock.advance(100);
+        }
+        clock.set(1_000);
+        let mut ages = [0; MSGS];
+        assert_eq!(port.pending_ages(&mut ages), 4);
+        assert_eq!(ages[..4], [900, 800, 700, 600]);
+        let mut short = [0; 2];
+        assert_eq!(port.pending_ages(&mut short), 2);
+        assert_eq!(short, [900, 800]);
+
+        port.dequeue().unwrap();
+        clock.advance(50);
+        assert_eq!((port.oldest_age(), port.newest_age()), (Some(850), Some(650)));
+        // Wrapped around the ring: the walk follows sequence numbers.
+        for _ in 0..MSGS - 3 {
+            port.enqueue(Message([9; SIZE])).unwrap();
+        }
+        assert_eq!(port.pending_ages(&mut ages), MSGS);
+        assert!(ages.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert_eq!((ages[0], ages[MSGS - 1]), (850, 0));
+
+        let mut plain = QueueingPort::with_config(&PortConfig::new().clock(Arc::new(Unread)));
+        plain.enqueue(Message([1; SIZE])).unwrap();
+        assert_eq!((plain.pending_ages(&mut ages), plain.oldest_age()), (0, None));
+    }
+
+    #[test]
+    fn pending_ages_during_a_drain() {
+        let clock = Arc::new(MockClock::new(0));
+        let config = PortConfig::new().wire_features(WireFeatures::TIMESTAMPS).clock(clock.clone());
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let mut sender = unsafe { QueueingPort::attach(segment) };
+        config.apply(&mut sender);
+        for tag in 0..MSGS as u8 {
+            sender.enqueue(Message([tag; SIZE])).unwrap();
+            clock.advance(10);
+        }
+        // Message n was enqueued at 10 * n, so its age is 1_000 - 10 * n.
+        clock.set(1_000);
+        let expected: Vec<u64> = (0..MSGS as u64).map(|n| 1_000 - 10 * n).collect();
+
+        let mut receiver = unsafe { QueueingPort::attach(segment) };
+        let drain = std::thread::spawn(move || {
+            for _ in 0..MSGS / 2 {
+                receiver.dequeue().unwrap();
+                std::thread::yield_now();
+            }
+        });
+        let mut ages = [0; MSGS];
+        while !drain.is_finished() {
+            let written = sender.pending_ages(&mut ages);
+            assert!(ages[..written].windows(2).all(|pair| pair[0] > pair[1]), "{:?}", &ages[..written]);
+            assert!(ages[..written].iter().all(|age| expected.contains(age)));
+        }
+        drain.join().unwrap();
+        assert_eq!(sender.pending_ages(&mut ages), MSGS / 2);
+        assert_eq!(ages[..MSGS / 2], expected[MSGS / 2..]);
+    }
+}
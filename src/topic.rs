@@ -0,0 +1,172 @@
+//! Named topics, each with its own subscribers.
+//!
+//! A `Registry` keeps one `Port<Broadcast>` per topic, made the first time
+//! someone subscribes to it. `Publisher::publish` puts a message on its
+//! topic's port, which fans it out: every `Subscriber` reads the port
+//! through a cursor of its own, starting at the write head when it
+//! subscribed, so each sees every message published to the topic after
+//! that and none before. Publishing to a topic nobody ever subscribed to
+//! drops the message, as no subscriber could ever read it.
+//!
+//! As on any broadcast port, a full topic drops its oldest message for the
+//! next one, and a subscriber that had not read it yet gets
+//! `QueueError::Lagged` once. A registry holds at most `TOPIC_CAPACITY`
+//! topics, each a segment of its own.
+//!
+//! ```
+//! # use ring_buffer::{Message, Registry, Topic, SIZE};
+//! const TEMPERATURE: Topic = Topic::new("temperature");
+//! let registry = Registry::new();
+//! let mut display = registry.subscribe(&TEMPERATURE).unwrap();
+//! registry.publisher().publish(&TEMPERATURE, Message([21; SIZE])).unwrap();
+//! assert_eq!(display.recv().unwrap().0[0], 21);
+//! ```
+
+use alloc::boxed::Box;
+use core::cell::OnceCell;
+
+use crate::{Broadcast, Message, Port, QueueError, Subscriber};
+
+/// How many topics a `Registry` holds.
+pub const TOPIC_CAPACITY: usize = 16;
+
+/// A topic, known by its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Topic {
+    pub name: &'static str,
+}
+
+impl Topic {
+    pub const fn new(name: &'static str) -> Topic {
+        Topic { name }
+    }
+}
+
+struct TopicPort {
+    name: &'static str,
+    port: Box<Port<Broadcast>>,
+}
+
+/// The topics of a process, see the module documentation.
+pub struct Registry {
+    // Filled from the front and never emptied, so subscribers can borrow
+    // the ports while new topics are added behind them.
+    topics: [OnceCell<TopicPort>; TOPIC_CAPACITY],
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry { topics: core::array::from_fn(|_| OnceCell::new()) }
+    }
+
+    /// A subscriber to every message published to `topic` from now on.
+    /// Fails with `QueueError::TooManyTopics` if `topic` is new and the
+    /// registry already holds `TOPIC_CAPACITY` topics.
+    pub fn subscribe(&self, topic: &Topic) -> Result<Subscriber<'_>, QueueError> {
+        self.port(topic).or_else(|| self.add(topic)).map(Port::subscribe).ok_or(QueueError::TooManyTopics)
+    }
+
+    /// A handle publishing to the registry's topics.
+    pub fn publisher(&self) -> Publisher<'_> {
+        Publisher { registry: self }
+    }
+
+    /// The names of the topics subscribed to so far, oldest first.
+    pub fn topics(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.topics.iter().map_while(OnceCell::get).map(|topic| topic.name)
+    }
+
+    fn port(&self, topic: &Topic) -> Option<&Port<Broadcast>> {
+        self.topics.iter().map_while(OnceCell::get).find(|known| known.name == topic.name).map(|known| &*known.port)
+    }
+
+    fn add(&self, topic: &Topic) -> Option<&Port<Broadcast>> {
+        let free = self.topics.iter().find(|cell| cell.get().is_none())?;
+        let added = free.get_or_init(|| TopicPort { name: topic.name, port: Box::new(Port::new()) });
+        Some(&added.port)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+/// Publishes to the topics of a `Registry`.
+pub struct Publisher<'a> {
+    registry: &'a Registry,
+}
+
+impl Publisher<'_> {
+    /// Hands `message` to every subscriber of `topic`, as
+    /// `Port<Broadcast>::publish` does.
+    pub fn publish(&self, topic: &Topic, message: Message) -> Result<(), QueueError> {
+        match self.registry.port(topic) {
+            Some(port) => port.publish(message),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+
+    const NEWS: Topic = Topic::new("news");
+    const WEATHER: Topic = Topic::new("weather");
+
+    fn recv_all(subscriber: &mut Subscriber<'_>) -> Vec<u8> {
+        core::iter::from_fn(|| subscriber.recv().ok()).map(|message| message.0[0]).collect()
+    }
+
+    #[test]
+    fn subscribers_receive_from_their_subscription_on() {
+        let registry = Registry::new();
+        let publisher = registry.publisher();
+        publisher.publish(&NEWS, Message([0; SIZE])).unwrap();
+
+        let mut first = registry.subscribe(&NEWS).unwrap();
+        publisher.publish(&NEWS, Message([1; SIZE])).unwrap();
+        let mut second = registry.subscribe(&NEWS).unwrap();
+        publisher.publish(&NEWS, Message([2; SIZE])).unwrap();
+        let mut third = registry.subscribe(&NEWS).unwrap();
+        publisher.publish(&NEWS, Message([3; SIZE])).unwrap();
+        publisher.publish(&NEWS, Message([4; SIZE])).unwrap();
+
+        // Each reads on its own: the first draining affects no other.
+        assert_eq!(recv_all(&mut first), [1, 2, 3, 4]);
+        assert_eq!(recv_all(&mut third), [3, 4]);
+        assert_eq!(recv_all(&mut second), [2, 3, 4]);
+        publisher.publish(&NEWS, Message([5; SIZE])).unwrap();
+        for subscriber in [&mut first, &mut second, &mut third] {
+            assert_eq!(recv_all(subscriber), [5]);
+        }
+    }
+
+    #[test]
+    fn topics_are_kept_apart() {
+        let registry = Registry::new();
+        let mut news = registry.subscribe(&NEWS).unwrap();
+        let mut weather = registry.subscribe(&WEATHER).unwrap();
+        let publisher = registry.publisher();
+        publisher.publish(&WEATHER, Message([1; SIZE])).unwrap();
+        publisher.publish(&NEWS, Message([2; SIZE])).unwrap();
+        publisher.publish(&Topic::new("sports"), Message([3; SIZE])).unwrap();
+
+        assert_eq!(recv_all(&mut news), [2]);
+        assert_eq!(recv_all(&mut weather), [1]);
+        assert_eq!(registry.topics().collect::<Vec<_>>(), ["news", "weather"], "nobody subscribed to sports");
+    }
+
+    #[test]
+    fn registry_is_limited() {
+        let registry = Registry::new();
+        let names: Vec<&'static str> =
+            (0..TOPIC_CAPACITY).map(|i| &*Box::leak(format!("topic {}", i).into_boxed_str())).collect();
+        let _subscribers: Vec<_> = names.iter().map(|&name| registry.subscribe(&Topic::new(name)).unwrap()).collect();
+        assert!(matches!(registry.subscribe(&NEWS), Err(QueueError::TooManyTopics)));
+        assert!(registry.subscribe(&Topic::new(names[3])).is_ok(), "known topics still take subscribers");
+    }
+}
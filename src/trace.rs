@@ -0,0 +1,204 @@
+//! Tracing instrumentation of enqueue and dequeue, with the `tracing` feature.
+//!
+//! Each operation runs inside a `queueing_port::enqueue` or
+//! `queueing_port::dequeue` span carrying `port.name`, `message.len`,
+//! `queue.len_before` and `queue.len_after`; a full or empty port is
+//! reported as a WARN event inside the span. Without the feature these
+//! wrappers compile to a plain call.
+//!
+//! Every enqueue and dequeue entry point goes through here, so the
+//! watermark callbacks are checked here too, see the `watermark` module,
+//! and in debug builds `assert_invariants` on ports owning their segment.
+//! With the `bpf-trace` feature, the probe points of the `uprobe` module
+//! are called here.
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use tracing::{field, Level};
+
+    use crate::{QueueError, QueueingPort, SIZE};
+
+    impl QueueingPort {
+        /// Sets the `port.name` reported in spans. Named ports start out
+        /// with their OS name, others with an empty one.
+        pub fn set_name(&mut self, name: &str) {
+            self.name = name.into();
+        }
+    }
+
+    pub(crate) fn enqueue<R>(
+        port: &mut QueueingPort,
+        op: impl FnOnce(&mut QueueingPort) -> Result<R, QueueError>,
+    ) -> Result<R, QueueError> {
+        let span = tracing::span!(
+            Level::TRACE,
+            "queueing_port::enqueue",
+            port.name = %port.name,
+            message.len = SIZE,
+            queue.len_before = port.len(),
+            queue.len_after = field::Empty,
+        );
+        let _entered = span.enter();
+        let result = op(port);
+        span.record("queue.len_after", port.len());
+        if let Err(QueueError::FullBuffer) = result {
+            tracing::event!(Level::WARN, "port full, message rejected");
+        }
+        result
+    }
+
+    pub(crate) fn dequeue<R>(
+        port: &mut QueueingPort,
+        op: impl FnOnce(&mut QueueingPort) -> Result<R, QueueError>,
+    ) -> Result<R, QueueError> {
+        let span = tracing::span!(
+            Level::TRACE,
+            "queueing_port::dequeue",
+            port.name = %port.name,
+            message.len = SIZE,
+            queue.len_before = port.len(),
+            queue.len_after = field::Empty,
+        );
+        let _entered = span.enter();
+        let result = op(port);
+        span.record("queue.len_after", port.len());
+        if let Err(QueueError::EmptyBuffer) = result {
+            tracing::event!(Level::WARN, "port empty, nothing dequeued");
+        }
+        result
+    }
+
+    /// A WARN event about something the port worked around.
+    pub(crate) fn warn(message: &str) {
+        tracing::event!(Level::WARN, "{}", message);
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use crate::{QueueError, QueueingPort};
+
+    #[inline(always)]
+    pub(crate) fn enqueue<R>(
+        port: &mut QueueingPort,
+        op: impl FnOnce(&mut QueueingPort) -> Result<R, QueueError>,
+    ) -> Result<R, QueueError> {
+        op(port)
+    }
+
+    #[inline(always)]
+    pub(crate) fn dequeue<R>(
+        port: &mut QueueingPort,
+        op: impl FnOnce(&mut QueueingPort) -> Result<R, QueueError>,
+    ) -> Result<R, QueueError> {
+        op(port)
+    }
+
+    #[inline(always)]
+    pub(crate) fn warn(_message: &str) {}
+}
+
+pub(crate) use imp::warn;
+
+use crate::{QueueError, QueueingPort};
+
+pub(crate) fn enqueue<R>(
+    port: &mut QueueingPort,
+    op: impl FnOnce(&mut QueueingPort) -> Result<R, QueueError>,
+) -> Result<R, QueueError> {
+    let result = imp::enqueue(port, op);
+    #[cfg(feature = "bpf-trace")]
+    if result.is_ok() {
+        crate::uprobe::probes::enqueued(port);
+    }
+    #[cfg(debug_assertions)]
+    port.assert_invariants_if_owned();
+    port.check_high_watermark();
+    result
+}
+
+pub(crate) fn dequeue<R>(
+    port: &mut QueueingPort,
+    op: impl FnOnce(&mut QueueingPort) -> Result<R, QueueError>,
+) -> Result<R, QueueError> {
+    let result = imp::dequeue(port, op);
+    #[cfg(feature = "bpf-trace")]
+    if result.is_ok() {
+        crate::uprobe::probes::dequeued(port);
+    }
+    #[cfg(debug_assertions)]
+    port.assert_invariants_if_owned();
+    port.check_low_watermark();
+    result
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::{Message, QueueingPort, SIZE};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn spans_for_each_operation() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            .with_writer({
+                let captured = captured.clone();
+                move || captured.clone()
+            })
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut port = QueueingPort::new();
+            port.set_name("imu");
+            for i in 0..3u8 {
+                port.enqueue(Message([i; SIZE])).unwrap();
+            }
+            port.dequeue().unwrap();
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let closed: Vec<&str> = output.lines().filter(|line| line.contains("close")).collect();
+        assert_eq!(closed.len(), 4, "{}", output);
+        assert!(closed[..3].iter().all(|line| line.contains("queueing_port::enqueue")));
+        assert!(closed[3].contains("queueing_port::dequeue"));
+        assert!(closed[2].contains("port.name=imu"), "{}", closed[2]);
+        assert!(closed[2].contains("queue.len_before=2") && closed[2].contains("queue.len_after=3"));
+    }
+
+    #[test]
+    fn empty_dequeue_warns() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let captured = captured.clone();
+                move || captured.clone()
+            })
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(QueueingPort::new().try_dequeue().is_err());
+        });
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("WARN") && output.contains("port empty"), "{}", output);
+    }
+}
@@ -0,0 +1,243 @@
+//! Lock elision with Intel TSX, for a port shared between threads.
+//!
+//! A `TsxMutex` runs its critical sections as hardware transactions where
+//! the CPU has RTM: `lock` starts one with `XBEGIN` and only reads the
+//! lock word, which is free, rather than taking it, so threads whose
+//! sections touch different cache lines run them at once, and the guard's
+//! drop commits with `XEND`. A conflicting access by another thread, or
+//! the lock being taken by one, aborts the transaction: every write in it
+//! is undone, and `lock` returns again from the start. After
+//! `TSX_RETRIES` aborts, or one the CPU says would fail again, `lock` takes
+//! the `SpinMutex` underneath for real.
+//!
+//! Without RTM, detected once with CPUID, a `TsxMutex` is that `SpinMutex`.
+//! Most CPUs shipped since 2021 have TSX disabled by microcode, and a
+//! section that makes a system call, or writes more than the cache holds,
+//! aborts every time, so elision is an optimization only: the fallback must
+//! be, and is, correct alone.
+//!
+//! Only on x86_64, with the `tsx` feature. `benches/tsx.rs` compares the
+//! two on the enqueue and dequeue hot path.
+
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Aborted transactions after which `TsxMutex::lock` takes the lock.
+pub const TSX_RETRIES: u32 = 3;
+
+/// `XBEGIN`'s status when the transaction started, not aborted.
+const XBEGIN_STARTED: u32 = !0;
+/// Set in an abort status if the transaction may succeed on a retry.
+const XABORT_RETRY: u32 = 1 << 1;
+/// The code `lock` aborts with on finding the lock taken.
+const XABORT_LOCKED: u32 = 0xff;
+
+/// Starts a transaction; `XBEGIN_STARTED`, or the abort status when the
+/// transaction aborts and execution comes back here.
+#[inline(always)]
+fn xbegin() -> u32 {
+    let status: u32;
+    // SAFETY: only called where RTM was detected.
+    unsafe { asm!("mov eax, -1", "xbegin 2f", "2:", out("eax") status, options(nostack)) };
+    status
+}
+
+#[inline(always)]
+fn xend() {
+    // SAFETY: only called inside a transaction `xbegin` started.
+    unsafe { asm!("xend", options(nostack)) };
+}
+
+fn rtm_supported() -> bool {
+    std::is_x86_feature_detected!("rtm")
+}
+
+/// A spin lock: the fallback of `TsxMutex`, and a lock of its own.
+pub struct SpinMutex<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// The lock hands the value to one thread at a time, as `Mutex` does.
+unsafe impl<T: Send> Send for SpinMutex<T> {}
+unsafe impl<T: Send> Sync for SpinMutex<T> {}
+
+/// The value of a locked `SpinMutex`, unlocked on drop.
+pub struct SpinMutexGuard<'a, T> {
+    mutex: &'a SpinMutex<T>,
+}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(value: T) -> SpinMutex<T> {
+        SpinMutex { locked: AtomicBool::new(false), value: UnsafeCell::new(value) }
+    }
+
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        let mut spins = 0u32;
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            while self.locked.load(Ordering::Relaxed) {
+                spins += 1;
+                if spins.is_multiple_of(64) {
+                    std::thread::yield_now();
+                } else {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        SpinMutexGuard { mutex: self }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Deref for SpinMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard holds the lock.
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard holds the lock.
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for SpinMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A `SpinMutex` whose critical sections are elided where possible, see
+/// the `tsx` module.
+pub struct TsxMutex<T> {
+    inner: SpinMutex<T>,
+    elide: bool,
+}
+
+/// The value of a locked `TsxMutex`: inside a transaction, or holding the
+/// lock. Dropping it commits, or unlocks.
+pub struct TsxMutexGuard<'a, T> {
+    mutex: &'a TsxMutex<T>,
+    /// Holding the lock; `None` inside a transaction.
+    held: Option<SpinMutexGuard<'a, T>>,
+    /// A transaction is the thread's, and only it can commit one.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> TsxMutex<T> {
+    /// A mutex that elides its lock if this CPU has RTM.
+    pub fn new(value: T) -> TsxMutex<T> {
+        TsxMutex { inner: SpinMutex::new(value), elide: rtm_supported() }
+    }
+
+    /// Whether `lock` tries transactions, that is whether the CPU has RTM.
+    pub fn elides(&self) -> bool {
+        self.elide
+    }
+
+    pub fn lock(&self) -> TsxMutexGuard<'_, T> {
+        if self.elide {
+            for _ in 0..TSX_RETRIES {
+                let status = xbegin();
+                if status == XBEGIN_STARTED {
+                    // Reading the lock word puts it in the transaction's
+                    // read set, so a thread taking the lock aborts it.
+                    if !self.inner.is_locked() {
+                        return TsxMutexGuard { mutex: self, held: None, _not_send: PhantomData };
+                    }
+                    // SAFETY: inside the transaction just started.
+                    unsafe { asm!("xabort {0}", const XABORT_LOCKED, options(nostack)) };
+                }
+                let locked = status >> 24 == XABORT_LOCKED && status & 1 != 0;
+                if status & XABORT_RETRY == 0 && !locked {
+                    break;
+                }
+                while self.inner.is_locked() {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        TsxMutexGuard { mutex: self, held: Some(self.inner.lock()), _not_send: PhantomData }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T> Deref for TsxMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: inside a transaction that saw the lock free, or holding it.
+        unsafe { &*self.mutex.inner.value.get() }
+    }
+}
+
+impl<T> DerefMut for TsxMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: as for `deref`.
+        unsafe { &mut *self.mutex.inner.value.get() }
+    }
+}
+
+impl<T> Drop for TsxMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.held.take().is_none() {
+            xend();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, QueueingPort, SIZE};
+
+    #[test]
+    fn a_million_pairs_lose_nothing() {
+        const PAIRS: u32 = 1_000_000;
+        let port = TsxMutex::new(QueueingPort::new());
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut sent = 0u32;
+                while sent < PAIRS {
+                    let mut message = Message([0; SIZE]);
+                    message.0[..4].copy_from_slice(&sent.to_le_bytes());
+                    if port.lock().enqueue(message).is_ok() {
+                        sent += 1;
+                    } else {
+                        // Full: let the consumer at the lock.
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            let mut expected = 0u32;
+            while expected < PAIRS {
+                let dequeued = port.lock().try_dequeue();
+                match dequeued {
+                    Ok(message) => {
+                        assert_eq!(message.0[..4], expected.to_le_bytes(), "lost or reordered");
+                        expected += 1;
+                    }
+                    Err(_) => std::thread::yield_now(),
+                }
+            }
+        });
+        assert!(port.into_inner().is_empty());
+    }
+}
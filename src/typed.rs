@@ -0,0 +1,195 @@
+//! Reading a message in place as a value of a plain-data type.
+//!
+//! `AlignedQueueingPort::dequeue_ref::<T>` hands out a `SlotRef<T>`, a
+//! reference to the `T` in the front slot that consumes the message when
+//! dropped. Both things that could make that reference unsound are checked
+//! first, each with its own error, and the message stays queued if either
+//! fails:
+//!
+//! - the message must have been enqueued with exactly `size_of::<T>()`
+//!   bytes (`QueueError::LengthMismatch`), so a shorter message is never
+//!   read past its end, nor a longer one silently cut short;
+//! - the slot must start on an address aligned for `T`
+//!   (`QueueError::Misaligned`). `for_type` rejects an `ALIGN` too small
+//!   for `T` when the port is set up, so on such a port this cannot fail.
+//!
+//! While the `SlotRef` lives it borrows the port mutably, so the reader
+//! cannot dequeue past the message, and the writer cannot reuse the slot
+//! because `dequeued` has not moved.
+
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::Deref;
+use core::sync::atomic::Ordering;
+
+use crate::index::next_counter;
+use crate::{AlignedQueueingPort, QueueError};
+
+/// Types any bit pattern of the right size is a valid value of, which
+/// therefore can be read straight from a slot.
+///
+/// # Safety
+///
+/// The type must be `Copy`, have no padding bytes, and be valid for every
+/// bit pattern; in practice a `#[repr(C)]` struct of fields that are `Pod`
+/// themselves, laid out without gaps.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! pod {
+    ($($ty:ty),*) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// The `T` in the front slot of a port; see the module documentation.
+pub struct SlotRef<'a, T: Pod> {
+    value: *const T,
+    /// Set by `keep`: dropping leaves the message queued.
+    keep: bool,
+    dequeued: &'a core::sync::atomic::AtomicU32,
+    /// What `dequeued` moves on to.
+    next: u32,
+    _port: PhantomData<&'a mut ()>,
+}
+
+impl<T: Pod> SlotRef<'_, T> {
+    /// Lets go of the message without consuming it: the next dequeue
+    /// returns it again.
+    pub fn keep(mut self) {
+        self.keep = true;
+    }
+}
+
+impl<T: Pod> Deref for SlotRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Checked for size and alignment by `dequeue_ref`, and the slot is
+        // the reader's until the guard consumes it.
+        unsafe { &*self.value }
+    }
+}
+
+impl<T: Pod> Drop for SlotRef<'_, T> {
+    fn drop(&mut self) {
+        if !self.keep {
+            self.dequeued.store(self.next, Ordering::Release);
+        }
+    }
+}
+
+impl<const MSG_SIZE: usize, const MSG_COUNT: usize, const ALIGN: usize> AlignedQueueingPort<MSG_SIZE, MSG_COUNT, ALIGN> {
+    /// A port for messages of type `T`, failing with `PortError::Misaligned`
+    /// if `ALIGN` does not give the slots `T`'s alignment, and with
+    /// `PortError::LayoutMismatch` if a `T` does not fit in `MSG_SIZE`.
+    #[cfg(feature = "alloc")]
+    pub fn for_type<T: Pod>() -> Result<Self, crate::PortError> {
+        if align_of::<T>() > ALIGN.max(4) {
+            return Err(crate::PortError::Misaligned);
+        }
+        if size_of::<T>() > MSG_SIZE {
+            return Err(crate::PortError::LayoutMismatch);
+        }
+        Ok(Self::new())
+    }
+
+    /// Queues a copy of `value`'s bytes, failing like `enqueue_bytes`.
+    pub fn enqueue_value<T: Pod>(&mut self, value: &T) -> Result<(), QueueError> {
+        // A `Pod` has no padding, so all its bytes are initialised.
+        let bytes = unsafe { core::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>()) };
+        self.enqueue_bytes(bytes)
+    }
+
+    /// The oldest message as a reference to the `T` in its slot, checked as
+    /// described in the module documentation. Dropping the guard dequeues
+    /// the message; `SlotRef::keep` leaves it queued.
+    pub fn dequeue_ref<T: Pod>(&mut self) -> Result<SlotRef<'_, T>, QueueError> {
+        let (enqueued, dequeued) = self.counters();
+        let read = dequeued.load(Ordering::Relaxed);
+        if enqueued.load(Ordering::Acquire) == read {
+            return Err(QueueError::EmptyBuffer);
+        }
+        let found = self.slot_len(read as usize).load(Ordering::Relaxed) as usize;
+        if found != size_of::<T>() {
+            return Err(QueueError::LengthMismatch { expected: size_of::<T>(), found });
+        }
+        let slot = self.slot_ptr(read as usize);
+        if !(slot as usize).is_multiple_of(align_of::<T>()) {
+            return Err(QueueError::Misaligned { align: align_of::<T>() });
+        }
+        let next = next_counter(read, MSG_COUNT);
+        Ok(SlotRef { value: slot.cast::<T>().cast_const(), keep: false, dequeued, next, _port: PhantomData })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PortError;
+    use core::ptr::NonNull;
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Sample {
+        id: u32,
+        channel: u32,
+        value: f64,
+    }
+
+    unsafe impl Pod for Sample {}
+
+    const SAMPLE: Sample = Sample { id: 7, channel: 2, value: 0.5 };
+
+    #[test]
+    fn values_are_read_in_place() {
+        let mut port = AlignedQueueingPort::<64, 4, 64>::for_type::<Sample>().unwrap();
+        for id in 0..3 {
+            port.enqueue_value(&Sample { id, ..SAMPLE }).unwrap();
+        }
+        let slot = port.slot_ptr(0).cast_const();
+        {
+            let sample = port.dequeue_ref::<Sample>().unwrap();
+            assert_eq!(*sample, Sample { id: 0, ..SAMPLE });
+            assert_eq!(&*sample as *const Sample as *const u8, slot, "a reference into the slot");
+        }
+        port.dequeue_ref::<Sample>().unwrap().keep();
+        assert_eq!(port.dequeue_ref::<Sample>().unwrap().id, 1, "kept, so still queued");
+        assert_eq!(port.dequeue_ref::<Sample>().unwrap().id, 2);
+        assert!(matches!(port.dequeue_ref::<Sample>(), Err(QueueError::EmptyBuffer)));
+        assert_eq!(port.copied_out(), 0, "no message was copied out");
+    }
+
+    #[test]
+    fn wrong_size_is_rejected() {
+        let mut port = AlignedQueueingPort::<64, 4, 8>::new();
+        port.enqueue_value(&7u32).unwrap();
+        assert!(matches!(
+            port.dequeue_ref::<Sample>(),
+            Err(QueueError::LengthMismatch { expected: 16, found: 4 })
+        ));
+        assert!(matches!(port.dequeue_ref::<u64>(), Err(QueueError::LengthMismatch { expected: 8, found: 4 })));
+        assert_eq!(*port.dequeue_ref::<u32>().unwrap(), 7, "still queued after the mismatches");
+        assert!(port.is_empty());
+    }
+
+    #[test]
+    fn unaligned_configuration_is_rejected() {
+        assert!(matches!(AlignedQueueingPort::<64, 4, 4>::for_type::<Sample>(), Err(PortError::Misaligned)));
+        assert!(matches!(AlignedQueueingPort::<8, 4, 8>::for_type::<Sample>(), Err(PortError::LayoutMismatch)));
+
+        // Slots of 4-byte alignment, in memory whose 8-byte alignment is off
+        // by 4, so no slot is aligned for a u64.
+        #[repr(C, align(8))]
+        struct Memory([u8; 128]);
+        let mut memory = Box::new(Memory([0; 128]));
+        let base = unsafe { NonNull::new(memory.0.as_mut_ptr()).unwrap().add(4) };
+        let mut port = unsafe { AlignedQueueingPort::<8, 4, 4>::attach(base) }.unwrap();
+        port.enqueue_value(&1u64).unwrap();
+        assert!(matches!(port.dequeue_ref::<u64>(), Err(QueueError::Misaligned { align: 8 })));
+        assert_eq!(port.dequeue().unwrap(), 1u64.to_ne_bytes());
+    }
+}
@@ -0,0 +1,126 @@
+//! Addresses to attach BPF uprobes to, and probe points for them.
+//!
+//! A uprobe traces a user-space function by the address of its first
+//! instruction, without rebuilding the program. `uprobe_entrypoints` lists
+//! the enqueue and dequeue entry points and the main checks they make,
+//! with the run-time address of each in this process; subtract the load
+//! address of the binary (in `/proc/<pid>/maps`) to get the offset to give
+//! `perf probe` or BCC. An optimized build inlines these into their callers,
+//! so a probe there only sees the calls that were not.
+//!
+//! With the `bpf-trace` feature, every successful enqueue and dequeue also
+//! calls a probe point that is never inlined: an exported function of one
+//! `nop`, named `__bpf_usdt_enqueue` and `__bpf_usdt_dequeue`, whose
+//! arguments, in the first two argument registers, are the address of the
+//! segment and the sequence number of the message (the last one, for a
+//! batch). A tracer attached to both by symbol can match each dequeue to
+//! its enqueue; `tools/bcc_message_latency.py` prints the time between.
+//! Without the feature the probe points are not there.
+
+use std::sync::OnceLock;
+
+use crate::{Message, PeerRole, QueueError, QueueingPort};
+
+impl QueueingPort {
+    /// The name and address of each function worth attaching a uprobe to,
+    /// see the `uprobe` module.
+    pub fn uprobe_entrypoints() -> &'static [(&'static str, usize)] {
+        static ENTRYPOINTS: OnceLock<Vec<(&'static str, usize)>> = OnceLock::new();
+        ENTRYPOINTS.get_or_init(|| {
+            let entrypoints = vec![
+                ("QueueingPort::enqueue", QueueingPort::enqueue as fn(&mut QueueingPort, Message) -> _ as usize),
+                ("QueueingPort::dequeue", QueueingPort::dequeue as fn(&mut QueueingPort) -> _ as usize),
+                (
+                    "QueueingPort::dequeue_unfiltered",
+                    QueueingPort::dequeue_unfiltered as fn(&mut QueueingPort) -> _ as usize,
+                ),
+                (
+                    "QueueingPort::enqueue_bytes",
+                    QueueingPort::enqueue_bytes as fn(&mut QueueingPort, &[u8]) -> _ as usize,
+                ),
+                (
+                    "QueueingPort::check_generation",
+                    QueueingPort::check_generation as fn(&QueueingPort) -> Result<(), QueueError> as usize,
+                ),
+                (
+                    "QueueingPort::check_poison",
+                    QueueingPort::check_poison as fn(&QueueingPort) -> Result<(), QueueError> as usize,
+                ),
+                (
+                    "QueueingPort::check_owner",
+                    QueueingPort::check_owner as fn(&QueueingPort, PeerRole) -> Result<(), QueueError> as usize,
+                ),
+            ];
+            #[cfg(feature = "bpf-trace")]
+            let entrypoints = {
+                let mut entrypoints = entrypoints;
+                entrypoints.extend([
+                    ("__bpf_usdt_enqueue", probes::__bpf_usdt_enqueue as extern "C" fn(_, _) as usize),
+                    ("__bpf_usdt_dequeue", probes::__bpf_usdt_dequeue as extern "C" fn(_, _) as usize),
+                ]);
+                entrypoints
+            };
+            entrypoints
+        })
+    }
+}
+
+#[cfg(feature = "bpf-trace")]
+pub(crate) mod probes {
+    use core::arch::asm;
+    use core::sync::atomic::Ordering;
+
+    use crate::QueueingPort;
+
+    #[no_mangle]
+    #[inline(never)]
+    pub extern "C" fn __bpf_usdt_enqueue(segment: *const u8, sequence: u32) {
+        // The operands keep the arguments, and the call, from being
+        // optimized away.
+        unsafe { asm!("nop /* {0} {1} */", in(reg) segment as usize, in(reg) sequence as usize, options(nomem, nostack)) };
+    }
+
+    #[no_mangle]
+    #[inline(never)]
+    pub extern "C" fn __bpf_usdt_dequeue(segment: *const u8, sequence: u32) {
+        unsafe { asm!("nop /* {0} {1} */", in(reg) segment as usize, in(reg) sequence as usize, options(nomem, nostack)) };
+    }
+
+    /// The probe arguments: the segment, and the sequence number of the
+    /// last message enqueued, or dequeued.
+    fn arguments(port: &QueueingPort, dequeued: bool) -> (*const u8, u32) {
+        let segment = port.segment();
+        let header = &segment.header;
+        let counter = if dequeued { &header.dequeued } else { &header.enqueued };
+        let sequence = counter.load(header.byte_order(), Ordering::Relaxed).wrapping_sub(1);
+        ((segment as *const crate::Segment).cast(), sequence)
+    }
+
+    pub(crate) fn enqueued(port: &QueueingPort) {
+        let (segment, sequence) = arguments(port, false);
+        __bpf_usdt_enqueue(segment, sequence);
+    }
+
+    pub(crate) fn dequeued(port: &QueueingPort) {
+        let (segment, sequence) = arguments(port, true);
+        __bpf_usdt_dequeue(segment, sequence);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entrypoints_have_distinct_addresses() {
+        let entrypoints = QueueingPort::uprobe_entrypoints();
+        assert!(entrypoints.iter().any(|&(name, _)| name == "QueueingPort::enqueue"));
+        assert!(entrypoints.iter().any(|&(name, _)| name == "QueueingPort::dequeue"));
+        let mut addresses: Vec<usize> = entrypoints.iter().map(|&(_, address)| address).collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+        assert_eq!(addresses.len(), entrypoints.len());
+        assert!(addresses[0] != 0);
+        assert!(core::ptr::eq(entrypoints, QueueingPort::uprobe_entrypoints()), "computed once");
+    }
+}
@@ -0,0 +1,766 @@
+//! Copying messages in and out of a named port with io_uring (Linux 5.1+).
+//!
+//! An `IoUringPort` leaves the copy into or out of a slot to the kernel:
+//! `enqueue` submits an `IORING_OP_WRITE_FIXED` of the message to the
+//! segment's shared-memory object, at the offset of the claimed slot, and
+//! `dequeue` an `IORING_OP_READ_FIXED` of the front slot. Both go through
+//! two buffers registered with the ring up front, so the kernel does not
+//! pin and map the caller's memory on every operation. The header stays
+//! with the `QueueingPort` underneath: the slot is claimed before the copy
+//! and published or freed after its completion.
+//!
+//! `poll_dequeue` submits the read without waiting for it. The completion
+//! is picked up by `process_completions`, the caller's completion loop,
+//! which wakes the task the read was submitted for.
+//!
+//! This does not make a port faster: every copy still costs a system call,
+//! and `benches/io_uring.rs` measures an enqueue-and-dequeue pair at tens
+//! of times the cost of the plain port's. It is for callers that want the
+//! copies off their own thread's instructions, or completions in an event
+//! loop they already drive.
+//!
+//! An `IoUringRegisteredPort` is for callers with a ring of their own: it
+//! registers the whole segment with that ring as one fixed buffer, and
+//! hands out the `IoUringSqe`s that read from a file or socket straight
+//! into a claimed slot, or write a queued message straight out of one,
+//! for the caller to submit alongside the rest of its I/O. The message is
+//! never copied through a buffer of the process's. The caller commits the
+//! slot once the completion comes back, and not before: until then the
+//! kernel may still be writing or reading it.
+//!
+//! The `io-uring` crate is not a dependency; the few ring operations this
+//! needs are done with `libc` below, as for `ivshmem` and `numa`.
+
+use core::task::{Context, Poll, Waker};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{DequeuePort, EnqueuePort, Message, PortError, QueueError, QueueingPort, SIZE};
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+const IORING_OFF_SQES: i64 = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_BUFFERS: u32 = 0;
+const IORING_UNREGISTER_BUFFERS: u32 = 1;
+const IORING_OP_READ_FIXED: u8 = 4;
+const IORING_OP_WRITE_FIXED: u8 = 5;
+
+/// Submission queue entries; a port has at most one read and one write in
+/// flight.
+const RING_ENTRIES: u32 = 4;
+/// `user_data` of the two kinds of operation, and the index of the
+/// registered buffer each uses.
+const WRITE: u16 = 0;
+const READ: u16 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// A submission queue entry, laid out as the kernel's `io_uring_sqe`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoUringSqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub ioprio: u16,
+    pub fd: i32,
+    pub off: u64,
+    pub addr: u64,
+    pub len: u32,
+    pub rw_flags: u32,
+    /// Comes back in the completion, for telling operations apart.
+    pub user_data: u64,
+    pub buf_index: u16,
+    pub personality: u16,
+    pub splice_fd_in: i32,
+    pub addr3: u64,
+    pub pad: u64,
+}
+
+const _: () = assert!(core::mem::size_of::<IoUringSqe>() == 64);
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A shared `mmap` of part of the ring file descriptor.
+struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: i32, len: usize, offset: i64) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: NonNull::new(ptr.cast()).expect("mmap returned null"),
+            len,
+        })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.as_ptr().add(offset as usize) }.cast()
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+/// One io_uring instance: its file descriptor and the three rings.
+struct Ring {
+    // Declared before the file, so the rings are unmapped first.
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    params: Params,
+    fd: File,
+}
+
+impl Ring {
+    fn new() -> io::Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, RING_ENTRIES, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { File::from_raw_fd(fd as i32) };
+        let raw = fd.as_raw_fd();
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * core::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * core::mem::size_of::<IoUringSqe>();
+        Ok(Ring {
+            sq: Mapping::new(raw, sq_len, IORING_OFF_SQ_RING)?,
+            cq: Mapping::new(raw, cq_len, IORING_OFF_CQ_RING)?,
+            sqes: Mapping::new(raw, sqes_len, IORING_OFF_SQES)?,
+            params,
+            fd,
+        })
+    }
+
+    fn register_buffers(&self, buffers: &mut [[u8; SIZE]; 2]) -> io::Result<()> {
+        let iovecs = buffers.each_mut().map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr().cast(),
+            iov_len: SIZE,
+        });
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.fd.as_raw_fd(),
+                IORING_REGISTER_BUFFERS,
+                iovecs.as_ptr(),
+                iovecs.len() as u32,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Queues `sqe` and submits it, waiting for `min_complete` completions.
+    fn submit(&self, sqe: IoUringSqe, min_complete: u32) -> io::Result<()> {
+        let tail = unsafe { &*self.sq.at::<AtomicU32>(self.params.sq_off.tail) };
+        let mask = unsafe { *self.sq.at::<u32>(self.params.sq_off.ring_mask) };
+        // Only this handle submits, so the tail is ours to read relaxed.
+        let current = tail.load(Ordering::Relaxed);
+        let index = current & mask;
+        unsafe {
+            ptr::write(self.sqes.at::<IoUringSqe>(0).add(index as usize), sqe);
+            *self.sq.at::<u32>(self.params.sq_off.array).add(index as usize) = index;
+        }
+        // Publishes the entry to the kernel.
+        tail.store(current.wrapping_add(1), Ordering::Release);
+        self.enter(1, min_complete)
+    }
+
+    fn enter(&self, to_submit: u32, min_complete: u32) -> io::Result<()> {
+        let flags = if min_complete > 0 { IORING_ENTER_GETEVENTS } else { 0 };
+        loop {
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    to_submit,
+                    min_complete,
+                    flags,
+                    ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if result >= 0 {
+                return Ok(());
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+
+    /// Calls `handle` with the `user_data` and result of every completion
+    /// posted so far.
+    fn reap(&self, mut handle: impl FnMut(u64, i32)) -> usize {
+        let head = unsafe { &*self.cq.at::<AtomicU32>(self.params.cq_off.head) };
+        let tail = unsafe { &*self.cq.at::<AtomicU32>(self.params.cq_off.tail) };
+        let mask = unsafe { *self.cq.at::<u32>(self.params.cq_off.ring_mask) };
+        let mut current = head.load(Ordering::Relaxed);
+        let end = tail.load(Ordering::Acquire);
+        let mut reaped = 0;
+        while current != end {
+            let cqe = unsafe { &*self.cq.at::<Cqe>(self.params.cq_off.cqes).add((current & mask) as usize) };
+            handle(cqe.user_data, cqe.res);
+            current = current.wrapping_add(1);
+            reaped += 1;
+        }
+        // Hands the entries back to the kernel.
+        head.store(current, Ordering::Release);
+        reaped
+    }
+}
+
+fn copy_failed(error: io::Error) -> QueueError {
+    QueueError::CopyFailed(error.raw_os_error().unwrap_or(0))
+}
+
+/// A named port whose slot copies are done by the kernel, see the module
+/// documentation.
+///
+/// Dereferences to the underlying `QueueingPort`, whose own methods copy
+/// with the CPU as usual.
+pub struct IoUringPort {
+    port: QueueingPort,
+    ring: Ring,
+    /// The port's shared-memory object, which the copies go through.
+    object: File,
+    /// Registered with the ring; index `WRITE` is the source of writes,
+    /// `READ` the destination of reads.
+    buffers: Box<[[u8; SIZE]; 2]>,
+    /// `None` while no read is submitted, `Some(None)` while one is in
+    /// flight and `Some(Some(res))` once it completed.
+    read: Option<Option<i32>>,
+    write: Option<i32>,
+    /// Woken once the read submitted by `poll_dequeue` completes.
+    waker: Option<Waker>,
+}
+
+// The ring is only used through `&mut self`, and the buffers are owned.
+unsafe impl Send for IoUringPort {}
+
+impl IoUringPort {
+    /// Sets up a ring for `port`, which must be a named port created or
+    /// opened as usual; fails with `PortError::Unnamed` otherwise.
+    pub fn new(port: QueueingPort) -> Result<IoUringPort, PortError> {
+        let name = port.memory.os_id().ok_or(PortError::Unnamed)?;
+        let name = CString::new(name).map_err(|_| PortError::Unnamed)?;
+        let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_RDWR, 0) };
+        if fd < 0 {
+            return Err(PortError::Io(io::Error::last_os_error()));
+        }
+        let object = unsafe { File::from_raw_fd(fd) };
+        let ring = Ring::new().map_err(PortError::Io)?;
+        let mut buffers = Box::new([[0; SIZE]; 2]);
+        ring.register_buffers(&mut buffers).map_err(PortError::Io)?;
+        Ok(IoUringPort { port, ring, object, buffers, read: None, write: None, waker: None })
+    }
+
+    /// Where `slot` lies in the shared-memory object.
+    fn offset_of(&self, slot: *const u8) -> u64 {
+        (slot as usize - self.port.segment() as *const crate::Segment as usize) as u64
+    }
+
+    fn sqe(&self, opcode: u8, slot: *const u8, buffer: u16) -> IoUringSqe {
+        IoUringSqe {
+            opcode,
+            fd: self.object.as_raw_fd(),
+            off: self.offset_of(slot),
+            addr: self.buffers[buffer as usize].as_ptr() as u64,
+            len: SIZE as u32,
+            user_data: u64::from(buffer),
+            buf_index: buffer,
+            ..IoUringSqe::default()
+        }
+    }
+
+    /// Enqueues `message`, written into its slot by the kernel. Fails like
+    /// `QueueingPort::enqueue` when there is no room, and with
+    /// `QueueError::CopyFailed` if the write does, leaving the slot claimed.
+    pub fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        let slot = self.port.claim_write_slice()?.as_ptr();
+        self.buffers[WRITE as usize] = message.0;
+        let sqe = self.sqe(IORING_OP_WRITE_FIXED, slot, WRITE);
+        self.ring.submit(sqe, 1).map_err(copy_failed)?;
+        while self.write.is_none() {
+            self.reap();
+            if self.write.is_none() {
+                self.ring.enter(0, 1).map_err(copy_failed)?;
+            }
+        }
+        match self.write.take() {
+            Some(res) if res as usize == SIZE => self.port.commit_write(),
+            Some(res) => Err(QueueError::CopyFailed(-res)),
+            None => unreachable!(),
+        }
+    }
+
+    /// Takes the front message, read out of its slot by the kernel. Like
+    /// `claim_read_slice`, this bypasses the type filter, the dedup window
+    /// and CRC checks.
+    pub fn dequeue(&mut self) -> Result<Message, QueueError> {
+        self.start_read()?;
+        loop {
+            if let Some(result) = self.finish_read() {
+                return result;
+            }
+            self.ring.enter(0, 1).map_err(copy_failed)?;
+            self.reap();
+        }
+    }
+
+    /// Like `dequeue`, without waiting for the kernel: submits the read
+    /// and returns `Pending` until `process_completions` has seen it done,
+    /// which wakes `cx`'s waker. An empty port is `Ready(Err(EmptyBuffer))`.
+    pub fn poll_dequeue(&mut self, cx: &mut Context<'_>) -> Poll<Result<Message, QueueError>> {
+        if let Err(error) = self.start_read() {
+            return Poll::Ready(Err(error));
+        }
+        self.waker = Some(cx.waker().clone());
+        self.reap();
+        match self.finish_read() {
+            Some(result) => {
+                self.waker = None;
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    /// Picks up the completions posted so far, waking the task waiting in
+    /// `poll_dequeue` if its read is among them. Returns how many there
+    /// were; never blocks.
+    pub fn process_completions(&mut self) -> usize {
+        let reaped = self.reap();
+        if matches!(self.read, Some(Some(_))) {
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+        reaped
+    }
+
+    fn reap(&mut self) -> usize {
+        let (read, write) = (&mut self.read, &mut self.write);
+        self.ring.reap(|user_data, res| match user_data as u16 {
+            READ => *read = Some(Some(res)),
+            _ => *write = Some(res),
+        })
+    }
+
+    /// Submits the read of the front slot, unless one is already submitted.
+    fn start_read(&mut self) -> Result<(), QueueError> {
+        if self.read.is_some() {
+            return Ok(());
+        }
+        let slot = self.port.claim_read_slice()?.as_ptr();
+        let sqe = self.sqe(IORING_OP_READ_FIXED, slot, READ);
+        self.ring.submit(sqe, 0).map_err(copy_failed)?;
+        self.read = Some(None);
+        Ok(())
+    }
+
+    /// The submitted read's message once it has completed. A failed read
+    /// leaves the message queued, for the next call to read again.
+    fn finish_read(&mut self) -> Option<Result<Message, QueueError>> {
+        let res = self.read?? as isize;
+        self.read = None;
+        if res as usize != SIZE {
+            return Some(Err(QueueError::CopyFailed(-res as i32)));
+        }
+        self.port.commit_read();
+        Some(Ok(Message(self.buffers[READ as usize])))
+    }
+}
+
+impl Drop for IoUringPort {
+    fn drop(&mut self) {
+        // The kernel may still be copying into the read buffer; writes are
+        // waited for by `enqueue`.
+        while matches!(self.read, Some(None)) {
+            if self.ring.enter(0, 1).is_err() {
+                break;
+            }
+            self.reap();
+        }
+    }
+}
+
+impl core::ops::Deref for IoUringPort {
+    type Target = QueueingPort;
+
+    fn deref(&self) -> &QueueingPort {
+        &self.port
+    }
+}
+
+impl core::ops::DerefMut for IoUringPort {
+    fn deref_mut(&mut self) -> &mut QueueingPort {
+        &mut self.port
+    }
+}
+
+impl EnqueuePort for IoUringPort {
+    fn enqueue(&mut self, message: Message) -> Result<(), QueueError> {
+        IoUringPort::enqueue(self, message)
+    }
+}
+
+impl DequeuePort for IoUringPort {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        IoUringPort::dequeue(self)
+    }
+}
+
+/// The index the segment of an `IoUringRegisteredPort` is registered at.
+pub const REGISTERED_BUFFER_INDEX: u16 = 0;
+
+/// A named port whose segment is registered with the caller's own
+/// io_uring instance, see the module documentation.
+///
+/// Dereferences to the underlying `QueueingPort`, as `IoUringPort` does.
+pub struct IoUringRegisteredPort {
+    port: QueueingPort,
+    /// The caller's ring, duplicated, to unregister the segment from when
+    /// dropped.
+    ring: OwnedFd,
+}
+
+/// A slot claimed for the kernel to fill, or to copy out of, by an
+/// `IoUringRegisteredPort`. It stays claimed until handed back to
+/// `commit_registered` or `release_registered`, which must not happen
+/// before the operation on it has completed.
+#[derive(Debug)]
+pub struct RegisteredBuffer {
+    addr: u64,
+    /// Claimed by `claim_registered`, rather than `claim_registered_front`.
+    write: bool,
+}
+
+impl RegisteredBuffer {
+    /// The first byte of the slot, `SIZE` bytes long.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.addr as *const u8
+    }
+}
+
+impl IoUringRegisteredPort {
+    /// Registers the segment of `port`, a named port created or opened as
+    /// usual, with `ring` as fixed buffer `REGISTERED_BUFFER_INDEX`. Fails
+    /// with `PortError::Unnamed` for any other port, and with
+    /// `PortError::Io` if the kernel refuses, `EBUSY` if the ring already
+    /// has buffers registered.
+    pub fn new(port: QueueingPort, ring: BorrowedFd<'_>) -> Result<IoUringRegisteredPort, PortError> {
+        // An owned segment moves with the port; a mapping stays put.
+        port.memory.os_id().ok_or(PortError::Unnamed)?;
+        let ring = ring.try_clone_to_owned().map_err(PortError::Io)?;
+        let iovec = libc::iovec {
+            iov_base: port.segment() as *const crate::Segment as *mut libc::c_void,
+            iov_len: core::mem::size_of::<crate::Segment>(),
+        };
+        let result = unsafe {
+            libc::syscall(libc::SYS_io_uring_register, ring.as_raw_fd(), IORING_REGISTER_BUFFERS, &iovec, 1u32)
+        };
+        if result < 0 {
+            return Err(PortError::Io(io::Error::last_os_error()));
+        }
+        Ok(IoUringRegisteredPort { port, ring })
+    }
+
+    /// Claims the next free slot for the kernel to fill, as
+    /// `claim_write_slice` does; claiming again before the commit returns
+    /// the same slot.
+    pub fn claim_registered(&mut self) -> Result<RegisteredBuffer, QueueError> {
+        let slot = self.port.claim_write_slice()?.as_ptr();
+        Ok(RegisteredBuffer { addr: slot as u64, write: true })
+    }
+
+    /// An `IORING_OP_READ_FIXED` of `SIZE` bytes at `offset` of `fd`
+    /// straight into `slot`, for the caller to submit on the registered
+    /// ring; `user_data` is 0 for the caller to set. Once it completes with
+    /// `SIZE`, `commit_registered` enqueues the message.
+    pub fn enqueue_registered(&self, slot: &RegisteredBuffer, fd: RawFd, offset: u64) -> IoUringSqe {
+        assert!(slot.write, "a slot claimed for dequeueing");
+        registered_sqe(IORING_OP_READ_FIXED, slot, fd, offset)
+    }
+
+    /// Enqueues the message the kernel wrote into `slot`, failing as
+    /// `QueueingPort::commit_write` does.
+    pub fn commit_registered(&mut self, slot: RegisteredBuffer) -> Result<(), QueueError> {
+        assert!(slot.write, "a slot claimed for dequeueing");
+        self.port.commit_write()
+    }
+
+    /// Claims the front message for the kernel to copy out, as
+    /// `claim_read_slice` does, bypassing the type filter.
+    pub fn claim_registered_front(&mut self) -> Result<RegisteredBuffer, QueueError> {
+        let slot = self.port.claim_read_slice()?.as_ptr();
+        Ok(RegisteredBuffer { addr: slot as u64, write: false })
+    }
+
+    /// An `IORING_OP_WRITE_FIXED` of the message in `slot` to `offset` of
+    /// `fd`, for the caller to submit; once it completes,
+    /// `release_registered` dequeues the message.
+    pub fn dequeue_registered(&self, slot: &RegisteredBuffer, fd: RawFd, offset: u64) -> IoUringSqe {
+        assert!(!slot.write, "a slot claimed for enqueueing");
+        registered_sqe(IORING_OP_WRITE_FIXED, slot, fd, offset)
+    }
+
+    /// Dequeues the message the kernel copied out of `slot`.
+    pub fn release_registered(&mut self, slot: RegisteredBuffer) {
+        assert!(!slot.write, "a slot claimed for enqueueing");
+        self.port.commit_read();
+    }
+}
+
+fn registered_sqe(opcode: u8, slot: &RegisteredBuffer, fd: RawFd, offset: u64) -> IoUringSqe {
+    IoUringSqe {
+        opcode,
+        fd,
+        off: offset,
+        addr: slot.addr,
+        len: SIZE as u32,
+        buf_index: REGISTERED_BUFFER_INDEX,
+        ..IoUringSqe::default()
+    }
+}
+
+impl Drop for IoUringRegisteredPort {
+    fn drop(&mut self) {
+        // The kernel waits for the operations in flight on the ring first.
+        unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.ring.as_raw_fd(),
+                IORING_UNREGISTER_BUFFERS,
+                ptr::null::<libc::iovec>(),
+                0u32,
+            )
+        };
+    }
+}
+
+impl core::ops::Deref for IoUringRegisteredPort {
+    type Target = QueueingPort;
+
+    fn deref(&self) -> &QueueingPort {
+        &self.port
+    }
+}
+
+impl core::ops::DerefMut for IoUringRegisteredPort {
+    fn deref_mut(&mut self) -> &mut QueueingPort {
+        &mut self.port
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PortError, HANDSHAKE_TIMEOUT};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::thread;
+    use std::os::unix::io::AsFd;
+    use std::time::Instant;
+
+    /// Both ends of a fresh named port.
+    fn named_pair(test: &str) -> (QueueingPort, QueueingPort) {
+        let name = format!("/qp_uring_{}_{}", test, std::process::id());
+        let opener = thread::spawn({
+            let name = name.clone();
+            move || {
+                let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+                loop {
+                    match QueueingPort::open(&name) {
+                        Err(PortError::Open(_)) if Instant::now() < deadline => thread::yield_now(),
+                        result => break result.unwrap(),
+                    }
+                }
+            }
+        });
+        let writer = QueueingPort::create(&name).unwrap();
+        (writer, opener.join().unwrap())
+    }
+
+    /// `None` where io_uring is disabled, e.g. by a container's seccomp
+    /// profile.
+    fn uring(port: QueueingPort) -> Option<IoUringPort> {
+        match IoUringPort::new(port) {
+            Err(PortError::Io(error)) if Ring::new().is_err() => {
+                eprintln!("io_uring unavailable: {}", error);
+                None
+            }
+            result => Some(result.unwrap()),
+        }
+    }
+
+    #[test]
+    fn messages_cross_between_kernel_and_cpu_copies() {
+        let (writer, mut reader) = named_pair("cross");
+        let Some(mut writer) = uring(writer) else { return };
+        writer.enqueue(Message([1; SIZE])).unwrap();
+        writer.enqueue(Message([2; SIZE])).unwrap();
+        assert_eq!(reader.dequeue().unwrap().0, [1; SIZE], "written by the kernel");
+
+        let mut reader = uring(reader).unwrap();
+        writer.port.enqueue(Message([3; SIZE])).unwrap();
+        assert_eq!(reader.dequeue().unwrap().0, [2; SIZE]);
+        assert_eq!(DequeuePort::dequeue(&mut reader).unwrap().0, [3; SIZE]);
+        assert!(matches!(reader.dequeue(), Err(QueueError::EmptyBuffer)));
+        assert_eq!((writer.stats().enqueued, reader.stats().dequeued), (3, 3));
+    }
+
+    struct Count(AtomicUsize);
+
+    impl Wake for Count {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn completion_loop_wakes_the_pending_read() {
+        let (writer, reader) = named_pair("poll");
+        let Some(mut writer) = uring(writer) else { return };
+        let mut reader = uring(reader).unwrap();
+        let count = Arc::new(Count(AtomicUsize::new(0)));
+        let waker = Waker::from(count.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(reader.poll_dequeue(&mut cx), Poll::Ready(Err(QueueError::EmptyBuffer))));
+
+        writer.enqueue(Message([7; SIZE])).unwrap();
+        let message = loop {
+            match reader.poll_dequeue(&mut cx) {
+                Poll::Ready(result) => break result.unwrap(),
+                Poll::Pending => {
+                    // The copy may finish inline; otherwise wait for it.
+                    while count.0.load(Ordering::Relaxed) == 0 {
+                        reader.process_completions();
+                        thread::yield_now();
+                    }
+                }
+            }
+        };
+        assert_eq!(message.0, [7; SIZE]);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn unnamed_ports_are_refused() {
+        assert!(matches!(IoUringPort::new(QueueingPort::new()), Err(PortError::Unnamed)));
+    }
+
+    #[test]
+    fn registered_slots_are_filled_and_drained_by_the_callers_ring() {
+        let (writer, reader) = named_pair("registered");
+        let Ok(ring) = Ring::new() else {
+            eprintln!("io_uring unavailable");
+            return;
+        };
+        let mut writer = IoUringRegisteredPort::new(writer, ring.fd.as_fd()).unwrap();
+        let other = Ring::new().unwrap();
+        let mut reader = IoUringRegisteredPort::new(reader, other.fd.as_fd()).unwrap();
+
+        let path = std::env::temp_dir().join(format!("qp_uring_registered_{}", std::process::id()));
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&file, &[5; SIZE], 0).unwrap();
+
+        // From the file into a slot, on the caller's ring.
+        let slot = writer.claim_registered().unwrap();
+        let mut sqe = writer.enqueue_registered(&slot, file.as_raw_fd(), 0);
+        sqe.user_data = 42;
+        ring.submit(sqe, 1).unwrap();
+        let mut completions = Vec::new();
+        ring.reap(|user_data, res| completions.push((user_data, res)));
+        assert_eq!(completions, [(42, SIZE as i32)]);
+        writer.commit_registered(slot).unwrap();
+
+        // And from the slot back out, at another offset.
+        let slot = reader.claim_registered_front().unwrap();
+        other.submit(reader.dequeue_registered(&slot, file.as_raw_fd(), SIZE as u64), 1).unwrap();
+        let mut res = 0;
+        other.reap(|_, result| res = result);
+        assert_eq!(res, SIZE as i32);
+        reader.release_registered(slot);
+
+        let mut copied = [0; SIZE];
+        std::os::unix::fs::FileExt::read_exact_at(&file, &mut copied, SIZE as u64).unwrap();
+        assert_eq!(copied, [5; SIZE]);
+        assert!(reader.is_empty());
+        assert_eq!(writer.stats().enqueued, 1);
+        assert!(matches!(IoUringRegisteredPort::new(QueueingPort::new(), ring.fd.as_fd()), Err(PortError::Unnamed)));
+    }
+}
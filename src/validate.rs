@@ -0,0 +1,111 @@
+//! A check every message must pass to be enqueued, for ports whose
+//! messages follow a schema: magic bytes at the start, a range of byte
+//! values, and the like.
+//!
+//! `set_validator` gives a handle a function called on each message it
+//! enqueues, once the message is in its slot and before the reader can see
+//! it. A message it fails is not queued, and the enqueue fails with
+//! `QueueError::ValidationFailed` and the validator's reason. Every enqueue
+//! runs it: closures filling the slot in place, `commit_write` on a claimed
+//! slot and `forward_with` on the forwarded message as patched. The batch
+//! enqueues stop at a message it fails and return that message, as one
+//! that did not fit. Only the end-of-stream record of `close_with_summary`
+//! is written without it, having a layout of its own.
+
+use core::sync::atomic::Ordering;
+
+use crate::{slot_index, Message, QueueError, QueueingPort, Segment};
+
+/// Why a validator rejected a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidationError {
+    pub reason: &'static str,
+}
+
+/// Checks a message about to be enqueued, see `set_validator`.
+pub type Validator = fn(&Message) -> Result<(), ValidationError>;
+
+impl QueueingPort {
+    /// Checks every message this handle enqueues with `validator` from now
+    /// on, see the `validate` module. There is none by default.
+    pub fn set_validator(&mut self, validator: Validator) {
+        self.validator = Some(validator);
+    }
+
+    pub fn clear_validator(&mut self) {
+        self.validator = None;
+    }
+
+    /// Runs the validator on the message written to the free slot `ahead`
+    /// of the next one.
+    pub(crate) fn validate_written(&self, segment: &Segment, ahead: usize) -> Result<(), QueueError> {
+        let Some(validator) = self.validator else { return Ok(()) };
+        let header = &segment.header;
+        let index = slot_index(header.write_index.load(header.byte_order(), Ordering::Relaxed) as usize + ahead);
+        // The slot is free, so the writer's alone; a `Message` is its bytes.
+        let message = unsafe { &*segment.slot(index).cast::<Message>() };
+        validator(message).map_err(QueueError::ValidationFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIZE;
+
+    fn ascii_first_byte(message: &Message) -> Result<(), ValidationError> {
+        if message.0[0] > 127 {
+            return Err(ValidationError { reason: "first byte is not ASCII" });
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_messages_are_not_queued() {
+        let mut port = QueueingPort::new();
+        port.set_validator(ascii_first_byte);
+        port.enqueue(Message([b'a'; SIZE])).unwrap();
+        let rejected = port.enqueue(Message([200; SIZE]));
+        assert!(matches!(
+            rejected,
+            Err(QueueError::ValidationFailed(ValidationError { reason: "first byte is not ASCII" }))
+        ));
+        assert!(matches!(port.enqueue_with(|slot| slot[0] = 255), Err(QueueError::ValidationFailed(_))));
+        assert_eq!((port.len(), port.stats().enqueued), (1, 1));
+
+        // The slot a rejected message was written to is reused.
+        port.enqueue(Message([b'b'; SIZE])).unwrap();
+        assert_eq!(port.dequeue().unwrap().0, [b'a'; SIZE]);
+        assert_eq!(port.dequeue().unwrap().0, [b'b'; SIZE]);
+
+        port.clear_validator();
+        port.enqueue(Message([200; SIZE])).unwrap();
+    }
+
+    #[test]
+    fn every_enqueue_path_validates() {
+        let mut port = QueueingPort::new();
+        port.set_validator(ascii_first_byte);
+        let messages = [Message([b'a'; SIZE]), Message([b'b'; SIZE]), Message([200; SIZE]), Message([b'c'; SIZE])];
+        let (placed, leftover) = port.enqueue_from_iter(messages.into_iter());
+        assert_eq!((placed, leftover.map(|message| message.0[0])), (2, Some(200)));
+        let items: [&[u8]; 2] = [b"d", &[255]];
+        assert!(matches!(port.enqueue_from_slices(items.into_iter()), (1, Some([255]))));
+
+        port.claim_write_slice().unwrap()[0] = 200;
+        assert!(matches!(port.commit_write(), Err(QueueError::ValidationFailed(_))));
+
+        let mut src = QueueingPort::new();
+        src.enqueue(Message([b'e'; SIZE])).unwrap();
+        let patched = src.forward_with(&mut port, |bytes| {
+            bytes[0] = 200;
+            SIZE
+        });
+        assert!(matches!(patched, Err(QueueError::ValidationFailed(_))));
+        assert_eq!(src.len(), 1, "left unforwarded");
+        src.forward_with(&mut port, |_| SIZE).unwrap();
+
+        let firsts: Vec<u8> = core::iter::from_fn(|| port.dequeue()).map(|message| message.0[0]).collect();
+        assert_eq!(firsts, b"abde");
+    }
+}
@@ -0,0 +1,103 @@
+//! Scatter/gather variants of enqueue and dequeue.
+
+use crate::{QueueError, QueueingPort, SIZE};
+
+impl QueueingPort {
+    /// Dequeues the next message, copying it across `bufs` in order and
+    /// returning the number of bytes written, always `SIZE`.
+    ///
+    /// If the buffers hold fewer than `SIZE` bytes in total, nothing is
+    /// dequeued and `QueueError::BufferTooSmall` is returned.
+    pub fn dequeue_vectored(&mut self, bufs: &mut [&mut [u8]]) -> Result<usize, QueueError> {
+        let capacity: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if capacity < SIZE {
+            return Err(QueueError::BufferTooSmall { needed: SIZE });
+        }
+        self.dequeue_with(|slot| {
+            let mut rest = &slot[..];
+            for buf in bufs.iter_mut() {
+                let len = buf.len().min(rest.len());
+                buf[..len].copy_from_slice(&rest[..len]);
+                rest = &rest[len..];
+            }
+            SIZE
+        })
+    }
+
+    /// Enqueues one message made of `bufs` laid end to end, zero-filled up to
+    /// `SIZE`. Fails with `QueueError::MessageTooLarge` if they add up to
+    /// more than a slot.
+    pub fn enqueue_vectored(&mut self, bufs: &[&[u8]]) -> Result<(), QueueError> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if len > SIZE {
+            return Err(QueueError::MessageTooLarge { len });
+        }
+        self.enqueue_with(|slot| {
+            let mut offset = 0;
+            for buf in bufs {
+                slot[offset..offset + buf.len()].copy_from_slice(buf);
+                offset += buf.len();
+            }
+            slot[offset..].fill(0);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    fn numbered() -> [u8; SIZE] {
+        core::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn scatter_into_three_buffers() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message(numbered())).unwrap();
+
+        let (mut header, mut empty, mut payload) = ([0u8; 8], [0u8; 0], [0u8; SIZE - 8]);
+        let written = port
+            .dequeue_vectored(&mut [&mut header, &mut empty, &mut payload])
+            .unwrap();
+        assert_eq!(written, SIZE);
+        assert_eq!(header, numbered()[..8]);
+        assert_eq!(payload, numbered()[8..]);
+        assert!(port.is_empty());
+    }
+
+    #[test]
+    fn short_buffers_leave_message_queued() {
+        let mut port = QueueingPort::new();
+        port.enqueue(Message(numbered())).unwrap();
+
+        let (mut a, mut b) = ([0u8; 100], [0u8; 100]);
+        assert!(matches!(
+            port.dequeue_vectored(&mut [&mut a, &mut b]),
+            Err(QueueError::BufferTooSmall { needed: SIZE })
+        ));
+        assert_eq!(port.len(), 1);
+        assert_eq!(port.dequeue().unwrap().0, numbered());
+    }
+
+    #[test]
+    fn gather_matches_single_slice() {
+        let bytes = numbered();
+        let mut port = QueueingPort::new();
+        port.enqueue_vectored(&[&bytes[..6], &[], &bytes[6..]]).unwrap();
+        port.enqueue_vectored(&[&bytes]).unwrap();
+        port.enqueue_vectored(&[&bytes[..4]]).unwrap();
+
+        assert_eq!(port.dequeue().unwrap().0, bytes);
+        assert_eq!(port.dequeue().unwrap().0, bytes);
+        let short = port.dequeue().unwrap().0;
+        assert_eq!(short[..4], bytes[..4]);
+        assert!(short[4..].iter().all(|&byte| byte == 0));
+
+        assert!(matches!(
+            port.enqueue_vectored(&[&bytes, &[1]]),
+            Err(QueueError::MessageTooLarge { len }) if len == SIZE + 1
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+//! A picture of the ring, for a terminal.
+//!
+//! `visualize_unicode` draws the slots as a row of block characters, `█`
+//! for a slot holding a message and `░` for a free one, between `▕` and
+//! `▏`, with the read index marked `▼` and the write index `▲` on the line
+//! below; `◆` marks both at once, on an empty or a full queue:
+//!
+//! ```text
+//! ▕░░░████░░░▏
+//!     ▼   ▲
+//! ```
+//!
+//! Both lines are `MSGS + 2` characters wide. With `enable_ansi_color` the
+//! full slots are green, the write index red and the read index blue, in
+//! ANSI escape sequences that add to the bytes but not to what is shown.
+//!
+//! The picture is taken from the header without a lock and may be torn if
+//! the peer is active meanwhile.
+
+use alloc::string::String;
+use core::sync::atomic::Ordering;
+
+use crate::{slot_index, QueueingPort, MSGS};
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const BLUE: &str = "\x1b[34m";
+const RESET: &str = "\x1b[0m";
+
+impl QueueingPort {
+    /// Has `visualize_unicode` colour its output with ANSI escapes, or not.
+    pub fn enable_ansi_color(&mut self, enabled: bool) {
+        self.ansi_color = enabled;
+    }
+
+    /// The slots and indices drawn in block characters, see the
+    /// `visualize` module.
+    pub fn visualize_unicode(&self) -> String {
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let read = slot_index(header.read_index.load(order, Ordering::Acquire) as usize);
+        let write = slot_index(header.write_index.load(order, Ordering::Acquire) as usize);
+        let count = (header.message_count.load(order, Ordering::Acquire) as usize).min(MSGS);
+        let full = |index: usize| (index + MSGS - read) % MSGS < count;
+
+        let mut out = String::new();
+        let push = |out: &mut String, c: char, color: &str| {
+            if self.ansi_color {
+                out.push_str(color);
+                out.push(c);
+                out.push_str(RESET);
+            } else {
+                out.push(c);
+            }
+        };
+        out.push('▕');
+        for index in 0..MSGS {
+            if full(index) {
+                push(&mut out, '█', GREEN);
+            } else {
+                out.push('░');
+            }
+        }
+        out.push_str("▏\n ");
+        for index in 0..MSGS {
+            match (index == read, index == write) {
+                (true, true) => out.push('◆'),
+                (true, false) => push(&mut out, '▼', BLUE),
+                (false, true) => push(&mut out, '▲', RED),
+                (false, false) => out.push(' '),
+            }
+        }
+        out.push(' ');
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+
+    #[test]
+    fn one_char_per_slot_between_the_frame() {
+        let mut port = QueueingPort::new();
+        for _ in 0..3 {
+            port.enqueue(Message([0; SIZE])).unwrap();
+            port.dequeue().unwrap();
+        }
+        let empty = port.visualize_unicode();
+        assert_eq!(empty, "▕░░░░░░░░░░▏\n    ◆       ");
+
+        for _ in 0..4 {
+            port.enqueue(Message([0; SIZE])).unwrap();
+        }
+        let picture = port.visualize_unicode();
+        assert_eq!(picture, "▕░░░████░░░▏\n    ▼   ▲   ");
+        assert!(picture.lines().all(|line| line.chars().count() == MSGS + 2));
+
+        port.enable_ansi_color(true);
+        let colored = port.visualize_unicode();
+        assert!(colored.contains("\x1b[32m█\x1b[0m") && colored.contains("\x1b[34m▼") && colored.contains("\x1b[31m▲"));
+        let shown: String = colored.replace(GREEN, "").replace(RED, "").replace(BLUE, "").replace(RESET, "");
+        assert_eq!(shown, picture);
+    }
+}
@@ -0,0 +1,63 @@
+//! Sleeping on a header word until the peer changes it.
+//!
+//! Both functions operate on the word's address, so they work across
+//! processes as long as the word sits in shared memory.
+
+#[cfg(all(feature = "linux-futex", target_os = "linux"))]
+mod imp {
+    use core::sync::atomic::AtomicU32;
+
+    /// Sleeps while `word` holds `expected`. Wakeups may be spurious.
+    pub(crate) fn wait(word: &AtomicU32, expected: u32) -> Result<(), i32> {
+        // No FUTEX_PRIVATE_FLAG: the futex has to be matched by the kernel
+        // across processes that map the same segment.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                word.as_ptr(),
+                libc::FUTEX_WAIT,
+                expected,
+                core::ptr::null::<libc::timespec>(),
+            )
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+        match unsafe { *libc::__errno_location() } {
+            // The word already changed, or a signal arrived: let the caller re-check.
+            libc::EAGAIN | libc::EINTR => Ok(()),
+            errno => Err(errno),
+        }
+    }
+
+    /// Wakes every waiter sleeping on `word`.
+    pub(crate) fn wake(word: &AtomicU32) {
+        unsafe { libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, i32::MAX) };
+    }
+}
+
+// Everywhere else the waiter backs off instead of sleeping on the word. On
+// Windows this is deliberate: WaitOnAddress only pairs waiters and wakers
+// inside one process, so it cannot replace the futex for a peer process.
+#[cfg(not(all(feature = "linux-futex", target_os = "linux")))]
+mod imp {
+    use core::sync::atomic::AtomicU32;
+
+    pub(crate) fn wait(_word: &AtomicU32, _expected: u32) -> Result<(), i32> {
+        super::backoff();
+        Ok(())
+    }
+
+    pub(crate) fn wake(_word: &AtomicU32) {}
+}
+
+pub(crate) use imp::{wait, wake};
+
+/// Gives up the CPU for a moment while polling for a condition nobody wakes
+/// us for, e.g. free space in a full queue.
+pub(crate) fn backoff() {
+    #[cfg(feature = "std")]
+    std::thread::yield_now();
+    #[cfg(not(feature = "std"))]
+    core::hint::spin_loop();
+}
@@ -0,0 +1,177 @@
+//! Telling a consumer that stopped from one with nothing to do.
+//!
+//! On a port created with `WireFeatures::PROGRESS`, the receiver stores
+//! the time of every dequeue in the header's `last_dequeue`, and the
+//! sender stores in `backlog_since` the time it enqueued into an empty
+//! queue, when the consumer last got something to do. The consumer's idle
+//! time is how long ago the later of the two was; it only counts while
+//! messages are queued, as a consumer waiting on an empty queue is not
+//! stalled however long it waits. `consumer_stalled` compares it with a
+//! threshold, from any handle, an `Observer` included.
+//!
+//! The first `consumer_stalled` call that finds the consumer stalled
+//! reports `QueueEvent::ConsumerStalled` to the handle's `EventSink`; the
+//! next report waits until a call has found the idle time back at half
+//! the threshold or less, or the queue empty, so a consumer hovering
+//! around the threshold is reported once. Times come from the clocks of
+//! the three handles, with the caveat of the `timing` module. Ports
+//! without the feature never read a clock for this, and never report a
+//! stall.
+
+use core::sync::atomic::Ordering;
+
+use crate::byteorder::{ByteOrder, WireU32};
+use crate::{QueueEvent, QueueingPort, WireFeatures};
+
+/// A 64-bit time in two header words, written by one handle.
+#[repr(C)]
+pub(crate) struct TimeField {
+    low: WireU32,
+    high: WireU32,
+}
+
+impl TimeField {
+    pub(crate) const fn new() -> TimeField {
+        TimeField { low: WireU32::zero(), high: WireU32::zero() }
+    }
+
+    pub(crate) fn store(&self, order: ByteOrder, time: u64) {
+        self.low.store(order, time as u32, Ordering::Relaxed);
+        self.high.store(order, (time >> 32) as u32, Ordering::Release);
+    }
+
+    fn load(&self, order: ByteOrder) -> u64 {
+        // Read again if the low word wrapped into the high one meanwhile.
+        loop {
+            let high = self.high.load(order, Ordering::Acquire);
+            let low = self.low.load(order, Ordering::Relaxed);
+            if self.high.load(order, Ordering::Relaxed) == high {
+                return u64::from(high) << 32 | u64::from(low);
+            }
+        }
+    }
+}
+
+impl QueueingPort {
+    /// How long the consumer has had messages to take and taken none, in
+    /// this handle's clock nanoseconds; `None` with an empty queue, and on
+    /// ports without `WireFeatures::PROGRESS`.
+    pub fn consumer_idle(&self) -> Option<u64> {
+        if !self.wire_features().contains(WireFeatures::PROGRESS) || self.is_empty() {
+            return None;
+        }
+        let header = &self.segment().header;
+        let order = header.byte_order();
+        let busy_since = header.last_dequeue.load(order).max(header.backlog_since.load(order));
+        Some(self.clock().now_ns().saturating_sub(busy_since))
+    }
+
+    /// Whether the consumer has been idle, as `consumer_idle`, for more
+    /// than `max_idle_ticks`; see the module documentation for the event
+    /// this reports.
+    pub fn consumer_stalled(&self, max_idle_ticks: u64) -> bool {
+        let idle = self.consumer_idle();
+        let stalled = idle.is_some_and(|idle| idle > max_idle_ticks);
+        if stalled && !self.stall_reported.get() {
+            self.stall_reported.set(true);
+            if let Some(sink) = self.event_sink {
+                sink(QueueEvent::ConsumerStalled { idle: idle.unwrap_or_default() });
+            }
+        } else if idle.is_none_or(|idle| idle <= max_idle_ticks / 2) {
+            self.stall_reported.set(false);
+        }
+        stalled
+    }
+
+    /// Records a dequeue, on a port with `WireFeatures::PROGRESS`.
+    pub(crate) fn note_dequeue(&self) {
+        if self.wire_features().contains(WireFeatures::PROGRESS) {
+            let header = &self.segment().header;
+            header.last_dequeue.store(header.byte_order(), self.clock().now_ns());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, MockClock, Observer, PortConfig, Segment, SIZE};
+    use core::ptr::NonNull;
+    use std::sync::{Arc, Mutex};
+
+    static EVENTS: Mutex<Vec<QueueEvent>> = Mutex::new(Vec::new());
+
+    fn record(event: QueueEvent) {
+        EVENTS.lock().unwrap().push(event);
+    }
+
+    /// A sender, a receiver and a supervising handle on one clock.
+    fn watched_port(clock: &Arc<MockClock>) -> (QueueingPort, QueueingPort, QueueingPort) {
+        let config = PortConfig::new().wire_features(WireFeatures::PROGRESS).clock(clock.clone());
+        let segment = NonNull::from(Box::leak(Box::new(Segment::new())));
+        let handles = unsafe { [(); 3].map(|()| QueueingPort::attach(segment)) };
+        let [mut sender, mut receiver, mut supervisor] = handles;
+        config.apply(&mut sender);
+        receiver.set_clock(clock.clone());
+        supervisor.set_clock(clock.clone());
+        (sender, receiver, supervisor)
+    }
+
+    #[test]
+    fn idle_on_an_empty_queue_is_not_stalled() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let (mut sender, mut receiver, supervisor) = watched_port(&clock);
+        clock.advance(1_000_000);
+        assert_eq!(supervisor.consumer_idle(), None);
+        assert!(!supervisor.consumer_stalled(100));
+
+        // Work that arrives after a long quiet spell starts the clock anew.
+        sender.enqueue(Message([1; SIZE])).unwrap();
+        clock.advance(50);
+        assert_eq!(supervisor.consumer_idle(), Some(50));
+        assert!(!supervisor.consumer_stalled(100));
+        receiver.dequeue().unwrap();
+        clock.advance(1_000_000);
+        assert!(!supervisor.consumer_stalled(100));
+
+        let plain = QueueingPort::new();
+        assert_eq!((plain.consumer_idle(), plain.consumer_stalled(0)), (None, false));
+    }
+
+    #[test]
+    fn a_backlog_left_alone_is_reported_once_per_stall() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let (mut sender, mut receiver, mut supervisor) = watched_port(&clock);
+        supervisor.set_event_sink(record);
+        let mut observer = unsafe { Observer::attach(NonNull::from(sender.segment())) };
+        observer.set_clock(clock.clone());
+        for tag in 0..4 {
+            sender.enqueue(Message([tag; SIZE])).unwrap();
+        }
+
+        clock.advance(100);
+        assert!(!supervisor.consumer_stalled(100), "not past the threshold yet");
+        clock.advance(1);
+        assert!(supervisor.consumer_stalled(100));
+        assert!(observer.consumer_stalled(100));
+        clock.advance(1_000);
+        assert!(supervisor.consumer_stalled(100));
+
+        // Progress, but not enough to count as recovered.
+        receiver.dequeue().unwrap();
+        clock.advance(80);
+        assert!(!supervisor.consumer_stalled(100));
+        clock.advance(40);
+        assert!(supervisor.consumer_stalled(100), "the same episode");
+        assert_eq!(EVENTS.lock().unwrap().len(), 1);
+
+        // Recovered, then stalled again.
+        receiver.dequeue().unwrap();
+        clock.advance(10);
+        assert!(!supervisor.consumer_stalled(100));
+        clock.advance(500);
+        assert!(supervisor.consumer_stalled(100));
+        let events: Vec<_> = EVENTS.lock().unwrap().drain(..).collect();
+        assert_eq!(events, [QueueEvent::ConsumerStalled { idle: 101 }, QueueEvent::ConsumerStalled { idle: 510 }]);
+    }
+}
@@ -0,0 +1,141 @@
+//! Callbacks when the queue fills past a high watermark and drains back to
+//! a low one, for flow control without polling `len`.
+//!
+//! After an enqueue through this handle leaves `len()` at or above the
+//! high watermark, `on_high_watermark` is called, once; it is not called
+//! again until a dequeue through this handle has left `len()` at or below
+//! the low watermark, calling `on_low_watermark`. A producer can pause in
+//! the one and resume in the other. Both are checked after every enqueue
+//! and dequeue entry point, whatever moved the count, and are per handle:
+//! each end sees only its own operations.
+
+use crate::{QueueingPort, MSGS};
+
+pub(crate) struct Watermarks {
+    high: usize,
+    low: usize,
+    on_high: Option<fn(&QueueingPort)>,
+    on_low: Option<fn(&QueueingPort)>,
+    /// `on_high` ran and `on_low` has not since.
+    above: bool,
+}
+
+impl Watermarks {
+    pub(crate) const fn new() -> Watermarks {
+        Watermarks { high: MSGS, low: 0, on_high: None, on_low: None, above: false }
+    }
+}
+
+impl QueueingPort {
+    /// Sets the length at which `on_high_watermark` is called; `capacity()`
+    /// by default. Panics unless the low watermark is below it and it is at
+    /// most `capacity()`.
+    pub fn set_high_watermark(&mut self, n: usize) {
+        assert!(self.watermarks.low < n && n <= self.capacity(), "watermarks need low < high <= capacity");
+        self.watermarks.high = n;
+    }
+
+    /// Sets the length at which `on_low_watermark` is called; 0 by default.
+    /// Panics unless it is below the high watermark.
+    pub fn set_low_watermark(&mut self, n: usize) {
+        assert!(n < self.watermarks.high, "watermarks need low < high <= capacity");
+        self.watermarks.low = n;
+    }
+
+    /// Calls `callback` when an enqueue fills the queue to the high
+    /// watermark, see the `watermark` module.
+    pub fn on_high_watermark(&mut self, callback: fn(&QueueingPort)) {
+        self.watermarks.on_high = Some(callback);
+    }
+
+    /// Calls `callback` when a dequeue drains the queue to the low
+    /// watermark after the high one was reached.
+    pub fn on_low_watermark(&mut self, callback: fn(&QueueingPort)) {
+        self.watermarks.on_low = Some(callback);
+    }
+
+    pub(crate) fn check_high_watermark(&mut self) {
+        let watermarks = &self.watermarks;
+        if watermarks.above || self.len() < watermarks.high {
+            return;
+        }
+        self.watermarks.above = true;
+        if let Some(callback) = self.watermarks.on_high {
+            callback(self);
+        }
+    }
+
+    pub(crate) fn check_low_watermark(&mut self) {
+        let watermarks = &self.watermarks;
+        if !watermarks.above || self.len() > watermarks.low {
+            return;
+        }
+        self.watermarks.above = false;
+        if let Some(callback) = self.watermarks.on_low {
+            callback(self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, SIZE};
+    use std::cell::RefCell;
+    use std::thread::LocalKey;
+
+    std::thread_local! {
+        static HIGH: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+        static LOW: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// The lengths the callback behind `calls` saw.
+    fn calls(calls: &'static LocalKey<RefCell<Vec<usize>>>) -> Vec<usize> {
+        calls.with_borrow(Vec::clone)
+    }
+
+    #[test]
+    fn high_then_low_fire_once_each() {
+        let mut port = QueueingPort::new();
+        assert_eq!(port.capacity(), 10);
+        port.set_high_watermark(8);
+        port.set_low_watermark(3);
+        port.on_high_watermark(|port| HIGH.with_borrow_mut(|seen| seen.push(port.len())));
+        port.on_low_watermark(|port| LOW.with_borrow_mut(|seen| seen.push(port.len())));
+
+        for n in 0..8 {
+            port.enqueue(Message([n; SIZE])).unwrap();
+        }
+        assert_eq!(calls(&HIGH), [8]);
+        // Still above: no second call.
+        port.enqueue(Message([8; SIZE])).unwrap();
+        assert_eq!(calls(&HIGH), [8]);
+
+        for _ in 0..6 {
+            port.dequeue().unwrap();
+        }
+        assert_eq!((calls(&HIGH), calls(&LOW)), (vec![8], vec![3]));
+        port.dequeue().unwrap();
+        assert_eq!(calls(&LOW), [3]);
+
+        // Armed again once drained.
+        while port.len() < 8 {
+            port.enqueue_bytes(&[1]).unwrap();
+        }
+        assert_eq!(calls(&HIGH), [8, 8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "low < high <= capacity")]
+    fn watermarks_must_be_ordered() {
+        let mut port = QueueingPort::new();
+        port.set_high_watermark(4);
+        port.set_low_watermark(4);
+    }
+
+    #[test]
+    #[should_panic(expected = "low < high <= capacity")]
+    fn high_watermark_at_most_capacity() {
+        QueueingPort::new().set_high_watermark(MSGS + 1);
+    }
+}
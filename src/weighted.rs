@@ -0,0 +1,149 @@
+//! Fan-in from several ports, served in proportion to their weights.
+//!
+//! `WeightedMergePort` dequeues from the ports added to it by Deficit
+//! Round Robin. Each port in turn has its weight added to its deficit
+//! counter and is then dequeued from until the counter is spent, one per
+//! message, or the port is empty, which forfeits what is left: an idle port
+//! saves up no credit to flood the others with later. Over a cycle in
+//! which every port has messages, a port of weight 3 delivers three times
+//! as many as one of weight 1; a port of weight 0 is never dequeued from.
+//!
+//! Each port keeps its own order. A port failing with something other
+//! than `QueueError::EmptyBuffer`, `Closed` for instance, has the error
+//! returned, and its turn ends.
+
+use alloc::vec::Vec;
+
+use crate::{DequeuePort, Message, QueueError, QueueingPort};
+
+struct Source<'a> {
+    port: &'a mut QueueingPort,
+    weight: u8,
+    deficit: usize,
+}
+
+/// Ports dequeued from by weight, see the `weighted` module.
+pub struct WeightedMergePort<'a> {
+    sources: Vec<Source<'a>>,
+    /// The source whose turn it is.
+    current: usize,
+    last_source: Option<usize>,
+}
+
+impl<'a> WeightedMergePort<'a> {
+    pub fn new() -> WeightedMergePort<'a> {
+        WeightedMergePort { sources: Vec::new(), current: 0, last_source: None }
+    }
+
+    /// Adds `port`, served `weight` messages a cycle.
+    pub fn add(&mut self, port: &'a mut QueueingPort, weight: u8) -> &mut Self {
+        self.sources.push(Source { port, weight, deficit: 0 });
+        self
+    }
+
+    /// The next message by Deficit Round Robin. Fails with
+    /// `QueueError::EmptyBuffer` if no port with a weight has one.
+    pub fn dequeue(&mut self) -> Result<Message, QueueError> {
+        let mut idle = 0;
+        while idle < self.sources.len() {
+            let index = self.current;
+            let source = &mut self.sources[index];
+            if source.deficit == 0 {
+                source.deficit = usize::from(source.weight);
+            }
+            let result = if source.deficit > 0 { source.port.try_dequeue() } else { Err(QueueError::EmptyBuffer) };
+            match result {
+                Ok(message) => {
+                    source.deficit -= 1;
+                    if source.deficit == 0 {
+                        self.advance();
+                    }
+                    self.last_source = Some(index);
+                    return Ok(message);
+                }
+                Err(error) => {
+                    source.deficit = 0;
+                    self.advance();
+                    if !matches!(error, QueueError::EmptyBuffer) {
+                        return Err(error);
+                    }
+                    idle += 1;
+                }
+            }
+        }
+        Err(QueueError::EmptyBuffer)
+    }
+
+    fn advance(&mut self) {
+        self.current = (self.current + 1) % self.sources.len();
+    }
+
+    /// The index, in the order added, of the port the last message came
+    /// from.
+    pub fn last_source(&self) -> Option<usize> {
+        self.last_source
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.iter().map(|source| source.port.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for WeightedMergePort<'_> {
+    fn default() -> Self {
+        WeightedMergePort::new()
+    }
+}
+
+impl DequeuePort for WeightedMergePort<'_> {
+    fn dequeue(&mut self) -> Result<Message, QueueError> {
+        WeightedMergePort::dequeue(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MSGS, SIZE};
+
+    fn refill(port: &mut QueueingPort, tag: u8) {
+        while port.enqueue(Message([tag; SIZE])).is_ok() {}
+    }
+
+    #[test]
+    fn weight_3_delivers_three_times_weight_1() {
+        let (mut heavy, mut light) = (QueueingPort::new(), QueueingPort::new());
+        let mut delivered = [0u32; 2];
+        for _ in 0..100 {
+            refill(&mut heavy, 0);
+            refill(&mut light, 1);
+            let mut merge = WeightedMergePort::new();
+            merge.add(&mut heavy, 3).add(&mut light, 1);
+            // Whole cycles of 4, with both ports still holding messages.
+            for _ in 0..8 {
+                let message = merge.dequeue().unwrap();
+                assert_eq!(usize::from(message.0[0]), merge.last_source().unwrap());
+                delivered[merge.last_source().unwrap()] += 1;
+            }
+        }
+        assert_eq!(delivered, [600, 200]);
+    }
+
+    #[test]
+    fn an_empty_port_forfeits_its_turn() {
+        let (mut a, mut b, mut unweighted) = (QueueingPort::new(), QueueingPort::new(), QueueingPort::new());
+        refill(&mut unweighted, 9);
+        b.enqueue(Message([1; SIZE])).unwrap();
+        let mut merge = WeightedMergePort::new();
+        merge.add(&mut a, 5).add(&mut b, 1).add(&mut unweighted, 0);
+        assert_eq!(merge.dequeue().unwrap().0, [1; SIZE]);
+        assert_eq!(merge.last_source(), Some(1));
+        assert!(matches!(merge.dequeue(), Err(QueueError::EmptyBuffer)));
+        assert_eq!(merge.len(), MSGS, "only the weight 0 port holds any");
+        assert!(matches!(WeightedMergePort::new().dequeue(), Err(QueueError::EmptyBuffer)));
+    }
+}
@@ -0,0 +1,52 @@
+/*
+ * Exercises queueing_port.h from C. build.rs compiles this file when the
+ * `ffi` feature is on, and the Rust test `ffi::tests::c_selftest_passes`
+ * calls qp_c_selftest.
+ */
+#include <string.h>
+
+#include "queueing_port.h"
+
+#define CHECK(cond) \
+    do { \
+        if (!(cond)) \
+            return __LINE__; \
+    } while (0)
+
+/* Returns 0, or the line of the first failed check. Uses ids `first_id`
+ * and `first_id + 1`. */
+int32_t qp_c_selftest(uint32_t first_id)
+{
+    const uint8_t hello[] = "hello from C";
+    uint8_t out[64];
+    size_t actual = 0;
+    uint32_t id = first_id, small = first_id + 1;
+
+    CHECK(qp_create(id, 32, 4) == QP_OK);
+    CHECK(qp_create(id, 32, 4) == QP_EEXIST);
+    CHECK(qp_create(QP_MAX_PORTS, 32, 4) == QP_EINVAL);
+    CHECK(qp_recv(id, out, sizeof out, &actual) == QP_EEMPTY);
+
+    CHECK(qp_send(id, hello, sizeof hello) == QP_OK);
+    memset(out, 0xff, sizeof out);
+    CHECK(qp_recv(id, out, sizeof out, &actual) == QP_OK);
+    CHECK(actual == 32);
+    CHECK(memcmp(out, hello, sizeof hello) == 0);
+    CHECK(out[sizeof hello] == 0);
+    CHECK(out[32] == 0xff);
+
+    CHECK(qp_create(small, 4, 2) == QP_OK);
+    CHECK(qp_send(small, hello, 5) == QP_ESIZE);
+    CHECK(qp_send(small, hello, 4) == QP_OK);
+    CHECK(qp_send(small, hello + 4, 4) == QP_OK);
+    CHECK(qp_send(small, hello, 4) == QP_EFULL);
+    CHECK(qp_recv(small, out, 3, &actual) == QP_ESIZE);
+    CHECK(qp_recv(small, out, sizeof out, &actual) == QP_OK);
+    CHECK(actual == 4 && memcmp(out, "hell", 4) == 0);
+    CHECK(qp_recv(small, out, sizeof out, &actual) == QP_OK);
+    CHECK(memcmp(out, "o fr", 4) == 0);
+
+    CHECK(qp_send(QP_MAX_PORTS - 1, hello, 1) == QP_ENOENT);
+    CHECK(qp_recv(id, NULL, sizeof out, &actual) == QP_EINVAL);
+    return 0;
+}
@@ -0,0 +1,33 @@
+//! The `latency_bench` binary, run as a user would run it.
+#![cfg(feature = "shmem")]
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+fn latency_bench(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_latency_bench")).args(args).output().unwrap()
+}
+
+#[test]
+fn runs_and_prints_the_table_in_under_30_seconds() {
+    let started = Instant::now();
+    let output = latency_bench(&["--iterations", "2000", "--queue-depth", "4", "--msg-size", "64"]);
+    assert!(started.elapsed() < Duration::from_secs(30));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("2000 messages of 64 bytes, up to 4 in flight"));
+    for column in ["min", "max", "mean", "p50", "p95", "p99"] {
+        assert!(stdout.contains(column), "{}", stdout);
+    }
+    assert!(stdout.lines().any(|line| line.starts_with("one way")));
+    assert!(stdout.lines().any(|line| line.starts_with("round trip")));
+}
+
+#[test]
+fn bad_arguments_are_refused() {
+    for args in [&["--queue-depth", "0"][..], &["--msg-size", "4"], &["--iterations"], &["--fast"]] {
+        let output = latency_bench(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("usage:"));
+    }
+}
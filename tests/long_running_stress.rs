@@ -0,0 +1,221 @@
+//! A minute of four writers and two readers on one port, and of a writer
+//! and a reader on another, for the wrap-around and interleaving bugs that
+//! short tests run out of time to hit.
+//!
+//! The first port is shared behind a `Mutex`, as with any queue several
+//! threads use, and set to `OverflowPolicy::DropOldest`, so the writers'
+//! enqueues drop the oldest message to make room when it is full. The
+//! second drops nothing, and its writer and reader each attach their own
+//! handle to the segment, so the two ends really run at the same time.
+//! Meanwhile another thread allocates, touches and frees large buffers
+//! without pause, keeping the allocator and the page cache busy.
+//!
+//! The test is ignored for its length; nightly, or by hand, run it in
+//! release with a bound:
+//!
+//! ```text
+//! timeout 120 cargo test --release --test long_running_stress -- --ignored
+//! ```
+//!
+//! `STRESS_SECS=<n>` runs it for `n` seconds instead of 60.
+#![cfg(feature = "std")]
+
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ring_buffer::{Message, OverflowPolicy, QueueError, QueueingPort, Segment, MSGS, SIZE};
+
+const WRITERS: usize = 4;
+const READERS: usize = 2;
+/// Bytes the pressure thread allocates at a time.
+const BALLAST: usize = 16 << 20;
+
+/// A thread's part, given the port, the totals and the stop flag.
+type Role = Box<dyn FnOnce(&Mutex<QueueingPort>, &Totals, &AtomicBool) + Send>;
+
+#[derive(Default)]
+struct Totals {
+    enqueued: AtomicU64,
+    dequeued: AtomicU64,
+    /// The same for the port without a lock.
+    handed_over: AtomicU64,
+    taken_over: AtomicU64,
+}
+
+fn lock(port: &Mutex<QueueingPort>) -> MutexGuard<'_, QueueingPort> {
+    port.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Writer id in byte 0, its sequence number in bytes 8..16, and the
+/// sequence number's low byte in the rest, checked by the reader.
+fn message(writer: usize, sequence: u64) -> Message {
+    let mut message = Message([sequence as u8; SIZE]);
+    message.0[0] = writer as u8;
+    message.0[8..16].copy_from_slice(&sequence.to_le_bytes());
+    message
+}
+
+/// Enqueues as fast as it can, the port dropping the oldest message when
+/// full.
+fn write(writer: usize, port: &Mutex<QueueingPort>, totals: &Totals, stop: &AtomicBool) {
+    let mut sequence = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let mut port = lock(port);
+        let full = port.len() >= MSGS;
+        port.enqueue(message(writer, sequence)).unwrap();
+        drop(port);
+        totals.enqueued.fetch_add(1, Ordering::Relaxed);
+        sequence += 1;
+        if full {
+            // The readers are behind; the lock would go straight back to a
+            // writer otherwise.
+            thread::yield_now();
+        }
+    }
+}
+
+/// The writer and sequence number of `message`, checking it is whole.
+fn check(message: &Message) -> (usize, u64) {
+    let writer = message.0[0] as usize;
+    let sequence = u64::from_le_bytes(message.0[8..16].try_into().unwrap());
+    assert!(writer < WRITERS, "no writer {}", writer);
+    assert!(message.0[16..].iter().all(|&byte| byte == sequence as u8), "torn message {} of {}", sequence, writer);
+    (writer, sequence)
+}
+
+/// Dequeues until stopped, checking every message is whole and comes after
+/// the one before of the same writer.
+fn read(port: &Mutex<QueueingPort>, totals: &Totals, stop: &AtomicBool) {
+    let mut last = [None; WRITERS];
+    while !stop.load(Ordering::Relaxed) {
+        let received = lock(port).try_dequeue();
+        let message = match received {
+            Ok(message) => message,
+            Err(QueueError::EmptyBuffer) => {
+                thread::yield_now();
+                continue;
+            }
+            Err(error) => panic!("dequeue failed: {:?}", error),
+        };
+        let (writer, sequence) = check(&message);
+        if let Some(before) = last[writer] {
+            assert!(sequence > before, "writer {}: {} after {}", writer, sequence, before);
+        }
+        last[writer] = Some(sequence);
+        totals.dequeued.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Enqueues through its own handle as fast as the reader makes room.
+fn hand_over(mut port: QueueingPort, totals: &Totals, stop: &AtomicBool) {
+    let mut sequence = 0;
+    while !stop.load(Ordering::Relaxed) {
+        match port.enqueue(message(0, sequence)) {
+            Ok(()) => {
+                totals.handed_over.fetch_add(1, Ordering::Relaxed);
+                sequence += 1;
+            }
+            Err(QueueError::FullBuffer) => thread::yield_now(),
+            Err(error) => panic!("enqueue failed: {:?}", error),
+        }
+    }
+}
+
+/// Dequeues through its own handle until stopped, checking every message
+/// is whole and the one after the last, as nothing is dropped.
+fn take_over(mut port: QueueingPort, totals: &Totals, stop: &AtomicBool) {
+    let mut expected = 0;
+    while !stop.load(Ordering::Relaxed) {
+        match port.try_dequeue() {
+            Ok(message) => {
+                assert_eq!(check(&message), (0, expected), "lost or out of order");
+                totals.taken_over.fetch_add(1, Ordering::Relaxed);
+                expected += 1;
+            }
+            Err(QueueError::EmptyBuffer) => thread::yield_now(),
+            Err(error) => panic!("dequeue failed: {:?}", error),
+        }
+    }
+}
+
+/// Allocates, touches and frees `BALLAST` bytes over and over.
+fn press_memory(stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        let mut ballast = vec![0u8; BALLAST];
+        for page in ballast.chunks_mut(4096) {
+            page[0] = 1;
+        }
+        std::hint::black_box(&ballast);
+    }
+}
+
+#[test]
+#[ignore = "runs for a minute; see the file documentation"]
+fn test_long_running_stress() {
+    let secs = std::env::var("STRESS_SECS").map_or(60, |secs| secs.parse().expect("STRESS_SECS is a number"));
+    let mut port = QueueingPort::new();
+    port.set_overflow_policy(OverflowPolicy::DropOldest);
+    let port = Arc::new(Mutex::new(port));
+    let segment = Box::new(Segment::new());
+    let totals = Arc::new(Totals::default());
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let spawn = |name: String, f: Role| {
+        let (port, totals, stop) = (port.clone(), totals.clone(), stop.clone());
+        thread::Builder::new().name(name).spawn(move || f(&port, &totals, &stop)).unwrap()
+    };
+    let mut threads = Vec::new();
+    for writer in 0..WRITERS {
+        let f = move |port: &_, totals: &_, stop: &_| write(writer, port, totals, stop);
+        threads.push(spawn(format!("writer {}", writer), Box::new(f)));
+    }
+    for reader in 0..READERS {
+        threads.push(spawn(format!("reader {}", reader), Box::new(read)));
+    }
+    // SAFETY: the segment outlives the threads, which are joined before the
+    // end of the test, and it has one handle enqueueing and one dequeueing.
+    let (sender, receiver) = unsafe {
+        let base = NonNull::from(&*segment);
+        (QueueingPort::attach(base), QueueingPort::attach(base))
+    };
+    threads.push(spawn("handing over".into(), Box::new(move |_, totals, stop| hand_over(sender, totals, stop))));
+    threads.push(spawn("taking over".into(), Box::new(move |_, totals, stop| take_over(receiver, totals, stop))));
+    threads.push(spawn("pressure".into(), Box::new(|_, _, stop| press_memory(stop))));
+
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    while Instant::now() < deadline && threads.iter().all(|thread| !thread.is_finished()) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    stop.store(true, Ordering::Relaxed);
+    let panicked: Vec<String> = threads
+        .into_iter()
+        .filter_map(|thread| {
+            let name = thread.thread().name().unwrap().to_string();
+            thread.join().is_err().then_some(name)
+        })
+        .collect();
+    assert!(panicked.is_empty(), "panicked: {:?}", panicked);
+
+    let port = lock(&port);
+    let (enqueued, dequeued) = (totals.enqueued.load(Ordering::Relaxed), totals.dequeued.load(Ordering::Relaxed));
+    let dropped = u64::from(port.stats().dropped_oldest);
+    assert!(dequeued >= (MSGS * 100) as u64, "only {} dequeued in {}s", dequeued, secs);
+    assert_eq!(port.stats().rejected, 0);
+    assert_eq!(enqueued, dropped + dequeued + port.len() as u64, "messages lost");
+    assert_eq!(port.check_invariants(), Ok(()));
+    #[cfg(debug_assertions)]
+    port.assert_invariants();
+
+    // SAFETY: the other handles are gone, and this one only looks.
+    let port = unsafe { QueueingPort::attach(NonNull::from(&*segment)) };
+    let handed_over = totals.handed_over.load(Ordering::Relaxed);
+    let taken_over = totals.taken_over.load(Ordering::Relaxed);
+    assert!(taken_over >= (MSGS * 100) as u64, "only {} taken over in {}s", taken_over, secs);
+    assert_eq!(handed_over, taken_over + port.len() as u64, "messages lost");
+    assert_eq!(port.check_invariants(), Ok(()));
+    #[cfg(debug_assertions)]
+    port.assert_invariants();
+}
@@ -0,0 +1,471 @@
+//! Random operation sequences run against a port and a `VecDeque` model.
+//!
+//! Every step must return the same payloads and errors from both, and
+//! leave the same length, counters and credits behind. A failing sequence
+//! is shrunk to the shortest one that still fails before it is reported,
+//! along with the seed that generated it; `MODEL_SEED=<seed>` runs only
+//! that case again.
+#![cfg(feature = "std")]
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+
+use ring_buffer::{
+    DedupKey, DeliveryOrder, Message, MockClock, PortConfig, QueueError, QueueStats, QueueingPort,
+    DEDUP_WINDOW_CAPACITY, MSGS, SIZE, TYPE_FILTER_CAPACITY,
+};
+
+const CASES: u64 = 300;
+const MAX_OPS: usize = 80;
+
+/// SplitMix64; a fixed seed gives the same case on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Port settings fixed at creation.
+#[derive(Debug, Clone, Copy)]
+struct Setup {
+    credit_limit: u32,
+    order: DeliveryOrder,
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    /// `len` bytes of id, type and `fill`, through `enqueue_vectored`.
+    Enqueue { len: usize, id: u32, msg_type: u16, fill: u8 },
+    /// A full message through `enqueue_by`, skipped if it would block.
+    EnqueueBy { id: u32, deadline_ns: u64 },
+    Dequeue,
+    /// `dequeue_shared` of the `n`th queued message.
+    Peek(usize),
+    /// `dequeue_all_into`.
+    Drain,
+    Compact,
+    Grant(u32),
+    SetFilter(Vec<u16>),
+    SetDedup(usize, DedupKey),
+    SetBudget(Option<usize>),
+    AdvanceClock(u64),
+}
+
+fn generate(rng: &mut Rng) -> (Setup, Vec<Op>) {
+    let setup = Setup {
+        credit_limit: [0, 0, 1, 3, 12][rng.below(5) as usize],
+        order: if rng.below(2) == 0 { DeliveryOrder::Unordered } else { DeliveryOrder::StrictFifo },
+    };
+    let ops = (0..rng.below(MAX_OPS as u64) + 1)
+        .map(|_| match rng.below(20) {
+            0..=6 => Op::Enqueue {
+                // Mostly short, sometimes exactly full or one byte over.
+                len: match rng.below(6) {
+                    0 => SIZE,
+                    1 => SIZE + 1,
+                    _ => rng.below(16) as usize,
+                },
+                id: rng.below(4) as u32,
+                msg_type: rng.below(3) as u16,
+                fill: rng.below(256) as u8,
+            },
+            7 => Op::EnqueueBy { id: rng.below(4) as u32, deadline_ns: rng.below(40) },
+            8..=12 => Op::Dequeue,
+            13 => Op::Peek(rng.below(MSGS as u64 + 1) as usize),
+            14 => Op::Drain,
+            15 => Op::Compact,
+            16 => Op::Grant(rng.below(5) as u32),
+            17 => match rng.below(4) {
+                0 => Op::SetFilter(Vec::new()),
+                // One more than the filter holds.
+                1 => Op::SetFilter(vec![0; TYPE_FILTER_CAPACITY + 1]),
+                _ => Op::SetFilter((0..rng.below(3)).map(|_| rng.below(3) as u16).collect()),
+            },
+            18 => Op::SetDedup(
+                rng.below(DEDUP_WINDOW_CAPACITY as u64 + 2) as usize / 4,
+                if rng.below(2) == 0 { DedupKey::Envelope } else { DedupKey::PayloadHash },
+            ),
+            _ => match rng.below(3) {
+                0 => Op::SetBudget(None),
+                _ => Op::SetBudget(Some(rng.below(4) as usize)),
+            },
+        })
+        .chain([Op::AdvanceClock(0)])
+        .collect();
+    (setup, ops)
+}
+
+fn bytes_of(len: usize, id: u32, msg_type: u16, fill: u8) -> Vec<u8> {
+    let mut bytes = id.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&msg_type.to_le_bytes());
+    bytes.resize(len.max(6), fill);
+    bytes.truncate(len);
+    bytes
+}
+
+fn padded(bytes: &[u8]) -> [u8; SIZE] {
+    let mut slot = [0; SIZE];
+    slot[..bytes.len()].copy_from_slice(bytes);
+    slot
+}
+
+/// What one step returned, comparable between port and model.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Unit(Result<(), String>),
+    Message(Result<Vec<u8>, String>),
+    Peeked(Option<Vec<u8>>),
+    Drained(Vec<Vec<u8>>),
+    Credits(u32),
+    Skipped,
+}
+
+fn error(error: QueueError) -> String {
+    format!("{:?}", error)
+}
+
+fn message(result: Result<Message, QueueError>) -> Outcome {
+    Outcome::Message(result.map(|message| message.0.to_vec()).map_err(error))
+}
+
+/// The queue as the documentation describes it.
+struct Model {
+    setup: Setup,
+    queue: VecDeque<[u8; SIZE]>,
+    credits: u32,
+    filter: Vec<u16>,
+    dedup_len: usize,
+    dedup_key: DedupKey,
+    seen: VecDeque<u64>,
+    budget: usize,
+    now_ns: u64,
+    stats: QueueStats,
+}
+
+impl Model {
+    fn new(setup: Setup) -> Model {
+        Model {
+            setup,
+            queue: VecDeque::new(),
+            credits: 0,
+            filter: Vec::new(),
+            dedup_len: 0,
+            dedup_key: DedupKey::Envelope,
+            seen: VecDeque::new(),
+            budget: usize::MAX,
+            now_ns: 0,
+            stats: QueueStats::default(),
+        }
+    }
+
+    fn room(&self) -> usize {
+        let free = MSGS - self.queue.len();
+        match self.setup.credit_limit {
+            0 => free,
+            _ => free.min(self.credits as usize),
+        }
+    }
+
+    fn enqueue(&mut self, slot: [u8; SIZE]) -> Result<(), String> {
+        if self.setup.credit_limit != 0 && self.credits == 0 {
+            return Err(error(QueueError::NoCredit));
+        }
+        if self.queue.len() == MSGS {
+            self.stats.rejected += 1;
+            return Err(error(QueueError::FullBuffer));
+        }
+        self.queue.push_back(slot);
+        self.credits = self.credits.saturating_sub(1);
+        self.stats.enqueued += 1;
+        self.stats.high_watermark = self.stats.high_watermark.max(self.queue.len() as u32);
+        Ok(())
+    }
+
+    fn is_new(&mut self, slot: &[u8; SIZE]) -> bool {
+        if self.dedup_len == 0 {
+            return true;
+        }
+        let id = match self.dedup_key {
+            DedupKey::Envelope => u64::from(u32::from_le_bytes(slot[..4].try_into().unwrap())),
+            DedupKey::PayloadHash => slot
+                .iter()
+                .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)),
+        };
+        if self.seen.contains(&id) {
+            self.stats.duplicates_dropped += 1;
+            return false;
+        }
+        if self.seen.len() == self.dedup_len {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(id);
+        true
+    }
+
+    fn dequeue(&mut self) -> Result<[u8; SIZE], String> {
+        let mut examined = 0;
+        loop {
+            if examined >= self.budget {
+                return Err(error(QueueError::BudgetExhausted { examined }));
+            }
+            examined += 1;
+            let slot = self.queue.pop_front().ok_or_else(|| error(QueueError::EmptyBuffer))?;
+            self.stats.dequeued += 1;
+            let msg_type = u16::from_le_bytes([slot[4], slot[5]]);
+            if !self.filter.is_empty() && !self.filter.contains(&msg_type) {
+                self.stats.filtered_out += 1;
+            } else if self.is_new(&slot) {
+                return Ok(slot);
+            }
+        }
+    }
+
+    fn step(&mut self, op: &Op) -> Outcome {
+        match *op {
+            Op::Enqueue { len, id, msg_type, fill } => Outcome::Unit(if len > SIZE {
+                Err(error(QueueError::MessageTooLarge { len }))
+            } else {
+                self.enqueue(padded(&bytes_of(len, id, msg_type, fill)))
+            }),
+            Op::EnqueueBy { id, deadline_ns } => {
+                if self.now_ns >= deadline_ns {
+                    self.stats.deadline_misses += 1;
+                    Outcome::Unit(Err(error(QueueError::DeadlineMissed)))
+                } else if self.room() == 0 {
+                    Outcome::Skipped
+                } else {
+                    Outcome::Unit(self.enqueue(padded(&bytes_of(SIZE, id, 0, 0xee))))
+                }
+            }
+            Op::Dequeue => Outcome::Message(self.dequeue().map(|slot| slot.to_vec())),
+            Op::Peek(n) => Outcome::Peeked(self.queue.get(n).map(|slot| slot.to_vec())),
+            Op::Drain => Outcome::Drained(core::iter::from_fn(|| self.dequeue().ok()).map(|s| s.to_vec()).collect()),
+            Op::Compact => Outcome::Skipped,
+            Op::Grant(n) => Outcome::Credits(match self.setup.credit_limit {
+                0 => 0,
+                limit => {
+                    self.credits = self.credits.saturating_add(n).min(limit);
+                    self.credits
+                }
+            }),
+            Op::SetFilter(ref types) => Outcome::Unit(if types.len() > TYPE_FILTER_CAPACITY {
+                Err(error(QueueError::FilterTooLarge))
+            } else {
+                self.filter = types.clone();
+                Ok(())
+            }),
+            Op::SetDedup(len, key) => Outcome::Unit(if len > DEDUP_WINDOW_CAPACITY {
+                Err(error(QueueError::WindowTooLarge))
+            } else {
+                self.dedup_len = len;
+                self.dedup_key = key;
+                self.seen.clear();
+                Ok(())
+            }),
+            Op::SetBudget(budget) => {
+                self.budget = budget.map_or(usize::MAX, |n| n.max(1));
+                Outcome::Skipped
+            }
+            Op::AdvanceClock(ns) => {
+                self.now_ns += ns;
+                Outcome::Skipped
+            }
+        }
+    }
+
+    fn credits(&self) -> Option<u32> {
+        (self.setup.credit_limit != 0).then_some(self.credits)
+    }
+}
+
+/// The port under test, with the clock it reads.
+struct Real {
+    port: QueueingPort,
+    clock: Arc<MockClock>,
+}
+
+impl Real {
+    fn new(setup: Setup) -> Real {
+        let clock = Arc::new(MockClock::new(0));
+        let config = PortConfig::new()
+            .credit_limit(setup.credit_limit)
+            .delivery_order(setup.order)
+            .clock(clock.clone());
+        Real { port: QueueingPort::with_config(&config), clock }
+    }
+
+    /// `would_block` comes from the model, so a blocked `enqueue_by` is
+    /// never started.
+    fn step(&mut self, op: &Op, would_block: bool) -> Outcome {
+        let port = &mut self.port;
+        match *op {
+            Op::Enqueue { len, id, msg_type, fill } => {
+                let bytes = bytes_of(len, id, msg_type, fill);
+                // Split in two to go through the gather path.
+                let (head, tail) = bytes.split_at(bytes.len() / 2);
+                Outcome::Unit(port.enqueue_vectored(&[head, tail]).map_err(error))
+            }
+            Op::EnqueueBy { .. } if would_block => Outcome::Skipped,
+            Op::EnqueueBy { id, deadline_ns } => {
+                let message = Message(padded(&bytes_of(SIZE, id, 0, 0xee)));
+                Outcome::Unit(port.enqueue_by(message, deadline_ns).map_err(error))
+            }
+            Op::Dequeue => message(port.try_dequeue()),
+            Op::Peek(n) => {
+                let sequence = port.stats().dequeued.wrapping_add(n as u32);
+                Outcome::Peeked(port.dequeue_shared(sequence).map(|message| message.0.to_vec()))
+            }
+            Op::Drain => {
+                let mut out = Vec::new();
+                port.dequeue_all_into(&mut out);
+                Outcome::Drained(out.into_iter().map(|message| message.0.to_vec()).collect())
+            }
+            Op::Compact => {
+                // SAFETY: the port has no other handle.
+                unsafe { port.compact() };
+                Outcome::Skipped
+            }
+            Op::Grant(n) => Outcome::Credits(port.grant_credits(n)),
+            Op::SetFilter(ref types) => Outcome::Unit(port.set_type_filter(types).map_err(error)),
+            Op::SetDedup(len, key) => Outcome::Unit(port.set_dedup_window(len, key).map_err(error)),
+            Op::SetBudget(budget) => {
+                match budget {
+                    Some(n) => port.set_skip_budget(n),
+                    None => port.clear_skip_budget(),
+                }
+                Outcome::Skipped
+            }
+            Op::AdvanceClock(ns) => {
+                self.clock.advance(ns);
+                Outcome::Skipped
+            }
+        }
+    }
+}
+
+/// Where port and model first disagreed.
+struct Divergence {
+    step: usize,
+    what: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {}: {}", self.step, self.what)
+    }
+}
+
+fn run(setup: Setup, ops: &[Op]) -> Result<(), Divergence> {
+    let mut model = Model::new(setup);
+    let mut real = Real::new(setup);
+    for (step, op) in ops.iter().enumerate() {
+        let would_block = matches!(*op, Op::EnqueueBy { deadline_ns, .. } if model.now_ns < deadline_ns && model.room() == 0);
+        let expected = model.step(op);
+        let got = real.step(op, would_block);
+        let diverged = |what: String| Err(Divergence { step, what });
+        if got != expected {
+            return diverged(format!("{:?} returned {:?}, model {:?}", op, got, expected));
+        }
+        if real.port.len() != model.queue.len() {
+            return diverged(format!("after {:?}, len {} but model {}", op, real.port.len(), model.queue.len()));
+        }
+        if real.port.stats() != model.stats {
+            return diverged(format!("after {:?}, stats {:?} but model {:?}", op, real.port.stats(), model.stats));
+        }
+        if real.port.credits_remaining() != model.credits() {
+            return diverged(format!(
+                "after {:?}, credits {:?} but model {:?}",
+                op,
+                real.port.credits_remaining(),
+                model.credits()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Drops and simplifies operations for as long as the sequence still fails.
+fn shrink(setup: Setup, mut ops: Vec<Op>) -> Vec<Op> {
+    let fails = |ops: &[Op]| run(setup, ops).is_err();
+    loop {
+        let before = ops.len();
+        // Everything after the first divergence is noise.
+        if let Err(divergence) = run(setup, &ops) {
+            ops.truncate(divergence.step + 1);
+        }
+        let mut i = 0;
+        while i < ops.len() {
+            let mut shorter = ops.clone();
+            shorter.remove(i);
+            if fails(&shorter) {
+                ops = shorter;
+            } else {
+                i += 1;
+            }
+        }
+        for i in 0..ops.len() {
+            if let Op::Enqueue { len, id, msg_type, fill: _ } = ops[i] {
+                for simpler in [
+                    Op::Enqueue { len: 0, id: 0, msg_type: 0, fill: 0 },
+                    Op::Enqueue { len: len.min(6), id, msg_type, fill: 0 },
+                ] {
+                    let mut candidate = ops.clone();
+                    candidate[i] = simpler;
+                    if fails(&candidate) {
+                        ops = candidate;
+                        break;
+                    }
+                }
+            }
+        }
+        if ops.len() == before {
+            return ops;
+        }
+    }
+}
+
+fn check_seed(seed: u64) {
+    let (setup, ops) = generate(&mut Rng(seed));
+    if run(setup, &ops).is_ok() {
+        return;
+    }
+    let minimal = shrink(setup, ops);
+    let divergence = run(setup, &minimal).expect_err("shrinking keeps the failure");
+    panic!(
+        "seed {} diverged with {:?}\nminimal sequence:\n{:#?}\n{}",
+        seed, setup, minimal, divergence
+    );
+}
+
+#[test]
+fn port_matches_model() {
+    match std::env::var("MODEL_SEED") {
+        Ok(seed) => check_seed(seed.parse().expect("MODEL_SEED is a number")),
+        Err(_) => (0..CASES).for_each(check_seed),
+    }
+}
+
+#[test]
+fn compacting_a_wrapped_strict_port_keeps_order() {
+    // Wraps the ring, so compacting has to move the sequence numbers with
+    // their slots for strict FIFO to keep accepting them.
+    let setup = Setup { credit_limit: 0, order: DeliveryOrder::StrictFifo };
+    let enqueue = |id| Op::Enqueue { len: 6, id, msg_type: 0, fill: 0 };
+    let mut ops: Vec<Op> = (0..MSGS as u32).map(enqueue).collect();
+    ops.extend([Op::Dequeue, Op::Dequeue, Op::Dequeue, enqueue(1), enqueue(2), Op::Compact, Op::Peek(8)]);
+    ops.extend([Op::Drain, enqueue(3), Op::Dequeue]);
+    if let Err(divergence) = run(setup, &ops) {
+        panic!("{}", divergence);
+    }
+}
@@ -0,0 +1,62 @@
+//! The process-wide limit on open named ports, read from
+//! `QUEUEING_PORT_MAX_OPEN`. The limit is global, so these checks run in a
+//! process of their own, as one test.
+#![cfg(feature = "shmem")]
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ring_buffer::{OpenPortLimit, PortError, QueueingPort, MAX_OPEN_ENV};
+
+fn port_name(n: usize) -> String {
+    format!("/qp_open_limit_{}_{}", n, std::process::id())
+}
+
+/// Creates the port `name` on another thread and opens it here, returning
+/// the creator and the opener.
+fn connected(name: &str) -> Result<(QueueingPort, QueueingPort), PortError> {
+    let creator = thread::spawn({
+        let name = name.to_owned();
+        move || QueueingPort::create(&name)
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        if creator.is_finished() {
+            return Err(creator.join().unwrap().err().expect("created without a reader"));
+        }
+        match QueueingPort::open(name) {
+            Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+            opened => return Ok((creator.join().unwrap()?, opened?)),
+        }
+    }
+}
+
+#[test]
+fn a_third_port_past_a_limit_of_two_fails() {
+    // Before the first named port, which reads it.
+    std::env::set_var(MAX_OPEN_ENV, "2");
+    assert_eq!((OpenPortLimit::open_ports(), OpenPortLimit::max()), (0, 2));
+
+    // Both ends count: one created and one opened port.
+    let (creator, opener) = connected(&port_name(1)).unwrap();
+    assert_eq!(OpenPortLimit::open_ports(), 2);
+    assert!(matches!(QueueingPort::create(&port_name(2)), Err(PortError::TooManyOpenPorts)));
+    assert!(matches!(QueueingPort::open(&port_name(1)), Err(PortError::TooManyOpenPorts)));
+    assert!(matches!(QueueingPort::open_observer(&port_name(1)), Err(PortError::TooManyOpenPorts)));
+    assert_eq!(OpenPortLimit::open_ports(), 2, "refused ports take no place");
+
+    // Closing a port frees its place.
+    drop(opener);
+    assert_eq!(OpenPortLimit::open_ports(), 1);
+    let observer = QueueingPort::open_observer(&port_name(1)).unwrap();
+    assert_eq!(OpenPortLimit::open_ports(), 2);
+    drop((creator, observer));
+    assert_eq!(OpenPortLimit::open_ports(), 0);
+
+    OpenPortLimit::set_max(4);
+    let first = connected(&port_name(3)).unwrap();
+    let second = connected(&port_name(4)).unwrap();
+    assert!(matches!(connected(&port_name(5)), Err(PortError::TooManyOpenPorts)));
+    drop((first, second));
+    assert_eq!(OpenPortLimit::open_ports(), 0);
+}
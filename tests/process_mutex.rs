@@ -0,0 +1,84 @@
+//! A `ProcessSharedMutex` in memory shared with a forked child.
+#![cfg(all(feature = "process-mutex", target_os = "linux"))]
+
+use std::mem::{size_of, MaybeUninit};
+use std::ptr;
+
+use ring_buffer::ProcessSharedMutex;
+
+/// An anonymous shared mapping a forked child shares with its parent.
+fn shared<T>() -> &'static mut MaybeUninit<T> {
+    let memory = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size_of::<T>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(memory, libc::MAP_FAILED);
+    unsafe { &mut *memory.cast() }
+}
+
+fn fork(child: impl FnOnce() -> i32) -> libc::pid_t {
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        let status = child();
+        unsafe { libc::_exit(status) };
+    }
+    pid
+}
+
+fn wait(pid: libc::pid_t) -> i32 {
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status));
+    libc::WEXITSTATUS(status)
+}
+
+/// Adds one to the counter, with the load and the store apart so that
+/// unsynchronized increments would get lost.
+fn increment(mutex: &ProcessSharedMutex<u64>, times: u64) {
+    for _ in 0..times {
+        let mut counter = mutex.lock();
+        let seen = unsafe { ptr::read_volatile(&*counter) };
+        std::thread::yield_now();
+        unsafe { ptr::write_volatile(&mut *counter, seen + 1) };
+    }
+}
+
+#[test]
+fn two_processes_exclude_each_other() {
+    const TIMES: u64 = 2000;
+    let mutex = unsafe { ProcessSharedMutex::init(shared(), 0u64).unwrap() };
+    let child = fork(|| {
+        increment(mutex, TIMES);
+        0
+    });
+    increment(mutex, TIMES);
+    assert_eq!(wait(child), 0);
+    assert_eq!(*mutex.lock(), 2 * TIMES);
+    unsafe { mutex.destroy() };
+}
+
+#[test]
+fn a_dead_owner_is_reported_to_the_next() {
+    let mutex = unsafe { ProcessSharedMutex::init(shared(), 0u64).unwrap() };
+    let child = fork(|| {
+        let mut guard = mutex.lock();
+        *guard = 7;
+        // Exits holding the lock.
+        std::mem::forget(guard);
+        0
+    });
+    assert_eq!(wait(child), 0);
+    let guard = mutex.lock();
+    assert!(guard.owner_died());
+    assert_eq!(*guard, 7);
+    drop(guard);
+    assert!(!mutex.lock().owner_died(), "consistent again");
+    unsafe { mutex.destroy() };
+}
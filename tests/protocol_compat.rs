@@ -0,0 +1,159 @@
+//! Byte-level checks of the segment layout documented in PROTOCOL.md.
+//!
+//! The buffers here are written by hand, the way a C or Ada peer would lay
+//! them out, so any change to offsets, padding or byte order shows up here.
+//! The `slot-poison` feature uses a different layout and is not covered.
+#![cfg(not(feature = "slot-poison"))]
+
+use ring_buffer::{Message, QueueingPort, WireFeatures, MSGS, SIZE};
+
+const HEADER_LEN: usize = 1280;
+const SEGMENT_LEN: usize = HEADER_LEN + SIZE * MSGS;
+
+#[repr(C, align(4))]
+struct RawSegment([u8; SEGMENT_LEN]);
+
+impl RawSegment {
+    fn zeroed() -> Box<RawSegment> {
+        Box::new(RawSegment([0; SEGMENT_LEN]))
+    }
+
+    fn put_u32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.0[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut [u8] {
+        let start = HEADER_LEN + index * SIZE;
+        &mut self.0[start..start + SIZE]
+    }
+
+    fn slot(&self, index: usize) -> &[u8] {
+        let start = HEADER_LEN + index * SIZE;
+        &self.0[start..start + SIZE]
+    }
+}
+
+#[test]
+fn segment_size_matches_spec() {
+    assert_eq!(core::mem::size_of::<ring_buffer::Segment>(), SEGMENT_LEN);
+}
+
+#[test]
+fn dequeue_reads_hand_written_segment() {
+    let mut raw = RawSegment::zeroed();
+    // 0x00: 03 00 00 00  01 00 00 00  02 00 00 00  03 00 00 00
+    raw.put_u32(0, 3); // write_index
+    raw.put_u32(4, 1); // read_index
+    raw.put_u32(8, 2); // message_count
+    raw.put_u32(12, 3); // enqueued
+    // 0x10: 01 00 00 00  00 00 00 00  03 00 00 00  00 00 00 00
+    raw.put_u32(16, 1); // dequeued
+    raw.put_u32(24, 3); // high_watermark
+    // 0x20: 03 00 00 00  (state = OPEN)
+    raw.0[32] = 3;
+    // Slot 1 at 0x600: 41 41 41 41 ..., slot 2 at 0x700: 42 42 42 42 ...
+    raw.slot_mut(1).fill(0x41);
+    raw.slot_mut(2).fill(0x42);
+    // slot_generation at 0xe8: slots 1 and 2 occupied (odd).
+    raw.put_u32(232 + 4, 1);
+    raw.put_u32(232 + 2 * 4, 1);
+
+    let mut port = QueueingPort::with_buffer(&mut raw.0).unwrap();
+    assert_eq!(port.len(), 2);
+    assert_eq!(port.dequeue().unwrap().0, [0x41; SIZE]);
+    assert_eq!(port.dequeue().unwrap().0, [0x42; SIZE]);
+    assert!(port.try_dequeue().is_err());
+    drop(port);
+
+    assert_eq!(raw.u32_at(4), 3, "read_index advanced past both slots");
+    assert_eq!(raw.u32_at(8), 0, "message_count");
+    assert_eq!(raw.u32_at(16), 3, "dequeued");
+    assert_eq!(raw.slot(1), [0; SIZE], "consumed slots are zeroed");
+    assert_eq!(raw.u32_at(232 + 4), 2, "consumed slots get an even generation");
+    assert_eq!(raw.u32_at(232 + 2 * 4), 2);
+}
+
+#[test]
+fn enqueue_writes_spec_layout() {
+    let mut raw = RawSegment::zeroed();
+    // Start near the end of the ring so the second enqueue wraps to slot 0.
+    raw.put_u32(0, 9); // write_index
+    raw.put_u32(4, 9); // read_index
+
+    let mut port = QueueingPort::with_buffer(&mut raw.0).unwrap();
+    let mut first = [0x11; SIZE];
+    // Type id 0x0102 in bytes 4..6: 02 01
+    first[4..6].copy_from_slice(&[0x02, 0x01]);
+    port.enqueue(Message(first)).unwrap();
+    port.enqueue(Message([0x22; SIZE])).unwrap();
+    drop(port);
+
+    // 0x00: 01 00 00 00  09 00 00 00  02 00 00 00  02 00 00 00
+    assert_eq!(&raw.0[..16], &[1, 0, 0, 0, 9, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0]);
+    // Slot 9 at 0xa60, slot 0 at 0x160.
+    assert_eq!(&raw.slot(9)[..6], &[0x11, 0x11, 0x11, 0x11, 0x02, 0x01]);
+    assert_eq!(raw.slot(0), [0x22; SIZE]);
+    // slot_sequence at 0xc0: slot 0 holds sequence 1, slot 9 sequence 0.
+    assert_eq!(raw.u32_at(192), 1);
+    assert_eq!(raw.u32_at(192 + 9 * 4), 0);
+    // slot_generation at 0xe8: both slots now occupied.
+    assert_eq!(raw.u32_at(232), 1);
+    assert_eq!(raw.u32_at(232 + 9 * 4), 1);
+}
+
+#[test]
+fn with_buffer_rejects_short_or_misaligned_buffers() {
+    let mut raw = RawSegment::zeroed();
+    assert!(QueueingPort::with_buffer(&mut raw.0[..SEGMENT_LEN - 1]).is_err());
+    let mut longer = vec![0u32; SEGMENT_LEN / 4 + 1];
+    let bytes = unsafe { core::slice::from_raw_parts_mut(longer.as_mut_ptr().cast::<u8>(), SEGMENT_LEN + 4) };
+    assert!(QueueingPort::with_buffer(&mut bytes[1..]).is_err());
+    assert!(QueueingPort::with_buffer(&mut bytes[4..]).is_ok());
+}
+
+#[test]
+fn metadata_read_from_header() {
+    let mut raw = RawSegment::zeroed();
+    // 0x34: 05 00 00 00  (generation)  'p' 'o' 'r' 't' '1'
+    raw.put_u32(52, 5);
+    raw.0[60..65].copy_from_slice(b"port1");
+    let port = QueueingPort::with_buffer(&mut raw.0).unwrap();
+    assert_eq!(port.metadata(), b"port1");
+}
+
+#[test]
+fn segment_generation_read_from_header() {
+    let mut raw = RawSegment::zeroed();
+    // 0x38: 78 56 34 12
+    raw.put_u32(56, 0x1234_5678);
+    let mut port = QueueingPort::with_buffer(&mut raw.0).unwrap();
+    assert_eq!(port.segment_generation(), 0x1234_5678);
+    port.enqueue(Message([1; SIZE])).unwrap();
+    assert_eq!(port.dequeue().unwrap().0, [1; SIZE]);
+}
+
+#[test]
+fn wire_features_read_from_header() {
+    let mut raw = RawSegment::zeroed();
+    // 0x28: 02 00 01 00  (CRC and TIMESTAMPS)
+    raw.put_u32(40, 0x0001_0002);
+    let port = QueueingPort::with_buffer(&mut raw.0).unwrap();
+    assert_eq!(port.wire_features(), WireFeatures::CRC | WireFeatures::TIMESTAMPS);
+}
+
+#[test]
+fn peers_word_gates_enqueue() {
+    let mut raw = RawSegment::zeroed();
+    // 0xbc: 00 01 00 00  (receiver required, nobody attached)
+    raw.put_u32(188, 0x0100);
+    let mut port = QueueingPort::with_buffer(&mut raw.0).unwrap();
+    assert!(port.enqueue(Message([1; SIZE])).is_err());
+    port.announce(ring_buffer::PeerRole::Receiver);
+    port.enqueue(Message([1; SIZE])).unwrap();
+    drop(port);
+    assert_eq!(raw.u32_at(188), 0x0102);
+}
@@ -0,0 +1,54 @@
+//! A channel declared with `queue_channel!`, used from the functions it
+//! declares. The channel is a static, so one test owns it.
+
+use std::thread;
+
+use ring_buffer::{queue_channel, Pod, QueueError};
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TelemetryFrame {
+    sequence: u32,
+    temperature: f32,
+    position: [i16; 4],
+}
+
+unsafe impl Pod for TelemetryFrame {}
+
+queue_channel!(telemetry: TelemetryFrame, capacity: 16);
+
+fn frame(sequence: u32) -> TelemetryFrame {
+    TelemetryFrame { sequence, temperature: sequence as f32 / 2.0, position: [sequence as i16, -1, 2, -3] }
+}
+
+#[test]
+fn frames_go_through_in_order() {
+    assert!(matches!(recv_telemetry(), Err(QueueError::EmptyBuffer)));
+    for sequence in 0..16 {
+        send_telemetry(frame(sequence)).unwrap();
+    }
+    assert!(matches!(send_telemetry(frame(16)), Err(QueueError::FullBuffer)));
+    for sequence in 0..16 {
+        assert_eq!(recv_telemetry().unwrap(), frame(sequence));
+    }
+    assert!(matches!(recv_telemetry(), Err(QueueError::EmptyBuffer)));
+
+    // From another thread, through more frames than the channel holds.
+    let sender = thread::spawn(|| {
+        for sequence in 0..1000 {
+            while send_telemetry(frame(sequence)).is_err() {
+                thread::yield_now();
+            }
+        }
+    });
+    for sequence in 0..1000 {
+        let received = loop {
+            match recv_telemetry() {
+                Ok(received) => break received,
+                Err(_) => thread::yield_now(),
+            }
+        };
+        assert_eq!(received, frame(sequence));
+    }
+    sender.join().unwrap();
+}
@@ -0,0 +1,68 @@
+//! Creating a port on a full `/dev/shm`.
+//!
+//! Filling the real `/dev/shm` takes as much memory as it has room for, so
+//! the test is ignored; run it on a small tmpfs of its own, as root:
+//!
+//! ```text
+//! unshare -m sh -c 'mount -t tmpfs -o size=1m tmpfs /dev/shm &&
+//!     cargo test --test shmem_exhausted -- --ignored'
+//! ```
+#![cfg(all(feature = "shmem", target_os = "linux"))]
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ring_buffer::{OpenFailure, PortError, QueueingPort};
+
+/// Writes a file into `/dev/shm` until it is full, returning its path.
+fn fill_dev_shm() -> String {
+    let path = format!("/dev/shm/qp_fill_{}", std::process::id());
+    let mut file = File::create(&path).unwrap();
+    // Large chunks, then pages, so not even a page is left.
+    for chunk in [1 << 20, 4096] {
+        let zeroes = vec![0; chunk];
+        loop {
+            match file.write_all(&zeroes) {
+                Ok(()) => {}
+                Err(error) if error.raw_os_error() == Some(28) => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => panic!("filling /dev/shm: {}", error),
+            }
+        }
+    }
+    path
+}
+
+#[test]
+#[ignore = "fills /dev/shm; run on a small tmpfs, see the file documentation"]
+fn a_full_dev_shm_is_an_error_and_creating_works_once_freed() {
+    let name = format!("/qp_exhausted_{}", std::process::id());
+    let filler = fill_dev_shm();
+
+    match QueueingPort::create_with_timeout(&name, Duration::from_millis(10)) {
+        Err(PortError::Open(error)) => {
+            assert!(matches!(error.kind, OpenFailure::InsufficientSpace { .. }), "{}", error);
+            assert!(error.is_retryable());
+        }
+        other => panic!("created on a full /dev/shm: {:?}", other.map(|_| ())),
+    }
+    assert!(QueueingPort::open(&name).is_err(), "the failed segment was unlinked");
+
+    fs::remove_file(&filler).unwrap();
+    let creator = thread::spawn({
+        let name = name.clone();
+        move || QueueingPort::create(&name)
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut opener = loop {
+        match QueueingPort::open(&name) {
+            Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+            opened => break opened.unwrap(),
+        }
+    };
+    let mut creator = creator.join().unwrap().unwrap();
+    creator.enqueue_bytes(b"after").unwrap();
+    assert_eq!(&opener.dequeue().unwrap().0[..5], b"after");
+}
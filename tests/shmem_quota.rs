@@ -0,0 +1,67 @@
+//! The process-wide shared-memory quota. The quota is global, so these
+//! checks run in a process of their own, as one test.
+#![cfg(feature = "shmem")]
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ring_buffer::{PortError, QueueingPort, Segment, ShmemQuota};
+
+const SEGMENT_BYTES: usize = core::mem::size_of::<Segment>();
+
+fn port_name(n: usize) -> String {
+    format!("/qp_quota_{}_{}", n, std::process::id())
+}
+
+/// Creates the port `name` and opens it, returning the creator and opener.
+fn connected(name: &str) -> Result<(QueueingPort, QueueingPort), PortError> {
+    let creator = thread::spawn({
+        let name = name.to_owned();
+        move || QueueingPort::create(&name)
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        if creator.is_finished() {
+            // Without a reader it can only have failed.
+            return Err(creator.join().unwrap().err().expect("created without a reader"));
+        }
+        match QueueingPort::open(name) {
+            Err(PortError::Open(_)) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+            opened => return Ok((creator.join().unwrap()?, opened?)),
+        }
+    }
+}
+
+#[test]
+fn creating_past_the_quota_fails() {
+    assert_eq!((ShmemQuota::current_usage(), ShmemQuota::max_usage()), (0, usize::MAX));
+    ShmemQuota::set_max_bytes(2 * SEGMENT_BYTES + SEGMENT_BYTES / 2);
+    assert_eq!(ShmemQuota::max_usage(), 2 * SEGMENT_BYTES + SEGMENT_BYTES / 2);
+
+    // Only creators are charged, not the handles opening their segments.
+    let first = connected(&port_name(1)).unwrap();
+    let second = connected(&port_name(2)).unwrap();
+    assert_eq!(ShmemQuota::current_usage(), 2 * SEGMENT_BYTES);
+
+    assert!(matches!(connected(&port_name(3)), Err(PortError::QuotaExceeded)));
+    assert!(QueueingPort::open(&port_name(3)).is_err(), "no segment was made");
+    assert_eq!(ShmemQuota::current_usage(), 2 * SEGMENT_BYTES);
+
+    // Dropping the creator gives its bytes back; the opener does not.
+    let (creator, opener) = first;
+    drop(opener);
+    assert_eq!(ShmemQuota::current_usage(), 2 * SEGMENT_BYTES);
+    drop(creator);
+    assert_eq!(ShmemQuota::current_usage(), SEGMENT_BYTES);
+    let third = connected(&port_name(3)).unwrap();
+    assert_eq!(ShmemQuota::current_usage(), 2 * SEGMENT_BYTES);
+
+    // A lower limit leaves the existing ports alone.
+    ShmemQuota::set_max_bytes(SEGMENT_BYTES);
+    assert!(matches!(QueueingPort::create(&port_name(4)), Err(PortError::QuotaExceeded)));
+    drop((second, third));
+    assert_eq!(ShmemQuota::current_usage(), 0);
+    let fourth = connected(&port_name(4)).unwrap();
+    assert_eq!(ShmemQuota::current_usage(), SEGMENT_BYTES);
+    drop(fourth);
+}
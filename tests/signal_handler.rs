@@ -0,0 +1,42 @@
+//! `signal_safe_enqueue` called from a real signal handler.
+#![cfg(unix)]
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ring_buffer::{signal_safe_enqueue, Message, QueueingPort, SIGNAL_QUEUE, SIZE};
+
+extern "C" fn on_sigusr1(_signal: libc::c_int) {
+    let _ = signal_safe_enqueue(Message([0x51; SIZE]));
+}
+
+#[test]
+fn messages_enqueued_by_a_handler_reach_the_port() {
+    let handler: extern "C" fn(libc::c_int) = on_sigusr1;
+    assert_ne!(unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) }, libc::SIG_ERR);
+
+    let port = Arc::new(Mutex::new(QueueingPort::new()));
+    let drainer = {
+        let port = Arc::clone(&port);
+        thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let mut drained = 0;
+            while drained < 3 && Instant::now() < deadline {
+                drained += SIGNAL_QUEUE.drain_into(&mut port.lock().unwrap());
+                thread::sleep(Duration::from_millis(1));
+            }
+            drained
+        })
+    };
+    for _ in 0..3 {
+        // Delivered to this thread before `raise` returns.
+        assert_eq!(unsafe { libc::raise(libc::SIGUSR1) }, 0);
+    }
+    assert_eq!(drainer.join().unwrap(), 3);
+    let mut port = port.lock().unwrap();
+    for _ in 0..3 {
+        assert_eq!(port.dequeue().unwrap().0, [0x51; SIZE]);
+    }
+    assert!(port.is_empty());
+}
@@ -0,0 +1,70 @@
+#!/usr/bin/env python3
+"""Prints the time each message spends queued, from enqueue to dequeue.
+
+Attaches BCC uprobes to the `__bpf_usdt_enqueue` and `__bpf_usdt_dequeue`
+probe points of a program built with the ring_buffer `bpf-trace` feature.
+Both take the segment address and the message's sequence number, so a
+dequeue is matched to its enqueue by the pair; within one process, which
+is where the segment address identifies the port.
+
+    sudo ./tools/bcc_message_latency.py path/to/binary [--pid PID]
+"""
+
+import argparse
+
+from bcc import BPF
+
+PROGRAM = r"""
+#include <uapi/linux/ptrace.h>
+
+struct key_t {
+    u64 segment;
+    u32 sequence;
+};
+
+BPF_HASH(enqueued_at, struct key_t, u64);
+
+int on_enqueue(struct pt_regs *ctx) {
+    struct key_t key = {};
+    key.segment = PT_REGS_PARM1(ctx);
+    key.sequence = PT_REGS_PARM2(ctx);
+    u64 now = bpf_ktime_get_ns();
+    enqueued_at.update(&key, &now);
+    return 0;
+}
+
+int on_dequeue(struct pt_regs *ctx) {
+    struct key_t key = {};
+    key.segment = PT_REGS_PARM1(ctx);
+    key.sequence = PT_REGS_PARM2(ctx);
+    u64 *then = enqueued_at.lookup(&key);
+    if (then == 0)
+        return 0;
+    bpf_trace_printk("%u %llu\n", key.sequence, bpf_ktime_get_ns() - *then);
+    enqueued_at.delete(&key);
+    return 0;
+}
+"""
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("binary", help="program built with the bpf-trace feature")
+    parser.add_argument("--pid", type=int, default=-1, help="trace this process only")
+    args = parser.parse_args()
+
+    bpf = BPF(text=PROGRAM)
+    bpf.attach_uprobe(name=args.binary, sym="__bpf_usdt_enqueue", fn_name="on_enqueue", pid=args.pid)
+    bpf.attach_uprobe(name=args.binary, sym="__bpf_usdt_dequeue", fn_name="on_dequeue", pid=args.pid)
+    print("%10s %12s" % ("SEQUENCE", "QUEUED (ns)"))
+    while True:
+        try:
+            (_, _, _, _, _, message) = bpf.trace_fields()
+        except KeyboardInterrupt:
+            break
+        sequence, latency = message.split()
+        print("%10s %12s" % (sequence.decode(), latency.decode()))
+
+
+if __name__ == "__main__":
+    main()